@@ -0,0 +1,43 @@
+//! Open a tunnel to a local service from your own program:
+//!
+//! ```sh
+//! cargo run --example embed -- 3000 localhost:5000
+//! ```
+use portal::{Tunnel, TunnelEvent};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let port = args
+        .next()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(8000);
+
+    let mut builder = Tunnel::builder().local_port(port);
+    if let Some((host, server_port)) = args.next().as_deref().and_then(|s| s.split_once(':')) {
+        let server_port = server_port.parse().expect("invalid server port");
+        builder = builder.server(host, server_port, false);
+    }
+
+    let mut tunnel = builder.connect().await?;
+    println!("forwarding {} to localhost:{}", tunnel.url(), port);
+
+    while let Some(event) = tunnel.next_event().await {
+        match event {
+            TunnelEvent::Request(request) => println!(
+                "{} {} {} ({} ms)",
+                request.method, request.path, request.status, request.duration
+            ),
+            TunnelEvent::Reconnecting { reason, delay, .. } => {
+                println!("lost the server ({}), retrying in {:?}", reason, delay)
+            }
+            TunnelEvent::Connected { url, .. } => println!("back on {}", url),
+            TunnelEvent::Notice { level, message } => println!("{}: {}", level, message),
+            TunnelEvent::UpdateAvailable(update) => {
+                println!("version {} is out: {}", update.version, update.download_url)
+            }
+            TunnelEvent::Stats(_) => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use rand::Rng;
+use std::time::Duration;
+
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Exponential backoff between reconnects, jittered so the agents of a server
+/// that went away don't all come back at the same moment
+#[derive(Debug, Default)]
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    /// How many times we waited since we were last connected
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Double the delay up to `MAX_DELAY`, waiting somewhere in its upper half
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = INITIAL_DELAY
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(MAX_DELAY);
+        self.attempt += 1;
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// Start over once we're connected again
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_doubles_up_to_max() {
+        let mut backoff = Backoff::default();
+        for attempt in 0..40 {
+            let full = INITIAL_DELAY
+                .saturating_mul(1 << attempt.min(16))
+                .min(MAX_DELAY);
+            let delay = backoff.next_delay();
+            assert!(
+                delay >= full / 2 && delay <= full,
+                "{}: {:?}",
+                attempt,
+                delay
+            );
+        }
+        assert_eq!(backoff.attempt(), 40);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut backoff = Backoff::default();
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.next_delay() <= INITIAL_DELAY);
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+
+use super::Commands;
+use crate::*;
+
+/// Run what the command line asks for
+pub async fn run() {
+    if let Some(Commands::SetAuth { key }) = &get_cli().command {
+        match save_key(key) {
+            Ok(path) => eprintln!("Authentication key stored in {}", path.display()),
+            Err(e) => {
+                eprintln!("Failed to store the authentication key: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(Commands::Run { port, command }) = &get_cli().command {
+        spawn::run(*port, command).await;
+        return;
+    }
+
+    if let Some(Commands::Service { action }) = &get_cli().command {
+        if let Err(e) = service::run(action) {
+            eprintln!("Service error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let configs = match &get_cli().command {
+        Some(Commands::Start { names }) => load_tunnels(names),
+        _ => vec![get_config().clone()],
+    };
+    serve(configs).await;
+}
+
+/// The config file `start` and the service use
+pub(crate) fn config_file() -> Option<PathBuf> {
+    get_cli().config.clone().or_else(default_config_file)
+}
+
+/// Load the named tunnels of the config file, exiting if we can't
+pub(crate) fn load_tunnels(names: &[String]) -> Vec<Config> {
+    let path = match config_file() {
+        Some(path) => path,
+        None => {
+            eprintln!("Please give the config file with the `--config` option");
+            std::process::exit(1);
+        }
+    };
+    match Config::load_tunnels(&path, names) {
+        Ok(configs) => configs,
+        Err(e) => {
+            eprintln!("Failed to load tunnels: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Open the tunnels and their dashboard, until the server turns them all away
+pub(crate) async fn serve(configs: Vec<Config>) {
+    let configs: Vec<Config> = configs
+        .into_iter()
+        .map(|config| match config.serve_dir.clone() {
+            Some(dir) => match files::serve(dir) {
+                Ok(addr) => config.serving(addr),
+                Err(e) => {
+                    eprintln!("Failed to serve files: {}", e);
+                    std::process::exit(1);
+                }
+            },
+            None => config,
+        })
+        .collect();
+    update::check().await;
+
+    let introspect_dash_addr = introspect::start_introspect_web_dashboard(configs.clone());
+
+    futures::future::join_all(configs.into_iter().map(|config| async move {
+        let state = TunnelState::new(Some(introspect_dash_addr), None);
+        let error = run_tunnel(config.clone(), state).await;
+        report_error(&config, &error);
+    }))
+    .await;
+}
+
+/// Tell the user why the server turned the tunnel away
+fn report_error(config: &Config, error: &Error) {
+    if let Error::AuthenticationFailed = error {
+        if config.secret_key.is_none() {
+            bunt::eprintln!("{$yellow}>> Please use an access key with the `--key` option{/$}");
+        }
+        bunt::eprintln!(
+            "{$yellow}>> You can get your access key here: {/$}{$yellow+underline}https://dashboard.portal.illusiontech.cn{/$}"
+        );
+        bunt::eprintln!("{$red}\nError: {e}{/$}", e = error);
+    } else {
+        bunt::eprintln!("{$red}Error: {e}{/$}", e = error);
+    }
+}
@@ -3,7 +3,17 @@ use std::borrow::Cow;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 
-use crate::{get_first_run, Config};
+mod commands;
+pub(crate) use self::commands::config_file;
+#[cfg(windows)]
+pub(crate) use self::commands::load_tunnels;
+pub use self::commands::run;
+pub(crate) use self::commands::serve;
+
+use crate::middleware::{AddHeader, BasicAuth};
+use crate::rewrite::HostHeader;
+use crate::routes::parse_route;
+use crate::{Config, PathRoute, Protocol, Proxy, Transport, DEFAULT_TUNNEL};
 use clap::{Parser, Subcommand};
 use cli_table::format::Padding;
 use cli_table::{format::Justify, print_stderr, Cell, Table};
@@ -24,6 +34,10 @@ pub struct Cli {
     #[arg(short, long, value_name = "FILE")]
     pub config: Option<PathBuf>,
 
+    /// Log to this file instead of the terminal, starting a new one every 10 MB
+    #[arg(long, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+
     /// Sets an API authentication key to use for this portal
     #[arg(short, long)]
     pub key: Option<String>,
@@ -40,13 +54,155 @@ pub struct Cli {
     #[arg(long = "use-tls", short = 't')]
     pub use_tls: bool,
 
+    /// Skip verifying the certificate of the local https service, i.e. a self-signed one
+    #[arg(long, requires = "use_tls")]
+    pub insecure: bool,
+
+    /// Only accept the local https service's certificate with this SHA-256 fingerprint
+    #[arg(
+        long,
+        value_name = "SHA256",
+        requires = "use_tls",
+        conflicts_with = "insecure"
+    )]
+    pub pin_cert: Option<String>,
+
+    /// The name to ask the local https service for (SNI) and verify, if not --host
+    #[arg(long, value_name = "NAME", requires = "use_tls")]
+    pub tls_server_name: Option<String>,
+
+    /// Serve the files in this directory instead of forwarding to a local service
+    #[arg(long, value_name = "DIR", conflicts_with = "use_tls")]
+    pub serve_dir: Option<PathBuf>,
+
+    /// What Host the local service sees: rewrite (to --host and --port), preserve or custom:<value>
+    #[arg(long, value_name = "MODE", default_value = "preserve")]
+    pub host_header: HostHeader,
+
+    /// Prepend this to the path of every request, i.e. /api
+    #[arg(long, value_name = "PREFIX")]
+    pub path_prefix: Option<String>,
+
+    /// Send requests whose path starts with PREFIX to this local port instead, i.e.
+    /// /api=8000, can be used multiple times
+    #[arg(long = "route", value_name = "PREFIX=PORT", value_parser = parse_route)]
+    pub routes: Vec<PathRoute>,
+
+    /// Only let visitors through with this user name and password
+    #[arg(long, value_name = "USER:PASSWORD")]
+    pub basic_auth: Option<BasicAuth>,
+
+    /// Set this header on every request toward the local service, can be used multiple times
+    #[arg(long = "add-header", value_name = "NAME:VALUE")]
+    pub add_headers: Vec<AddHeader>,
+
+    /// How to connect to the control server: websocket, or quic to keep the tunnel across networks
+    #[arg(long, value_name = "TRANSPORT", default_value = "websocket")]
+    pub transport: Transport,
+
+    /// Reach the control server through this http://, socks5:// or socks5h:// proxy,
+    /// with user:password@ before the host if it asks for credentials [env: CTRL_PROXY]
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<Proxy>,
+
+    /// What visitors speak to reach the tunnel: http, https, tcp, tls or udp
+    #[arg(long, value_name = "PROTOCOL", default_value = "http")]
+    pub protocol: Protocol,
+
+    /// The public port a tcp or udp tunnel asks for, any free one when not given
+    #[arg(long, value_name = "PORT")]
+    pub remote_port: Option<u16>,
+
+    /// Have the server hold visitors' requests, i.e. webhooks, while the tunnel is offline
+    /// and deliver them in order once it's back
+    #[arg(long)]
+    pub queue_offline: bool,
+
+    /// Let the server cache responses to GET requests for as long as their Cache-Control
+    /// allows, answering repeats without reaching the tunnel
+    #[arg(long)]
+    pub cache: bool,
+
+    /// Also receive a copy of every request to this other sub-domain of yours, i.e. to try
+    /// a new version against live traffic; the copies' responses are dropped
+    #[arg(long, value_name = "SUB_DOMAIN")]
+    pub mirror_of: Option<String>,
+
+    /// Have the server write the tunnel's visitors to its access log, for servers that only
+    /// log the tunnels asking for it
+    #[arg(long)]
+    pub access_log: bool,
+
+    /// Have the server add CORS headers to responses and answer preflight requests, letting
+    /// any origin call the tunnel unless --cors-origin is given
+    #[arg(long)]
+    pub cors: bool,
+
+    /// An origin allowed to call the tunnel, i.e. https://app.example.com, can be used
+    /// multiple times
+    #[arg(long = "cors-origin", value_name = "ORIGIN")]
+    pub cors_origins: Vec<String>,
+
+    /// Let browsers send cookies and credentials along with cross-origin requests
+    #[arg(long)]
+    pub cors_credentials: bool,
+
+    /// Have the server redirect visitors coming over plain http to https, for servers
+    /// behind a proxy terminating TLS
+    #[arg(long)]
+    pub https_redirect: bool,
+
+    /// Have the server add Strict-Transport-Security to responses over https, keeping
+    /// browsers on https once they've been there
+    #[arg(long)]
+    pub hsts: bool,
+
+    /// Have the server close the tunnel this many seconds after opening it, i.e. for a
+    /// temporary demo link; servers may close it sooner
+    #[arg(long, value_name = "SECONDS")]
+    pub ttl: Option<u64>,
+
+    /// Ask to be served from this region, i.e. the closest, by servers that redirect agents
+    /// to the region they prefer
+    #[arg(long, value_name = "REGION")]
+    pub region: Option<String>,
+
+    /// Ask to be served by this server instance, when the server knows it
+    #[arg(long, value_name = "INSTANCE_ID")]
+    pub instance: Option<String>,
+
+    /// Record the tunnel's exchanges to this HAR file, i.e. to open in a browser's devtools
+    #[arg(long, value_name = "FILE")]
+    pub har: Option<PathBuf>,
+
+    /// Body bytes of each request and response the HAR file keeps, the rest is cut off
+    #[arg(long, value_name = "BYTES", default_value = "65536", requires = "har")]
+    pub har_max_body: usize,
+
+    /// Redact this header in the HAR file on top of credentials and cookies, can be used
+    /// multiple times
+    #[arg(long, value_name = "HEADER", requires = "har")]
+    pub har_redact: Vec<String>,
+
     /// Sets the port to forward incoming portal traffic to on the target host
     #[arg(short, long, default_value = "8000")]
     pub port: u16,
 
-    /// Sets the address of the local introspection dashboard
+    /// Sets the port of the local introspection dashboard on 127.0.0.1 [default: 4040]
     #[arg(long = "dashboard-port")]
     pub dashboard_port: Option<u16>,
+
+    /// Print every request on the tunnel, including those served by other agents
+    #[arg(long)]
+    pub tail: bool,
+
+    /// Have the server alert when the tunnel's 95th percentile response time exceeds these milliseconds
+    #[arg(long, value_name = "MS")]
+    pub alert_p95_latency: Option<u64>,
+
+    /// Have the server alert when the tunnel's share of 5xx responses exceeds this, between 0 and 1
+    #[arg(long, value_name = "RATE")]
+    pub alert_error_rate: Option<f64>,
 }
 
 #[derive(Subcommand)]
@@ -57,6 +213,44 @@ pub enum Commands {
         #[arg(short, long)]
         key: String,
     },
+    /// Start the tunnels of the config file, all of them unless some are named
+    Start {
+        /// The names of the tunnels to start, i.e. `[tunnels.web]`
+        names: Vec<String>,
+    },
+    /// Run a command, i.e. a dev server, and tunnel to the port it listens on until it exits
+    Run {
+        /// The port the command listens on, also given to it as $PORT, instead of detecting it
+        #[arg(short, long)]
+        port: Option<u16>,
+
+        /// The command and its arguments, after `--`
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
+    },
+    /// Run the tunnels of the config file as a service that starts with the machine
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ServiceAction {
+    /// Install the service for the tunnels of the config file, all of them unless some are named
+    Install {
+        /// The names of the tunnels to start, i.e. `[tunnels.web]`
+        names: Vec<String>,
+    },
+    /// Stop and remove the service
+    Uninstall,
+    /// Start the installed service now rather than at the next boot
+    Start,
+    /// Stop the service until it's started again or the machine boots
+    Stop,
+    /// What the Windows service manager runs
+    #[command(hide = true)]
+    Run { names: Vec<String> },
 }
 
 pub struct CliInterface {
@@ -66,7 +260,15 @@ pub struct CliInterface {
 }
 impl CliInterface {
     pub fn start(config: Config, introspect: SocketAddr) -> Self {
-        let msg = format!("Opening remote tunnel to {}", config.portal_url());
+        let msg = if config.name == DEFAULT_TUNNEL {
+            format!("Opening remote tunnel to {}", config.portal_url())
+        } else {
+            format!(
+                "Opening remote tunnel {} to {}",
+                config.name,
+                config.portal_url()
+            )
+        };
         let spinner = new_spinner(msg);
         Self {
             spinner,
@@ -89,23 +291,20 @@ impl CliInterface {
         }
     }
 
-    pub async fn did_connect(&self, sub_domain: &str, full_hostname: &str) {
+    pub fn did_connect(&self, sub_domain: &str, url: &str, first_run: bool) {
         self.spinner.finish_with_message(
             "\x1b[32mSuccess! Remote tunnel is now open.\x1b[0m\n".to_string(),
         );
 
-        if !*get_first_run().lock().await {
+        if !first_run {
             return;
         }
 
-        let public_url = format!(
-            "\x1b[1;33m{}\x1b[0m",
-            self.config.activation_url(full_hostname)
-        );
+        let public_url = format!("\x1b[1;33m{}\x1b[0m", url);
         let forward_url = self.config.forward_url();
-        let inspect = format!("\x1b[35mhttp://localhost:{}\x1b[0m", self.introspect.port());
+        let inspect = format!("\x1b[35mhttp://{}\x1b[0m", self.introspect);
 
-        let table = vec![
+        let mut table = vec![
             vec![
                 "\x1b[32mPublic tunnel URL\x1b[0m".cell(),
                 public_url
@@ -128,6 +327,33 @@ impl CliInterface {
                     .justify(Justify::Left),
             ],
         ];
+        for route in &self.config.service.routes {
+            let addr = SocketAddr::new(self.config.local_addr.ip(), route.port);
+            table.push(vec![
+                format!("Forwarding {}/ to", route.prefix).cell(),
+                self.config
+                    .clone()
+                    .forwarding_to(addr)
+                    .forward_url()
+                    .cell()
+                    .padding(Padding::builder().left(4).build())
+                    .justify(Justify::Left),
+            ]);
+        }
+        if self.config.name != DEFAULT_TUNNEL {
+            table.insert(
+                0,
+                vec![
+                    "Tunnel".cell(),
+                    self.config
+                        .name
+                        .as_str()
+                        .cell()
+                        .padding(Padding::builder().left(4).build())
+                        .justify(Justify::Left),
+                ],
+            );
+        }
 
         let table = table.table();
         print_stderr(table).expect("failed to generate starting terminal user interface");
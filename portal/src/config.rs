@@ -1,43 +1,375 @@
 use serde::Deserialize;
 
 use super::*;
+use crate::introspect::har::{HarOptions, DEFAULT_MAX_BODY};
+use crate::middleware::{AddHeader, BasicAuth, Middlewares};
+use crate::rewrite::{parse_path_prefix, HostHeader};
+use crate::routes::parse_route;
+use crate::tls::TlsVerify;
 use std::{
+    collections::BTreeMap,
     error::Error,
     net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
 };
 
 const HOST_ENV: &str = "CTRL_HOST";
 const PORT_ENV: &str = "CTRL_PORT";
 const TLS_OFF_ENV: &str = "CTRL_TLS_OFF";
+const QUIC_PORT_ENV: &str = "CTRL_QUIC_PORT";
+const PATH_ENV: &str = "CTRL_PATH";
+const PROXY_ENV: &str = "CTRL_PROXY";
 
 const DEFAULT_HOST: &str = "localhost";
 const DEFAULT_CONTROL_HOST: &str = "localhost";
 const DEFAULT_CONTROL_PORT: &str = "5000";
+const DEFAULT_CONTROL_PATH: &str = "/wormhole";
+const DEFAULT_DASHBOARD_PORT: u16 = 4040;
 
 const SETTINGS_DIR: &str = ".portal";
 const SECRET_KEY_FILE: &str = "key.token";
+const AGENT_ID_FILE: &str = "agent.id";
+const CONFIG_FILE: &str = "config.toml";
+const LOGS_DIR: &str = "logs";
+const LOG_FILE: &str = "portal.log";
 
-#[derive(Deserialize, Debug)]
-struct InternalConfig {
-    sub_domain: Option<String>,
-    portal_host: Option<String>,
-    portal_port: Option<u16>,
-    portal_tls: Option<bool>,
-    local_host: Option<String>,
-    local_port: Option<u16>,
-    local_tls: Option<bool>,
-    dashboard_port: Option<u16>,
-    verbose: Option<bool>,
+/// The name of the tunnel a config file without `[tunnels]` describes
+pub const DEFAULT_TUNNEL: &str = "default";
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub(crate) struct InternalConfig {
+    pub(crate) sub_domain: Option<String>,
+    pub(crate) secret_key: Option<String>,
+    pub(crate) portal_host: Option<String>,
+    pub(crate) portal_port: Option<u16>,
+    pub(crate) portal_tls: Option<bool>,
+    /// `websocket` or `quic`
+    pub(crate) transport: Option<Transport>,
+    /// the control server's UDP port for QUIC, `portal_port` by default
+    pub(crate) portal_quic_port: Option<u16>,
+    /// the path of the control server's websocket, for a server taking agents on
+    /// its public port under a path of its choosing
+    pub(crate) portal_path: Option<String>,
+    /// `http://`, `socks5://` or `socks5h://` proxy URL we reach the control server through
+    pub(crate) proxy: Option<String>,
+    /// what visitors speak: `http`, `https`, `tcp`, `tls` or `udp`
+    pub(crate) protocol: Option<Protocol>,
+    /// the public port a `tcp` or `udp` tunnel asks for, any free one by default
+    pub(crate) remote_port: Option<u16>,
+    /// have the server queue visitors' requests while we're offline, i.e. webhooks
+    pub(crate) queue_offline: Option<bool>,
+    /// let the server cache our cacheable responses to GET requests
+    pub(crate) cache: Option<bool>,
+    /// also receive copies of the requests to this sub-domain of ours
+    pub(crate) mirror_of: Option<String>,
+    /// ask the server to write our visitors to its access log
+    pub(crate) access_log: Option<bool>,
+    /// have the server add CORS headers to our responses, letting any origin call us
+    /// unless `cors_origins` are given
+    pub(crate) cors: Option<bool>,
+    /// the origins allowed to call us, implying `cors`
+    pub(crate) cors_origins: Option<Vec<String>>,
+    /// let browsers send credentials along with their cross-origin requests
+    pub(crate) cors_credentials: Option<bool>,
+    /// have the server redirect visitors coming over plain http to https
+    pub(crate) https_redirect: Option<bool>,
+    /// have the server add HSTS to our responses over https
+    pub(crate) hsts: Option<bool>,
+    /// seconds the server keeps our tunnel open before closing it for good
+    pub(crate) ttl: Option<u64>,
+    /// the region we'd rather be served from, by servers that redirect us to it
+    pub(crate) region: Option<String>,
+    /// the server instance we'd rather be served by
+    pub(crate) instance: Option<String>,
+    /// the HAR file we record our exchanges to
+    pub(crate) har: Option<PathBuf>,
+    /// body bytes of each request and response the HAR file keeps
+    pub(crate) har_max_body: Option<usize>,
+    /// headers the HAR file redacts on top of credentials and cookies
+    pub(crate) har_redact: Option<Vec<String>>,
+    pub(crate) local_host: Option<String>,
+    pub(crate) local_port: Option<u16>,
+    pub(crate) local_tls: Option<bool>,
+    pub(crate) local_tls_insecure: Option<bool>,
+    pub(crate) local_tls_pin: Option<String>,
+    pub(crate) local_tls_server_name: Option<String>,
+    pub(crate) host_header: Option<String>,
+    pub(crate) path_prefix: Option<String>,
+    /// `prefix=port` routes sending requests by path to other local ports
+    pub(crate) routes: Option<Vec<String>>,
+    /// `user:password` visitors need to get through
+    pub(crate) basic_auth: Option<String>,
+    /// `name:value` headers set on every request toward the local service
+    pub(crate) add_headers: Option<Vec<String>>,
+    pub(crate) serve_dir: Option<PathBuf>,
+    pub(crate) dashboard_port: Option<u16>,
+    pub(crate) verbose: Option<bool>,
+    pub(crate) tail: Option<bool>,
+    pub(crate) alert_p95_latency: Option<u64>,
+    pub(crate) alert_error_rate: Option<f64>,
+    /// named tunnels, each falling back to the settings above
+    #[serde(default)]
+    pub(crate) tunnels: BTreeMap<String, InternalConfig>,
+}
+
+impl InternalConfig {
+    /// Fill in what this tunnel doesn't set from the file wide settings
+    fn or(self, defaults: &InternalConfig) -> InternalConfig {
+        let defaults = defaults.clone();
+        InternalConfig {
+            sub_domain: self.sub_domain.or(defaults.sub_domain),
+            secret_key: self.secret_key.or(defaults.secret_key),
+            portal_host: self.portal_host.or(defaults.portal_host),
+            portal_port: self.portal_port.or(defaults.portal_port),
+            portal_tls: self.portal_tls.or(defaults.portal_tls),
+            transport: self.transport.or(defaults.transport),
+            portal_quic_port: self.portal_quic_port.or(defaults.portal_quic_port),
+            portal_path: self.portal_path.or(defaults.portal_path),
+            proxy: self.proxy.or(defaults.proxy),
+            protocol: self.protocol.or(defaults.protocol),
+            remote_port: self.remote_port.or(defaults.remote_port),
+            queue_offline: self.queue_offline.or(defaults.queue_offline),
+            cache: self.cache.or(defaults.cache),
+            mirror_of: self.mirror_of.or(defaults.mirror_of),
+            access_log: self.access_log.or(defaults.access_log),
+            cors: self.cors.or(defaults.cors),
+            cors_origins: self.cors_origins.or(defaults.cors_origins),
+            cors_credentials: self.cors_credentials.or(defaults.cors_credentials),
+            https_redirect: self.https_redirect.or(defaults.https_redirect),
+            hsts: self.hsts.or(defaults.hsts),
+            ttl: self.ttl.or(defaults.ttl),
+            region: self.region.or(defaults.region),
+            instance: self.instance.or(defaults.instance),
+            har: self.har.or(defaults.har),
+            har_max_body: self.har_max_body.or(defaults.har_max_body),
+            har_redact: self.har_redact.or(defaults.har_redact),
+            local_host: self.local_host.or(defaults.local_host),
+            local_port: self.local_port.or(defaults.local_port),
+            local_tls: self.local_tls.or(defaults.local_tls),
+            local_tls_insecure: self.local_tls_insecure.or(defaults.local_tls_insecure),
+            local_tls_pin: self.local_tls_pin.or(defaults.local_tls_pin),
+            local_tls_server_name: self
+                .local_tls_server_name
+                .or(defaults.local_tls_server_name),
+            host_header: self.host_header.or(defaults.host_header),
+            path_prefix: self.path_prefix.or(defaults.path_prefix),
+            routes: self.routes.or(defaults.routes),
+            basic_auth: self.basic_auth.or(defaults.basic_auth),
+            add_headers: self.add_headers.or(defaults.add_headers),
+            serve_dir: self.serve_dir.or(defaults.serve_dir),
+            dashboard_port: self.dashboard_port.or(defaults.dashboard_port),
+            verbose: self.verbose.or(defaults.verbose),
+            tail: self.tail.or(defaults.tail),
+            alert_p95_latency: self.alert_p95_latency.or(defaults.alert_p95_latency),
+            alert_error_rate: self.alert_error_rate.or(defaults.alert_error_rate),
+            tunnels: BTreeMap::new(),
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, String> {
+        let local_host = self.local_host.as_deref().unwrap_or(DEFAULT_HOST);
+        let local_port = self.local_port.unwrap_or(8000);
+        (local_host, local_port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .ok_or_else(|| format!("cannot resolve {}:{}", local_host, local_port))
+    }
+
+    fn local_tls_verify(&self) -> Result<TlsVerify, String> {
+        TlsVerify::new(
+            self.local_tls_insecure.unwrap_or(false),
+            self.local_tls_pin.as_deref(),
+        )
+    }
+
+    fn host_header(&self) -> Result<HostHeader, String> {
+        self.host_header
+            .as_deref()
+            .map_or(Ok(HostHeader::Preserve), str::parse)
+    }
+
+    fn path_prefix(&self) -> Result<Option<String>, String> {
+        self.path_prefix
+            .as_deref()
+            .map(parse_path_prefix)
+            .transpose()
+    }
+
+    fn basic_auth(&self) -> Result<Option<BasicAuth>, String> {
+        self.basic_auth.as_deref().map(str::parse).transpose()
+    }
+
+    fn proxy(&self) -> Result<Option<Proxy>, String> {
+        let proxy = self.proxy.as_deref().map(str::parse).transpose()?;
+        if proxy.is_some() && self.transport == Some(Transport::Quic) {
+            return Err("QUIC can't go through a proxy, use the websocket transport".into());
+        }
+        Ok(proxy)
+    }
+
+    fn service(&self) -> Result<ServiceInfo, String> {
+        let protocol = self.protocol.unwrap_or_default();
+        if self.remote_port.is_some() && !matches!(protocol, Protocol::Tcp | Protocol::Udp) {
+            return Err(format!(
+                "remote_port only applies to tcp and udp tunnels, not {}",
+                protocol
+            ));
+        }
+        let routes = self
+            .routes
+            .iter()
+            .flatten()
+            .map(|route| parse_route(route))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !routes.is_empty() && !protocol.is_http() {
+            return Err(format!(
+                "routes only apply to http tunnels, not {}",
+                protocol
+            ));
+        }
+        Ok(ServiceInfo {
+            protocol,
+            port: self.remote_port,
+            routes,
+        })
+    }
+
+    fn cors_options(&self) -> Option<CorsOptions> {
+        let enabled = self.cors.unwrap_or(self.cors_origins.is_some());
+        enabled.then(|| CorsOptions {
+            origins: self.cors_origins.clone().unwrap_or_default(),
+            credentials: self.cors_credentials.unwrap_or(false),
+        })
+    }
+
+    fn add_headers(&self) -> Result<Vec<AddHeader>, String> {
+        self.add_headers
+            .iter()
+            .flatten()
+            .map(|header| header.parse())
+            .collect()
+    }
+
+    /// Find the settings `Config::from` can't make sense of
+    pub(crate) fn check(&self) -> Result<(), String> {
+        self.local_addr()?;
+        self.local_tls_verify()?;
+        self.host_header()?;
+        self.path_prefix()?;
+        self.basic_auth()?;
+        self.add_headers()?;
+        self.proxy()?;
+        let service = self.service()?;
+        if self.queue_offline == Some(true) && !service.protocol.is_http() {
+            return Err(format!(
+                "queue_offline only applies to http tunnels, not {}",
+                service.protocol
+            ));
+        }
+        if self.cache == Some(true) && !service.protocol.is_http() {
+            return Err(format!(
+                "cache only applies to http tunnels, not {}",
+                service.protocol
+            ));
+        }
+        if self.mirror_of.is_some() && !service.protocol.is_http() {
+            return Err(format!(
+                "mirror_of only applies to http tunnels, not {}",
+                service.protocol
+            ));
+        }
+        if self.har.is_some() && !service.protocol.is_http() {
+            return Err(format!(
+                "har only applies to http tunnels, not {}",
+                service.protocol
+            ));
+        }
+        if self.cors_options().is_some() && !service.protocol.is_http() {
+            return Err(format!(
+                "cors only applies to http tunnels, not {}",
+                service.protocol
+            ));
+        }
+        if self.https_redirect == Some(true) && !service.protocol.is_http() {
+            return Err(format!(
+                "https_redirect only applies to http tunnels, not {}",
+                service.protocol
+            ));
+        }
+        if self.hsts == Some(true) && !service.protocol.is_http() {
+            return Err(format!(
+                "hsts only applies to http tunnels, not {}",
+                service.protocol
+            ));
+        }
+        match &self.portal_path {
+            Some(path) if !path.starts_with('/') => {
+                Err(format!("portal_path {} has to start with /", path))
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 /// Config
 #[derive(Debug, Clone)]
 pub struct Config {
+    /// the tunnel's name in the config file
+    pub name: String,
     pub client_id: ClientId,
     pub portal_host: String,
     pub portal_port: u16,
     pub portal_tls: bool,
+    /// how we connect to the control server
+    pub transport: Transport,
+    /// the control server's UDP port we connect to over QUIC
+    pub quic_port: u16,
+    /// the path of the control server's websocket
+    pub portal_path: String,
+    /// what we reach the control server through
+    pub proxy: Option<Proxy>,
+    /// what visitors speak to reach us
+    pub service: ServiceInfo,
+    /// whether the server queues visitors' requests while we're offline
+    pub queue_offline: bool,
+    /// whether the server may cache our responses
+    pub cache: bool,
+    /// the sub-domain whose requests we receive copies of
+    pub mirror_of: Option<String>,
+    /// whether we ask the server to access log our visitors
+    pub access_log: bool,
+    /// the CORS headers we ask the server to add to our responses
+    pub cors: Option<CorsOptions>,
+    /// whether we ask the server to send plain http visitors to https
+    pub https_redirect: bool,
+    /// whether we ask the server to add HSTS to our responses over https
+    pub hsts: bool,
+    /// seconds we ask the server to keep our tunnel open for
+    pub ttl: Option<u64>,
+    /// the region we'd rather be served from
+    pub region: Option<String>,
+    /// the server instance we'd rather be served by
+    pub instance: Option<String>,
+    /// where and how we record our exchanges as HAR
+    pub har: Option<HarOptions>,
     pub local_tls: bool,
+    /// how we check the local https service's certificate
+    pub local_tls_verify: TlsVerify,
+    /// the name we ask the local https service for, if not `local_host`
+    pub local_tls_server_name: Option<String>,
+    /// what Host the local service sees
+    pub host_header: HostHeader,
+    /// prepended to the path of every request
+    pub path_prefix: Option<String>,
+    /// the credentials visitors need to get through
+    pub basic_auth: Option<BasicAuth>,
+    /// set on every request toward the local service
+    pub add_headers: Vec<AddHeader>,
+    /// run after the built-ins above, registered in code
+    pub middleware: Middlewares,
+    /// the directory we serve ourselves instead of forwarding to a local service
+    pub serve_dir: Option<PathBuf>,
     pub local_host: String,
     pub local_port: u16,
     pub local_addr: SocketAddr,
@@ -45,6 +377,8 @@ pub struct Config {
     pub secret_key: Option<SecretKey>,
     pub dashboard_port: u16,
     pub verbose: bool,
+    pub tail: bool,
+    pub alerts: AlertThresholds,
 }
 
 impl From<&mut InternalConfig> for Config {
@@ -54,12 +388,31 @@ impl From<&mut InternalConfig> for Config {
             .clone()
             .unwrap_or(DEFAULT_HOST.to_string());
         let local_port = config.local_port.unwrap_or(8000);
-        let local_addr = (local_host.as_str(), local_port)
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap();
+        let local_addr = config
+            .local_addr()
+            .expect("config files are checked when loaded");
         let local_tls = config.local_tls.unwrap_or(false);
+        let local_tls_verify = config
+            .local_tls_verify()
+            .expect("config files are checked when loaded");
+        let host_header = config
+            .host_header()
+            .expect("config files are checked when loaded");
+        let path_prefix = config
+            .path_prefix()
+            .expect("config files are checked when loaded");
+        let basic_auth = config
+            .basic_auth()
+            .expect("config files are checked when loaded");
+        let add_headers = config
+            .add_headers()
+            .expect("config files are checked when loaded");
+        let proxy = config
+            .proxy()
+            .expect("config files are checked when loaded");
+        let service = config
+            .service()
+            .expect("config files are checked when loaded");
 
         let portal_tls = config.portal_tls.unwrap_or(false);
         let portal_schema = if portal_tls { "wss" } else { "ws" };
@@ -68,35 +421,192 @@ impl From<&mut InternalConfig> for Config {
             .take()
             .unwrap_or(DEFAULT_CONTROL_HOST.to_string());
         let portal_port = config.portal_port.unwrap_or(5000);
-        let secret_key = None.map(SecretKey);
-        let dashboard_port = config.dashboard_port.unwrap_or(0);
+        let transport = config.transport.unwrap_or_default();
+        let quic_port = config.portal_quic_port.unwrap_or(portal_port);
+        let portal_path = config
+            .portal_path
+            .take()
+            .unwrap_or(DEFAULT_CONTROL_PATH.to_string());
+        let secret_key = config.secret_key.take().or_else(saved_key).map(SecretKey);
+        let dashboard_port = config.dashboard_port.unwrap_or(DEFAULT_DASHBOARD_PORT);
         let verbose = config.verbose.unwrap_or(false);
+        let tail = config.tail.unwrap_or(false);
+        let queue_offline = config.queue_offline.unwrap_or(false);
+        let cache = config.cache.unwrap_or(false);
+        let mirror_of = config.mirror_of.take();
+        let access_log = config.access_log.unwrap_or(false);
+        let cors = config.cors_options();
+        let https_redirect = config.https_redirect.unwrap_or(false);
+        let hsts = config.hsts.unwrap_or(false);
+        let har = config.har.take().map(|path| HarOptions {
+            path,
+            max_body: config.har_max_body.unwrap_or(DEFAULT_MAX_BODY),
+            redact: config.har_redact.take().unwrap_or_default(),
+        });
+        let alerts = AlertThresholds {
+            p95_latency: config.alert_p95_latency,
+            error_rate: config.alert_error_rate,
+        };
 
         Config {
+            name: DEFAULT_TUNNEL.to_string(),
             client_id: ClientId::generate(),
             sub_domain: config.sub_domain.take(),
             local_host,
             local_port,
             local_addr,
             local_tls,
+            local_tls_verify,
+            local_tls_server_name: config.local_tls_server_name.take(),
+            serve_dir: config.serve_dir.take(),
+            host_header,
+            path_prefix,
+            basic_auth,
+            add_headers,
+            middleware: Middlewares::default(),
             portal_host,
             portal_port,
             portal_tls,
+            transport,
+            quic_port,
+            portal_path,
+            proxy,
+            service,
+            queue_offline,
+            cache,
+            mirror_of,
+            access_log,
+            cors,
+            https_redirect,
+            hsts,
+            ttl: config.ttl,
+            region: config.region.take(),
+            instance: config.instance.take(),
+            har,
             secret_key,
             dashboard_port,
             verbose,
+            tail,
+            alerts,
         }
     }
 }
 
+/// Where `set-auth` keeps the key, i.e. `~/.portal/key.token`
+fn key_file() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(SETTINGS_DIR).join(SECRET_KEY_FILE))
+}
+
+/// The key stored with `set-auth`, used when none is given
+pub fn saved_key() -> Option<String> {
+    let key = std::fs::read_to_string(key_file()?).ok()?;
+    let key = key.trim();
+    (!key.is_empty()).then(|| key.to_string())
+}
+
+/// Store the key for future runs, returning where it went
+pub fn save_key(key: &str) -> std::io::Result<PathBuf> {
+    let path = key_file()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no home directory"))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, key)?;
+    Ok(path)
+}
+
+/// Where the agent keeps its id, i.e. `~/.portal/agent.id`
+fn agent_id_file() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(SETTINGS_DIR).join(AGENT_ID_FILE))
+}
+
+/// The id this machine's agent sends the server on every handshake, so operators can
+/// tell which devices hold which keys. Made up and stored on the first run; without a
+/// home directory to keep it in, agents don't send one.
+pub fn agent_id() -> Option<String> {
+    static AGENT_ID: OnceLock<Option<String>> = OnceLock::new();
+    AGENT_ID
+        .get_or_init(|| {
+            let path = agent_id_file()?;
+            if let Ok(id) = std::fs::read_to_string(&path) {
+                let id = id.trim();
+                if !id.is_empty() {
+                    return Some(id.to_string());
+                }
+            }
+
+            let id = uuid::Uuid::new_v4().to_string();
+            let saved = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(&path, &id));
+            if let Err(error) = saved {
+                warn!(
+                    "could not keep the agent id in {}: {}",
+                    path.display(),
+                    error
+                );
+            }
+            Some(id)
+        })
+        .clone()
+}
+
+/// Where `start` looks for tunnels without `--config`, i.e. `~/.portal/config.toml`
+pub fn default_config_file() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(SETTINGS_DIR).join(CONFIG_FILE))
+}
+
+/// Where the service logs unless `--log-file` says otherwise, i.e. `~/.portal/logs/portal.log`
+pub fn default_log_file() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(SETTINGS_DIR).join(LOGS_DIR).join(LOG_FILE))
+}
+
 impl Config {
+    /// Load the tunnels of a config file with these names, or all of them if none
+    /// are given. A file without `[tunnels]` describes a single one.
+    pub fn load_tunnels(path: &Path, names: &[String]) -> Result<Vec<Config>, Box<dyn Error>> {
+        let config = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        let mut config: InternalConfig = toml::from_str(&config)?;
+        if config.verbose.unwrap_or(false) || get_cli().verbose {
+            std::env::set_var("RUST_LOG", "portal=debug");
+        }
+        logs::init();
+
+        let mut tunnels = std::mem::take(&mut config.tunnels);
+        if tunnels.is_empty() {
+            tunnels.insert(DEFAULT_TUNNEL.to_string(), InternalConfig::default());
+        }
+
+        if let Some(unknown) = names.iter().find(|name| !tunnels.contains_key(*name)) {
+            return Err(format!("no tunnel named {} in {}", unknown, path.display()).into());
+        }
+
+        tunnels
+            .into_iter()
+            .filter(|(name, _)| names.is_empty() || names.contains(name))
+            .map(|(name, tunnel)| {
+                let mut tunnel = tunnel.or(&config);
+                tunnel
+                    .check()
+                    .map_err(|e| format!("tunnel {}: {}", name, e))?;
+                Ok(Config {
+                    name,
+                    ..Config::from(&mut tunnel)
+                })
+            })
+            .collect()
+    }
+
     pub fn load_from_file(path: &str) -> Result<Config, Box<dyn Error>> {
         let config = std::fs::read_to_string(path)?;
         let mut config: InternalConfig = toml::from_str(&config)?;
         if config.verbose.unwrap_or(false) {
             std::env::set_var("RUST_LOG", "portal=debug");
         }
-        pretty_env_logger::init();
+        logs::init();
+        config.check()?;
         Ok(Config::from(&mut config))
     }
 
@@ -106,10 +616,18 @@ impl Config {
             std::env::set_var("RUST_LOG", "portal=debug");
         }
 
-        pretty_env_logger::init();
+        logs::init();
 
-        let secret_key: Option<String> = None;
+        let secret_key = cli.key.clone().or_else(saved_key);
         let sub_domain = cli.sub_domain.clone();
+        let local_tls_verify =
+            TlsVerify::new(cli.insecure, cli.pin_cert.as_deref()).map_err(|e| error!("{}", e))?;
+        let path_prefix = cli
+            .path_prefix
+            .as_deref()
+            .map(parse_path_prefix)
+            .transpose()
+            .map_err(|e| error!("{}", e))?;
 
         let local_addr = (cli.local_host.as_str(), cli.port)
             .to_socket_addrs()
@@ -133,20 +651,124 @@ impl Config {
         let tls_off = env::var(TLS_OFF_ENV).is_ok();
         let portal_host = env::var(HOST_ENV).unwrap_or(DEFAULT_CONTROL_HOST.to_string());
         let portal_port = env::var(PORT_ENV).unwrap_or(DEFAULT_CONTROL_PORT.to_string());
+        let portal_port: u16 = portal_port.parse().unwrap();
+        let quic_port = env::var(QUIC_PORT_ENV)
+            .map(|port| port.parse().unwrap())
+            .unwrap_or(portal_port);
+        let portal_path = env::var(PATH_ENV).unwrap_or(DEFAULT_CONTROL_PATH.to_string());
+        let proxy = match cli.proxy.clone() {
+            Some(proxy) => Some(proxy),
+            None => env::var(PROXY_ENV)
+                .ok()
+                .map(|proxy| proxy.parse())
+                .transpose()
+                .map_err(|e: String| error!("{}", e))?,
+        };
+        if proxy.is_some() && cli.transport == Transport::Quic {
+            error!("QUIC can't go through a proxy, use the websocket transport");
+            return Err(());
+        }
+
+        if cli.remote_port.is_some() && !matches!(cli.protocol, Protocol::Tcp | Protocol::Udp) {
+            error!("--remote-port only applies to tcp and udp tunnels");
+            return Err(());
+        }
+
+        if !cli.routes.is_empty() && !cli.protocol.is_http() {
+            error!("--route only applies to http tunnels");
+            return Err(());
+        }
+
+        if cli.queue_offline && !cli.protocol.is_http() {
+            error!("--queue-offline only applies to http tunnels");
+            return Err(());
+        }
+
+        if cli.cache && !cli.protocol.is_http() {
+            error!("--cache only applies to http tunnels");
+            return Err(());
+        }
+
+        if cli.mirror_of.is_some() && !cli.protocol.is_http() {
+            error!("--mirror-of only applies to http tunnels");
+            return Err(());
+        }
+
+        if cli.har.is_some() && !cli.protocol.is_http() {
+            error!("--har only applies to http tunnels");
+            return Err(());
+        }
+
+        let cors = (cli.cors || !cli.cors_origins.is_empty()).then(|| CorsOptions {
+            origins: cli.cors_origins.clone(),
+            credentials: cli.cors_credentials,
+        });
+        if cors.is_some() && !cli.protocol.is_http() {
+            error!("--cors only applies to http tunnels");
+            return Err(());
+        }
+
+        if cli.https_redirect && !cli.protocol.is_http() {
+            error!("--https-redirect only applies to http tunnels");
+            return Err(());
+        }
+
+        if cli.hsts && !cli.protocol.is_http() {
+            error!("--hsts only applies to http tunnels");
+            return Err(());
+        }
 
         info!("Control Server URL: {}", &portal_host);
 
         Ok(Config {
+            name: DEFAULT_TUNNEL.to_string(),
             client_id: ClientId::generate(),
             portal_host,
-            portal_port: portal_port.parse().unwrap(),
+            portal_port,
+            transport: cli.transport,
+            quic_port,
+            portal_path,
+            proxy,
+            service: ServiceInfo {
+                protocol: cli.protocol,
+                port: cli.remote_port,
+                routes: cli.routes.clone(),
+            },
+            queue_offline: cli.queue_offline,
+            cache: cli.cache,
+            mirror_of: cli.mirror_of.clone(),
+            access_log: cli.access_log,
+            cors,
+            https_redirect: cli.https_redirect,
+            hsts: cli.hsts,
+            ttl: cli.ttl,
+            region: cli.region.clone(),
+            instance: cli.instance.clone(),
+            har: cli.har.clone().map(|path| HarOptions {
+                path,
+                max_body: cli.har_max_body,
+                redact: cli.har_redact.clone(),
+            }),
             local_host: cli.local_host.clone(),
             local_port: cli.port,
             local_tls: cli.use_tls,
+            local_tls_verify,
+            local_tls_server_name: cli.tls_server_name.clone(),
+            serve_dir: cli.serve_dir.clone(),
+            host_header: cli.host_header.clone(),
+            path_prefix,
+            basic_auth: cli.basic_auth.clone(),
+            add_headers: cli.add_headers.clone(),
+            middleware: Middlewares::default(),
             local_addr,
             sub_domain,
-            dashboard_port: cli.dashboard_port.unwrap_or(0),
+            dashboard_port: cli.dashboard_port.unwrap_or(DEFAULT_DASHBOARD_PORT),
             verbose: cli.verbose,
+            tail: cli.tail,
+            alerts: AlertThresholds {
+                p95_latency: cli.alert_p95_latency,
+                error_rate: cli.alert_error_rate,
+            },
             secret_key: secret_key.map(SecretKey),
             portal_tls: !tls_off,
         })
@@ -160,8 +782,37 @@ impl Config {
         )
     }
 
+    /// Forward to the file server at `addr` serving our `serve_dir`
+    pub fn serving(self, addr: SocketAddr) -> Config {
+        Config {
+            local_host: addr.ip().to_string(),
+            local_port: addr.port(),
+            local_addr: addr,
+            local_tls: false,
+            ..self
+        }
+    }
+
+    /// Forward to the server a command we spawned listens on at `addr`
+    pub fn forwarding_to(self, addr: SocketAddr) -> Config {
+        Config {
+            local_port: addr.port(),
+            local_addr: addr,
+            ..self
+        }
+    }
+
     pub fn forward_url(&self) -> String {
-        let scheme = if self.local_tls { "https" } else { "http" };
+        if let Some(dir) = &self.serve_dir {
+            return format!("files in {}", dir.display());
+        }
+
+        let scheme = match (self.service.protocol.is_http(), self.local_tls) {
+            (true, false) => "http",
+            (true, true) => "https",
+            (false, false) => "tcp",
+            (false, true) => "tls",
+        };
         format!("{}://{}:{}", &scheme, &self.local_host, &self.local_port)
     }
 
@@ -170,13 +821,32 @@ impl Config {
         format!("{}://{}:{}", scheme, &self.local_host, &self.local_port)
     }
 
+    /// The same tunnel on the control server at `address`, the `host:port` another one
+    /// sent us to
+    pub(crate) fn at(&self, address: &str) -> Config {
+        let mut config = self.clone();
+        let Some((host, Ok(port))) = address
+            .rsplit_once(':')
+            .map(|(host, port)| (host, port.parse::<u16>()))
+        else {
+            return config;
+        };
+        if config.quic_port == config.portal_port {
+            config.quic_port = port;
+        }
+        config.portal_host = host.trim_matches(['[', ']']).to_string();
+        config.portal_port = port;
+        config
+    }
+
     /// Get the URL to use to connect to the wormhole control server
     pub fn portal_url(&self) -> String {
         format!(
-            "{}://{}:{}/wormhole",
+            "{}://{}:{}{}",
             self.portal_schema(),
             self.portal_host,
-            self.portal_port
+            self.portal_port,
+            self.portal_path
         )
     }
 
@@ -0,0 +1,174 @@
+//! The connection to the control server we hold our tunnel over, a websocket or,
+//! for networks we may leave, QUIC
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use portal_lib::quic::{self, QuicControl, QuicReceiver, QuicSender};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::Endpoint;
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::tls::{self, TlsVerify};
+use crate::{Config, ControlPacket, Error};
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How we connect to the control server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    WebSocket,
+    /// keeps the tunnel across changes of our address, i.e. on cellular networks
+    Quic,
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "websocket" => Ok(Transport::WebSocket),
+            "quic" => Ok(Transport::Quic),
+            _ => Err(format!(
+                "invalid transport {}, expected websocket or quic",
+                s
+            )),
+        }
+    }
+}
+
+pub(crate) struct Control {
+    pub(crate) sink: ControlSink,
+    pub(crate) stream: ControlStream,
+}
+
+pub(crate) enum ControlSink {
+    WebSocket(SplitSink<WebSocket, Message>),
+    Quic(QuicSender),
+}
+
+pub(crate) enum ControlStream {
+    WebSocket(SplitStream<WebSocket>),
+    Quic(QuicReceiver),
+}
+
+impl Control {
+    pub(crate) fn websocket(websocket: WebSocket) -> Self {
+        let (sink, stream) = websocket.split();
+        Control {
+            sink: ControlSink::WebSocket(sink),
+            stream: ControlStream::WebSocket(stream),
+        }
+    }
+
+    /// Connect to the control server's QUIC port
+    pub(crate) async fn quic(config: &Config) -> Result<Self, Error> {
+        let addr = tokio::net::lookup_host((config.portal_host.as_str(), config.quic_port))
+            .await
+            .map_err(quic_error)?
+            .next()
+            .ok_or_else(|| quic_error("the control server's host doesn't resolve"))?;
+
+        let client = quic_client_config(config.portal_tls).map_err(quic_error)?;
+
+        let bind: SocketAddr = if addr.is_ipv6() {
+            ([0u16; 8], 0).into()
+        } else {
+            ([0u8; 4], 0).into()
+        };
+        let mut endpoint = Endpoint::client(bind).map_err(quic_error)?;
+        endpoint.set_default_client_config(client);
+        let connection = endpoint
+            .connect(addr, &config.portal_host)
+            .map_err(quic_error)?
+            .await
+            .map_err(quic_error)?;
+        let (sender, receiver) = QuicControl::open(connection)
+            .await
+            .map_err(quic_error)?
+            .split();
+
+        Ok(Control {
+            sink: ControlSink::Quic(sender),
+            stream: ControlStream::Quic(receiver),
+        })
+    }
+}
+
+impl ControlSink {
+    /// Send a handshake message
+    pub(crate) async fn send_hello(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        match self {
+            ControlSink::WebSocket(sink) => Ok(sink.send(Message::binary(data)).await?),
+            ControlSink::Quic(sender) => sender.send_frame(data).await.map_err(quic_error),
+        }
+    }
+
+    pub(crate) async fn send(&mut self, packet: ControlPacket) -> Result<(), Error> {
+        match self {
+            ControlSink::WebSocket(sink) => {
+                Ok(sink.send(Message::binary(packet.serialize())).await?)
+            }
+            ControlSink::Quic(sender) => sender.send(packet).await.map_err(quic_error),
+        }
+    }
+}
+
+impl ControlStream {
+    /// The next message, `None` once the server closed the tunnel
+    pub(crate) async fn next(&mut self) -> Result<Option<Bytes>, Error> {
+        match self {
+            ControlStream::WebSocket(stream) => match stream.next().await {
+                Some(Ok(message)) if message.is_close() => Ok(None),
+                Some(Ok(message)) => Ok(Some(Bytes::from(message.into_data()))),
+                Some(Err(e)) => Err(e.into()),
+                None => Err(Error::NoResponseFromServer),
+            },
+            ControlStream::Quic(receiver) => match receiver.next().await {
+                Some(Ok(frame)) => Ok(Some(frame)),
+                Some(Err(e)) => Err(quic_error(e)),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
+/// The QUIC client config for reaching the control server, `portal_tls` or not
+fn quic_client_config(portal_tls: bool) -> Result<quinn::ClientConfig, String> {
+    // QUIC always encrypts, without TLS toward the server we take its word for who it is
+    let verify = if portal_tls {
+        TlsVerify::Roots
+    } else {
+        TlsVerify::Insecure
+    };
+    let mut tls = tls::client_config(&verify);
+    tls.alpn_protocols = vec![quic::ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(tls).map_err(|e| e.to_string())?;
+    let mut client = quinn::ClientConfig::new(Arc::new(crypto));
+    client.transport_config(quic::transport());
+    Ok(client)
+}
+
+fn quic_error(e: impl std::fmt::Display) -> Error {
+    Error::QuicError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quic_client_config() {
+        for portal_tls in [true, false] {
+            assert!(quic_client_config(portal_tls).is_ok());
+        }
+    }
+}
@@ -5,6 +5,12 @@ pub enum Error {
     #[error("Failed to connect to control server: {0}.")]
     WebSocketError(#[from] tokio_tungstenite::tungstenite::error::Error),
 
+    #[error("Failed to connect to control server over QUIC: {0}.")]
+    QuicError(String),
+
+    #[error("Failed to connect to control server through the proxy: {0}.")]
+    ProxyError(#[from] std::io::Error),
+
     #[error("Server denied the connection.")]
     AuthenticationFailed,
 
@@ -14,8 +20,11 @@ pub enum Error {
     #[error("Invalid sub-domain specified.")]
     InvalidSubDomain,
 
-    #[error("Cannot use this sub-domain, it is already taken.")]
-    SubDomainInUse,
+    #[error("Cannot use this sub-domain, it is already taken.{}", suggest(.suggestions))]
+    SubDomainInUse {
+        /// sub-domains the server suggests instead
+        suggestions: Vec<String>,
+    },
 
     #[error("{0}")]
     ServerError(String),
@@ -28,4 +37,35 @@ pub enum Error {
 
     #[error("The server timed out sending us something.")]
     Timeout,
+
+    #[error("The server sent us to instance {0}.")]
+    Redirected(String),
+
+    #[error("The server sent us to the control server at {0}.")]
+    RedirectedTo(String),
+
+    #[error("Invalid tunnel settings: {0}.")]
+    InvalidConfig(String),
+
+    #[error("Could not open the tunnel: {0}.")]
+    CouldNotConnect(String),
+
+    #[error("The tunnel reached the end of its lifetime and was closed.")]
+    Expired,
+
+    #[error("The server closed the tunnel: {0}.")]
+    Disconnected(String),
+
+    #[error("This version is too old for the server, which needs {minimum} or newer: download it from {download_url}")]
+    VersionTooOld {
+        minimum: String,
+        download_url: String,
+    },
+}
+
+fn suggest(suggestions: &[String]) -> String {
+    match suggestions {
+        [] => String::new(),
+        _ => format!(" Try one of: {}.", suggestions.join(", ")),
+    }
 }
@@ -0,0 +1,148 @@
+use std::path::{Component, Path, PathBuf};
+
+use warp::http::{header, Response, StatusCode};
+use warp::path::FullPath;
+use warp::Filter;
+
+use super::*;
+
+/// Serve the files under `dir` on an ephemeral localhost port for the tunnel to
+/// forward to, listing the directories without an `index.html`
+pub fn serve(dir: PathBuf) -> Result<SocketAddr, String> {
+    if !dir.is_dir() {
+        return Err(format!("{} is not a directory", dir.display()));
+    }
+
+    let root = dir.clone();
+    let listing = warp::get()
+        .and(warp::path::full())
+        .and_then(move |path: FullPath| list_dir(root.clone(), path));
+    let files = listing.or(warp::fs::dir(dir));
+
+    let (addr, server) = warp::serve(files)
+        .try_bind_ephemeral(SocketAddr::from(([127, 0, 0, 1], 0)))
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(server);
+    Ok(addr)
+}
+
+/// The directory under `root` a request path points to, if it doesn't leave `root`
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(path)?;
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(resolved)
+}
+
+async fn list_dir(
+    root: PathBuf,
+    path: FullPath,
+) -> Result<Response<String>, warp::reject::Rejection> {
+    let dir = match resolve(&root, path.as_str()) {
+        Some(dir) if dir.is_dir() && !dir.join("index.html").is_file() => dir,
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    // relative links need the trailing slash
+    if !path.as_str().ends_with('/') {
+        return Ok(Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(header::LOCATION, format!("{}/", path.as_str()))
+            .body(String::new())
+            .unwrap());
+    }
+
+    let mut entries: Vec<(String, bool)> = std::fs::read_dir(&dir)
+        .map_err(|_| warp::reject::not_found())?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            (entry.file_name().to_string_lossy().into_owned(), is_dir)
+        })
+        .collect();
+    entries.sort_by(|(a, a_dir), (b, b_dir)| b_dir.cmp(a_dir).then(a.cmp(b)));
+
+    let title = html_escape(&percent_decode(path.as_str()).unwrap_or_default());
+    let mut body = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n<ul>\n",
+        title = title
+    );
+    if path.as_str() != "/" {
+        body.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        body.push_str(&format!(
+            "<li><a href=\"{href}{slash}\">{name}{slash}</a></li>\n",
+            href = percent_encode(&name),
+            name = html_escape(&name),
+            slash = slash
+        ));
+    }
+    body.push_str("</ul>\n</body>\n</html>\n");
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(body)
+        .unwrap())
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let root = Path::new("/srv/site");
+        assert_eq!(
+            resolve(root, "/docs/./a%20b/"),
+            Some(PathBuf::from("/srv/site/docs/a b"))
+        );
+        assert_eq!(resolve(root, "/"), Some(root.to_path_buf()));
+        assert_eq!(resolve(root, "/../etc"), None);
+        assert_eq!(resolve(root, "/docs/%2E%2E/%2E%2E/etc"), None);
+        assert_eq!(
+            resolve(root, "/%2Fetc"),
+            Some(PathBuf::from("/srv/site/etc"))
+        );
+        assert_eq!(resolve(root, "/%zz"), None);
+    }
+}
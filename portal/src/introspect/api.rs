@@ -0,0 +1,264 @@
+//! Our tunnels as ngrok's local API lists them on `/api/tunnels`, so tooling
+//! written against ngrok, i.e. test frameworks looking up the public url, finds
+//! ours without changes
+use portal_lib::TunnelStats;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// The longest window ngrok reports a rate over
+const WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// How many recent durations we keep for the percentiles
+const MAX_SAMPLES: usize = 1000;
+
+static TUNNELS: OnceLock<Mutex<BTreeMap<String, TunnelEntry>>> = OnceLock::new();
+
+fn tunnels() -> &'static Mutex<BTreeMap<String, TunnelEntry>> {
+    TUNNELS.get_or_init(Default::default)
+}
+
+struct TunnelEntry {
+    public_url: String,
+    addr: String,
+    conns: Metrics,
+    http: Metrics,
+    /// what the server last told us about the tunnel's traffic
+    server: Option<ServerTraffic>,
+}
+
+/// The server's counts of a tunnel's traffic, with the throughput since the count
+/// before
+#[derive(Clone, Copy, Serialize)]
+struct ServerTraffic {
+    #[serde(flatten)]
+    stats: TunnelStats,
+    /// bytes per second
+    rate_in: f64,
+    rate_out: f64,
+    #[serde(skip)]
+    at: Instant,
+}
+
+/// Counts and recent durations of connections or requests
+#[derive(Default)]
+struct Metrics {
+    count: u64,
+    gauge: i64,
+    /// when each recent one finished, and how long it took
+    recent: VecDeque<(Instant, Duration)>,
+}
+
+impl Metrics {
+    fn record(&mut self, duration: Duration) {
+        let now = Instant::now();
+        self.count += 1;
+        self.recent.push_back((now, duration));
+        while self.recent.len() > MAX_SAMPLES
+            || self
+                .recent
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > WINDOW)
+        {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Per second over the last `window`
+    fn rate(&self, now: Instant, window: Duration) -> f64 {
+        let within = self
+            .recent
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) <= window)
+            .count();
+        within as f64 / window.as_secs_f64()
+    }
+
+    fn report(&self, gauge: bool) -> MetricsReport {
+        let now = Instant::now();
+        let mut durations: Vec<Duration> = self.recent.iter().map(|(_, d)| *d).collect();
+        durations.sort();
+        let percentile = |p: usize| match durations.len() {
+            0 => 0.0,
+            len => durations[(len - 1) * p / 100].as_nanos() as f64,
+        };
+        MetricsReport {
+            count: self.count,
+            gauge: gauge.then_some(self.gauge),
+            rate1: self.rate(now, Duration::from_secs(60)),
+            rate5: self.rate(now, Duration::from_secs(5 * 60)),
+            rate15: self.rate(now, WINDOW),
+            p50: percentile(50),
+            p90: percentile(90),
+            p95: percentile(95),
+            p99: percentile(99),
+        }
+    }
+}
+
+/// The tunnel `name` is open on `public_url`, forwarding to `addr`
+pub fn connected(name: &str, public_url: &str, addr: &str) {
+    let mut tunnels = tunnels().lock().unwrap();
+    let entry = tunnels
+        .entry(name.to_string())
+        .or_insert_with(|| TunnelEntry {
+            public_url: String::new(),
+            addr: String::new(),
+            conns: Metrics::default(),
+            http: Metrics::default(),
+            server: None,
+        });
+    entry.public_url = public_url.to_string();
+    entry.addr = addr.to_string();
+}
+
+/// The tunnel `name` closed for good
+pub fn closed(name: &str) {
+    tunnels().lock().unwrap().remove(name);
+}
+
+/// A visitor's connection came through the tunnel
+pub(super) fn stream_opened(tunnel: &str) {
+    if let Some(entry) = tunnels().lock().unwrap().get_mut(tunnel) {
+        entry.conns.gauge += 1;
+    }
+}
+
+/// A visitor's connection closed, after a request we saw answered if `answered`
+pub(super) fn stream_closed(tunnel: &str, duration: Duration, answered: bool) {
+    if let Some(entry) = tunnels().lock().unwrap().get_mut(tunnel) {
+        entry.conns.gauge -= 1;
+        entry.conns.record(duration);
+        if answered {
+            entry.http.record(duration);
+        }
+    }
+}
+
+/// The server counted `stats` of the tunnel's traffic so far
+pub fn server_stats(tunnel: &str, stats: TunnelStats) {
+    let mut tunnels = tunnels().lock().unwrap();
+    let Some(entry) = tunnels.get_mut(tunnel) else {
+        return;
+    };
+    let now = Instant::now();
+    let (rate_in, rate_out) = match entry.server {
+        // the counts start over when the tunnel moves to another server
+        Some(last)
+            if stats.bytes_in >= last.stats.bytes_in && stats.bytes_out >= last.stats.bytes_out =>
+        {
+            let elapsed = now.duration_since(last.at).as_secs_f64().max(f64::EPSILON);
+            (
+                (stats.bytes_in - last.stats.bytes_in) as f64 / elapsed,
+                (stats.bytes_out - last.stats.bytes_out) as f64 / elapsed,
+            )
+        }
+        _ => (0.0, 0.0),
+    };
+    entry.server = Some(ServerTraffic {
+        stats,
+        rate_in,
+        rate_out,
+        at: now,
+    });
+}
+
+#[derive(Serialize)]
+pub(super) struct TunnelList {
+    tunnels: Vec<TunnelReport>,
+    uri: &'static str,
+}
+
+#[derive(Serialize)]
+pub(super) struct TunnelReport {
+    name: String,
+    uri: String,
+    public_url: String,
+    proto: String,
+    config: TunnelConfig,
+    metrics: TunnelMetrics,
+}
+
+#[derive(Serialize)]
+struct TunnelConfig {
+    addr: String,
+    inspect: bool,
+}
+
+#[derive(Serialize)]
+struct TunnelMetrics {
+    conns: MetricsReport,
+    http: MetricsReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<ServerTraffic>,
+}
+
+#[derive(Serialize)]
+struct MetricsReport {
+    count: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gauge: Option<i64>,
+    rate1: f64,
+    rate5: f64,
+    rate15: f64,
+    p50: f64,
+    p90: f64,
+    p95: f64,
+    p99: f64,
+}
+
+/// The error body ngrok answers with
+#[derive(Serialize)]
+pub(super) struct ApiError {
+    error_code: u16,
+    status_code: u16,
+    msg: String,
+    details: BTreeMap<String, String>,
+}
+
+fn report(name: &str, entry: &TunnelEntry) -> TunnelReport {
+    let proto = entry
+        .public_url
+        .split_once("://")
+        .map_or("http", |(scheme, _)| scheme);
+    TunnelReport {
+        name: name.to_string(),
+        uri: format!("/api/tunnels/{}", name),
+        public_url: entry.public_url.clone(),
+        proto: proto.to_string(),
+        config: TunnelConfig {
+            addr: entry.addr.clone(),
+            inspect: true,
+        },
+        metrics: TunnelMetrics {
+            conns: entry.conns.report(true),
+            http: entry.http.report(false),
+            server: entry.server,
+        },
+    }
+}
+
+pub(super) fn list() -> TunnelList {
+    let tunnels = tunnels().lock().unwrap();
+    TunnelList {
+        tunnels: tunnels
+            .iter()
+            .map(|(name, entry)| report(name, entry))
+            .collect(),
+        uri: "/api/tunnels",
+    }
+}
+
+pub(super) fn get(name: &str) -> Result<TunnelReport, ApiError> {
+    let tunnels = tunnels().lock().unwrap();
+    tunnels
+        .get(name)
+        .map(|entry| report(name, entry))
+        .ok_or_else(|| ApiError {
+            error_code: 100,
+            status_code: 404,
+            msg: "tunnel not found".to_string(),
+            details: BTreeMap::from([("err".to_string(), format!("tunnel {} not found", name))]),
+        })
+}
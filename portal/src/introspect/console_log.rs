@@ -1,3 +1,18 @@
+use portal_lib::RequestLogEntry;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether the server reports our requests, which is more accurate than
+/// what we see locally (i.e. every request on a keep-alive connection)
+static SERVER_LOG: AtomicBool = AtomicBool::new(false);
+
+pub fn set_server_log(enabled: bool) {
+    SERVER_LOG.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_server_log() -> bool {
+    SERVER_LOG.load(Ordering::Relaxed)
+}
+
 pub fn connect_failed() {
     bunt::eprintln!("{$red}CONNECTION REFUSED{/$}");
 }
@@ -15,3 +30,24 @@ pub fn log(request: &httparse::Request, response: &httparse::Response) {
     eprint!("{}", out);
     bunt::eprintln!("\t\t{[yellow]}\t{[blue]}", method.to_uppercase(), path);
 }
+
+/// Print a request reported by the server
+pub fn log_entry(entry: &RequestLogEntry) {
+    let out = match entry.status {
+        code @ 200..=399 => format!("\x1b[32m{}\x1b[0m", code),
+        0 => "\x1b[31m???\x1b[0m".to_string(),
+        code => format!("\x1b[31m{}\x1b[0m", code),
+    };
+
+    eprint!("{}", out);
+    bunt::eprintln!(
+        "\t\t{[yellow]}\t{[blue]}\t{[dimmed]}",
+        entry.method.to_uppercase(),
+        entry.path,
+        if entry.replay {
+            format!("{}ms (replay)", entry.duration)
+        } else {
+            format!("{}ms", entry.duration)
+        }
+    );
+}
@@ -0,0 +1,302 @@
+//! Record the exchanges of a tunnel to a HAR file, i.e. to open in a browser's devtools.
+//!
+//! The file is a complete HAR document after every exchange: each one is written over
+//! the closing brackets, which are then written again after it.
+use super::Request;
+use crate::warn;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Body bytes of each request and response recorded by default
+pub const DEFAULT_MAX_BODY: usize = 64 * 1024;
+
+/// Headers whose values never make it into a HAR file
+const REDACTED: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+const CLOSING: &[u8] = b"\n]}}\n";
+
+/// Where and how a tunnel's exchanges are recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HarOptions {
+    pub path: PathBuf,
+    /// body bytes kept of each request and response, the rest is cut off
+    pub max_body: usize,
+    /// headers to redact on top of the credentials and cookies we always do
+    pub redact: Vec<String>,
+}
+
+impl HarOptions {
+    fn is_redacted(&self, name: &str) -> bool {
+        REDACTED
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(name))
+            || self
+                .redact
+                .iter()
+                .any(|header| header.eq_ignore_ascii_case(name))
+    }
+}
+
+/// An open HAR file and whether it has entries yet
+struct HarFile {
+    file: File,
+    empty: bool,
+}
+
+impl HarFile {
+    fn create(path: &Path) -> std::io::Result<Self> {
+        let mut file = File::create(path)?;
+        let creator = json!({ "name": "portal", "version": env!("CARGO_PKG_VERSION") });
+        write!(
+            file,
+            "{{\"log\":{{\"version\":\"1.2\",\"creator\":{},\"entries\":[",
+            creator
+        )?;
+        file.write_all(CLOSING)?;
+        Ok(HarFile { file, empty: true })
+    }
+
+    fn append(&mut self, entry: &Value) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::End(-(CLOSING.len() as i64)))?;
+        if !self.empty {
+            self.file.write_all(b",")?;
+        }
+        self.file.write_all(b"\n")?;
+        serde_json::to_writer(&mut self.file, entry)?;
+        self.file.write_all(CLOSING)?;
+        self.file.flush()?;
+        self.empty = false;
+        Ok(())
+    }
+}
+
+/// The HAR files being written, by path, shared by the tunnels recording to the same one
+static FILES: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<HarFile>>>>> = OnceLock::new();
+
+fn open(path: &Path) -> std::io::Result<Arc<Mutex<HarFile>>> {
+    let mut files = FILES.get_or_init(Default::default).lock().unwrap();
+    if let Some(file) = files.get(path) {
+        return Ok(file.clone());
+    }
+    let file = Arc::new(Mutex::new(HarFile::create(path)?));
+    files.insert(path.to_path_buf(), file.clone());
+    Ok(file)
+}
+
+/// Add an exchange to the HAR file
+pub fn record(options: &HarOptions, request: &Request) {
+    let result =
+        open(&options.path).and_then(|file| file.lock().unwrap().append(&entry(options, request)));
+    if let Err(error) = result {
+        warn!(
+            "failed to record to the HAR file {}: {}",
+            options.path.display(),
+            error
+        );
+    }
+}
+
+fn headers(options: &HarOptions, headers: &[(String, String)]) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if options.is_redacted(name) {
+                "[redacted]"
+            } else {
+                value.as_str()
+            };
+            json!({ "name": name, "value": value })
+        })
+        .collect()
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// The body of a chunked message without its framing, or `None` if it isn't well formed
+fn dechunk(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let line = data.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&data[..line]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        data = &data[line + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(data.get(..size)?);
+        data = data.get(size + 2..)?;
+    }
+}
+
+/// A body as HAR has it: text if it's utf8, base64 otherwise, cut off after the cap
+fn content(options: &HarOptions, headers: &[(String, String)], data: &[u8]) -> Value {
+    let chunked = header(headers, "transfer-encoding")
+        .is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"));
+    let body = match chunked.then(|| dechunk(data)).flatten() {
+        Some(body) => body,
+        None => data.to_vec(),
+    };
+    let kept = &body[..body.len().min(options.max_body)];
+
+    let mut content = json!({
+        "size": body.len(),
+        "mimeType": header(headers, "content-type").unwrap_or_default(),
+    });
+    match std::str::from_utf8(kept) {
+        Ok(text) => content["text"] = json!(text),
+        Err(_) => {
+            content["text"] = json!(base64::engine::general_purpose::STANDARD.encode(kept));
+            content["encoding"] = json!("base64");
+        }
+    }
+    if kept.len() < body.len() {
+        content["comment"] = json!(format!("cut off after {} bytes", kept.len()));
+    }
+    content
+}
+
+fn entry(options: &HarOptions, request: &Request) -> Value {
+    let path = request.path.as_deref().unwrap_or("/");
+    let scheme = header(&request.headers, "x-forwarded-proto").unwrap_or("http");
+    let host = header(&request.headers, "host").unwrap_or("localhost");
+    let query: Vec<Value> = path
+        .split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            json!({ "name": name, "value": value })
+        })
+        .collect();
+    let started = request
+        .started
+        .and_local_timezone(chrono::Local)
+        .single()
+        .map(|started| started.to_rfc3339())
+        .unwrap_or_default();
+    let time = (request.completed - request.started).num_milliseconds();
+
+    let mut har_request = json!({
+        "method": request.method.as_deref().unwrap_or_default(),
+        "url": format!("{}://{}{}", scheme, host, path),
+        "httpVersion": "HTTP/1.1",
+        "cookies": [],
+        "headers": headers(options, &request.headers),
+        "queryString": query,
+        "headersSize": -1,
+        "bodySize": request.body_data.len(),
+    });
+    if !request.body_data.is_empty() {
+        let body = content(options, &request.headers, &request.body_data);
+        har_request["postData"] = json!({
+            "mimeType": body["mimeType"],
+            "text": body["text"],
+        });
+    }
+
+    json!({
+        "startedDateTime": started,
+        "time": time,
+        "request": har_request,
+        "response": {
+            "status": request.status,
+            "statusText": "",
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": headers(options, &request.response_headers),
+            "content": content(options, &request.response_headers, &request.response_data),
+            "redirectURL": header(&request.response_headers, "location").unwrap_or_default(),
+            "headersSize": -1,
+            "bodySize": request.response_data.len(),
+        },
+        "cache": {},
+        "timings": { "send": 0, "wait": time, "receive": 0 },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str) -> Request {
+        let now = chrono::Local::now().naive_local();
+        Request {
+            id: "1".to_string(),
+            tunnel: "app".to_string(),
+            status: 200,
+            is_replay: false,
+            path: Some(path.to_string()),
+            method: Some("POST".to_string()),
+            headers: vec![
+                ("Host".to_string(), "app.example.com".to_string()),
+                ("Authorization".to_string(), "Bearer secret".to_string()),
+                ("X-Api-Key".to_string(), "key".to_string()),
+            ],
+            body_data: b"name=portal".to_vec(),
+            response_headers: vec![
+                ("Content-Type".to_string(), "text/plain".to_string()),
+                ("Transfer-Encoding".to_string(), "chunked".to_string()),
+            ],
+            response_data: b"5\r\nhello\r\n0\r\n\r\n".to_vec(),
+            started: now,
+            completed: now,
+            entire_request: vec![],
+        }
+    }
+
+    #[test]
+    fn test_records_complete_documents() {
+        let path = std::env::temp_dir().join(format!("portal-{}.har", uuid::Uuid::new_v4()));
+        let options = HarOptions {
+            path: path.clone(),
+            max_body: 4,
+            redact: vec!["x-api-key".to_string()],
+        };
+
+        record(&options, &request("/a?b=c&d"));
+        let har: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let entry = &har["log"]["entries"][0];
+        assert_eq!(entry["request"]["url"], "http://app.example.com/a?b=c&d");
+        assert_eq!(
+            entry["request"]["queryString"],
+            json!([{ "name": "b", "value": "c" }, { "name": "d", "value": "" }])
+        );
+        assert_eq!(entry["request"]["headers"][1]["value"], "[redacted]");
+        assert_eq!(entry["request"]["headers"][2]["value"], "[redacted]");
+        assert_eq!(entry["request"]["postData"]["text"], "name");
+        assert_eq!(entry["response"]["content"]["text"], "hell");
+        assert_eq!(entry["response"]["content"]["size"], 5);
+
+        record(&options, &request("/e"));
+        let har: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(har["log"]["entries"].as_array().unwrap().len(), 2);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_dechunk() {
+        assert_eq!(
+            dechunk(b"3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"),
+            Some(b"abcde".to_vec())
+        );
+        assert_eq!(dechunk(b"3\r\nab"), None);
+        assert_eq!(dechunk(b"zz\r\nabc\r\n"), None);
+    }
+}
@@ -1,19 +1,28 @@
+pub mod api;
 pub mod console_log;
+pub mod har;
 pub use self::console_log::*;
+mod tunnel_log;
+pub use self::tunnel_log::get_tunnel_log;
+use self::tunnel_log::Notice;
 use super::*;
 
 use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::StreamExt;
+use portal_lib::ShareRequest;
 use std::net::SocketAddr;
 use std::sync::OnceLock;
+use std::time::Duration;
 use std::vec;
 use uuid::Uuid;
-use warp::Filter;
+use warp::{Filter, Reply};
 
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct Request {
     id: String,
+    /// the name of the tunnel it came through
+    tunnel: String,
     status: u16,
     is_replay: bool,
     path: Option<String>,
@@ -38,14 +47,20 @@ impl Request {
     }
 }
 
+/// How long we wait for the server to answer a share link request
+const SHARE_TIMEOUT: Duration = Duration::from_secs(10);
+
 static REQUESTS: OnceLock<Arc<RwLock<HashMap<String, Request>>>> = OnceLock::new();
 
 pub fn get_requests() -> &'static Arc<RwLock<HashMap<String, Request>>> {
     REQUESTS.get_or_init(|| Arc::new(RwLock::new(HashMap::new())))
 }
 
-pub fn start_introspect_web_dashboard(config: Config) -> SocketAddr {
-    let dash_addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], config.dashboard_port));
+/// Serve the dashboard of these tunnels on localhost, on the first one's port or on
+/// any free port if that is taken
+pub fn start_introspect_web_dashboard(configs: Vec<Config>) -> SocketAddr {
+    let dash_addr = SocketAddr::from(([127, 0, 0, 1], configs[0].dashboard_port));
+    let configs = Arc::new(configs);
 
     let css = warp::get().and(warp::path!("static" / "css" / "styles.css").map(|| {
         let mut res = warp::http::Response::new(warp::hyper::Body::from(include_str!(
@@ -75,16 +90,50 @@ pub fn start_introspect_web_dashboard(config: Config) -> SocketAddr {
             .and(warp::path("detail"))
             .and(warp::path::param())
             .and_then(request_detail))
+        .or(warp::get()
+            .and(warp::path!("api" / "requests"))
+            .map(list_requests))
+        .or(warp::get()
+            .and(warp::path!("api" / "tunnels"))
+            .map(|| warp::reply::json(&api::list())))
+        .or(warp::get()
+            .and(warp::path!("api" / "tunnels" / String))
+            .map(|name: String| match api::get(&name) {
+                Ok(tunnel) => warp::reply::json(&tunnel).into_response(),
+                Err(error) => warp::reply::with_status(
+                    warp::reply::json(&error),
+                    warp::http::StatusCode::NOT_FOUND,
+                )
+                .into_response(),
+            }))
         .or(warp::post()
             .and(warp::path("replay"))
             .and(warp::path::param())
-            .and_then(move |id| replay_request(id, config.clone())))
+            .and_then(move |id| replay_request(id, configs.clone())))
+        .or(warp::post()
+            .and(warp::path!("tunnel" / String / "replay" / String))
+            .and_then(replay_on_server))
+        .or(warp::post()
+            .and(warp::path!("api" / "tunnels" / String / "share"))
+            .and(warp::query::<ShareRequest>())
+            .then(share_tunnel))
         .or(css)
         .or(logo);
 
-    let (web_explorer_address, explorer_server) =
-        warp::serve(web_explorer).bind_ephemeral(dash_addr);
-    tokio::spawn(explorer_server);
+    let web_explorer_address = match warp::serve(web_explorer.clone()).try_bind_ephemeral(dash_addr)
+    {
+        Ok((address, explorer_server)) => {
+            tokio::spawn(explorer_server);
+            address
+        }
+        Err(e) => {
+            warn!("dashboard cannot listen on {}: {}", dash_addr, e);
+            let (address, explorer_server) =
+                warp::serve(web_explorer).bind_ephemeral(SocketAddr::from(([127, 0, 0, 1], 0)));
+            tokio::spawn(explorer_server);
+            address
+        }
+    };
 
     web_explorer_address
 }
@@ -95,12 +144,12 @@ pub struct IntrospectChannels {
     pub response: UnboundedSender<Vec<u8>>,
 }
 
-pub fn introspect_stream() -> IntrospectChannels {
+pub fn introspect_stream(tunnel: String, har: Option<har::HarOptions>) -> IntrospectChannels {
     let id = Uuid::new_v4();
     let (request_tx, request_rx) = unbounded::<Vec<u8>>();
     let (response_tx, response_rx) = unbounded::<Vec<u8>>();
 
-    tokio::spawn(async move { collect_stream(id, request_rx, response_rx).await });
+    tokio::spawn(async move { collect_stream(id, tunnel, har, request_rx, response_rx).await });
 
     IntrospectChannels {
         request: request_tx,
@@ -110,10 +159,14 @@ pub fn introspect_stream() -> IntrospectChannels {
 
 async fn collect_stream(
     id: Uuid,
+    tunnel: String,
+    har: Option<har::HarOptions>,
     mut request_rx: UnboundedReceiver<Vec<u8>>,
     mut response_rx: UnboundedReceiver<Vec<u8>>,
 ) {
     let started = chrono::Local::now().naive_local();
+    let opened = std::time::Instant::now();
+    api::stream_opened(&tunnel);
     let mut collected_request: Vec<u8> = vec![];
     let mut collected_response: Vec<u8> = vec![];
 
@@ -133,6 +186,7 @@ async fn collect_stream(
         Ok(httparse::Status::Complete(len)) => len,
         _ => {
             warn!("incomplete request received");
+            api::stream_closed(&tunnel, opened.elapsed(), false);
             return;
         }
     };
@@ -147,11 +201,15 @@ async fn collect_stream(
         _ => 0,
     };
     let response_data = collected_response.as_slice()[parts_len..].to_vec();
+    api::stream_closed(&tunnel, opened.elapsed(), response.code.is_some());
 
-    console_log::log(&request, &response);
+    if !console_log::is_server_log() {
+        console_log::log(&request, &response);
+    }
 
     let stored_request = Request {
         id: id.to_string(),
+        tunnel,
         path: request.path.map(String::from),
         method: request.method.map(String::from),
         headers: request_headers
@@ -183,6 +241,10 @@ async fn collect_stream(
         entire_request: collected_request,
     };
 
+    if let Some(options) = har {
+        har::record(&options, &stored_request);
+    }
+
     get_requests()
         .write()
         .unwrap()
@@ -193,6 +255,30 @@ async fn collect_stream(
 #[template(path = "index.html")]
 struct Inspector {
     requests: Vec<Request>,
+    tunnel_requests: Vec<TunnelRequest>,
+    notices: Vec<Notice>,
+}
+
+/// A request the server reported, with its tunnel and start in local time
+#[derive(Debug, Clone)]
+struct TunnelRequest {
+    tunnel: String,
+    entry: RequestLogEntry,
+    started: chrono::NaiveDateTime,
+}
+
+impl From<(String, RequestLogEntry)> for TunnelRequest {
+    fn from((tunnel, entry): (String, RequestLogEntry)) -> Self {
+        let started = chrono::DateTime::from_timestamp_millis(entry.timestamp as i64)
+            .unwrap_or_default()
+            .with_timezone(&chrono::Local)
+            .naive_local();
+        TunnelRequest {
+            tunnel,
+            entry,
+            started,
+        }
+    }
 }
 
 #[derive(Debug, Clone, askama::Template)]
@@ -225,10 +311,28 @@ enum DataType {
 async fn inspector() -> Result<Page<Inspector>, warp::reject::Rejection> {
     let mut requests: Vec<Request> = get_requests().read().unwrap().values().cloned().collect();
     requests.sort_by(|a, b| b.completed.cmp(&a.completed));
-    let inspect = Inspector { requests };
+    let inspect = Inspector {
+        requests,
+        tunnel_requests: get_tunnel_log()
+            .entries()
+            .into_iter()
+            .map(TunnelRequest::from)
+            .collect(),
+        notices: get_tunnel_log().notices(),
+    };
     Ok(Page(inspect))
 }
 
+/// How many requests we saw, so the dashboard can tell when to refresh
+fn list_requests() -> warp::reply::Json {
+    let local: Vec<String> = get_requests().read().unwrap().keys().cloned().collect();
+    warp::reply::json(&serde_json::json!({
+        "local": local,
+        "tunnel": get_tunnel_log().entries(),
+        "notices": get_tunnel_log().notices().len(),
+    }))
+}
+
 async fn request_detail(rid: String) -> Result<Page<InspectorDetail>, warp::reject::Rejection> {
     let request: Request = match get_requests().read().unwrap().get(&rid) {
         Some(r) => r.clone(),
@@ -263,12 +367,16 @@ fn get_body_data(input: &[u8]) -> BodyData {
 
 async fn replay_request(
     rid: String,
-    config: Config,
+    configs: Arc<Vec<Config>>,
 ) -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
     let request: Request = match get_requests().read().unwrap().get(&rid) {
         Some(r) => r.clone(),
         None => return Err(warp::reject::not_found()),
     };
+    let config = match configs.iter().find(|config| config.name == request.tunnel) {
+        Some(config) => config.clone(),
+        None => return Err(warp::reject::not_found()),
+    };
 
     let (tx, rx) = unbounded::<ControlPacket>();
     tokio::spawn(async move {
@@ -280,7 +388,14 @@ async fn replay_request(
         }
     });
 
-    let tx = local::setup_new_stream(config, tx, StreamId::generate()).await;
+    let tx = local::setup_new_stream(
+        config,
+        tx,
+        StreamId::generate(),
+        None,
+        &request.entire_request,
+    )
+    .await;
 
     // send the data to the stream
     if let Some(mut tx) = tx {
@@ -293,6 +408,46 @@ async fn replay_request(
     Ok(Box::new(warp::redirect(warp::http::Uri::from_static("/"))))
 }
 
+async fn replay_on_server(
+    tunnel: String,
+    rid: String,
+) -> Result<Box<dyn warp::Reply>, warp::reject::Rejection> {
+    if !get_tunnel_log().replay(&tunnel, rid) {
+        error!("failed to replay request: not connected to the server");
+        return Err(warp::reject::not_found());
+    }
+
+    Ok(Box::new(warp::redirect(warp::http::Uri::from_static("/"))))
+}
+
+/// Ask the server for a share link to the tunnel, i.e.
+/// `POST /api/tunnels/<name>/share?max_uses=1&ttl=3600`
+async fn share_tunnel(tunnel: String, request: ShareRequest) -> warp::reply::Response {
+    use warp::http::StatusCode;
+    let error = |status, error: &str| {
+        warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": error })),
+            status,
+        )
+        .into_response()
+    };
+
+    let Some(answer) = get_tunnel_log().share(&tunnel, request) else {
+        return error(
+            StatusCode::NOT_FOUND,
+            "tunnel isn't connected to the server",
+        );
+    };
+    match tokio::time::timeout(SHARE_TIMEOUT, answer).await {
+        Ok(Ok(link)) => match &link.error {
+            Some(refused) => error(StatusCode::UNPROCESSABLE_ENTITY, refused),
+            None => warp::reply::json(&link).into_response(),
+        },
+        // the server may predate share links and ignore us
+        _ => error(StatusCode::GATEWAY_TIMEOUT, "the server didn't answer"),
+    }
+}
+
 struct Page<T>(T);
 
 impl<T> warp::reply::Reply for Page<T>
@@ -0,0 +1,126 @@
+use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
+use portal_lib::{ControlPacket, NoticeLevel, RequestLogEntry, ShareLink, ShareRequest};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+
+/// How many of the requests the server reported we keep for the dashboard
+const MAX_ENTRIES: usize = 200;
+
+/// How many of the operators' notices we keep for the dashboard
+const MAX_NOTICES: usize = 20;
+
+/// A notice from the server's operators, with its tunnel and when it came in local time
+#[derive(Debug, Clone)]
+pub struct Notice {
+    pub tunnel: String,
+    pub level: NoticeLevel,
+    pub message: String,
+    pub received: chrono::NaiveDateTime,
+}
+
+/// The requests the server reported on our tunnels, including those other
+/// agents served when tailing, the operators' notices, and the tunnels to ask for
+/// replays and share links on
+#[derive(Default)]
+pub struct TunnelLog {
+    entries: Mutex<VecDeque<(String, RequestLogEntry)>>,
+    tunnels: Mutex<HashMap<String, UnboundedSender<ControlPacket>>>,
+    /// share links asked for and not answered yet, by request id
+    shares: Mutex<HashMap<String, oneshot::Sender<ShareLink>>>,
+    notices: Mutex<VecDeque<Notice>>,
+}
+
+static TUNNEL_LOG: OnceLock<TunnelLog> = OnceLock::new();
+
+pub fn get_tunnel_log() -> &'static TunnelLog {
+    TUNNEL_LOG.get_or_init(TunnelLog::default)
+}
+
+impl TunnelLog {
+    pub fn record(&self, tunnel: &str, entry: RequestLogEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back((tunnel.to_string(), entry));
+    }
+
+    /// Newest first, with the name of their tunnel
+    pub fn entries(&self) -> Vec<(String, RequestLogEntry)> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    pub fn notice(&self, tunnel: &str, level: NoticeLevel, message: String) {
+        let mut notices = self.notices.lock().unwrap();
+        if notices.len() == MAX_NOTICES {
+            notices.pop_front();
+        }
+        notices.push_back(Notice {
+            tunnel: tunnel.to_string(),
+            level,
+            message,
+            received: chrono::Local::now().naive_local(),
+        });
+    }
+
+    /// Newest first
+    pub fn notices(&self) -> Vec<Notice> {
+        self.notices.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// Send the named tunnel's replays over this connection, i.e. after we (re)connected
+    pub fn set_tunnel(&self, name: &str, tunnel: UnboundedSender<ControlPacket>) {
+        self.tunnels
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), tunnel);
+    }
+
+    /// Forget a tunnel that closed for good
+    pub fn remove_tunnel(&self, name: &str) {
+        self.tunnels.lock().unwrap().remove(name);
+    }
+
+    /// Ask the server to replay the captured request with this id, returning
+    /// whether the tunnel is connected to ask
+    pub fn replay(&self, name: &str, id: String) -> bool {
+        match self.tunnels.lock().unwrap().get(name) {
+            Some(tunnel) => tunnel.unbounded_send(ControlPacket::Replay(id)).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Ask the server for a share link to the named tunnel, returning where its
+    /// answer arrives unless the tunnel isn't connected to ask
+    pub fn share(
+        &self,
+        name: &str,
+        mut request: ShareRequest,
+    ) -> Option<oneshot::Receiver<ShareLink>> {
+        let tunnels = self.tunnels.lock().unwrap();
+        let tunnel = tunnels.get(name)?;
+        let (tx, rx) = oneshot::channel();
+        request.id = uuid::Uuid::new_v4().to_string();
+        let mut shares = self.shares.lock().unwrap();
+        // those we gave up waiting for
+        shares.retain(|_, tx| !tx.is_canceled());
+        shares.insert(request.id.clone(), tx);
+        drop(shares);
+        if tunnel
+            .unbounded_send(ControlPacket::Share(request.clone()))
+            .is_err()
+        {
+            self.shares.lock().unwrap().remove(&request.id);
+            return None;
+        }
+        Some(rx)
+    }
+
+    /// The server's answer to a share link we asked for
+    pub fn shared(&self, link: ShareLink) {
+        if let Some(tx) = self.shares.lock().unwrap().remove(&link.id) {
+            let _ = tx.send(link);
+        }
+    }
+}
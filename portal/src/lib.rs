@@ -0,0 +1,717 @@
+use bytes::Bytes;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::{SinkExt, StreamExt};
+
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+pub use log::{debug, error, info, warn};
+
+use std::collections::HashMap;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+
+mod backoff;
+pub mod cli;
+mod config;
+mod control;
+mod error;
+mod files;
+mod introspect;
+mod local;
+mod logs;
+pub mod middleware;
+mod proxy;
+mod rewrite;
+mod routes;
+mod service;
+mod spawn;
+mod tls;
+mod tunnel;
+mod update;
+pub use async_trait::async_trait;
+use cli::{Cli, CliInterface};
+pub use control::Transport;
+use control::{Control, ControlStream};
+pub use middleware::{Middleware, Response, Verdict};
+pub use proxy::Proxy;
+pub use tunnel::{Tunnel, TunnelBuilder, TunnelEvent};
+
+pub use self::error::*;
+
+pub use config::*;
+pub use portal_lib::*;
+
+use clap::Parser;
+use futures::future::Either;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+pub type ActiveStreams = Arc<RwLock<HashMap<StreamId, UnboundedSender<StreamMessage>>>>;
+/// The data packets each stream may still send, for servers that flow control
+pub type StreamCredits = Arc<RwLock<HashMap<StreamId, Arc<tokio::sync::Semaphore>>>>;
+
+static CLI: OnceLock<Cli> = OnceLock::new();
+static ACTIVE_STREAMS: OnceLock<ActiveStreams> = OnceLock::new();
+static STREAM_CREDITS: OnceLock<StreamCredits> = OnceLock::new();
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub fn get_cli() -> &'static Cli {
+    CLI.get_or_init(Cli::parse)
+}
+
+pub fn get_active_streams() -> &'static ActiveStreams {
+    ACTIVE_STREAMS.get_or_init(|| Arc::new(RwLock::new(HashMap::new())))
+}
+
+pub fn get_stream_credits() -> &'static StreamCredits {
+    STREAM_CREDITS.get_or_init(|| Arc::new(RwLock::new(HashMap::new())))
+}
+
+pub fn get_config() -> &'static Config {
+    CONFIG.get_or_init(|| match get_cli().config {
+        Some(ref config_path) => Config::load_from_file(config_path.to_str().unwrap()).unwrap(),
+        None => Config::load().unwrap(),
+    })
+}
+
+/// What a tunnel keeps across its reconnects
+#[derive(Clone)]
+pub(crate) struct TunnelState {
+    reconnect_token: Arc<Mutex<Option<ReconnectToken>>>,
+    /// The instance the server redirected us to, for our next connection
+    redirect: Arc<Mutex<Option<String>>>,
+    /// The control server (`host:port`) we were sent to, for all our next connections
+    server: Arc<Mutex<Option<String>>>,
+    /// The sub-domain the server moved us to, for all our next connections
+    sub_domain: Arc<Mutex<Option<String>>>,
+    /// Whether the server accepted us since we last looked
+    connected: Arc<AtomicBool>,
+    /// The dashboard's address when we report to a terminal
+    interface: Option<SocketAddr>,
+    events: Option<UnboundedSender<TunnelEvent>>,
+}
+
+impl TunnelState {
+    pub(crate) fn new(
+        interface: Option<SocketAddr>,
+        events: Option<UnboundedSender<TunnelEvent>>,
+    ) -> Self {
+        TunnelState {
+            reconnect_token: Arc::new(Mutex::new(None)),
+            redirect: Arc::new(Mutex::new(None)),
+            server: Arc::new(Mutex::new(None)),
+            sub_domain: Arc::new(Mutex::new(None)),
+            connected: Arc::new(AtomicBool::new(false)),
+            interface,
+            events,
+        }
+    }
+
+    fn emit(&self, event: TunnelEvent) {
+        if let Some(events) = &self.events {
+            let _ = events.unbounded_send(event);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum StreamMessage {
+    Data(Vec<u8>),
+    Close,
+}
+
+/// Keep a tunnel open, reconnecting until the server turns us away for good
+pub(crate) async fn run_tunnel(config: Config, state: TunnelState) -> Error {
+    let mut first_run = true;
+    let mut backoff = backoff::Backoff::default();
+
+    loop {
+        let (restart_tx, mut restart_rx) = unbounded();
+        let wormhole = run_wormhole(config.clone(), state.clone(), first_run, restart_tx);
+        let result = futures::future::select(Box::pin(wormhole), restart_rx.next()).await;
+        let was_connected = state.connected.swap(false, Ordering::Relaxed);
+        if was_connected {
+            backoff.reset();
+        }
+
+        match result {
+            Either::Left((Err(e), _)) => match e {
+                Error::WebSocketError(_)
+                | Error::QuicError(_)
+                | Error::ProxyError(_)
+                | Error::NoResponseFromServer
+                | Error::Timeout => {
+                    error!("Control error: {:?}", e);
+                    reconnect_after(&config, &state, &mut backoff, e.to_string()).await;
+                }
+                // the server may not have noticed our previous connection is gone yet
+                Error::SubDomainInUse { .. } if !first_run => {
+                    warn!("sub-domain still in use by our previous connection");
+                    reconnect_after(&config, &state, &mut backoff, e.to_string()).await;
+                }
+                Error::Redirected(instance_id) => {
+                    info!("redirected to instance {}", instance_id);
+                    *state.redirect.lock().await = Some(instance_id);
+                }
+                Error::RedirectedTo(address) => {
+                    info!("redirected to control server {}", address);
+                    *state.server.lock().await = Some(address);
+                }
+                e => return e,
+            },
+            Either::Right((Some(e), _)) => {
+                warn!("restarting from error: {:?}", e);
+                let reason = e.map_or("the tunnel restarted".to_string(), |e| e.to_string());
+                reconnect_after(&config, &state, &mut backoff, reason).await;
+            }
+            _ => {
+                let reason = "the server closed the tunnel".to_string();
+                reconnect_after(&config, &state, &mut backoff, reason).await
+            }
+        };
+        first_run = first_run && !was_connected;
+
+        info!("restarting wormhole");
+    }
+}
+
+/// Tell the user we lost the tunnel and wait our turn to open it again
+async fn reconnect_after(
+    config: &Config,
+    state: &TunnelState,
+    backoff: &mut backoff::Backoff,
+    reason: String,
+) {
+    let delay = backoff.next_delay();
+    state.emit(TunnelEvent::Reconnecting {
+        reason,
+        attempt: backoff.attempt(),
+        delay,
+    });
+    if state.interface.is_none() {
+        tokio::time::sleep(delay).await;
+        return;
+    }
+
+    let tunnel = if config.name == DEFAULT_TUNNEL {
+        String::new()
+    } else {
+        format!(" {}", config.name)
+    };
+    bunt::eprintln!(
+        "{$yellow}Tunnel{} disconnected, reconnecting in {} (attempt {})...{/$}",
+        tunnel,
+        format!("{:.1}s", delay.as_secs_f64()),
+        backoff.attempt()
+    );
+    tokio::time::sleep(delay).await;
+}
+
+/// Setup the tunnel to our control server
+async fn run_wormhole(
+    config: Config,
+    state: TunnelState,
+    first_run: bool,
+    mut restart_tx: UnboundedSender<Option<Error>>,
+) -> Result<(), Error> {
+    let interface = state
+        .interface
+        .map(|dashboard| CliInterface::start(config.clone(), dashboard));
+    if interface.is_some() {
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    }
+    let Wormhole {
+        control,
+        sub_domain,
+        hostname,
+        endpoint,
+        stream_window,
+    } = connect_to_wormhole(&config, &state).await?;
+
+    state.connected.store(true, Ordering::Relaxed);
+    let url = match endpoint {
+        Some(endpoint) => endpoint.to_string(),
+        None => config.activation_url(&hostname),
+    };
+    introspect::api::connected(&config.name, &url, &config.forward_url());
+    state.emit(TunnelEvent::Connected {
+        url: url.clone(),
+        sub_domain: sub_domain.clone(),
+    });
+    if let Some(interface) = &interface {
+        interface.did_connect(&sub_domain, &url, first_run);
+    }
+
+    let Control {
+        mut sink,
+        mut stream,
+    } = control;
+
+    // tunnel channel, and the control channel written ahead of it so our pongs don't
+    // wait behind a backlog of stream data
+    let (tunnel_tx, mut tunnel_rx) = unbounded::<ControlPacket>();
+    let (control_tx, mut control_rx) = unbounded::<ControlPacket>();
+    introspect::get_tunnel_log().set_tunnel(&config.name, control_tx.clone());
+
+    // follow the requests other agents of our tunnel serve too
+    if config.tail {
+        let _ = control_tx.unbounded_send(ControlPacket::Tail(true));
+    }
+
+    // continuously write to the control server
+    let mut restart = restart_tx.clone();
+    tokio::spawn(async move {
+        loop {
+            let packet = tokio::select! {
+                biased;
+                Some(packet) = control_rx.next() => Some(packet),
+                packet = tunnel_rx.next() => packet,
+            };
+            let packet = match packet {
+                Some(data) => data,
+                None => {
+                    warn!("control flow didn't send anything!");
+                    let _ = restart.send(Some(Error::Timeout)).await;
+                    return;
+                }
+            };
+
+            if let Err(e) = sink.send(packet).await {
+                warn!("failed to write message to the control server: {:?}", e);
+                let _ = restart.send(Some(e)).await;
+                return;
+            }
+        }
+    });
+
+    // continuously read from the control server
+    let drained = read_wormhole(
+        &config,
+        &state,
+        &mut stream,
+        &tunnel_tx,
+        &control_tx,
+        stream_window,
+    )
+    .await?;
+    if drained {
+        info!("server is draining, reconnecting");
+        // our streams finish over the old connection while we reconnect
+        tokio::spawn(async move {
+            let _ = read_wormhole(
+                &config,
+                &state,
+                &mut stream,
+                &tunnel_tx,
+                &control_tx,
+                stream_window,
+            )
+            .await;
+        });
+    }
+    let _ = restart_tx.send(None).await;
+    Ok(())
+}
+
+/// Process what the server sends until it closes the tunnel, or asks us to leave it
+/// in which case we return `true`
+async fn read_wormhole(
+    config: &Config,
+    state: &TunnelState,
+    stream: &mut ControlStream,
+    tunnel_tx: &UnboundedSender<ControlPacket>,
+    control_tx: &UnboundedSender<ControlPacket>,
+    stream_window: Option<usize>,
+) -> Result<bool, Error> {
+    loop {
+        match stream.next().await {
+            Ok(None) => {
+                debug!("got close message");
+                return Ok(false);
+            }
+            Ok(Some(message)) => {
+                let packet = process_control_flow_message(
+                    config.clone(),
+                    state,
+                    tunnel_tx.clone(),
+                    control_tx.clone(),
+                    stream_window,
+                    message,
+                )
+                .await
+                .map_err(|e| {
+                    error!("Malformed protocol control packet: {:?}", e);
+                    Error::MalformedMessageFromServer
+                })?;
+                debug!("Processed packet: {:?}", packet.packet_type());
+
+                match packet {
+                    ControlPacket::Drain => return Ok(true),
+                    ControlPacket::Expired => return Err(Error::Expired),
+                    ControlPacket::Disconnected(reason) => return Err(Error::Disconnected(reason)),
+                    _ => {}
+                }
+            }
+            Err(e) => {
+                warn!("control read error: {:?}", e);
+                return Err(Error::Timeout);
+            }
+        }
+    }
+}
+
+struct Wormhole {
+    control: Control,
+    sub_domain: String,
+    hostname: String,
+    /// where visitors reach us, from servers that tell
+    endpoint: Option<Endpoint>,
+    /// the data packets each stream may send ahead of the server's credits
+    stream_window: Option<usize>,
+}
+
+async fn connect_to_wormhole(config: &Config, state: &TunnelState) -> Result<Wormhole, Error> {
+    let redirect = state.redirect.lock().await.take();
+    let server = state.server.lock().await.clone();
+    let sub_domain = state
+        .sub_domain
+        .lock()
+        .await
+        .clone()
+        .or_else(|| config.sub_domain.clone());
+    let redirected_config;
+    let config = match &server {
+        Some(address) => {
+            redirected_config = config.at(address);
+            &redirected_config
+        }
+        None => config,
+    };
+    let mut control = match config.transport {
+        Transport::WebSocket => {
+            debug!("connecting to wormhole at {}", config.portal_url());
+            let mut request = config.portal_url().into_client_request()?;
+            if let Some(instance_id) = &redirect {
+                if let Ok(value) = HeaderValue::from_str(instance_id) {
+                    request.headers_mut().insert(INSTANCE_HEADER, value);
+                }
+            }
+            let (websocket, _) = match &config.proxy {
+                Some(proxy) => {
+                    let stream = proxy
+                        .connect(&config.portal_host, config.portal_port)
+                        .await?;
+                    tokio_tungstenite::client_async_tls(request, stream).await?
+                }
+                None => tokio_tungstenite::connect_async(request).await?,
+            };
+            Control::websocket(websocket)
+        }
+        // without a header to route on we land on any instance, which serves
+        // us rather than redirecting us again
+        Transport::Quic => {
+            debug!(
+                "connecting to wormhole at {}:{} over quic",
+                config.portal_host, config.quic_port
+            );
+            Control::quic(config).await?
+        }
+    };
+
+    // send our Client Hello message
+    let mut client_hello = match config.secret_key.clone() {
+        Some(secret_key) => ClientHello::generate(sub_domain, ClientType::Auth { key: secret_key }),
+        None => {
+            // if we have a reconnect token, use it.
+            if let Some(reconnect) = state.reconnect_token.lock().await.clone() {
+                ClientHello::reconnect(reconnect)
+            } else {
+                ClientHello::generate(sub_domain, ClientType::Anonymous)
+            }
+        }
+    };
+
+    client_hello.version = Some(env!("CARGO_PKG_VERSION").to_string());
+    client_hello.name = std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok();
+    client_hello.agent_id = config::agent_id();
+    client_hello.request_log = true;
+    client_hello.alerts = config.alerts;
+    client_hello.accepts_redirect = true;
+    client_hello.accepts_suggestions = true;
+    client_hello.redirected = redirect.is_some() || server.is_some();
+    client_hello.service = config.service.clone();
+    client_hello.queue_offline = config.queue_offline;
+    client_hello.cache = config.cache;
+    client_hello.mirror_of = config.mirror_of.clone();
+    client_hello.access_log = config.access_log;
+    client_hello.preferred_region = config.region.clone();
+    client_hello.preferred_instance = config.instance.clone();
+    client_hello.stats = true;
+    client_hello.cors = config.cors.clone();
+    client_hello.https_redirect = config.https_redirect;
+    client_hello.hsts = config.hsts;
+    client_hello.ttl = config.ttl;
+    client_hello.flow_control = true;
+
+    info!("connecting to wormhole...");
+
+    let hello = serde_json::to_vec(&client_hello).unwrap_or_default();
+    control.sink.send_hello(hello).await?;
+
+    // wait for Server hello
+    let server_hello_data = control
+        .stream
+        .next()
+        .await?
+        .ok_or(Error::NoResponseFromServer)?;
+    let server_hello = serde_json::from_slice::<ServerHello>(&server_hello_data).map_err(|e| {
+        error!("Couldn't parse server_hello from {:?}", e);
+        Error::ServerReplyInvalid
+    })?;
+
+    let (sub_domain, hostname, endpoint, stream_window) = match server_hello {
+        ServerHello::Success {
+            sub_domain,
+            client_id,
+            hostname,
+            request_log,
+            endpoint,
+            expires_in,
+            update_available,
+            stream_window,
+        } => {
+            info!("Server accepted our connection. I am client_{}", client_id);
+            if let Some(expires_in) = expires_in {
+                info!("the tunnel expires in {}s", expires_in);
+            }
+            if let Some(update) = update_available {
+                announce_update(state, update);
+            }
+            introspect::set_server_log(request_log);
+            let stream_window = stream_window.map(|window| window as usize);
+            (sub_domain, hostname, endpoint, stream_window)
+        }
+        ServerHello::AuthFailed => {
+            return Err(Error::AuthenticationFailed);
+        }
+        ServerHello::InvalidSubDomain => {
+            return Err(Error::InvalidSubDomain);
+        }
+        ServerHello::SubDomainInUse => {
+            return Err(Error::SubDomainInUse {
+                suggestions: vec![],
+            });
+        }
+        ServerHello::SubDomainTaken { suggestions } => {
+            return Err(Error::SubDomainInUse { suggestions });
+        }
+        ServerHello::Error(error) => return Err(Error::ServerError(error)),
+        ServerHello::Redirect {
+            address: Some(address),
+            ..
+        } => return Err(Error::RedirectedTo(address)),
+        ServerHello::Redirect { instance_id, .. } => return Err(Error::Redirected(instance_id)),
+        ServerHello::VersionTooOld {
+            minimum,
+            download_url,
+        } => {
+            return Err(Error::VersionTooOld {
+                minimum,
+                download_url,
+            })
+        }
+    };
+
+    Ok(Wormhole {
+        control,
+        sub_domain,
+        hostname,
+        endpoint,
+        stream_window,
+    })
+}
+
+/// Prompt whoever runs us to upgrade to the newer version the server knows of
+fn announce_update(state: &TunnelState, update: UpdateAvailable) {
+    info!(
+        "version {} is available at {}",
+        update.version, update.download_url
+    );
+    if state.interface.is_some() {
+        bunt::eprintln!(
+            "{$yellow+italic}New version available:{/$} {[cyan]} => {[green]} {}",
+            env!("CARGO_PKG_VERSION"),
+            update.version.as_str(),
+            update.download_url
+        );
+    }
+    state.emit(TunnelEvent::UpdateAvailable(update));
+}
+
+async fn process_control_flow_message(
+    config: Config,
+    state: &TunnelState,
+    mut tunnel_tx: UnboundedSender<ControlPacket>,
+    mut control_tx: UnboundedSender<ControlPacket>,
+    stream_window: Option<usize>,
+    payload: Bytes,
+) -> Result<ControlPacket, Box<dyn std::error::Error>> {
+    let control_packet = ControlPacket::deserialize(payload)?;
+
+    match &control_packet {
+        ControlPacket::Init(stream_id) => {
+            info!("stream[{:?}] -> init", stream_id.to_string());
+        }
+        ControlPacket::Ping(reconnect_token) => {
+            log::info!("got ping. reconnect_token={}", reconnect_token.is_some());
+
+            if let Some(reconnect) = reconnect_token {
+                let _ = state
+                    .reconnect_token
+                    .lock()
+                    .await
+                    .replace(reconnect.clone());
+            }
+            let _ = control_tx.send(ControlPacket::Ping(None)).await;
+        }
+        ControlPacket::Refused(_)
+        | ControlPacket::Replay(_)
+        | ControlPacket::Tail(_)
+        | ControlPacket::Share(_) => return Err("unexpected control packet".into()),
+        ControlPacket::ShareLink(link) => {
+            introspect::get_tunnel_log().shared(link.clone());
+        }
+        ControlPacket::Request(_, entry) => {
+            if state.interface.is_some() {
+                introspect::log_entry(entry);
+            }
+            introspect::get_tunnel_log().record(&config.name, entry.clone());
+            state.emit(TunnelEvent::Request(entry.clone()));
+        }
+        ControlPacket::Drain | ControlPacket::Expired | ControlPacket::Disconnected(_) => {}
+        ControlPacket::Transferred(transfer) => {
+            let _ = state
+                .sub_domain
+                .lock()
+                .await
+                .replace(transfer.sub_domain.clone());
+            let url = match &transfer.endpoint {
+                Some(endpoint) => endpoint.to_string(),
+                None => config.activation_url(&transfer.hostname),
+            };
+            info!("the server moved the tunnel to {}", url);
+            if state.interface.is_some() {
+                bunt::eprintln!("{$yellow}The tunnel moved to {}{/$}", url);
+            }
+            introspect::api::connected(&config.name, &url, &config.forward_url());
+            state.emit(TunnelEvent::Connected {
+                url,
+                sub_domain: transfer.sub_domain.clone(),
+            });
+        }
+        ControlPacket::Notice { level, message } => {
+            match level {
+                NoticeLevel::Info => info!("notice from the server: {}", message),
+                NoticeLevel::Warning => warn!("notice from the server: {}", message),
+                NoticeLevel::Critical => error!("notice from the server: {}", message),
+            }
+            if state.interface.is_some() {
+                match level {
+                    NoticeLevel::Info => {
+                        bunt::eprintln!("{$cyan}Notice from the server: {}{/$}", message)
+                    }
+                    NoticeLevel::Warning => {
+                        bunt::eprintln!("{$yellow}Notice from the server: {}{/$}", message)
+                    }
+                    NoticeLevel::Critical => {
+                        bunt::eprintln!("{$red+bold}Notice from the server: {}{/$}", message)
+                    }
+                }
+            }
+            introspect::get_tunnel_log().notice(&config.name, *level, message.clone());
+            state.emit(TunnelEvent::Notice {
+                level: *level,
+                message: message.clone(),
+            });
+        }
+        ControlPacket::UpdateAvailable(update) => announce_update(state, update.clone()),
+        ControlPacket::Stats(stats) => {
+            introspect::api::server_stats(&config.name, *stats);
+            state.emit(TunnelEvent::Stats(*stats));
+        }
+        ControlPacket::Credit(stream_id, packets) => {
+            let credits = get_stream_credits().read().unwrap().get(stream_id).cloned();
+            if let Some(credits) = credits {
+                credits.add_permits(*packets as usize);
+            }
+        }
+        ControlPacket::End(stream_id) => {
+            // find the stream
+            let stream_id = stream_id.clone();
+
+            info!("got end stream [{:?}]", &stream_id);
+
+            // the server won't take any more of it, so we stop reading it right away
+            if let Some(credits) = get_stream_credits().write().unwrap().remove(&stream_id) {
+                credits.close();
+            }
+
+            tokio::spawn(async move {
+                let stream = get_active_streams()
+                    .read()
+                    .unwrap()
+                    .get(&stream_id)
+                    .cloned();
+                if let Some(mut tx) = stream {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    let _ = tx.send(StreamMessage::Close).await.map_err(|e| {
+                        error!("failed to send stream close: {:?}", e);
+                    });
+                    get_active_streams().write().unwrap().remove(&stream_id);
+                }
+            });
+        }
+        ControlPacket::Data(stream_id, data) => {
+            info!(
+                "stream[{:?}] -> new data: {:?}",
+                stream_id.to_string(),
+                data.len()
+            );
+
+            if !get_active_streams().read().unwrap().contains_key(stream_id)
+                && local::setup_new_stream(
+                    config.clone(),
+                    tunnel_tx.clone(),
+                    stream_id.clone(),
+                    stream_window,
+                    data,
+                )
+                .await
+                .is_none()
+            {
+                error!("failed to open local tunnel")
+            }
+
+            // find the right stream
+            let active_stream = get_active_streams().read().unwrap().get(stream_id).cloned();
+
+            // forward data to it
+            if let Some(mut tx) = active_stream {
+                tx.send(StreamMessage::Data(data.to_vec())).await?;
+                info!("forwarded to local tcp ({})", stream_id.to_string());
+            } else {
+                error!("got data but no stream to send it to.");
+                tunnel_tx
+                    .send(ControlPacket::Refused(stream_id.clone()))
+                    .await?;
+            }
+        }
+    };
+
+    Ok(control_packet.clone())
+}
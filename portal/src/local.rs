@@ -7,22 +7,36 @@ use futures::{SinkExt, StreamExt};
 use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
 use tokio_rustls::rustls::pki_types::ServerName;
-use tokio_rustls::rustls::{ClientConfig, RootCertStore};
 use tokio_rustls::TlsConnector;
 
 use crate::introspect::{self, introspect_stream, IntrospectChannels};
+use crate::middleware::{self, Forward, RequestHooks, ResponseHooks};
+use crate::routes;
+use crate::tls;
 
 pub trait AnyTcpStream: AsyncRead + AsyncWrite + Unpin + Send {}
 impl<T: AsyncRead + AsyncWrite + Unpin + Send> AnyTcpStream for T {}
 
-/// Establish a new local stream and start processing messages to it
+/// Establish a new local stream and start processing messages to it, sending no more
+/// than `window` data packets ahead of the server's credits if it flow controls
 pub async fn setup_new_stream(
     config: Config,
     mut tunnel_tx: UnboundedSender<ControlPacket>,
     stream_id: StreamId,
+    window: Option<usize>,
+    request: &[u8],
 ) -> Option<UnboundedSender<StreamMessage>> {
     info!("setting up local stream: {}", &stream_id.to_string());
+    // the whole stream goes where the path of its first request is routed
+    let config = match routes::port_for(&config.service.routes, request) {
+        Some(port) => {
+            let addr = SocketAddr::new(config.local_addr.ip(), port);
+            config.forwarding_to(addr)
+        }
+        None => config,
+    };
     debug!("connecting to local service: {:?}", config.local_addr);
     let local_tcp = match TcpStream::connect(config.local_addr).await {
         Ok(s) => s,
@@ -34,15 +48,16 @@ pub async fn setup_new_stream(
         }
     };
 
+    let tunnel = config.name.clone();
+    // only http is ours to rewrite, other protocols reach the local service untouched
+    let (request_hooks, response_hooks) = if config.service.protocol.is_http() {
+        middleware::hooks(&middleware::for_config(&config)).unzip()
+    } else {
+        (None, None)
+    };
     let local_tcp: Box<dyn AnyTcpStream> = if config.local_tls {
-        let dns_name = config.local_host;
-        let mut root_store = RootCertStore::empty();
-
-        root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-
-        let config = ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_no_client_auth();
+        let dns_name = config.local_tls_server_name.unwrap_or(config.local_host);
+        let config = tls::client_config(&config.local_tls_verify);
 
         let config = TlsConnector::from(Arc::new(config));
         let dns_name = ServerName::try_from(dns_name).ok()?;
@@ -65,14 +80,36 @@ pub async fn setup_new_stream(
     let IntrospectChannels {
         request: introspect_request,
         response: introspect_response,
-    } = introspect_stream();
+    } = introspect_stream(tunnel, config.har.clone());
 
     let (stream, sink) = split(local_tcp);
 
+    let credits = window.map(|window| Arc::new(Semaphore::new(window)));
+    if let Some(credits) = &credits {
+        get_stream_credits()
+            .write()
+            .unwrap()
+            .insert(stream_id.clone(), credits.clone());
+    }
+
     // Read local tcp bytes, send them tunnel
     let stream_id_clone = stream_id.clone();
+    let tunnel_tx_clone = tunnel_tx.clone();
+    let credits_clone = credits.clone();
     tokio::spawn(async move {
-        process_local_tcp(stream, tunnel_tx, stream_id_clone, introspect_response).await;
+        process_local_tcp(
+            stream,
+            tunnel_tx_clone,
+            stream_id_clone.clone(),
+            credits_clone,
+            response_hooks,
+            introspect_response,
+        )
+        .await;
+        get_stream_credits()
+            .write()
+            .unwrap()
+            .remove(&stream_id_clone);
     });
 
     // Forward remote packets to local tcp
@@ -83,7 +120,16 @@ pub async fn setup_new_stream(
         .insert(stream_id.clone(), tx.clone());
 
     tokio::spawn(async move {
-        forward_to_local_tcp(sink, rx, introspect_request).await;
+        forward_to_local_tcp(
+            sink,
+            rx,
+            tunnel_tx,
+            stream_id,
+            credits,
+            request_hooks,
+            introspect_request,
+        )
+        .await;
     });
 
     Some(tx)
@@ -93,6 +139,8 @@ pub async fn process_local_tcp<T>(
     mut stream: ReadHalf<T>,
     mut tunnel: UnboundedSender<ControlPacket>,
     stream_id: StreamId,
+    credits: Option<Arc<Semaphore>>,
+    mut hooks: Option<ResponseHooks>,
     mut introspect: UnboundedSender<Vec<u8>>,
 ) where
     T: AnyTcpStream,
@@ -100,14 +148,19 @@ pub async fn process_local_tcp<T>(
     let mut buf = [0; 4 * 1024];
 
     loop {
-        let n = stream
-            .read(&mut buf)
-            .await
-            .expect("failed to read data from socket");
+        // a local service that resets the connection only ends this stream
+        let n = match stream.read(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                error!("failed to read from local service: {:?}", e);
+                0
+            }
+        };
 
         if n == 0 {
             info!("done reading from client stream");
             get_active_streams().write().unwrap().remove(&stream_id);
+            let _ = tunnel.send(ControlPacket::End(stream_id)).await;
             return;
         }
 
@@ -117,11 +170,26 @@ pub async fn process_local_tcp<T>(
             std::str::from_utf8(&data).unwrap_or("<non utf8>")
         );
 
-        let packet = ControlPacket::Data(stream_id.clone(), data.clone());
-        tunnel
-            .send(packet)
-            .await
-            .expect("failed to tunnel packet from local tcp to tunnel");
+        let data = match hooks.as_mut() {
+            Some(hooks) => hooks.response(&data).await,
+            None => data,
+        };
+        if data.is_empty() {
+            continue;
+        }
+
+        if !take_credit(credits.as_deref()).await {
+            info!("server ended the stream, done reading from client stream");
+            get_active_streams().write().unwrap().remove(&stream_id);
+            return;
+        }
+
+        let packet = ControlPacket::Data(stream_id.clone(), data.clone().into());
+        if tunnel.send(packet).await.is_err() {
+            warn!("tunnel closed, dropping local stream");
+            get_active_streams().write().unwrap().remove(&stream_id);
+            return;
+        }
 
         let _ = introspect.send(data).await;
     }
@@ -130,6 +198,10 @@ pub async fn process_local_tcp<T>(
 async fn forward_to_local_tcp<T>(
     mut sink: WriteHalf<T>,
     mut queue: UnboundedReceiver<StreamMessage>,
+    mut tunnel: UnboundedSender<ControlPacket>,
+    stream_id: StreamId,
+    credits: Option<Arc<Semaphore>>,
+    mut hooks: Option<RequestHooks>,
     mut introspect: UnboundedSender<Vec<u8>>,
 ) where
     T: AnyTcpStream,
@@ -146,11 +218,50 @@ async fn forward_to_local_tcp<T>(
             }
         };
 
-        sink.write_all(&data)
-            .await
-            .expect("failed to write packet data to local tcp socket");
-        debug!("wrote to local service: {:?}", data.len());
+        // the inspector keeps what the visitor sent, so replays go through the middleware once
+        let (local, response) = match hooks.as_mut() {
+            Some(hooks) => match hooks.request(&data).await {
+                Forward::Local(local) => (local, None),
+                Forward::Respond(local, response) => (local, Some(response)),
+            },
+            None => (data.clone(), None),
+        };
+        if let Err(e) = sink.write_all(&local).await {
+            error!("failed to write to local service: {:?}", e);
+            get_active_streams().write().unwrap().remove(&stream_id);
+            let _ = tunnel.send(ControlPacket::End(stream_id)).await;
+            return;
+        }
+        debug!("wrote to local service: {:?}", local.len());
 
         let _ = introspect.send(data).await;
+
+        // a middleware answered the visitor itself
+        if let Some(response) = response {
+            get_active_streams().write().unwrap().remove(&stream_id);
+            if take_credit(credits.as_deref()).await {
+                let _ = tunnel
+                    .send(ControlPacket::Data(stream_id.clone(), response.into()))
+                    .await;
+            }
+            let _ = tunnel.send(ControlPacket::End(stream_id)).await;
+            let _ = sink.shutdown().await;
+            return;
+        }
+    }
+}
+
+/// Wait for the server to credit us with a data packet of the stream, if it flow
+/// controls, returning `false` if it ended the stream meanwhile
+async fn take_credit(credits: Option<&Semaphore>) -> bool {
+    match credits {
+        Some(credits) => match credits.acquire().await {
+            Ok(permit) => {
+                permit.forget();
+                true
+            }
+            Err(_) => false,
+        },
+        None => true,
     }
 }
@@ -0,0 +1,88 @@
+//! Where the agent logs to: the terminal, or with `--log-file` a file that's
+//! rotated as it grows, i.e. when running as a service
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use env_logger::{Target, WriteStyle};
+
+use super::*;
+
+/// How large the log file grows before we start a new one
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+/// How many rotated log files we keep, i.e. `portal.log.1` to `portal.log.3`
+const KEEP_LOGS: usize = 3;
+
+/// Start logging, honoring `RUST_LOG` like `pretty_env_logger::init`
+pub fn init() {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+
+    if let Some(path) = &get_cli().log_file {
+        match RotatingFile::open(path) {
+            Ok(file) => {
+                builder
+                    .target(Target::Pipe(Box::new(file)))
+                    .write_style(WriteStyle::Never);
+            }
+            Err(e) => eprintln!("Failed to open the log file {}: {}", path.display(), e),
+        }
+    }
+
+    let _ = builder.try_init();
+}
+
+/// A log file that moves aside to `<file>.1` once it reaches `MAX_LOG_SIZE`
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..KEEP_LOGS).rev() {
+            let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        *self = RotatingFile::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
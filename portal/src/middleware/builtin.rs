@@ -0,0 +1,105 @@
+use std::str::FromStr;
+
+use base64::engine::general_purpose;
+use base64::Engine;
+
+use super::*;
+
+/// Asks visitors for a user name and password before they reach the local service
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    /// `user:password` as visitors send it
+    credentials: String,
+}
+
+impl BasicAuth {
+    pub fn new(user: &str, password: &str) -> Self {
+        BasicAuth {
+            credentials: general_purpose::STANDARD.encode(format!("{}:{}", user, password)),
+        }
+    }
+
+    fn is_authorized(&self, head: &RequestHead) -> bool {
+        head.headers
+            .get("Authorization")
+            .and_then(|value| value.trim().split_once(' '))
+            .is_some_and(|(scheme, credentials)| {
+                scheme.eq_ignore_ascii_case("basic") && credentials.trim() == self.credentials
+            })
+    }
+}
+
+impl FromStr for BasicAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((user, password)) if !user.is_empty() => Ok(BasicAuth::new(user, password)),
+            _ => Err(format!(
+                "invalid basic auth {}, expected <user>:<password>",
+                s
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for BasicAuth {
+    async fn on_request(&self, head: &mut RequestHead) -> Verdict {
+        if self.is_authorized(head) {
+            // the local service has no use for our credentials
+            head.headers.remove("Authorization");
+            return Verdict::Forward;
+        }
+
+        let mut response = Response::new(401, "Unauthorized", "Unauthorized\n");
+        response
+            .headers
+            .set("WWW-Authenticate", "Basic realm=\"portal\"");
+        Verdict::Respond(response)
+    }
+}
+
+/// Sets a header on every request toward the local service, replacing what the
+/// visitor sent
+#[derive(Debug, Clone)]
+pub struct AddHeader {
+    name: String,
+    value: String,
+}
+
+impl AddHeader {
+    pub fn new(name: &str, value: &str) -> Self {
+        AddHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+impl FromStr for AddHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let is_token = |name: &str| {
+            !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+        };
+        match s.split_once(':') {
+            Some((name, value)) if is_token(name) && !value.contains(['\r', '\n']) => {
+                Ok(AddHeader::new(name, value.trim()))
+            }
+            _ => Err(format!("invalid header {}, expected <name>:<value>", s)),
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for AddHeader {
+    async fn on_request(&self, head: &mut RequestHead) -> Verdict {
+        head.headers.set(&self.name, self.value.as_str());
+        Verdict::Forward
+    }
+}
@@ -0,0 +1,278 @@
+//! Hooks into the requests a tunnel forwards to the local service and the
+//! responses it sends back
+use std::fmt;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use portal_lib::http::{
+    Headers, RequestFrame, RequestFramer, RequestHead, ResponseFrame, ResponseFramer, ResponseHead,
+};
+
+use super::*;
+use crate::rewrite::{HostHeader, PathPrefix, SetHost};
+use crate::routes::CloseConnection;
+
+mod builtin;
+pub use self::builtin::{AddHeader, BasicAuth};
+
+/// Looks at or changes what goes through a tunnel. The hooks see the heads of
+/// HTTP/1.x messages, bodies pass untouched.
+///
+/// What the local service receives is changed, the dashboard and its replays
+/// keep what the visitor sent.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Called with each request head before it reaches the local service
+    async fn on_request(&self, _head: &mut RequestHead) -> Verdict {
+        Verdict::Forward
+    }
+
+    /// Called with each final response head before it goes back to the visitor
+    async fn on_response(&self, _head: &mut ResponseHead) {}
+}
+
+/// What to do with a request a middleware saw
+#[derive(Debug, Clone)]
+pub enum Verdict {
+    /// pass it on to the next middleware and then the local service
+    Forward,
+    /// answer the visitor ourselves and close the connection
+    Respond(Response),
+}
+
+/// A response a middleware answers with instead of the local service
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, reason: &str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status,
+            reason: reason.to_string(),
+            headers: Headers::default(),
+            body: body.into(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut head = ResponseHead {
+            version: 1,
+            status: self.status,
+            reason: self.reason.clone(),
+            headers: self.headers.clone(),
+        };
+        head.headers
+            .set("Content-Length", self.body.len().to_string());
+        head.headers.set("Connection", "close");
+
+        let mut out = head.to_bytes();
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
+
+/// The middleware of a tunnel, run in order
+#[derive(Clone, Default)]
+pub struct Middlewares(Vec<Arc<dyn Middleware>>);
+
+impl Middlewares {
+    pub fn push(&mut self, middleware: impl Middleware + 'static) {
+        self.0.push(Arc::new(middleware));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Middlewares {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Middlewares({})", self.0.len())
+    }
+}
+
+/// The built-ins the config asks for, followed by the middleware registered in code
+pub(crate) fn for_config(config: &Config) -> Middlewares {
+    let mut chain = Middlewares::default();
+    if let Some(auth) = &config.basic_auth {
+        chain.push(auth.clone());
+    }
+    match &config.host_header {
+        HostHeader::Preserve => {}
+        HostHeader::Rewrite => chain.push(SetHost(format!(
+            "{}:{}",
+            config.local_host, config.local_port
+        ))),
+        HostHeader::Custom(host) => chain.push(SetHost(host.clone())),
+    }
+    if let Some(prefix) = &config.path_prefix {
+        chain.push(PathPrefix(prefix.clone()));
+    }
+    if !config.service.routes.is_empty() {
+        chain.push(CloseConnection);
+    }
+    for header in &config.add_headers {
+        chain.push(header.clone());
+    }
+    chain.0.extend(config.middleware.0.iter().cloned());
+    chain
+}
+
+/// The hooks of a new stream, `None` if there's no middleware to run
+pub(crate) fn hooks(middleware: &Middlewares) -> Option<(RequestHooks, ResponseHooks)> {
+    if middleware.is_empty() {
+        return None;
+    }
+
+    let (methods_tx, methods_rx) = unbounded();
+    let (upgrades_tx, upgrades_rx) = unbounded();
+    let requests = RequestHooks {
+        middleware: middleware.clone(),
+        framer: RequestFramer::default(),
+        methods: methods_tx,
+        upgrades: upgrades_rx,
+        gave_up: false,
+    };
+    let responses = ResponseHooks {
+        middleware: middleware.clone(),
+        framer: ResponseFramer::default(),
+        methods: methods_rx,
+        upgrades: upgrades_tx,
+        gave_up: false,
+    };
+    Some((requests, responses))
+}
+
+/// What to do with bytes the visitor sent
+pub(crate) enum Forward {
+    /// write them to the local service
+    Local(Vec<u8>),
+    /// write the first to the local service, then answer the visitor with the
+    /// second and close the stream
+    Respond(Vec<u8>, Vec<u8>),
+}
+
+/// Runs the middleware on the requests of one stream
+pub(crate) struct RequestHooks {
+    middleware: Middlewares,
+    framer: RequestFramer,
+    /// the method of every request we forwarded and whether it asked to upgrade,
+    /// for the responses to expect
+    methods: UnboundedSender<(String, bool)>,
+    /// whether each upgrade switched protocols, as its response tells
+    upgrades: UnboundedReceiver<bool>,
+    /// whether the stream stopped looking like HTTP/1.x and we pass it on untouched
+    gave_up: bool,
+}
+
+impl RequestHooks {
+    /// Feed the bytes the visitor sent
+    pub(crate) async fn request(&mut self, data: &[u8]) -> Forward {
+        if self.gave_up || self.framer.is_passthrough() {
+            return Forward::Local(data.to_vec());
+        }
+
+        let frames = match self.frame(data).await {
+            Ok(frames) => frames,
+            Err(e) => {
+                warn!("not running middleware on the rest of the stream: {}", e);
+                self.gave_up = true;
+                return Forward::Local(data.to_vec());
+            }
+        };
+
+        let mut out = Vec::with_capacity(data.len());
+        for frame in frames {
+            match frame {
+                RequestFrame::Head(mut head) => {
+                    for middleware in &self.middleware.0 {
+                        if let Verdict::Respond(response) = middleware.on_request(&mut head).await {
+                            return Forward::Respond(out, response.to_bytes());
+                        }
+                    }
+                    let _ = self
+                        .methods
+                        .unbounded_send((head.method.clone(), head.is_upgrade()));
+                    out.extend(head.to_bytes());
+                }
+                RequestFrame::Body(body) => out.extend(body),
+            }
+        }
+        Forward::Local(out)
+    }
+
+    /// Split the bytes into frames, telling those after an upgrade by its response
+    async fn frame(&mut self, data: &[u8]) -> Result<Vec<RequestFrame>, portal_lib::http::Error> {
+        if !self.framer.is_upgrading() {
+            return self.framer.push(data);
+        }
+
+        // the server only sends what follows an upgrade once it's answered
+        let switched = self.upgrades.next().await.unwrap_or(false);
+        let mut frames = self.framer.upgraded(switched)?;
+        frames.extend(self.framer.push(data)?);
+        Ok(frames)
+    }
+}
+
+/// Runs the middleware on the responses of one stream
+pub(crate) struct ResponseHooks {
+    middleware: Middlewares,
+    framer: ResponseFramer,
+    methods: UnboundedReceiver<(String, bool)>,
+    upgrades: UnboundedSender<bool>,
+    gave_up: bool,
+}
+
+impl ResponseHooks {
+    /// Feed the bytes the local service sent, returning what to send the visitor
+    pub(crate) async fn response(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.gave_up {
+            return data.to_vec();
+        }
+
+        // a request always goes out before the local service answers it
+        while let Ok(Some((method, upgrade))) = self.methods.try_next() {
+            if upgrade {
+                self.framer.expect_upgrade(&method);
+            } else {
+                self.framer.expect(&method);
+            }
+        }
+
+        let frames = match self.framer.push_frames(data) {
+            Ok(frames) => frames,
+            Err(e) => {
+                warn!("not running middleware on the rest of the responses: {}", e);
+                self.gave_up = true;
+                return data.to_vec();
+            }
+        };
+        if let Some(switched) = self.framer.take_upgrade() {
+            let _ = self.upgrades.unbounded_send(switched);
+        }
+
+        let mut out = Vec::with_capacity(data.len());
+        for frame in frames {
+            match frame {
+                ResponseFrame::Head(mut head) => {
+                    if !head.is_informational() {
+                        for middleware in &self.middleware.0 {
+                            middleware.on_response(&mut head).await;
+                        }
+                    }
+                    out.extend(head.to_bytes());
+                }
+                ResponseFrame::Body(body) => out.extend(body),
+                ResponseFrame::End { .. } => {}
+            }
+        }
+        out
+    }
+}
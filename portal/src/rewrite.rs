@@ -0,0 +1,119 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use portal_lib::http::RequestHead;
+
+use crate::middleware::{Middleware, Verdict};
+
+/// What Host the local service sees on requests
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HostHeader {
+    /// the public host the visitor asked for
+    #[default]
+    Preserve,
+    /// the local service's address, i.e. `localhost:8000`
+    Rewrite,
+    Custom(String),
+}
+
+impl FromStr for HostHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(HostHeader::Preserve),
+            "rewrite" => Ok(HostHeader::Rewrite),
+            _ => match s.strip_prefix("custom:") {
+                Some(host) if !host.is_empty() => Ok(HostHeader::Custom(host.to_string())),
+                _ => Err(format!(
+                    "invalid host header {}, expected rewrite, preserve or custom:<value>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Check a path prefix, returning it without its trailing slash
+pub fn parse_path_prefix(prefix: &str) -> Result<String, String> {
+    if !prefix.starts_with('/') {
+        return Err(format!("path prefix {} must start with /", prefix));
+    }
+    Ok(prefix.trim_end_matches('/').to_string())
+}
+
+/// Sets the Host the local service sees
+pub(crate) struct SetHost(pub String);
+
+#[async_trait]
+impl Middleware for SetHost {
+    async fn on_request(&self, head: &mut RequestHead) -> Verdict {
+        head.headers.set("Host", self.0.as_str());
+        Verdict::Forward
+    }
+}
+
+/// Prepends a path to every request
+pub(crate) struct PathPrefix(pub String);
+
+#[async_trait]
+impl Middleware for PathPrefix {
+    async fn on_request(&self, head: &mut RequestHead) -> Verdict {
+        // leave `*` and absolute-form targets alone
+        if head.path.starts_with('/') {
+            head.path = format!("{}{}", self.0, head.path);
+        }
+        Verdict::Forward
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str) -> RequestHead {
+        let head = format!("GET {} HTTP/1.1\r\nHost: app.example.com\r\n\r\n", path);
+        RequestHead::parse(head.as_bytes()).unwrap().unwrap().0
+    }
+
+    #[test]
+    fn test_parse_host_header() {
+        assert_eq!("preserve".parse(), Ok(HostHeader::Preserve));
+        assert_eq!("rewrite".parse(), Ok(HostHeader::Rewrite));
+        assert_eq!(
+            "custom:app.local".parse(),
+            Ok(HostHeader::Custom("app.local".to_string()))
+        );
+        assert!("custom:".parse::<HostHeader>().is_err());
+        assert!("keep".parse::<HostHeader>().is_err());
+    }
+
+    #[test]
+    fn test_parse_path_prefix() {
+        assert_eq!(parse_path_prefix("/api/"), Ok("/api".to_string()));
+        assert_eq!(parse_path_prefix("/api"), Ok("/api".to_string()));
+        assert_eq!(parse_path_prefix("/"), Ok(String::new()));
+        assert!(parse_path_prefix("api").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_set_host() {
+        let mut head = request("/");
+        SetHost("localhost:8000".to_string())
+            .on_request(&mut head)
+            .await;
+        assert_eq!(head.headers.get("host"), Some("localhost:8000"));
+    }
+
+    #[tokio::test]
+    async fn test_path_prefix() {
+        let prefix = PathPrefix("/app".to_string());
+        let mut head = request("/a?b=c");
+        prefix.on_request(&mut head).await;
+        assert_eq!(head.path, "/app/a?b=c");
+
+        let mut head = request("*");
+        prefix.on_request(&mut head).await;
+        assert_eq!(head.path, "*");
+    }
+}
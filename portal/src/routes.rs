@@ -0,0 +1,86 @@
+use async_trait::async_trait;
+use portal_lib::http::{RequestHead, ResponseHead};
+use portal_lib::PathRoute;
+
+use crate::middleware::Middleware;
+use crate::rewrite::parse_path_prefix;
+
+/// Parse a `prefix=port` route, i.e. `/api=8000`
+pub fn parse_route(s: &str) -> Result<PathRoute, String> {
+    let Some((prefix, port)) = s.rsplit_once('=') else {
+        return Err(format!("invalid route {}, expected <prefix>=<port>", s));
+    };
+    let port = port
+        .parse()
+        .map_err(|_| format!("invalid port {} in route {}", port, s))?;
+    Ok(PathRoute {
+        prefix: parse_path_prefix(prefix)?,
+        port,
+    })
+}
+
+/// The local port of the route whose prefix is the longest one of the request's
+/// path, if the first bytes of a stream are a request head
+pub(crate) fn port_for(routes: &[PathRoute], request: &[u8]) -> Option<u16> {
+    if routes.is_empty() {
+        return None;
+    }
+    let Ok(Some((head, _))) = RequestHead::parse(request) else {
+        return None;
+    };
+    let path = head.path.split('?').next().unwrap_or_default();
+    routes
+        .iter()
+        .filter(|route| {
+            path.strip_prefix(route.prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        })
+        .max_by_key(|route| route.prefix.len())
+        .map(|route| route.port)
+}
+
+/// Has visitors open a new connection for each request, as a connection goes to the
+/// local port its first request was routed to
+pub(crate) struct CloseConnection;
+
+#[async_trait]
+impl Middleware for CloseConnection {
+    async fn on_response(&self, head: &mut ResponseHead) {
+        head.headers.set("Connection", "close");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(prefix: &str, port: u16) -> PathRoute {
+        PathRoute {
+            prefix: prefix.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!(parse_route("/api/=8000"), Ok(route("/api", 8000)));
+        assert!(parse_route("/api").is_err());
+        assert!(parse_route("/api=http").is_err());
+        assert!(parse_route("api=8000").is_err());
+    }
+
+    #[test]
+    fn test_port_for_longest_prefix() {
+        let routes = [route("/api", 8000), route("/api/v2", 8001)];
+        let port =
+            |path: &str| port_for(&routes, format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes());
+
+        assert_eq!(port("/api"), Some(8000));
+        assert_eq!(port("/api/users?page=2"), Some(8000));
+        assert_eq!(port("/api/v2/users"), Some(8001));
+        assert_eq!(port("/apiary"), None);
+        assert_eq!(port("/"), None);
+        assert_eq!(port_for(&routes, b"\x16\x03\x01"), None);
+        assert_eq!(port_for(&[], b"GET /api HTTP/1.1\r\n\r\n"), None);
+    }
+}
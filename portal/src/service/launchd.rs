@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::Path;
+
+use super::*;
+
+const LABEL: &str = "cn.illusiontech.portal";
+
+/// The job's file, a daemon when we're root and the user's agent otherwise
+fn plist_file() -> Result<PathBuf, String> {
+    let file = format!("{}.plist", LABEL);
+    if is_root() {
+        return Ok(PathBuf::from("/Library/LaunchDaemons").join(file));
+    }
+    dirs::home_dir()
+        .map(|home| home.join("Library").join("LaunchAgents").join(file))
+        .ok_or_else(|| "no home directory".to_string())
+}
+
+/// Write the job, which launchd loads at boot or login from now on
+pub fn install(service: &Service) -> Result<(), String> {
+    let path = plist_file()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    fs::write(&path, plist(service)).map_err(|e| format!("cannot write {}: {}", path.display(), e))
+}
+
+pub fn uninstall() -> Result<(), String> {
+    let path = plist_file()?;
+    let _ = launchctl("unload", &path);
+    fs::remove_file(&path).map_err(|e| format!("cannot remove {}: {}", path.display(), e))
+}
+
+pub fn start() -> Result<(), String> {
+    launchctl("load", &plist_file()?)
+}
+
+pub fn stop() -> Result<(), String> {
+    launchctl("unload", &plist_file()?)
+}
+
+fn launchctl(action: &str, path: &Path) -> Result<(), String> {
+    command("launchctl", &[action, &path.to_string_lossy()])
+}
+
+fn plist(service: &Service) -> String {
+    let arguments: String = std::iter::once(service.program.to_string_lossy().into_owned())
+        .chain(service.args.iter().cloned())
+        .map(|arg| format!("        <string>{}</string>\n", escape(&arg)))
+        .collect();
+
+    // we log to `--log-file` ourselves, stderr only catches what happens before
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{label}</string>
+    <key>ProgramArguments</key>
+    <array>
+{arguments}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>/dev/null</string>
+    <key>StandardErrorPath</key>
+    <string>{log_file}</string>
+</dict>
+</plist>
+"#,
+        label = LABEL,
+        arguments = arguments,
+        log_file = escape(&service.log_file.to_string_lossy())
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
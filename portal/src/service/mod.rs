@@ -0,0 +1,147 @@
+//! Run the tunnels of the config file as a service of the OS, so they survive
+//! reboots: a systemd unit on Linux, a launchd job on macOS and a Windows service
+use std::path::PathBuf;
+
+use super::*;
+use crate::cli::ServiceAction;
+
+#[cfg(target_os = "linux")]
+mod systemd;
+#[cfg(target_os = "linux")]
+use self::systemd as platform;
+
+#[cfg(target_os = "macos")]
+mod launchd;
+#[cfg(target_os = "macos")]
+use self::launchd as platform;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use self::windows as platform;
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use super::*;
+
+    const UNSUPPORTED: &str = "services are not supported on this platform";
+
+    pub fn install(_service: &Service) -> Result<(), String> {
+        Err(UNSUPPORTED.into())
+    }
+
+    pub fn uninstall() -> Result<(), String> {
+        Err(UNSUPPORTED.into())
+    }
+
+    pub fn start() -> Result<(), String> {
+        Err(UNSUPPORTED.into())
+    }
+
+    pub fn stop() -> Result<(), String> {
+        Err(UNSUPPORTED.into())
+    }
+}
+
+const SERVICE_NAME: &str = "portal";
+#[cfg(any(target_os = "linux", windows))]
+const DESCRIPTION: &str = "portal tunnels";
+
+/// What the service runs
+pub struct Service {
+    program: PathBuf,
+    args: Vec<String>,
+    log_file: PathBuf,
+}
+
+impl Service {
+    /// The service for these tunnels of the config file, checking it loads
+    fn new(names: &[String]) -> Result<Self, String> {
+        let config =
+            cli::config_file().ok_or("Please give the config file with the `--config` option")?;
+        let config = config
+            .canonicalize()
+            .map_err(|e| format!("cannot find {}: {}", config.display(), e))?;
+        // better now than on every boot
+        Config::load_tunnels(&config, names).map_err(|e| e.to_string())?;
+
+        let log_file = get_cli()
+            .log_file
+            .clone()
+            .or_else(default_log_file)
+            .ok_or("Please give a log file with the `--log-file` option")?;
+        let log_file = env::current_dir()
+            .map_err(|e| e.to_string())?
+            .join(log_file);
+        let program = env::current_exe().map_err(|e| e.to_string())?;
+
+        let mut args = vec![
+            "--config".to_string(),
+            config.to_string_lossy().into_owned(),
+            "--log-file".to_string(),
+            log_file.to_string_lossy().into_owned(),
+        ];
+        if cfg!(windows) {
+            args.extend(["service".to_string(), "run".to_string()]);
+        } else {
+            args.push("start".to_string());
+        }
+        args.extend(names.iter().cloned());
+
+        Ok(Service {
+            program,
+            args,
+            log_file,
+        })
+    }
+}
+
+/// Install, uninstall, start or stop the service
+pub fn run(action: &ServiceAction) -> Result<(), String> {
+    match action {
+        ServiceAction::Install { names } => {
+            let service = Service::new(names)?;
+            platform::install(&service)?;
+            eprintln!(
+                "Installed the {} service, logging to {}",
+                SERVICE_NAME,
+                service.log_file.display()
+            );
+        }
+        ServiceAction::Uninstall => platform::uninstall()?,
+        ServiceAction::Start => platform::start()?,
+        ServiceAction::Stop => platform::stop()?,
+        #[cfg(windows)]
+        ServiceAction::Run { .. } => platform::run()?,
+        #[cfg(not(windows))]
+        ServiceAction::Run { .. } => {
+            return Err("only the Windows service manager runs the service this way".into())
+        }
+    }
+    Ok(())
+}
+
+/// Run a service manager's command, failing with what it printed
+#[cfg(unix)]
+fn command(program: &str, args: &[&str]) -> Result<(), String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("cannot run {}: {}", program, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+/// Whether we install for the whole machine rather than the user
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
@@ -0,0 +1,97 @@
+use std::fs;
+
+use super::*;
+
+const UNIT: &str = "portal.service";
+
+/// The unit file, system wide when we're root and the user's otherwise
+fn unit_file() -> Result<PathBuf, String> {
+    if is_root() {
+        return Ok(PathBuf::from("/etc/systemd/system").join(UNIT));
+    }
+    dirs::config_dir()
+        .map(|dir| dir.join("systemd").join("user").join(UNIT))
+        .ok_or_else(|| "no config directory".to_string())
+}
+
+fn systemctl(args: &[&str]) -> Result<(), String> {
+    let mut all = vec![];
+    if !is_root() {
+        all.push("--user");
+    }
+    all.extend(args);
+    command("systemctl", &all)
+}
+
+pub fn install(service: &Service) -> Result<(), String> {
+    let path = unit_file()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+    }
+    fs::write(&path, unit(service))
+        .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", UNIT])?;
+    if !is_root() {
+        eprintln!("Run `loginctl enable-linger` for the service to start at boot, not at login");
+    }
+    Ok(())
+}
+
+pub fn uninstall() -> Result<(), String> {
+    let path = unit_file()?;
+    let _ = systemctl(&["disable", "--now", UNIT]);
+    fs::remove_file(&path).map_err(|e| format!("cannot remove {}: {}", path.display(), e))?;
+    systemctl(&["daemon-reload"])
+}
+
+pub fn start() -> Result<(), String> {
+    systemctl(&["start", UNIT])
+}
+
+pub fn stop() -> Result<(), String> {
+    systemctl(&["stop", UNIT])
+}
+
+fn unit(service: &Service) -> String {
+    let exec_start = std::iter::once(service.program.to_string_lossy().into_owned())
+        .chain(service.args.iter().cloned())
+        .map(|arg| quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let wanted_by = if is_root() {
+        "multi-user.target"
+    } else {
+        "default.target"
+    };
+
+    format!(
+        "[Unit]
+Description={description}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+ExecStart={exec_start}
+Restart=always
+RestartSec=5
+
+[Install]
+WantedBy={wanted_by}
+",
+        description = DESCRIPTION,
+        exec_start = exec_start,
+        wanted_by = wanted_by
+    )
+}
+
+/// Quote an argument of `ExecStart`, where `%` starts a specifier
+fn quote(arg: &str) -> String {
+    format!(
+        "\"{}\"",
+        arg.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%")
+    )
+}
@@ -0,0 +1,131 @@
+use std::ffi::{OsStr, OsString};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use windows_service::service::{
+    Service as WindowsService, ServiceAccess, ServiceControl, ServiceControlAccept,
+    ServiceErrorControl, ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState,
+    ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use super::*;
+
+pub fn install(service: &Service) -> Result<(), String> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(|e| e.to_string())?;
+
+    let info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(DESCRIPTION),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: service.program.clone(),
+        launch_arguments: service.args.iter().map(OsString::from).collect(),
+        dependencies: vec![],
+        // LocalSystem
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(|e| e.to_string())?;
+    service
+        .set_description(DESCRIPTION)
+        .map_err(|e| e.to_string())
+}
+
+pub fn uninstall() -> Result<(), String> {
+    let service = open(ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)?;
+    let status = service.query_status().map_err(|e| e.to_string())?;
+    if status.current_state != ServiceState::Stopped {
+        let _ = service.stop();
+    }
+    service.delete().map_err(|e| e.to_string())
+}
+
+pub fn start() -> Result<(), String> {
+    open(ServiceAccess::START)?
+        .start(&[] as &[&OsStr])
+        .map_err(|e| e.to_string())
+}
+
+pub fn stop() -> Result<(), String> {
+    open(ServiceAccess::STOP)?
+        .stop()
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn open(access: ServiceAccess) -> Result<WindowsService, String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| e.to_string())?;
+    manager
+        .open_service(SERVICE_NAME, access)
+        .map_err(|e| e.to_string())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Hand this process to the service manager, which calls `service_main` on its own thread
+pub fn run() -> Result<(), String> {
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(|e| e.to_string())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = serve_until_stopped() {
+        error!("service failed: {}", e);
+    }
+}
+
+fn serve_until_stopped() -> windows_service::Result<()> {
+    let stop = Arc::new(Notify::new());
+    let stop_handler = stop.clone();
+    let status = service_control_handler::register(SERVICE_NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            stop_handler.notify_one();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+    let report = |state, controls_accepted| ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    };
+
+    status.set_service_status(report(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    ))?;
+
+    // the service manager passes the arguments of `install` to the process rather than to us
+    let names = match &get_cli().command {
+        Some(cli::Commands::Service {
+            action: ServiceAction::Run { names },
+        }) => names.clone(),
+        _ => vec![],
+    };
+    let runtime = tokio::runtime::Runtime::new().expect("failed to start the runtime");
+    runtime.block_on(async {
+        tokio::select! {
+            _ = cli::serve(cli::load_tunnels(&names)) => {}
+            _ = stop.notified() => {}
+        }
+    });
+    runtime.shutdown_background();
+
+    status.set_service_status(report(ServiceState::Stopped, ServiceControlAccept::empty()))
+}
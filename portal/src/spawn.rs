@@ -0,0 +1,258 @@
+//! `portal run -- <command>`: spawn a dev server, find the port it listens on
+//! and tunnel to it for as long as it runs
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::process::Stdio;
+
+use futures::channel::mpsc::UnboundedReceiver;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+use super::*;
+
+/// How often we look for the port while the command starts up
+const DETECT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Run the command and tunnel to it, exiting with its exit code
+pub async fn run(port: Option<u16>, command: &[String]) {
+    let config = get_config().clone();
+
+    let mut child = Command::new(&command[0]);
+    child
+        .args(&command[1..])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(port) = port {
+        child.env("PORT", port.to_string());
+    }
+    let mut child = match child.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to run {}: {}", command[0], e);
+            std::process::exit(1);
+        }
+    };
+
+    // pass the output on, watching it for the address the command prints
+    let (printed_tx, printed_rx) = unbounded();
+    if let Some(stdout) = child.stdout.take() {
+        tokio::spawn(relay(stdout, tokio::io::stdout(), printed_tx.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(relay(stderr, tokio::io::stderr(), printed_tx));
+    }
+
+    let pid = child.id();
+    let found = async {
+        match port {
+            Some(port) => wait_until_listening(&config, port).await,
+            None => detect(&config, pid, printed_rx).await,
+        }
+    };
+    let addr = tokio::select! {
+        addr = found => addr,
+        status = child.wait() => exit_with(status),
+        _ = tokio::signal::ctrl_c() => std::process::exit(130),
+    };
+    info!("{} listens on {}", command[0], addr);
+
+    let config = config.forwarding_to(addr);
+    tokio::select! {
+        status = child.wait() => exit_with(status),
+        _ = cli::serve(vec![config]) => {
+            let _ = child.kill().await;
+            std::process::exit(1);
+        }
+        _ = tokio::signal::ctrl_c() => {
+            let _ = child.kill().await;
+            std::process::exit(130);
+        }
+    }
+}
+
+/// Exit like the command did, which closes the tunnel with us
+fn exit_with(status: std::io::Result<std::process::ExitStatus>) -> ! {
+    match status {
+        Ok(status) => {
+            eprintln!("The command exited with {}", status);
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Err(e) => {
+            eprintln!("Failed to wait for the command: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Copy the command's output to ours line by line, sending the ports it mentions
+async fn relay(
+    from: impl AsyncRead + Unpin,
+    mut to: impl AsyncWrite + Unpin,
+    printed: UnboundedSender<u16>,
+) {
+    let mut lines = BufReader::new(from).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if let Some(port) = printed_port(&line) {
+            let _ = printed.unbounded_send(port);
+        }
+        let _ = to.write_all(format!("{}\n", line).as_bytes()).await;
+        let _ = to.flush().await;
+    }
+}
+
+/// The local address the command serves on, from the sockets it listens on
+/// or the addresses it prints, i.e. `Local: http://localhost:5173/`
+async fn detect(
+    config: &Config,
+    pid: Option<u32>,
+    mut printed: UnboundedReceiver<u16>,
+) -> SocketAddr {
+    let mut interval = tokio::time::interval(DETECT_INTERVAL);
+    loop {
+        let candidates = tokio::select! {
+            Some(port) = printed.next() => vec![port],
+            _ = interval.tick() => pid.map(listening_ports).unwrap_or_default(),
+        };
+        for port in candidates {
+            if let Some(addr) = accepting(config, port).await {
+                return addr;
+            }
+        }
+    }
+}
+
+async fn wait_until_listening(config: &Config, port: u16) -> SocketAddr {
+    let mut interval = tokio::time::interval(DETECT_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Some(addr) = accepting(config, port).await {
+            return addr;
+        }
+    }
+}
+
+/// Where on this machine `port` accepts connections: servers may only listen
+/// on one of IPv4 and IPv6, whatever `--host` resolves to
+async fn accepting(config: &Config, port: u16) -> Option<SocketAddr> {
+    let ips = [
+        config.local_addr.ip(),
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(Ipv6Addr::LOCALHOST),
+    ];
+    for ip in ips {
+        let addr = SocketAddr::new(ip, port);
+        if TcpStream::connect(addr).await.is_ok() {
+            return Some(addr);
+        }
+    }
+    None
+}
+
+/// The port of a local address in a line of output
+fn printed_port(line: &str) -> Option<u16> {
+    let line = strip_ansi(line);
+    ["localhost:", "127.0.0.1:", "0.0.0.0:", "[::1]:", "[::]:"]
+        .iter()
+        .flat_map(|host| line.match_indices(host).map(move |(i, _)| i + host.len()))
+        .find_map(|start| {
+            let digits: String = line[start..]
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            digits.parse().ok().filter(|port| *port != 0)
+        })
+}
+
+/// Drop the color codes dev servers like to highlight the port with
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // ESC [ parameters final-letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// The TCP ports the process or any of its descendants listen on
+#[cfg(target_os = "linux")]
+fn listening_ports(pid: u32) -> Vec<u16> {
+    use std::collections::HashSet;
+    use std::fs;
+
+    // every process by its parent, to find the server `npm start` spawned
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for entry in fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let Some(child) = entry.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        // `pid (comm) state ppid ...` where comm may contain anything
+        let parent = stat
+            .rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+            .and_then(|ppid| ppid.parse().ok());
+        if let Some(parent) = parent {
+            children.entry(parent).or_default().push(child);
+        }
+    }
+
+    let mut sockets = HashSet::new();
+    let mut pids = vec![pid];
+    while let Some(pid) = pids.pop() {
+        pids.extend(children.get(&pid).into_iter().flatten());
+        let fds = fs::read_dir(format!("/proc/{}/fd", pid));
+        for fd in fds.into_iter().flatten().flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            let inode = target
+                .to_str()
+                .and_then(|t| t.strip_prefix("socket:["))
+                .and_then(|t| t.strip_suffix(']'))
+                .and_then(|inode| inode.parse::<u64>().ok());
+            sockets.extend(inode);
+        }
+    }
+
+    // `sl local_address rem_address st ... inode`, listening when st is 0A
+    let mut ports = vec![];
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(table) = fs::read_to_string(table) else {
+            continue;
+        };
+        for line in table.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 || fields[3] != "0A" {
+                continue;
+            }
+            let inode = fields[9].parse::<u64>().ok();
+            let port = fields[1]
+                .rsplit_once(':')
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            if let (Some(inode), Some(port)) = (inode, port) {
+                if sockets.contains(&inode) && !ports.contains(&port) {
+                    ports.push(port);
+                }
+            }
+        }
+    }
+    ports
+}
+
+/// Elsewhere we only go by what the command prints
+#[cfg(not(target_os = "linux"))]
+fn listening_ports(_pid: u32) -> Vec<u16> {
+    vec![]
+}
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{
+    verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+
+/// How we check the certificate of a local https service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TlsVerify {
+    /// against the web's root certificates
+    Roots,
+    /// not at all, i.e. for a dev server's self-signed certificate
+    Insecure,
+    /// by the SHA-256 fingerprint of its certificate, as lowercase hex
+    Pinned(String),
+}
+
+impl TlsVerify {
+    pub fn new(insecure: bool, pin: Option<&str>) -> Result<Self, String> {
+        match (insecure, pin) {
+            (true, Some(_)) => Err("cannot both skip and pin certificate verification".into()),
+            (true, None) => Ok(TlsVerify::Insecure),
+            (false, Some(pin)) => {
+                // accept the `AB:CD:...` form browsers and openssl print too
+                let pin = pin.replace(':', "").to_lowercase();
+                if pin.len() != 64 || !pin.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("invalid SHA-256 certificate fingerprint: {}", pin));
+                }
+                Ok(TlsVerify::Pinned(pin))
+            }
+            (false, None) => Ok(TlsVerify::Roots),
+        }
+    }
+}
+
+/// The TLS client config for connecting to a local https service
+pub fn client_config(verify: &TlsVerify) -> ClientConfig {
+    let mut root_store = RootCertStore::empty();
+    let pin = match verify {
+        TlsVerify::Roots => {
+            root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            return ClientConfig::builder()
+                .with_root_certificates(root_store)
+                .with_no_client_auth();
+        }
+        TlsVerify::Insecure => None,
+        TlsVerify::Pinned(pin) => Some(pin.clone()),
+    };
+
+    // the verifier checks signatures with the same provider as the rest of the handshake
+    let mut config = ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    let provider = config.crypto_provider().clone();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(LocalVerifier { pin, provider }));
+    config
+}
+
+/// Accepts any certificate, or only the pinned one, as long as the server
+/// proves it holds the certificate's key
+#[derive(Debug)]
+struct LocalVerifier {
+    pin: Option<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for LocalVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        let pin = match &self.pin {
+            Some(pin) => pin,
+            None => return Ok(ServerCertVerified::assertion()),
+        };
+
+        let fingerprint: String = hmac_sha256::Hash::hash(end_entity.as_ref())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if &fingerprint == pin {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(tokio_rustls::rustls::Error::General(format!(
+                "certificate fingerprint {} isn't the pinned one",
+                fingerprint
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // rustls only picks a provider itself with exactly one built in, and panics otherwise
+    #[test]
+    fn test_client_config() {
+        let pin = "ab".repeat(32);
+        for verify in [
+            TlsVerify::Roots,
+            TlsVerify::Insecure,
+            TlsVerify::Pinned(pin),
+        ] {
+            let config = client_config(&verify);
+            assert!(!config.crypto_provider().cipher_suites.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        assert_eq!(TlsVerify::new(false, None), Ok(TlsVerify::Roots));
+        assert_eq!(TlsVerify::new(true, None), Ok(TlsVerify::Insecure));
+        assert!(TlsVerify::new(true, Some(&"ab".repeat(32))).is_err());
+
+        let pin = "AB:".repeat(31) + "AB";
+        assert_eq!(
+            TlsVerify::new(false, Some(&pin)),
+            Ok(TlsVerify::Pinned("ab".repeat(32)))
+        );
+        assert!(TlsVerify::new(false, Some("abcd")).is_err());
+        assert!(TlsVerify::new(false, Some(&"zz".repeat(32))).is_err());
+    }
+}
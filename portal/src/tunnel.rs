@@ -0,0 +1,359 @@
+use futures::channel::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+
+use super::*;
+use crate::middleware::Middlewares;
+
+/// What happens to a tunnel after it's open
+#[derive(Debug, Clone)]
+pub enum TunnelEvent {
+    /// the server accepted the tunnel, again after a reconnect or once it was moved to
+    /// another sub-domain
+    Connected { url: String, sub_domain: String },
+    /// the server served a request through the tunnel
+    Request(RequestLogEntry),
+    /// we lost the server and try again after `delay`
+    Reconnecting {
+        reason: String,
+        attempt: u32,
+        delay: Duration,
+    },
+    /// the server's counts of the tunnel's traffic, every few seconds
+    Stats(TunnelStats),
+    /// a message from the server's operators, i.e. of an upcoming restart
+    Notice { level: NoticeLevel, message: String },
+    /// the server knows of a newer version than ours, once we connect and now and then
+    UpdateAvailable(UpdateAvailable),
+}
+
+/// A tunnel opened from code rather than the command line.
+///
+/// ```no_run
+/// # async fn open() -> Result<(), portal::Error> {
+/// let mut tunnel = portal::Tunnel::builder()
+///     .local_port(3000)
+///     .subdomain("my-app")
+///     .connect()
+///     .await?;
+/// println!("serving on {}", tunnel.url());
+/// while let Some(event) = tunnel.next_event().await {
+///     println!("{:?}", event);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The tunnel reconnects on its own like the CLI does, and closes when dropped.
+pub struct Tunnel {
+    name: String,
+    url: String,
+    sub_domain: String,
+    events: UnboundedReceiver<TunnelEvent>,
+    task: Option<JoinHandle<Error>>,
+}
+
+impl Tunnel {
+    pub fn builder() -> TunnelBuilder {
+        TunnelBuilder::default()
+    }
+
+    /// The public url visitors reach the local service on
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn sub_domain(&self) -> &str {
+        &self.sub_domain
+    }
+
+    /// The next thing that happened to the tunnel, `None` once it closed for good
+    pub async fn next_event(&mut self) -> Option<TunnelEvent> {
+        let event = self.events.next().await;
+        if let Some(TunnelEvent::Connected { url, sub_domain }) = &event {
+            self.url = url.clone();
+            self.sub_domain = sub_domain.clone();
+        }
+        event
+    }
+
+    /// Wait for the server to turn the tunnel away for good, i.e. when the key is revoked
+    pub async fn closed(mut self) -> Error {
+        let task = self.task.take().expect("the task is only taken here");
+        match task.await {
+            Ok(error) => error,
+            Err(e) => Error::CouldNotConnect(e.to_string()),
+        }
+    }
+
+    pub fn close(self) {}
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        introspect::get_tunnel_log().remove_tunnel(&self.name);
+        introspect::api::closed(&self.name);
+    }
+}
+
+/// Settings for a [`Tunnel`], the same the config file takes
+#[derive(Debug, Clone, Default)]
+pub struct TunnelBuilder {
+    config: InternalConfig,
+    middleware: Middlewares,
+}
+
+impl TunnelBuilder {
+    /// The host of the local service, `localhost` by default
+    pub fn local_host(mut self, host: impl Into<String>) -> Self {
+        self.config.local_host = Some(host.into());
+        self
+    }
+
+    /// The port of the local service, 8000 by default
+    pub fn local_port(mut self, port: u16) -> Self {
+        self.config.local_port = Some(port);
+        self
+    }
+
+    /// Whether the local service speaks https
+    pub fn local_tls(mut self, tls: bool) -> Self {
+        self.config.local_tls = Some(tls);
+        self
+    }
+
+    /// Accept the local service's certificate whatever it is, i.e. a self-signed one
+    pub fn local_tls_insecure(mut self, insecure: bool) -> Self {
+        self.config.local_tls_insecure = Some(insecure);
+        self
+    }
+
+    /// Only accept the local service's certificate with this SHA-256 fingerprint
+    pub fn local_tls_pin(mut self, fingerprint: impl Into<String>) -> Self {
+        self.config.local_tls_pin = Some(fingerprint.into());
+        self
+    }
+
+    /// `preserve`, `rewrite` or `custom:<host>`
+    pub fn host_header(mut self, host_header: impl Into<String>) -> Self {
+        self.config.host_header = Some(host_header.into());
+        self
+    }
+
+    pub fn path_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config.path_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Send requests whose path starts with `prefix`, i.e. `/api`, to another local port
+    pub fn route(mut self, prefix: &str, port: u16) -> Self {
+        self.config
+            .routes
+            .get_or_insert_with(Vec::new)
+            .push(format!("{}={}", prefix, port));
+        self
+    }
+
+    /// Only let visitors through with this user name and password
+    pub fn basic_auth(mut self, user: &str, password: &str) -> Self {
+        self.config.basic_auth = Some(format!("{}:{}", user, password));
+        self
+    }
+
+    /// Set a header on every request toward the local service
+    pub fn add_header(mut self, name: &str, value: &str) -> Self {
+        self.config
+            .add_headers
+            .get_or_insert_with(Vec::new)
+            .push(format!("{}:{}", name, value));
+        self
+    }
+
+    /// Run this on every request and response, after the built-ins
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    pub fn subdomain(mut self, sub_domain: impl Into<String>) -> Self {
+        self.config.sub_domain = Some(sub_domain.into());
+        self
+    }
+
+    /// The key to authenticate with, the one stored with `set-auth` by default
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.config.secret_key = Some(key.into());
+        self
+    }
+
+    /// The control server to open the tunnel on
+    pub fn server(mut self, host: impl Into<String>, port: u16, tls: bool) -> Self {
+        self.config.portal_host = Some(host.into());
+        self.config.portal_port = Some(port);
+        self.config.portal_tls = Some(tls);
+        self
+    }
+
+    /// The path of the server's control websocket, for servers taking agents on
+    /// their public port
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.config.portal_path = Some(path.into());
+        self
+    }
+
+    /// Reach the server through an `http://`, `socks5://` or `socks5h://` proxy
+    pub fn proxy(mut self, url: impl Into<String>) -> Self {
+        self.config.proxy = Some(url.into());
+        self
+    }
+
+    /// What visitors speak to reach the tunnel, http unless set
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.config.protocol = Some(protocol);
+        self
+    }
+
+    /// The public port a tcp or udp tunnel asks for, any free one unless set
+    pub fn remote_port(mut self, port: u16) -> Self {
+        self.config.remote_port = Some(port);
+        self
+    }
+
+    /// Have the server hold visitors' requests while the tunnel is offline, delivering
+    /// them in order once it's back
+    pub fn queue_offline(mut self, queue: bool) -> Self {
+        self.config.queue_offline = Some(queue);
+        self
+    }
+
+    /// Let the server cache responses to GET requests as their `Cache-Control` allows
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.config.cache = Some(cache);
+        self
+    }
+
+    /// Also receive copies of the requests to `sub_domain`, another tunnel of the same
+    /// key, dropping their responses
+    pub fn mirror_of(mut self, sub_domain: impl Into<String>) -> Self {
+        self.config.mirror_of = Some(sub_domain.into());
+        self
+    }
+
+    /// Ask the server to write the tunnel's visitors to its access log, when it only logs
+    /// the tunnels that ask
+    pub fn access_log(mut self, access_log: bool) -> Self {
+        self.config.access_log = Some(access_log);
+        self
+    }
+
+    /// Have the server add CORS headers to the tunnel's responses and answer preflight
+    /// requests, for the origins of `options` or any origin if it names none
+    pub fn cors(mut self, options: CorsOptions) -> Self {
+        self.config.cors = Some(true);
+        self.config.cors_origins = Some(options.origins);
+        self.config.cors_credentials = Some(options.credentials);
+        self
+    }
+
+    /// Have the server redirect visitors coming over plain http to https
+    pub fn https_redirect(mut self, https_redirect: bool) -> Self {
+        self.config.https_redirect = Some(https_redirect);
+        self
+    }
+
+    /// Have the server add `Strict-Transport-Security` to the tunnel's responses over
+    /// https
+    pub fn hsts(mut self, hsts: bool) -> Self {
+        self.config.hsts = Some(hsts);
+        self
+    }
+
+    /// Have the server close the tunnel for good `ttl` after opening it, i.e. for a
+    /// temporary demo link
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.config.ttl = Some(ttl.as_secs());
+        self
+    }
+
+    /// Ask to be served from `region`, i.e. the closest, by servers that redirect agents
+    /// to the region they prefer
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.config.region = Some(region.into());
+        self
+    }
+
+    /// Ask to be served by the server instance `instance_id`
+    pub fn instance(mut self, instance_id: impl Into<String>) -> Self {
+        self.config.instance = Some(instance_id.into());
+        self
+    }
+
+    /// Record the tunnel's exchanges to the HAR file at `path`
+    pub fn har(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.config.har = Some(path.into());
+        self
+    }
+
+    /// Hold the tunnel over QUIC on the server's `port`, keeping it across networks
+    pub fn quic(mut self, port: u16) -> Self {
+        self.config.transport = Some(Transport::Quic);
+        self.config.portal_quic_port = Some(port);
+        self
+    }
+
+    /// Follow the requests every agent of the tunnel serves, not just ours
+    pub fn tail(mut self, tail: bool) -> Self {
+        self.config.tail = Some(tail);
+        self
+    }
+
+    /// Open the tunnel, returning once the server accepted it
+    pub async fn connect(self) -> Result<Tunnel, Error> {
+        let TunnelBuilder {
+            mut config,
+            middleware,
+        } = self;
+        config.check().map_err(Error::InvalidConfig)?;
+        let mut config = Config::from(&mut config);
+        config.middleware = middleware;
+        // keep our replays apart from other tunnels of the process
+        config.name = format!("tunnel-{}", config.client_id);
+
+        let (events_tx, mut events) = unbounded();
+        let name = config.name.clone();
+        let state = TunnelState::new(None, Some(events_tx));
+        let mut task = tokio::spawn(run_tunnel(config, state));
+
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(TunnelEvent::Connected { url, sub_domain }) => {
+                        return Ok(Tunnel {
+                            name,
+                            url,
+                            sub_domain,
+                            events,
+                            task: Some(task),
+                        });
+                    }
+                    // don't keep the caller waiting on a server we can't reach
+                    Some(TunnelEvent::Reconnecting { reason, .. }) => {
+                        task.abort();
+                        return Err(Error::CouldNotConnect(reason));
+                    }
+                    Some(_) => {}
+                    None => break,
+                },
+                result = &mut task => {
+                    return Err(result.unwrap_or_else(|e| Error::CouldNotConnect(e.to_string())));
+                }
+            }
+        }
+
+        Err(task
+            .await
+            .unwrap_or_else(|e| Error::CouldNotConnect(e.to_string())))
+    }
+}
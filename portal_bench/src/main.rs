@@ -0,0 +1,209 @@
+//! Load test the proxy path: start a `portal_server`, open N tunnels to it from
+//! simulated agents and have M visitors send requests through them, reporting
+//! handshake latency, request round trips and throughput.
+//!
+//! ```sh
+//! cargo build --release -p portal_server -p portal_bench
+//! target/release/portal_bench --agents 8 --visitors 64
+//! ```
+//!
+//! The server runs as a child process as it only builds as a binary, agents and
+//! visitors run in this one.
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use portal::Tunnel;
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+
+mod origin;
+mod stats;
+mod visitor;
+use self::stats::Samples;
+use self::visitor::Visitor;
+
+/// How long the server may take to start listening
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser)]
+#[command(about = "Load test a portal server with simulated agents and visitors")]
+struct Args {
+    /// Agents, each opening its own tunnel
+    #[arg(short, long, default_value = "4")]
+    agents: usize,
+
+    /// Visitors sending requests at the same time, spread over the tunnels
+    #[arg(short, long, default_value = "32")]
+    visitors: usize,
+
+    /// Requests each visitor sends one after another to measure round trips
+    #[arg(short, long, default_value = "200")]
+    requests: usize,
+
+    /// Bytes each visitor downloads to measure throughput
+    #[arg(long, default_value = "4194304")]
+    payload: usize,
+
+    /// The server binary, `portal_server` next to this one by default
+    #[arg(long, value_name = "PATH")]
+    server: Option<PathBuf>,
+
+    /// Let the server log to our stderr
+    #[arg(long)]
+    server_logs: bool,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    if let Err(error) = run(args).await {
+        eprintln!("benchmark failed: {}", error);
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let origin = origin::spawn(args.payload).await?;
+
+    let remote_port = free_port()?;
+    let control_port = free_port()?;
+    let _server = start_server(&args, remote_port, control_port).await?;
+    let remote = SocketAddr::from((Ipv4Addr::LOCALHOST, remote_port));
+
+    // agents
+    let mut handshakes = Samples::default();
+    let mut tunnels = Vec::with_capacity(args.agents);
+    for i in 0..args.agents {
+        let started = Instant::now();
+        let tunnel = Tunnel::builder()
+            .local_host("127.0.0.1")
+            .local_port(origin.port())
+            .subdomain(format!("bench{}", i))
+            .server("localhost", control_port, false)
+            .connect()
+            .await?;
+        handshakes.push(started.elapsed());
+        tunnels.push(tunnel);
+    }
+    let hosts: Vec<String> = tunnels
+        .iter()
+        .map(|tunnel| format!("{}.localhost", tunnel.sub_domain()))
+        .collect();
+
+    // visitors, connected before we start the clock
+    let mut visitors = Vec::with_capacity(args.visitors);
+    for i in 0..args.visitors {
+        let host = hosts[i % hosts.len()].clone();
+        visitors.push(Visitor::connect(remote, host).await?);
+    }
+
+    let started = Instant::now();
+    let requests = args.requests;
+    let tasks: Vec<_> = visitors
+        .into_iter()
+        .map(|mut visitor| {
+            tokio::spawn(async move {
+                let mut round_trips = Samples::default();
+                for _ in 0..requests {
+                    let sent = Instant::now();
+                    visitor.get("/ping").await?;
+                    round_trips.push(sent.elapsed());
+                }
+                Ok::<_, std::io::Error>((visitor, round_trips))
+            })
+        })
+        .collect();
+    let mut round_trips = Samples::default();
+    let mut visitors = Vec::with_capacity(args.visitors);
+    for task in tasks {
+        let (visitor, samples) = task.await??;
+        round_trips.extend(samples);
+        visitors.push(visitor);
+    }
+    let rtt_elapsed = started.elapsed();
+
+    let started = Instant::now();
+    let tasks: Vec<_> = visitors
+        .into_iter()
+        .map(|mut visitor| tokio::spawn(async move { visitor.get("/bytes").await }))
+        .collect();
+    let mut bytes = 0;
+    for task in tasks {
+        bytes += task.await??;
+    }
+    let throughput_elapsed = started.elapsed();
+
+    println!(
+        "{} agents, {} visitors, {} requests each",
+        args.agents, args.visitors, args.requests
+    );
+    println!("handshake      {}", handshakes.summary());
+    println!(
+        "round trip     {}, {:.0} requests/s",
+        round_trips.summary(),
+        round_trips.len() as f64 / rtt_elapsed.as_secs_f64()
+    );
+    println!(
+        "throughput     {:.1} MiB/s ({} MiB in {:.2?})",
+        bytes as f64 / (1024.0 * 1024.0) / throughput_elapsed.as_secs_f64(),
+        bytes / (1024 * 1024),
+        throughput_elapsed
+    );
+    Ok(())
+}
+
+/// A port nothing listens on right now, for the server to take
+fn free_port() -> std::io::Result<u16> {
+    Ok(TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?
+        .local_addr()?
+        .port())
+}
+
+/// Start the server on these ports, returning once it accepts agents.
+/// It's killed when the returned child is dropped.
+async fn start_server(
+    args: &Args,
+    remote_port: u16,
+    control_port: u16,
+) -> Result<Child, Box<dyn std::error::Error>> {
+    let path = match &args.server {
+        Some(path) => path.clone(),
+        None => std::env::current_exe()?
+            .with_file_name(format!("portal_server{}", std::env::consts::EXE_SUFFIX)),
+    };
+
+    let mut server = Command::new(&path);
+    server
+        .env("ALLOWED_HOSTS", "localhost")
+        .env("PORT", remote_port.to_string())
+        .env("CTRL_PORT", control_port.to_string())
+        .env("NET_PORT", free_port()?.to_string())
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    if !args.server_logs {
+        server.stdout(Stdio::null()).stderr(Stdio::null());
+    }
+    let mut server = server
+        .spawn()
+        .map_err(|e| format!("cannot run {}: {}", path.display(), e))?;
+
+    let deadline = Instant::now() + SERVER_START_TIMEOUT;
+    let control = SocketAddr::from((Ipv4Addr::LOCALHOST, control_port));
+    let remote = SocketAddr::from((Ipv4Addr::LOCALHOST, remote_port));
+    loop {
+        if let Some(status) = server.try_wait()? {
+            return Err(format!("the server exited with {}", status).into());
+        }
+        if TcpStream::connect(control).await.is_ok() && TcpStream::connect(remote).await.is_ok()
+        {
+            return Ok(server);
+        }
+        if Instant::now() > deadline {
+            return Err("the server didn't start listening".into());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
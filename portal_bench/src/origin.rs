@@ -0,0 +1,55 @@
+//! The local service behind every simulated agent: a minimal keep-alive HTTP/1.1
+//! server answering `/ping` with `ok` and `/bytes` with the throughput payload
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serve on a free local port, returning its address
+pub async fn spawn(payload_size: usize) -> std::io::Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let payload: Arc<[u8]> = vec![b'x'; payload_size].into();
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let _ = socket.set_nodelay(true);
+            tokio::spawn(serve(socket, payload.clone()));
+        }
+    });
+    Ok(addr)
+}
+
+async fn serve(mut socket: TcpStream, payload: Arc<[u8]>) {
+    let mut buf = Vec::with_capacity(4096);
+    loop {
+        // our visitors only send bodiless requests, so a head is a whole request
+        let end = loop {
+            if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+            let mut chunk = [0; 4096];
+            match socket.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+        };
+        let head = String::from_utf8_lossy(&buf[..end]).into_owned();
+        buf.drain(..end);
+
+        let body: &[u8] = match head.split_whitespace().nth(1) {
+            Some("/bytes") => &payload,
+            _ => b"ok",
+        };
+        let response_head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: application/octet-stream\r\n\r\n",
+            body.len()
+        );
+        if socket.write_all(response_head.as_bytes()).await.is_err()
+            || socket.write_all(body).await.is_err()
+        {
+            return;
+        }
+    }
+}
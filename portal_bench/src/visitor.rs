@@ -0,0 +1,71 @@
+//! A visitor keeping one connection to the server's remote port, sending requests
+//! to a tunnel one after another
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+pub struct Visitor {
+    socket: TcpStream,
+    host: String,
+    buf: Vec<u8>,
+}
+
+impl Visitor {
+    pub async fn connect(remote: SocketAddr, host: String) -> std::io::Result<Self> {
+        let socket = TcpStream::connect(remote).await?;
+        socket.set_nodelay(true)?;
+        Ok(Visitor {
+            socket,
+            host,
+            buf: Vec::with_capacity(4096),
+        })
+    }
+
+    /// Request `path` and read the whole response, returning the size of its body
+    pub async fn get(&mut self, path: &str) -> std::io::Result<usize> {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: portal_bench\r\n\r\n",
+            path, self.host
+        );
+        self.socket.write_all(request.as_bytes()).await?;
+
+        let end = loop {
+            if let Some(end) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+            self.read_more().await?;
+        };
+        let head = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        self.buf.drain(..end);
+
+        let status = head.split_whitespace().nth(1).unwrap_or_default();
+        if status != "200" {
+            return Err(Error::other(format!("the tunnel answered {}", status)));
+        }
+        let length: usize = head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.trim().parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "response without a length"))?;
+
+        while self.buf.len() < length {
+            self.read_more().await?;
+        }
+        self.buf.drain(..length);
+        Ok(length)
+    }
+
+    async fn read_more(&mut self) -> std::io::Result<()> {
+        let mut chunk = [0; 16 * 1024];
+        match self.socket.read(&mut chunk).await? {
+            0 => Err(ErrorKind::UnexpectedEof.into()),
+            n => {
+                self.buf.extend_from_slice(&chunk[..n]);
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,52 @@
+//! Relaying a large transfer through the tunnel protocol: the bytes a visitor sent
+//! become a `ControlPacket::Data`, go over the websocket and are parsed on the other end
+use bytes::{Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use portal_lib::{ControlPacket, StreamId};
+
+const TRANSFER: usize = 16 * 1024 * 1024;
+const READ_BUFFER: usize = 16 * 1024;
+
+/// How the relay copied every chunk before packets carried `Bytes`
+fn relay_copying(source: &[u8]) -> usize {
+    let mut buf = vec![0u8; READ_BUFFER];
+    let mut relayed = 0;
+    for chunk in source.chunks(READ_BUFFER) {
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let data = buf[..chunk.len()].to_vec();
+        let frame = [vec![0x02], vec![0; 8], data].concat();
+        let received = frame[9..].to_vec();
+        relayed += black_box(received).len();
+    }
+    relayed
+}
+
+fn relay_bytes(stream_id: &StreamId, source: &[u8]) -> usize {
+    let mut buf = BytesMut::new();
+    let mut relayed = 0;
+    for chunk in source.chunks(READ_BUFFER) {
+        buf.reserve(READ_BUFFER);
+        buf.extend_from_slice(chunk);
+        let packet = ControlPacket::Data(stream_id.clone(), buf.split().freeze());
+        let frame = packet.serialize();
+        match ControlPacket::deserialize(frame) {
+            Ok(ControlPacket::Data(_, received)) => relayed += black_box(received).len(),
+            _ => unreachable!("we sent data"),
+        }
+    }
+    relayed
+}
+
+fn bench_relay(c: &mut Criterion) {
+    let source = Bytes::from(vec![0x5a; TRANSFER]);
+    let stream_id = StreamId::generate();
+
+    let mut group = c.benchmark_group("relay");
+    group.throughput(Throughput::Bytes(TRANSFER as u64));
+    group.bench_function("copying", |b| b.iter(|| relay_copying(&source)));
+    group.bench_function("bytes", |b| b.iter(|| relay_bytes(&stream_id, &source)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_relay);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+//! Relaying a large transfer between two TCP connections, the way an instance hands a
+//! visitor to the instance serving their tunnel: on tokio's epoll reactor against
+//! io_uring threads.
+//!
+//! ```sh
+//! cargo bench -p portal_lib --features io-uring --bench uring_bench
+//! ```
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use portal_lib::uring::UringRelay;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+const TRANSFER: usize = 16 * 1024 * 1024;
+const CHUNK: usize = 64 * 1024;
+
+/// A connected pair of sockets
+async fn pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+/// Send `TRANSFER` bytes from a visitor through `relay` to an instance
+async fn transfer<F>(relay: impl FnOnce(TcpStream, TcpStream) -> F)
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    let (mut visitor, a) = pair().await;
+    let (b, mut instance) = pair().await;
+    let relayed = tokio::spawn(relay(a, b));
+
+    let sent = tokio::spawn(async move {
+        let chunk = vec![0x5a; CHUNK];
+        for _ in 0..TRANSFER / CHUNK {
+            visitor.write_all(&chunk).await.unwrap();
+        }
+        visitor.shutdown().await.unwrap();
+        // until the relay is done with us
+        let _ = visitor.read(&mut [0; 1]).await;
+    });
+
+    let mut received = 0;
+    let mut buf = vec![0; CHUNK];
+    loop {
+        match instance.read(&mut buf).await.unwrap() {
+            0 => break,
+            n => received += n,
+        }
+    }
+    assert_eq!(received, TRANSFER);
+    drop(instance);
+    sent.await.unwrap();
+    relayed.await.unwrap();
+}
+
+fn bench_uring(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let uring = &*Box::leak(Box::new(
+        UringRelay::start(2).expect("io_uring unavailable"),
+    ));
+
+    let mut group = c.benchmark_group("tcp_relay");
+    group.throughput(Throughput::Bytes(TRANSFER as u64));
+    group.bench_function("epoll", |b| {
+        b.iter(|| {
+            runtime.block_on(transfer(|mut a, mut b| async move {
+                let _ = tokio::io::copy_bidirectional(&mut a, &mut b).await;
+            }))
+        })
+    });
+    group.bench_function("io_uring", |b| {
+        b.iter(|| {
+            runtime.block_on(transfer(|a, b| async move {
+                let a = a.into_std().unwrap();
+                let b = b.into_std().unwrap();
+                let _ = uring.relay(a, b).await;
+            }))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_uring);
+criterion_main!(benches);
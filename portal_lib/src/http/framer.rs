@@ -0,0 +1,532 @@
+use super::*;
+
+/// A piece of an HTTP/1.x request stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestFrame {
+    /// A complete request head
+    Head(RequestHead),
+    /// Raw body bytes (including chunk framing), or raw bytes after an upgrade
+    Body(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Head,
+    Length(u64),
+    Chunked(Chunk),
+    /// A request asked to switch protocols, what follows waits for the answer
+    Upgrading,
+    /// The connection switched protocols, forward everything untouched
+    Passthrough,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Chunk {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailers,
+}
+
+/// Size limits enforced on every request of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_head_size: usize,
+    pub max_body_size: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_head_size: MAX_HEAD_SIZE,
+            max_body_size: None,
+        }
+    }
+}
+
+/// Incrementally splits a stream of bytes into request heads and bodies
+/// so that every request on a keep-alive connection can be inspected.
+#[derive(Debug)]
+pub struct RequestFramer {
+    buf: Vec<u8>,
+    state: State,
+    limits: Limits,
+    /// body bytes announced so far for the current request
+    body_size: u64,
+}
+
+impl Default for RequestFramer {
+    fn default() -> Self {
+        Self::with_limits(Limits::default())
+    }
+}
+
+impl RequestFramer {
+    pub fn with_limits(limits: Limits) -> Self {
+        Self {
+            buf: Vec::new(),
+            state: State::Head,
+            limits,
+            body_size: 0,
+        }
+    }
+
+    /// A framer for connections that are never parsed as HTTP/1.x (i.e. h2c)
+    pub fn passthrough() -> Self {
+        Self {
+            state: State::Passthrough,
+            ..Self::default()
+        }
+    }
+
+    /// Whether the connection switched protocols and bytes no longer need framing
+    pub fn is_passthrough(&self) -> bool {
+        self.state == State::Passthrough
+    }
+
+    /// Whether a request asked to switch protocols and the bytes after it are held
+    /// until [`RequestFramer::upgraded`] tells what they are
+    pub fn is_upgrading(&self) -> bool {
+        self.state == State::Upgrading
+    }
+
+    /// The response to the upgrade arrived: either the connection `switched`
+    /// protocols and everything from here on passes through, or it goes on with
+    /// requests. Returns the frames of the bytes held in the meantime.
+    pub fn upgraded(&mut self, switched: bool) -> Result<Vec<RequestFrame>, Error> {
+        if self.state != State::Upgrading {
+            return Ok(vec![]);
+        }
+
+        let held = std::mem::take(&mut self.buf);
+        if switched {
+            self.state = State::Passthrough;
+            if held.is_empty() {
+                return Ok(vec![]);
+            }
+            return Ok(vec![RequestFrame::Body(held)]);
+        }
+        self.state = State::Head;
+        self.push(&held)
+    }
+
+    /// Whether we're in the middle of receiving a request head
+    pub fn has_partial_head(&self) -> bool {
+        self.state == State::Head && !self.buf.is_empty()
+    }
+
+    /// Whether the bodies of the requests so far arrived in full
+    pub fn is_between_requests(&self) -> bool {
+        self.state == State::Head
+    }
+
+    /// Feed newly read bytes and collect every frame they complete. Body bytes go
+    /// straight into frames, only a head or chunk line cut off by the end of `data`
+    /// is kept until the next read.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<RequestFrame>, Error> {
+        let mut input = data;
+        let mut frames = vec![];
+        let mut body = vec![];
+
+        loop {
+            match self.state {
+                State::Head => {
+                    if !body.is_empty() {
+                        frames.push(RequestFrame::Body(std::mem::take(&mut body)));
+                    }
+
+                    let max_head_size = self.limits.max_head_size;
+                    let Some(mut head) =
+                        take_head(&mut self.buf, &mut input, max_head_size, RequestHead::parse)?
+                    else {
+                        break;
+                    };
+                    head.sanitize()?;
+                    self.body_size = 0;
+
+                    self.state = if head.is_upgrade() {
+                        State::Upgrading
+                    } else {
+                        match head.headers.body_kind()? {
+                            BodyKind::Length(0) => State::Head,
+                            BodyKind::Length(n) => {
+                                self.add_body_size(n)?;
+                                State::Length(n)
+                            }
+                            BodyKind::Chunked => State::Chunked(Chunk::Size),
+                        }
+                    };
+                    frames.push(RequestFrame::Head(head));
+                }
+                State::Upgrading => {
+                    // held like a head, the peer may not wait for the answer
+                    if self.buf.len() + input.len() > self.limits.max_head_size {
+                        return Err(Error::HeadTooLarge(self.limits.max_head_size));
+                    }
+                    self.buf.extend_from_slice(input);
+                    break;
+                }
+                State::Passthrough => {
+                    body.extend_from_slice(input);
+                    break;
+                }
+                State::Length(remaining) => {
+                    if input.is_empty() {
+                        break;
+                    }
+                    let n = remaining.min(input.len() as u64);
+                    let (taken, rest) = input.split_at(n as usize);
+                    body.extend_from_slice(taken);
+                    input = rest;
+                    self.state = match remaining - n {
+                        0 => State::Head,
+                        rest => State::Length(rest),
+                    };
+                }
+                State::Chunked(chunk) => match self.step_chunk(chunk, &mut input, &mut body)? {
+                    Some(state) => self.state = state,
+                    None => break,
+                },
+            }
+        }
+
+        if !body.is_empty() {
+            frames.push(RequestFrame::Body(body));
+        }
+
+        Ok(frames)
+    }
+
+    /// Advance a chunked body, enforcing the body size limit
+    fn step_chunk(
+        &mut self,
+        chunk: Chunk,
+        input: &mut &[u8],
+        body: &mut Vec<u8>,
+    ) -> Result<Option<State>, Error> {
+        let Some(step) = step_chunk(&mut self.buf, input, chunk, body)? else {
+            return Ok(None);
+        };
+        if let ChunkStep::Size(size, _) = step {
+            self.add_body_size(size)?;
+        }
+        Ok(Some(match step.next() {
+            Some(chunk) => State::Chunked(chunk),
+            None => State::Head,
+        }))
+    }
+
+    /// Account for `size` more body bytes of the current request
+    fn add_body_size(&mut self, size: u64) -> Result<(), Error> {
+        self.body_size = self.body_size.saturating_add(size);
+        match self.limits.max_body_size {
+            Some(max) if self.body_size > max => Err(Error::BodyTooLarge(max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What a step through a chunked body consumed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ChunkStep {
+    /// a chunk size line announcing that many bytes
+    Size(u64, Chunk),
+    Next(Chunk),
+    /// the trailers and thereby the body are complete
+    Done,
+}
+
+impl ChunkStep {
+    /// The chunk state to continue in, `None` once the body is complete
+    pub(super) fn next(self) -> Option<Chunk> {
+        match self {
+            ChunkStep::Size(_, chunk) | ChunkStep::Next(chunk) => Some(chunk),
+            ChunkStep::Done => None,
+        }
+    }
+}
+
+/// Parse a head off the front of `input`, joined to the start of it `kept` from
+/// earlier reads. An incomplete head is kept instead, up to `max_head_size`.
+pub(super) fn take_head<H>(
+    kept: &mut Vec<u8>,
+    input: &mut &[u8],
+    max_head_size: usize,
+    parse: impl Fn(&[u8]) -> Result<Option<(H, usize)>, Error>,
+) -> Result<Option<H>, Error> {
+    let before = kept.len();
+    let parsed = if before == 0 {
+        parse(input)?
+    } else {
+        kept.extend_from_slice(input);
+        parse(kept)?
+    };
+
+    match parsed {
+        Some((_, len)) if len > max_head_size => Err(Error::HeadTooLarge(max_head_size)),
+        Some((head, len)) => {
+            *input = &input[len - before..];
+            // a head split across reads is rare, don't hold on to its buffer
+            *kept = Vec::new();
+            Ok(Some(head))
+        }
+        None if before + input.len() > max_head_size => Err(Error::HeadTooLarge(max_head_size)),
+        None => {
+            if before == 0 {
+                kept.extend_from_slice(input);
+            }
+            *input = &[];
+            Ok(None)
+        }
+    }
+}
+
+/// Advance the chunked body state machine, moving consumed bytes from `input` to `body`.
+/// Returns `None` when more data is needed, keeping a partial line in `kept`.
+pub(super) fn step_chunk(
+    kept: &mut Vec<u8>,
+    input: &mut &[u8],
+    chunk: Chunk,
+    body: &mut Vec<u8>,
+) -> Result<Option<ChunkStep>, Error> {
+    match chunk {
+        Chunk::Size => {
+            let Some(line) = take_line(kept, input, body)? else {
+                return Ok(None);
+            };
+            let size = line.split(|b| *b == b';').next().unwrap_or_default();
+            let size = std::str::from_utf8(size)
+                .ok()
+                .and_then(|s| u64::from_str_radix(s.trim(), 16).ok())
+                .ok_or(Error::InvalidChunk)?;
+
+            Ok(Some(ChunkStep::Size(
+                size,
+                match size {
+                    0 => Chunk::Trailers,
+                    n => Chunk::Data(n),
+                },
+            )))
+        }
+        Chunk::Data(remaining) => {
+            if input.is_empty() {
+                return Ok(None);
+            }
+            let n = remaining.min(input.len() as u64);
+            let (taken, rest) = input.split_at(n as usize);
+            body.extend_from_slice(taken);
+            *input = rest;
+            Ok(Some(ChunkStep::Next(match remaining - n {
+                0 => Chunk::DataEnd,
+                rest => Chunk::Data(rest),
+            })))
+        }
+        Chunk::DataEnd => match take_line(kept, input, body)? {
+            Some(line) if line.is_empty() => Ok(Some(ChunkStep::Next(Chunk::Size))),
+            Some(_) => Err(Error::InvalidChunk),
+            None => Ok(None),
+        },
+        Chunk::Trailers => match take_line(kept, input, body)? {
+            Some(line) if line.is_empty() => Ok(Some(ChunkStep::Done)),
+            Some(_) => Ok(Some(ChunkStep::Next(Chunk::Trailers))),
+            None => Ok(None),
+        },
+    }
+}
+
+/// Consume a CRLF terminated line, moving it to `body` and returning it without the
+/// CRLF. A line cut off by the end of `input` is kept in `kept` until it's complete.
+fn take_line(
+    kept: &mut Vec<u8>,
+    input: &mut &[u8],
+    body: &mut Vec<u8>,
+) -> Result<Option<Vec<u8>>, Error> {
+    loop {
+        let (line, rest) = match input.iter().position(|b| *b == b'\n') {
+            Some(end) => input.split_at(end + 1),
+            None => (*input, &[][..]),
+        };
+        kept.extend_from_slice(line);
+        *input = rest;
+        // chunk size lines and trailers are short, unlike a body sent without its CRLF
+        if kept.len() > MAX_HEAD_SIZE {
+            return Err(Error::InvalidChunk);
+        }
+
+        if kept.ends_with(b"\r\n") {
+            body.extend_from_slice(kept);
+            let line = kept[..kept.len() - 2].to_vec();
+            kept.clear();
+            return Ok(Some(line));
+        }
+        if input.is_empty() {
+            return Ok(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heads(frames: &[RequestFrame]) -> Vec<&RequestHead> {
+        frames
+            .iter()
+            .filter_map(|f| match f {
+                RequestFrame::Head(h) => Some(h),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let mut framer = RequestFramer::default();
+        let frames = framer
+            .push(b"POST /a HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1], RequestFrame::Body(b"hello".to_vec()));
+        let heads = heads(&frames);
+        assert_eq!(heads[0].path, "/a");
+        assert_eq!(heads[1].path, "/b");
+    }
+
+    #[test]
+    fn test_split_chunked_body() {
+        let mut framer = RequestFramer::default();
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\nX-Trailer: 1\r\n\r\nGET /next HTTP/1.1\r\n\r\n";
+
+        let mut frames = vec![];
+        for byte in raw.iter() {
+            frames.extend(framer.push(&[*byte]).unwrap());
+        }
+
+        let body: Vec<u8> = frames
+            .iter()
+            .filter_map(|f| match f {
+                RequestFrame::Body(b) => Some(b.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(body, b"5\r\nhello\r\n0\r\nX-Trailer: 1\r\n\r\n".to_vec());
+        assert_eq!(heads(&frames)[1].path, "/next");
+    }
+
+    #[test]
+    fn test_keeps_only_partial_heads() {
+        let mut framer = RequestFramer::default();
+        assert!(framer
+            .push(b"POST / HTTP/1.1\r\nContent-Le")
+            .unwrap()
+            .is_empty());
+        assert!(framer.has_partial_head());
+
+        let frames = framer.push(b"ngth: 10\r\n\r\nhello").unwrap();
+        assert_eq!(heads(&frames)[0].path, "/");
+        assert_eq!(frames[1], RequestFrame::Body(b"hello".to_vec()));
+        // the body streams through, nothing of it stays behind
+        assert_eq!(framer.buf.capacity(), 0);
+
+        let frames = framer.push(b"world").unwrap();
+        assert_eq!(frames, vec![RequestFrame::Body(b"world".to_vec())]);
+        assert_eq!(framer.buf.capacity(), 0);
+    }
+
+    #[test]
+    fn test_upgrade_passthrough() {
+        let mut framer = RequestFramer::default();
+        let frames = framer
+            .push(b"GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n\x81\x00")
+            .unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(framer.is_upgrading());
+        assert!(framer.push(b"\x81\x01").unwrap().is_empty());
+
+        let frames = framer.upgraded(true).unwrap();
+        assert!(framer.is_passthrough());
+        assert_eq!(
+            frames,
+            vec![RequestFrame::Body(b"\x81\x00\x81\x01".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_refused_upgrade() {
+        let mut framer = RequestFramer::default();
+        let frames = framer
+            .push(b"GET /ws HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\nGET /next HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi")
+            .unwrap();
+        assert_eq!(heads(&frames)[0].path, "/ws");
+        assert!(framer.is_upgrading());
+
+        // the request after it is one, not bytes of another protocol
+        let frames = framer.upgraded(false).unwrap();
+        assert_eq!(heads(&frames)[0].path, "/next");
+        assert_eq!(frames[1], RequestFrame::Body(b"hi".to_vec()));
+        assert!(framer.is_between_requests());
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let mut framer = RequestFramer::default();
+        let frames = framer
+            .push(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive, X-Secret\r\nX-Secret: 1\r\nKeep-Alive: 5\r\nUpgrade: h2c\r\nHost: A\r\nContent-Length: 0\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        assert_eq!(
+            heads(&frames)[0].to_bytes(),
+            b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\nContent-Length: 0\r\n\r\n"
+        );
+
+        for smuggled in [
+            &b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, identity\r\n\r\n",
+            b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n",
+        ] {
+            assert!(matches!(
+                RequestFramer::default().push(smuggled),
+                Err(Error::Ambiguous(_))
+            ));
+        }
+        assert!(matches!(
+            RequestFramer::default().push(b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n"),
+            Err(Error::InvalidContentLength)
+        ));
+        // the framing headers stay, whatever `Connection` says
+        let frames = RequestFramer::default()
+            .push(b"POST / HTTP/1.1\r\nConnection: content-length\r\nContent-Length: 2\r\n\r\nhi")
+            .unwrap();
+        assert_eq!(frames[1], RequestFrame::Body(b"hi".to_vec()));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_head_size: 64,
+            max_body_size: Some(8),
+        };
+
+        let mut framer = RequestFramer::with_limits(limits);
+        let head = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "x".repeat(64));
+        assert!(matches!(
+            framer.push(head.as_bytes()),
+            Err(Error::HeadTooLarge(64))
+        ));
+
+        let mut framer = RequestFramer::with_limits(limits);
+        assert!(matches!(
+            framer.push(b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n"),
+            Err(Error::BodyTooLarge(8))
+        ));
+
+        let mut framer = RequestFramer::with_limits(limits);
+        framer
+            .push(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n")
+            .unwrap();
+        assert!(matches!(framer.push(b"4\r\n"), Err(Error::BodyTooLarge(8))));
+    }
+}
@@ -0,0 +1,335 @@
+use super::*;
+
+/// Headers about the connection they came on rather than the request, besides
+/// `Connection` itself and those it names
+const HOP_BY_HOP: [&str; 3] = ["keep-alive", "proxy-connection", "te"];
+
+/// `Connection` tokens we pass on, with `Upgrade` as long as it's asked for
+const CONNECTION_OPTIONS: [&str; 3] = ["close", "keep-alive", "upgrade"];
+
+/// Headers a `Connection` token may not remove, as the framing depends on them
+const PROTECTED: [&str; 3] = ["host", "content-length", "transfer-encoding"];
+
+/// A single header, with the name's original casing preserved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+/// An ordered list of headers with case-insensitive lookups
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers(Vec<Header>);
+
+impl Headers {
+    fn from_parsed(headers: &[httparse::Header]) -> Self {
+        Headers(
+            headers
+                .iter()
+                .map(|h| Header {
+                    name: h.name.to_string(),
+                    value: h.value.to_vec(),
+                })
+                .collect(),
+        )
+    }
+
+    /// The first value for `name`, if it is valid utf8
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name)
+            .next()
+            .and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    /// All values for `name`, in order
+    pub fn get_all<'a, 'n>(&'a self, name: &'n str) -> impl Iterator<Item = &'a [u8]> + 'n
+    where
+        'a: 'n,
+    {
+        self.0
+            .iter()
+            .filter(move |h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.as_slice())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get_all(name).next().is_some()
+    }
+
+    /// Whether any comma separated value of `name` equals `token` (case-insensitive)
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .filter_map(|v| std::str::from_utf8(v).ok())
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Comma separated values of `name` across all of its headers, trimmed
+    fn tokens(&self, name: &str) -> Vec<String> {
+        self.get_all(name)
+            .map(|v| String::from_utf8_lossy(v).to_string())
+            .flat_map(|v| {
+                v.split(',')
+                    .map(|t| t.trim().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|t| !t.is_empty())
+            .collect()
+    }
+
+    /// Keep only the first header named `name`, where it is
+    fn keep_first(&mut self, name: &str) {
+        let mut seen = false;
+        self.0.retain(|h| {
+            if !h.name.eq_ignore_ascii_case(name) {
+                return true;
+            }
+            !std::mem::replace(&mut seen, true)
+        });
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|h| !h.name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn append(&mut self, name: &str, value: impl Into<Vec<u8>>) {
+        self.0.push(Header {
+            name: name.to_string(),
+            value: value.into(),
+        });
+    }
+
+    /// Replace all values of `name` with a single `value`
+    pub fn set(&mut self, name: &str, value: impl Into<Vec<u8>>) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        for header in &self.0 {
+            out.extend_from_slice(header.name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(&header.value);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// How the body following a message head is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    Length(u64),
+    Chunked,
+}
+
+impl Headers {
+    /// Determine the body framing from `Transfer-Encoding` / `Content-Length`
+    pub fn body_kind(&self) -> Result<BodyKind, Error> {
+        if self.contains("transfer-encoding") {
+            return Ok(BodyKind::Chunked);
+        }
+
+        match self.get_all("content-length").next() {
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .map(BodyKind::Length)
+                .ok_or(Error::InvalidContentLength),
+            None => Ok(BodyKind::Length(0)),
+        }
+    }
+}
+
+/// The parsed head of an HTTP/1.x request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub version: u8,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    /// Parse a request head from the start of `buf`, returning the head and its length
+    /// or `None` if more data is needed.
+    pub fn parse(buf: &[u8]) -> Result<Option<(RequestHead, usize)>, Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+
+        let len = match req.parse(buf)? {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => return Ok(None),
+        };
+
+        let head = RequestHead {
+            method: req.method.unwrap_or_default().to_string(),
+            path: req.path.unwrap_or_default().to_string(),
+            version: req.version.unwrap_or(1),
+            headers: Headers::from_parsed(req.headers),
+        };
+
+        Ok(Some((head, len)))
+    }
+
+    /// Drop the hop-by-hop headers and reject what we and the agent's server could
+    /// read differently: conflicting `Host` or `Content-Length` headers, and
+    /// `Content-Length` along with `Transfer-Encoding`. Duplicates that agree are
+    /// folded into one.
+    pub fn sanitize(&mut self) -> Result<(), Error> {
+        let headers = &mut self.headers;
+
+        let connection = headers.tokens("connection");
+        for token in &connection {
+            if !CONNECTION_OPTIONS
+                .iter()
+                .any(|o| o.eq_ignore_ascii_case(token))
+                && !PROTECTED.iter().any(|p| p.eq_ignore_ascii_case(token))
+            {
+                headers.remove(token);
+            }
+        }
+        for name in HOP_BY_HOP {
+            headers.remove(name);
+        }
+        let options: Vec<_> = connection
+            .into_iter()
+            .filter(|t| CONNECTION_OPTIONS.iter().any(|o| o.eq_ignore_ascii_case(t)))
+            .collect();
+        if !options.iter().any(|o| o.eq_ignore_ascii_case("upgrade")) {
+            headers.remove("upgrade");
+        }
+        if options.is_empty() {
+            headers.remove("connection");
+        } else {
+            headers.set("Connection", options.join(", "));
+        }
+
+        let hosts = headers.tokens("host");
+        if hosts.len() > 1 {
+            if hosts.iter().any(|h| !h.eq_ignore_ascii_case(&hosts[0])) {
+                return Err(Error::Ambiguous("conflicting host headers"));
+            }
+            headers.keep_first("host");
+        }
+
+        let lengths = headers.tokens("content-length");
+        if lengths
+            .iter()
+            .any(|l| !l.bytes().all(|b| b.is_ascii_digit()))
+        {
+            return Err(Error::InvalidContentLength);
+        }
+        if lengths.iter().any(|l| *l != lengths[0]) {
+            return Err(Error::Ambiguous("conflicting content-length headers"));
+        }
+        if lengths.len() > 1 {
+            headers.set("Content-Length", lengths[0].as_str());
+        }
+
+        if headers.contains("transfer-encoding") {
+            if self.version == 0 {
+                return Err(Error::Ambiguous("transfer-encoding in http/1.0"));
+            }
+            if !lengths.is_empty() {
+                return Err(Error::Ambiguous("content-length with transfer-encoding"));
+            }
+            let codings = headers.tokens("transfer-encoding");
+            let chunked = |t: &String| t.eq_ignore_ascii_case("chunked");
+            if !codings.last().is_some_and(chunked)
+                || codings.iter().filter(|t| chunked(t)).count() > 1
+            {
+                return Err(Error::Ambiguous("transfer-encoding not ending in chunked"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether this request asks to switch the connection to another protocol
+    pub fn is_upgrade(&self) -> bool {
+        self.method.eq_ignore_ascii_case("CONNECT")
+            || (self.headers.contains("upgrade") && self.headers.has_token("connection", "upgrade"))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(256);
+        out.extend_from_slice(self.method.as_bytes());
+        out.push(b' ');
+        out.extend_from_slice(self.path.as_bytes());
+        out.extend_from_slice(format!(" HTTP/1.{}\r\n", self.version).as_bytes());
+        self.headers.write_to(&mut out);
+        out
+    }
+}
+
+/// The parsed head of an HTTP/1.x response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    pub version: u8,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    /// Parse a response head from the start of `buf`, returning the head and its length
+    /// or `None` if more data is needed.
+    pub fn parse(buf: &[u8]) -> Result<Option<(ResponseHead, usize)>, Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut res = httparse::Response::new(&mut headers);
+
+        let len = match res.parse(buf)? {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => return Ok(None),
+        };
+
+        let head = ResponseHead {
+            version: res.version.unwrap_or(1),
+            status: res.code.unwrap_or_default(),
+            reason: res.reason.unwrap_or_default().to_string(),
+            headers: Headers::from_parsed(res.headers),
+        };
+
+        Ok(Some((head, len)))
+    }
+
+    /// Settle the framing the visitor could read differently than we do: along with
+    /// `Transfer-Encoding`, `Content-Length` is dropped as the encoding decides, and
+    /// `Content-Length` headers that conflict are rejected. Duplicates that agree are
+    /// folded into one.
+    pub fn sanitize(&mut self) -> Result<(), Error> {
+        let headers = &mut self.headers;
+        if headers.contains("transfer-encoding") {
+            headers.remove("content-length");
+            return Ok(());
+        }
+
+        let lengths = headers.tokens("content-length");
+        if lengths.iter().any(|l| *l != lengths[0]) {
+            return Err(Error::Ambiguous("conflicting content-length headers"));
+        }
+        if lengths.len() > 1 {
+            headers.set("Content-Length", lengths[0].as_str());
+        }
+        Ok(())
+    }
+
+    /// Whether more responses follow for the same request, i.e. `100 Continue`
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.status) && self.status != 101
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(256);
+        out.extend_from_slice(
+            format!(
+                "HTTP/1.{} {} {}\r\n",
+                self.version, self.status, self.reason
+            )
+            .as_bytes(),
+        );
+        self.headers.write_to(&mut out);
+        out
+    }
+}
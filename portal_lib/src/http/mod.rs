@@ -0,0 +1,50 @@
+use thiserror::Error;
+
+mod head;
+pub use self::head::*;
+mod framer;
+pub use self::framer::*;
+mod response;
+pub use self::response::*;
+
+/// The maximum number of headers we parse in a message head
+pub const MAX_HEADERS: usize = 100;
+
+/// The default maximum size of a message head we buffer before giving up
+pub const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid http message: {0}")]
+    Parse(#[from] httparse::Error),
+
+    #[error("http message head is larger than {0} bytes")]
+    HeadTooLarge(usize),
+
+    #[error("http message body is larger than {0} bytes")]
+    BodyTooLarge(u64),
+
+    #[error("invalid content-length header")]
+    InvalidContentLength,
+
+    #[error("invalid chunked body encoding")]
+    InvalidChunk,
+
+    /// a message whose framing or host we could read differently than its recipient
+    #[error("ambiguous http request: {0}")]
+    Ambiguous(&'static str),
+
+    #[error("invalid http/2 frame")]
+    InvalidFrame,
+}
+
+impl Error {
+    /// The configured limit this message ran into, if any
+    pub fn exceeded_limit(&self) -> Option<(&'static str, u64)> {
+        match self {
+            Error::HeadTooLarge(limit) => Some(("max_header_size", *limit as u64)),
+            Error::BodyTooLarge(limit) => Some(("max_body_size", *limit)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! Follow the responses on a connection to learn where each one ends
+use super::*;
+use std::collections::VecDeque;
+
+/// Something we learned from response bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseEvent {
+    /// The final head answering the oldest open request
+    Head(ResponseHead),
+    /// The current response is complete, `size` bytes including its head
+    End { size: u64 },
+}
+
+/// A piece of a response stream, keeping all of its bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseFrame {
+    /// A response head, including interim ones like `100 Continue`
+    Head(ResponseHead),
+    /// Raw body bytes (including chunk framing), or raw bytes after an upgrade
+    Body(Vec<u8>),
+    /// The current final response is complete, `size` bytes including its head
+    End { size: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Head,
+    Length(u64),
+    Chunked(Chunk),
+    /// The body is delimited by the connection closing, or the protocol switched
+    UntilClose,
+}
+
+/// What a request tells us about the response to it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Expected {
+    /// a `HEAD` request, answered without a body
+    head: bool,
+    /// a `CONNECT` request, whose 2xx response starts a tunnel
+    connect: bool,
+    /// the request asked to switch protocols, see [`ResponseFramer::take_upgrade`]
+    upgrade: bool,
+}
+
+/// Incrementally finds the boundaries of the responses in a stream of bytes.
+/// It only observes: the bytes themselves are forwarded untouched elsewhere.
+#[derive(Debug)]
+pub struct ResponseFramer {
+    buf: Vec<u8>,
+    state: State,
+    /// the requests awaiting a response, oldest first
+    expected: VecDeque<Expected>,
+    /// whether the last upgrade switched protocols, until it's taken
+    upgrade: Option<bool>,
+    /// bytes of the current response so far
+    size: u64,
+}
+
+impl Default for ResponseFramer {
+    fn default() -> Self {
+        Self {
+            buf: Vec::new(),
+            state: State::Head,
+            expected: VecDeque::new(),
+            upgrade: None,
+            size: 0,
+        }
+    }
+}
+
+impl ResponseFramer {
+    /// Announce the next request on the connection, whose response we should expect
+    pub fn expect(&mut self, method: &str) {
+        self.expected.push_back(Expected {
+            head: method.eq_ignore_ascii_case("HEAD"),
+            connect: method.eq_ignore_ascii_case("CONNECT"),
+            upgrade: false,
+        });
+    }
+
+    /// Announce a request asking to switch protocols, i.e. a websocket handshake or
+    /// `CONNECT`, whose response tells [`ResponseFramer::take_upgrade`] if it did
+    pub fn expect_upgrade(&mut self, method: &str) {
+        self.expect(method);
+        if let Some(expected) = self.expected.back_mut() {
+            expected.upgrade = true;
+        }
+    }
+
+    /// Whether the connection switched protocols, once the response to an upgrade
+    /// arrived. Until it's taken, nothing that follows the upgrade is a request.
+    pub fn take_upgrade(&mut self) -> Option<bool> {
+        self.upgrade.take()
+    }
+
+    /// Feed response bytes and collect what they tell us
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<ResponseEvent>, Error> {
+        Ok(self
+            .push_frames(data)?
+            .into_iter()
+            .filter_map(|frame| match frame {
+                ResponseFrame::Head(head) if !head.is_informational() => {
+                    Some(ResponseEvent::Head(head))
+                }
+                ResponseFrame::End { size } => Some(ResponseEvent::End { size }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Feed response bytes and split them into frames, which together are the
+    /// bytes fed so far minus those of an incomplete head
+    pub fn push_frames(&mut self, data: &[u8]) -> Result<Vec<ResponseFrame>, Error> {
+        let mut input = data;
+        let mut frames = vec![];
+        let mut body = vec![];
+
+        loop {
+            match self.state {
+                State::Head => {
+                    let before = self.buf.len() + input.len();
+                    let Some(head) = take_head(
+                        &mut self.buf,
+                        &mut input,
+                        MAX_HEAD_SIZE,
+                        ResponseHead::parse,
+                    )?
+                    else {
+                        break;
+                    };
+                    self.size += (before - input.len()) as u64;
+
+                    if head.is_informational() {
+                        frames.push(ResponseFrame::Head(head));
+                        continue;
+                    }
+
+                    let expected = self.expected.pop_front().unwrap_or_default();
+                    let switched = head.status == 101
+                        || (expected.connect && (200..300).contains(&head.status));
+                    if expected.upgrade {
+                        self.upgrade = Some(switched);
+                    }
+                    self.state = if switched {
+                        State::UntilClose
+                    } else if expected.head || head.status == 204 || head.status == 304 {
+                        State::Length(0)
+                    } else if head.headers.contains("transfer-encoding") {
+                        State::Chunked(Chunk::Size)
+                    } else if head.headers.contains("content-length") {
+                        match head.headers.body_kind()? {
+                            BodyKind::Length(n) => State::Length(n),
+                            BodyKind::Chunked => State::Chunked(Chunk::Size),
+                        }
+                    } else {
+                        State::UntilClose
+                    };
+                    frames.push(ResponseFrame::Head(head));
+                }
+                State::Length(0) => {
+                    flush_body(&mut frames, &mut body);
+                    frames.push(ResponseFrame::End {
+                        size: std::mem::take(&mut self.size),
+                    });
+                    self.state = State::Head;
+                }
+                State::Length(remaining) => {
+                    if input.is_empty() {
+                        break;
+                    }
+                    let n = remaining.min(input.len() as u64);
+                    let (taken, rest) = input.split_at(n as usize);
+                    body.extend_from_slice(taken);
+                    input = rest;
+                    self.size += n;
+                    self.state = State::Length(remaining - n);
+                }
+                State::Chunked(chunk) => {
+                    let before = body.len();
+                    let Some(step) = step_chunk(&mut self.buf, &mut input, chunk, &mut body)?
+                    else {
+                        break;
+                    };
+                    self.size += (body.len() - before) as u64;
+                    self.state = match step.next() {
+                        Some(chunk) => State::Chunked(chunk),
+                        None => State::Length(0),
+                    };
+                }
+                State::UntilClose => {
+                    self.size += input.len() as u64;
+                    body.extend_from_slice(input);
+                    break;
+                }
+            }
+        }
+
+        flush_body(&mut frames, &mut body);
+        Ok(frames)
+    }
+
+    /// The connection closed: ends a response delimited by it, if one is open
+    pub fn close(&mut self) -> Option<ResponseEvent> {
+        match self.state {
+            State::UntilClose => {
+                self.state = State::Head;
+                Some(ResponseEvent::End {
+                    size: std::mem::take(&mut self.size),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn flush_body(frames: &mut Vec<ResponseFrame>, body: &mut Vec<u8>) {
+    if !body.is_empty() {
+        frames.push(ResponseFrame::Body(std::mem::take(body)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statuses_and_sizes(events: &[ResponseEvent]) -> Vec<(Option<u16>, Option<u64>)> {
+        events
+            .iter()
+            .map(|e| match e {
+                ResponseEvent::Head(head) => (Some(head.status), None),
+                ResponseEvent::End { size } => (None, Some(*size)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pipelined_responses() {
+        let mut framer = ResponseFramer::default();
+        framer.expect("GET");
+        framer.expect("HEAD");
+        framer.expect("POST");
+
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloHTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        let mut events = vec![];
+        for byte in raw.iter() {
+            events.extend(framer.push(&[*byte]).unwrap());
+        }
+
+        assert_eq!(
+            statuses_and_sizes(&events),
+            vec![
+                (Some(200), None),
+                (None, Some(43)),
+                (Some(200), None),
+                (None, Some(38)),
+                (Some(201), None),
+                (None, Some(92)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_close_delimited() {
+        let mut framer = ResponseFramer::default();
+        framer.expect("GET");
+        let events = framer.push(b"HTTP/1.0 200 OK\r\n\r\nsome body").unwrap();
+        assert_eq!(statuses_and_sizes(&events), vec![(Some(200), None)]);
+        framer.push(b" and more").unwrap();
+        assert_eq!(framer.close(), Some(ResponseEvent::End { size: 37 }));
+    }
+
+    #[test]
+    fn test_upgrades() {
+        let mut framer = ResponseFramer::default();
+        framer.expect("GET");
+        framer.expect_upgrade("GET");
+        framer
+            .push(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi")
+            .unwrap();
+        assert_eq!(framer.take_upgrade(), None);
+        framer
+            .push(b"HTTP/1.1 426 Upgrade Required\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        assert_eq!(framer.take_upgrade(), Some(false));
+        assert_eq!(framer.take_upgrade(), None);
+
+        // a tunnel's bytes aren't a body, whatever the head says
+        framer.expect_upgrade("CONNECT");
+        let events = framer
+            .push(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n\x16\x03\x01")
+            .unwrap();
+        assert_eq!(statuses_and_sizes(&events), vec![(Some(200), None)]);
+        assert_eq!(framer.take_upgrade(), Some(true));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let head = |raw: &str| ResponseHead::parse(raw.as_bytes()).unwrap().unwrap().0;
+
+        let mut both =
+            head("HTTP/1.1 200 OK\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n");
+        both.sanitize().unwrap();
+        assert!(!both.headers.contains("content-length"));
+
+        let mut twice = head("HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n");
+        twice.sanitize().unwrap();
+        assert_eq!(twice.headers.get_all("content-length").count(), 1);
+
+        let mut conflicting =
+            head("HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n");
+        assert!(matches!(conflicting.sanitize(), Err(Error::Ambiguous(_))));
+    }
+
+    #[test]
+    fn test_frames_keep_the_bytes() {
+        let mut framer = ResponseFramer::default();
+        framer.expect("POST");
+        framer.expect("GET");
+
+        let raw = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\nHTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\nnope";
+        let mut frames = vec![];
+        for part in raw.chunks(7) {
+            frames.extend(framer.push_frames(part).unwrap());
+        }
+
+        let mut bytes = vec![];
+        let mut ends = 0;
+        for frame in frames {
+            match frame {
+                ResponseFrame::Head(head) => bytes.extend(head.to_bytes()),
+                ResponseFrame::Body(body) => bytes.extend(body),
+                ResponseFrame::End { .. } => ends += 1,
+            }
+        }
+        assert_eq!(bytes, raw.to_vec());
+        assert_eq!(ends, 2);
+    }
+}
@@ -1,9 +1,16 @@
 use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
 use std::fmt;
 
+pub mod http;
+#[cfg(feature = "quic")]
+pub mod quic;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(transparent)]
 pub struct SecretKey(pub String);
@@ -34,13 +41,52 @@ pub enum ServerHello {
         sub_domain: String,
         hostname: String,
         client_id: ClientId,
+        /// whether we'll send a `ControlPacket::Request` for each proxied request
+        #[serde(default)]
+        request_log: bool,
+        /// where visitors reach the tunnel, absent from servers that only speak http
+        #[serde(default)]
+        endpoint: Option<Endpoint>,
+        /// seconds until we close the tunnel, for tunnels that don't live forever
+        #[serde(default)]
+        expires_in: Option<u64>,
+        /// a newer agent than ours, from servers that know of one
+        #[serde(default)]
+        update_available: Option<UpdateAvailable>,
+        /// data packets of each stream we may send ahead of the server's credits, from
+        /// servers that flow control the streams of agents that asked them to
+        #[serde(default)]
+        stream_window: Option<u32>,
     },
     SubDomainInUse,
+    /// like `SubDomainInUse`, with sub-domains we could have instead, for agents that
+    /// accept suggestions
+    SubDomainTaken {
+        suggestions: Vec<String>,
+    },
     InvalidSubDomain,
     AuthFailed,
     Error(String),
+    /// our subdomain is placed on another instance, reconnect to it by sending
+    /// its id in the `INSTANCE_HEADER`
+    Redirect {
+        instance_id: String,
+        /// `host:port` of the control server to reconnect to instead, i.e. one in the
+        /// region we prefer, with no instance to ask for if `instance_id` is empty
+        #[serde(default)]
+        address: Option<String>,
+    },
+    /// our version is older than the oldest the server lets in
+    VersionTooOld {
+        minimum: String,
+        /// where to get a newer agent
+        download_url: String,
+    },
 }
 
+/// Header asking the edge to route the wormhole connection to a specific instance
+pub const INSTANCE_HEADER: &str = "fly-force-instance-id";
+
 impl ServerHello {
     #[allow(unused)]
     pub fn random_domain() -> String {
@@ -54,7 +100,7 @@ impl ServerHello {
 
     #[allow(unused)]
     pub fn prefixed_random_domain(prefix: &str) -> String {
-        format!("{}", prefix)
+        prefix.to_string()
     }
 }
 
@@ -65,6 +111,206 @@ pub struct ClientHello {
     pub sub_domain: Option<String>,
     pub client_type: ClientType,
     pub reconnect_token: Option<ReconnectToken>,
+    /// the agent's version, i.e. `0.1.20`
+    #[serde(default)]
+    pub version: Option<String>,
+    /// a human readable name for the agent, i.e. its hostname
+    #[serde(default)]
+    pub name: Option<String>,
+    /// the id the agent keeps across runs on its machine
+    #[serde(default)]
+    pub agent_id: Option<String>,
+    /// ask for a `ControlPacket::Request` for each proxied request
+    #[serde(default)]
+    pub request_log: bool,
+    /// when to alert about our tunnel, overriding the server's thresholds
+    #[serde(default)]
+    pub alerts: AlertThresholds,
+    /// we understand `ServerHello::Redirect`
+    #[serde(default)]
+    pub accepts_redirect: bool,
+    /// we were redirected here, so don't redirect us again
+    #[serde(default)]
+    pub redirected: bool,
+    /// we understand `ServerHello::SubDomainTaken`
+    #[serde(default)]
+    pub accepts_suggestions: bool,
+    /// what visitors speak to reach us, http unless asked otherwise
+    #[serde(default)]
+    pub service: ServiceInfo,
+    /// have the server queue our visitors' requests while we're offline, and deliver
+    /// them once we're back
+    #[serde(default)]
+    pub queue_offline: bool,
+    /// let the server cache our cacheable responses to GET requests
+    #[serde(default)]
+    pub cache: bool,
+    /// also receive a copy of every request to this sub-domain of ours, whose
+    /// responses are dropped
+    #[serde(default)]
+    pub mirror_of: Option<String>,
+    /// have the server write our visitors' requests to its access log, when it only
+    /// logs the tunnels that ask
+    #[serde(default)]
+    pub access_log: bool,
+    /// the region we'd rather be served from, i.e. the closest, for servers that
+    /// redirect us to it
+    #[serde(default)]
+    pub preferred_region: Option<String>,
+    /// the instance we'd rather be served by
+    #[serde(default)]
+    pub preferred_instance: Option<String>,
+    /// send us `ControlPacket::Stats` with our tunnel's traffic every few seconds
+    #[serde(default)]
+    pub stats: bool,
+    /// have the server add CORS headers to our responses, and answer preflight requests
+    #[serde(default)]
+    pub cors: Option<CorsOptions>,
+    /// have the server redirect visitors coming over plain http to https
+    #[serde(default)]
+    pub https_redirect: bool,
+    /// have the server add `Strict-Transport-Security` to our responses over https
+    #[serde(default)]
+    pub hsts: bool,
+    /// have the server close our tunnel this many seconds after opening it, i.e. for a
+    /// temporary demo link
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// we send no more of a stream's data than the server credits us with, so it
+    /// doesn't have to hold up our other streams for a visitor that reads slowly
+    #[serde(default)]
+    pub flow_control: bool,
+}
+
+/// What visitors speak to reach a tunnel
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Http,
+    /// http with TLS terminated in front of the server
+    Https,
+    /// raw TCP on a port of the tunnel's own
+    Tcp,
+    /// TLS passed through to the agent untouched, routed by the name visitors ask for
+    Tls,
+    Udp,
+}
+
+impl Protocol {
+    /// Whether the server reads the requests visitors send
+    pub fn is_http(self) -> bool {
+        matches!(self, Protocol::Http | Protocol::Https)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Http => "http",
+            Protocol::Https => "https",
+            Protocol::Tcp => "tcp",
+            Protocol::Tls => "tls",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(Protocol::Http),
+            "https" => Ok(Protocol::Https),
+            "tcp" => Ok(Protocol::Tcp),
+            "tls" => Ok(Protocol::Tls),
+            "udp" => Ok(Protocol::Udp),
+            _ => Err(format!(
+                "invalid protocol {}, expected http, https, tcp, tls or udp",
+                s
+            )),
+        }
+    }
+}
+
+/// The service an agent exposes and how visitors reach it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServiceInfo {
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// the public port asked for, for `tcp` and `udp`; any free one otherwise
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// path prefixes of an http tunnel the agent sends to other local ports
+    #[serde(default)]
+    pub routes: Vec<PathRoute>,
+}
+
+/// Requests whose path starts with `prefix` go to another local port than the rest
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PathRoute {
+    /// i.e. `/api`, without a trailing slash
+    pub prefix: String,
+    pub port: u16,
+}
+
+/// Where visitors reach a tunnel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub protocol: Protocol,
+    pub host: String,
+    pub port: u16,
+}
+
+impl fmt::Display for Endpoint {
+    /// As a url, leaving out the port web browsers assume
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.protocol, self.port) {
+            (Protocol::Http, 80) | (Protocol::Https, 443) => {
+                write!(f, "{}://{}", self.protocol, self.host)
+            }
+            _ => write!(f, "{}://{}:{}", self.protocol, self.host, self.port),
+        }
+    }
+}
+
+/// When the server alerts about a tunnel's health, unset thresholds never fire
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct AlertThresholds {
+    /// 95th percentile response time in milliseconds
+    #[serde(default)]
+    pub p95_latency: Option<u64>,
+    /// share of responses with a 5xx status, between 0 and 1
+    #[serde(default)]
+    pub error_rate: Option<f64>,
+}
+
+impl AlertThresholds {
+    /// Our thresholds where set, `other`'s otherwise
+    pub fn or(self, other: AlertThresholds) -> AlertThresholds {
+        AlertThresholds {
+            p95_latency: self.p95_latency.or(other.p95_latency),
+            error_rate: self.error_rate.or(other.error_rate),
+        }
+    }
+}
+
+/// The CORS headers the server adds to a tunnel's responses, so browser frontends
+/// hosted elsewhere may call it
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CorsOptions {
+    /// the origins allowed to call the tunnel, i.e. `https://app.example.com`, any
+    /// origin if empty
+    #[serde(default)]
+    pub origins: Vec<String>,
+    /// let browsers send cookies and credentials along
+    #[serde(default)]
+    pub credentials: bool,
 }
 
 impl ClientHello {
@@ -74,6 +320,27 @@ impl ClientHello {
             client_type: typ,
             sub_domain,
             reconnect_token: None,
+            version: None,
+            name: None,
+            agent_id: None,
+            request_log: false,
+            alerts: AlertThresholds::default(),
+            accepts_redirect: false,
+            redirected: false,
+            accepts_suggestions: false,
+            service: ServiceInfo::default(),
+            queue_offline: false,
+            cache: false,
+            mirror_of: None,
+            access_log: false,
+            stats: false,
+            cors: None,
+            https_redirect: false,
+            hsts: false,
+            ttl: None,
+            flow_control: false,
+            preferred_region: None,
+            preferred_instance: None,
         }
     }
 
@@ -83,6 +350,27 @@ impl ClientHello {
             sub_domain: None,
             client_type: ClientType::Anonymous,
             reconnect_token: Some(reconnect_token),
+            version: None,
+            name: None,
+            agent_id: None,
+            request_log: false,
+            alerts: AlertThresholds::default(),
+            accepts_redirect: false,
+            redirected: false,
+            accepts_suggestions: false,
+            service: ServiceInfo::default(),
+            queue_offline: false,
+            cache: false,
+            mirror_of: None,
+            access_log: false,
+            stats: false,
+            cors: None,
+            https_redirect: false,
+            hsts: false,
+            ttl: None,
+            flow_control: false,
+            preferred_region: None,
+            preferred_instance: None,
         }
     }
 }
@@ -125,6 +413,21 @@ impl StreamId {
     }
 }
 
+impl std::str::FromStr for StreamId {
+    type Err = ();
+
+    /// Parse the `stream_...` form produced by `Display`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s.strip_prefix("stream_").ok_or(())?;
+        let id = general_purpose::URL_SAFE_NO_PAD
+            .decode(encoded)
+            .map_err(|_| ())?
+            .try_into()
+            .map_err(|_| ())?;
+        Ok(StreamId(id))
+    }
+}
+
 impl fmt::Display for StreamId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -135,13 +438,180 @@ impl fmt::Display for StreamId {
     }
 }
 
+/// A request the server proxied through a tunnel, and how it went
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RequestLogEntry {
+    /// identifies the request in the server's log, i.e. to replay it
+    #[serde(default)]
+    pub id: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// milliseconds since the unix epoch when the request head arrived
+    pub timestamp: u64,
+    /// milliseconds until the response was complete
+    pub duration: u64,
+    /// bytes including the head
+    pub request_size: u64,
+    pub response_size: u64,
+    /// whether this was a replay of a captured request
+    #[serde(default)]
+    pub replay: bool,
+}
+
+/// Traffic of every stream of a tunnel on the server the agent is connected to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TunnelStats {
+    /// bytes read from visitors
+    pub bytes_in: u64,
+    /// bytes written to visitors
+    pub bytes_out: u64,
+    /// streams open right now
+    pub connections: u64,
+}
+
+/// A share link the agent asks for, letting visitors reach its tunnel for a while
+/// or a number of visitors without learning its sub-domain
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShareRequest {
+    /// picked by the agent to match the server's answer to its request
+    #[serde(default)]
+    pub id: String,
+    /// how many visitors may use the link, any number if not set
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+    /// seconds the link works for, for as long as the tunnel is open if not set
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+/// The server's answer to a `ShareRequest`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShareLink {
+    /// the id of the request this answers
+    pub id: String,
+    /// where visitors use the link, empty if it was refused
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+    /// seconds until the link stops working, if it does
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    /// why there's no link
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// How much a notice from the operators matters
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NoticeLevel {
+    #[default]
+    Info,
+    /// i.e. an upcoming restart
+    Warning,
+    /// the tunnel is about to go away
+    Critical,
+}
+
+impl NoticeLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NoticeLevel::Info => "info",
+            NoticeLevel::Warning => "warning",
+            NoticeLevel::Critical => "critical",
+        }
+    }
+}
+
+impl fmt::Display for NoticeLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for NoticeLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "info" => Ok(NoticeLevel::Info),
+            "warning" => Ok(NoticeLevel::Warning),
+            "critical" => Ok(NoticeLevel::Critical),
+            _ => Err(format!(
+                "invalid notice level {}, expected info, warning or critical",
+                s
+            )),
+        }
+    }
+}
+
+/// The payload of a `ControlPacket::Notice`
+#[derive(Serialize, Deserialize)]
+struct NoticeBody {
+    #[serde(default)]
+    level: NoticeLevel,
+    message: String,
+}
+
+/// A newer agent version than ours the server knows of
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UpdateAvailable {
+    /// i.e. `0.1.21`
+    pub version: String,
+    /// where to get it
+    pub download_url: String,
+}
+
+/// Where an operator moved our tunnel to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SubDomainTransfer {
+    pub sub_domain: String,
+    pub hostname: String,
+    /// where visitors reach us now, from servers that tell
+    #[serde(default)]
+    pub endpoint: Option<Endpoint>,
+}
+
 #[derive(Debug, Clone)]
 pub enum ControlPacket {
     Init(StreamId),
-    Data(StreamId, Vec<u8>),
+    Data(StreamId, Bytes),
     Refused(StreamId),
     End(StreamId),
     Ping(Option<ReconnectToken>),
+    Request(StreamId, RequestLogEntry),
+    /// ask the server to replay the captured request with this id
+    Replay(String),
+    /// (un)subscribe to a `ControlPacket::Request` for every request on our tunnel,
+    /// including those served by other agents
+    Tail(bool),
+    /// the server is shutting down, reconnect so we get to another instance
+    Drain,
+    /// the traffic of our tunnel so far, sent every few seconds if we asked for it
+    Stats(TunnelStats),
+    /// our tunnel reached the end of its lifetime and is closed, don't reconnect
+    Expired,
+    /// ask the server for a share link to our tunnel
+    Share(ShareRequest),
+    /// the share link the server made for us, or why it didn't
+    ShareLink(ShareLink),
+    /// an operator closed our tunnel for this reason, don't reconnect
+    Disconnected(String),
+    /// an operator moved our tunnel to another sub-domain, keep it when reconnecting
+    Transferred(SubDomainTransfer),
+    /// a message from the operators for whoever runs the agent, i.e. of an upcoming
+    /// restart
+    Notice {
+        level: NoticeLevel,
+        message: String,
+    },
+    /// a newer agent than ours was released, sent now and then until we upgrade
+    UpdateAvailable(UpdateAvailable),
+    /// the server passed on this many more data packets of the stream, so we may send
+    /// as many more, if we flow control
+    Credit(StreamId, u32),
 }
 
 pub const PING_INTERVAL: u64 = 30;
@@ -153,7 +623,14 @@ impl ControlPacket {
     pub fn serialize(self) -> Vec<u8> {
         match self {
             ControlPacket::Init(sid) => [vec![0x01], sid.0.to_vec()].concat(),
-            ControlPacket::Data(sid, data) => [vec![0x02], sid.0.to_vec(), data].concat(),
+            ControlPacket::Data(sid, data) => {
+                // the one copy into the websocket frame
+                let mut packet = Vec::with_capacity(9 + data.len());
+                packet.push(0x02);
+                packet.extend_from_slice(&sid.0);
+                packet.extend_from_slice(&data);
+                packet
+            }
             ControlPacket::Refused(sid) => [vec![0x03], sid.0.to_vec()].concat(),
             ControlPacket::End(sid) => [vec![0x04], sid.0.to_vec()].concat(),
             ControlPacket::Ping(tok) => {
@@ -162,6 +639,62 @@ impl ControlPacket {
                 });
                 [vec![0x05], data].concat()
             }
+            ControlPacket::Request(sid, entry) => [
+                vec![0x06],
+                sid.0.to_vec(),
+                serde_json::to_vec(&entry).unwrap_or_default(),
+            ]
+            .concat(),
+            ControlPacket::Replay(id) => {
+                [vec![0x07], EMPTY_STREAM.0.to_vec(), id.into_bytes()].concat()
+            }
+            ControlPacket::Tail(enabled) => {
+                [vec![0x08], EMPTY_STREAM.0.to_vec(), vec![enabled as u8]].concat()
+            }
+            ControlPacket::Drain => [vec![0x09], EMPTY_STREAM.0.to_vec()].concat(),
+            ControlPacket::Stats(stats) => [
+                vec![0x0A],
+                EMPTY_STREAM.0.to_vec(),
+                serde_json::to_vec(&stats).unwrap_or_default(),
+            ]
+            .concat(),
+            ControlPacket::Expired => [vec![0x0B], EMPTY_STREAM.0.to_vec()].concat(),
+            ControlPacket::Share(request) => [
+                vec![0x0C],
+                EMPTY_STREAM.0.to_vec(),
+                serde_json::to_vec(&request).unwrap_or_default(),
+            ]
+            .concat(),
+            ControlPacket::ShareLink(link) => [
+                vec![0x0D],
+                EMPTY_STREAM.0.to_vec(),
+                serde_json::to_vec(&link).unwrap_or_default(),
+            ]
+            .concat(),
+            ControlPacket::Disconnected(reason) => {
+                [vec![0x0E], EMPTY_STREAM.0.to_vec(), reason.into_bytes()].concat()
+            }
+            ControlPacket::Transferred(transfer) => [
+                vec![0x0F],
+                EMPTY_STREAM.0.to_vec(),
+                serde_json::to_vec(&transfer).unwrap_or_default(),
+            ]
+            .concat(),
+            ControlPacket::Notice { level, message } => [
+                vec![0x10],
+                EMPTY_STREAM.0.to_vec(),
+                serde_json::to_vec(&NoticeBody { level, message }).unwrap_or_default(),
+            ]
+            .concat(),
+            ControlPacket::UpdateAvailable(update) => [
+                vec![0x11],
+                EMPTY_STREAM.0.to_vec(),
+                serde_json::to_vec(&update).unwrap_or_default(),
+            ]
+            .concat(),
+            ControlPacket::Credit(sid, packets) => {
+                [vec![0x12], sid.0.to_vec(), packets.to_be_bytes().to_vec()].concat()
+            }
         }
     }
 
@@ -172,10 +705,38 @@ impl ControlPacket {
             ControlPacket::Data(_, _) => "STREAM DATA",
             ControlPacket::Refused(_) => "REFUSED",
             ControlPacket::End(_) => "END STREAM",
+            ControlPacket::Request(_, _) => "REQUEST LOG",
+            ControlPacket::Replay(_) => "REPLAY",
+            ControlPacket::Tail(_) => "TAIL",
+            ControlPacket::Drain => "DRAIN",
+            ControlPacket::Stats(_) => "STATS",
+            ControlPacket::Expired => "EXPIRED",
+            ControlPacket::Share(_) => "SHARE",
+            ControlPacket::ShareLink(_) => "SHARE_LINK",
+            ControlPacket::Disconnected(_) => "DISCONNECTED",
+            ControlPacket::Transferred(_) => "TRANSFERRED",
+            ControlPacket::Notice { .. } => "NOTICE",
+            ControlPacket::UpdateAvailable(_) => "UPDATE_AVAILABLE",
+            ControlPacket::Credit(_, _) => "CREDIT",
         }
     }
 
-    pub fn deserialize(data: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Whether the packet is about the connection rather than one of its streams, so it
+    /// may overtake stream data queued before it. A stream's own packets, its end
+    /// included, have to arrive in order.
+    pub fn is_control(&self) -> bool {
+        !matches!(
+            self,
+            ControlPacket::Init(_)
+                | ControlPacket::Data(_, _)
+                | ControlPacket::Refused(_)
+                | ControlPacket::End(_)
+        )
+    }
+
+    /// Parse a packet, the data of a `ControlPacket::Data` sharing `data`'s buffer
+    pub fn deserialize(data: impl Into<Bytes>) -> Result<Self, Box<dyn std::error::Error>> {
+        let data = data.into();
         if data.len() < 9 {
             return Err("invalid DataPacket, missing stream id".into());
         }
@@ -186,7 +747,7 @@ impl ControlPacket {
 
         let packet = match data[0] {
             0x01 => ControlPacket::Init(stream_id),
-            0x02 => ControlPacket::Data(stream_id, data[9..].to_vec()),
+            0x02 => ControlPacket::Data(stream_id, data.slice(9..)),
             0x03 => ControlPacket::Refused(stream_id),
             0x04 => ControlPacket::End(stream_id),
             0x05 => {
@@ -198,6 +759,28 @@ impl ControlPacket {
                     )))
                 }
             }
+            0x06 => ControlPacket::Request(stream_id, serde_json::from_slice(&data[9..])?),
+            0x07 => ControlPacket::Replay(String::from_utf8_lossy(&data[9..]).to_string()),
+            0x08 => ControlPacket::Tail(data.get(9) == Some(&1)),
+            0x09 => ControlPacket::Drain,
+            0x0A => ControlPacket::Stats(serde_json::from_slice(&data[9..])?),
+            0x0B => ControlPacket::Expired,
+            0x0C => ControlPacket::Share(serde_json::from_slice(&data[9..])?),
+            0x0D => ControlPacket::ShareLink(serde_json::from_slice(&data[9..])?),
+            0x0E => ControlPacket::Disconnected(String::from_utf8_lossy(&data[9..]).to_string()),
+            0x0F => ControlPacket::Transferred(serde_json::from_slice(&data[9..])?),
+            0x10 => {
+                let NoticeBody { level, message } = serde_json::from_slice(&data[9..])?;
+                ControlPacket::Notice { level, message }
+            }
+            0x11 => ControlPacket::UpdateAvailable(serde_json::from_slice(&data[9..])?),
+            0x12 => {
+                let packets = data
+                    .get(9..13)
+                    .and_then(|packets| packets.try_into().ok())
+                    .ok_or("invalid credit, missing the packet count")?;
+                ControlPacket::Credit(stream_id, u32::from_be_bytes(packets))
+            }
             _ => return Err("invalid control byte in DataPacket".into()),
         };
 
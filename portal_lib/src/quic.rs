@@ -0,0 +1,331 @@
+//! The control connection between an agent and a server over QUIC rather than a
+//! websocket. The handshake and the packets not about a stream go over the stream
+//! the agent opens first, and each tunnel stream's packets over a QUIC stream of
+//! their own, so a lost datagram only holds up the stream it belongs to. QUIC
+//! connections outlive the agent's address changing, i.e. switching networks.
+use crate::{ControlPacket, StreamId};
+use bytes::{Bytes, BytesMut};
+use futures::stream::{BoxStream, SelectAll};
+use futures::{FutureExt, SinkExt, Stream, StreamExt};
+use quinn::{Connection, RecvStream, SendStream, TransportConfig, VarInt};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+/// The protocol agents and servers agree on during the TLS handshake
+pub const ALPN: &[u8] = b"portal-control/1";
+
+/// Largest packet we take from the other side
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Streams each side may have open toward the other at once, past which packets
+/// of new streams share the control stream
+const MAX_STREAMS: u32 = 1024;
+
+/// Often enough to keep NAT mappings alive and notice a dead path early
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long the other side may go silent before we consider it gone
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a connection we're done with lingers for the other side to get
+/// what we sent last, i.e. why we turned it away
+const LINGER: Duration = Duration::from_secs(5);
+
+/// The transport settings both sides use
+pub fn transport() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+        .max_idle_timeout(Some(
+            IDLE_TIMEOUT
+                .try_into()
+                .expect("the idle timeout is in range"),
+        ))
+        .max_concurrent_bidi_streams(VarInt::from_u32(1))
+        .max_concurrent_uni_streams(VarInt::from_u32(MAX_STREAMS));
+    Arc::new(transport)
+}
+
+fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME_SIZE)
+        .new_codec()
+}
+
+/// Both directions of a control connection
+pub struct QuicControl {
+    sender: QuicSender,
+    receiver: QuicReceiver,
+}
+
+impl QuicControl {
+    /// Open the control stream of a connection we made
+    pub async fn open(connection: Connection) -> io::Result<Self> {
+        let (send, recv) = connection.open_bi().await?;
+        Ok(Self::new(connection, send, recv))
+    }
+
+    /// Accept the control stream of a connection made to us
+    pub async fn accept(connection: Connection) -> io::Result<Self> {
+        let (send, recv) = connection.accept_bi().await?;
+        Ok(Self::new(connection, send, recv))
+    }
+
+    fn new(connection: Connection, send: SendStream, recv: RecvStream) -> Self {
+        QuicControl {
+            sender: QuicSender {
+                connection: connection.clone(),
+                control: FramedWrite::new(send, codec()),
+                streams: HashMap::new(),
+            },
+            receiver: QuicReceiver::new(connection, recv),
+        }
+    }
+
+    /// Where the other side is now, which changes when it migrates
+    pub fn remote_address(&self) -> SocketAddr {
+        self.sender.connection.remote_address()
+    }
+
+    /// Send a handshake message
+    pub async fn send_frame(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        self.sender.send_frame(frame).await
+    }
+
+    /// The next message, `None` once the other side hung up
+    pub async fn next(&mut self) -> Option<io::Result<Bytes>> {
+        self.receiver.next().await
+    }
+
+    pub fn split(self) -> (QuicSender, QuicReceiver) {
+        (self.sender, self.receiver)
+    }
+}
+
+/// Sends packets, each stream's over a QUIC stream of its own
+pub struct QuicSender {
+    connection: Connection,
+    control: FramedWrite<SendStream, LengthDelimitedCodec>,
+    /// the streams we sent packets of, `None` for those riding the control stream
+    streams: HashMap<StreamId, Option<FramedWrite<SendStream, LengthDelimitedCodec>>>,
+}
+
+impl QuicSender {
+    /// Send a message over the control stream
+    pub async fn send_frame(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        self.control.send(Bytes::from(frame)).await
+    }
+
+    pub async fn send(&mut self, packet: ControlPacket) -> io::Result<()> {
+        let (stream_id, last) = match &packet {
+            ControlPacket::Init(id) | ControlPacket::Data(id, _) => (id.clone(), false),
+            ControlPacket::Refused(id) | ControlPacket::End(id) => (id.clone(), true),
+            _ => return self.send_frame(packet.serialize()).await,
+        };
+
+        if !self.streams.contains_key(&stream_id) {
+            // open a stream only when we're allowed to right away, rather than
+            // holding up every other stream waiting for one to end
+            let stream = match (last, self.connection.open_uni().now_or_never()) {
+                (false, Some(Ok(send))) => Some(FramedWrite::new(send, codec())),
+                _ => None,
+            };
+            self.streams.insert(stream_id.clone(), stream);
+        }
+
+        let frame = Bytes::from(packet.serialize());
+        let result = match self.streams.get_mut(&stream_id) {
+            Some(Some(stream)) => stream.send(frame).await,
+            _ => self.control.send(frame).await,
+        };
+        if last {
+            if let Some(Some(mut stream)) = self.streams.remove(&stream_id) {
+                let _ = stream.get_mut().finish();
+            }
+        }
+        result
+    }
+
+    /// Hang up once what we sent was delivered
+    pub async fn close(&mut self) {
+        let _ = self.control.close().await;
+    }
+}
+
+impl Drop for QuicSender {
+    fn drop(&mut self) {
+        // dropping the last handle closes the connection at once, dropping what
+        // the other side didn't acknowledge yet
+        let control = self.control.get_mut();
+        let _ = control.finish();
+        let delivered = control.stopped();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(tokio::time::timeout(LINGER, delivered));
+        }
+    }
+}
+
+/// Receives the packets of every stream, in order within each of them
+pub struct QuicReceiver {
+    control: FramedRead<RecvStream, LengthDelimitedCodec>,
+    incoming: BoxStream<'static, RecvStream>,
+    streams: SelectAll<FramedRead<RecvStream, LengthDelimitedCodec>>,
+    /// the other side finished the control stream
+    hung_up: bool,
+}
+
+impl QuicReceiver {
+    fn new(connection: Connection, control: RecvStream) -> Self {
+        let incoming = futures::stream::unfold(connection, |connection| async move {
+            let stream = connection.accept_uni().await.ok()?;
+            Some((stream, connection))
+        });
+        QuicReceiver {
+            control: FramedRead::new(control, codec()),
+            incoming: incoming.boxed(),
+            streams: SelectAll::new(),
+            hung_up: false,
+        }
+    }
+}
+
+impl Stream for QuicReceiver {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        while let Poll::Ready(Some(stream)) = this.incoming.poll_next_unpin(cx) {
+            this.streams.push(FramedRead::new(stream, codec()));
+        }
+
+        // a stream the other side gave up on ends alone, losing the connection
+        // ends the control stream too
+        while let Poll::Ready(Some(frame)) = this.streams.poll_next_unpin(cx) {
+            if let Ok(frame) = frame {
+                return Poll::Ready(Some(Ok(frame.freeze())));
+            }
+        }
+
+        if this.hung_up {
+            return if this.streams.is_empty() {
+                Poll::Ready(None)
+            } else {
+                Poll::Pending
+            };
+        }
+        match this.control.poll_next_unpin(cx) {
+            Poll::Ready(Some(frame)) => Poll::Ready(Some(frame.map(BytesMut::freeze))),
+            // the streams still open finish delivering what was sent before
+            Poll::Ready(None) => {
+                this.hung_up = true;
+                Pin::new(this).poll_next(cx)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+    use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+    use quinn::rustls::{self, RootCertStore};
+    use quinn::{ClientConfig, Endpoint, ServerConfig};
+
+    fn endpoints() -> (Endpoint, Endpoint) {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let der = CertificateDer::from(cert.cert);
+        let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![der.clone()], key.into())
+            .unwrap();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut server =
+            ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls).unwrap()));
+        server.transport_config(transport());
+        let server = Endpoint::server(server, ([127, 0, 0, 1], 0).into()).unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(der).unwrap();
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let mut client = Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+        client.set_default_client_config(ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(tls).unwrap(),
+        )));
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn test_streams_keep_their_order() {
+        let (server, client) = endpoints();
+        let addr = server.local_addr().unwrap();
+
+        let accept = tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            let mut control = QuicControl::accept(connection).await.unwrap();
+            let mut received = vec![control.next().await.unwrap().unwrap()];
+            control.send_frame(b"welcome".to_vec()).await.unwrap();
+            while let Some(Ok(frame)) = control.next().await {
+                received.push(frame);
+            }
+            received
+        });
+
+        let connection = client.connect(addr, "localhost").unwrap().await.unwrap();
+        let mut control = QuicControl::open(connection).await.unwrap();
+        control.send_frame(b"hello".to_vec()).await.unwrap();
+        let welcome = control.next().await.unwrap().unwrap();
+        assert_eq!(&welcome[..], b"welcome");
+
+        let (mut sender, _receiver) = control.split();
+        let (a, b) = (StreamId::generate(), StreamId::generate());
+        for i in 0..10u8 {
+            for id in [&a, &b] {
+                let data = Bytes::from(vec![i]);
+                sender
+                    .send(ControlPacket::Data(id.clone(), data))
+                    .await
+                    .unwrap();
+            }
+        }
+        sender.send(ControlPacket::End(a.clone())).await.unwrap();
+        sender.send(ControlPacket::Ping(None)).await.unwrap();
+        sender.send(ControlPacket::End(b.clone())).await.unwrap();
+        sender.close().await;
+
+        let received = accept.await.unwrap();
+        assert_eq!(&received[0][..], b"hello");
+        let packets: Vec<ControlPacket> = received[1..]
+            .iter()
+            .map(|frame| ControlPacket::deserialize(frame.clone()).unwrap())
+            .collect();
+        assert_eq!(packets.len(), 23);
+        for id in [&a, &b] {
+            let data: Vec<u8> = packets
+                .iter()
+                .filter_map(|packet| match packet {
+                    ControlPacket::Data(sid, data) if sid == id => Some(data[0]),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(data, (0..10).collect::<Vec<u8>>());
+        }
+    }
+}
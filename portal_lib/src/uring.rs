@@ -0,0 +1,152 @@
+//! Relaying raw TCP between two sockets on io_uring rather than epoll, for Linux
+//! deployments moving a lot of bytes through tunnels. The rings run on threads of
+//! their own, each with a `tokio_uring` runtime, and any runtime hands them sockets.
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{mpsc, oneshot};
+
+/// Bytes read at once in either direction
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Two sockets to relay, and who's waiting for them to be done
+struct Job {
+    a: TcpStream,
+    b: TcpStream,
+    done: oneshot::Sender<io::Result<(u64, u64)>>,
+}
+
+pub struct UringRelay {
+    workers: Vec<mpsc::UnboundedSender<Job>>,
+    next: AtomicUsize,
+}
+
+impl UringRelay {
+    /// Start `threads` rings, failing if the kernel won't let us set one up
+    pub fn start(threads: usize) -> io::Result<Self> {
+        let mut workers = Vec::with_capacity(threads);
+        for i in 0..threads.max(1) {
+            let (jobs, queue) = mpsc::unbounded_channel();
+            let (ready, started) = std::sync::mpsc::channel();
+            std::thread::Builder::new()
+                .name(format!("uring-relay-{}", i))
+                .spawn(move || {
+                    let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                        Ok(runtime) => runtime,
+                        Err(error) => {
+                            let _ = ready.send(Err(error));
+                            return;
+                        }
+                    };
+                    let _ = ready.send(Ok(()));
+                    runtime.block_on(serve(queue));
+                })?;
+            started
+                .recv()
+                .map_err(|_| io::Error::other("io_uring thread exited"))??;
+            workers.push(jobs);
+        }
+
+        Ok(UringRelay {
+            workers,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Copy between `a` and `b` both ways until both are done, returning the bytes
+    /// copied from `a` to `b` and from `b` to `a`
+    pub async fn relay(&self, a: TcpStream, b: TcpStream) -> io::Result<(u64, u64)> {
+        let worker = self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        let (done, result) = oneshot::channel();
+        self.workers[worker]
+            .send(Job { a, b, done })
+            .map_err(|_| io::Error::other("io_uring relay stopped"))?;
+        result
+            .await
+            .map_err(|_| io::Error::other("io_uring relay stopped"))?
+    }
+}
+
+async fn serve(mut queue: mpsc::UnboundedReceiver<Job>) {
+    while let Some(job) = queue.recv().await {
+        tokio_uring::spawn(async move {
+            let _ = job.done.send(relay(job.a, job.b).await);
+        });
+    }
+}
+
+async fn relay(a: TcpStream, b: TcpStream) -> io::Result<(u64, u64)> {
+    // a ring waits on the socket itself, it mustn't give up with `WouldBlock`
+    a.set_nonblocking(false)?;
+    b.set_nonblocking(false)?;
+    let a = tokio_uring::net::TcpStream::from_std(a);
+    let b = tokio_uring::net::TcpStream::from_std(b);
+
+    let (a_to_b, b_to_a) = tokio::join!(copy(&a, &b), copy(&b, &a));
+    Ok((a_to_b?, b_to_a?))
+}
+
+/// Copy until `from` is done sending, then tell `to` we are too
+async fn copy(
+    from: &tokio_uring::net::TcpStream,
+    to: &tokio_uring::net::TcpStream,
+) -> io::Result<u64> {
+    let mut buf = Vec::with_capacity(BUFFER_SIZE);
+    let mut copied = 0;
+    loop {
+        buf.clear();
+        let (read, returned) = from.read(buf).await;
+        buf = returned;
+        if read? == 0 {
+            break;
+        }
+
+        let (written, returned) = to.write_all(buf).await;
+        buf = returned;
+        written?;
+        copied += buf.len() as u64;
+    }
+    let _ = to.shutdown(Shutdown::Write);
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// A connected pair of sockets
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_relays_both_ways() {
+        let relay = UringRelay::start(1).unwrap();
+        let (mut visitor, a) = pair();
+        let (b, mut instance) = pair();
+
+        let visitor = tokio::task::spawn_blocking(move || {
+            visitor.write_all(b"hello").unwrap();
+            visitor.shutdown(Shutdown::Write).unwrap();
+            let mut response = String::new();
+            visitor.read_to_string(&mut response).unwrap();
+            response
+        });
+        let instance = tokio::task::spawn_blocking(move || {
+            let mut request = String::new();
+            instance.read_to_string(&mut request).unwrap();
+            instance.write_all(b"hi there").unwrap();
+            instance.shutdown(Shutdown::Write).unwrap();
+            request
+        });
+
+        assert_eq!(relay.relay(a, b).await.unwrap(), (5, 8));
+        assert_eq!(instance.await.unwrap(), "hello");
+        assert_eq!(visitor.await.unwrap(), "hi there");
+    }
+}
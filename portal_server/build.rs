@@ -0,0 +1,12 @@
+fn main() {
+    // the admin API's gRPC service, built with the protoc we ship rather than the system's
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/admin.proto"], &["proto"])
+            .expect("failed to compile proto/admin.proto");
+    }
+}
@@ -0,0 +1,222 @@
+"use strict";
+
+const TOKEN_KEY = "portal_admin_token";
+// samples of the traffic graph, one per snapshot
+const HISTORY = 120;
+
+let token = localStorage.getItem(TOKEN_KEY);
+let events = null;
+let previous = null;
+// the tunnel whose requests are shown
+let selected = null;
+const history = [];
+
+const $ = (id) => document.getElementById(id);
+
+function api(method, path) {
+    return fetch(`api/${path}`, {
+        method,
+        headers: { Authorization: `Bearer ${token}` },
+    });
+}
+
+function formatBytes(bytes) {
+    const units = ["B", "KB", "MB", "GB", "TB"];
+    let unit = 0;
+    while (bytes >= 1024 && unit < units.length - 1) {
+        bytes /= 1024;
+        unit += 1;
+    }
+    return `${bytes.toFixed(unit === 0 ? 0 : 1)} ${units[unit]}`;
+}
+
+function formatDuration(secs) {
+    if (secs < 60) return `${secs}s`;
+    if (secs < 3600) return `${Math.floor(secs / 60)}m ${secs % 60}s`;
+    if (secs < 86400) return `${Math.floor(secs / 3600)}h ${Math.floor((secs % 3600) / 60)}m`;
+    return `${Math.floor(secs / 86400)}d ${Math.floor((secs % 86400) / 3600)}h`;
+}
+
+function cell(text, code = false) {
+    const td = document.createElement("td");
+    if (code) {
+        const el = document.createElement("code");
+        el.textContent = text;
+        td.appendChild(el);
+    } else {
+        td.textContent = text;
+    }
+    return td;
+}
+
+function actionCell(label, onClick) {
+    const td = document.createElement("td");
+    const button = document.createElement("button");
+    button.textContent = label;
+    button.addEventListener("click", onClick);
+    td.appendChild(button);
+    return td;
+}
+
+function render(snapshot) {
+    const { stats, clients, streams } = snapshot;
+
+    $("instance").textContent = `${stats.instance_id} · v${stats.version}`;
+    $("uptime").textContent = formatDuration(stats.uptime_secs);
+    $("clients-count").textContent = stats.connected_clients;
+    $("streams-count").textContent = stats.active_streams;
+
+    // agents serving the same subdomain share its byte counters
+    const tunnels = new Map(clients.map((c) => [c.sub_domain, c]));
+    const total = { in: 0, out: 0 };
+    for (const tunnel of tunnels.values()) {
+        total.in += tunnel.bytes_in;
+        total.out += tunnel.bytes_out;
+    }
+    const rate = previous
+        ? { in: Math.max(0, total.in - previous.in), out: Math.max(0, total.out - previous.out) }
+        : { in: 0, out: 0 };
+    previous = total;
+    history.push(rate);
+    if (history.length > HISTORY) history.shift();
+
+    $("rate-in").textContent = `${formatBytes(rate.in)}/s`;
+    $("rate-out").textContent = `${formatBytes(rate.out)}/s`;
+    drawTraffic();
+
+    const clientRows = clients.map((client) => {
+        const tr = document.createElement("tr");
+        tr.className = client.sub_domain === selected ? "selectable selected" : "selectable";
+        tr.addEventListener("click", (event) => {
+            if (event.target.tagName === "BUTTON") return;
+            selected = client.sub_domain;
+            $("requests-tunnel").textContent = `· ${selected}`;
+            loadRequests();
+        });
+        const connected = Math.floor((Date.now() - Date.parse(client.connected_at)) / 1000);
+        tr.append(
+            cell(client.sub_domain, true),
+            cell(client.name || (client.is_anonymous ? "anonymous" : client.client_id.slice(0, 8))),
+            cell(client.version || "-"),
+            cell(formatDuration(Math.max(0, connected))),
+            cell(client.streams),
+            cell(formatBytes(client.bytes_in)),
+            cell(formatBytes(client.bytes_out)),
+            actionCell("Disconnect", () => api("DELETE", `clients/${client.session_id}`)),
+        );
+        return tr;
+    });
+    $("clients").replaceChildren(...clientRows);
+
+    const streamRows = streams.map((stream) => {
+        const tr = document.createElement("tr");
+        tr.append(
+            cell(stream.stream_id, true),
+            cell(stream.sub_domain),
+            cell(formatDuration(stream.age_secs)),
+            cell(formatDuration(stream.idle_secs)),
+            cell(formatBytes(stream.bytes_in)),
+            cell(formatBytes(stream.bytes_out)),
+            actionCell("Close", () => api("DELETE", `streams/${stream.stream_id}`)),
+        );
+        return tr;
+    });
+    $("streams").replaceChildren(...streamRows);
+
+    loadRequests();
+}
+
+async function loadRequests() {
+    if (!selected) return;
+    const response = await api("GET", `requests/${encodeURIComponent(selected)}`).catch(() => null);
+    if (!response || !response.ok) return;
+    const requests = await response.json();
+
+    const rows = requests.reverse().map((request) => {
+        const tr = document.createElement("tr");
+        const status = cell(request.status || "-");
+        status.className = request.status && request.status < 400 ? "status-ok" : "status-error";
+        tr.append(
+            cell(new Date(request.timestamp).toLocaleTimeString()),
+            cell(request.replay ? `${request.method} ↻` : request.method),
+            cell(request.path, true),
+            status,
+            cell(`${request.duration}ms`),
+            cell(formatBytes(request.request_size)),
+            cell(formatBytes(request.response_size)),
+            request.captured
+                ? actionCell("Replay", () => api("POST", `requests/${encodeURIComponent(selected)}/${request.id}/replay`).then(loadRequests))
+                : cell(""),
+        );
+        return tr;
+    });
+    $("requests").replaceChildren(...rows);
+}
+
+function drawTraffic() {
+    const canvas = $("traffic");
+    const width = (canvas.width = canvas.clientWidth * devicePixelRatio);
+    const height = (canvas.height = 120 * devicePixelRatio);
+    const ctx = canvas.getContext("2d");
+    const max = Math.max(1024, ...history.map((r) => Math.max(r.in, r.out)));
+    const step = width / (HISTORY - 1);
+
+    for (const [direction, color] of [["in", "#4dabf7"], ["out", "#b197fc"]]) {
+        ctx.beginPath();
+        ctx.strokeStyle = color;
+        ctx.lineWidth = 2 * devicePixelRatio;
+        history.forEach((rate, i) => {
+            const x = width - (history.length - 1 - i) * step;
+            const y = height - (rate[direction] / max) * (height - 8) - 4;
+            if (i === 0) ctx.moveTo(x, y);
+            else ctx.lineTo(x, y);
+        });
+        ctx.stroke();
+    }
+}
+
+function connect() {
+    events = new EventSource(`api/events?token=${encodeURIComponent(token)}`);
+    events.addEventListener("snapshot", (event) => render(JSON.parse(event.data)));
+    events.onopen = () => $("status").classList.add("live");
+    events.onerror = () => $("status").classList.remove("live");
+}
+
+async function start() {
+    const response = await api("GET", "stats").catch(() => null);
+    if (!response || !response.ok) {
+        $("login-error").textContent = response && response.status === 401 ? "invalid token" : "";
+        showLogin();
+        return;
+    }
+
+    localStorage.setItem(TOKEN_KEY, token);
+    $("login").hidden = true;
+    $("dashboard").hidden = false;
+    connect();
+}
+
+function showLogin() {
+    if (events) events.close();
+    $("dashboard").hidden = true;
+    $("login").hidden = false;
+    $("token").focus();
+}
+
+$("login").addEventListener("submit", (event) => {
+    event.preventDefault();
+    token = $("token").value;
+    start();
+});
+
+$("logout").addEventListener("click", () => {
+    localStorage.removeItem(TOKEN_KEY);
+    token = null;
+    showLogin();
+});
+
+if (token) {
+    start();
+} else {
+    showLogin();
+}
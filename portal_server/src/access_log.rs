@@ -0,0 +1,300 @@
+//! Visitors' requests, and the connections of tunnels we don't speak http on, in the
+//! Common or Combined Log Format web log analyzers read
+use crate::config::Config;
+use crate::connected_clients::ConnectedClient;
+use crate::get_config;
+use crate::request_log::Recorded;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// How many rotated access logs we keep, i.e. `access.log.1` to `access.log.5`
+const KEEP_FILES: usize = 5;
+
+/// How access log lines look
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// `host ident user [time] "request" status bytes`
+    Common,
+    /// common, then the `"referer" "user-agent"`
+    #[default]
+    Combined,
+    /// combined, after the tunnel the request was for
+    VhostCombined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            "vhost_combined" => Ok(AccessLogFormat::VhostCombined),
+            other => Err(format!("unknown access log format: {}", other)),
+        }
+    }
+}
+
+/// What goes into a line, whatever the format
+struct Line<'a> {
+    tunnel: &'a str,
+    peer: IpAddr,
+    time: DateTime<Local>,
+    request: String,
+    status: u16,
+    size: u64,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+impl AccessLogFormat {
+    fn format(self, line: &Line) -> String {
+        let size = match line.size {
+            0 => "-".to_string(),
+            size => size.to_string(),
+        };
+        let common = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            line.peer,
+            line.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&line.request),
+            line.status,
+            size
+        );
+        let combined = || {
+            format!(
+                "{} \"{}\" \"{}\"",
+                common,
+                escape(line.referer.unwrap_or("-")),
+                escape(line.user_agent.unwrap_or("-"))
+            )
+        };
+        match self {
+            AccessLogFormat::Common => common,
+            AccessLogFormat::Combined => combined(),
+            AccessLogFormat::VhostCombined => format!("{} {}", line.tunnel, combined()),
+        }
+    }
+}
+
+/// Quotes and backslashes escaped, and control characters as `\xNN`, so a visitor
+/// can't break out of the field or start a line of their own
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Where access log lines go, if anywhere
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sink: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl AccessLog {
+    pub fn open(config: &Config) -> Self {
+        let sink: Option<Box<dyn Write + Send>> = match config.access_log.as_deref() {
+            None => None,
+            Some("-") => Some(Box::new(io::stdout())),
+            Some(path) => match RotatingFile::open(Path::new(path), config.access_log_max_size) {
+                Ok(file) => Some(Box::new(file)),
+                Err(error) => {
+                    tracing::error!(%error, path, "failed to open the access log");
+                    None
+                }
+            },
+        };
+        AccessLog {
+            format: config.access_log_format,
+            sink: sink.map(Mutex::new),
+        }
+    }
+
+    /// Whether the visitors of `client`'s tunnel are logged
+    pub fn logs(&self, client: &ConnectedClient) -> bool {
+        self.sink.is_some() && (client.access_log || !get_config().access_log_opt_in)
+    }
+
+    /// A request `peer` sent through `client`'s tunnel was answered
+    pub fn request(&self, client: &ConnectedClient, peer: IpAddr, recorded: &Recorded) {
+        if !self.logs(client) {
+            return;
+        }
+        let entry = &recorded.entry;
+        let time = DateTime::from_timestamp_millis(entry.timestamp as i64)
+            .unwrap_or_default()
+            .with_timezone(&Local);
+        self.write(&Line {
+            tunnel: &client.host,
+            peer,
+            time,
+            request: format!(
+                "{} {} HTTP/1.{}",
+                entry.method, entry.path, recorded.version
+            ),
+            status: entry.status,
+            size: entry.response_size,
+            referer: recorded.referer.as_deref(),
+            user_agent: recorded.user_agent.as_deref(),
+        });
+    }
+
+    /// A connection `peer` made to `client`'s tcp or tls tunnel closed, after we sent
+    /// it `bytes_out`
+    pub fn connection(
+        &self,
+        client: &ConnectedClient,
+        peer: IpAddr,
+        opened: SystemTime,
+        bytes_out: u64,
+    ) {
+        if !self.logs(client) {
+            return;
+        }
+        self.write(&Line {
+            tunnel: &client.host,
+            peer,
+            time: opened.into(),
+            request: format!(
+                "CONNECT {} {}",
+                client.host,
+                client.protocol.to_string().to_uppercase()
+            ),
+            status: 200,
+            size: bytes_out,
+            referer: None,
+            user_agent: None,
+        });
+    }
+
+    fn write(&self, line: &Line) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        let mut line = self.format.format(line);
+        line.push('\n');
+        if let Err(error) = sink.lock().unwrap().write_all(line.as_bytes()) {
+            tracing::warn!(%error, "failed to write the access log");
+        }
+    }
+}
+
+/// An access log file that moves aside to `<file>.1` once it reaches its size
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// bytes we rotate at, 0 never rotates
+    max_size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..KEEP_FILES).rev() {
+            let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        *self = RotatingFile::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn line<'a>(request: &str, referer: Option<&'a str>, user_agent: Option<&'a str>) -> Line<'a> {
+        Line {
+            tunnel: "demo",
+            peer: "203.0.113.7".parse().unwrap(),
+            time: Local.with_ymd_and_hms(2024, 3, 9, 13, 55, 36).unwrap(),
+            request: request.to_string(),
+            status: 200,
+            size: 2326,
+            referer,
+            user_agent,
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        let line = line("GET /a?b=c HTTP/1.1", Some("https://example.com/"), None);
+        let time = line.time.format("%d/%b/%Y:%H:%M:%S %z").to_string();
+        let common = format!(
+            "203.0.113.7 - - [{}] \"GET /a?b=c HTTP/1.1\" 200 2326",
+            time
+        );
+        assert_eq!(AccessLogFormat::Common.format(&line), common);
+        assert_eq!(
+            AccessLogFormat::Combined.format(&line),
+            format!("{} \"https://example.com/\" \"-\"", common)
+        );
+        assert_eq!(
+            AccessLogFormat::VhostCombined.format(&line),
+            format!("demo {} \"https://example.com/\" \"-\"", common)
+        );
+        assert!(time.starts_with("09/Mar/2024:13:55:36 "));
+    }
+
+    #[test]
+    fn test_escapes_visitor_fields() {
+        let line = line("GET / HTTP/1.1", None, Some("evil\" \"agent\n"));
+        assert!(AccessLogFormat::Combined
+            .format(&line)
+            .ends_with("\"-\" \"evil\\\" \\\"agent\\x0a\""));
+    }
+}
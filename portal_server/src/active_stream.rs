@@ -1,30 +1,349 @@
+use crate::error_page::ErrorPage;
+use crate::http::cache::CacheTracker;
+use crate::http::edge::EdgeTracker;
+use crate::http::expect::ContinueTracker;
+use crate::http::framing::ResponseTracker;
+use crate::http::https::HttpsPolicy;
+use crate::http::sse::EventStreamTracker;
+use crate::observability::metrics::get_metrics;
+use crate::request_log::RequestTracker;
+use crate::tasks::CancellationToken;
+use portal_lib::TunnelStats;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often the reaper looks for expired streams
+pub const REAP_INTERVAL: Duration = Duration::from_secs(5);
+/// How often agents waiting for stats look whether a reload enabled them again
+const STATS_DISABLED_RECHECK: Duration = Duration::from_secs(30);
+
 #[derive(Debug, Clone)]
 pub struct ActiveStream {
     pub id: StreamId,
     pub client: ConnectedClient,
-    pub tx: UnboundedSender<StreamMessage>,
+    /// for the tasks relaying the stream, each keeping its clone
+    pub tx: Sender<StreamMessage>,
+    /// the sender every clone shares for what's queued from elsewhere, i.e. the agent's
+    /// data: a sender gets a slot of its own on top of the queue's, so one for each
+    /// message would leave the queue unbounded
+    queued: Arc<Mutex<Sender<StreamMessage>>>,
+    pub stats: Arc<StreamStats>,
+    /// cancelled when the stream or its agent is done, ending the tasks relaying it
+    pub cancel: CancellationToken,
+}
+
+/// Activity, traffic and requests of a stream, shared by the tasks relaying it
+#[derive(Debug)]
+pub struct StreamStats {
+    pub created_at: Instant,
+    /// milliseconds after `created_at` we last saw traffic
+    last_activity: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    pub requests: RequestTracker,
+    pub cache: CacheTracker,
+    /// CORS and HSTS headers, and the requests answered at the edge
+    pub edge: EdgeTracker,
+    /// the `Expect: 100-continue` answered at the edge
+    pub expect: ContinueTracker,
+    /// the responses, framed once for the trackers that look at them
+    pub responses: ResponseTracker,
+    /// whether it's relaying server-sent events, which mustn't time out for idling
+    pub events: EventStreamTracker,
+    /// the counters of the stream's tunnel, which its traffic adds to
+    tunnel: Arc<TrafficCounters>,
+}
+
+impl StreamStats {
+    fn new(client: &ConnectedClient) -> Self {
+        StreamStats {
+            created_at: Instant::now(),
+            last_activity: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            requests: RequestTracker::new(get_request_log(), get_access_log().logs(client)),
+            cache: CacheTracker::new(&client.host, client.cache),
+            edge: EdgeTracker::new(client.cors.clone(), HttpsPolicy::new(client, &get_config())),
+            expect: ContinueTracker::new(get_config().answer_continue),
+            responses: ResponseTracker::default(),
+            events: EventStreamTracker::default(),
+            tunnel: get_tunnel_traffic().opened(&client.host),
+        }
+    }
+
+    /// Record traffic in either direction
+    pub fn touch(&self) {
+        let elapsed = self.created_at.elapsed().as_millis() as u64;
+        self.last_activity.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// Count bytes read from the visitor
+    pub fn add_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.tunnel
+            .bytes_in
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count bytes written to the visitor
+    pub fn add_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.tunnel
+            .bytes_out
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for StreamStats {
+    fn drop(&mut self) {
+        self.tunnel.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Traffic of a tunnel's streams on this instance, since its first agent connected
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    connections: AtomicU64,
+}
+
+impl TrafficCounters {
+    pub fn stats(&self) -> TunnelStats {
+        TunnelStats {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The traffic of every tunnel with agents here, reported to the agents that ask
+#[derive(Debug, Default)]
+pub struct TunnelTraffic {
+    tunnels: DashMap<String, Arc<TrafficCounters>>,
+}
+
+impl TunnelTraffic {
+    /// Count a new stream of `host`, returning the counters its traffic adds to
+    fn opened(&self, host: &str) -> Arc<TrafficCounters> {
+        let counters = self.tunnels.entry(host.to_string()).or_default().clone();
+        counters.connections.fetch_add(1, Ordering::Relaxed);
+        counters
+    }
+
+    pub fn stats(&self, host: &str) -> TunnelStats {
+        self.tunnels
+            .get(host)
+            .map(|counters| counters.stats())
+            .unwrap_or_default()
+    }
+
+    /// Forget a tunnel whose last agent left, its remaining streams counting on their own
+    pub fn remove(&self, host: &str) {
+        self.tunnels.remove(host);
+    }
 }
 
 impl ActiveStream {
-    pub fn new(client: ConnectedClient) -> (Self, UnboundedReceiver<StreamMessage>) {
-        let (tx, rx) = unbounded();
+    pub fn new(client: ConnectedClient) -> (Self, Receiver<StreamMessage>) {
+        // holding all the data packets an agent that flow controls may send ahead
+        let size = client
+            .stream_window
+            .unwrap_or_else(|| get_config().stream_queue_size);
+        let (tx, rx) = channel(size);
         (
             ActiveStream {
                 id: StreamId::generate(),
+                cancel: client.cancel.child_token(),
+                stats: Arc::new(StreamStats::new(&client)),
                 client,
+                queued: Arc::new(Mutex::new(tx.clone())),
                 tx,
             },
             rx,
         )
     }
+
+    /// Record traffic in either direction
+    pub fn touch(&self) {
+        self.stats.touch();
+    }
+
+    pub fn age(&self) -> Duration {
+        self.stats.created_at.elapsed()
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        let last_activity = Duration::from_millis(self.stats.last_activity.load(Ordering::Relaxed));
+        self.age().saturating_sub(last_activity)
+    }
+
+    /// Whether this stream has outlived the configured idle or lifetime limits. Event
+    /// streams idle for as long as their app has nothing to send.
+    fn is_expired(&self, config: &Config) -> bool {
+        config
+            .stream_idle_timeout
+            .filter(|_| !self.stats.events.is_streaming())
+            .is_some_and(|timeout| self.idle_for() >= timeout)
+            || config
+                .max_stream_lifetime
+                .is_some_and(|lifetime| self.age() >= lifetime)
+    }
+
+    /// Whether the relay writing to the visitor is gone, so nothing would remove the stream
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Whether the agent the stream goes through is gone. One that visitors no longer
+    /// reach, i.e. draining or replaced, still finishes its streams until it's cancelled.
+    pub fn is_orphaned(&self) -> bool {
+        self.client.cancel.is_cancelled()
+    }
+
+    /// Why the sweep should close this stream, if it should
+    fn sweep_reason(&self, config: &Config) -> Option<&'static str> {
+        if self.is_closed() {
+            Some("closed")
+        } else if self.is_orphaned() {
+            Some("orphaned")
+        } else if self.is_expired(config) {
+            Some("expired")
+        } else {
+            None
+        }
+    }
+
+    /// Queue a message for the visitor without waiting for room, returning `false`
+    /// if the queue is full
+    pub fn queue(&self, message: StreamMessage) -> bool {
+        match self.queued.lock().unwrap().try_send(message) {
+            Ok(()) => true,
+            Err(error) => !error.is_full(),
+        }
+    }
+
+    /// Queue a message for the visitor once there's room, returning `false` if the
+    /// stream is gone
+    pub async fn send(&self, mut message: StreamMessage) -> bool {
+        loop {
+            let ready = futures::future::poll_fn(|cx| self.queued.lock().unwrap().poll_ready(cx));
+            if ready.await.is_err() {
+                return false;
+            }
+            // unless a message queued without waiting took the room meanwhile
+            match self.queued.lock().unwrap().try_send(message) {
+                Ok(()) => return true,
+                Err(error) if error.is_full() => message = error.into_inner(),
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Drop the stream, closing the visitor connection and telling the agent
+    pub fn close(&self) {
+        get_active_streams().remove(&self.id);
+        // a relay stuck behind a full queue is stopped instead
+        if !self.queue(StreamMessage::Close) {
+            self.cancel.cancel();
+        }
+        self.client.queue(ControlPacket::End(self.id.clone()));
+    }
 }
 
 pub type ActiveStreams = Arc<DashMap<StreamId, ActiveStream>>;
 
+/// Send an agent that asked for them its tunnel's traffic stats, until it's gone
+pub async fn send_stats(client: ConnectedClient) {
+    loop {
+        // a reload may change or disable the interval
+        let interval = get_config().stats_interval;
+        tokio::time::sleep(interval.unwrap_or(STATS_DISABLED_RECHECK)).await;
+        if interval.is_some() {
+            let host = Connections::current(&client).host;
+            let stats = get_tunnel_traffic().stats(&host);
+            client.queue(ControlPacket::Stats(stats));
+        }
+    }
+}
+
+/// Periodically close streams that went idle or outlived their maximum lifetime,
+/// so abandoned visitor connections don't hold on to memory and sockets forever.
+/// Streams whose relay or agent is gone without removing them are swept too.
+pub fn spawn_reaper() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REAP_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            // the timeouts may have been reloaded since
+            let config = get_config();
+            let swept: Vec<(ActiveStream, &str)> = get_active_streams()
+                .iter()
+                .filter_map(|stream| Some((stream.value().clone(), stream.sweep_reason(&config)?)))
+                .collect();
+
+            for (stream, reason) in swept {
+                tracing::debug!(
+                    stream_id = %stream.id.to_string(),
+                    reason,
+                    age = ?stream.age(),
+                    idle = ?stream.idle_for(),
+                    "reaping stream"
+                );
+                get_metrics().stream_swept(reason);
+                stream.close();
+            }
+        }
+    });
+}
+
 use super::*;
 #[derive(Debug, Clone)]
 pub enum StreamMessage {
-    Data(Vec<u8>),
+    Data(Bytes),
+    /// a response from the cache, written in place of the agent's
+    Cached(Bytes),
     TunnelRefused,
     NoClientTunnel,
+    InvalidRequest(ErrorPage),
+    Close,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel_traffic() {
+        let traffic = TunnelTraffic::default();
+        let first = traffic.opened("foo");
+        let second = traffic.opened("foo");
+        first.bytes_in.fetch_add(10, Ordering::Relaxed);
+        second.bytes_out.fetch_add(32, Ordering::Relaxed);
+        traffic.opened("bar");
+
+        assert_eq!(
+            traffic.stats("foo"),
+            TunnelStats {
+                bytes_in: 10,
+                bytes_out: 32,
+                connections: 2,
+            }
+        );
+        assert_eq!(traffic.stats("bar").connections, 1);
+
+        traffic.remove("foo");
+        assert_eq!(traffic.stats("foo"), TunnelStats::default());
+    }
 }
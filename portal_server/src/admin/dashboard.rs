@@ -0,0 +1,98 @@
+//! The web dashboard: static assets embedded in the binary and a stream of live updates
+use super::{clients, stats, streams, ClientInfo, InstanceStats, StreamInfo, StreamsQuery};
+use crate::get_request_log;
+use rust_embed::RustEmbed;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use warp::path::Tail;
+use warp::sse::Event;
+use warp::{Filter, Rejection, Reply};
+
+/// How often connected dashboards receive a fresh snapshot
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(RustEmbed)]
+#[folder = "dashboard/"]
+struct Assets;
+
+/// Everything the dashboard renders, sent as one `snapshot` event
+#[derive(Debug, Serialize)]
+struct Snapshot {
+    stats: InstanceStats,
+    clients: Vec<ClientInfo>,
+    streams: Vec<StreamInfo>,
+}
+
+impl Snapshot {
+    fn now() -> Self {
+        Snapshot {
+            stats: stats(),
+            clients: clients(),
+            streams: streams(&StreamsQuery { older_than: None }),
+        }
+    }
+}
+
+/// `GET /events`: server-sent snapshots of this instance
+pub fn events() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("events").and(warp::get()).map(|| {
+        let interval = tokio::time::interval(UPDATE_INTERVAL);
+        let updates = futures::stream::unfold(interval, |mut interval| async move {
+            interval.tick().await;
+            let event = Event::default()
+                .event("snapshot")
+                .json_data(Snapshot::now());
+            Some((event, interval))
+        });
+        warp::sse::reply(warp::sse::keep_alive().stream(updates))
+    })
+}
+
+/// `GET /requests/:tunnel/tail`: server-sent `request` events as the tunnel's requests complete
+pub fn tail() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("requests" / String / "tail")
+        .and(warp::get())
+        .map(|tunnel: String| {
+            let receiver = get_request_log().subscribe();
+            let requests = futures::stream::unfold(receiver, move |mut receiver| {
+                let tunnel = tunnel.clone();
+                async move {
+                    loop {
+                        match receiver.recv().await {
+                            Ok((host, entry)) if host == tunnel => {
+                                let event = Event::default().event("request").json_data(entry);
+                                return Some((event, receiver));
+                            }
+                            Ok(_) => continue,
+                            Err(RecvError::Lagged(missed)) => {
+                                tracing::debug!(missed, "request tail fell behind");
+                                continue;
+                            }
+                            Err(RecvError::Closed) => return None,
+                        }
+                    }
+                }
+            });
+            warp::sse::reply(warp::sse::keep_alive().stream(requests))
+        })
+}
+
+/// The dashboard's html, scripts and styles. These hold no data, so they're served
+/// without authentication and the page asks for the admin token itself.
+pub fn assets() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path::tail())
+        .and_then(|tail: Tail| async move {
+            let path = match tail.as_str() {
+                "" => "index.html",
+                path => path,
+            };
+            let asset = Assets::get(path).ok_or_else(warp::reject::not_found)?;
+            Ok::<_, Rejection>(warp::reply::with_header(
+                asset.data.into_owned(),
+                "content-type",
+                asset.metadata.mimetype(),
+            ))
+        })
+}
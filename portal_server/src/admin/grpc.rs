@@ -0,0 +1,337 @@
+//! The admin API as a gRPC service, see `proto/admin.proto`. Watches send what changed
+//! since the last look, taken as often as the dashboard's snapshots.
+use super::{
+    clients, constant_time_eq, kill_stream, stats, streams, ClientInfo, StreamInfo, StreamsQuery,
+    DEFAULT_DISCONNECT_REASON, STARTED_AT,
+};
+use crate::{client_manager, get_config};
+use futures::{Stream, StreamExt};
+use portal_lib::NoticeLevel;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("portal.admin.v1");
+}
+
+use proto::admin_server::{Admin, AdminServer};
+use proto::Change;
+
+/// How often watchers learn what changed
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+type Watch<E> = Pin<Box<dyn Stream<Item = Result<E, Status>> + Send>>;
+
+impl From<ClientInfo> for proto::Tunnel {
+    fn from(client: ClientInfo) -> Self {
+        proto::Tunnel {
+            client_id: client.client_id,
+            session_id: client.session_id,
+            name: client.name,
+            version: client.version,
+            sub_domain: client.sub_domain,
+            is_anonymous: client.is_anonymous,
+            tier: client.tier,
+            connected_at: Some(prost_types::Timestamp {
+                seconds: client.connected_at.timestamp(),
+                nanos: client.connected_at.timestamp_subsec_nanos() as i32,
+            }),
+            streams: client.streams as u64,
+            bytes_in: client.bytes_in,
+            bytes_out: client.bytes_out,
+            protocol: client.protocol.to_string(),
+            agent_id: client.agent_id,
+        }
+    }
+}
+
+impl From<StreamInfo> for proto::Stream {
+    fn from(stream: StreamInfo) -> Self {
+        proto::Stream {
+            stream_id: stream.stream_id,
+            session_id: stream.session_id,
+            sub_domain: stream.sub_domain,
+            age_secs: stream.age_secs,
+            idle_secs: stream.idle_secs,
+            bytes_in: stream.bytes_in,
+            bytes_out: stream.bytes_out,
+            closed: stream.closed,
+            orphaned: stream.orphaned,
+        }
+    }
+}
+
+struct AdminService;
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn list_tunnels(
+        &self,
+        _: Request<proto::ListTunnelsRequest>,
+    ) -> Result<Response<proto::ListTunnelsResponse>, Status> {
+        let tunnels = clients().into_iter().map(proto::Tunnel::from).collect();
+        Ok(Response::new(proto::ListTunnelsResponse { tunnels }))
+    }
+
+    type WatchTunnelsStream = Watch<proto::TunnelEvent>;
+
+    async fn watch_tunnels(
+        &self,
+        request: Request<proto::WatchTunnelsRequest>,
+    ) -> Result<Response<Self::WatchTunnelsStream>, Status> {
+        let sub_domain = request.into_inner().sub_domain;
+        let snapshot = move || {
+            clients()
+                .into_iter()
+                .filter(|client| sub_domain.as_ref().is_none_or(|s| *s == client.sub_domain))
+                .map(|client| (client.session_id.clone(), proto::Tunnel::from(client)))
+                .collect()
+        };
+        let events = watch(
+            snapshot,
+            |a, b| a != b,
+            |change, tunnel| proto::TunnelEvent {
+                change: change.into(),
+                tunnel: Some(tunnel),
+            },
+        );
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    async fn disconnect_tunnel(
+        &self,
+        request: Request<proto::DisconnectTunnelRequest>,
+    ) -> Result<Response<proto::DisconnectTunnelResponse>, Status> {
+        let request = request.into_inner();
+        let session_id = request
+            .session_id
+            .parse()
+            .map_err(|_| Status::invalid_argument("invalid session id"))?;
+        let reason = request
+            .reason
+            .as_deref()
+            .unwrap_or(DEFAULT_DISCONNECT_REASON);
+        if !client_manager::disconnect(&session_id, reason) {
+            return Err(Status::not_found("no tunnel with this session id"));
+        }
+        Ok(Response::new(proto::DisconnectTunnelResponse {}))
+    }
+
+    async fn transfer_tunnel(
+        &self,
+        request: Request<proto::TransferTunnelRequest>,
+    ) -> Result<Response<proto::Tunnel>, Status> {
+        let request = request.into_inner();
+        let session_id = request
+            .session_id
+            .parse()
+            .map_err(|_| Status::invalid_argument("invalid session id"))?;
+        match client_manager::transfer(&session_id, &request.sub_domain) {
+            Ok(client) => Ok(Response::new(ClientInfo::new(&client).into())),
+            Err(error @ client_manager::Error::NotFound) => {
+                Err(Status::not_found(error.to_string()))
+            }
+            Err(error) => Err(Status::invalid_argument(error.to_string())),
+        }
+    }
+
+    async fn broadcast(
+        &self,
+        request: Request<proto::BroadcastRequest>,
+    ) -> Result<Response<proto::BroadcastResponse>, Status> {
+        let request = request.into_inner();
+        if request.message.trim().is_empty() {
+            return Err(Status::invalid_argument("the message is empty"));
+        }
+        let level = match request.level.as_str() {
+            "" => NoticeLevel::default(),
+            level => level.parse().map_err(Status::invalid_argument)?,
+        };
+        let agents = client_manager::broadcast(level, &request.message) as u64;
+        Ok(Response::new(proto::BroadcastResponse { agents }))
+    }
+
+    async fn list_streams(
+        &self,
+        request: Request<proto::ListStreamsRequest>,
+    ) -> Result<Response<proto::ListStreamsResponse>, Status> {
+        let query = StreamsQuery {
+            older_than: request.into_inner().older_than,
+        };
+        let streams = streams(&query)
+            .into_iter()
+            .map(proto::Stream::from)
+            .collect();
+        Ok(Response::new(proto::ListStreamsResponse { streams }))
+    }
+
+    type WatchStreamsStream = Watch<proto::StreamEvent>;
+
+    async fn watch_streams(
+        &self,
+        request: Request<proto::WatchStreamsRequest>,
+    ) -> Result<Response<Self::WatchStreamsStream>, Status> {
+        let sub_domain = request.into_inner().sub_domain;
+        let snapshot = move || {
+            streams(&StreamsQuery { older_than: None })
+                .into_iter()
+                .filter(|stream| sub_domain.as_ref().is_none_or(|s| *s == stream.sub_domain))
+                .map(|stream| (stream.stream_id.clone(), proto::Stream::from(stream)))
+                .collect()
+        };
+        // a stream ages every second, which alone isn't news
+        let changed = |a: &proto::Stream, b: &proto::Stream| {
+            (a.bytes_in, a.bytes_out, a.closed, a.orphaned)
+                != (b.bytes_in, b.bytes_out, b.closed, b.orphaned)
+        };
+        let events = watch(snapshot, changed, |change, stream| proto::StreamEvent {
+            change: change.into(),
+            stream: Some(stream),
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    async fn close_stream(
+        &self,
+        request: Request<proto::CloseStreamRequest>,
+    ) -> Result<Response<proto::CloseStreamResponse>, Status> {
+        let stream_id = request
+            .into_inner()
+            .stream_id
+            .parse()
+            .map_err(|_| Status::invalid_argument("invalid stream id"))?;
+        if !kill_stream(&stream_id) {
+            return Err(Status::not_found("no stream with this id"));
+        }
+        Ok(Response::new(proto::CloseStreamResponse {}))
+    }
+
+    async fn get_stats(
+        &self,
+        _: Request<proto::GetStatsRequest>,
+    ) -> Result<Response<proto::Stats>, Status> {
+        let stats = stats();
+        Ok(Response::new(proto::Stats {
+            instance_id: stats.instance_id,
+            version: stats.version.to_string(),
+            uptime_secs: stats.uptime_secs,
+            connected_clients: stats.connected_clients as u64,
+            active_streams: stats.active_streams as u64,
+            bytes_in: stats.bytes_in,
+            bytes_out: stats.bytes_out,
+        }))
+    }
+}
+
+/// Take a `snapshot` every `WATCH_INTERVAL` and send an `event` for what opened,
+/// `changed` or closed since the one before. The first has everything opened.
+fn watch<T, E>(
+    snapshot: impl Fn() -> BTreeMap<String, T> + Send + 'static,
+    changed: fn(&T, &T) -> bool,
+    event: fn(Change, T) -> E,
+) -> impl Stream<Item = Result<E, Status>> + Send
+where
+    T: Clone + Send + 'static,
+    E: Send + 'static,
+{
+    let interval = tokio::time::interval(WATCH_INTERVAL);
+    futures::stream::unfold(
+        (interval, BTreeMap::new(), snapshot),
+        move |(mut interval, last, snapshot)| async move {
+            interval.tick().await;
+            let current = snapshot();
+            let events: Vec<_> = diff(&last, &current, changed)
+                .into_iter()
+                .map(|(change, item)| event(change, item))
+                .collect();
+            Some((events, (interval, current, snapshot)))
+        },
+    )
+    .flat_map(futures::stream::iter)
+    .map(Ok)
+}
+
+/// What opened, changed or closed from `last` to `current`
+fn diff<T: Clone>(
+    last: &BTreeMap<String, T>,
+    current: &BTreeMap<String, T>,
+    changed: fn(&T, &T) -> bool,
+) -> Vec<(Change, T)> {
+    let mut events = vec![];
+    for (key, item) in current {
+        match last.get(key) {
+            None => events.push((Change::Opened, item.clone())),
+            Some(before) if changed(before, item) => events.push((Change::Updated, item.clone())),
+            Some(_) => {}
+        }
+    }
+    for (key, item) in last {
+        if !current.contains_key(key) {
+            events.push((Change::Closed, item.clone()));
+        }
+    }
+    events
+}
+
+/// Calls must carry `authorization: Bearer <admin_token>`, as `/api` requests do
+#[allow(clippy::result_large_err)] // tonic's interceptors return its `Status`
+fn authorize(request: Request<()>) -> Result<Request<()>, Status> {
+    let config = get_config();
+    let given = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (config.admin_token.as_deref(), given) {
+        (Some(expected), Some(given)) if constant_time_eq(expected, given) => Ok(request),
+        _ => Err(Status::unauthenticated("unauthorized")),
+    }
+}
+
+pub fn spawn<A: Into<SocketAddr>>(addr: A) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    STARTED_AT.get_or_init(Instant::now);
+
+    if get_config().admin_token.is_none() {
+        tracing::error!("grpc admin api disabled: no admin token configured");
+        return Ok(());
+    }
+
+    // bound here rather than in the server, so a taken port fails startup
+    let incoming = TcpIncoming::new(addr.into(), true, None)?;
+    let service = AdminServer::with_interceptor(AdminService, authorize);
+    let server = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_incoming(incoming);
+    tokio::spawn(async move {
+        if let Err(error) = server.await {
+            tracing::error!(%error, "grpc admin api failed");
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let last = BTreeMap::from([("a".to_string(), 1), ("b".to_string(), 2)]);
+        let current = BTreeMap::from([("b".to_string(), 3), ("c".to_string(), 4)]);
+        let changed = |a: &i32, b: &i32| a != b;
+
+        assert_eq!(
+            diff(&last, &current, changed),
+            vec![
+                (Change::Updated, 3),
+                (Change::Opened, 4),
+                (Change::Closed, 1)
+            ]
+        );
+        assert!(diff(&current, &current, changed).is_empty());
+    }
+}
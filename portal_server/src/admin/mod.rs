@@ -0,0 +1,628 @@
+//! Authenticated admin API to inspect and operate this instance
+use crate::client_manager;
+use crate::connected_clients::{ConnectedClient, Connections, SessionId};
+use crate::dns::{self, DnsRecord};
+use crate::observability::metrics::get_metrics;
+use crate::replay;
+use crate::request_log::Recorded;
+use crate::storage::{Agent, Session};
+use crate::{
+    get_active_streams, get_config, get_dns_provider, get_request_log, get_storage, get_usage,
+    ActiveStream, StreamId,
+};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
+use portal_lib::{NoticeLevel, Protocol, RequestLogEntry};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+mod dashboard;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+static STARTED_AT: OnceLock<Instant> = OnceLock::new();
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub session_id: String,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// the id the agent keeps across runs on its machine
+    #[serde(default)]
+    pub agent_id: Option<String>,
+    pub sub_domain: String,
+    pub is_anonymous: bool,
+    #[serde(default)]
+    pub tier: String,
+    pub connected_at: DateTime<Utc>,
+    pub streams: usize,
+    /// bytes relayed through this client's tunnel, shared with other agents serving it
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// what visitors speak to reach the tunnel
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// when the tunnel is closed, for tunnels that don't live forever
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ClientInfo {
+    fn new(client: &ConnectedClient) -> Self {
+        let (bytes_in, bytes_out) = get_metrics().tunnel_totals(&client.host);
+        ClientInfo {
+            client_id: client.id.to_string(),
+            session_id: client.session_id.to_string(),
+            name: client.name.clone(),
+            version: client.version.clone(),
+            agent_id: client.agent_id.clone(),
+            sub_domain: client.host.clone(),
+            is_anonymous: client.is_anonymous,
+            tier: client.tier.clone(),
+            connected_at: client.connected_at,
+            streams: get_active_streams()
+                .iter()
+                .filter(|stream| stream.client.session_id == client.session_id)
+                .count(),
+            bytes_in,
+            bytes_out,
+            protocol: client.protocol,
+            expires_at: client.expires_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamInfo {
+    pub stream_id: String,
+    pub session_id: String,
+    pub sub_domain: String,
+    pub age_secs: u64,
+    pub idle_secs: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// the stream outlived the relay to its visitor or its agent, until the next sweep
+    pub closed: bool,
+    pub orphaned: bool,
+}
+
+impl StreamInfo {
+    fn new(stream: &ActiveStream) -> Self {
+        StreamInfo {
+            stream_id: stream.id.to_string(),
+            session_id: stream.client.session_id.to_string(),
+            sub_domain: stream.client.host.clone(),
+            age_secs: stream.age().as_secs(),
+            idle_secs: stream.idle_for().as_secs(),
+            bytes_in: stream.stats.bytes_in(),
+            bytes_out: stream.stats.bytes_out(),
+            closed: stream.is_closed(),
+            orphaned: stream.is_orphaned(),
+        }
+    }
+}
+
+/// Only the streams open for at least `older_than` seconds, i.e. to look for leaks
+#[derive(Debug, Deserialize)]
+pub struct StreamsQuery {
+    pub older_than: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InstanceStats {
+    pub instance_id: String,
+    pub version: &'static str,
+    pub uptime_secs: u64,
+    pub connected_clients: usize,
+    pub active_streams: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// A logged request, and whether it was captured
+#[derive(Debug, Serialize)]
+pub struct RequestInfo {
+    #[serde(flatten)]
+    pub entry: RequestLogEntry,
+    pub captured: bool,
+}
+
+impl RequestInfo {
+    fn new(recorded: &Recorded) -> Self {
+        RequestInfo {
+            entry: recorded.entry.clone(),
+            captured: recorded.capture.is_some(),
+        }
+    }
+}
+
+/// A logged request with the captured bytes, base64 encoded
+#[derive(Debug, Serialize)]
+pub struct CaptureInfo {
+    #[serde(flatten)]
+    pub entry: RequestLogEntry,
+    pub request: Option<String>,
+    pub request_truncated: bool,
+    pub response: Option<String>,
+    pub response_truncated: bool,
+}
+
+impl CaptureInfo {
+    fn new(recorded: &Recorded) -> Self {
+        let capture = recorded.capture.as_deref();
+        let encode = |data: &[u8]| general_purpose::STANDARD.encode(data);
+        CaptureInfo {
+            entry: recorded.entry.clone(),
+            request: capture.map(|c| encode(&c.request)),
+            request_truncated: capture.is_some_and(|c| c.request_truncated),
+            response: capture.map(|c| encode(&c.response)),
+            response_truncated: capture.is_some_and(|c| c.response_truncated),
+        }
+    }
+}
+
+pub fn clients() -> Vec<ClientInfo> {
+    Connections::all().iter().map(ClientInfo::new).collect()
+}
+
+pub fn streams(query: &StreamsQuery) -> Vec<StreamInfo> {
+    let older_than = Duration::from_secs(query.older_than.unwrap_or(0));
+    get_active_streams()
+        .iter()
+        .filter(|stream| stream.age() >= older_than)
+        .map(|stream| StreamInfo::new(stream.value()))
+        .collect()
+}
+
+pub fn stats() -> InstanceStats {
+    let streams = get_active_streams();
+    InstanceStats {
+        instance_id: get_config().instance_id.clone(),
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: STARTED_AT.get_or_init(Instant::now).elapsed().as_secs(),
+        connected_clients: Connections::all().len(),
+        active_streams: streams.len(),
+        bytes_in: streams.iter().map(|s| s.stats.bytes_in()).sum(),
+        bytes_out: streams.iter().map(|s| s.stats.bytes_out()).sum(),
+    }
+}
+
+/// A time range of usage, both ends optional
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// The agents of one account, or of all of them
+#[derive(Debug, Deserialize)]
+pub struct AgentsQuery {
+    pub account: Option<String>,
+}
+
+/// A sub-domain only one account may claim
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reservation {
+    pub sub_domain: String,
+    pub account: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReserveBody {
+    pub account: String,
+}
+
+/// What a disconnected agent is told, if not `DEFAULT_DISCONNECT_REASON`
+#[derive(Debug, Deserialize)]
+pub struct DisconnectQuery {
+    pub reason: Option<String>,
+}
+
+pub const DEFAULT_DISCONNECT_REASON: &str = "an administrator disconnected it";
+
+#[derive(Debug, Deserialize)]
+pub struct TransferBody {
+    pub sub_domain: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BroadcastBody {
+    pub message: String,
+    #[serde(default)]
+    pub level: NoticeLevel,
+}
+
+/// Move an agent's tunnel to another sub-domain, replying with the agent as it is now
+fn transfer(session_id: &str, sub_domain: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    let Ok(session_id) = session_id.parse::<SessionId>() else {
+        return error_reply(StatusCode::NOT_FOUND, "no agent with this session");
+    };
+    match client_manager::transfer(&session_id, sub_domain) {
+        Ok(client) => {
+            warp::reply::with_status(warp::reply::json(&ClientInfo::new(&client)), StatusCode::OK)
+        }
+        Err(error) => {
+            let status = match error {
+                client_manager::Error::NotFound => StatusCode::NOT_FOUND,
+                client_manager::Error::InvalidSubDomain | client_manager::Error::NotHttp(_) => {
+                    StatusCode::BAD_REQUEST
+                }
+            };
+            error_reply(status, &error.to_string())
+        }
+    }
+}
+
+/// The record a custom domain needs to reach us, and whether the zone has it
+#[derive(Debug, Serialize)]
+pub struct DomainInfo {
+    #[serde(flatten)]
+    pub record: DnsRecord,
+    pub verified: bool,
+}
+
+/// Check or, with `create`, add the record pointing the custom domain `name` at us
+async fn domain(name: &str, create: bool) -> warp::reply::WithStatus<warp::reply::Json> {
+    let config = get_config();
+    let (Some(provider), Some(target)) = (get_dns_provider(), dns::target(&config)) else {
+        return error_reply(StatusCode::NOT_IMPLEMENTED, "no dns provider configured");
+    };
+    if !name.contains('.')
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || "-.*".contains(c))
+    {
+        return error_reply(StatusCode::BAD_REQUEST, "invalid domain name");
+    }
+
+    let record = DnsRecord::pointing(name, &target, config.dns_ttl);
+    let verified = if create {
+        dns::ensure(provider, &record).await.map(|_| true)
+    } else {
+        dns::verify(provider, &record).await
+    };
+    match verified {
+        Ok(verified) => warp::reply::with_status(
+            warp::reply::json(&DomainInfo { record, verified }),
+            StatusCode::OK,
+        ),
+        Err(error) => {
+            tracing::error!(%error, %name, "dns provider failed");
+            error_reply(StatusCode::BAD_GATEWAY, &error.to_string())
+        }
+    }
+}
+
+/// Close a visitor stream, returning whether it was open
+fn kill_stream(stream_id: &StreamId) -> bool {
+    let Some(stream) = get_active_streams()
+        .get(stream_id)
+        .map(|s| s.value().clone())
+    else {
+        return false;
+    };
+
+    tracing::info!(stream_id=%stream_id, "admin closed stream");
+    stream.close();
+    true
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+/// Requests must carry `Authorization: Bearer <admin_token>`, or a `token` query
+/// parameter where headers can't be set (i.e. a browser's `EventSource`)
+pub fn authorized() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(
+            |header: Option<String>, query: HashMap<String, String>| async move {
+                let config = get_config();
+                let expected = config.admin_token.as_deref();
+                let given = header
+                    .as_deref()
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                    .or(query.get("token").map(String::as_str));
+                match (expected, given) {
+                    (Some(expected), Some(given)) if constant_time_eq(expected, given) => Ok(()),
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            },
+        )
+        .untuple_one()
+}
+
+pub(crate) fn constant_time_eq(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> bool {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// The `/api` routes
+pub fn api() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let clients = warp::path!("clients")
+        .and(warp::get())
+        .map(|| warp::reply::json(&clients()));
+
+    let disconnect = warp::path!("clients" / String)
+        .and(warp::delete())
+        .and(warp::query::<DisconnectQuery>())
+        .map(|session_id: String, query: DisconnectQuery| {
+            let reason = query.reason.as_deref().unwrap_or(DEFAULT_DISCONNECT_REASON);
+            let found = session_id
+                .parse()
+                .map(|id| client_manager::disconnect(&id, reason))
+                .unwrap_or(false);
+            status_reply(found)
+        });
+
+    let move_client = warp::path!("clients" / String / "transfer")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(|session_id: String, body: TransferBody| transfer(&session_id, &body.sub_domain));
+
+    let broadcast = warp::path!("broadcast")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map(|body: BroadcastBody| {
+            if body.message.trim().is_empty() {
+                return error_reply(StatusCode::BAD_REQUEST, "the message is empty");
+            }
+            let agents = client_manager::broadcast(body.level, &body.message);
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "agents": agents })),
+                StatusCode::OK,
+            )
+        });
+
+    let streams = warp::path!("streams")
+        .and(warp::get())
+        .and(warp::query::<StreamsQuery>())
+        .map(|query: StreamsQuery| warp::reply::json(&streams(&query)));
+
+    let kill = warp::path!("streams" / String)
+        .and(warp::delete())
+        .map(|stream_id: String| {
+            let found = stream_id
+                .parse()
+                .map(|id| kill_stream(&id))
+                .unwrap_or(false);
+            status_reply(found)
+        });
+
+    let requests = warp::path!("requests" / String)
+        .and(warp::get())
+        .map(|tunnel: String| {
+            let requests: Vec<_> = get_request_log()
+                .recent(&tunnel)
+                .iter()
+                .map(RequestInfo::new)
+                .collect();
+            warp::reply::json(&requests)
+        });
+
+    let request = warp::path!("requests" / String / String)
+        .and(warp::get())
+        .then(|tunnel: String, id: String| async move {
+            match get_request_log().find(&tunnel, &id).await {
+                Some(recorded) => warp::reply::with_status(
+                    warp::reply::json(&CaptureInfo::new(&recorded)),
+                    StatusCode::OK,
+                ),
+                None => error_reply(StatusCode::NOT_FOUND, "no request with this id"),
+            }
+        });
+
+    let replay = warp::path!("requests" / String / String / "replay")
+        .and(warp::post())
+        .then(|tunnel: String, id: String| async move {
+            match replay::replay(&tunnel, &id).await {
+                Ok(recorded) => warp::reply::with_status(
+                    warp::reply::json(&CaptureInfo::new(&recorded)),
+                    StatusCode::OK,
+                ),
+                Err(error) => {
+                    let status = match error {
+                        replay::Error::NotFound => StatusCode::NOT_FOUND,
+                        replay::Error::Truncated => StatusCode::CONFLICT,
+                        replay::Error::TunnelOffline => StatusCode::SERVICE_UNAVAILABLE,
+                        replay::Error::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                        replay::Error::Closed => StatusCode::BAD_GATEWAY,
+                    };
+                    error_reply(status, &error.to_string())
+                }
+            }
+        });
+
+    let usage = warp::path!("usage")
+        .and(warp::get())
+        .and(warp::query::<UsageQuery>())
+        .then(|query: UsageQuery| async move {
+            storage_reply(get_usage().totals(query.from, query.to).await)
+        });
+
+    let account_usage = warp::path!("usage" / String)
+        .and(warp::get())
+        .and(warp::query::<UsageQuery>())
+        .then(|account: String, query: UsageQuery| async move {
+            storage_reply(
+                get_usage()
+                    .query(Some(&account), query.from, query.to)
+                    .await,
+            )
+        });
+
+    let sessions = warp::path!("sessions")
+        .and(warp::get())
+        .then(|| async { storage_reply::<Vec<Session>>(get_storage().sessions().await) });
+
+    let agents = warp::path!("agents")
+        .and(warp::get())
+        .and(warp::query::<AgentsQuery>())
+        .then(|query: AgentsQuery| async move {
+            storage_reply::<Vec<Agent>>(get_storage().agents(query.account.as_deref()).await)
+        });
+
+    // one machine may have used several keys, so an id is an agent per account
+    let agent =
+        warp::path!("agents" / String)
+            .and(warp::get())
+            .then(|agent_id: String| async move {
+                match get_storage().agents(None).await {
+                    Ok(agents) => {
+                        let agents: Vec<Agent> = agents
+                            .into_iter()
+                            .filter(|agent| agent.agent_id == agent_id)
+                            .collect();
+                        if agents.is_empty() {
+                            error_reply(StatusCode::NOT_FOUND, "no agent with this id was seen")
+                        } else {
+                            storage_reply(Ok(agents))
+                        }
+                    }
+                    Err(error) => storage_reply::<()>(Err(error)),
+                }
+            });
+
+    let reservation = warp::path!("reservations" / String).and(warp::get()).then(
+        |sub_domain: String| async move {
+            match get_storage().reservation(&sub_domain).await {
+                Ok(Some(account)) => storage_reply(Ok(Reservation {
+                    sub_domain,
+                    account,
+                })),
+                Ok(None) => error_reply(StatusCode::NOT_FOUND, "sub-domain isn't reserved"),
+                Err(error) => storage_reply::<()>(Err(error)),
+            }
+        },
+    );
+
+    let reserve = warp::path!("reservations" / String)
+        .and(warp::put())
+        .and(warp::body::json())
+        .then(|sub_domain: String, body: ReserveBody| async move {
+            match get_storage().reserve(&sub_domain, &body.account).await {
+                Ok(true) => storage_reply(Ok(Reservation {
+                    sub_domain,
+                    account: body.account,
+                })),
+                Ok(false) => error_reply(
+                    StatusCode::CONFLICT,
+                    "sub-domain is reserved for another account",
+                ),
+                Err(error) => storage_reply::<()>(Err(error)),
+            }
+        });
+
+    let release = warp::path!("reservations" / String)
+        .and(warp::delete())
+        .then(|sub_domain: String| async move {
+            match get_storage().release(&sub_domain).await {
+                Ok(released) => status_reply(released).into_response(),
+                Err(error) => storage_reply::<()>(Err(error)).into_response(),
+            }
+        });
+
+    let check_domain = warp::path!("domains" / String)
+        .and(warp::get())
+        .then(|name: String| async move { domain(&name, false).await });
+
+    let add_domain =
+        warp::path!("domains" / String)
+            .and(warp::put())
+            .then(|name: String| async move {
+                tracing::info!(%name, "admin added custom domain");
+                domain(&name, true).await
+            });
+
+    let stats = warp::path!("stats")
+        .and(warp::get())
+        .map(|| warp::reply::json(&stats()));
+
+    warp::path("api").and(authorized()).and(
+        clients
+            .or(disconnect)
+            .or(move_client)
+            .or(broadcast)
+            .or(streams)
+            .or(kill)
+            .or(requests)
+            .or(dashboard::tail())
+            .or(request)
+            .or(replay)
+            .or(usage)
+            .or(account_usage)
+            .or(sessions)
+            .or(agents)
+            .or(agent)
+            .or(reservation)
+            .or(reserve)
+            .or(release)
+            .or(check_domain)
+            .or(add_domain)
+            .or(stats)
+            .or(dashboard::events()),
+    )
+}
+
+fn error_reply(status: StatusCode, error: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "error": error })),
+        status,
+    )
+}
+
+/// The value as json, or a 500 if the storage failed us
+fn storage_reply<T: Serialize>(
+    result: Result<T, crate::storage::Error>,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    match result {
+        Ok(value) => warp::reply::with_status(warp::reply::json(&value), StatusCode::OK),
+        Err(error) => {
+            tracing::error!(%error, "storage failed");
+            error_reply(StatusCode::INTERNAL_SERVER_ERROR, &error.to_string())
+        }
+    }
+}
+
+fn status_reply(found: bool) -> warp::reply::WithStatus<&'static str> {
+    if found {
+        warp::reply::with_status("ok", StatusCode::OK)
+    } else {
+        warp::reply::with_status("not found", StatusCode::NOT_FOUND)
+    }
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, message) = if rejection.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, "unauthorized")
+    } else if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found")
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+    } else {
+        (StatusCode::BAD_REQUEST, "bad request")
+    };
+    Ok(warp::reply::with_status(message, status))
+}
+
+pub fn spawn<A: Into<SocketAddr>>(addr: A) -> Result<(), warp::Error> {
+    STARTED_AT.get_or_init(Instant::now);
+
+    if get_config().admin_token.is_none() {
+        tracing::error!("admin api disabled: no admin token configured");
+        return Ok(());
+    }
+
+    let routes = api().or(dashboard::assets()).recover(handle_rejection);
+    let (_, server) = warp::serve(routes).try_bind_ephemeral(addr.into())?;
+    tokio::spawn(server);
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! Limits on the visitor connections we take on, so a spike toward one tunnel
+//! is shed at the door instead of starving the agents' control connections
+//! of CPU and file descriptors
+use crate::observability::metrics::get_metrics;
+use crate::throttle::TokenBucket;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::net::TcpStream;
+
+/// What an over capacity visitor gets, without waiting for it to send anything
+const HTTP_BUSY_RESPONSE: &[u8] = b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nRetry-After: 1\r\nConnection: close\r\n\r\n";
+
+/// Counts the visitor connections open and those accepted lately
+#[derive(Default)]
+pub struct Gate {
+    open: AtomicUsize,
+    accepts: Mutex<Option<TokenBucket>>,
+}
+
+impl std::fmt::Debug for Gate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Gate").field("open", &self.open()).finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum Admission {
+    Admitted(Permit),
+    /// as many connections are open as we allow
+    Busy,
+    /// more connections came in this second than we accept
+    RateLimited,
+}
+
+/// A visitor connection counted as open until dropped
+#[derive(Debug)]
+pub struct Permit(&'static Gate);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+        get_metrics().visitor_connections.dec();
+    }
+}
+
+impl Gate {
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Whether to serve one more connection, given at most `max_connections` open
+    /// at once and `accept_rate` new ones a second, bursting up to a second's worth
+    pub fn admit(
+        &'static self,
+        max_connections: Option<usize>,
+        accept_rate: Option<u32>,
+        now: Instant,
+    ) -> Admission {
+        if let Some(rate) = accept_rate {
+            let mut accepts = self.accepts.lock().unwrap();
+            // the rate may have been reloaded since
+            let bucket = match accepts.as_mut() {
+                Some(bucket) if bucket.rate == rate as u64 => bucket,
+                _ => accepts.insert(TokenBucket::new(rate as u64, now)),
+            };
+            if !bucket.try_take(1, now) {
+                return Admission::RateLimited;
+            }
+        }
+
+        let open = self.open.fetch_add(1, Ordering::Relaxed);
+        if max_connections.is_some_and(|max| open >= max) {
+            self.open.fetch_sub(1, Ordering::Relaxed);
+            return Admission::Busy;
+        }
+        get_metrics().visitor_connections.inc();
+        Admission::Admitted(Permit(self))
+    }
+}
+
+/// Turn a visitor away as cheaply as we can: a 503 if the socket takes it right away
+/// when we're at capacity, a reset when connections come in faster than we accept them
+pub fn shed(socket: TcpStream, admission: &Admission) {
+    let reason = match admission {
+        Admission::Admitted(_) => return,
+        Admission::Busy => {
+            let _ = socket.try_write(HTTP_BUSY_RESPONSE);
+            "busy"
+        }
+        Admission::RateLimited => {
+            let _ = socket.set_linger(Some(std::time::Duration::ZERO));
+            "rate_limited"
+        }
+    };
+    tracing::debug!(reason, "shedding visitor connection");
+    get_metrics().connection_shed(reason);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_connection_cap() {
+        let gate: &'static Gate = Box::leak(Box::default());
+        let now = Instant::now();
+
+        let first = gate.admit(Some(2), None, now);
+        let second = gate.admit(Some(2), None, now);
+        assert!(matches!(first, Admission::Admitted(_)));
+        assert!(matches!(second, Admission::Admitted(_)));
+        assert!(matches!(gate.admit(Some(2), None, now), Admission::Busy));
+        assert_eq!(gate.open(), 2);
+
+        // closing one makes room for the next
+        drop(first);
+        assert!(matches!(
+            gate.admit(Some(2), None, now),
+            Admission::Admitted(_)
+        ));
+        assert_eq!(gate.open(), 1);
+    }
+
+    #[test]
+    fn test_accept_rate() {
+        let gate: &'static Gate = Box::leak(Box::default());
+        let start = Instant::now();
+
+        let burst: Vec<_> = (0..3).map(|_| gate.admit(None, Some(3), start)).collect();
+        assert!(burst.iter().all(|a| matches!(a, Admission::Admitted(_))));
+        assert!(matches!(
+            gate.admit(None, Some(3), start),
+            Admission::RateLimited
+        ));
+
+        // a third of a second later there's room for one more
+        let later = start + Duration::from_millis(340);
+        assert!(matches!(
+            gate.admit(None, Some(3), later),
+            Admission::Admitted(_)
+        ));
+        assert!(matches!(
+            gate.admit(None, Some(3), later),
+            Admission::RateLimited
+        ));
+    }
+}
@@ -0,0 +1,146 @@
+//! Tell agents of a newer version than theirs, so whoever runs them may upgrade. We
+//! know of the newest version from the config, or learn it from a release feed.
+use crate::connected_clients::Connections;
+use crate::{get_agent_updates, get_config};
+use portal_lib::{ControlPacket, UpdateAvailable};
+use serde::Deserialize;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// How long the release feed has to answer
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The newest release in a GitHub style `releases/latest` feed
+#[derive(Deserialize)]
+struct Release {
+    /// the version, i.e. `0.1.21` or `v0.1.21`
+    name: String,
+    html_url: String,
+}
+
+#[derive(Default)]
+pub struct AgentUpdates {
+    /// the newest version the release feed told us of, and where to get it
+    released: RwLock<Option<(semver::Version, String)>>,
+}
+
+impl AgentUpdates {
+    /// The newest agent version we know of, and where to get it
+    pub fn latest(&self) -> Option<(semver::Version, String)> {
+        let config = get_config();
+        let configured = config
+            .latest_agent_version
+            .clone()
+            .map(|version| (version, config.agent_download_url.clone()));
+        let released = self.released.read().unwrap().clone();
+        configured
+            .into_iter()
+            .chain(released)
+            .max_by(|a, b| a.0.cmp(&b.0))
+    }
+
+    /// The newer version an agent of `version` should upgrade to, if we know of one
+    pub fn for_agent(&self, version: Option<&str>) -> Option<UpdateAvailable> {
+        let (latest, download_url) = self.latest()?;
+        newer_than(version, &latest, &download_url)
+    }
+
+    /// Learn the newest version from the release feed
+    async fn refresh(&self, feed: &str) {
+        let release = match fetch(feed).await {
+            Ok(release) => release,
+            Err(error) => {
+                tracing::warn!(%feed, %error, "failed to fetch the agent release feed");
+                return;
+            }
+        };
+        match semver::Version::parse(release.name.trim_start_matches('v')) {
+            Ok(version) => {
+                tracing::debug!(%version, "learned the newest agent version");
+                *self.released.write().unwrap() = Some((version, release.html_url));
+            }
+            Err(error) => {
+                tracing::warn!(name=%release.name, %error, "agent release isn't a version")
+            }
+        }
+    }
+}
+
+/// The update for an agent of `version` when `latest` is newer. Agents that don't say
+/// their version predate saying it, so they're older.
+fn newer_than(
+    version: Option<&str>,
+    latest: &semver::Version,
+    download_url: &str,
+) -> Option<UpdateAvailable> {
+    let current = version.and_then(|version| semver::Version::parse(version).ok());
+    if current.is_some_and(|current| &current >= latest) {
+        return None;
+    }
+    Some(UpdateAvailable {
+        version: latest.to_string(),
+        download_url: download_url.to_string(),
+    })
+}
+
+async fn fetch(feed: &str) -> Result<Release, reqwest::Error> {
+    reqwest::Client::new()
+        .get(feed)
+        .header(reqwest::header::USER_AGENT, "portal-server")
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+/// Every `agent_update_interval`, fetch the release feed and tell the agents older than
+/// the newest version about it
+pub fn spawn_notifier() {
+    let Some(period) = get_config().agent_update_interval else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Some(feed) = get_config().agent_release_feed.clone() {
+                get_agent_updates().refresh(&feed).await;
+            }
+
+            let mut told = 0;
+            for client in Connections::all() {
+                if let Some(update) = get_agent_updates().for_agent(client.version.as_deref()) {
+                    client.queue(ControlPacket::UpdateAvailable(update));
+                    told += 1;
+                }
+            }
+            if told > 0 {
+                tracing::info!(agents = told, "told agents of a newer version");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_older_agents_are_told() {
+        let latest = semver::Version::new(0, 1, 21);
+        let url = "https://example.com/releases";
+
+        let update = newer_than(Some("0.1.20"), &latest, url).unwrap();
+        assert_eq!(update.version, "0.1.21");
+        assert_eq!(update.download_url, url);
+        assert!(newer_than(Some("0.1.21"), &latest, url).is_none());
+        assert!(newer_than(Some("0.2.0"), &latest, url).is_none());
+
+        // agents that don't say their version are older
+        assert!(newer_than(None, &latest, url).is_some());
+        assert!(newer_than(Some("dev"), &latest, url).is_some());
+    }
+}
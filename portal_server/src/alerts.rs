@@ -0,0 +1,217 @@
+//! Alert when a tunnel gets slow or starts failing
+use crate::connected_clients::ConnectedClient;
+use crate::get_webhooks;
+use crate::webhooks::Event;
+use dashmap::DashMap;
+use portal_lib::{AlertThresholds, RequestLogEntry};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Most requests we keep per tunnel, however busy it is
+const MAX_SAMPLES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    P95Latency,
+    ErrorRate,
+}
+
+impl Metric {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::P95Latency => "p95_latency",
+            Metric::ErrorRate => "error_rate",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    duration: u64,
+    error: bool,
+}
+
+/// Recent requests of one tunnel and which alerts are firing
+#[derive(Debug, Default)]
+struct Window {
+    samples: VecDeque<Sample>,
+    latency_firing: bool,
+    errors_firing: bool,
+}
+
+impl Window {
+    fn p95_latency(&self) -> u64 {
+        let mut durations: Vec<u64> = self.samples.iter().map(|s| s.duration).collect();
+        durations.sort_unstable();
+        let rank = (durations.len() * 95).div_ceil(100);
+        durations
+            .get(rank.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn error_rate(&self) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        self.samples.iter().filter(|s| s.error).count() as f64 / self.samples.len() as f64
+    }
+}
+
+/// A threshold was crossed, either way
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub metric: Metric,
+    pub value: f64,
+    pub threshold: f64,
+    /// whether the alert started, or otherwise resolved
+    pub firing: bool,
+}
+
+/// Per tunnel latency and error rates over a sliding window
+#[derive(Debug)]
+pub struct Alerts {
+    window: Duration,
+    /// requests a window needs before it may fire, so a single failure won't
+    min_requests: usize,
+    tunnels: DashMap<String, Window>,
+}
+
+impl Alerts {
+    pub fn new(window: Duration, min_requests: usize) -> Self {
+        Alerts {
+            window,
+            min_requests: min_requests.max(1),
+            tunnels: DashMap::new(),
+        }
+    }
+
+    /// Account a completed request to the client's tunnel, alerting if that crossed a threshold
+    pub fn observe(&self, client: &ConnectedClient, entry: &RequestLogEntry) {
+        if entry.replay || client.alerts == AlertThresholds::default() {
+            return;
+        }
+
+        let sample = Sample {
+            at: Instant::now(),
+            duration: entry.duration,
+            error: entry.status >= 500 || entry.status == 0,
+        };
+        for alert in self.push(&client.host, sample, client.alerts) {
+            if alert.firing {
+                tracing::warn!(tunnel=%client.host, metric=alert.metric.as_str(), value=alert.value, threshold=alert.threshold, "tunnel alert firing");
+            } else {
+                tracing::info!(tunnel=%client.host, metric=alert.metric.as_str(), value=alert.value, threshold=alert.threshold, "tunnel alert resolved");
+            }
+            get_webhooks().emit(Event::Alert {
+                sub_domain: client.host.clone(),
+                metric: alert.metric.as_str(),
+                value: alert.value,
+                threshold: alert.threshold,
+                firing: alert.firing,
+            });
+        }
+    }
+
+    fn push(&self, tunnel: &str, sample: Sample, thresholds: AlertThresholds) -> Vec<Alert> {
+        let mut window = self.tunnels.entry(tunnel.to_string()).or_default();
+        while window
+            .samples
+            .front()
+            .is_some_and(|oldest| sample.at.saturating_duration_since(oldest.at) > self.window)
+            || window.samples.len() >= MAX_SAMPLES
+        {
+            window.samples.pop_front();
+        }
+        window.samples.push_back(sample);
+
+        let mut alerts = vec![];
+        if window.samples.len() < self.min_requests {
+            return alerts;
+        }
+
+        if let Some(threshold) = thresholds.p95_latency {
+            let value = window.p95_latency();
+            let firing = value > threshold;
+            if firing != window.latency_firing {
+                window.latency_firing = firing;
+                alerts.push(Alert {
+                    metric: Metric::P95Latency,
+                    value: value as f64,
+                    threshold: threshold as f64,
+                    firing,
+                });
+            }
+        }
+
+        if let Some(threshold) = thresholds.error_rate {
+            let value = window.error_rate();
+            let firing = value > threshold;
+            if firing != window.errors_firing {
+                window.errors_firing = firing;
+                alerts.push(Alert {
+                    metric: Metric::ErrorRate,
+                    value,
+                    threshold,
+                    firing,
+                });
+            }
+        }
+
+        alerts
+    }
+
+    /// Forget a tunnel no agent serves anymore
+    pub fn remove(&self, tunnel: &str) {
+        self.tunnels.remove(tunnel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_rate_alert() {
+        let alerts = Alerts::new(Duration::from_secs(60), 4);
+        let thresholds = AlertThresholds {
+            p95_latency: Some(100),
+            error_rate: Some(0.5),
+        };
+        let start = Instant::now();
+        let push = |secs, duration, error| {
+            let sample = Sample {
+                at: start + Duration::from_secs(secs),
+                duration,
+                error,
+            };
+            alerts.push("foo", sample, thresholds)
+        };
+
+        // not enough requests yet
+        assert!(push(0, 10, true).is_empty());
+        assert!(push(1, 10, true).is_empty());
+        assert!(push(2, 10, true).is_empty());
+
+        let fired = push(3, 10, false);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].metric, Metric::ErrorRate);
+        assert!(fired[0].firing);
+
+        // still failing, so no news
+        assert!(push(4, 10, true).is_empty());
+
+        // the failures slide out of the window
+        let resolved = push(62, 10, false);
+        assert_eq!(resolved.len(), 1);
+        assert!(!resolved[0].firing);
+        assert_eq!(resolved[0].metric, Metric::ErrorRate);
+
+        // too slow
+        let fired = push(63, 500, false);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].metric, Metric::P95Latency);
+        assert_eq!(fired[0].value, 500.0);
+    }
+}
@@ -1,67 +1,201 @@
 use crate::auth::reconnect_token::ReconnectTokenPayload;
-use crate::auth::{AuthResult, AuthService};
-use crate::{get_config, ReconnectToken};
-use futures::{SinkExt, StreamExt};
-use portal_lib::{ClientHello, ClientId, ClientType, ServerHello};
+use crate::auth::{AuthResult, AuthService, Tier};
+use crate::subdomain;
+use crate::transport::AgentConnection;
+use crate::webhooks::Event;
+use crate::{get_config, get_webhooks, ReconnectToken};
+use chrono::{DateTime, Utc};
+use portal_lib::{
+    AlertThresholds, ClientHello, ClientId, ClientType, CorsOptions, ServerHello, ServiceInfo,
+};
 use tracing::{debug, error};
-use warp::filters::ws::{Message, WebSocket};
+
+/// Longest agent id we keep, longer ones are ignored
+const MAX_AGENT_ID_LENGTH: usize = 64;
 
 pub struct ClientHandshake {
     pub id: ClientId,
     pub sub_domain: String,
     pub is_anonymous: bool,
+    /// the account's tier
+    pub tier: String,
+    /// bytes per second, overriding the tier's and the server wide limit
+    pub bandwidth_limit: Option<u64>,
+    /// the agent's self reported version
+    pub version: Option<String>,
+    /// the agent's self reported name
+    pub name: Option<String>,
+    /// the id the agent keeps across runs on its machine
+    pub agent_id: Option<String>,
+    /// whether the agent asked to receive the request log
+    pub request_log: bool,
+    /// the agent's own alert thresholds
+    pub alerts: AlertThresholds,
+    /// whether the agent reconnects where we redirect it
+    pub accepts_redirect: bool,
+    /// whether the agent already followed a redirect here
+    pub redirected: bool,
+    /// what visitors speak to reach the agent
+    pub service: ServiceInfo,
+    /// whether the agent asked for its visitors' requests to be queued while it's offline
+    pub queue_offline: bool,
+    /// whether the agent lets us cache its responses
+    pub cache: bool,
+    /// the tunnel whose requests the agent receives copies of
+    pub mirror_of: Option<String>,
+    /// whether the agent asked for its tunnel's access log
+    pub access_log: bool,
+    /// where the agent would rather be served
+    pub preferred_region: Option<String>,
+    pub preferred_instance: Option<String>,
+    /// whether the agent asked for its tunnel's traffic stats
+    pub stats: bool,
+    /// the CORS headers the agent asked us to add to its responses
+    pub cors: Option<CorsOptions>,
+    /// whether the agent asked for plain http visitors to be sent to https
+    pub https_redirect: bool,
+    /// whether the agent asked for HSTS on its responses over https
+    pub hsts: bool,
+    /// seconds the agent asked its tunnel to stay open for
+    pub ttl: Option<u64>,
+    /// when the tunnel is closed, kept across reconnects so they don't extend it
+    pub expires_at: Option<DateTime<Utc>>,
+    /// whether the agent sends no more of a stream's data than we credit it with
+    pub flow_control: bool,
+    /// the data packets of each stream the agent may send ahead of our credits, if it
+    /// flow controls
+    pub stream_window: Option<usize>,
 }
 
-#[tracing::instrument(skip(websocket))]
+#[tracing::instrument(skip(connection))]
 pub async fn auth_client_handshake(
-    mut websocket: WebSocket,
-) -> Option<(WebSocket, ClientHandshake)> {
-    let client_hello_data = match websocket.next().await {
-        Some(Ok(msg)) => msg,
-        _ => {
+    mut connection: AgentConnection,
+) -> Option<(AgentConnection, ClientHandshake)> {
+    let client_hello_data = match connection.next().await {
+        Some(data) => data,
+        None => {
             error!("no client init message");
             return None;
         }
     };
     debug!("got client init message: {:?}", client_hello_data);
-    auth_client(client_hello_data.as_bytes(), websocket).await
+    auth_client(&client_hello_data, connection).await
 }
 
-#[tracing::instrument(skip(client_hello_data, websocket))]
+#[tracing::instrument(skip(client_hello_data, connection))]
 async fn auth_client(
     client_hello_data: &[u8],
-    mut websocket: WebSocket,
-) -> Option<(WebSocket, ClientHandshake)> {
+    mut connection: AgentConnection,
+) -> Option<(AgentConnection, ClientHandshake)> {
     // parse the client hello
     let client_hello: ClientHello = match serde_json::from_slice(client_hello_data) {
         Ok(ch) => ch,
         Err(error) => {
             error!(?error, "invalid client hello");
+            auth_failure("invalid_hello", None);
             let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
+            let _ = connection.send(data).await;
             return None;
         }
     };
 
     debug!("got client hello: {:?}", client_hello);
 
-    let (auth_key, client_id, requested_sub_domain) = match client_hello.client_type {
+    let version = client_hello.version.clone();
+    let name = client_hello.name.clone();
+    let agent_id = client_hello
+        .agent_id
+        .as_deref()
+        .map(str::trim)
+        .filter(|id| !id.is_empty() && id.len() <= MAX_AGENT_ID_LENGTH)
+        .map(str::to_string);
+    let request_log = client_hello.request_log;
+    let alerts = client_hello.alerts;
+    let accepts_redirect = client_hello.accepts_redirect;
+    let redirected = client_hello.redirected;
+    let service = client_hello.service.clone();
+    let queue_offline = client_hello.queue_offline;
+    let cache = client_hello.cache;
+    let mirror_of = client_hello.mirror_of.as_deref().map(str::to_lowercase);
+    let access_log = client_hello.access_log;
+    let preferred_region = client_hello.preferred_region.clone();
+    let preferred_instance = client_hello.preferred_instance.clone();
+    let stats = client_hello.stats;
+    let cors = client_hello.cors.clone();
+    let https_redirect = client_hello.https_redirect;
+    let hsts = client_hello.hsts;
+    let ttl = client_hello.ttl;
+    let flow_control = client_hello.flow_control;
+    let (connection, handshake) = auth_client_hello(client_hello, connection).await?;
+    Some((
+        connection,
+        ClientHandshake {
+            version,
+            name,
+            agent_id,
+            request_log,
+            alerts,
+            accepts_redirect,
+            redirected,
+            service,
+            queue_offline,
+            cache,
+            mirror_of,
+            access_log,
+            preferred_region,
+            preferred_instance,
+            stats,
+            cors,
+            https_redirect,
+            hsts,
+            ttl,
+            flow_control,
+            ..handshake
+        },
+    ))
+}
+
+async fn auth_client_hello(
+    client_hello: ClientHello,
+    mut connection: AgentConnection,
+) -> Option<(AgentConnection, ClientHandshake)> {
+    let suggest = client_hello.accepts_suggestions;
+    let (auth_key, client_id, requested_sub_domain, tier) = match client_hello.client_type {
         ClientType::Anonymous => {
             // let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            // let _ = websocket.send(Message::binary(data)).await;
+            // let _ = connection.send(data).await;
             // return None;
 
+            let config = get_config();
+            let anonymous_tier = config.tier(&config.anonymous_tier);
+
             // determine the client and subdomain
             let (client_id, sub_domain) =
                 match (client_hello.reconnect_token, client_hello.sub_domain) {
                     (Some(token), _) => {
-                        return handle_reconnect_token(token, websocket).await;
+                        return handle_reconnect_token(token, connection).await;
                     }
-                    (None, Some(sd)) => (
-                        ClientId::generate(),
-                        ServerHello::prefixed_random_domain(&sd),
-                    ),
-                    (None, None) => (ClientId::generate(), ServerHello::random_domain()),
+                    (None, Some(sd)) if anonymous_tier.custom_sub_domains => {
+                        let sub_domain = ServerHello::prefixed_random_domain(&sd);
+                        if subdomain::is_reserved_for(
+                            &get_config(),
+                            &anonymous_tier,
+                            &sub_domain.to_lowercase(),
+                        ) {
+                            error!("invalid client hello: sub-domain restrict!");
+                            let data = sub_domain_taken(
+                                &sub_domain.to_lowercase(),
+                                &anonymous_tier,
+                                None,
+                                suggest,
+                            )
+                            .await;
+                            let _ = connection.send(data).await;
+                            return None;
+                        }
+                        (ClientId::generate(), sub_domain)
+                    }
+                    (None, _) => (ClientId::generate(), subdomain::random()),
                 };
 
             debug!(
@@ -70,41 +204,82 @@ async fn auth_client(
                 "generated client id and sub domain"
             );
             return Some((
-                websocket,
+                connection,
                 ClientHandshake {
                     id: client_id,
                     sub_domain,
                     is_anonymous: true,
+                    tier: config.anonymous_tier.clone(),
+                    bandwidth_limit: None,
+                    version: None,
+                    name: None,
+                    agent_id: None,
+                    request_log: false,
+                    alerts: AlertThresholds::default(),
+                    accepts_redirect: false,
+                    redirected: false,
+                    service: ServiceInfo::default(),
+                    queue_offline: false,
+                    cache: false,
+                    mirror_of: None,
+                    access_log: false,
+                    stats: false,
+                    cors: None,
+                    https_redirect: false,
+                    hsts: false,
+                    ttl: None,
+                    expires_at: None,
+                    flow_control: false,
+                    stream_window: None,
+                    preferred_region: None,
+                    preferred_instance: None,
                 },
             ));
         }
-        ClientType::Auth { key } => match client_hello.sub_domain {
-            Some(requested_sub_domain) => {
-                let client_id = key.client_id();
-                let (ws, sub_domain) = match sanitize_sub_domain_and_pre_validate(
-                    websocket,
-                    requested_sub_domain,
-                    &client_id,
-                )
-                .await
-                {
-                    Some(s) => s,
-                    None => return None,
-                };
-                websocket = ws;
+        ClientType::Auth { key } => {
+            let tier = crate::get_auth_db_service()
+                .tier(&key.0)
+                .unwrap_or_else(|error| {
+                    error!(?error, "error getting account tier");
+                    None
+                })
+                .unwrap_or_else(|| get_config().default_tier.clone());
+            let account_tier = get_config().tier(&tier);
 
-                (key, client_id, sub_domain)
-            }
-            None => {
-                if let Some(token) = client_hello.reconnect_token {
-                    return handle_reconnect_token(token, websocket).await;
-                } else {
-                    let sub_domain = ServerHello::random_domain();
+            // tiers that can't pick their sub-domain get a random one
+            let requested_sub_domain = client_hello
+                .sub_domain
+                .filter(|_| account_tier.custom_sub_domains);
+            match requested_sub_domain {
+                Some(requested_sub_domain) => {
                     let client_id = key.client_id();
-                    (key, client_id, sub_domain)
+                    let (accepted, sub_domain) = match sanitize_sub_domain_and_pre_validate(
+                        connection,
+                        requested_sub_domain,
+                        &client_id,
+                        &account_tier,
+                        suggest,
+                    )
+                    .await
+                    {
+                        Some(s) => s,
+                        None => return None,
+                    };
+                    connection = accepted;
+
+                    (key, client_id, sub_domain, tier)
+                }
+                None => {
+                    if let Some(token) = client_hello.reconnect_token {
+                        return handle_reconnect_token(token, connection).await;
+                    } else {
+                        let sub_domain = subdomain::random();
+                        let client_id = key.client_id();
+                        (key, client_id, sub_domain, tier)
+                    }
                 }
             }
-        },
+        }
     };
 
     tracing::info!(requested_sub_domain=%requested_sub_domain, "will auth sub domain");
@@ -118,46 +293,82 @@ async fn auth_client(
                 // ServerHello::prefixed_random_domain(&requested_sub_domain)
                 // TODO: create free trial domain
                 tracing::info!(requested_sub_domain=%requested_sub_domain, "payment required");
+                auth_failure("payment_required", Some(requested_sub_domain));
                 let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-                let _ = websocket.send(Message::binary(data)).await;
+                let _ = connection.send(data).await;
                 return None;
             }
             Ok(AuthResult::ReservedByOther) => {
-                let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-                let _ = websocket.send(Message::binary(data)).await;
+                let tier = get_config().tier(&tier);
+                let data =
+                    sub_domain_taken(&requested_sub_domain, &tier, Some(&client_id), suggest).await;
+                let _ = connection.send(data).await;
                 return None;
             }
             Err(error) => {
                 error!(?error, "error auth-ing user");
+                auth_failure("auth_error", Some(requested_sub_domain));
                 let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-                let _ = websocket.send(Message::binary(data)).await;
+                let _ = connection.send(data).await;
                 return None;
             }
         };
 
     tracing::info!(subdomain=%sub_domain, "did auth sub_domain");
 
+    let bandwidth_limit = crate::get_auth_db_service()
+        .bandwidth_limit(&auth_key.0)
+        .unwrap_or_else(|error| {
+            error!(?error, "error getting bandwidth limit");
+            None
+        });
+
     Some((
-        websocket,
+        connection,
         ClientHandshake {
             id: client_id,
             sub_domain,
             is_anonymous: false,
+            tier,
+            bandwidth_limit,
+            version: None,
+            name: None,
+            agent_id: None,
+            request_log: false,
+            alerts: AlertThresholds::default(),
+            accepts_redirect: false,
+            redirected: false,
+            service: ServiceInfo::default(),
+            queue_offline: false,
+            cache: false,
+            mirror_of: None,
+            access_log: false,
+            stats: false,
+            cors: None,
+            https_redirect: false,
+            hsts: false,
+            ttl: None,
+            expires_at: None,
+            flow_control: false,
+            stream_window: None,
+            preferred_region: None,
+            preferred_instance: None,
         },
     ))
 }
 
-#[tracing::instrument(skip(token, websocket))]
+#[tracing::instrument(skip(token, connection))]
 async fn handle_reconnect_token(
     token: ReconnectToken,
-    mut websocket: WebSocket,
-) -> Option<(WebSocket, ClientHandshake)> {
+    mut connection: AgentConnection,
+) -> Option<(AgentConnection, ClientHandshake)> {
     let payload = match ReconnectTokenPayload::verify(token, &get_config().master_sig_key) {
         Ok(payload) => payload,
         Err(error) => {
             error!(?error, "invalid reconnect token");
+            auth_failure("invalid_reconnect_token", None);
             let data = serde_json::to_vec(&ServerHello::AuthFailed).unwrap_or_default();
-            let _ = websocket.send(Message::binary(data)).await;
+            let _ = connection.send(data).await;
             return None;
         }
     };
@@ -168,20 +379,50 @@ async fn handle_reconnect_token(
     );
 
     Some((
-        websocket,
+        connection,
         ClientHandshake {
             id: payload.client_id,
             sub_domain: payload.sub_domain,
             is_anonymous: true,
+            tier: get_config().anonymous_tier.clone(),
+            bandwidth_limit: None,
+            version: None,
+            name: None,
+            agent_id: None,
+            request_log: false,
+            alerts: AlertThresholds::default(),
+            accepts_redirect: false,
+            redirected: false,
+            service: ServiceInfo::default(),
+            queue_offline: false,
+            cache: false,
+            mirror_of: None,
+            access_log: false,
+            stats: false,
+            cors: None,
+            https_redirect: false,
+            hsts: false,
+            ttl: None,
+            expires_at: payload.tunnel_expires,
+            flow_control: false,
+            stream_window: None,
+            preferred_region: None,
+            preferred_instance: None,
         },
     ))
 }
 
+fn auth_failure(reason: &'static str, sub_domain: Option<String>) {
+    get_webhooks().emit(Event::AuthFailure { reason, sub_domain });
+}
+
 async fn sanitize_sub_domain_and_pre_validate(
-    mut websocket: WebSocket,
+    mut connection: AgentConnection,
     requested_sub_domain: String,
     client_id: &ClientId,
-) -> Option<(WebSocket, String)> {
+    tier: &Tier,
+    suggest: bool,
+) -> Option<(AgentConnection, String)> {
     // ignore uppercase
     let sub_domain = requested_sub_domain.to_lowercase();
 
@@ -193,27 +434,41 @@ async fn sanitize_sub_domain_and_pre_validate(
     {
         error!("invalid client hello: only alphanumeric/hyphen chars allowed!");
         let data = serde_json::to_vec(&ServerHello::InvalidSubDomain).unwrap_or_default();
-        let _ = websocket.send(Message::binary(data)).await;
+        let _ = connection.send(data).await;
         return None;
     }
 
     // ensure it's not a restricted one
-    if get_config().blocked_sub_domains.contains(&sub_domain) {
+    if subdomain::is_reserved_for(&get_config(), tier, &sub_domain) {
         error!("invalid client hello: sub-domain restrict!");
-        let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-        let _ = websocket.send(Message::binary(data)).await;
+        let data = sub_domain_taken(&sub_domain, tier, Some(client_id), suggest).await;
+        let _ = connection.send(data).await;
         return None;
     }
 
+    // ensure no other account reserved it
+    match crate::get_storage().reservation(&sub_domain).await {
+        Ok(Some(account)) if account != client_id.to_string() => {
+            error!("invalid client hello: sub-domain reserved for another account!");
+            let data = sub_domain_taken(&sub_domain, tier, Some(client_id), suggest).await;
+            let _ = connection.send(data).await;
+            return None;
+        }
+        Ok(_) => {}
+        Err(error) => {
+            error!(%error, "failed to look up the sub-domain's reservation");
+        }
+    }
+
     // ensure this sub-domain isn't taken
-    // check all instances
-    match crate::network::instance_for_host(&sub_domain).await {
+    // check all instances, a cached answer may be stale
+    match crate::get_host_registry().lookup(&sub_domain).await {
         Err(crate::network::Error::DoesNotServeHost) => {}
         Ok((_, existing_client)) => {
             if &existing_client != client_id {
                 error!("invalid client hello: requested sub domain in use already!");
-                let data = serde_json::to_vec(&ServerHello::SubDomainInUse).unwrap_or_default();
-                let _ = websocket.send(Message::binary(data)).await;
+                let data = sub_domain_taken(&sub_domain, tier, Some(client_id), suggest).await;
+                let _ = connection.send(data).await;
                 return None;
             }
         }
@@ -222,5 +477,23 @@ async fn sanitize_sub_domain_and_pre_validate(
         }
     }
 
-    Some((websocket, sub_domain))
+    Some((connection, sub_domain))
+}
+
+/// The hello telling an agent the sub-domain it asked for is taken, suggesting others it
+/// could have if it understands suggestions
+async fn sub_domain_taken(
+    sub_domain: &str,
+    tier: &Tier,
+    account: Option<&ClientId>,
+    suggest: bool,
+) -> Vec<u8> {
+    let hello = if suggest {
+        ServerHello::SubDomainTaken {
+            suggestions: subdomain::suggestions(sub_domain, tier, account).await,
+        }
+    } else {
+        ServerHello::SubDomainInUse
+    };
+    serde_json::to_vec(&hello).unwrap_or_default()
 }
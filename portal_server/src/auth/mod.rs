@@ -1,3 +1,4 @@
+use crate::usage::UsageRecord;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
@@ -19,11 +20,21 @@ impl std::fmt::Debug for SigKey {
 #[serde(transparent)]
 pub struct Signature(String);
 
+impl Signature {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 impl SigKey {
     pub fn generate() -> Self {
         SigKey(rand::thread_rng().gen::<[u8; 32]>())
     }
 
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
     pub fn from_hex(hex: &str) -> Result<Self, ()> {
         let bytes = hex::decode(hex)
             .map_err(|_| ())?
@@ -58,6 +69,51 @@ pub trait AuthService {
         auth_key: &Self::AuthKey,
         subdomain: &str,
     ) -> Result<AuthResult, Self::Error>;
+
+    /// The name of this key's account tier, `None` for the default tier
+    fn tier(&self, _auth_key: &Self::AuthKey) -> Result<Option<String>, Self::Error> {
+        Ok(None)
+    }
+
+    /// The bandwidth limit in bytes per second for this key's account, overriding
+    /// its tier's, `None` to use the tier's or else the server wide limit
+    fn bandwidth_limit(&self, _auth_key: &Self::AuthKey) -> Result<Option<u64>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Persist the usage accrued since the last call, i.e. for metering
+    fn record_usage(&self, _usage: &[UsageRecord]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// What the accounts of a tier may do, configured by name, i.e. `[tiers.free]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tier {
+    /// Tunnels an account may have open on an instance at once
+    pub max_tunnels: Option<usize>,
+    /// Bytes per second each tunnel may relay
+    pub bandwidth_limit: Option<u64>,
+    /// Seconds a tunnel may stay open before it's closed
+    pub max_tunnel_lifetime: Option<u64>,
+    /// Whether agents may pick their sub-domain rather than getting a random one
+    pub custom_sub_domains: bool,
+    /// Whether agents may have sub-domains that are reserved for everyone else
+    pub reserved_sub_domains: bool,
+}
+
+/// Accounts of tiers that aren't configured aren't limited
+impl Default for Tier {
+    fn default() -> Self {
+        Tier {
+            max_tunnels: None,
+            bandwidth_limit: None,
+            max_tunnel_lifetime: None,
+            custom_sub_domains: true,
+            reserved_sub_domains: false,
+        }
+    }
 }
 
 /// A result for authenticating a subdomain
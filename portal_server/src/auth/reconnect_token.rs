@@ -25,6 +25,9 @@ pub struct ReconnectTokenPayload {
     pub sub_domain: String,
     pub client_id: ClientId,
     pub expires: DateTime<Utc>,
+    /// when the tunnel itself is closed, however often it reconnects
+    #[serde(default)]
+    pub tunnel_expires: Option<DateTime<Utc>>,
 }
 impl ReconnectTokenPayload {
     pub fn to_token(&self, key: &SigKey) -> Result<ReconnectToken, Error> {
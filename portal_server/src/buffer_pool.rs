@@ -0,0 +1,88 @@
+//! Read buffers shared by every stream relay, so thousands of short connections
+//! reuse the same allocations rather than each making their own
+use crate::observability::metrics::get_metrics;
+use bytes::BytesMut;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// Idle read buffers, handed out to relays for as long as they read with them
+pub struct BufferPool {
+    idle: Mutex<Vec<BytesMut>>,
+    /// most idle buffers we keep, the rest are freed
+    size: usize,
+    /// bytes each read may take
+    buffer_size: usize,
+}
+
+impl BufferPool {
+    pub fn new(size: usize, buffer_size: usize) -> Self {
+        BufferPool {
+            idle: Mutex::new(Vec::with_capacity(size)),
+            size,
+            buffer_size,
+        }
+    }
+
+    /// An idle buffer, or a new one when there's none
+    pub fn get(&'static self) -> PooledBuffer {
+        let idle = self.idle.lock().unwrap().pop();
+        let buf = match idle {
+            Some(buf) => {
+                get_metrics().buffers_pooled.dec();
+                buf
+            }
+            None => {
+                get_metrics().buffers_allocated.inc();
+                BytesMut::with_capacity(self.buffer_size)
+            }
+        };
+        get_metrics().buffers_in_use.inc();
+        PooledBuffer { buf, pool: self }
+    }
+
+    fn put(&self, mut buf: BytesMut) {
+        get_metrics().buffers_in_use.dec();
+        buf.clear();
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.size {
+            idle.push(buf);
+            get_metrics().buffers_pooled.inc();
+        }
+    }
+}
+
+/// A read buffer that goes back to its pool when dropped.
+///
+/// What's read is split off and sent on as `Bytes`, and `reserve` takes the
+/// allocation back once they were written and dropped.
+pub struct PooledBuffer {
+    buf: BytesMut,
+    pool: &'static BufferPool,
+}
+
+impl PooledBuffer {
+    /// Make room for the next read
+    pub fn reserve(&mut self) {
+        self.buf.reserve(self.pool.buffer_size);
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
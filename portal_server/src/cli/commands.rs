@@ -0,0 +1,107 @@
+//! The commands besides serving, for operating a server from the command line
+use crate::admin::ClientInfo;
+use crate::auth::SigKey;
+use crate::config::{Config, Layers};
+use crate::get_cli;
+use portal_lib::NoticeLevel;
+use std::error::Error;
+
+/// Fail with every problem of the configuration, printing it if asked to
+pub fn check_config(print: bool) -> Result<(), Box<dyn Error>> {
+    let layers = Layers::load(get_cli())
+        .and_then(|layers| layers.config().map(|_| layers))
+        .map_err(|error| format!("invalid config:\n{}", error))?;
+    if print {
+        println!("{}", layers.describe());
+    } else {
+        println!("config ok");
+    }
+    Ok(())
+}
+
+pub fn generate_key() -> Result<(), Box<dyn Error>> {
+    println!("{}", SigKey::generate().to_hex());
+    Ok(())
+}
+
+/// The admin api of the running server and its token, unless given
+fn admin_api(url: Option<&str>, token: Option<&str>) -> Result<(String, String), Box<dyn Error>> {
+    let config = Config::load(get_cli())?;
+    let url = match (url, config.admin_addr) {
+        (Some(url), _) => url.trim_end_matches('/').to_string(),
+        // one bound on every interface answers on loopback
+        (None, Some(addr)) if addr.ip().is_unspecified() => {
+            format!("http://127.0.0.1:{}", addr.port())
+        }
+        (None, Some(addr)) => format!("http://{}", addr),
+        (None, None) => return Err("the admin api isn't enabled, set admin_port or --url".into()),
+    };
+    let token = token
+        .or(config.admin_token.as_deref())
+        .ok_or("the admin api needs a token, set admin_token or --token")?;
+    Ok((url, token.to_string()))
+}
+
+pub async fn list_clients(
+    url: Option<&str>,
+    token: Option<&str>,
+    json: bool,
+) -> Result<(), Box<dyn Error>> {
+    let (url, token) = admin_api(url, token)?;
+    let clients: Vec<ClientInfo> = reqwest::Client::new()
+        .get(format!("{}/api/clients", url))
+        .bearer_auth(&token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&clients)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:<38} {:<16} {:<10} {:>7}  CONNECTED",
+        "SUB DOMAIN", "SESSION", "NAME", "VERSION", "STREAMS"
+    );
+    for client in clients {
+        println!(
+            "{:<24} {:<38} {:<16} {:<10} {:>7}  {}",
+            client.sub_domain,
+            client.session_id,
+            client.name.as_deref().unwrap_or("-"),
+            client.version.as_deref().unwrap_or("-"),
+            client.streams,
+            client.connected_at.format("%Y-%m-%d %H:%M:%S"),
+        );
+    }
+    Ok(())
+}
+
+pub async fn broadcast(
+    message: &str,
+    level: NoticeLevel,
+    url: Option<&str>,
+    token: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let (url, token) = admin_api(url, token)?;
+    let reply: serde_json::Value = reqwest::Client::new()
+        .post(format!("{}/api/broadcast", url))
+        .bearer_auth(&token)
+        .json(&serde_json::json!({ "message": message, "level": level }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    println!("sent to {} agents", reply["agents"]);
+    Ok(())
+}
+
+/// Our auth backend keeps its state in memory, which leaves no schema to migrate
+pub fn migrate() -> Result<(), Box<dyn Error>> {
+    println!("nothing to migrate: the auth backend has no database");
+    Ok(())
+}
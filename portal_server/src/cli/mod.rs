@@ -1,11 +1,80 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use portal_lib::NoticeLevel;
+
+pub mod commands;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Use a toml file for configuration.
-    #[arg(short, long, value_name = "FILE")]
+    #[arg(short, long, value_name = "FILE", global = true)]
     pub config: Option<PathBuf>,
+
+    /// Override a setting of the file and environment, i.e. `--set remote_port=8081`.
+    #[arg(long = "set", value_name = "KEY=VALUE", global = true)]
+    pub settings: Vec<String>,
+
+    /// Print the effective configuration and where each setting came from, then exit.
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// How log lines are written to stdout.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the server, what happens without a command.
+    Serve,
+    /// Check the configuration and exit, failing if it's invalid.
+    CheckConfig {
+        /// Print the effective configuration too.
+        #[arg(long)]
+        print: bool,
+    },
+    /// Print a new master signature key.
+    GenerateKey,
+    /// List the agents connected to a running server, through its admin API.
+    ListClients {
+        /// The admin API, `http://127.0.0.1:<admin_port>` by default, or on `admin_bind` if set.
+        #[arg(long)]
+        url: Option<String>,
+        /// The admin token, `admin_token` by default.
+        #[arg(long)]
+        token: Option<String>,
+        /// Print the clients as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show every agent connected to a running server a message, i.e. of an upcoming
+    /// restart, through its admin API.
+    Broadcast {
+        /// What the agents show.
+        message: String,
+        /// How much it matters: info, warning or critical.
+        #[arg(long, default_value = "warning")]
+        level: NoticeLevel,
+        /// The admin API, `http://127.0.0.1:<admin_port>` by default, or on `admin_bind` if set.
+        #[arg(long)]
+        url: Option<String>,
+        /// The admin token, `admin_token` by default.
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Migrate the auth backend's database.
+    Migrate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line
+    Json,
 }
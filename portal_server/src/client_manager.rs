@@ -0,0 +1,122 @@
+//! Operate the agents connected to this instance: close their tunnels, move a
+//! sub-domain to another of them, or tell them all something. Agents learn of each
+//! through a control packet, so they don't take it for a lost connection.
+use crate::connected_clients::{ConnectedClient, Connections, SessionId};
+use crate::storage::Session;
+use crate::{get_active_streams, get_config, get_tasks, StreamMessage};
+use portal_lib::{ControlPacket, Endpoint, NoticeLevel, Protocol, SubDomainTransfer};
+use std::time::Duration;
+use thiserror::Error;
+
+/// How long a disconnected agent has to hang up before we cut it off
+const DISCONNECT_GRACE: Duration = Duration::from_secs(5);
+
+/// What agents losing their sub-domain to a transfer are told
+const TRANSFERRED_AWAY: &str = "the sub-domain was transferred to another agent";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("no agent with this session")]
+    NotFound,
+
+    #[error("invalid sub-domain")]
+    InvalidSubDomain,
+
+    #[error("only http tunnels can be transferred, not {0}")]
+    NotHttp(Protocol),
+}
+
+/// Close an agent's tunnel, telling it why so it doesn't reconnect. Returns whether it
+/// was connected.
+pub fn disconnect(session_id: &SessionId, reason: &str) -> bool {
+    let Some(client) = Connections::get(session_id) else {
+        return false;
+    };
+
+    tracing::info!(%session_id, sub_domain=%client.host, %reason, "admin disconnected client");
+    close(&client, reason);
+    true
+}
+
+/// Have the agent of `session_id` serve `sub_domain`, disconnecting those serving it
+/// now. Returns the agent as it is after the move.
+pub fn transfer(session_id: &SessionId, sub_domain: &str) -> Result<ConnectedClient, Error> {
+    let sub_domain = sub_domain.to_lowercase();
+    if sub_domain.is_empty()
+        || !sub_domain.chars().all(|c| c.is_alphanumeric() || c == '-')
+        || crate::share::is_link(&sub_domain)
+    {
+        return Err(Error::InvalidSubDomain);
+    }
+    let client = Connections::get(session_id).ok_or(Error::NotFound)?;
+    if !client.protocol.is_http() {
+        return Err(Error::NotHttp(client.protocol));
+    }
+    if client.host == sub_domain {
+        return Ok(client);
+    }
+
+    for owner in Connections::for_host(&sub_domain) {
+        tracing::info!(session_id=%owner.session_id, %sub_domain, "client lost its sub-domain to a transfer");
+        close(&owner, TRANSFERRED_AWAY);
+    }
+    let previous = client.host.clone();
+    let client = Connections::rehost(&client, &sub_domain);
+    tracing::info!(%session_id, from=%previous, to=%sub_domain, "admin transferred sub-domain");
+
+    let config = get_config();
+    crate::storage::open_session(Session {
+        session_id: client.session_id.to_string(),
+        account: client.id.to_string(),
+        sub_domain: sub_domain.clone(),
+        instance_id: config.instance_id.clone(),
+        connected_at: client.connected_at,
+    });
+    let hostname = format!("{}.{}", sub_domain, config.portal_host);
+    let port = match client.protocol {
+        Protocol::Https => config.public_https_port.unwrap_or(443),
+        _ => config.public_http_port,
+    };
+    client.queue(ControlPacket::Transferred(SubDomainTransfer {
+        sub_domain,
+        endpoint: Some(Endpoint {
+            protocol: client.protocol,
+            host: hostname.clone(),
+            port,
+        }),
+        hostname,
+    }));
+    Ok(client)
+}
+
+/// Show every agent connected to this instance a message, i.e. of an upcoming restart,
+/// returning how many got it
+pub fn broadcast(level: NoticeLevel, message: &str) -> usize {
+    let clients = Connections::all();
+    tracing::info!(agents = clients.len(), %level, %message, "admin broadcast a notice");
+    for client in &clients {
+        client.queue(ControlPacket::Notice {
+            level,
+            message: message.to_string(),
+        });
+    }
+    clients.len()
+}
+
+/// Turn the agent's visitors away and tell it why we hang up on it
+fn close(client: &ConnectedClient, reason: &str) {
+    client.queue(ControlPacket::Disconnected(reason.to_string()));
+    for stream in get_active_streams().iter() {
+        if stream.client.session_id == client.session_id {
+            stream.queue(StreamMessage::NoClientTunnel);
+        }
+    }
+    // the packet goes out ahead of the hang up, then the agent leaves on its own
+    Connections::forget(client);
+
+    let cancel = client.cancel.clone();
+    get_tasks().spawn("client_disconnect", client.cancel.clone(), async move {
+        tokio::time::sleep(DISCONNECT_GRACE).await;
+        cancel.cancel();
+    });
+}
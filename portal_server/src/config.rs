@@ -1,14 +1,30 @@
-use crate::auth::SigKey;
+use crate::access_log::AccessLogFormat;
+use crate::auth::{SigKey, Tier};
+use crate::cli::Cli;
+use crate::connected_clients::LoadBalancing;
+use crate::dns::DnsProviderKind;
+use crate::http::{Limits, MAX_HEAD_SIZE};
+use crate::service::PortRange;
+use crate::subdomain::Scheme;
+use crate::webhooks::Webhook;
+use portal_lib::AlertThresholds;
 
+use std::collections::HashMap;
 use std::error::Error;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tracing::info;
+use tracing::level_filters::LevelFilter;
 use uuid::Uuid;
 
-#[derive(Deserialize, Debug)]
+/// Where agents too old to be let in get a newer one, unless `agent_download_url` says otherwise
+const DEFAULT_AGENT_DOWNLOAD_URL: &str = "https://github.com/illusion-tech/portal/releases";
+
+#[derive(Deserialize, Serialize, Debug, Default)]
 struct InternalConfig {
     /// What hosts do we allow tunnels on:
     /// i.e:    baz.com => *.baz.com
@@ -19,15 +35,75 @@ struct InternalConfig {
     /// i.e:    dashboard.tunnelto.dev
     blocked_sub_domains: Option<Vec<String>>,
 
+    /// File with words, one per line, no sub-domain may contain, i.e. against profanity
+    /// and squatting
+    sub_domain_filter: Option<String>,
+
+    /// How random sub-domains look: `alphanumeric` (the default), `hex` or `words`
+    sub_domain_scheme: Option<Scheme>,
+
+    /// Characters of alphanumeric and hex sub-domains
+    sub_domain_length: Option<usize>,
+
+    /// Put before every random sub-domain, i.e. `demo-`
+    sub_domain_prefix: Option<String>,
+
+    /// Put after every random sub-domain
+    sub_domain_suffix: Option<String>,
+
     /// port for remote streams (end users)
     remote_port: Option<u16>,
 
     /// port for the control server
     control_port: Option<u16>,
 
+    /// UDP port agents may hold their tunnels over with QUIC, unset disables QUIC
+    quic_port: Option<u16>,
+
+    /// PEM certificate chain served to QUIC agents, self-signed when unset. Read again
+    /// on reload, for renewed certificates
+    quic_cert: Option<String>,
+
+    /// PEM private key of `quic_cert`
+    quic_key: Option<String>,
+
+    /// Path on the remote listener's own hosts whose websocket upgrades go to the
+    /// control server, for agents that can only reach the public port
+    control_path: Option<String>,
+
     /// internal port for instance-to-instance gossip communications
     internal_network_port: Option<u16>,
 
+    /// address the remote listener binds, `::` by default
+    remote_bind: Option<IpAddr>,
+
+    /// address the control server binds, `0.0.0.0` by default
+    control_bind: Option<IpAddr>,
+
+    /// address the QUIC control server binds, the control server's by default
+    quic_bind: Option<IpAddr>,
+
+    /// address the internal network service binds, `::` by default
+    internal_network_bind: Option<IpAddr>,
+
+    /// More addresses to accept remote streams on, i.e. `["[::]:443"]`
+    remote_listeners: Option<Vec<SocketAddr>>,
+
+    /// Port visitors reach http tunnels on, `remote_port` unless a proxy in front of
+    /// us listens on another
+    public_http_port: Option<u16>,
+
+    /// Port a proxy in front of us terminates TLS for https tunnels on, unset refuses
+    /// https tunnels
+    public_https_port: Option<u16>,
+
+    /// Ports tcp tunnels each get one of, i.e. `20000-20999`; unset refuses tcp tunnels
+    tcp_ports: Option<PortRange>,
+
+    /// Port on the remote listener's interface passing TLS through to tls tunnels, by
+    /// the name visitors ask for; unset refuses tls tunnels
+    tls_port: Option<u16>,
+
     /// our signature key path
     master_sig_key: Option<String>,
 
@@ -40,11 +116,324 @@ struct InternalConfig {
     /// The identifier for this instance of the server
     instance_id: Option<String>,
 
-    /// Blocked IP addresses
-    blocked_ips: Option<Vec<IpAddr>>,
+    /// Blocked IP addresses, or ranges of them like `10.0.0.0/8`
+    blocked_ips: Option<Vec<String>>,
 
-    /// The host on which we create tunnels on
+    /// The host on which we create tunnels on, `portal.illusiontech.cn` by default
     portal_host: Option<String>,
+
+    /// Trust `Forwarded` / `X-Forwarded-*` headers sent by any peer
+    trust_forwarded_headers: Option<bool>,
+
+    /// Peers whose `Forwarded` / `X-Forwarded-*` headers we trust, addresses or ranges
+    trusted_proxies: Option<Vec<String>>,
+
+    /// Redirect plain http visitors of every http tunnel to https, as told by a trusted
+    /// proxy terminating TLS in front of us
+    https_redirect: Option<bool>,
+
+    /// Add `Strict-Transport-Security` to every http tunnel's responses over https
+    hsts: Option<bool>,
+
+    /// Seconds browsers stay on https once told to by HSTS
+    hsts_max_age: Option<u64>,
+
+    /// Answer `Expect: 100-continue` at the edge rather than through the tunnel, on by
+    /// default
+    answer_continue: Option<bool>,
+
+    /// Directory with custom error page templates (`error.html`, `404.html`, ...), read
+    /// again on reload
+    error_pages_dir: Option<String>,
+
+    /// Seconds a stream may go without traffic before it is closed, 0 disables
+    stream_idle_timeout: Option<u64>,
+
+    /// Seconds a stream may stay open at all, 0 disables
+    max_stream_lifetime: Option<u64>,
+
+    /// Seconds the streams of an agent whose connection dropped get to write out what it
+    /// sent them, 0 closes them at once
+    disconnect_grace: Option<u64>,
+
+    /// Seconds new visitors of a tunnel whose agent dropped are asked to retry, rather
+    /// than told it's not found, 0 disables
+    reconnect_window: Option<u64>,
+
+    /// Seconds between the traffic stats sent to agents that ask for them, 0 disables
+    stats_interval: Option<u64>,
+
+    /// Bytes per second each tunnel may relay, 0 disables
+    bandwidth_limit: Option<u64>,
+
+    /// Seconds a tunnel may stay open before it's closed, however long its agent asked
+    /// for; 0 disables
+    max_tunnel_lifetime: Option<u64>,
+
+    /// Limits of account tiers by name, i.e. `free` and `pro`
+    tiers: Option<HashMap<String, Tier>>,
+
+    /// Tier of accounts the auth backend doesn't assign one, `free` by default
+    default_tier: Option<String>,
+
+    /// Tier of anonymous agents, the default tier by default
+    anonymous_tier: Option<String>,
+
+    /// Oldest agent version let in, i.e. `0.1.20`; older agents are turned away
+    minimum_agent_version: Option<semver::Version>,
+
+    /// Where agents turned away for their version are told to get a newer one
+    agent_download_url: Option<String>,
+
+    /// Newest agent version, i.e. `0.1.21`; older agents are told to upgrade
+    latest_agent_version: Option<semver::Version>,
+
+    /// A GitHub style `releases/latest` url to learn the newest agent version from
+    agent_release_feed: Option<String>,
+
+    /// Seconds between telling older agents of a newer version, and fetching the
+    /// release feed, 6 hours by default
+    agent_update_interval: Option<u64>,
+
+    /// Largest request head in bytes we accept from visitors
+    max_header_size: Option<usize>,
+
+    /// Largest request body in bytes we accept from visitors, 0 disables
+    max_body_size: Option<u64>,
+
+    /// Seconds a visitor has to send a complete request head, 0 disables
+    header_read_timeout: Option<u64>,
+
+    /// Seconds from a visitor's request head to the first byte of the response,
+    /// covering the host lookup, a hop to another instance and the agent, 0 disables
+    request_timeout: Option<u64>,
+
+    /// How visitors are spread over several agents serving the same host
+    load_balancing: Option<LoadBalancing>,
+
+    /// Name of the cookie pinning a visitor to one agent, unset disables stickiness
+    sticky_cookie: Option<String>,
+
+    /// Dedicated port for the prometheus `/metrics` endpoint,
+    /// which is always served on the internal network port too
+    metrics_port: Option<u16>,
+
+    /// address the metrics endpoint binds, `::` by default
+    metrics_bind: Option<IpAddr>,
+
+    /// OTLP/HTTP traces endpoint, i.e. `http://collector:4318/v1/traces`
+    otlp_endpoint: Option<String>,
+
+    /// Headers sent with every OTLP export, i.e. for authentication
+    otlp_headers: Option<HashMap<String, String>>,
+
+    /// How many recent requests to keep per tunnel, 0 disables the request log
+    request_log_size: Option<usize>,
+
+    /// Bytes of each logged request and response to capture for inspection and replay,
+    /// 0 (the default) captures nothing
+    capture_size: Option<usize>,
+
+    /// Requests kept per tunnel while its agent is offline, for agents that ask for it,
+    /// 0 disables queueing
+    offline_queue_size: Option<usize>,
+
+    /// Largest request body queued for an offline agent, in bytes
+    offline_queue_max_body: Option<u64>,
+
+    /// Seconds a queued request waits for its agent before it's dropped
+    offline_queue_ttl: Option<u64>,
+
+    /// Directory the queued requests are kept in across restarts, in memory only if unset
+    offline_queue_dir: Option<String>,
+
+    /// Bytes of responses kept for tunnels that let us cache them, 0 disables caching
+    cache_size: Option<usize>,
+
+    /// Largest response cached, in bytes
+    cache_max_entry: Option<usize>,
+
+    /// Most seconds a response is served from the cache, whatever its `max-age`
+    cache_max_ttl: Option<u64>,
+
+    /// Answer requests for a range of a cached response with that part of it
+    cache_ranges: Option<bool>,
+
+    /// Largest request body copied to the tunnels mirroring a tunnel, in bytes
+    mirror_max_body: Option<usize>,
+
+    /// Where visitors' requests and connections are logged: a file, or `-` for stdout.
+    /// Unset disables the access log
+    access_log: Option<String>,
+
+    /// How access log lines look: `common`, `combined` (the default) or `vhost_combined`
+    access_log_format: Option<AccessLogFormat>,
+
+    /// Bytes the access log file grows to before it's rotated, 0 never rotates
+    access_log_max_size: Option<u64>,
+
+    /// Only log the tunnels whose agent asks for it, rather than all of them
+    access_log_opt_in: Option<bool>,
+
+    /// Where reservations, usage, sessions and captured requests are kept: `memory` (the
+    /// default), `sqlite://<path>` or a `redis://` url to share them within a cluster
+    storage_url: Option<String>,
+
+    /// Port of the admin API, which stays disabled without an `admin_token`
+    admin_port: Option<u16>,
+
+    /// address the admin API binds, `::` by default
+    admin_bind: Option<IpAddr>,
+
+    /// Bearer token required by every admin API request
+    admin_token: Option<String>,
+
+    /// Port of the admin API as a gRPC service, in servers built with the `grpc` feature
+    grpc_port: Option<u16>,
+
+    /// address the gRPC admin API binds, `::` by default
+    grpc_bind: Option<IpAddr>,
+
+    /// Where to send notifications about tunnels, failed logins and exceeded quotas
+    webhooks: Option<Vec<Webhook>>,
+
+    /// Seconds between handing usage to the auth backend
+    usage_flush_interval: Option<u64>,
+
+    /// Hours of usage kept in memory for the admin API
+    usage_retention: Option<u64>,
+
+    /// Alert when a tunnel's 95th percentile response time exceeds these milliseconds
+    alert_p95_latency: Option<u64>,
+
+    /// Alert when a tunnel's share of 5xx responses exceeds this, between 0 and 1
+    alert_error_rate: Option<f64>,
+
+    /// Seconds of requests alerts look at
+    alert_window: Option<u64>,
+
+    /// Requests within the window before alerts may fire
+    alert_min_requests: Option<usize>,
+
+    /// Find the instance serving a host in redis rather than asking every instance
+    redis_url: Option<String>,
+
+    /// The address other instances reach this one on, published to redis
+    instance_ip: Option<IpAddr>,
+
+    /// Seconds to remember which instance serves a host, 0 to always look it up
+    host_cache_ttl: Option<u64>,
+
+    /// Gossip cluster membership and hosts with other instances on this udp port
+    gossip_port: Option<u16>,
+
+    /// `host:port` of instances to join the gossip through, besides those found through DNS
+    gossip_seeds: Option<Vec<String>>,
+
+    /// Websocket links kept to each instance we proxy to, 0 to dial it for every connection
+    instance_links: Option<usize>,
+
+    /// Seconds to wait for streams in flight when shutting down
+    drain_timeout: Option<u64>,
+
+    /// Packets queued toward an agent before we stop reading from its visitors
+    tunnel_queue_size: Option<usize>,
+
+    /// Packets queued toward a visitor before we stop reading from its agent
+    stream_queue_size: Option<usize>,
+
+    /// Seconds a visitor may take to accept what we write before we drop its stream
+    stream_write_timeout: Option<u64>,
+
+    /// Visitor connections served at once, beyond which new ones get a 503, 0 disables
+    max_connections: Option<usize>,
+
+    /// Visitor connections accepted per second, beyond which new ones are reset, 0 disables
+    accept_rate: Option<u32>,
+
+    /// Idle read buffers kept for the next connections
+    buffer_pool_size: Option<usize>,
+
+    /// Bytes read from a connection at once
+    read_buffer_size: Option<usize>,
+
+    /// Place subdomains on instances by hashing over the gossiped membership
+    consistent_hashing: Option<bool>,
+
+    /// Expect a PROXY protocol header on every remote stream, i.e. behind an L4 load balancer
+    /// in `trusted_proxies`. Without `instance_links` the other instances send one too, so
+    /// they have to be in it as well.
+    proxy_protocol: Option<bool>,
+
+    /// Set `TCP_NODELAY` on every connection, on by default
+    tcp_nodelay: Option<bool>,
+
+    /// Seconds a connection idles before TCP keepalive probes it, 0 disables
+    tcp_keepalive: Option<u64>,
+
+    /// Seconds between TCP keepalive probes, the system's default when unset
+    tcp_keepalive_interval: Option<u64>,
+
+    /// Bind listeners with `SO_REUSEPORT`, so several processes may share their ports
+    reuse_port: Option<bool>,
+
+    /// Accept loops on each remote address, sharing it with `SO_REUSEPORT`, 1 by default
+    accept_shards: Option<usize>,
+
+    /// Secret shared by all instances, required on the internal network service and gossip
+    internal_secret: Option<String>,
+
+    /// Milliseconds an instance has to answer whether it serves a host
+    host_query_timeout: Option<u64>,
+
+    /// How often to retry a host query that failed rather than being answered
+    host_query_retries: Option<u32>,
+
+    /// Milliseconds before asking a slow instance again, 0 disables hedging
+    host_query_hedge: Option<u64>,
+
+    /// Find the other instances in this Consul agent's catalog rather than through DNS
+    consul_url: Option<String>,
+
+    /// Name we register as in Consul
+    consul_service: Option<String>,
+
+    /// ACL token for the Consul API
+    consul_token: Option<String>,
+
+    /// Create the records of allowed hosts and custom domains with this provider:
+    /// `cloudflare` or `route53`
+    dns_provider: Option<DnsProviderKind>,
+
+    /// The zone the records go in, its Cloudflare zone id or Route 53 hosted zone id
+    dns_zone_id: Option<String>,
+
+    /// Cloudflare API token allowed to edit the zone's DNS
+    dns_api_token: Option<String>,
+
+    /// Route 53 access keys allowed to change the zone's record sets
+    dns_access_key_id: Option<String>,
+    dns_secret_access_key: Option<String>,
+
+    /// What the records point at, an address or a host to alias, `instance_ip` if unset
+    dns_target: Option<String>,
+
+    /// Seconds resolvers may cache the records, 300 if unset
+    dns_ttl: Option<u32>,
+
+    /// Where this instance runs, i.e. `fra`, so others prefer it for visitors of that region
+    region: Option<String>,
+
+    /// Milliseconds to wait for an instance in our region to answer a host query
+    /// once one elsewhere did
+    region_wait: Option<u64>,
+
+    /// The control server (`host:port`) of each other region, agents preferring one are
+    /// redirected to, i.e. `fra = "fra.portal.example.com:5000"`
+    regions: Option<HashMap<String, String>>,
+
+    /// Most verbose level logged, i.e. `info`, `debug` by default
+    log_level: Option<String>,
 }
 
 /// Global service configuration
@@ -59,6 +448,24 @@ pub struct Config {
     /// i.e:    dashboard.tunnelto.dev
     pub blocked_sub_domains: Vec<String>,
 
+    /// File with words no sub-domain may contain
+    pub sub_domain_filter: Option<String>,
+
+    /// The words of the sub-domain filter, lowercase
+    pub filtered_words: Vec<String>,
+
+    /// How random sub-domains look
+    pub sub_domain_scheme: Scheme,
+
+    /// Characters of alphanumeric and hex sub-domains
+    pub sub_domain_length: usize,
+
+    /// Put before every random sub-domain
+    pub sub_domain_prefix: String,
+
+    /// Put after every random sub-domain
+    pub sub_domain_suffix: String,
+
     /// port for remote streams (end users)
     pub remote_port: u16,
 
@@ -68,6 +475,40 @@ pub struct Config {
     /// internal port for instance-to-instance gossip coms
     pub internal_network_port: u16,
 
+    /// Every address we accept remote streams on, the first one on `remote_port`
+    pub remote_addrs: Vec<SocketAddr>,
+
+    /// Address the control server binds
+    pub control_addr: SocketAddr,
+
+    /// Address the QUIC control server binds, serving agents alongside the websocket
+    /// control server
+    pub quic_addr: Option<SocketAddr>,
+
+    /// PEM certificate chain served to QUIC agents
+    pub quic_cert: Option<String>,
+
+    /// PEM private key of `quic_cert`
+    pub quic_key: Option<String>,
+
+    /// Path on `allowed_hosts` the remote listener hands to the control server
+    pub control_path: Option<String>,
+
+    /// Port visitors reach http tunnels on
+    pub public_http_port: u16,
+
+    /// Port visitors reach https tunnels on, if we take any
+    pub public_https_port: Option<u16>,
+
+    /// Ports tcp tunnels each get one of, if we take any
+    pub tcp_ports: Option<PortRange>,
+
+    /// Address passing TLS through to tls tunnels, if we take any
+    pub tls_addr: Option<SocketAddr>,
+
+    /// Address the internal network service binds
+    pub internal_network_addr: SocketAddr,
+
     /// our signature key
     pub master_sig_key: SigKey,
 
@@ -80,117 +521,1511 @@ pub struct Config {
     /// The identifier for this instance of the server
     pub instance_id: String,
 
-    /// Blocked IP addresses
-    pub blocked_ips: Vec<IpAddr>,
+    /// Blocked IP addresses and ranges
+    pub blocked_ips: Vec<Cidr>,
 
     /// The host on which we create tunnels on
     pub portal_host: String,
+
+    /// Trust `Forwarded` / `X-Forwarded-*` headers sent by any peer
+    pub trust_forwarded_headers: bool,
+
+    /// Peers whose `Forwarded` / `X-Forwarded-*` headers we trust
+    pub trusted_proxies: Vec<Cidr>,
+
+    /// Redirect plain http visitors of every http tunnel to https
+    pub https_redirect: bool,
+
+    /// Add HSTS to every http tunnel's responses over https
+    pub hsts: bool,
+
+    /// How long browsers stay on https once told to by HSTS
+    pub hsts_max_age: Duration,
+
+    /// Answer `Expect: 100-continue` at the edge, so uploads don't wait on the tunnel
+    pub answer_continue: bool,
+
+    /// Directory with custom error page templates (`error.html`, `404.html`, ...)
+    pub error_pages_dir: Option<String>,
+
+    /// How long a stream may go without traffic before it is closed
+    pub stream_idle_timeout: Option<Duration>,
+
+    /// How long a stream may stay open at all
+    pub max_stream_lifetime: Option<Duration>,
+
+    /// How long the streams of an agent whose connection dropped get to write out what
+    /// it sent them before they're closed
+    pub disconnect_grace: Option<Duration>,
+
+    /// How long new visitors of a tunnel whose agent dropped get a 503 with
+    /// `Retry-After`, in case it reconnects
+    pub reconnect_window: Option<Duration>,
+
+    /// How often agents that ask for them get their tunnel's traffic stats
+    pub stats_interval: Option<Duration>,
+
+    /// Bytes per second each tunnel may relay, unless its account tier says otherwise
+    pub bandwidth_limit: Option<u64>,
+
+    /// How long a tunnel may stay open, unless its account tier says otherwise
+    pub max_tunnel_lifetime: Option<Duration>,
+
+    /// Limits of account tiers by name
+    pub tiers: HashMap<String, Tier>,
+
+    /// Tier of accounts the auth backend doesn't assign one
+    pub default_tier: String,
+
+    /// Tier of anonymous agents
+    pub anonymous_tier: String,
+
+    /// Oldest agent version let in
+    pub minimum_agent_version: Option<semver::Version>,
+
+    /// Where agents too old to be let in get a newer one
+    pub agent_download_url: String,
+
+    /// Newest agent version, unless the release feed knows a newer one
+    pub latest_agent_version: Option<semver::Version>,
+
+    /// Where we learn the newest agent version
+    pub agent_release_feed: Option<String>,
+
+    /// How often older agents are told of a newer version, never if `None`
+    pub agent_update_interval: Option<Duration>,
+
+    /// Largest request head in bytes we accept from visitors
+    pub max_header_size: usize,
+
+    /// Largest request body in bytes we accept from visitors
+    pub max_body_size: Option<u64>,
+
+    /// How long a visitor has to send a complete request head
+    pub header_read_timeout: Option<Duration>,
+
+    /// How long a visitor waits on the response to their request before a 504
+    pub request_timeout: Option<Duration>,
+
+    /// How visitors are spread over several agents serving the same host
+    pub load_balancing: LoadBalancing,
+
+    /// Name of the cookie pinning a visitor to one agent
+    pub sticky_cookie: Option<String>,
+
+    /// Dedicated address for the prometheus `/metrics` endpoint
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// OTLP/HTTP traces endpoint, unset disables trace export
+    pub otlp_endpoint: Option<String>,
+
+    /// Headers sent with every OTLP export
+    pub otlp_headers: HashMap<String, String>,
+
+    /// How many recent requests to keep per tunnel
+    pub request_log_size: usize,
+
+    /// Bytes of each logged request and response to capture
+    pub capture_size: usize,
+
+    /// Requests kept per tunnel while its agent is offline
+    pub offline_queue_size: usize,
+
+    /// Largest request body queued for an offline agent
+    pub offline_queue_max_body: u64,
+
+    /// How long a queued request waits for its agent
+    pub offline_queue_ttl: Duration,
+
+    /// Directory the queued requests are kept in across restarts
+    pub offline_queue_dir: Option<String>,
+
+    /// Bytes of responses kept in the cache
+    pub cache_size: usize,
+
+    /// Largest response cached
+    pub cache_max_entry: usize,
+
+    /// Longest a response is served from the cache
+    pub cache_max_ttl: Duration,
+
+    /// Serve the ranges of cached responses asked for, rather than leaving them to the tunnel
+    pub cache_ranges: bool,
+
+    /// Largest request body copied to mirrors
+    pub mirror_max_body: usize,
+
+    /// Where visitors' requests and connections are logged
+    pub access_log: Option<String>,
+
+    /// How access log lines look
+    pub access_log_format: AccessLogFormat,
+
+    /// Bytes the access log file grows to before it's rotated
+    pub access_log_max_size: u64,
+
+    /// Whether only the tunnels that ask for it are logged
+    pub access_log_opt_in: bool,
+
+    /// Where reservations, usage, sessions and captured requests are kept
+    pub storage_url: Option<String>,
+
+    /// Address of the admin API
+    pub admin_addr: Option<SocketAddr>,
+
+    /// Bearer token required by every admin API request
+    pub admin_token: Option<String>,
+
+    /// Address of the admin API as a gRPC service
+    pub grpc_addr: Option<SocketAddr>,
+
+    /// Where to send notifications about tunnels, failed logins and exceeded quotas
+    pub webhooks: Vec<Webhook>,
+
+    /// How often usage is handed to the auth backend
+    pub usage_flush_interval: Duration,
+
+    /// How long usage is kept in memory for the admin API
+    pub usage_retention: Duration,
+
+    /// When to alert about tunnels whose agents don't set their own thresholds
+    pub alerts: AlertThresholds,
+
+    /// How far back alerts look
+    pub alert_window: Duration,
+
+    /// Requests within the window before alerts may fire
+    pub alert_min_requests: usize,
+
+    /// Find the instance serving a host in redis rather than asking every instance
+    pub redis_url: Option<String>,
+
+    /// The address other instances reach this one on
+    pub instance_ip: Option<IpAddr>,
+
+    /// How long to remember which instance serves a host
+    pub host_cache_ttl: Option<Duration>,
+
+    /// Gossip cluster membership and hosts with other instances on this udp port
+    pub gossip_port: Option<u16>,
+
+    /// `host:port` of instances to join the gossip through
+    pub gossip_seeds: Vec<String>,
+
+    /// Websocket links kept to each instance we proxy to, 0 to dial it for every connection
+    pub instance_links: usize,
+
+    /// How long to wait for streams in flight when shutting down
+    pub drain_timeout: Duration,
+
+    /// Packets queued toward an agent before we stop reading from its visitors
+    pub tunnel_queue_size: usize,
+
+    /// Packets queued toward a visitor before we stop reading from its agent
+    pub stream_queue_size: usize,
+
+    /// How long a visitor may take to accept what we write, so one that stopped
+    /// reading doesn't hold up the rest of its tunnel
+    pub stream_write_timeout: Duration,
+
+    /// Visitor connections served at once, so a spike toward one tunnel
+    /// can't take the file descriptors agents need
+    pub max_connections: Option<usize>,
+
+    /// Visitor connections accepted per second, bursting up to a second's worth
+    pub accept_rate: Option<u32>,
+
+    /// Idle read buffers kept for the next connections
+    pub buffer_pool_size: usize,
+
+    /// Bytes read from a connection at once
+    pub read_buffer_size: usize,
+
+    /// Place subdomains on instances by hashing over the gossiped membership,
+    /// redirecting agents to where their subdomain belongs
+    pub consistent_hashing: bool,
+
+    /// Expect a PROXY protocol header on every remote stream, and send one along
+    /// with the streams we proxy to other instances
+    pub proxy_protocol: bool,
+
+    /// Set `TCP_NODELAY` on the remote listener's, control server's and instance links'
+    /// connections
+    pub tcp_nodelay: bool,
+
+    /// How long a connection idles before TCP keepalive probes it
+    pub tcp_keepalive: Option<Duration>,
+
+    /// How long between TCP keepalive probes, the system's default when `None`
+    pub tcp_keepalive_interval: Option<Duration>,
+
+    /// Bind listeners with `SO_REUSEPORT`
+    pub reuse_port: bool,
+
+    /// Accept loops on each remote address, each with a listener of its own sharing the
+    /// port with `SO_REUSEPORT`, so the kernel spreads connections across them
+    pub accept_shards: usize,
+
+    /// Secret shared by all instances, required on the internal network service and gossip
+    pub internal_secret: Option<String>,
+
+    /// How long an instance has to answer whether it serves a host
+    pub host_query_timeout: Duration,
+
+    /// How often to retry a host query that failed rather than being answered
+    pub host_query_retries: u32,
+
+    /// How long before asking a slow instance again
+    pub host_query_hedge: Option<Duration>,
+
+    /// Find the other instances in this Consul agent's catalog rather than through DNS
+    pub consul_url: Option<String>,
+
+    /// Name we register as in Consul
+    pub consul_service: String,
+
+    /// ACL token for the Consul API
+    pub consul_token: Option<String>,
+
+    /// Create the records of allowed hosts and custom domains with this provider
+    pub dns_provider: Option<DnsProviderKind>,
+
+    /// The zone the records go in
+    pub dns_zone_id: Option<String>,
+
+    /// Cloudflare API token
+    pub dns_api_token: Option<String>,
+
+    /// Route 53 access keys
+    pub dns_access_key_id: Option<String>,
+    pub dns_secret_access_key: Option<String>,
+
+    /// What the records point at, `instance_ip` if unset
+    pub dns_target: Option<String>,
+
+    /// Seconds resolvers may cache the records
+    pub dns_ttl: u32,
+
+    /// Where this instance runs
+    pub region: Option<String>,
+
+    /// How long to wait for an instance in our region to answer a host query
+    /// once one elsewhere did
+    pub region_wait: Duration,
+
+    /// The control server of each other region
+    pub regions: HashMap<String, String>,
+
+    /// Most verbose level logged
+    pub log_level: LevelFilter,
 }
 
 impl From<InternalConfig> for Config {
     fn from(config: InternalConfig) -> Self {
         let allowed_hosts = config.allowed_hosts.unwrap_or_default();
         let blocked_sub_domains = config.blocked_sub_domains.unwrap_or_default();
+        let sub_domain_filter = config.sub_domain_filter;
+        // an unreadable file is reported by `validate`
+        let filtered_words = sub_domain_filter
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|words| {
+                words
+                    .lines()
+                    .map(|word| word.trim().to_lowercase())
+                    .filter(|word| !word.is_empty() && !word.starts_with('#'))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let sub_domain_scheme = config.sub_domain_scheme.unwrap_or_default();
+        let sub_domain_length = config.sub_domain_length.unwrap_or(8);
+        let sub_domain_prefix = config.sub_domain_prefix.unwrap_or_default();
+        let sub_domain_suffix = config.sub_domain_suffix.unwrap_or_default();
         let remote_port = config.remote_port.unwrap_or(8080);
         let control_port = config.control_port.unwrap_or(5000);
         let internal_network_port = config.internal_network_port.unwrap_or(6000);
+        let remote_addrs = std::iter::once(SocketAddr::new(
+            config.remote_bind.unwrap_or(Ipv6Addr::UNSPECIFIED.into()),
+            remote_port,
+        ))
+        .chain(config.remote_listeners.unwrap_or_default())
+        .collect::<Vec<_>>();
+        let public_http_port = config.public_http_port.unwrap_or(remote_port);
+        let public_https_port = config.public_https_port;
+        let tcp_ports = config.tcp_ports;
+        let tls_addr = config
+            .tls_port
+            .map(|port| SocketAddr::new(remote_addrs[0].ip(), port));
+        let control_addr = SocketAddr::new(
+            config.control_bind.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+            control_port,
+        );
+        let quic_addr = config
+            .quic_port
+            .map(|port| SocketAddr::new(config.quic_bind.unwrap_or(control_addr.ip()), port));
+        let quic_cert = config.quic_cert;
+        let quic_key = config.quic_key;
+        let control_path = config.control_path.filter(|path| !path.is_empty());
+        let internal_network_addr = SocketAddr::new(
+            config
+                .internal_network_bind
+                .unwrap_or(Ipv6Addr::UNSPECIFIED.into()),
+            internal_network_port,
+        );
+        // `take_invalid` reported keys and levels we can't make sense of
         let master_sig_key = config
             .master_sig_key
-            .map(|key| {
-                SigKey::from_hex(&key).expect("invalid master key: not hex or length incorrect")
-            })
-            .unwrap_or_else(SigKey::generate);
+            .and_then(|key| SigKey::from_hex(&key).ok())
+            .unwrap_or_else(|| {
+                tracing::warn!("WARNING! generating ephemeral signature key!");
+                SigKey::generate()
+            });
         let gossip_dns_host = config.gossip_dns_host;
         let honeycomb_api_key = config.honeycomb_api_key;
         let instance_id = config
             .instance_id
             .unwrap_or_else(|| Uuid::new_v4().to_string());
-        let blocked_ips = config.blocked_ips.unwrap_or_default();
+        let blocked_ips = cidrs(config.blocked_ips);
         let portal_host = config
             .portal_host
-            .unwrap_or_else(|| "tunnelto.dev".to_string());
+            // the environment's default, which files now share rather than `tunnelto.dev`
+            .unwrap_or_else(|| "portal.illusiontech.cn".to_string());
+        let trust_forwarded_headers = config.trust_forwarded_headers.unwrap_or(false);
+        let trusted_proxies = cidrs(config.trusted_proxies);
+        let https_redirect = config.https_redirect.unwrap_or(false);
+        let hsts = config.hsts.unwrap_or(false);
+        let hsts_max_age = Duration::from_secs(config.hsts_max_age.unwrap_or(31536000));
+        let answer_continue = config.answer_continue.unwrap_or(true);
+        let error_pages_dir = config.error_pages_dir;
+        let stream_idle_timeout = seconds(config.stream_idle_timeout.unwrap_or(600));
+        let max_stream_lifetime = seconds(config.max_stream_lifetime.unwrap_or(0));
+        let disconnect_grace = seconds(config.disconnect_grace.unwrap_or(10));
+        let reconnect_window = seconds(config.reconnect_window.unwrap_or(30));
+        let stats_interval = seconds(config.stats_interval.unwrap_or(2));
+        let bandwidth_limit = config.bandwidth_limit.filter(|limit| *limit > 0);
+        let max_tunnel_lifetime = seconds(config.max_tunnel_lifetime.unwrap_or(0));
+        let tiers = config.tiers.unwrap_or_default();
+        let default_tier = config.default_tier.unwrap_or_else(|| "free".to_string());
+        let anonymous_tier = config
+            .anonymous_tier
+            .unwrap_or_else(|| default_tier.clone());
+        let minimum_agent_version = config.minimum_agent_version;
+        let agent_download_url = config
+            .agent_download_url
+            .unwrap_or_else(|| DEFAULT_AGENT_DOWNLOAD_URL.to_string());
+        let latest_agent_version = config.latest_agent_version;
+        let agent_release_feed = config.agent_release_feed;
+        let agent_update_interval = seconds(config.agent_update_interval.unwrap_or(6 * 60 * 60));
+        let max_header_size = config.max_header_size.unwrap_or(MAX_HEAD_SIZE);
+        let max_body_size = config.max_body_size.filter(|limit| *limit > 0);
+        let header_read_timeout = seconds(config.header_read_timeout.unwrap_or(30));
+        let request_timeout = seconds(config.request_timeout.unwrap_or(60));
+        let load_balancing = config.load_balancing.unwrap_or_default();
+        let sticky_cookie = config.sticky_cookie;
+        let metrics_addr = config.metrics_port.map(|port| {
+            SocketAddr::new(
+                config.metrics_bind.unwrap_or(Ipv6Addr::UNSPECIFIED.into()),
+                port,
+            )
+        });
+        let otlp_endpoint = config.otlp_endpoint;
+        let otlp_headers = config.otlp_headers.unwrap_or_default();
+        let request_log_size = config.request_log_size.unwrap_or(100);
+        let capture_size = config.capture_size.unwrap_or(0);
+        let offline_queue_size = config.offline_queue_size.unwrap_or(100);
+        let offline_queue_max_body = config.offline_queue_max_body.unwrap_or(1024 * 1024);
+        let offline_queue_ttl = Duration::from_secs(config.offline_queue_ttl.unwrap_or(86400));
+        let offline_queue_dir = config.offline_queue_dir.filter(|dir| !dir.is_empty());
+        let cache_size = config.cache_size.unwrap_or(64 * 1024 * 1024);
+        let cache_max_entry = config.cache_max_entry.unwrap_or(1024 * 1024);
+        let cache_max_ttl = Duration::from_secs(config.cache_max_ttl.unwrap_or(3600));
+        let cache_ranges = config.cache_ranges.unwrap_or(false);
+        let mirror_max_body = config.mirror_max_body.unwrap_or(1024 * 1024);
+        let access_log = config.access_log.filter(|path| !path.is_empty());
+        let access_log_format = config.access_log_format.unwrap_or_default();
+        let access_log_max_size = config.access_log_max_size.unwrap_or(100 * 1024 * 1024);
+        let access_log_opt_in = config.access_log_opt_in.unwrap_or(false);
+        let storage_url = config.storage_url.filter(|url| !url.is_empty());
+        let admin_addr = config.admin_port.map(|port| {
+            SocketAddr::new(
+                config.admin_bind.unwrap_or(Ipv6Addr::UNSPECIFIED.into()),
+                port,
+            )
+        });
+        let admin_token = config.admin_token.filter(|token| !token.is_empty());
+        let grpc_addr = config.grpc_port.map(|port| {
+            SocketAddr::new(
+                config.grpc_bind.unwrap_or(Ipv6Addr::UNSPECIFIED.into()),
+                port,
+            )
+        });
+        let webhooks = config.webhooks.unwrap_or_default();
+        let usage_flush_interval =
+            Duration::from_secs(config.usage_flush_interval.unwrap_or(60).max(1));
+        let usage_retention = Duration::from_secs(config.usage_retention.unwrap_or(168) * 3600);
+        let alerts = AlertThresholds {
+            p95_latency: config.alert_p95_latency,
+            error_rate: config.alert_error_rate,
+        };
+        let alert_window = Duration::from_secs(config.alert_window.unwrap_or(300));
+        let alert_min_requests = config.alert_min_requests.unwrap_or(20);
+        let redis_url = config.redis_url;
+        let instance_ip = config.instance_ip;
+        let host_cache_ttl = seconds(config.host_cache_ttl.unwrap_or(10));
+        let gossip_port = config.gossip_port;
+        let gossip_seeds = config.gossip_seeds.unwrap_or_default();
+        let instance_links = config.instance_links.unwrap_or(4);
+        let drain_timeout = Duration::from_secs(config.drain_timeout.unwrap_or(30));
+        let tunnel_queue_size = config.tunnel_queue_size.unwrap_or(64).max(1);
+        let stream_queue_size = config.stream_queue_size.unwrap_or(16).max(1);
+        let stream_write_timeout =
+            Duration::from_secs(config.stream_write_timeout.unwrap_or(30).max(1));
+        let max_connections = config.max_connections.filter(|max| *max > 0);
+        let accept_rate = config.accept_rate.filter(|rate| *rate > 0);
+        let buffer_pool_size = config.buffer_pool_size.unwrap_or(1024);
+        let read_buffer_size = config.read_buffer_size.unwrap_or(16 * 1024).max(1024);
+        let consistent_hashing = config.consistent_hashing.unwrap_or(false);
+        let proxy_protocol = config.proxy_protocol.unwrap_or(false);
+        let tcp_nodelay = config.tcp_nodelay.unwrap_or(true);
+        let tcp_keepalive = seconds(config.tcp_keepalive.unwrap_or(0));
+        let tcp_keepalive_interval = config.tcp_keepalive_interval.and_then(seconds);
+        let reuse_port = config.reuse_port.unwrap_or(false);
+        let accept_shards = config.accept_shards.unwrap_or(1).max(1);
+        let internal_secret = config.internal_secret.filter(|secret| !secret.is_empty());
+        let host_query_timeout =
+            Duration::from_millis(config.host_query_timeout.unwrap_or(2000).max(1));
+        let host_query_retries = config.host_query_retries.unwrap_or(1);
+        let host_query_hedge = Some(config.host_query_hedge.unwrap_or(250))
+            .filter(|hedge| *hedge > 0)
+            .map(Duration::from_millis);
+        let consul_url = config.consul_url;
+        let consul_service = config
+            .consul_service
+            .unwrap_or_else(|| "portal".to_string());
+        let consul_token = config.consul_token;
+        let dns_provider = config.dns_provider;
+        let dns_zone_id = config.dns_zone_id;
+        let dns_api_token = config.dns_api_token;
+        let dns_access_key_id = config.dns_access_key_id;
+        let dns_secret_access_key = config.dns_secret_access_key;
+        let dns_target = config.dns_target;
+        let dns_ttl = config.dns_ttl.unwrap_or(300);
+        let region = config.region;
+        let region_wait = Duration::from_millis(config.region_wait.unwrap_or(100));
+        let regions = config.regions.unwrap_or_default();
+        let log_level = config
+            .log_level
+            .and_then(|level| LevelFilter::from_str(&level).ok())
+            .unwrap_or(LevelFilter::DEBUG);
 
         Config {
             allowed_hosts,
             blocked_sub_domains,
+            sub_domain_filter,
+            filtered_words,
+            sub_domain_scheme,
+            sub_domain_length,
+            sub_domain_prefix,
+            sub_domain_suffix,
             remote_port,
             control_port,
             internal_network_port,
+            remote_addrs,
+            control_addr,
+            quic_addr,
+            quic_cert,
+            quic_key,
+            control_path,
+            public_http_port,
+            public_https_port,
+            tcp_ports,
+            tls_addr,
+            internal_network_addr,
             master_sig_key,
             gossip_dns_host,
             honeycomb_api_key,
             instance_id,
             blocked_ips,
             portal_host,
+            trust_forwarded_headers,
+            trusted_proxies,
+            https_redirect,
+            hsts,
+            hsts_max_age,
+            answer_continue,
+            error_pages_dir,
+            stream_idle_timeout,
+            max_stream_lifetime,
+            disconnect_grace,
+            reconnect_window,
+            stats_interval,
+            bandwidth_limit,
+            max_tunnel_lifetime,
+            tiers,
+            default_tier,
+            anonymous_tier,
+            minimum_agent_version,
+            agent_download_url,
+            latest_agent_version,
+            agent_release_feed,
+            agent_update_interval,
+            max_header_size,
+            max_body_size,
+            header_read_timeout,
+            request_timeout,
+            load_balancing,
+            sticky_cookie,
+            metrics_addr,
+            otlp_endpoint,
+            otlp_headers,
+            request_log_size,
+            capture_size,
+            offline_queue_size,
+            offline_queue_max_body,
+            offline_queue_ttl,
+            offline_queue_dir,
+            cache_size,
+            cache_max_entry,
+            cache_max_ttl,
+            cache_ranges,
+            mirror_max_body,
+            access_log,
+            access_log_format,
+            access_log_max_size,
+            access_log_opt_in,
+            storage_url,
+            admin_addr,
+            admin_token,
+            grpc_addr,
+            webhooks,
+            usage_flush_interval,
+            usage_retention,
+            alerts,
+            alert_window,
+            alert_min_requests,
+            redis_url,
+            instance_ip,
+            host_cache_ttl,
+            gossip_port,
+            gossip_seeds,
+            instance_links,
+            drain_timeout,
+            tunnel_queue_size,
+            stream_queue_size,
+            stream_write_timeout,
+            max_connections,
+            accept_rate,
+            buffer_pool_size,
+            read_buffer_size,
+            consistent_hashing,
+            proxy_protocol,
+            tcp_nodelay,
+            tcp_keepalive,
+            tcp_keepalive_interval,
+            reuse_port,
+            accept_shards,
+            internal_secret,
+            host_query_timeout,
+            host_query_retries,
+            host_query_hedge,
+            consul_url,
+            consul_service,
+            consul_token,
+            dns_provider,
+            dns_zone_id,
+            dns_api_token,
+            dns_access_key_id,
+            dns_secret_access_key,
+            dns_target,
+            dns_ttl,
+            region,
+            region_wait,
+            regions,
+            log_level,
         }
     }
 }
 
 impl Config {
+    /// Load the defaults, overridden by the file given on the command line, the environment
+    /// and the settings given on the command line, in that order
+    pub fn load(cli: &Cli) -> Result<Config, Box<dyn Error>> {
+        Layers::load(cli)?.config()
+    }
+
     pub fn load_from_file(path: &str) -> Result<Config, Box<dyn Error>> {
-        info!("loading config from file: {}", path);
-        let config = std::fs::read_to_string(path)?;
-        let config: InternalConfig = toml::from_str(&config)?;
+        let mut settings = file_settings(path)?;
+        let problems = settings.take_invalid();
+        if !problems.is_empty() {
+            return Err(Problems(problems).into());
+        }
+        Ok(Config::from(settings))
+    }
 
-        Ok(Config::from(config))
+    /// Carry over what only takes effect at startup, i.e. the listeners, the cluster and
+    /// generated keys, when a reloaded config replaces `current`
+    pub fn keep_startup_settings(&mut self, current: &Config) {
+        self.remote_port = current.remote_port;
+        self.control_port = current.control_port;
+        self.internal_network_port = current.internal_network_port;
+        self.remote_addrs = current.remote_addrs.clone();
+        self.control_addr = current.control_addr;
+        self.quic_addr = current.quic_addr;
+        self.tls_addr = current.tls_addr;
+        self.internal_network_addr = current.internal_network_addr;
+        self.master_sig_key = current.master_sig_key.clone();
+        self.instance_id = current.instance_id.clone();
+        self.instance_ip = current.instance_ip;
+        self.metrics_addr = current.metrics_addr;
+        self.admin_addr = current.admin_addr;
+        self.grpc_addr = current.grpc_addr;
+        self.offline_queue_dir = current.offline_queue_dir.clone();
+        self.access_log = current.access_log.clone();
+        self.storage_url = current.storage_url.clone();
+        self.access_log_format = current.access_log_format;
+        self.access_log_max_size = current.access_log_max_size;
+        self.redis_url = current.redis_url.clone();
+        self.gossip_port = current.gossip_port;
+        self.gossip_seeds = current.gossip_seeds.clone();
+        self.consul_url = current.consul_url.clone();
+        self.consul_service = current.consul_service.clone();
+        self.dns_provider = current.dns_provider;
+        self.dns_zone_id = current.dns_zone_id.clone();
+        self.dns_api_token = current.dns_api_token.clone();
+        self.dns_access_key_id = current.dns_access_key_id.clone();
+        self.dns_secret_access_key = current.dns_secret_access_key.clone();
+        self.internal_secret = current.internal_secret.clone();
+        self.sub_domain_scheme = current.sub_domain_scheme;
+        self.sub_domain_length = current.sub_domain_length;
+        self.sub_domain_prefix = current.sub_domain_prefix.clone();
+        self.sub_domain_suffix = current.sub_domain_suffix.clone();
+        self.buffer_pool_size = current.buffer_pool_size;
+        self.read_buffer_size = current.read_buffer_size;
+        self.reuse_port = current.reuse_port;
+        self.accept_shards = current.accept_shards;
     }
 
-    pub fn load_from_env() -> Config {
-        info!("loading config from ENV");
-        let allowed_hosts = std::env::var("ALLOWED_HOSTS")
-            .map(|s| s.split(',').map(String::from).collect())
-            .unwrap_or_default();
+    /// What accounts of the tier named `name` may do
+    pub fn tier(&self, name: &str) -> Tier {
+        self.tiers.get(name).cloned().unwrap_or_default()
+    }
 
-        let blocked_sub_domains = std::env::var("BLOCKED_SUB_DOMAINS")
-            .map(|s| s.split(',').map(String::from).collect())
-            .unwrap_or_default();
+    /// Size limits applied to every visitor request
+    pub fn request_limits(&self) -> Limits {
+        Limits {
+            max_head_size: self.max_header_size,
+            max_body_size: self.max_body_size,
+        }
+    }
+
+    /// Where we reach our own remote listener, which may be bound to every interface
+    pub fn local_remote_addr(&self) -> SocketAddr {
+        loopback_if_unspecified(self.remote_addrs[0])
+    }
+
+    /// Whether forwarding headers set by `peer` should be trusted
+    pub fn trusts_forwarded_headers_from(&self, peer: IpAddr) -> bool {
+        self.trust_forwarded_headers
+            || self
+                .trusted_proxies
+                .iter()
+                .any(|range| range.contains(peer))
+    }
+
+    /// Whether `peer` may tell us who connected in a PROXY protocol header. Only load
+    /// balancers in `trusted_proxies` may, and our own instance links over loopback.
+    pub fn trusts_proxy_header_from(&self, peer: IpAddr) -> bool {
+        peer.to_canonical().is_loopback()
+            || self
+                .trusted_proxies
+                .iter()
+                .any(|range| range.contains(peer))
+    }
+
+    /// Whether `ip` is on the block list
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.blocked_ips.iter().any(|range| range.contains(ip))
+    }
+
+    /// Whether a proxy in front of us can tell which requests came over https, as
+    /// we never see their TLS ourselves
+    pub fn tells_https(&self) -> bool {
+        self.trust_forwarded_headers || !self.trusted_proxies.is_empty()
+    }
+
+    pub fn load_from_env() -> Result<Config, Problems> {
+        let mut env = Env::default();
+        let mut settings = env_settings(&mut env);
+        let mut problems = env.problems;
+        problems.extend(settings.take_invalid());
+        if !problems.is_empty() {
+            return Err(Problems(problems));
+        }
+        Ok(Config::from(settings))
+    }
+
+    /// Check the settings make sense together, listing every problem rather than the first
+    pub fn validate(&self) -> Result<(), Problems> {
+        let mut problems = vec![];
+
+        let mut listeners: Vec<(&str, SocketAddr)> = self
+            .remote_addrs
+            .iter()
+            .map(|addr| ("remote listener", *addr))
+            .collect();
+        listeners.push(("control server", self.control_addr));
+        listeners.push(("internal network service", self.internal_network_addr));
+        if let Some(addr) = self.tls_addr {
+            listeners.push(("tls listener", addr));
+        }
+        if let Some(addr) = self.metrics_addr {
+            listeners.push(("metrics", addr));
+        }
+        if let Some(addr) = self.admin_addr {
+            listeners.push(("admin api", addr));
+        }
+        if let Some(addr) = self.grpc_addr {
+            listeners.push(("grpc admin api", addr));
+            if !cfg!(feature = "grpc") {
+                problems.push("grpc_port needs a server built with the grpc feature".to_string());
+            }
+        }
+        for (i, (name, addr)) in listeners.iter().enumerate() {
+            for (other, other_addr) in &listeners[i + 1..] {
+                if overlaps(*addr, *other_addr) {
+                    problems.push(format!(
+                        "the {} on {} and the {} on {} can't share a port",
+                        name, addr, other, other_addr
+                    ));
+                }
+            }
+        }
+
+        // QUIC is over udp, like the membership gossip on every interface
+        if let (Some(addr), Some(port)) = (self.quic_addr, self.gossip_port) {
+            let gossip = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+            if overlaps(addr, gossip) {
+                problems.push(format!(
+                    "the quic control server on {} and the gossip on {} can't share a port",
+                    addr, gossip
+                ));
+            }
+        }
+
+        // tcp tunnels bind their ports on every interface
+        if let Some(range) = &self.tcp_ports {
+            for (name, addr) in &listeners {
+                if range.contains(addr.port()) {
+                    problems.push(format!(
+                        "the {} on {} is in tcp_ports {}",
+                        name, addr, range
+                    ));
+                }
+            }
+        }
+
+        match (&self.quic_cert, &self.quic_key) {
+            (Some(_), None) | (None, Some(_)) => {
+                problems.push("quic_cert and quic_key go together".to_string())
+            }
+            (Some(cert), Some(key)) => {
+                for path in [cert, key] {
+                    if let Err(error) = std::fs::metadata(path) {
+                        problems.push(format!("{} can't be read: {}", path, error));
+                    }
+                }
+            }
+            (None, None) => {}
+        }
 
-        let master_sig_key = if let Ok(key) = std::env::var("MASTER_SIG_KEY") {
-            SigKey::from_hex(&key).expect("invalid master key: not hex or length incorrect")
+        // no load balancer could send us its header
+        if self.proxy_protocol && self.trusted_proxies.is_empty() {
+            problems.push("proxy_protocol needs the load balancers in trusted_proxies".to_string());
+        }
+
+        // every request would look like plain http, and be redirected forever
+        if self.https_redirect && !self.tells_https() {
+            problems.push(
+                "https_redirect needs trusted_proxies or trust_forwarded_headers".to_string(),
+            );
+        }
+
+        if let Some(path) = &self.control_path {
+            if !path.starts_with('/') || path == "/" || path.contains('?') {
+                problems.push(format!(
+                    "control_path {} isn't a path below the root, like /connect",
+                    path
+                ));
+            }
+        }
+
+        if !is_hostname(&self.portal_host) {
+            problems.push(format!(
+                "portal_host {} isn't a valid host",
+                self.portal_host
+            ));
+        }
+        for host in &self.allowed_hosts {
+            if !is_hostname(host) {
+                problems.push(format!("allowed host {} isn't a valid host", host));
+            }
+        }
+
+        if let Some(path) = &self.sub_domain_filter {
+            if let Err(error) = std::fs::metadata(path) {
+                problems.push(format!(
+                    "sub_domain_filter {} can't be read: {}",
+                    path, error
+                ));
+            }
+        }
+        // short words would filter most random names
+        for word in self.filtered_words.iter().filter(|word| word.len() < 3) {
+            problems.push(format!("filtered word {} is shorter than 3 letters", word));
+        }
+
+        if !self.tiers.is_empty() {
+            for tier in [&self.default_tier, &self.anonymous_tier] {
+                if !self.tiers.contains_key(tier) {
+                    problems.push(format!("tier {} isn't one of the configured tiers", tier));
+                }
+            }
+        }
+
+        if !(4..=32).contains(&self.sub_domain_length) {
+            problems.push(format!(
+                "sub_domain_length {} isn't between 4 and 32",
+                self.sub_domain_length
+            ));
+        }
+        let affixes = format!("{}{}", self.sub_domain_prefix, self.sub_domain_suffix);
+        if affixes.len() > 24
+            || !affixes
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            problems.push(format!(
+                "sub_domain_prefix and sub_domain_suffix must be letters, digits or hyphens \
+                 and at most 24 long together, not {}",
+                affixes
+            ));
+        }
+
+        if let Some(dir) = &self.error_pages_dir {
+            if !std::path::Path::new(dir).is_dir() {
+                problems.push(format!("error_pages_dir {} isn't a directory", dir));
+            }
+        }
+
+        if let Some(Err(problem)) = self.storage_url.as_deref().map(crate::storage::check_url) {
+            problems.push(problem);
+        }
+
+        if let Some(dir) = &self.offline_queue_dir {
+            if !std::path::Path::new(dir).is_dir() {
+                problems.push(format!("offline_queue_dir {} isn't a directory", dir));
+            }
+        }
+
+        let urls = [
+            ("redis_url", self.redis_url.as_deref()),
+            ("consul_url", self.consul_url.as_deref()),
+            ("otlp_endpoint", self.otlp_endpoint.as_deref()),
+            ("agent_download_url", Some(self.agent_download_url.as_str())),
+            ("agent_release_feed", self.agent_release_feed.as_deref()),
+        ]
+        .into_iter()
+        .chain(
+            self.webhooks
+                .iter()
+                .map(|webhook| ("webhook url", Some(webhook.url.as_str()))),
+        );
+        for (name, url) in urls {
+            if let Some(Err(error)) = url.map(url::Url::parse) {
+                problems.push(format!(
+                    "{} {} isn't a valid url: {}",
+                    name,
+                    url.unwrap(),
+                    error
+                ));
+            }
+        }
+
+        // the admin apis only answer to the admin token
+        if self.admin_token.is_none() {
+            for (name, port) in [
+                ("admin_port", self.admin_addr),
+                ("grpc_port", self.grpc_addr),
+            ] {
+                if port.is_some() {
+                    problems.push(format!("{} needs an admin_token", name));
+                }
+            }
+        }
+
+        if self.redis_url.is_some() && !cfg!(feature = "redis") {
+            problems.push("redis_url needs a server built with the redis feature".to_string());
+        }
+
+        let shared_registry =
+            self.redis_url.is_some() || self.gossip_port.is_some() || self.consul_url.is_some();
+        if shared_registry && self.instance_ip.is_none() {
+            problems.push(
+                "instance_ip is required to share hosts through redis, gossip or consul"
+                    .to_string(),
+            );
+        }
+        if let Some(provider) = self.dns_provider {
+            if self.dns_zone_id.is_none() {
+                problems.push("dns_provider needs the dns_zone_id of the zone".to_string());
+            }
+            if crate::dns::target(self).is_none() {
+                problems.push("dns_provider needs a dns_target or instance_ip".to_string());
+            }
+            let (credentials, needed) = match provider {
+                DnsProviderKind::Cloudflare => (self.dns_api_token.is_some(), "dns_api_token"),
+                DnsProviderKind::Route53 => (
+                    self.dns_access_key_id.is_some() && self.dns_secret_access_key.is_some(),
+                    "dns_access_key_id and dns_secret_access_key",
+                ),
+            };
+            if !credentials {
+                problems.push(format!("dns_provider needs {}", needed));
+            }
+        }
+        for (region, address) in &self.regions {
+            let port = address
+                .rsplit_once(':')
+                .map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(_))) {
+                problems.push(format!(
+                    "control server {} of region {} isn't a host:port",
+                    address, region
+                ));
+            }
+        }
+        if self.consistent_hashing && self.gossip_port.is_none() {
+            problems
+                .push("consistent_hashing needs the membership gossip_port provides".to_string());
+        }
+
+        if let Some(rate) = self.alerts.error_rate {
+            if !(0.0..=1.0).contains(&rate) {
+                problems.push(format!("alert_error_rate {} isn't between 0 and 1", rate));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
         } else {
-            tracing::warn!("WARNING! generating ephemeral signature key!");
-            SigKey::generate()
-        };
+            Err(Problems(problems))
+        }
+    }
+}
+
+/// Everything wrong with a config, one problem per line
+#[derive(Debug, thiserror::Error)]
+#[error("{}", .0.join("\n"))]
+pub struct Problems(pub Vec<String>);
+
+impl InternalConfig {
+    /// Drop the settings `Config::from` can't make sense of, describing what's wrong with them
+    fn take_invalid(&mut self) -> Vec<String> {
+        let mut problems = vec![];
+        if let Some(key) = &self.master_sig_key {
+            if SigKey::from_hex(key).is_err() {
+                problems.push("master_sig_key isn't 32 bytes of hex".to_string());
+                self.master_sig_key = None;
+            }
+        }
+        if let Some(level) = &self.log_level {
+            if LevelFilter::from_str(level).is_err() {
+                problems.push(format!(
+                    "log_level {} isn't one of off, error, warn, info, debug or trace",
+                    level
+                ));
+                self.log_level = None;
+            }
+        }
+        for (name, ranges) in [
+            ("blocked_ips", &mut self.blocked_ips),
+            ("trusted_proxies", &mut self.trusted_proxies),
+        ] {
+            if let Some(ranges) = ranges {
+                ranges.retain(|range| match range.parse::<Cidr>() {
+                    Ok(_) => true,
+                    Err(error) => {
+                        problems.push(format!("{} entry {}: {}", name, range, error));
+                        false
+                    }
+                });
+            }
+        }
+        problems
+    }
+}
+
+fn file_settings(path: &str) -> Result<InternalConfig, Box<dyn Error>> {
+    info!("loading config from file: {}", path);
+    let config = std::fs::read_to_string(path)?;
+    Ok(toml::from_str(&config)?)
+}
+
+/// The settings given through environment variables, leaving out those that aren't set
+fn env_settings(env: &mut Env) -> InternalConfig {
+    info!("loading config from ENV");
+    let allowed_hosts = std::env::var("ALLOWED_HOSTS")
+        .map(|s| s.split(',').map(String::from).collect())
+        .ok();
+
+    let blocked_sub_domains = std::env::var("BLOCKED_SUB_DOMAINS")
+        .map(|s| s.split(',').map(String::from).collect())
+        .ok();
+
+    let master_sig_key = std::env::var("MASTER_SIG_KEY").ok();
+
+    let gossip_dns_host = std::env::var("FLY_APP_NAME")
+        .map(|app_name| format!("global.{}.internal", app_name))
+        .ok();
+
+    let honeycomb_api_key = std::env::var("HONEYCOMB_API_KEY").ok();
+    let instance_id = std::env::var("FLY_ALLOC_ID").ok();
+    let blocked_ips = env.list("BLOCKED_IPS");
 
-        let gossip_dns_host = std::env::var("FLY_APP_NAME")
-            .map(|app_name| format!("global.{}.internal", app_name))
-            .ok();
+    let portal_host = std::env::var("PORTAL_HOST").ok();
 
-        let honeycomb_api_key = std::env::var("HONEYCOMB_API_KEY").ok();
-        let instance_id = std::env::var("FLY_ALLOC_ID").unwrap_or(Uuid::new_v4().to_string());
-        let blocked_ips = std::env::var("BLOCKED_IPS")
+    InternalConfig {
+        allowed_hosts,
+        blocked_sub_domains,
+        sub_domain_filter: std::env::var("SUB_DOMAIN_FILTER").ok(),
+        sub_domain_scheme: env.parse("SUB_DOMAIN_SCHEME"),
+        sub_domain_length: env.parse("SUB_DOMAIN_LENGTH"),
+        sub_domain_prefix: std::env::var("SUB_DOMAIN_PREFIX").ok(),
+        sub_domain_suffix: std::env::var("SUB_DOMAIN_SUFFIX").ok(),
+        control_port: env.parse("CTRL_PORT"),
+        quic_port: env.parse("QUIC_PORT"),
+        quic_cert: std::env::var("QUIC_CERT").ok(),
+        quic_key: std::env::var("QUIC_KEY").ok(),
+        control_path: std::env::var("CONTROL_PATH").ok(),
+        remote_port: env.parse("PORT"),
+        internal_network_port: env.parse("NET_PORT"),
+        remote_bind: env.parse("BIND"),
+        control_bind: env.parse("CTRL_BIND"),
+        quic_bind: env.parse("QUIC_BIND"),
+        internal_network_bind: env.parse("NET_BIND"),
+        remote_listeners: env.list("REMOTE_LISTENERS"),
+        public_http_port: env.parse("PUBLIC_HTTP_PORT"),
+        public_https_port: env.parse("PUBLIC_HTTPS_PORT"),
+        tcp_ports: env.parse("TCP_PORTS"),
+        tls_port: env.parse("TLS_PORT"),
+        master_sig_key,
+        gossip_dns_host,
+        honeycomb_api_key,
+        instance_id,
+        blocked_ips,
+        portal_host,
+        trust_forwarded_headers: env.bool("TRUST_FORWARDED_HEADERS"),
+        trusted_proxies: env.list("TRUSTED_PROXIES"),
+        https_redirect: env.bool("HTTPS_REDIRECT"),
+        hsts: env.bool("HSTS"),
+        hsts_max_age: env.parse("HSTS_MAX_AGE"),
+        answer_continue: env.bool("ANSWER_CONTINUE"),
+        error_pages_dir: std::env::var("ERROR_PAGES_DIR").ok(),
+        stream_idle_timeout: env.parse("STREAM_IDLE_TIMEOUT"),
+        max_stream_lifetime: env.parse("MAX_STREAM_LIFETIME"),
+        disconnect_grace: env.parse("DISCONNECT_GRACE"),
+        reconnect_window: env.parse("RECONNECT_WINDOW"),
+        stats_interval: env.parse("STATS_INTERVAL"),
+        bandwidth_limit: env.parse("BANDWIDTH_LIMIT"),
+        max_tunnel_lifetime: env.parse("MAX_TUNNEL_LIFETIME"),
+        tiers: None,
+        default_tier: std::env::var("DEFAULT_TIER").ok(),
+        anonymous_tier: std::env::var("ANONYMOUS_TIER").ok(),
+        minimum_agent_version: env.parse("MINIMUM_AGENT_VERSION"),
+        agent_download_url: std::env::var("AGENT_DOWNLOAD_URL").ok(),
+        latest_agent_version: env.parse("LATEST_AGENT_VERSION"),
+        agent_release_feed: std::env::var("AGENT_RELEASE_FEED").ok(),
+        agent_update_interval: env.parse("AGENT_UPDATE_INTERVAL"),
+        max_header_size: env.parse("MAX_HEADER_SIZE"),
+        max_body_size: env.parse("MAX_BODY_SIZE"),
+        header_read_timeout: env.parse("HEADER_READ_TIMEOUT"),
+        request_timeout: env.parse("REQUEST_TIMEOUT"),
+        load_balancing: env.parse("LOAD_BALANCING"),
+        sticky_cookie: std::env::var("STICKY_COOKIE").ok(),
+        metrics_port: env.parse("METRICS_PORT"),
+        metrics_bind: env.parse("METRICS_BIND"),
+        otlp_endpoint: std::env::var("OTLP_ENDPOINT").ok(),
+        otlp_headers: std::env::var("OTLP_HEADERS")
             .map(|s| {
                 s.split(',')
-                    .map(IpAddr::from_str)
-                    .filter_map(Result::ok)
+                    .filter_map(|header| header.split_once('='))
+                    .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
                     .collect()
             })
-            .unwrap_or_default();
+            .ok(),
+        request_log_size: env.parse("REQUEST_LOG_SIZE"),
+        capture_size: env.parse("CAPTURE_SIZE"),
+        offline_queue_size: env.parse("OFFLINE_QUEUE_SIZE"),
+        offline_queue_max_body: env.parse("OFFLINE_QUEUE_MAX_BODY"),
+        offline_queue_ttl: env.parse("OFFLINE_QUEUE_TTL"),
+        offline_queue_dir: std::env::var("OFFLINE_QUEUE_DIR").ok(),
+        cache_size: env.parse("CACHE_SIZE"),
+        cache_max_entry: env.parse("CACHE_MAX_ENTRY"),
+        cache_max_ttl: env.parse("CACHE_MAX_TTL"),
+        cache_ranges: env.bool("CACHE_RANGES"),
+        mirror_max_body: env.parse("MIRROR_MAX_BODY"),
+        access_log: std::env::var("ACCESS_LOG").ok(),
+        access_log_format: env.parse("ACCESS_LOG_FORMAT"),
+        access_log_max_size: env.parse("ACCESS_LOG_MAX_SIZE"),
+        access_log_opt_in: env.bool("ACCESS_LOG_OPT_IN"),
+        storage_url: std::env::var("STORAGE_URL").ok(),
+        admin_port: env.parse("ADMIN_PORT"),
+        admin_bind: env.parse("ADMIN_BIND"),
+        admin_token: std::env::var("ADMIN_TOKEN").ok(),
+        grpc_port: env.parse("GRPC_PORT"),
+        grpc_bind: env.parse("GRPC_BIND"),
+        webhooks: std::env::var("WEBHOOK_URL").ok().map(|url| {
+            vec![Webhook {
+                url,
+                secret: std::env::var("WEBHOOK_SECRET").ok(),
+                events: env.list("WEBHOOK_EVENTS").unwrap_or_default(),
+            }]
+        }),
+        usage_flush_interval: env.parse("USAGE_FLUSH_INTERVAL"),
+        usage_retention: env.parse("USAGE_RETENTION"),
+        alert_p95_latency: env.parse("ALERT_P95_LATENCY"),
+        alert_error_rate: env.parse("ALERT_ERROR_RATE"),
+        alert_window: env.parse("ALERT_WINDOW"),
+        alert_min_requests: env.parse("ALERT_MIN_REQUESTS"),
+        redis_url: std::env::var("REDIS_URL").ok(),
+        instance_ip: env
+            .parse("INSTANCE_IP")
+            .or_else(|| env.parse("FLY_PRIVATE_IP")),
+        host_cache_ttl: env.parse("HOST_CACHE_TTL"),
+        gossip_port: env.parse("GOSSIP_PORT"),
+        gossip_seeds: std::env::var("GOSSIP_SEEDS")
+            .ok()
+            .map(|s| s.split(',').map(String::from).collect()),
+        instance_links: env.parse("INSTANCE_LINKS"),
+        drain_timeout: env.parse("DRAIN_TIMEOUT"),
+        tunnel_queue_size: env.parse("TUNNEL_QUEUE_SIZE"),
+        stream_queue_size: env.parse("STREAM_QUEUE_SIZE"),
+        stream_write_timeout: env.parse("STREAM_WRITE_TIMEOUT"),
+        max_connections: env.parse("MAX_CONNECTIONS"),
+        accept_rate: env.parse("ACCEPT_RATE"),
+        buffer_pool_size: env.parse("BUFFER_POOL_SIZE"),
+        read_buffer_size: env.parse("READ_BUFFER_SIZE"),
+        consistent_hashing: env.bool("CONSISTENT_HASHING"),
+        proxy_protocol: env.bool("PROXY_PROTOCOL"),
+        tcp_nodelay: env.bool("TCP_NODELAY"),
+        tcp_keepalive: env.parse("TCP_KEEPALIVE"),
+        tcp_keepalive_interval: env.parse("TCP_KEEPALIVE_INTERVAL"),
+        reuse_port: env.bool("REUSE_PORT"),
+        accept_shards: env.parse("ACCEPT_SHARDS"),
+        internal_secret: std::env::var("INTERNAL_SECRET").ok(),
+        host_query_timeout: env.parse("HOST_QUERY_TIMEOUT"),
+        host_query_retries: env.parse("HOST_QUERY_RETRIES"),
+        host_query_hedge: env.parse("HOST_QUERY_HEDGE"),
+        consul_url: std::env::var("CONSUL_URL").ok(),
+        consul_service: std::env::var("CONSUL_SERVICE").ok(),
+        consul_token: std::env::var("CONSUL_HTTP_TOKEN").ok(),
+        dns_provider: env.parse("DNS_PROVIDER"),
+        dns_zone_id: std::env::var("DNS_ZONE_ID").ok(),
+        dns_api_token: std::env::var("DNS_API_TOKEN").ok(),
+        dns_access_key_id: std::env::var("AWS_ACCESS_KEY_ID").ok(),
+        dns_secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").ok(),
+        dns_target: std::env::var("DNS_TARGET").ok(),
+        dns_ttl: env.parse("DNS_TTL"),
+        region: std::env::var("REGION")
+            .or_else(|_| std::env::var("FLY_REGION"))
+            .ok(),
+        region_wait: env.parse("REGION_WAIT"),
+        regions: std::env::var("REGIONS")
+            .map(|s| {
+                s.split(',')
+                    .filter_map(|region| region.split_once('='))
+                    .map(|(name, addr)| (name.trim().to_string(), addr.trim().to_string()))
+                    .collect()
+            })
+            .ok(),
+        log_level: std::env::var("LOG_LEVEL").ok(),
+    }
+}
 
-        let portal_host = std::env::var("PORTAL_HOST").unwrap_or("portal.illusiontech.cn".to_string());
+/// Parses environment variables, collecting the invalid ones rather than stopping at the first
+#[derive(Default)]
+struct Env {
+    problems: Vec<String>,
+}
 
-        Config {
-            allowed_hosts,
-            blocked_sub_domains,
-            control_port: get_port("CTRL_PORT", 5000),
-            remote_port: get_port("PORT", 8080),
-            internal_network_port: get_port("NET_PORT", 6000),
-            master_sig_key,
-            gossip_dns_host,
-            honeycomb_api_key,
-            instance_id,
-            blocked_ips,
-            portal_host,
+impl Env {
+    fn parse<T: FromStr>(&mut self, var: &'static str) -> Option<T>
+    where
+        T::Err: std::fmt::Display,
+    {
+        let value = std::env::var(var).ok()?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(error) => {
+                self.problems
+                    .push(format!("invalid ENV {}={}: {}", var, value, error));
+                None
+            }
+        }
+    }
+
+    /// A comma separated list
+    fn list<T: FromStr>(&mut self, var: &'static str) -> Option<Vec<T>>
+    where
+        T::Err: std::fmt::Display,
+    {
+        let value = std::env::var(var).ok()?;
+        let mut items = vec![];
+        for item in value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            match item.parse() {
+                Ok(parsed) => items.push(parsed),
+                Err(error) => self
+                    .problems
+                    .push(format!("invalid ENV {} entry {}: {}", var, item, error)),
+            }
         }
+        Some(items)
+    }
+
+    fn bool(&self, var: &'static str) -> Option<bool> {
+        std::env::var(var)
+            .ok()
+            .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
     }
 }
 
-fn get_port(var: &'static str, default: u16) -> u16 {
-    match std::env::var(var) {
-        Ok(port) => port.parse().unwrap_or_else(|_| {
-            panic!("invalid port ENV {}={}", var, port);
-        }),
-        Err(_) => default,
+/// Where a setting came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    File(PathBuf),
+    Env,
+    Cli,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "file {}", path.display()),
+            Source::Env => f.write_str("env"),
+            Source::Cli => f.write_str("cli"),
+        }
+    }
+}
+
+/// Settings that are never printed
+const SECRET_SETTINGS: &[&str] = &[
+    "master_sig_key",
+    "honeycomb_api_key",
+    "otlp_headers",
+    "admin_token",
+    "webhooks",
+    "internal_secret",
+    "consul_token",
+    "dns_api_token",
+    "dns_secret_access_key",
+];
+
+/// The settings of every source, each overriding those before it
+#[derive(Debug, Default)]
+pub struct Layers {
+    settings: toml::Table,
+    sources: HashMap<String, Source>,
+    /// environment variables we couldn't parse
+    problems: Vec<String>,
+}
+
+impl Layers {
+    /// The config file given on the command line, the environment, then the command line
+    pub fn load(cli: &Cli) -> Result<Layers, Box<dyn Error>> {
+        let mut layers = Layers::default();
+        if let Some(path) = &cli.config {
+            layers.add(
+                &file_settings(&path.to_string_lossy())?,
+                Source::File(path.clone()),
+            )?;
+        }
+        let mut env = Env::default();
+        layers.add(&env_settings(&mut env), Source::Env)?;
+        layers.problems = env.problems;
+        layers.add(&cli_settings(&cli.settings)?, Source::Cli)?;
+        Ok(layers)
+    }
+
+    fn add(&mut self, settings: &InternalConfig, source: Source) -> Result<(), Box<dyn Error>> {
+        // unset settings are left out, keeping what the layers before say
+        for (key, value) in toml::Table::try_from(settings)? {
+            self.sources.insert(key.clone(), source.clone());
+            self.settings.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn settings(&self) -> Result<InternalConfig, Box<dyn Error>> {
+        Ok(self.settings.clone().try_into()?)
+    }
+
+    /// The config these settings make, or everything that's wrong with them
+    pub fn config(&self) -> Result<Config, Box<dyn Error>> {
+        let mut settings = self.settings()?;
+        let mut problems = self.problems.clone();
+        problems.extend(settings.take_invalid());
+
+        let config = Config::from(settings);
+        if let Err(Problems(invalid)) = config.validate() {
+            problems.extend(invalid);
+        }
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(Problems(problems).into())
+        }
+    }
+
+    /// The effective settings as toml, each commented with where it came from
+    pub fn describe(&self) -> String {
+        let mut lines = vec!["# defaults < file < env < cli".to_string()];
+        for key in setting_names() {
+            let line = match (self.settings.get(&key), self.sources.get(&key)) {
+                (Some(_), Some(source)) if SECRET_SETTINGS.contains(&key.as_str()) => {
+                    format!("{} = \"<hidden>\" # {}", key, source)
+                }
+                (Some(value), Some(source)) => format!("{} = {} # {}", key, value, source),
+                _ => format!("# {} is unset, using the default", key),
+            };
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+}
+
+/// The name of every setting there is, sorted
+fn setting_names() -> Vec<String> {
+    // unlike toml, json keeps the unset ones as nulls
+    let mut names: Vec<String> = match serde_json::to_value(InternalConfig::default()) {
+        Ok(serde_json::Value::Object(settings)) => settings.keys().cloned().collect(),
+        _ => vec![],
+    };
+    names.sort();
+    names
+}
+
+/// Settings given as `key=value` on the command line, with values parsed as toml
+/// or else taken as a string
+fn cli_settings(settings: &[String]) -> Result<InternalConfig, Box<dyn Error>> {
+    let names = setting_names();
+    let mut table = toml::Table::new();
+    for setting in settings {
+        let (key, value) = setting
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=VALUE, got {}", setting))?;
+        let key = key.trim();
+        if !names.iter().any(|name| name == key) {
+            return Err(format!("unknown setting: {}", key).into());
+        }
+        let parsed = format!("value = {}", value).parse::<toml::Table>();
+        let value = match parsed.ok().and_then(|mut parsed| parsed.remove("value")) {
+            Some(parsed) => parsed,
+            None => toml::Value::String(value.to_string()),
+        };
+        table.insert(key.to_string(), value);
+    }
+    Ok(table.try_into()?)
+}
+
+/// A zero duration disables the timeout
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// An address, or a range of them in CIDR notation like `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is in the range, an IPv4 address mapped into IPv6 counting as IPv4
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // the bits of both addresses, of which the first `prefix` have to match
+        let (net, ip, width) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                (u32::from(net) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        self.prefix == 0 || (net ^ ip) >> (width - self.prefix) == 0
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr.trim())
+            .map_err(|_| format!("{} isn't an ip address", addr))?
+            .to_canonical();
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= width)
+                .ok_or_else(|| format!("/{} isn't a prefix length up to {}", prefix, width))?,
+            None => width,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// The ranges of a list `take_invalid` left only valid ones in
+fn cidrs(ranges: Option<Vec<String>>) -> Vec<Cidr> {
+    ranges
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|range| range.parse().ok())
+        .collect()
+}
+
+/// Whether two listeners would fight over the same port
+fn overlaps(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() == b.port()
+        && a.port() != 0
+        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+fn is_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn loopback_if_unspecified(addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+    } else {
+        addr
     }
 }
 
@@ -205,4 +2040,164 @@ mod tests {
         let config = Config::load_from_file("tests/config.toml").unwrap();
         println!("config from file: {:?}", config);
     }
+
+    #[test]
+    fn test_layers() {
+        let mut layers = Layers::default();
+        let file = InternalConfig {
+            remote_port: Some(80),
+            portal_host: Some("example.com".to_string()),
+            ..Default::default()
+        };
+        let env = InternalConfig {
+            remote_port: Some(8080),
+            ..Default::default()
+        };
+        layers
+            .add(&file, Source::File("portal.toml".into()))
+            .unwrap();
+        layers.add(&env, Source::Env).unwrap();
+        let cli = cli_settings(&[
+            "control_port=5001".to_string(),
+            "allowed_hosts=[\"localhost\"]".to_string(),
+            "sticky_cookie=portal".to_string(),
+            "minimum_agent_version=\"0.1.20\"".to_string(),
+            "tcp_keepalive=30".to_string(),
+        ])
+        .unwrap();
+        layers.add(&cli, Source::Cli).unwrap();
+
+        let config = Config::from(layers.settings().unwrap());
+        assert_eq!(config.remote_port, 8080);
+        assert_eq!(config.control_port, 5001);
+        assert_eq!(config.portal_host, "example.com");
+        assert_eq!(config.allowed_hosts, vec!["localhost"]);
+        assert_eq!(config.sticky_cookie.as_deref(), Some("portal"));
+        assert_eq!(
+            config.minimum_agent_version,
+            Some(semver::Version::new(0, 1, 20))
+        );
+        assert!(config.tcp_nodelay);
+        assert!(config.answer_continue);
+        assert_eq!(config.tcp_keepalive, Some(Duration::from_secs(30)));
+        assert_eq!(config.tcp_keepalive_interval, None);
+        assert_eq!(layers.sources["remote_port"], Source::Env);
+        assert_eq!(
+            layers.sources["portal_host"],
+            Source::File("portal.toml".into())
+        );
+
+        assert!(cli_settings(&["nonsense=1".to_string()]).is_err());
+        assert!(cli_settings(&["remote_port=http".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = Config::from(InternalConfig::default());
+        assert!(config.validate().is_ok());
+
+        let mut settings = InternalConfig {
+            remote_port: Some(5000),
+            portal_host: Some("not a host".to_string()),
+            consul_url: Some("http://localhost:8500".to_string()),
+            master_sig_key: Some("abc".to_string()),
+            control_path: Some("connect".to_string()),
+            tcp_ports: Some("5990-6010".parse().unwrap()),
+            https_redirect: Some(true),
+            ..Default::default()
+        };
+        let mut problems = settings.take_invalid();
+        assert_eq!(settings.master_sig_key, None);
+        problems.extend(Config::from(settings).validate().unwrap_err().0);
+        assert_eq!(problems.len(), 7, "{:?}", problems);
+
+        // websocket and QUIC agents are served at once, on ports of their own
+        let settings = InternalConfig {
+            control_port: Some(5000),
+            quic_port: Some(5000),
+            quic_bind: Some("127.0.0.1".parse().unwrap()),
+            gossip_port: Some(5000),
+            instance_ip: Some("10.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        let config = Config::from(settings);
+        assert_eq!(config.control_addr, "0.0.0.0:5000".parse().unwrap());
+        assert_eq!(config.quic_addr, Some("127.0.0.1:5000".parse().unwrap()));
+        let problems = config.validate().unwrap_err().0;
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].contains("gossip"), "{:?}", problems);
+
+        // the admin apis may keep to one interface, and share a port across them
+        let settings = InternalConfig {
+            admin_port: Some(7000),
+            admin_bind: Some("127.0.0.1".parse().unwrap()),
+            admin_token: Some("secret".to_string()),
+            metrics_port: Some(7000),
+            metrics_bind: Some("10.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        let config = Config::from(settings);
+        assert_eq!(config.admin_addr, Some("127.0.0.1:7000".parse().unwrap()));
+        assert!(config.validate().is_ok());
+
+        // allowlists and the backends are checked before we start, not when first used
+        let mut settings = InternalConfig {
+            blocked_ips: Some(vec!["10.0.0.0/8".to_string(), "10.0.0.0/33".to_string()]),
+            trusted_proxies: Some(vec!["proxy".to_string()]),
+            admin_port: Some(5001),
+            storage_url: Some("sqlite:///nonexistent/portal.db".to_string()),
+            ..Default::default()
+        };
+        let mut problems = settings.take_invalid();
+        assert_eq!(settings.blocked_ips, Some(vec!["10.0.0.0/8".to_string()]));
+        let config = Config::from(settings);
+        assert!(config.is_blocked("10.1.2.3".parse().unwrap()));
+        problems.extend(config.validate().unwrap_err().0);
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+    }
+
+    #[test]
+    fn test_trusts_proxy_header_from() {
+        let settings = InternalConfig {
+            proxy_protocol: Some(true),
+            ..Default::default()
+        };
+        let config = Config::from(settings);
+        assert!(config.validate().is_err());
+        assert!(config.trusts_proxy_header_from("127.0.0.1".parse().unwrap()));
+        assert!(!config.trusts_proxy_header_from("203.0.113.7".parse().unwrap()));
+
+        let settings = InternalConfig {
+            proxy_protocol: Some(true),
+            trusted_proxies: Some(vec!["10.0.0.0/8".to_string()]),
+            trust_forwarded_headers: Some(true),
+            ..Default::default()
+        };
+        let config = Config::from(settings);
+        assert!(config.validate().is_ok());
+        assert!(config.trusts_proxy_header_from("10.1.2.3".parse().unwrap()));
+        assert!(config.trusts_proxy_header_from("::ffff:10.1.2.3".parse().unwrap()));
+        // trusting every peer's headers doesn't extend to the PROXY header
+        assert!(!config.trusts_proxy_header_from("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_cidr() {
+        let range: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains("10.255.0.1".parse().unwrap()));
+        assert!(range.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!range.contains("11.0.0.1".parse().unwrap()));
+
+        let ip: Cidr = "2001:db8::1".parse().unwrap();
+        assert!(ip.contains("2001:db8::1".parse().unwrap()));
+        assert!(!ip.contains("2001:db8::2".parse().unwrap()));
+
+        let any: Cidr = "::/0".parse().unwrap();
+        assert!(any.contains("2001:db8::2".parse().unwrap()));
+        assert!(!any.contains("127.0.0.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
 }
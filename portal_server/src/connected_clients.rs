@@ -1,28 +1,236 @@
 use super::*;
+use crate::observability::metrics::get_metrics;
+use crate::tasks::CancellationToken;
+use crate::throttle::Throttle;
+use crate::webhooks::Event;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 use std::fmt::Formatter;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// How long a connection replaced by its agent's newer one keeps serving its streams
+const REPLACED_GRACE: Duration = Duration::from_secs(5);
+
+/// One agent's tunnel: the account, the id the agent keeps on its machine, and the host.
+/// A machine's tunnels share its agent id, so the host tells them apart.
+type AgentKey = (ClientId, String, String);
+
+/// Identifies one agent connection. Agents authenticated with the same key
+/// share a `ClientId` and may serve the same host side by side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId(Uuid);
+
+impl SessionId {
+    pub fn generate() -> Self {
+        SessionId(Uuid::new_v4())
+    }
+}
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for SessionId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Uuid::from_str(s).map(SessionId)
+    }
+}
 
 #[derive(Clone)]
 pub struct ConnectedClient {
     pub id: ClientId,
+    pub session_id: SessionId,
     pub host: String,
     pub is_anonymous: bool,
-    pub tx: UnboundedSender<ControlPacket>,
+    /// what visitors speak to reach the tunnel
+    pub protocol: Protocol,
+    /// the account's tier
+    pub tier: String,
+    /// stream packets toward the agent, bounded so a slow agent pauses its visitors
+    pub tx: Sender<ControlPacket>,
+    /// pings, stats and the like, sent ahead of any stream data waiting in `tx` so a
+    /// busy agent doesn't look dead
+    pub control: Sender<ControlPacket>,
+    /// the senders `queue` goes through, shared by every clone
+    pub queued: Arc<Queued>,
+    /// the data packets of each stream the agent may send ahead of our credits, for
+    /// agents that flow control
+    pub stream_window: Option<usize>,
+    /// bandwidth limit shared by all of this tunnel's streams
+    pub throttle: Option<Throttle>,
+    /// the agent's self reported version and name
+    pub version: Option<String>,
+    pub name: Option<String>,
+    /// the id the agent keeps across runs on its machine
+    pub agent_id: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    /// whether the agent receives a `ControlPacket::Request` per request it served
+    pub request_log: bool,
+    /// whether the agent subscribed to every request on its tunnel
+    pub tail: Arc<AtomicBool>,
+    /// when to alert about this agent's tunnel
+    pub alerts: AlertThresholds,
+    /// whether its responses may be cached, for http tunnels that asked for it
+    pub cache: bool,
+    /// the host whose requests the agent receives copies of
+    pub mirror_of: Option<String>,
+    /// whether the agent asked for its visitors to be access logged
+    pub access_log: bool,
+    /// whether the agent gets a `ControlPacket::Stats` now and then
+    pub stats: bool,
+    /// the CORS headers added to its responses, for http tunnels that asked for them
+    pub cors: Option<CorsOptions>,
+    /// whether plain http visitors are sent to https, for http tunnels that asked for it
+    pub https_redirect: bool,
+    /// whether responses over https get HSTS, for http tunnels that asked for it
+    pub hsts: bool,
+    /// when the tunnel is closed, for tunnels that don't live forever
+    pub expires_at: Option<DateTime<Utc>>,
+    /// cancelled once the agent is gone, ending the tasks serving it and its streams
+    pub cancel: CancellationToken,
+}
+
+impl ConnectedClient {
+    fn agent_key(&self) -> Option<AgentKey> {
+        let agent_id = self.agent_id.clone()?;
+        Some((self.id.clone(), agent_id, self.host.clone()))
+    }
+
+    /// Whether this is the connection an agent reconnecting for `host` replaces
+    pub fn is_replaced_by(&self, id: &ClientId, agent_id: Option<&str>, host: &str) -> bool {
+        self.agent_id.is_some()
+            && self.agent_id.as_deref() == agent_id
+            && &self.id == id
+            && self.host == host
+    }
+
+    pub fn is_tailing(&self) -> bool {
+        self.tail.load(Ordering::Relaxed)
+    }
+
+    pub fn set_tailing(&self, tail: bool) {
+        self.tail.store(tail, Ordering::Relaxed);
+    }
+
+    /// Whether the agent sends no more of a stream's data than we credit it with
+    pub fn flow_controls(&self) -> bool {
+        self.stream_window.is_some()
+    }
+
+    /// Queue a packet for the agent without waiting for room, i.e. from sync code,
+    /// dropping it when the queue is full
+    pub fn queue(&self, packet: ControlPacket) {
+        let queued = if packet.is_control() {
+            &self.queued.control
+        } else {
+            &self.queued.tx
+        };
+        if let Err(error) = queued.lock().unwrap().try_send(packet) {
+            if error.is_full() {
+                tracing::debug!(tunnel=%self.host, packet=%error.into_inner().packet_type(), "agent queue full, dropping packet");
+            }
+        }
+    }
+}
+
+/// One sender of each of an agent's queues for what's queued without waiting. A sender
+/// gets a slot of its own on top of the queue's, so cloning one for every packet would
+/// leave the queues unbounded; sharing these, they hold one packet more at most.
+#[derive(Debug)]
+pub struct Queued {
+    pub tx: Mutex<Sender<ControlPacket>>,
+    pub control: Mutex<Sender<ControlPacket>>,
 }
 
 impl std::fmt::Debug for ConnectedClient {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ConnectedClient")
             .field("id", &self.id)
+            .field("session", &self.session_id)
             .field("sub", &self.host)
             .field("anon", &self.is_anonymous)
+            .field("protocol", &self.protocol)
+            .field("throttle", &self.throttle)
+            .field("version", &self.version)
+            .field("name", &self.name)
             .finish()
     }
 }
 
+/// How visitor connections are spread over the agents serving a host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    #[default]
+    RoundRobin,
+    LeastStreams,
+}
+
+impl FromStr for LoadBalancing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(LoadBalancing::RoundRobin),
+            "least_streams" => Ok(LoadBalancing::LeastStreams),
+            other => Err(format!("unknown load balancing strategy: {}", other)),
+        }
+    }
+}
+
+/// The agents serving one host
+#[derive(Default)]
+struct AgentPool {
+    agents: Vec<ConnectedClient>,
+    next: usize,
+}
+
+impl AgentPool {
+    fn pick(&mut self, strategy: LoadBalancing) -> Option<ConnectedClient> {
+        if self.agents.is_empty() {
+            return None;
+        }
+
+        let agent = match strategy {
+            LoadBalancing::RoundRobin => {
+                self.next = self.next.wrapping_add(1);
+                &self.agents[self.next % self.agents.len()]
+            }
+            LoadBalancing::LeastStreams => self
+                .agents
+                .iter()
+                .min_by_key(|agent| stream_count(&agent.session_id))?,
+        };
+
+        Some(agent.clone())
+    }
+}
+
+fn stream_count(session_id: &SessionId) -> usize {
+    get_active_streams()
+        .iter()
+        .filter(|stream| &stream.client.session_id == session_id)
+        .count()
+}
+
 pub struct Connections {
-    clients: Arc<DashMap<ClientId, ConnectedClient>>,
-    hosts: Arc<DashMap<String, ConnectedClient>>,
+    clients: Arc<DashMap<SessionId, ConnectedClient>>,
+    hosts: Arc<DashMap<String, AgentPool>>,
+    /// the agents mirroring each host
+    mirrors: Arc<DashMap<String, Vec<ConnectedClient>>>,
+    /// the connection of each agent's tunnel
+    agents: Arc<DashMap<AgentKey, SessionId>>,
+    /// until when the hosts whose last agent dropped may see it reconnect
+    reconnecting: Arc<DashMap<String, Instant>>,
 }
 
 impl Default for Connections {
@@ -30,6 +238,9 @@ impl Default for Connections {
         Self {
             clients: Arc::new(DashMap::new()),
             hosts: Arc::new(DashMap::new()),
+            mirrors: Arc::new(DashMap::new()),
+            agents: Arc::new(DashMap::new()),
+            reconnecting: Arc::new(DashMap::new()),
         }
     }
 }
@@ -39,27 +250,114 @@ impl Connections {
         Self::default()
     }
 
-    pub fn update_host(client: &ConnectedClient) {
-        get_connections()
-            .hosts
-            .insert(client.host.clone(), client.clone());
+    pub fn remove(client: &ConnectedClient) {
+        Self::forget(client);
+        client.cancel.cancel();
     }
 
-    pub fn remove(client: &ConnectedClient) {
-        client.tx.close_channel();
+    /// The agent's connection dropped. Visitors stop reaching it at once, while the
+    /// streams it served get `disconnect_grace` to write out what it sent them before
+    /// they're closed. If it was the host's last agent, the host's new visitors are asked
+    /// to retry for `reconnect_window`, in case it's back by then.
+    pub fn disconnected(client: &ConnectedClient) {
+        let config = get_config();
+        let client = Self::current(client);
+        Self::forget(&client);
 
         let connections = get_connections();
-        // ensure another client isn't using this host
-        if connections
-            .hosts
-            .get(&client.host)
-            .map_or(false, |c| c.id == client.id)
-        {
-            tracing::debug!("dropping sub-domain: {}", &client.host);
-            connections.hosts.remove(&client.host);
+        if let Some(window) = config.reconnect_window {
+            if !connections.hosts.contains_key(&client.host) {
+                let until = Instant::now() + window;
+                connections.reconnecting.insert(client.host.clone(), until);
+            }
+        }
+
+        let streams: Vec<ActiveStream> = get_active_streams()
+            .iter()
+            .filter(|stream| stream.client.session_id == client.session_id)
+            .map(|stream| stream.value().clone())
+            .collect();
+        let Some(grace) = config.disconnect_grace.filter(|_| !streams.is_empty()) else {
+            client.cancel.cancel();
+            return;
         };
 
-        connections.clients.remove(&client.id);
+        tracing::debug!(
+            session_id=%client.session_id,
+            streams = streams.len(),
+            "agent disconnected, draining its streams"
+        );
+        let cancel = client.cancel.clone();
+        get_tasks().spawn("client_draining", client.cancel.clone(), async move {
+            // the streams end after what the agent sent them, as they would on its `End`
+            let drained = streams.iter().map(|stream| async move {
+                stream.send(StreamMessage::Close).await;
+                stream.cancel.cancelled().await;
+            });
+            if tokio::time::timeout(grace, futures::future::join_all(drained))
+                .await
+                .is_err()
+            {
+                tracing::debug!("streams of disconnected agent didn't finish in time");
+            }
+            cancel.cancel();
+        });
+    }
+
+    /// How much longer `host`'s agent may reconnect in, since its connection dropped
+    pub fn reconnecting(host: &str) -> Option<Duration> {
+        let reconnecting = &get_connections().reconnecting;
+        let until = *reconnecting.get(host)?;
+        let remaining = until.checked_duration_since(Instant::now());
+        if remaining.is_none() {
+            reconnecting.remove_if(host, |_, current| *current == until);
+        }
+        remaining
+    }
+
+    /// Stop routing to the agent and close its queues, leaving the tasks serving it to
+    /// send what's queued already, i.e. why it's being disconnected
+    pub fn forget(client: &ConnectedClient) {
+        // the clone may predate its sub-domain being transferred
+        let current = Self::current(client);
+        let client = &current;
+
+        // closes the channel for every sender, not just this clone
+        client.tx.clone().close_channel();
+        client.control.clone().close_channel();
+
+        Self::retire(client);
+        if let Some(key) = client.agent_key() {
+            get_connections()
+                .agents
+                .remove_if(&key, |_, session_id| session_id == &client.session_id);
+        }
+    }
+
+    /// Stop routing to the agent and let go of its session, leaving its queues open
+    fn retire(client: &ConnectedClient) {
+        let connections = get_connections();
+        Self::leave_host(client);
+        if let Some(host) = &client.mirror_of {
+            connections.mirrors.remove_if_mut(host, |_, mirrors| {
+                mirrors.retain(|mirror| mirror.session_id != client.session_id);
+                mirrors.is_empty()
+            });
+        }
+        tracing::debug!(
+            "dropping agent {} from sub-domain: {}",
+            &client.session_id,
+            &client.host
+        );
+
+        if connections.clients.remove(&client.session_id).is_some() {
+            get_usage().disconnected(client);
+            crate::storage::close_session(client.session_id.to_string());
+            get_webhooks().emit(Event::tunnel_closed(client));
+        }
+        get_metrics()
+            .connected_clients
+            .set(connections.clients.len() as i64);
         tracing::debug!("rm client: {}", &client.id);
 
         // // drop all the streams
@@ -72,26 +370,192 @@ impl Connections {
         // }
     }
 
+    /// Take the agent out of its host's pool, cleaning up after the host if it was the
+    /// last one serving it
+    fn leave_host(client: &ConnectedClient) {
+        let emptied = get_connections()
+            .hosts
+            .remove_if_mut(&client.host, |_, pool| {
+                pool.agents
+                    .retain(|agent| agent.session_id != client.session_id);
+                pool.agents.is_empty()
+            });
+        if emptied.is_some() {
+            get_request_log().remove(&client.host);
+            get_alerts().remove(&client.host);
+            get_tunnel_traffic().remove(&client.host);
+            crate::network::unpublish_host(client.host.clone());
+            get_host_cache().invalidate(&client.host);
+            crate::network::broadcast_invalidate(client.host.clone());
+            get_offline_queues().went_offline(&client.host);
+            get_response_cache().purge(&client.host, None);
+            get_share_links().remove(&client.host);
+        }
+    }
+
+    /// Have the agent serve `host` instead of its own, returning its new record
+    pub fn rehost(client: &ConnectedClient, host: &str) -> ConnectedClient {
+        let current = Self::current(client);
+        if current.host == host {
+            return current;
+        }
+        let mut moved = current.clone();
+        moved.host = host.to_string();
+        // in the new pool before leaving the old one, so a pong can't put it back there
+        Self::add(moved.clone());
+        Self::leave_host(&current);
+        if let (Some(old), Some(new)) = (current.agent_key(), moved.agent_key()) {
+            let agents = &get_connections().agents;
+            agents.remove_if(&old, |_, session_id| session_id == &current.session_id);
+            agents.insert(new, moved.session_id);
+        }
+        moved
+    }
+
+    /// Route to a newly connected agent. The connection it had for the same tunnel,
+    /// i.e. before a flaky reconnect, is replaced at once: visitors only reach the new
+    /// one, while the old one gets `REPLACED_GRACE` to finish its streams before it's
+    /// closed. Returns the connection replaced.
+    pub fn connect(client: ConnectedClient) -> Option<ConnectedClient> {
+        let Some(key) = client.agent_key() else {
+            Self::add(client);
+            return None;
+        };
+
+        // the entry stays locked until visitors reach the new connection rather than
+        // the old, so of two handshakes racing the later one wins
+        let mut entry = get_connections()
+            .agents
+            .entry(key)
+            .or_insert(client.session_id);
+        let previous = std::mem::replace(entry.value_mut(), client.session_id);
+        Self::add(client.clone());
+        let replaced = Self::get(&previous).filter(|_| previous != client.session_id);
+        if let Some(replaced) = &replaced {
+            Self::retire(replaced);
+        }
+        drop(entry);
+
+        let replaced = replaced?;
+        tracing::info!(
+            session_id=%replaced.session_id,
+            new_session_id=%client.session_id,
+            sub_domain=%client.host,
+            "agent reconnected, replacing its previous connection"
+        );
+        let old = replaced.clone();
+        get_tasks().spawn("client_replaced", replaced.cancel.clone(), async move {
+            tokio::time::sleep(REPLACED_GRACE).await;
+            Connections::remove(&old);
+        });
+        Some(replaced)
+    }
+
     pub fn client_for_host(host: &String) -> Option<ClientId> {
-        get_connections().hosts.get(host).map(|c| c.id.clone())
+        get_connections()
+            .hosts
+            .get(host)
+            .and_then(|pool| pool.agents.first().map(|c| c.id.clone()))
+    }
+
+    /// Every agent serving `host`
+    pub fn for_host(host: &str) -> Vec<ConnectedClient> {
+        get_connections()
+            .hosts
+            .get(host)
+            .map(|pool| pool.agents.clone())
+            .unwrap_or_default()
     }
 
-    pub fn get(client_id: &ClientId) -> Option<ConnectedClient> {
+    /// The agents receiving copies of the requests `client` serves, which must be
+    /// the same account's
+    pub fn mirrors_of(client: &ConnectedClient) -> Vec<ConnectedClient> {
+        get_connections()
+            .mirrors
+            .get(&client.host)
+            .map(|mirrors| {
+                mirrors
+                    .iter()
+                    .filter(|mirror| mirror.id == client.id)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn get(session_id: &SessionId) -> Option<ConnectedClient> {
         get_connections()
             .clients
-            .get(client_id)
+            .get(session_id)
             .map(|c| c.value().clone())
     }
 
-    pub fn find_by_host(host: &String) -> Option<ConnectedClient> {
-        get_connections().hosts.get(host).map(|c| c.value().clone())
+    /// The latest record of the agent's connection, i.e. after its sub-domain was
+    /// transferred, or the clone itself once it's gone
+    pub fn current(client: &ConnectedClient) -> ConnectedClient {
+        Self::get(&client.session_id).unwrap_or_else(|| client.clone())
+    }
+
+    /// Every agent connected to this instance
+    pub fn all() -> Vec<ConnectedClient> {
+        get_connections()
+            .clients
+            .iter()
+            .map(|c| c.value().clone())
+            .collect()
+    }
+
+    /// Pick one of the agents serving `host`, preferring the `sticky` session if it's still there
+    pub fn find_by_host(host: &String, sticky: Option<SessionId>) -> Option<ConnectedClient> {
+        let mut pool = get_connections().hosts.get_mut(host)?;
+
+        if let Some(agent) = sticky.and_then(|session_id| {
+            pool.agents
+                .iter()
+                .find(|agent| agent.session_id == session_id)
+        }) {
+            return Some(agent.clone());
+        }
+
+        pool.pick(get_config().load_balancing)
     }
 
     pub fn add(client: ConnectedClient) {
         let connections = get_connections();
         connections
             .clients
-            .insert(client.id.clone(), client.clone());
-        connections.hosts.insert(client.host.clone(), client);
+            .insert(client.session_id, client.clone());
+        get_metrics()
+            .connected_clients
+            .set(connections.clients.len() as i64);
+
+        if let Some(host) = &client.mirror_of {
+            let mut mirrors = connections.mirrors.entry(host.clone()).or_default();
+            mirrors.retain(|mirror| mirror.session_id != client.session_id);
+            mirrors.push(client.clone());
+        }
+
+        connections.reconnecting.remove(&client.host);
+        let mut pool = connections.hosts.entry(client.host.clone()).or_default();
+        if pool.agents.is_empty() {
+            crate::network::publish_host(client.host.clone(), client.id.clone());
+        }
+        match pool
+            .agents
+            .iter_mut()
+            .find(|agent| agent.session_id == client.session_id)
+        {
+            Some(agent) => *agent = client,
+            None => pool.agents.push(client),
+        }
+    }
+
+    /// Every host served on this instance, with the client of one of its agents
+    pub fn hosts() -> Vec<(String, ClientId)> {
+        get_connections()
+            .hosts
+            .iter()
+            .filter_map(|pool| Some((pool.key().clone(), pool.agents.first()?.id.clone())))
+            .collect()
     }
 }
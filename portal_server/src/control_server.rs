@@ -1,41 +1,97 @@
 pub use super::*;
 use crate::auth::reconnect_token::ReconnectTokenPayload;
 use crate::client_auth::ClientHandshake;
-use chrono::Utc;
+use crate::observability::metrics::get_metrics;
+use crate::socket::PeerAddr;
+use crate::storage::{Agent, Session};
+use crate::throttle::Throttle;
+use crate::transport::{self, AgentConnection, AgentSink, AgentStream, WebSocketTransport};
+use crate::webhooks::Event;
+use chrono::{DateTime, Utc};
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
 use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
 use tracing::{error, info, warn, Instrument};
-use warp::Rejection;
+use warp::http::StatusCode;
+use warp::path::FullPath;
+use warp::ws::WebSocket;
+use warp::{Rejection, Reply};
 
-pub fn spawn<A: Into<SocketAddr>>(addr: A) {
+/// How long an expired tunnel's agent has to leave before we close the tunnel on it
+const EXPIRED_GRACE: Duration = Duration::from_secs(5);
+
+/// Serve agents on `addr`, returning the address bound, i.e. the port picked for port 0
+pub fn spawn<A: Into<SocketAddr>>(addr: A) -> std::io::Result<SocketAddr> {
+    // spawn our websocket control server
+    let listener = crate::socket::bind(addr.into())?;
+    let addr = listener.local_addr()?;
+    get_health().set_control_listening(true);
+    let (transport, websockets) = WebSocketTransport::new();
+    tokio::spawn(transport::serve(transport));
+    tokio::spawn(crate::socket::serve(
+        listener,
+        warp::service(routes(websockets)),
+    ));
+    Ok(addr)
+}
+
+/// Serve an agent that reached us on another listener, i.e. the remote one, as if it
+/// had connected to the control server from `peer_addr`
+pub async fn serve_connection(stream: tokio::net::TcpStream, peer_addr: SocketAddr) {
+    let (transport, websockets) = WebSocketTransport::new();
+    tokio::spawn(transport::serve(transport));
+    crate::socket::serve_connection(stream, peer_addr, warp::service(routes(websockets))).await
+}
+
+/// The control server's routes, handing agents' websockets to `websockets`
+fn routes(
+    websockets: UnboundedSender<(IpAddr, WebSocket)>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
         tracing::debug!("Health Check #2 triggered");
         "ok"
     });
 
-    let client_conn = warp::path("wormhole").and(client_ip()).and(warp::ws()).map(
-        move |client_ip: IpAddr, ws: Ws| {
-            ws.on_upgrade(move |w| {
-                async move { handle_new_connection(client_ip, w).await }
-                    .instrument(observability::remote_trace("handle_websocket"))
-            })
-        },
-    );
+    // agents reaching us through the remote listener may use the configured path
+    let control_path = warp::path::full()
+        .and_then(|path: FullPath| async move {
+            match &get_config().control_path {
+                Some(control_path) if control_path == path.as_str() => Ok(()),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .untuple_one();
+    let wormhole = warp::path("wormhole").or(control_path).unify();
 
-    let routes = client_conn.or(health_check);
+    let client_conn =
+        wormhole
+            .and(client_ip())
+            .and(warp::ws())
+            .map(move |client_ip: IpAddr, ws: Ws| {
+                // agents retry until they get to an instance that stays
+                if get_health().is_draining() {
+                    return warp::reply::with_status("draining", StatusCode::SERVICE_UNAVAILABLE)
+                        .into_response();
+                }
+                let websockets = websockets.clone();
+                ws.on_upgrade(move |w| async move {
+                    let _ = websockets.send((client_ip, w));
+                })
+                .into_response()
+            });
 
-    // spawn our websocket control server
-    tokio::spawn(warp::serve(routes).run(addr.into()));
+    client_conn.or(health_check)
 }
 
 fn client_ip() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Copy {
     warp::any()
         .and(warp::header::optional("Fly-Client-IP"))
         .and(warp::header::optional("X-Forwarded-For"))
-        .and(warp::addr::remote())
+        .and(warp::ext::optional::<PeerAddr>())
         .map(
-            |client_ip: Option<String>, fwd: Option<String>, remote: Option<SocketAddr>| {
+            |client_ip: Option<String>, fwd: Option<String>, remote: Option<PeerAddr>| {
                 let client_ip = client_ip.and_then(|s| IpAddr::from_str(&s).ok());
                 let fwd = fwd.and_then(|s| {
                     s.split(',')
@@ -43,7 +99,7 @@ fn client_ip() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Copy {
                         .map(IpAddr::from_str)
                         .and_then(Result::ok)
                 });
-                let remote = remote.map(|r| r.ip());
+                let remote = remote.map(|PeerAddr(r)| r.ip());
                 client_ip
                     .or(fwd)
                     .or(remote)
@@ -52,54 +108,148 @@ fn client_ip() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Copy {
         )
 }
 
-#[tracing::instrument(skip(websocket))]
-async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
+#[tracing::instrument(skip(connection))]
+pub async fn handle_new_connection(client_ip: IpAddr, connection: AgentConnection) {
     let config = get_config();
     // check if this client is blocked
-    if config.blocked_ips.contains(&client_ip) {
+    if config.is_blocked(client_ip) {
         warn!(?client_ip, "client ip is on block list, denying connection");
-        let _ = websocket.close().await;
+        get_metrics().handshake_failed("blocked_ip");
+        get_webhooks().emit(Event::AuthFailure {
+            reason: "blocked_ip",
+            sub_domain: None,
+        });
+        connection.close().await;
         return;
     }
 
-    let (websocket, handshake) = match try_client_handshake(websocket).await {
-        Some(ws) => ws,
+    let (connection, handshake, listener) = match try_client_handshake(connection).await {
+        Some(accepted) => accepted,
         None => return,
     };
 
     info!(client_ip=%client_ip, subdomain=%handshake.sub_domain, "open tunnel");
 
-    let (tx, rx) = unbounded::<ControlPacket>();
-    let mut client = ConnectedClient {
+    let (tx, rx) = channel::<ControlPacket>(config.tunnel_queue_size);
+    let (control, control_rx) = channel::<ControlPacket>(config.tunnel_queue_size);
+    let client = ConnectedClient {
         id: handshake.id,
+        session_id: SessionId::generate(),
         host: handshake.sub_domain,
         is_anonymous: handshake.is_anonymous,
+        protocol: handshake.service.protocol,
+        queued: Arc::new(Queued {
+            tx: std::sync::Mutex::new(tx.clone()),
+            control: std::sync::Mutex::new(control.clone()),
+        }),
         tx,
+        control,
+        stream_window: handshake.stream_window,
+        throttle: handshake
+            .bandwidth_limit
+            .or(config.tier(&handshake.tier).bandwidth_limit)
+            .or(config.bandwidth_limit)
+            .map(Throttle::new),
+        tier: handshake.tier,
+        version: handshake.version,
+        name: handshake.name,
+        agent_id: handshake.agent_id,
+        connected_at: Utc::now(),
+        request_log: handshake.request_log && get_request_log().is_enabled(),
+        tail: Arc::new(AtomicBool::new(false)),
+        alerts: handshake.alerts.or(config.alerts),
+        cache: handshake.cache && handshake.service.protocol.is_http() && config.cache_size > 0,
+        mirror_of: handshake.mirror_of,
+        access_log: handshake.access_log,
+        stats: handshake.stats,
+        cors: handshake
+            .cors
+            .filter(|_| handshake.service.protocol.is_http()),
+        // without a proxy telling us, every visitor looks like it came over plain http
+        https_redirect: handshake.https_redirect
+            && handshake.service.protocol.is_http()
+            && config.tells_https(),
+        hsts: handshake.hsts && handshake.service.protocol.is_http(),
+        expires_at: handshake.expires_at,
+        cancel: get_tasks().token(),
     };
-    Connections::add(client.clone());
+    Connections::connect(client.clone());
+    crate::storage::open_session(Session {
+        session_id: client.session_id.to_string(),
+        account: client.id.to_string(),
+        sub_domain: client.host.clone(),
+        instance_id: config.instance_id.clone(),
+        connected_at: client.connected_at,
+    });
+    // which machines hold a key, anonymous agents have none
+    if let Some(agent_id) = client.agent_id.clone().filter(|_| !client.is_anonymous) {
+        crate::storage::agent_seen(Agent {
+            agent_id,
+            account: client.id.to_string(),
+            name: client.name.clone(),
+            version: client.version.clone(),
+            last_ip: Some(client_ip.to_string()),
+            first_seen: client.connected_at,
+            last_seen: client.connected_at,
+        });
+    }
+    get_webhooks().emit(Event::tunnel_opened(&client));
+    get_offline_queues().connected(&client, handshake.queue_offline);
 
-    let (sink, stream) = websocket.split();
+    let (sink, stream) = connection.split();
+
+    // a tcp tunnel's own port, given up with the agent
+    if let Some(listener) = listener {
+        get_tasks().spawn(
+            "tcp_listener",
+            client.cancel.clone(),
+            crate::service::serve_tcp(listener, client.clone())
+                .instrument(observability::remote_trace("serve_tcp")),
+        );
+    }
 
     let client_clone = client.clone();
 
-    tokio::spawn(
+    get_tasks().spawn(
+        "tunnel_client",
+        client.cancel.clone(),
         async move {
-            tunnel_client(client_clone, sink, rx).await;
+            tunnel_client(client_clone, sink, control_rx, rx).await;
         }
         .instrument(observability::remote_trace("tunnel_client")),
     );
 
     let client_clone = client.clone();
 
-    tokio::spawn(
+    get_tasks().spawn(
+        "process_client",
+        client.cancel.clone(),
         async move {
             process_client_messages(client_clone, stream).await;
         }
         .instrument(observability::remote_trace("process_client")),
     );
 
+    if client.stats {
+        get_tasks().spawn(
+            "agent_stats",
+            client.cancel.clone(),
+            crate::active_stream::send_stats(client.clone()),
+        );
+    }
+
+    if let Some(expires_at) = client.expires_at {
+        get_tasks().spawn(
+            "tunnel_expiry",
+            client.cancel.clone(),
+            expire_tunnel(client.clone(), expires_at),
+        );
+    }
+
     // play ping pong
-    tokio::spawn(
+    get_tasks().spawn(
+        "control_ping",
+        client.cancel.clone(),
         async move {
             loop {
                 tracing::trace!("sending ping");
@@ -107,9 +257,11 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
                 // create a new reconnect token for anonymous clients
                 let reconnect_token = if client.is_anonymous {
                     ReconnectTokenPayload {
-                        sub_domain: client.host.clone(),
+                        // where an admin may have moved the tunnel since
+                        sub_domain: Connections::current(&client).host,
                         client_id: client.id.clone(),
                         expires: Utc::now() + chrono::Duration::minutes(2),
+                        tunnel_expires: client.expires_at,
                     }
                     .to_token(&config.master_sig_key)
                     .map_err(|e| error!("unable to create reconnect token: {:?}", e))
@@ -118,7 +270,12 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
                     None
                 };
 
-                match client.tx.send(ControlPacket::Ping(reconnect_token)).await {
+                match client
+                    .control
+                    .clone()
+                    .send(ControlPacket::Ping(reconnect_token))
+                    .await
+                {
                     Ok(_) => {}
                     Err(e) => {
                         tracing::debug!("Failed to send ping: {:?}, removing client", e);
@@ -134,10 +291,205 @@ async fn handle_new_connection(client_ip: IpAddr, websocket: WebSocket) {
     );
 }
 
-#[tracing::instrument(skip(websocket))]
-async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, ClientHandshake)> {
+/// Close the tunnel once it expires, telling its agent not to reconnect
+async fn expire_tunnel(client: ConnectedClient, expires_at: DateTime<Utc>) {
+    let remaining = (expires_at - Utc::now()).to_std().unwrap_or_default();
+    tokio::time::sleep(remaining).await;
+
+    info!(subdomain=%client.host, "tunnel expired, closing it");
+    let _ = client.control.clone().send(ControlPacket::Expired).await;
+    // the agent leaves on its own, unless it doesn't listen
+    tokio::time::sleep(EXPIRED_GRACE).await;
+    Connections::remove(&client);
+}
+
+/// When the tunnel is closed: once the lifetime its agent asked for is over, at most its
+/// tier's or the server's, and never later than before the agent reconnected
+fn tunnel_expiry(handshake: &ClientHandshake) -> Option<DateTime<Utc>> {
+    let config = get_config();
+    let limit = config
+        .tier(&handshake.tier)
+        .max_tunnel_lifetime
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+        .or(config.max_tunnel_lifetime);
+    let lifetime = handshake
+        .ttl
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+        .into_iter()
+        .chain(limit)
+        .min();
+    let expires_at = lifetime
+        .and_then(|lifetime| chrono::Duration::from_std(lifetime).ok())
+        .map(|lifetime| Utc::now() + lifetime);
+    expires_at.into_iter().chain(handshake.expires_at).min()
+}
+
+/// The refusal of an agent older than `minimum_agent_version`. Agents that don't say
+/// their version predate saying it, so they're too old as well.
+fn version_too_old(version: Option<&str>) -> Option<ServerHello> {
+    let config = get_config();
+    let minimum = config.minimum_agent_version.as_ref()?;
+    let parsed = version.and_then(|version| semver::Version::parse(version).ok());
+    if parsed.is_some_and(|parsed| &parsed >= minimum) {
+        return None;
+    }
+    warn!(?version, %minimum, "agent is too old, turning it away");
+    Some(ServerHello::VersionTooOld {
+        minimum: minimum.to_string(),
+        download_url: config.agent_download_url.clone(),
+    })
+}
+
+/// A redirect to the instance or the region the agent prefers, when it isn't us and
+/// we know how to reach it
+fn preferred_elsewhere(handshake: &ClientHandshake) -> Option<ServerHello> {
+    if !handshake.accepts_redirect || handshake.redirected {
+        return None;
+    }
+    let config = get_config();
+
+    if let Some(instance_id) = &handshake.preferred_instance {
+        let known = get_host_registry()
+            .members()
+            .is_some_and(|members| members.iter().any(|member| &member.id == instance_id));
+        if instance_id != &config.instance_id && known {
+            info!(subdomain=%handshake.sub_domain, instance=%instance_id, "redirecting agent to its preferred instance");
+            return Some(ServerHello::Redirect {
+                instance_id: instance_id.clone(),
+                address: None,
+            });
+        }
+    }
+
+    let region = handshake.preferred_region.as_ref()?;
+    if config.region.as_ref() == Some(region) {
+        return None;
+    }
+    let address = config.regions.get(region)?;
+    info!(subdomain=%handshake.sub_domain, %region, %address, "redirecting agent to its preferred region");
+    Some(ServerHello::Redirect {
+        instance_id: String::new(),
+        address: Some(address.clone()),
+    })
+}
+
+#[tracing::instrument(skip(connection))]
+async fn try_client_handshake(
+    connection: AgentConnection,
+) -> Option<(AgentConnection, ClientHandshake, Option<TcpListener>)> {
     // Authenticate client handshake
-    let (mut websocket, client_handshake) = client_auth::auth_client_handshake(websocket).await?;
+    let Some((mut connection, mut client_handshake)) =
+        client_auth::auth_client_handshake(connection).await
+    else {
+        get_metrics().handshake_failed("auth");
+        return None;
+    };
+
+    // agents too old to be let in are told where to get a newer one
+    if let Some(refused) = version_too_old(client_handshake.version.as_deref()) {
+        get_metrics().handshake_failed("version");
+        let data = serde_json::to_vec(&refused).unwrap_or_default();
+        let _ = connection.send(data).await;
+        return None;
+    }
+
+    // send the agent where it would rather be, if we know that place
+    if let Some(redirect) = preferred_elsewhere(&client_handshake) {
+        let data = serde_json::to_vec(&redirect).unwrap_or_default();
+        let _ = connection.send(data).await;
+        return None;
+    }
+
+    // send the agent to the instance its subdomain is placed on
+    if client_handshake.accepts_redirect && !client_handshake.redirected {
+        let placed = network::placed_instance(&client_handshake.sub_domain)
+            .filter(|owner| owner.id != get_config().instance_id);
+        if let Some(owner) = placed {
+            info!(subdomain=%client_handshake.sub_domain, instance=%owner.id, "redirecting agent");
+            let data = serde_json::to_vec(&ServerHello::Redirect {
+                instance_id: owner.id,
+                address: None,
+            })
+            .unwrap_or_default();
+            let _ = connection.send(data).await;
+            return None;
+        }
+    }
+
+    // the account's tier may limit how many tunnels it has open
+    if let Some(max_tunnels) = get_config().tier(&client_handshake.tier).max_tunnels {
+        // not counting the connection a reconnecting agent replaces
+        let open = Connections::all()
+            .iter()
+            .filter(|client| client.id == client_handshake.id)
+            .filter(|client| {
+                !client.is_replaced_by(
+                    &client_handshake.id,
+                    client_handshake.agent_id.as_deref(),
+                    &client_handshake.sub_domain,
+                )
+            })
+            .count();
+        if open >= max_tunnels {
+            warn!(client_id=%client_handshake.id, open, "account has too many tunnels open");
+            get_metrics().handshake_failed("max_tunnels");
+            get_webhooks().emit(Event::QuotaExceeded {
+                sub_domain: client_handshake.sub_domain.clone(),
+                quota: "max_tunnels",
+                limit: max_tunnels as u64,
+            });
+            let data = serde_json::to_vec(&ServerHello::Error(format!(
+                "You reached your plan's limit of open tunnels ({}).",
+                max_tunnels
+            )))
+            .unwrap_or_default();
+            let _ = connection.send(data).await;
+            return None;
+        }
+    }
+
+    // only the account's own tunnels may be mirrored, over http
+    if let Some(mirror_of) = &client_handshake.mirror_of {
+        let refused = if client_handshake.is_anonymous {
+            Some("Mirroring a tunnel takes an authentication key.")
+        } else if !client_handshake.service.protocol.is_http() {
+            Some("Only http tunnels can mirror another.")
+        } else if mirror_of == &client_handshake.sub_domain {
+            Some("A tunnel can't mirror itself.")
+        } else {
+            None
+        };
+        if let Some(reason) = refused {
+            warn!(subdomain=%client_handshake.sub_domain, %mirror_of, reason, "refusing mirror");
+            get_metrics().handshake_failed("mirror");
+            let data =
+                serde_json::to_vec(&ServerHello::Error(reason.to_string())).unwrap_or_default();
+            let _ = connection.send(data).await;
+            return None;
+        }
+    }
+
+    // the listener visitors reach the tunnel on
+    let (endpoint, listener) =
+        match crate::service::open(&client_handshake.service, &client_handshake.sub_domain).await {
+            Ok(opened) => opened,
+            Err(reason) => {
+                warn!(subdomain=%client_handshake.sub_domain, %reason, "refusing tunnel");
+                get_metrics().handshake_failed("protocol");
+                let data = serde_json::to_vec(&ServerHello::Error(reason)).unwrap_or_default();
+                let _ = connection.send(data).await;
+                return None;
+            }
+        };
+
+    client_handshake.expires_at = tunnel_expiry(&client_handshake);
+    // the window is the stream queue's size when the agent connected, so a reload
+    // changing it doesn't let the agent's streams overrun their queues
+    client_handshake.stream_window = client_handshake
+        .flow_control
+        .then(|| get_config().stream_queue_size);
 
     // Send server hello success
     let data = serde_json::to_vec(&ServerHello::Success {
@@ -148,12 +500,22 @@ async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, Client
             get_config().portal_host
         ),
         client_id: client_handshake.id.clone(),
+        request_log: client_handshake.request_log && get_request_log().is_enabled(),
+        endpoint: Some(endpoint),
+        expires_in: client_handshake
+            .expires_at
+            .map(|expires_at| (expires_at - Utc::now()).num_seconds().max(0) as u64),
+        update_available: get_agent_updates().for_agent(client_handshake.version.as_deref()),
+        stream_window: client_handshake
+            .stream_window
+            .map(|window| window.try_into().unwrap_or(u32::MAX)),
     })
     .unwrap_or_default();
 
-    let send_result = websocket.send(Message::binary(data)).await;
+    let send_result = connection.send(data).await;
     if let Err(error) = send_result {
         error!(?error, "aborting...failed to write server hello");
+        get_metrics().handshake_failed("server_hello");
         return None;
     }
 
@@ -166,7 +528,7 @@ async fn try_client_handshake(websocket: WebSocket) -> Option<(WebSocket, Client
             ""
         }
     );
-    Some((websocket, client_handshake))
+    Some((connection, client_handshake, listener))
 }
 
 /// Send the client a "stream init" message
@@ -189,29 +551,15 @@ pub async fn send_client_stream_init(mut stream: ActiveStream) {
 
 /// Process client control messages
 #[tracing::instrument(skip(client_conn))]
-async fn process_client_messages(client: ConnectedClient, mut client_conn: SplitStream<WebSocket>) {
+async fn process_client_messages(client: ConnectedClient, mut client_conn: AgentStream) {
     loop {
-        let result = client_conn.next().await;
-
-        let message = match result {
-            // handle protocol message
-            Some(Ok(msg)) if (msg.is_binary() || msg.is_text()) && !msg.as_bytes().is_empty() => {
-                msg.into_bytes()
-            }
-            // handle close with reason
-            Some(Ok(msg)) if msg.is_close() && !msg.as_bytes().is_empty() => {
-                tracing::debug!(close_reason=?msg, "got close");
-                Connections::remove(&client);
-                return;
-            }
-            _ => {
-                tracing::debug!(?client.id, "goodbye client");
-                Connections::remove(&client);
-                return;
-            }
+        let Some(message) = client_conn.next().await else {
+            tracing::debug!(?client.id, "goodbye client");
+            Connections::disconnected(&client);
+            return;
         };
 
-        let packet = match ControlPacket::deserialize(&message) {
+        let packet = match ControlPacket::deserialize(message) {
             Ok(packet) => packet,
             Err(error) => {
                 error!(?error, "invalid data packet");
@@ -228,13 +576,83 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
                 tracing::debug!("tunnel says: refused");
                 (stream_id, StreamMessage::TunnelRefused)
             }
-            ControlPacket::Init(_) | ControlPacket::End(_) => {
+            ControlPacket::End(stream_id) => {
+                tracing::debug!(?stream_id, "tunnel says: end stream");
+                (stream_id, StreamMessage::Close)
+            }
+            ControlPacket::Init(_) => {
                 error!("invalid protocol control::init message");
                 continue;
             }
+            ControlPacket::Request(_, _) => {
+                error!("invalid protocol control::request message");
+                continue;
+            }
+            ControlPacket::Drain => {
+                error!("invalid protocol control::drain message");
+                continue;
+            }
+            ControlPacket::Stats(_) => {
+                error!("invalid protocol control::stats message");
+                continue;
+            }
+            ControlPacket::Expired => {
+                error!("invalid protocol control::expired message");
+                continue;
+            }
+            ControlPacket::ShareLink(_) => {
+                error!("invalid protocol control::share_link message");
+                continue;
+            }
+            ControlPacket::Disconnected(_) => {
+                error!("invalid protocol control::disconnected message");
+                continue;
+            }
+            ControlPacket::Transferred(_) => {
+                error!("invalid protocol control::transferred message");
+                continue;
+            }
+            ControlPacket::Notice { .. } => {
+                error!("invalid protocol control::notice message");
+                continue;
+            }
+            ControlPacket::UpdateAvailable(_) => {
+                error!("invalid protocol control::update_available message");
+                continue;
+            }
+            ControlPacket::Credit(_, _) => {
+                error!("invalid protocol control::credit message");
+                continue;
+            }
+            ControlPacket::Share(request) => {
+                let link = crate::share::share(&Connections::current(&client), &request);
+                let _ = client
+                    .control
+                    .clone()
+                    .send(ControlPacket::ShareLink(link))
+                    .await;
+                continue;
+            }
+            ControlPacket::Replay(id) => {
+                let tunnel = Connections::current(&client).host;
+                get_tasks().spawn("replay", client.cancel.clone(), async move {
+                    if let Err(error) = crate::replay::replay(&tunnel, &id).await {
+                        warn!(%tunnel, %id, %error, "agent requested replay failed");
+                    }
+                });
+                continue;
+            }
+            ControlPacket::Tail(tail) => {
+                tracing::debug!(tunnel=%client.host, tail, "agent tail");
+                client.set_tailing(tail);
+                continue;
+            }
             ControlPacket::Ping(_) => {
                 tracing::trace!("pong");
-                Connections::add(client.clone());
+                // unless it was disconnected meanwhile
+                if let Some(client) = Connections::get(&client.session_id) {
+                    Connections::add(client);
+                }
                 continue;
             }
         };
@@ -243,10 +661,22 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
             .get(&stream_id)
             .map(|s| s.value().clone());
 
-        if let Some(mut stream) = stream {
-            let _ = stream.tx.send(message).await.map_err(|error| {
-                tracing::trace!(?error, "Failed to send to stream tx");
-            });
+        let Some(stream) = stream else {
+            continue;
+        };
+        stream.touch();
+        // an agent that flow controls sends no more than the stream's queue holds, so
+        // we needn't wait on a visitor reading slowly and hold up the tunnel's other
+        // streams meanwhile. One that overruns the queue loses the stream. Others send
+        // on regardless, and the tunnel waits for the room.
+        if client.flow_controls() {
+            if !stream.queue(message) {
+                warn!(tunnel=%client.host, ?stream_id, "agent overran the stream's window, closing it");
+                get_metrics().routing_error("stream_overrun");
+                stream.close();
+            }
+        } else {
+            stream.send(message).await;
         }
     }
 }
@@ -254,21 +684,30 @@ async fn process_client_messages(client: ConnectedClient, mut client_conn: Split
 #[tracing::instrument(skip(sink, queue))]
 async fn tunnel_client(
     client: ConnectedClient,
-    mut sink: SplitSink<WebSocket, Message>,
-    mut queue: UnboundedReceiver<ControlPacket>,
+    mut sink: AgentSink,
+    mut control: Receiver<ControlPacket>,
+    mut queue: Receiver<ControlPacket>,
 ) {
     loop {
-        match queue.next().await {
+        // control packets first, so pings don't wait behind a backlog of data
+        let packet = tokio::select! {
+            biased;
+            packet = control.next() => packet,
+            packet = queue.next() => packet,
+        };
+        match packet {
             Some(packet) => {
-                let result = sink.send(Message::binary(packet.serialize())).await;
+                let result = sink.send(packet).await;
                 if let Err(error) = result {
                     tracing::trace!(?error, "client disconnected: aborting.");
-                    Connections::remove(&client);
+                    Connections::disconnected(&client);
                     return;
                 }
             }
             None => {
                 tracing::debug!("ending client tunnel");
+                // hang up on the agent, i.e. when an admin disconnected it
+                sink.close().await;
                 return;
             }
         };
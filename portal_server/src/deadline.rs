@@ -0,0 +1,60 @@
+//! The time a visitor's request gets to be answered in, spent across finding the
+//! tunnel, hopping to the instance serving it and waiting on its agent.
+use crate::get_config;
+use std::future::Future;
+use tokio::time::Instant;
+
+/// When we give up on answering a request, if ever
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(Option<Instant>);
+
+/// The deadline passed before the future completed
+#[derive(Debug)]
+pub struct Expired;
+
+impl Deadline {
+    /// Start the budget of a request arriving now
+    pub fn start() -> Self {
+        Deadline(
+            get_config()
+                .request_timeout
+                .map(|timeout| Instant::now() + timeout),
+        )
+    }
+
+    /// A deadline that never passes
+    pub fn none() -> Self {
+        Deadline(None)
+    }
+
+    /// Run `future` with what's left of the budget
+    pub async fn run<F: Future>(self, future: F) -> Result<F::Output, Expired> {
+        match self.0 {
+            Some(deadline) => tokio::time::timeout_at(deadline, future)
+                .await
+                .map_err(|_| Expired),
+            None => Ok(future.await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_budget_is_shared() {
+        let deadline = Deadline(Some(Instant::now() + Duration::from_millis(100)));
+
+        let lookup = tokio::time::sleep(Duration::from_millis(60));
+        assert!(deadline.run(lookup).await.is_ok());
+
+        // what the lookup took is gone for the agent
+        let agent = tokio::time::sleep(Duration::from_millis(60));
+        assert!(deadline.run(agent).await.is_err());
+
+        let slow = tokio::time::sleep(Duration::from_millis(150));
+        assert!(Deadline::none().run(slow).await.is_ok());
+    }
+}
@@ -0,0 +1,122 @@
+//! Records in a zone on Cloudflare, with an API token allowed to edit its DNS
+use super::{DnsProvider, DnsRecord, Error, RecordType};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+const API_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// Every answer of the API, whether it worked or not
+#[derive(Debug, Deserialize)]
+struct Envelope<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ApiError>,
+    result: Option<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    code: u32,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Record {
+    id: String,
+    name: String,
+    content: String,
+    ttl: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct NewRecord<'a> {
+    #[serde(rename = "type")]
+    kind: RecordType,
+    name: &'a str,
+    content: &'a str,
+    ttl: u32,
+    /// visitors reach us directly, Cloudflare's proxy would end their TLS
+    proxied: bool,
+}
+
+pub struct Cloudflare {
+    client: reqwest::Client,
+    zone_id: String,
+    token: String,
+}
+
+impl Cloudflare {
+    pub fn new(zone_id: &str, token: &str) -> Self {
+        Cloudflare {
+            client: reqwest::Client::new(),
+            zone_id: zone_id.to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(
+                method,
+                format!("{}/zones/{}/dns_records{}", API_URL, self.zone_id, path),
+            )
+            .bearer_auth(&self.token)
+    }
+
+    /// The result of a request, or the errors the API answered with instead
+    async fn send<T: DeserializeOwned>(request: reqwest::RequestBuilder) -> Result<T, Error> {
+        let envelope: Envelope<T> = request.send().await?.json().await?;
+        match envelope.result {
+            Some(result) if envelope.success => Ok(result),
+            _ => {
+                let errors: Vec<_> = envelope
+                    .errors
+                    .iter()
+                    .map(|error| format!("{} ({})", error.message, error.code))
+                    .collect();
+                Err(Error::Api(errors.join(", ")))
+            }
+        }
+    }
+
+    async fn find(&self, name: &str, kind: RecordType) -> Result<Vec<Record>, Error> {
+        let request = self
+            .request(reqwest::Method::GET, "")
+            .query(&[("type", kind.to_string().as_str()), ("name", name)]);
+        Self::send(request).await
+    }
+}
+
+#[async_trait]
+impl DnsProvider for Cloudflare {
+    async fn records(&self, name: &str, kind: RecordType) -> Result<Vec<DnsRecord>, Error> {
+        Ok(self
+            .find(name, kind)
+            .await?
+            .into_iter()
+            .map(|record| DnsRecord {
+                name: record.name,
+                kind,
+                content: record.content,
+                ttl: record.ttl,
+            })
+            .collect())
+    }
+
+    async fn upsert(&self, record: &DnsRecord) -> Result<(), Error> {
+        let body = NewRecord {
+            kind: record.kind,
+            name: &record.name,
+            content: &record.content,
+            ttl: record.ttl,
+            proxied: false,
+        };
+        let request = match self.find(&record.name, record.kind).await?.first() {
+            Some(existing) => self.request(reqwest::Method::PUT, &format!("/{}", existing.id)),
+            None => self.request(reqwest::Method::POST, ""),
+        };
+        Self::send::<serde_json::Value>(request.json(&body)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! Point names at us with the DNS provider hosting our zone: the wildcard of every
+//! allowed host once it's configured, and custom domains added through the admin API
+use crate::config::Config;
+use crate::{get_config, get_dns_provider};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+mod cloudflare;
+mod route53;
+
+pub use self::cloudflare::Cloudflare;
+pub use self::route53::Route53;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("RequestError: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("the dns provider refused: {0}")]
+    Api(String),
+
+    #[error("{0} doesn't point at {1} after updating it")]
+    NotVerified(String, String),
+}
+
+/// Which API manages our zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsProviderKind {
+    Cloudflare,
+    Route53,
+}
+
+impl FromStr for DnsProviderKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cloudflare" => Ok(DnsProviderKind::Cloudflare),
+            "route53" => Ok(DnsProviderKind::Route53),
+            other => Err(format!("unknown dns provider: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Cname,
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordType::A => f.write_str("A"),
+            RecordType::Aaaa => f.write_str("AAAA"),
+            RecordType::Cname => f.write_str("CNAME"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsRecord {
+    /// without the trailing dot, i.e. `*.portal.example.com`
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: RecordType,
+    pub content: String,
+    pub ttl: u32,
+}
+
+impl DnsRecord {
+    /// A record pointing `name` at `target`, an address or else a host to alias
+    pub fn pointing(name: &str, target: &str, ttl: u32) -> Self {
+        let kind = match target.parse() {
+            Ok(IpAddr::V4(_)) => RecordType::A,
+            Ok(IpAddr::V6(_)) => RecordType::Aaaa,
+            Err(_) => RecordType::Cname,
+        };
+        DnsRecord {
+            name: normalize(name),
+            kind,
+            content: normalize(target),
+            ttl,
+        }
+    }
+
+    fn points_at(&self, content: &str) -> bool {
+        normalize(content) == self.content
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Manages the records of our zone
+#[async_trait]
+pub trait DnsProvider: Send + Sync {
+    /// The records of `name` of this type
+    async fn records(&self, name: &str, kind: RecordType) -> Result<Vec<DnsRecord>, Error>;
+
+    /// Create `record`, or replace the ones of its name and type
+    async fn upsert(&self, record: &DnsRecord) -> Result<(), Error>;
+}
+
+/// The provider the config names, if any
+pub fn open(config: &Config) -> Option<Box<dyn DnsProvider>> {
+    let zone_id = config.dns_zone_id.clone().unwrap_or_default();
+    match config.dns_provider? {
+        DnsProviderKind::Cloudflare => Some(Box::new(Cloudflare::new(
+            &zone_id,
+            config.dns_api_token.as_deref().unwrap_or_default(),
+        ))),
+        DnsProviderKind::Route53 => Some(Box::new(Route53::new(
+            &zone_id,
+            config.dns_access_key_id.as_deref().unwrap_or_default(),
+            config.dns_secret_access_key.as_deref().unwrap_or_default(),
+        ))),
+    }
+}
+
+/// What our records point at: `dns_target`, or else our `instance_ip`
+pub fn target(config: &Config) -> Option<String> {
+    config
+        .dns_target
+        .clone()
+        .or_else(|| config.instance_ip.map(|ip| ip.to_string()))
+}
+
+/// The records every allowed host needs, for itself and its sub-domains
+pub fn host_records(config: &Config) -> Vec<DnsRecord> {
+    let Some(target) = target(config) else {
+        return vec![];
+    };
+    config
+        .allowed_hosts
+        .iter()
+        .filter(|host| host.contains('.'))
+        .flat_map(|host| {
+            [
+                DnsRecord::pointing(host, &target, config.dns_ttl),
+                DnsRecord::pointing(&format!("*.{}", host), &target, config.dns_ttl),
+            ]
+        })
+        .collect()
+}
+
+/// Whether the zone already points `record`'s name at its content
+pub async fn verify(provider: &dyn DnsProvider, record: &DnsRecord) -> Result<bool, Error> {
+    let records = provider.records(&record.name, record.kind).await?;
+    Ok(records.iter().any(|found| record.points_at(&found.content)))
+}
+
+/// Point `record`'s name at its content unless it already is, returning whether we
+/// changed the zone
+pub async fn ensure(provider: &dyn DnsProvider, record: &DnsRecord) -> Result<bool, Error> {
+    if verify(provider, record).await? {
+        return Ok(false);
+    }
+    provider.upsert(record).await?;
+    if !verify(provider, record).await? {
+        return Err(Error::NotVerified(
+            record.name.clone(),
+            record.content.clone(),
+        ));
+    }
+    Ok(true)
+}
+
+/// Create the records of the allowed hosts that are missing, i.e. at startup and after
+/// a reload added one
+pub fn spawn_provision() {
+    let Some(provider) = get_dns_provider() else {
+        return;
+    };
+    let records = host_records(&get_config());
+    tokio::spawn(async move {
+        for record in records {
+            match ensure(provider, &record).await {
+                Ok(true) => {
+                    tracing::info!(name=%record.name, target=%record.content, "created dns record")
+                }
+                Ok(false) => tracing::debug!(name=%record.name, "dns record is up to date"),
+                Err(error) => {
+                    tracing::error!(%error, name=%record.name, "failed to create dns record")
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_type_follows_target() {
+        let record = DnsRecord::pointing("*.Portal.example.com.", "203.0.113.7", 300);
+        assert_eq!(record.name, "*.portal.example.com");
+        assert_eq!(record.kind, RecordType::A);
+        assert_eq!(
+            DnsRecord::pointing("a.example.com", "2001:db8::1", 300).kind,
+            RecordType::Aaaa
+        );
+
+        let alias = DnsRecord::pointing("a.example.com", "lb.example.net.", 300);
+        assert_eq!(alias.kind, RecordType::Cname);
+        assert!(alias.points_at("LB.example.net."));
+        assert!(!alias.points_at("other.example.net"));
+    }
+}
@@ -0,0 +1,246 @@
+//! Records in a hosted zone on Route 53, with access keys allowed to change its record sets
+use super::{DnsProvider, DnsRecord, Error, RecordType};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+const HOST: &str = "route53.amazonaws.com";
+/// Route 53 is global, but requests are signed for this region
+const REGION: &str = "us-east-1";
+const SERVICE: &str = "route53";
+
+pub struct Route53 {
+    client: reqwest::Client,
+    zone_id: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+impl Route53 {
+    pub fn new(zone_id: &str, access_key_id: &str, secret_access_key: &str) -> Self {
+        Route53 {
+            client: reqwest::Client::new(),
+            // the console shows ids as `/hostedzone/<id>`
+            zone_id: zone_id.trim_start_matches("/hostedzone/").to_string(),
+            access_key_id: access_key_id.to_string(),
+            secret_access_key: secret_access_key.to_string(),
+        }
+    }
+
+    fn path(&self) -> String {
+        format!("/2013-04-01/hostedzone/{}/rrset", self.zone_id)
+    }
+
+    /// Send a request signed with AWS Signature Version 4, returning the body of the
+    /// answer or the error message in it
+    async fn send(
+        &self,
+        method: reqwest::Method,
+        query: &str,
+        body: String,
+    ) -> Result<String, Error> {
+        let path = self.path();
+        let now = Utc::now();
+        let authorization = self.authorization(method.as_str(), &path, query, &body, now);
+        let mut url = format!("https://{}{}", HOST, path);
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+        let response = self
+            .client
+            .request(method, url)
+            .header("x-amz-date", amz_date(now))
+            .header(reqwest::header::AUTHORIZATION, authorization)
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            let message = tags(&text, "Message").first().copied().unwrap_or(&text);
+            return Err(Error::Api(format!("{}: {}", status, message)));
+        }
+        Ok(text)
+    }
+
+    fn authorization(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        body: &str,
+        now: DateTime<Utc>,
+    ) -> String {
+        let amz_date = amz_date(now);
+        let date = now.format("%Y%m%d").to_string();
+        let signed_headers = "host;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-date:{}\n\n{}\n{}",
+            method,
+            path,
+            query,
+            HOST,
+            amz_date,
+            signed_headers,
+            hex::encode(Sha256::digest(body))
+        );
+        let scope = format!("{}/{}/{}/aws4_request", date, REGION, SERVICE);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(&canonical_request))
+        );
+
+        let key = format!("AWS4{}", self.secret_access_key);
+        let key = hmac_sha256::HMAC::mac(date.as_bytes(), key.as_bytes());
+        let key = hmac_sha256::HMAC::mac(REGION.as_bytes(), key);
+        let key = hmac_sha256::HMAC::mac(SERVICE.as_bytes(), key);
+        let key = hmac_sha256::HMAC::mac(b"aws4_request", key);
+        let signature = hmac_sha256::HMAC::mac(string_to_sign.as_bytes(), key);
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id,
+            scope,
+            signed_headers,
+            hex::encode(signature)
+        )
+    }
+}
+
+fn amz_date(now: DateTime<Utc>) -> String {
+    now.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Percent-encode all but the characters AWS leaves as they are
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// The text inside every `<tag>` of `xml`, which is all we need of Route 53's answers
+fn tags<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        found.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    found
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Route 53 answers with names ending in a dot and `*` as `\052`
+fn unescape_name(name: &str) -> String {
+    name.replace("\\052", "*")
+        .trim_end_matches('.')
+        .to_lowercase()
+}
+
+/// The records of a `ListResourceRecordSets` answer named `name`, of this type
+fn parse_records(xml: &str, name: &str, kind: RecordType) -> Vec<DnsRecord> {
+    let kind_name = kind.to_string();
+    tags(xml, "ResourceRecordSet")
+        .into_iter()
+        .filter(|set| {
+            tags(set, "Name").first().map(|found| unescape_name(found)) == Some(name.to_string())
+                && tags(set, "Type").first() == Some(&kind_name.as_str())
+        })
+        .flat_map(|set| {
+            let ttl = tags(set, "TTL")
+                .first()
+                .and_then(|ttl| ttl.parse().ok())
+                .unwrap_or_default();
+            tags(set, "Value").into_iter().map(move |value| DnsRecord {
+                name: name.to_string(),
+                kind,
+                content: value.to_string(),
+                ttl,
+            })
+        })
+        .collect()
+}
+
+#[async_trait]
+impl DnsProvider for Route53 {
+    async fn records(&self, name: &str, kind: RecordType) -> Result<Vec<DnsRecord>, Error> {
+        // sorted by key, as the signature needs it
+        let query = format!(
+            "maxitems=1&name={}&type={}",
+            uri_encode(name),
+            uri_encode(&kind.to_string())
+        );
+        let xml = self
+            .send(reqwest::Method::GET, &query, String::new())
+            .await?;
+        Ok(parse_records(&xml, &name.to_lowercase(), kind))
+    }
+
+    async fn upsert(&self, record: &DnsRecord) -> Result<(), Error> {
+        let body = format!(
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<ChangeResourceRecordSetsRequest xmlns="https://route53.amazonaws.com/doc/2013-04-01/">"#,
+                "<ChangeBatch><Changes><Change><Action>UPSERT</Action><ResourceRecordSet>",
+                "<Name>{}</Name><Type>{}</Type><TTL>{}</TTL>",
+                "<ResourceRecords><ResourceRecord><Value>{}</Value></ResourceRecord></ResourceRecords>",
+                "</ResourceRecordSet></Change></Changes></ChangeBatch>",
+                "</ChangeResourceRecordSetsRequest>"
+            ),
+            escape(&record.name),
+            record.kind,
+            record.ttl,
+            escape(&record.content)
+        );
+        self.send(reqwest::Method::POST, "", body).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_records() {
+        let xml = r#"<ListResourceRecordSetsResponse><ResourceRecordSets>
+            <ResourceRecordSet><Name>\052.portal.example.com.</Name><Type>A</Type><TTL>300</TTL>
+            <ResourceRecords><ResourceRecord><Value>203.0.113.7</Value></ResourceRecord></ResourceRecords>
+            </ResourceRecordSet>
+            <ResourceRecordSet><Name>portal.example.com.</Name><Type>A</Type><TTL>60</TTL>
+            <ResourceRecords><ResourceRecord><Value>203.0.113.8</Value></ResourceRecord></ResourceRecords>
+            </ResourceRecordSet>
+            </ResourceRecordSets></ListResourceRecordSetsResponse>"#;
+
+        let records = parse_records(xml, "*.portal.example.com", RecordType::A);
+        assert_eq!(
+            records,
+            vec![DnsRecord {
+                name: "*.portal.example.com".to_string(),
+                kind: RecordType::A,
+                content: "203.0.113.7".to_string(),
+                ttl: 300,
+            }]
+        );
+        assert!(parse_records(xml, "*.portal.example.com", RecordType::Cname).is_empty());
+        assert_eq!(uri_encode("*.a b"), "%2A.a%20b");
+    }
+}
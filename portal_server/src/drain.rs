@@ -0,0 +1,73 @@
+//! Leave the cluster without dropping visitors, i.e. for a deploy
+use crate::connected_clients::Connections;
+use crate::{
+    get_active_streams, get_config, get_health, get_tasks, get_usage, network, ControlPacket,
+};
+use std::time::{Duration, Instant};
+
+/// How often we check whether our streams are done
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long the tasks left after the drain get to wind down once cancelled
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// Drain and exit once we're asked to terminate
+pub fn spawn_signal_handler() {
+    tokio::spawn(async {
+        if let Err(error) = terminated().await {
+            tracing::error!(%error, "failed to listen for shutdown signals");
+            return;
+        }
+        drain().await;
+        std::process::exit(0);
+    });
+}
+
+/// Resolves on SIGTERM or SIGINT
+#[cfg(unix)]
+async fn terminated() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, the one shutdown signal there is everywhere
+#[cfg(not(unix))]
+async fn terminated() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// Stop taking new agents, send the ones we have to other instances,
+/// and wait for the streams in flight until the drain timeout
+pub async fn drain() {
+    tracing::info!("draining");
+    get_health().set_draining(true);
+
+    // other instances stop routing to us, and the agents find them
+    for (host, _) in Connections::hosts() {
+        network::unpublish_host(host.clone());
+        network::broadcast_invalidate(host);
+    }
+    for client in Connections::all() {
+        client.queue(ControlPacket::Drain);
+    }
+
+    let deadline = Instant::now() + get_config().drain_timeout;
+    while !get_active_streams().is_empty() && Instant::now() < deadline {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    let remaining = get_active_streams().len();
+    if remaining > 0 {
+        tracing::warn!(remaining, "drain timed out, dropping streams");
+    }
+    get_tasks().shutdown(SHUTDOWN_GRACE).await;
+    get_usage().flush().await;
+    tracing::info!("drained");
+}
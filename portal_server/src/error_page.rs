@@ -0,0 +1,199 @@
+use crate::get_config;
+use crate::http;
+use std::collections::HashMap;
+use std::path::Path;
+
+const DEFAULT_TEMPLATE: &str = include_str!("../templates/error.html");
+
+/// The errors we answer visitors with instead of proxying their request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPage {
+    /// The host header doesn't belong to any of our tunnel domains
+    InvalidHost,
+    /// The request couldn't be parsed
+    InvalidRequest,
+    /// The request head didn't arrive in time
+    RequestTimeout,
+    /// The request body is over the configured limit
+    PayloadTooLarge,
+    /// The request head is over the configured limit
+    HeadersTooLarge,
+    /// No agent serves this sub-domain
+    TunnelNotFound,
+    /// The agent serving this sub-domain disconnected
+    TunnelOffline,
+    /// The agent serving this sub-domain just disconnected, and may be back in the
+    /// seconds visitors are asked to retry after
+    TunnelReconnecting { retry_after: u64 },
+    /// The agent couldn't reach its local service
+    TunnelRefused,
+    /// The agent's local service answered with a response we couldn't read
+    InvalidResponse,
+    /// We failed to ask the other instances who serves this host
+    ErrorLocatingTunnel,
+    /// We failed to relay the stream to the instance serving this host
+    ErrorProxyingTunnel,
+    /// No response to the request arrived within the request timeout
+    GatewayTimeout,
+    /// The share link expired or was used by as many visitors as it allows
+    ShareLinkGone,
+}
+
+impl ErrorPage {
+    pub fn status(&self) -> u16 {
+        match self {
+            ErrorPage::InvalidHost | ErrorPage::InvalidRequest => 400,
+            ErrorPage::TunnelNotFound => 404,
+            ErrorPage::RequestTimeout => 408,
+            ErrorPage::ShareLinkGone => 410,
+            ErrorPage::PayloadTooLarge => 413,
+            ErrorPage::HeadersTooLarge => 431,
+            ErrorPage::TunnelRefused
+            | ErrorPage::InvalidResponse
+            | ErrorPage::ErrorProxyingTunnel => 502,
+            ErrorPage::TunnelOffline
+            | ErrorPage::TunnelReconnecting { .. }
+            | ErrorPage::ErrorLocatingTunnel => 503,
+            ErrorPage::GatewayTimeout => 504,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self.status() {
+            400 => "Bad Request",
+            404 => "Not Found",
+            408 => "Request Timeout",
+            410 => "Gone",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            502 => "Bad Gateway",
+            504 => "Gateway Timeout",
+            _ => "Service Unavailable",
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorPage::InvalidHost => "This hostname is not a valid tunnel address.",
+            ErrorPage::InvalidRequest => "The request could not be understood.",
+            ErrorPage::RequestTimeout => "The request took too long to arrive.",
+            ErrorPage::PayloadTooLarge => "The request body is too large.",
+            ErrorPage::HeadersTooLarge => "The request headers are too large.",
+            ErrorPage::TunnelNotFound => "There is no tunnel open for this address.",
+            ErrorPage::TunnelOffline => {
+                "The tunnel for this address went offline. Try again in a moment."
+            }
+            ErrorPage::TunnelReconnecting { .. } => {
+                "The tunnel for this address is reconnecting. Try again in a moment."
+            }
+            ErrorPage::TunnelRefused => {
+                "The tunnel is open, but the service behind it refused the connection."
+            }
+            ErrorPage::InvalidResponse => {
+                "The service behind the tunnel sent a response that couldn't be read."
+            }
+            ErrorPage::ErrorLocatingTunnel => {
+                "We couldn't locate the tunnel for this address. Try again in a moment."
+            }
+            ErrorPage::ErrorProxyingTunnel => "We couldn't reach the tunnel for this address.",
+            ErrorPage::GatewayTimeout => {
+                "The tunnel didn't answer in time. The service behind it may be stuck."
+            }
+            ErrorPage::ShareLinkGone => "This share link has expired or was already used.",
+        }
+    }
+
+    /// A complete HTTP response for this error, rendered for the requested `hostname`
+    pub fn response(&self, hostname: &str) -> Vec<u8> {
+        let body = crate::get_error_pages().render(self, hostname);
+        let retry_after = match self {
+            ErrorPage::TunnelReconnecting { retry_after } => {
+                format!("Retry-After: {}\r\n", retry_after)
+            }
+            _ => String::new(),
+        };
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nCache-Control: no-store\r\n{}Connection: close\r\n\r\n",
+            self.status(),
+            self.reason(),
+            body.len(),
+            retry_after
+        )
+        .into_bytes();
+        response.extend(body.into_bytes());
+        response
+    }
+}
+
+impl From<&http::Error> for ErrorPage {
+    fn from(error: &http::Error) -> Self {
+        match error {
+            http::Error::HeadTooLarge(_) => ErrorPage::HeadersTooLarge,
+            http::Error::BodyTooLarge(_) => ErrorPage::PayloadTooLarge,
+            _ => ErrorPage::InvalidRequest,
+        }
+    }
+}
+
+/// HTML templates for error pages, optionally overridden per status code
+pub struct ErrorPages {
+    default: String,
+    by_status: HashMap<u16, String>,
+}
+
+impl Default for ErrorPages {
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_TEMPLATE.to_string(),
+            by_status: HashMap::new(),
+        }
+    }
+}
+
+impl ErrorPages {
+    /// Load `error.html` and `<status>.html` templates from `dir`, if given
+    pub fn load(dir: Option<&str>) -> Self {
+        let mut pages = Self::default();
+        let Some(dir) = dir else {
+            return pages;
+        };
+
+        let dir = Path::new(dir);
+        match std::fs::read_to_string(dir.join("error.html")) {
+            Ok(template) => pages.default = template,
+            Err(error) => tracing::debug!(?error, "no custom default error page"),
+        }
+
+        for status in [400, 404, 408, 410, 413, 431, 502, 503, 504] {
+            if let Ok(template) = std::fs::read_to_string(dir.join(format!("{}.html", status))) {
+                tracing::info!(%status, "loaded custom error page");
+                pages.by_status.insert(status, template);
+            }
+        }
+
+        pages
+    }
+
+    fn render(&self, page: &ErrorPage, hostname: &str) -> String {
+        let template = self.by_status.get(&page.status()).unwrap_or(&self.default);
+        let subdomain = hostname.split('.').next().unwrap_or_default();
+
+        template
+            .replace("{{status}}", &page.status().to_string())
+            .replace("{{reason}}", page.reason())
+            .replace("{{message}}", page.message())
+            .replace("{{subdomain}}", &escape_html(subdomain))
+            .replace("{{hostname}}", &escape_html(hostname))
+            .replace("{{host}}", &escape_html(&get_config().portal_host))
+    }
+}
+
+/// Escape visitor supplied values before putting them in a page
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,80 @@
+//! Liveness and readiness of this instance, for orchestrators like Kubernetes
+use crate::connected_clients::Connections;
+use crate::get_active_streams;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use warp::http::StatusCode;
+use warp::Filter;
+
+/// The state of our listeners and whether we're on our way out
+#[derive(Debug, Default)]
+pub struct Health {
+    control_listening: AtomicBool,
+    remote_listening: AtomicBool,
+    draining: AtomicBool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub ready: bool,
+    pub control_listening: bool,
+    pub remote_listening: bool,
+    pub draining: bool,
+    pub connected_clients: usize,
+    pub active_streams: usize,
+}
+
+impl Health {
+    pub fn set_control_listening(&self, listening: bool) {
+        self.control_listening.store(listening, Ordering::Relaxed);
+    }
+
+    pub fn set_remote_listening(&self, listening: bool) {
+        self.remote_listening.store(listening, Ordering::Relaxed);
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Whether we should be sent new agents and visitors
+    pub fn is_ready(&self) -> bool {
+        self.control_listening.load(Ordering::Relaxed)
+            && self.remote_listening.load(Ordering::Relaxed)
+            && !self.is_draining()
+    }
+
+    pub fn report(&self) -> HealthReport {
+        HealthReport {
+            ready: self.is_ready(),
+            control_listening: self.control_listening.load(Ordering::Relaxed),
+            remote_listening: self.remote_listening.load(Ordering::Relaxed),
+            draining: self.is_draining(),
+            connected_clients: Connections::all().len(),
+            active_streams: get_active_streams().len(),
+        }
+    }
+}
+
+/// `GET /healthz` answers as long as we're alive, `GET /readyz` only while we're ready
+pub fn routes() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let healthz = warp::path!("healthz")
+        .and(warp::get())
+        .map(|| warp::reply::json(&crate::get_health().report()));
+
+    let readyz = warp::path!("readyz").and(warp::get()).map(|| {
+        let report = crate::get_health().report();
+        let status = if report.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        warp::reply::with_status(warp::reply::json(&report), status)
+    });
+
+    healthz.or(readyz)
+}
@@ -0,0 +1,450 @@
+//! Caching responses to GET requests at the edge, for tunnels that ask for it.
+//!
+//! Only responses that say how long they stay fresh (`s-maxage` or `max-age`) and
+//! aren't meant for one visitor are kept, for at most the configured TTL, so bursts of
+//! the same request are answered here instead of crossing the agent's uplink again.
+//! With `cache_ranges`, requests for a range of a cached response get that part of it.
+use super::range::{self, ByteRange};
+use super::*;
+use crate::get_config;
+use crate::get_response_cache;
+use crate::observability::metrics::get_metrics;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Statuses a response may be cached with without the origin saying so explicitly
+const CACHEABLE_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// Headers about the connection a response came over, not the response itself
+const HOP_HEADERS: [&str; 2] = ["connection", "keep-alive"];
+
+/// A tunnel and a path on it, including the query
+type Key = (String, String);
+
+/// A cached response, with the request headers it was chosen by
+#[derive(Debug)]
+struct Entry {
+    id: u64,
+    /// the values the request it answered had for each header the response `Vary`s on
+    vary: Vec<(String, Option<String>)>,
+    head: ResponseHead,
+    body: Bytes,
+    size: usize,
+    stored_at: Instant,
+    ttl: Duration,
+}
+
+impl Entry {
+    fn is_fresh(&self) -> bool {
+        self.stored_at.elapsed() < self.ttl
+    }
+
+    fn matches(&self, request: &Headers) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request.get(name) == value.as_deref())
+    }
+
+    /// The response to `request` as written to a visitor, saying how long we've had it,
+    /// or `None` if it asks for a range we leave to the tunnel
+    fn response(&self, request: &Headers) -> Option<Bytes> {
+        let mut head = self.head.clone();
+        let mut body = self.body.clone();
+        if let Some(value) = request.get("range") {
+            // a chunked body is stored with its chunks, which we can't count bytes of
+            if self.head.status != 200 || self.head.headers.contains("transfer-encoding") {
+                return None;
+            }
+            let range = ByteRange::parse(value)?;
+            let current = request
+                .get("if-range")
+                .is_none_or(|if_range| range::if_range_matches(if_range, &self.head.headers));
+            if current {
+                let len = body.len() as u64;
+                match range.resolve(len) {
+                    Some((first, last)) => {
+                        head.status = 206;
+                        head.reason = "Partial Content".to_string();
+                        head.headers
+                            .set("Content-Range", format!("bytes {}-{}/{}", first, last, len));
+                        body = body.slice(first as usize..=last as usize);
+                    }
+                    None => {
+                        head.status = 416;
+                        head.reason = "Range Not Satisfiable".to_string();
+                        head.headers
+                            .set("Content-Range", format!("bytes */{}", len));
+                        body = Bytes::new();
+                    }
+                }
+                head.headers.set("Content-Length", body.len().to_string());
+            }
+        }
+
+        head.headers
+            .set("Age", self.stored_at.elapsed().as_secs().to_string());
+        head.headers.set("X-Cache", "HIT");
+        let mut response = head.to_bytes();
+        response.extend_from_slice(&body);
+        Some(response.into())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    by_key: HashMap<Key, Vec<Entry>>,
+    /// where each entry is kept, oldest first
+    order: VecDeque<(Key, u64)>,
+    size: usize,
+    next_id: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &Key, id: u64) {
+        let Some(entries) = self.by_key.get_mut(key) else {
+            return;
+        };
+        if let Some(index) = entries.iter().position(|entry| entry.id == id) {
+            self.size -= entries.remove(index).size;
+        }
+        if entries.is_empty() {
+            self.by_key.remove(key);
+        }
+        self.order.retain(|(k, i)| !(*i == id && k == key));
+    }
+}
+
+/// Responses cached for all tunnels, the oldest making room for new ones
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    /// A fresh response to the request, if we have one
+    fn get(&self, host: &str, head: &RequestHead) -> Option<Bytes> {
+        let key = (host.to_string(), head.path.clone());
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .by_key
+            .get(&key)?
+            .iter()
+            .find(|entry| entry.matches(&head.headers))?;
+        if entry.is_fresh() {
+            return entry.response(&head.headers);
+        }
+
+        let id = entry.id;
+        entries.remove(&key, id);
+        get_metrics().cache_bytes.set(entries.size as i64);
+        None
+    }
+
+    fn store(&self, response: Response) {
+        let capacity = get_config().cache_size;
+        let size = response.head.to_bytes().len() + response.body.len();
+        if size > capacity {
+            return;
+        }
+
+        let Response {
+            key,
+            vary,
+            head,
+            body,
+            ttl,
+        } = response;
+        let mut entries = self.entries.lock().unwrap();
+        let replaced: Vec<u64> = entries
+            .by_key
+            .get(&key)
+            .into_iter()
+            .flatten()
+            .filter(|entry| entry.vary == vary)
+            .map(|entry| entry.id)
+            .collect();
+        for id in replaced {
+            entries.remove(&key, id);
+        }
+        while entries.size + size > capacity {
+            let Some((oldest, id)) = entries.order.front().cloned() else {
+                break;
+            };
+            entries.remove(&oldest, id);
+        }
+
+        let id = entries.next_id;
+        entries.next_id += 1;
+        entries.size += size;
+        entries.order.push_back((key.clone(), id));
+        entries.by_key.entry(key).or_default().push(Entry {
+            id,
+            vary,
+            head,
+            body: body.into(),
+            size,
+            stored_at: Instant::now(),
+            ttl,
+        });
+        get_metrics().cache_bytes.set(entries.size as i64);
+    }
+
+    /// Forget the responses of a tunnel, or of one path on it
+    pub fn purge(&self, host: &str, path: Option<&str>) {
+        let mut entries = self.entries.lock().unwrap();
+        let purged: Vec<(Key, u64)> = entries
+            .order
+            .iter()
+            .filter(|((h, p), _)| h == host && path.is_none_or(|path| path == p))
+            .cloned()
+            .collect();
+        for (key, id) in purged {
+            entries.remove(&key, id);
+        }
+        get_metrics().cache_bytes.set(entries.size as i64);
+    }
+}
+
+/// The values of the `Cache-Control` directives, lowercased, in order
+fn directives(headers: &Headers) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all("cache-control")
+        .filter_map(|value| std::str::from_utf8(value).ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (
+                name.trim().to_ascii_lowercase(),
+                Some(value.trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+/// Whether the request may be answered from the cache: a GET without a body or
+/// credentials
+fn is_cacheable(head: &RequestHead) -> bool {
+    head.method == "GET"
+        && !head.is_upgrade()
+        && !head.headers.contains("authorization")
+        && matches!(head.headers.body_kind(), Ok(BodyKind::Length(0)))
+        && !directives(&head.headers)
+            .iter()
+            .any(|(name, _)| name == "no-store")
+}
+
+/// Whether the request's response may be stored, i.e. it's the whole of it
+fn is_storable(head: &RequestHead) -> bool {
+    is_cacheable(head) && !head.headers.contains("range")
+}
+
+/// Whether the visitor asked for a response straight from the origin
+fn wants_fresh(head: &RequestHead) -> bool {
+    head.headers.has_token("pragma", "no-cache")
+        || directives(&head.headers).iter().any(|(name, value)| {
+            name == "no-cache" || (name == "max-age" && value.as_deref() == Some("0"))
+        })
+}
+
+/// How long a response may be served from the cache, if at all
+fn ttl(head: &ResponseHead, max_ttl: Duration) -> Option<Duration> {
+    if !CACHEABLE_STATUSES.contains(&head.status)
+        || head.headers.contains("set-cookie")
+        || head.headers.has_token("vary", "*")
+    {
+        return None;
+    }
+
+    let directives = directives(&head.headers);
+    if directives
+        .iter()
+        .any(|(name, _)| matches!(name.as_str(), "no-store" | "no-cache" | "private"))
+    {
+        return None;
+    }
+    let max_age = |directive: &str| {
+        directives
+            .iter()
+            .find(|(name, _)| name == directive)
+            .and_then(|(_, value)| value.as_deref()?.parse::<u64>().ok())
+    };
+    let fresh_for = max_age("s-maxage").or_else(|| max_age("max-age"))?;
+    let age = head
+        .headers
+        .get("age")
+        .and_then(|age| age.trim().parse::<u64>().ok())
+        .unwrap_or(0);
+
+    let ttl = Duration::from_secs(fresh_for.saturating_sub(age)).min(max_ttl);
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+/// A request sent to the agent whose response we'll store if it's cacheable
+#[derive(Debug)]
+struct Storable {
+    path: String,
+    headers: Headers,
+}
+
+/// A cacheable response being read off the agent
+#[derive(Debug)]
+struct Response {
+    key: Key,
+    vary: Vec<(String, Option<String>)>,
+    head: ResponseHead,
+    body: Vec<u8>,
+    ttl: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Exchanges {
+    /// for each request the agent has yet to answer, whether to store its response
+    outstanding: VecDeque<Option<Storable>>,
+    response: Option<Response>,
+}
+
+/// Answers the requests of one stream from the cache when it can, and caches the
+/// responses to those it can't
+#[derive(Debug)]
+pub struct CacheTracker {
+    /// the tunnel, if it lets us cache its responses
+    host: Option<String>,
+    exchanges: Mutex<Exchanges>,
+}
+
+impl CacheTracker {
+    pub fn new(host: &str, enabled: bool) -> Self {
+        CacheTracker {
+            host: enabled.then(|| host.to_string()),
+            exchanges: Mutex::new(Exchanges::default()),
+        }
+    }
+
+    /// A request from the visitor, returning the response to write in its place if it
+    /// needn't go to the agent
+    pub fn request(&self, head: &RequestHead) -> Option<Bytes> {
+        let host = self.host.as_ref()?;
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let cache = get_response_cache();
+        // a request that may change the resource makes what we have of it stale
+        if !matches!(head.method.as_str(), "GET" | "HEAD" | "OPTIONS" | "TRACE") {
+            cache.purge(host, Some(&head.path));
+        }
+
+        let storable = is_storable(head);
+        let ranged = !storable && get_config().cache_ranges && is_cacheable(head);
+        // responses go out in the order of the requests, so only when all the
+        // earlier ones have been answered
+        if (storable || ranged) && exchanges.outstanding.is_empty() && !wants_fresh(head) {
+            if let Some(response) = cache.get(host, head) {
+                get_metrics().cache_lookup("hit");
+                return Some(response);
+            }
+        }
+        get_metrics().cache_lookup(if storable { "miss" } else { "bypass" });
+
+        exchanges.outstanding.push_back(storable.then(|| Storable {
+            path: head.path.clone(),
+            headers: head.headers.clone(),
+        }));
+        None
+    }
+
+    /// Response frames came from the agent
+    pub fn response(&self, frames: &[ResponseFrame]) {
+        let Some(host) = &self.host else {
+            return;
+        };
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let config = get_config();
+        for frame in frames {
+            match frame {
+                ResponseFrame::Head(head) if head.is_informational() => {}
+                ResponseFrame::Head(head) => {
+                    let storable = exchanges.outstanding.pop_front().flatten();
+                    exchanges.response = storable.and_then(|request| {
+                        let ttl = ttl(head, config.cache_max_ttl)?;
+                        let vary = head
+                            .headers
+                            .get_all("vary")
+                            .filter_map(|value| std::str::from_utf8(value).ok())
+                            .flat_map(|value| value.split(','))
+                            .map(|name| {
+                                let name = name.trim().to_ascii_lowercase();
+                                let value = request.headers.get(&name).map(str::to_string);
+                                (name, value)
+                            })
+                            .collect();
+                        let mut head = head.clone();
+                        for name in HOP_HEADERS {
+                            head.headers.remove(name);
+                        }
+                        Some(Response {
+                            key: (host.clone(), request.path),
+                            vary,
+                            head,
+                            body: vec![],
+                            ttl,
+                        })
+                    });
+                }
+                ResponseFrame::Body(body) => {
+                    let Some(response) = &mut exchanges.response else {
+                        continue;
+                    };
+                    if response.body.len() + body.len() > config.cache_max_entry {
+                        exchanges.response = None;
+                    } else {
+                        response.body.extend_from_slice(body);
+                    }
+                }
+                ResponseFrame::End { .. } => {
+                    if let Some(response) = exchanges.response.take() {
+                        get_response_cache().store(response);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &str) -> ResponseHead {
+        let raw = format!("HTTP/1.1 200 OK\r\n{}\r\n", headers);
+        ResponseHead::parse(raw.as_bytes()).unwrap().unwrap().0
+    }
+
+    #[test]
+    fn test_ttl() {
+        let max = Duration::from_secs(600);
+        let ttl = |headers| ttl(&response(headers), max);
+
+        assert_eq!(ttl(""), None);
+        assert_eq!(
+            ttl("Cache-Control: public, max-age=60\r\n"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            ttl("Cache-Control: max-age=60, s-maxage=\"120\"\r\n"),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            ttl("Cache-Control: max-age=60\r\nAge: 20\r\n"),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(ttl("Cache-Control: max-age=86400\r\n"), Some(max));
+        assert_eq!(ttl("Cache-Control: max-age=60\r\nAge: 60\r\n"), None);
+        assert_eq!(ttl("Cache-Control: private, max-age=60\r\n"), None);
+        assert_eq!(ttl("Cache-Control: no-store\r\n"), None);
+        assert_eq!(
+            ttl("Cache-Control: max-age=60\r\nSet-Cookie: a=b\r\n"),
+            None
+        );
+        assert_eq!(ttl("Cache-Control: max-age=60\r\nVary: *\r\n"), None);
+    }
+}
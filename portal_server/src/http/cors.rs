@@ -0,0 +1,69 @@
+//! CORS headers added to a tunnel's responses at the edge, for tunnels that ask for it.
+//!
+//! APIs tunneled for browser frontends hosted elsewhere get the headers browsers look
+//! for on every response, and their preflight requests answered here, without the
+//! local service knowing about CORS.
+use super::*;
+use bytes::Bytes;
+use portal_lib::CorsOptions;
+
+/// Methods preflight requests are told we allow when they don't name one
+const ALLOW_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS";
+
+/// Seconds browsers may remember a preflight answer
+const MAX_AGE: &str = "86400";
+
+/// Whether `origin` may read the tunnel's responses
+pub(super) fn allows(options: &CorsOptions, origin: &str) -> bool {
+    options.origins.is_empty()
+        || options
+            .origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+}
+
+/// Let `origin` read the response, replacing what the local service said about it
+pub(super) fn allow(options: &CorsOptions, origin: &str, headers: &mut Headers) {
+    // any origin may read it, unless it's for one origin's credentials
+    if options.origins.is_empty() && !options.credentials {
+        headers.set("Access-Control-Allow-Origin", "*");
+        headers.set("Access-Control-Expose-Headers", "*");
+    } else {
+        headers.set("Access-Control-Allow-Origin", origin);
+        if !headers.has_token("vary", "origin") {
+            headers.append("Vary", "Origin");
+        }
+    }
+    if options.credentials {
+        headers.set("Access-Control-Allow-Credentials", "true");
+    }
+}
+
+/// Whether the browser asks what it may send before sending it
+pub(super) fn is_preflight(head: &RequestHead) -> bool {
+    head.method == "OPTIONS" && head.headers.contains("access-control-request-method")
+}
+
+/// The answer to a preflight request from an allowed origin
+pub(super) fn preflight_response(options: &CorsOptions, request: &RequestHead) -> Bytes {
+    let origin = request.headers.get("origin").unwrap_or_default();
+    let mut head = ResponseHead {
+        version: request.version,
+        status: 204,
+        reason: "No Content".to_string(),
+        headers: Headers::default(),
+    };
+    allow(options, origin, &mut head.headers);
+    let methods = request
+        .headers
+        .get("access-control-request-method")
+        .filter(|method| !method.is_empty())
+        .unwrap_or(ALLOW_METHODS);
+    head.headers.set("Access-Control-Allow-Methods", methods);
+    if let Some(headers) = request.headers.get("access-control-request-headers") {
+        head.headers.set("Access-Control-Allow-Headers", headers);
+    }
+    head.headers.set("Access-Control-Max-Age", MAX_AGE);
+    head.headers.set("Content-Length", "0");
+    head.to_bytes().into()
+}
@@ -0,0 +1,243 @@
+//! Headers added to a tunnel's responses at the edge, and the requests answered there
+//! instead of by the tunnel: CORS preflights and redirects to https.
+//!
+//! Responses go out in the order of their requests, so each one is matched to what
+//! its request asked of us by counting the exchanges on the stream.
+use super::cors;
+use super::https::HttpsPolicy;
+use super::*;
+use bytes::Bytes;
+use portal_lib::CorsOptions;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// What the response to one request gets at the edge
+#[derive(Debug, Clone, Default)]
+struct Exchange {
+    /// the `Origin` allowed to read it
+    origin: Option<String>,
+    /// whether it went out over https and gets the HSTS header
+    hsts: bool,
+}
+
+#[derive(Debug, Default)]
+struct Exchanges {
+    /// the requests the visitor has yet to get the whole answer to
+    outstanding: VecDeque<Exchange>,
+}
+
+/// Adds the headers a tunnel asked for to the responses of one stream, and answers
+/// the requests that never reach it
+#[derive(Debug)]
+pub struct EdgeTracker {
+    cors: Option<CorsOptions>,
+    https: HttpsPolicy,
+    exchanges: Mutex<Exchanges>,
+}
+
+impl EdgeTracker {
+    pub fn new(cors: Option<CorsOptions>, https: HttpsPolicy) -> Self {
+        EdgeTracker {
+            cors,
+            https,
+            exchanges: Mutex::new(Exchanges::default()),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.cors.is_some() || self.https.is_enabled()
+    }
+
+    /// A request from the visitor, and whether it came over https, returning the
+    /// answer to write in its place if we answer it ourselves
+    pub fn request(&self, head: &RequestHead, secure: bool) -> Option<Bytes> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let origin = head
+            .headers
+            .get("origin")
+            .map(str::to_string)
+            .filter(|origin| {
+                self.cors
+                    .as_ref()
+                    .is_some_and(|cors| cors::allows(cors, origin))
+            });
+        // responses go out in the order of the requests, so only when all the
+        // earlier ones have been answered. Pipelined requests behind them are left to
+        // the tunnel, browsers don't pipeline
+        let answer = if !exchanges.outstanding.is_empty() {
+            None
+        } else if !secure && self.https.redirect_port.is_some() {
+            self.https.redirect(head)
+        } else {
+            match (&self.cors, &origin) {
+                (Some(cors), Some(_)) if cors::is_preflight(head) => {
+                    Some(cors::preflight_response(cors, head))
+                }
+                _ => None,
+            }
+        };
+
+        exchanges.outstanding.push_back(Exchange {
+            // our answers already have their cors headers
+            origin: origin.filter(|_| answer.is_none()),
+            hsts: secure && self.https.hsts.is_some(),
+        });
+        answer
+    }
+
+    /// Response frames on their way to the visitor, adding our headers to every final
+    /// response head
+    pub fn response(&self, frames: &mut [ResponseFrame]) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut exchanges = self.exchanges.lock().unwrap();
+        for frame in frames {
+            match frame {
+                ResponseFrame::Head(head) if head.is_informational() => {}
+                ResponseFrame::Head(head) => {
+                    let exchange = exchanges.outstanding.front().cloned().unwrap_or_default();
+                    if let (Some(cors), Some(origin)) = (&self.cors, &exchange.origin) {
+                        cors::allow(cors, origin, &mut head.headers);
+                    }
+                    if let Some(hsts) = self.https.hsts.as_ref().filter(|_| exchange.hsts) {
+                        head.headers.set("Strict-Transport-Security", hsts.as_str());
+                    }
+                }
+                ResponseFrame::Body(_) => {}
+                // done once the last of it went out, as our answers mustn't cut in
+                ResponseFrame::End { .. } => {
+                    exchanges.outstanding.pop_front();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::framing::{self, ResponseTracker};
+
+    /// A stream's exchanges through an edge tracker, as the remote relays them
+    struct Stream {
+        responses: ResponseTracker,
+        edge: EdgeTracker,
+    }
+
+    impl Stream {
+        fn new(cors: Option<CorsOptions>, https: HttpsPolicy) -> Self {
+            Stream {
+                responses: ResponseTracker::default(),
+                edge: EdgeTracker::new(cors, https),
+            }
+        }
+
+        fn request(&self, raw: &str, secure: bool) -> Option<Bytes> {
+            let head = request(raw);
+            self.responses.request(&head);
+            self.edge.request(&head, secure)
+        }
+
+        fn response(&self, data: &[u8]) -> Bytes {
+            let mut frames = self.responses.response(data).unwrap().unwrap();
+            self.edge.response(&mut frames);
+            framing::to_bytes(frames)
+        }
+    }
+
+    #[test]
+    fn test_adds_cors_headers_per_request() {
+        let stream = Stream::new(
+            Some(CorsOptions {
+                origins: vec!["https://app.example.com/".to_string()],
+                credentials: true,
+            }),
+            HttpsPolicy::default(),
+        );
+        assert!(stream
+            .request(
+                "GET /a HTTP/1.1\r\nOrigin: https://app.example.com\r\n\r\n",
+                false
+            )
+            .is_none());
+        assert!(stream
+            .request(
+                "GET /b HTTP/1.1\r\nOrigin: https://evil.example\r\n\r\n",
+                false
+            )
+            .is_none());
+
+        let response = stream.response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhiHTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(
+            response,
+            Bytes::from_static(
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nAccess-Control-Allow-Origin: https://app.example.com\r\nVary: Origin\r\nAccess-Control-Allow-Credentials: true\r\n\r\nhiHTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_answers_preflight() {
+        let stream = Stream::new(Some(CorsOptions::default()), HttpsPolicy::default());
+        let answer = stream
+            .request("OPTIONS /a HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: PUT\r\nAccess-Control-Request-Headers: content-type\r\n\r\n", false)
+            .unwrap();
+        assert_eq!(
+            answer,
+            Bytes::from_static(
+                b"HTTP/1.1 204 No Content\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Expose-Headers: *\r\nAccess-Control-Allow-Methods: PUT\r\nAccess-Control-Allow-Headers: content-type\r\nAccess-Control-Max-Age: 86400\r\nContent-Length: 0\r\n\r\n"
+            )
+        );
+        // the answer goes out through the tracker like any other response
+        assert_eq!(stream.response(&answer), answer);
+
+        let disabled = Stream::new(None, HttpsPolicy::default());
+        assert!(disabled
+            .request("OPTIONS /a HTTP/1.1\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: PUT\r\n\r\n", false)
+            .is_none());
+    }
+
+    #[test]
+    fn test_redirects_to_https_with_hsts() {
+        let stream = Stream::new(
+            None,
+            HttpsPolicy {
+                redirect_port: Some(443),
+                hsts: Some("max-age=60".to_string()),
+            },
+        );
+        let redirect = stream
+            .request(
+                "GET /a HTTP/1.1\r\nHost: app.portal.example.com\r\n\r\n",
+                false,
+            )
+            .unwrap();
+        // a redirect over plain http gets no HSTS, browsers would ignore it
+        assert_eq!(stream.response(&redirect), redirect);
+
+        assert!(stream
+            .request(
+                "GET /a HTTP/1.1\r\nHost: app.portal.example.com\r\n\r\n",
+                true
+            )
+            .is_none());
+        assert_eq!(
+            stream.response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nh"),
+            Bytes::from_static(
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nStrict-Transport-Security: max-age=60\r\n\r\nh"
+            )
+        );
+        // not in the middle of the body of the one before
+        assert!(stream
+            .request(
+                "GET /b HTTP/1.1\r\nHost: app.portal.example.com\r\n\r\n",
+                false
+            )
+            .is_none());
+    }
+}
@@ -0,0 +1,87 @@
+//! `Expect: 100-continue`: clients uploading a body ask whether to go ahead, and hold
+//! it back for a second or more waiting to be told. Through a tunnel the answer is a
+//! round trip away, if the service sends one at all, so we give it at the edge and
+//! leave the header off the request the tunnel gets.
+//!
+//! Responses go out in the order of their requests, so we only answer when the
+//! earlier ones have all gone out, counting the exchanges on the stream.
+use super::*;
+use bytes::Bytes;
+use std::sync::Mutex;
+
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// Answers the `Expect: 100-continue` of the requests of one stream
+#[derive(Debug)]
+pub struct ContinueTracker {
+    enabled: bool,
+    /// requests the visitor has yet to get the whole answer to
+    outstanding: Mutex<usize>,
+}
+
+impl ContinueTracker {
+    pub fn new(enabled: bool) -> Self {
+        ContinueTracker {
+            enabled,
+            outstanding: Mutex::new(0),
+        }
+    }
+
+    /// A request from the visitor, returning the `100 Continue` to write if we answer
+    /// its `Expect` here, in which case the header has to come off the request
+    pub fn request(&self, head: &RequestHead) -> Option<Bytes> {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        // HTTP/1.0 clients don't know interim responses
+        let answer = self.enabled
+            && *outstanding == 0
+            && head.version >= 1
+            && head.headers.has_token("expect", "100-continue");
+
+        *outstanding += 1;
+        answer.then(|| Bytes::from_static(CONTINUE))
+    }
+
+    /// Response frames on their way to the visitor
+    pub fn response(&self, frames: &[ResponseFrame]) {
+        let ends = frames
+            .iter()
+            .filter(|frame| matches!(frame, ResponseFrame::End { .. }))
+            .count();
+        let mut outstanding = self.outstanding.lock().unwrap();
+        *outstanding = outstanding.saturating_sub(ends);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::framing::ResponseTracker;
+
+    #[test]
+    fn test_answers_in_order() {
+        let responses = ResponseTracker::default();
+        let tracker = ContinueTracker::new(true);
+        let upload =
+            request("PUT /a HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n");
+        let send = |head: &RequestHead| {
+            responses.request(head);
+            tracker.request(head)
+        };
+        let relay = |data: &[u8]| tracker.response(&responses.response(data).unwrap().unwrap());
+        assert!(send(&upload).is_some());
+
+        // the first response is still going out, so the second upload waits for its own
+        assert!(send(&upload).is_none());
+        relay(CONTINUE);
+        relay(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n");
+        relay(b"HTTP/1.1 100 Continue\r\n\r\n");
+        relay(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n");
+        assert!(send(&upload).is_some());
+
+        let old = "PUT /a HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\n";
+        let tracker = ContinueTracker::new(true);
+        assert!(tracker.request(&request(old)).is_none());
+        let tracker = ContinueTracker::new(false);
+        assert!(tracker.request(&upload).is_none());
+    }
+}
@@ -0,0 +1,169 @@
+//! `Forwarded` (RFC 7239) and `X-Forwarded-*` header injection
+use super::*;
+use std::net::{IpAddr, SocketAddr};
+
+const FORWARDED: &str = "Forwarded";
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+
+/// What we know about the hop a request arrived on
+#[derive(Debug, Clone)]
+pub struct ForwardedContext {
+    /// The address of the peer that connected to us
+    pub peer_addr: SocketAddr,
+    /// The scheme the visitor used to reach us
+    pub proto: &'static str,
+    /// Whether headers set by the peer are trusted and appended to
+    pub trusted: bool,
+}
+
+impl ForwardedContext {
+    pub fn new(peer_addr: SocketAddr, trusted: bool) -> Self {
+        Self {
+            peer_addr,
+            proto: "http",
+            trusted,
+        }
+    }
+
+    /// Whether the visitor sent `head` over https, which only a trusted peer in front
+    /// of us can tell, i.e. the proxy that terminated its TLS
+    pub fn is_https(&self, head: &RequestHead) -> bool {
+        if !self.trusted {
+            return self.proto == "https";
+        }
+        // the first hop is the visitor's
+        let forwarded_proto = head.headers.get(X_FORWARDED_PROTO).map(|proto| {
+            proto
+                .split(',')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        });
+        let proto = forwarded_proto.or_else(|| {
+            let element = head.headers.get(FORWARDED)?.split(',').next()?;
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("proto")
+                    .then(|| value.trim().trim_matches('"').to_string())
+            })
+        });
+        proto
+            .unwrap_or_else(|| self.proto.to_string())
+            .eq_ignore_ascii_case("https")
+    }
+
+    /// Add our hop to the forwarding headers of `head`.
+    ///
+    /// Existing values are extended when the peer is trusted and
+    /// replaced otherwise, so visitors can't spoof their address.
+    pub fn apply(&self, head: &mut RequestHead) {
+        let headers = &mut head.headers;
+        let peer_ip = self.peer_addr.ip().to_canonical();
+        let host = headers.get("host").unwrap_or_default().to_string();
+
+        if !self.trusted {
+            headers.remove(FORWARDED);
+            headers.remove(X_FORWARDED_FOR);
+            headers.remove(X_FORWARDED_PROTO);
+            headers.remove(X_FORWARDED_HOST);
+        }
+
+        let mut element = format!("for={};proto={}", forwarded_node(peer_ip), self.proto);
+        if !host.is_empty() {
+            element.push_str(&format!(";host={}", forwarded_value(&host)));
+        }
+        append_list(headers, FORWARDED, &element);
+        append_list(headers, X_FORWARDED_FOR, &peer_ip.to_string());
+
+        if !headers.contains(X_FORWARDED_PROTO) {
+            headers.set(X_FORWARDED_PROTO, self.proto);
+        }
+        if !headers.contains(X_FORWARDED_HOST) && !host.is_empty() {
+            headers.set(X_FORWARDED_HOST, host);
+        }
+    }
+}
+
+/// Join all existing values of `name` and `value` into a single comma separated header
+fn append_list(headers: &mut Headers, name: &str, value: &str) {
+    let mut values: Vec<String> = headers
+        .get_all(name)
+        .map(|v| String::from_utf8_lossy(v).trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+    values.push(value.to_string());
+    headers.set(name, values.join(", "));
+}
+
+/// Format a node identifier, quoting and bracketing IPv6 addresses per RFC 7239
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// Quote a value unless it is a valid token
+fn forwarded_value(value: &str) -> String {
+    let is_token = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_untrusted_replaces() {
+        let mut req = request("GET / HTTP/1.1\r\nHost: a.example.com:8080\r\nX-Forwarded-For: 6.6.6.6\r\nForwarded: for=6.6.6.6\r\n\r\n");
+        let ctx = ForwardedContext::new("[2001:db8::1]:1234".parse().unwrap(), false);
+        ctx.apply(&mut req);
+
+        assert_eq!(req.headers.get("x-forwarded-for"), Some("2001:db8::1"));
+        assert_eq!(
+            req.headers.get("forwarded"),
+            Some("for=\"[2001:db8::1]\";proto=http;host=\"a.example.com:8080\"")
+        );
+        assert_eq!(req.headers.get("x-forwarded-proto"), Some("http"));
+        assert_eq!(
+            req.headers.get("x-forwarded-host"),
+            Some("a.example.com:8080")
+        );
+    }
+
+    #[test]
+    fn test_trusted_appends() {
+        let mut req = request("GET / HTTP/1.1\r\nHost: a.example.com\r\nX-Forwarded-For: 1.1.1.1\r\nX-Forwarded-Proto: https\r\n\r\n");
+        let ctx = ForwardedContext::new("10.0.0.1:1234".parse().unwrap(), true);
+        ctx.apply(&mut req);
+
+        assert_eq!(
+            req.headers.get("x-forwarded-for"),
+            Some("1.1.1.1, 10.0.0.1")
+        );
+        assert_eq!(req.headers.get("x-forwarded-proto"), Some("https"));
+    }
+
+    #[test]
+    fn test_is_https() {
+        let trusted = ForwardedContext::new("10.0.0.1:1234".parse().unwrap(), true);
+        let untrusted = ForwardedContext::new("10.0.0.1:1234".parse().unwrap(), false);
+        let proxied = request("GET / HTTP/1.1\r\nX-Forwarded-Proto: https, http\r\n\r\n");
+        assert!(trusted.is_https(&proxied));
+        assert!(!untrusted.is_https(&proxied));
+
+        let forwarded = request("GET / HTTP/1.1\r\nForwarded: for=1.1.1.1;Proto=\"https\"\r\n\r\n");
+        assert!(trusted.is_https(&forwarded));
+        assert!(!trusted.is_https(&request("GET / HTTP/1.1\r\n\r\n")));
+    }
+}
@@ -0,0 +1,154 @@
+//! The responses a tunnel sends its visitors, framed once for everything that looks
+//! at them: the cache, the edge's headers, `Expect` and event streams. Bodies are
+//! relayed as they arrive, chunked ones with their chunk lines and trailers as they
+//! are, but the visitor has to read them the way we do: a response we can't tell the
+//! end of could be taken for the answer to the visitor's next request.
+use super::*;
+use bytes::Bytes;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+/// Follows the responses of one stream, settling the framing of their heads
+#[derive(Debug)]
+pub struct ResponseTracker {
+    /// `None` on streams that aren't HTTP/1.x, i.e. h2c or raw TCP
+    framer: Mutex<Option<ResponseFramer>>,
+    /// told whether the stream switched protocols once its upgrade is answered
+    upgrade: Mutex<Option<oneshot::Sender<bool>>>,
+}
+
+impl Default for ResponseTracker {
+    fn default() -> Self {
+        ResponseTracker {
+            framer: Mutex::new(Some(ResponseFramer::default())),
+            upgrade: Mutex::new(None),
+        }
+    }
+}
+
+impl ResponseTracker {
+    /// Pass the stream's bytes on as they are, it doesn't carry HTTP/1.x
+    pub fn disable(&self) {
+        *self.framer.lock().unwrap() = None;
+    }
+
+    /// A request went to the agent or was answered at the edge. For an upgrade,
+    /// returns whether the response to it switched protocols.
+    pub fn request(&self, head: &RequestHead) -> Option<oneshot::Receiver<bool>> {
+        let mut framer = self.framer.lock().unwrap();
+        let framer = framer.as_mut()?;
+        if !head.is_upgrade() {
+            framer.expect(&head.method);
+            return None;
+        }
+
+        framer.expect_upgrade(&head.method);
+        let (switched, answer) = oneshot::channel();
+        *self.upgrade.lock().unwrap() = Some(switched);
+        Some(answer)
+    }
+
+    /// Response bytes on their way to the visitor split into frames with their heads
+    /// sanitized, `None` on streams we don't frame, or the error that makes them unfit
+    /// to pass on
+    pub fn response(&self, data: &[u8]) -> Result<Option<Vec<ResponseFrame>>, Error> {
+        let mut framer = self.framer.lock().unwrap();
+        let Some(framer) = framer.as_mut() else {
+            return Ok(None);
+        };
+
+        let mut frames = framer.push_frames(data)?;
+        if let Some(switched) = framer.take_upgrade() {
+            if let Some(upgrade) = self.upgrade.lock().unwrap().take() {
+                let _ = upgrade.send(switched);
+            }
+        }
+        for frame in &mut frames {
+            if let ResponseFrame::Head(head) = frame {
+                if !head.is_informational() {
+                    head.sanitize()?;
+                }
+            }
+        }
+        Ok(Some(frames))
+    }
+}
+
+/// The bytes of `frames` as written to the visitor
+pub fn to_bytes(mut frames: Vec<ResponseFrame>) -> Bytes {
+    // most reads are body bytes only, which go out without another copy
+    if let [ResponseFrame::Body(body)] = frames.as_mut_slice() {
+        return std::mem::take(body).into();
+    }
+
+    let mut out = vec![];
+    for frame in frames {
+        match frame {
+            ResponseFrame::Head(head) => out.extend(head.to_bytes()),
+            ResponseFrame::Body(body) => out.extend(body),
+            ResponseFrame::End { .. } => {}
+        }
+    }
+    out.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passes_chunks_and_trailers() {
+        let tracker = ResponseTracker::default();
+        tracker.request(&request("GET /a HTTP/1.1\r\n\r\n"));
+
+        let head = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n";
+        assert_eq!(
+            to_bytes(tracker.response(head).unwrap().unwrap()),
+            Bytes::from_static(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")
+        );
+        let body = b"5\r\nhello\r\n0\r\nX-Checksum: 1\r\n\r\n";
+        assert_eq!(
+            to_bytes(tracker.response(body).unwrap().unwrap()),
+            &body[..]
+        );
+    }
+
+    #[test]
+    fn test_answers_upgrades() {
+        let tracker = ResponseTracker::default();
+        let mut refused = tracker
+            .request(&request(
+                "GET /ws HTTP/1.1\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n",
+            ))
+            .unwrap();
+        tracker
+            .response(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        assert_eq!(refused.try_recv(), Ok(false));
+
+        let mut switched = tracker
+            .request(&request(
+                "GET /ws HTTP/1.1\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n",
+            ))
+            .unwrap();
+        tracker
+            .response(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n")
+            .unwrap();
+        assert_eq!(switched.try_recv(), Ok(true));
+    }
+
+    #[test]
+    fn test_rejects_broken_chunks() {
+        let tracker = ResponseTracker::default();
+        tracker.request(&request("GET /a HTTP/1.1\r\n\r\n"));
+
+        tracker
+            .response(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")
+            .unwrap();
+        assert!(tracker.response(b"zz\r\nhello\r\n").is_err());
+
+        let disabled = ResponseTracker::default();
+        disabled.disable();
+        assert_eq!(disabled.response(b"zz\r\n").unwrap(), None);
+    }
+}
@@ -0,0 +1,130 @@
+//! Sniffing of HTTP/2 prior-knowledge (h2c) connections.
+//!
+//! We don't terminate HTTP/2: once we know which host the connection is for,
+//! the raw stream is passed through to the agent and on to the local service,
+//! so gRPC streaming, trailers and flow control work end to end.
+use super::*;
+
+/// The connection preface every HTTP/2 client sends first
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_CONTINUATION: u8 = 0x9;
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+/// Whether `buf` is (the start of) an HTTP/2 connection preface
+pub fn is_preface(buf: &[u8]) -> bool {
+    let len = buf.len().min(PREFACE.len());
+    len > 0 && buf[..len] == PREFACE[..len]
+}
+
+/// Find the `:authority` of the first request on an h2c connection.
+///
+/// Returns `None` if more bytes are needed to see the complete first header block.
+pub fn peek_authority(buf: &[u8]) -> Result<Option<String>, Error> {
+    if buf.len() < PREFACE.len() {
+        return Ok(None);
+    }
+
+    let mut rest = &buf[PREFACE.len()..];
+    let mut block: Vec<u8> = vec![];
+    let mut in_headers = false;
+
+    loop {
+        if rest.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([0, rest[0], rest[1], rest[2]]) as usize;
+        let (typ, flags) = (rest[3], rest[4]);
+        if rest.len() < FRAME_HEADER_LEN + len {
+            return Ok(None);
+        }
+        let payload = &rest[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len];
+        rest = &rest[FRAME_HEADER_LEN + len..];
+
+        match (typ, in_headers) {
+            (FRAME_HEADERS, false) => {
+                block.extend_from_slice(headers_fragment(payload, flags)?);
+                in_headers = true;
+            }
+            (FRAME_CONTINUATION, true) => block.extend_from_slice(payload),
+            // nothing may be interleaved with a header block
+            (_, true) => return Err(Error::InvalidFrame),
+            // skip SETTINGS, WINDOW_UPDATE etc. sent ahead of the first request
+            (_, false) => continue,
+        }
+
+        if flags & FLAG_END_HEADERS != 0 {
+            break;
+        }
+    }
+
+    let headers = hpack::Decoder::new()
+        .decode(&block)
+        .map_err(|_| Error::InvalidFrame)?;
+
+    let authority = headers
+        .iter()
+        .find(|(name, _)| name == b":authority")
+        .or_else(|| headers.iter().find(|(name, _)| name == b"host"))
+        .and_then(|(_, value)| String::from_utf8(value.clone()).ok());
+
+    authority.map(Some).ok_or(Error::InvalidFrame)
+}
+
+/// Strip the padding and priority fields from a HEADERS frame payload
+fn headers_fragment(payload: &[u8], flags: u8) -> Result<&[u8], Error> {
+    let mut fragment = payload;
+
+    let padding = if flags & FLAG_PADDED != 0 {
+        let (pad_len, rest) = fragment.split_first().ok_or(Error::InvalidFrame)?;
+        fragment = rest;
+        *pad_len as usize
+    } else {
+        0
+    };
+
+    if flags & FLAG_PRIORITY != 0 {
+        fragment = fragment.get(5..).ok_or(Error::InvalidFrame)?;
+    }
+
+    fragment
+        .get(..fragment.len().saturating_sub(padding))
+        .filter(|_| padding <= fragment.len())
+        .ok_or(Error::InvalidFrame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(typ: u8, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let len = (payload.len() as u32).to_be_bytes();
+        [&len[1..], &[typ, flags, 0, 0, 0, 1], payload].concat()
+    }
+
+    #[test]
+    fn test_peek_authority() {
+        let block = hpack::Encoder::new().encode(&vec![
+            (b":method".to_vec(), b"POST".to_vec()),
+            (b":authority".to_vec(), b"grpc.example.com".to_vec()),
+        ]);
+        let (first, second) = block.split_at(3);
+
+        let mut conn = PREFACE.to_vec();
+        conn.extend(frame(0x4, 0, &[]));
+        conn.extend(frame(FRAME_HEADERS, 0, first));
+        assert!(is_preface(&conn[..10]));
+        assert_eq!(peek_authority(&conn).unwrap(), None);
+
+        conn.extend(frame(FRAME_CONTINUATION, FLAG_END_HEADERS, second));
+        assert_eq!(
+            peek_authority(&conn).unwrap(),
+            Some("grpc.example.com".to_string())
+        );
+    }
+}
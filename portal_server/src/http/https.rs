@@ -0,0 +1,121 @@
+//! Plain http visitors sent over to https, and HSTS on the responses they get there.
+//!
+//! TLS is terminated by a proxy in front of us, so a request came over https when a
+//! peer we trust says so in its forwarding headers.
+use super::*;
+use crate::config::Config;
+use crate::connected_clients::ConnectedClient;
+use bytes::Bytes;
+
+/// Where ACME http-01 challenges are answered, which only ever come over plain http
+const ACME_CHALLENGE: &str = "/.well-known/acme-challenge/";
+
+/// How a tunnel's visitors are kept on https
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HttpsPolicy {
+    /// the port plain http visitors are redirected to, if they are
+    pub redirect_port: Option<u16>,
+    /// the `Strict-Transport-Security` of responses over https, if any
+    pub hsts: Option<String>,
+}
+
+impl HttpsPolicy {
+    /// What the tunnel asked for, or the server does for every http tunnel
+    pub fn new(client: &ConnectedClient, config: &Config) -> Self {
+        if !client.protocol.is_http() {
+            return HttpsPolicy::default();
+        }
+        let redirect = client.https_redirect || config.https_redirect;
+        let hsts = client.hsts || config.hsts;
+        HttpsPolicy {
+            redirect_port: redirect.then(|| config.public_https_port.unwrap_or(443)),
+            hsts: hsts.then(|| format!("max-age={}", config.hsts_max_age.as_secs())),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.redirect_port.is_some() || self.hsts.is_some()
+    }
+
+    /// The answer sending a request that came over plain http to https, unless it
+    /// may stay on http
+    pub fn redirect(&self, head: &RequestHead) -> Option<Bytes> {
+        let port = self.redirect_port?;
+        if head.path.starts_with(ACME_CHALLENGE) {
+            return None;
+        }
+        let host = head.headers.get("host").filter(|host| !host.is_empty())?;
+        // the port visitors used is the one of plain http
+        let name = match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        };
+        // an absolute-form target names its own scheme and host
+        let path = match head.path.find("://") {
+            Some(scheme) => {
+                let authority = &head.path[scheme + 3..];
+                authority.find('/').map_or("/", |path| &authority[path..])
+            }
+            None if head.path.starts_with('/') => head.path.as_str(),
+            None => "/",
+        };
+        let location = match port {
+            443 => format!("https://{}{}", name, path),
+            port => format!("https://{}:{}{}", name, port, path),
+        };
+
+        let mut response = ResponseHead {
+            version: head.version,
+            status: 308,
+            reason: "Permanent Redirect".to_string(),
+            headers: Headers::default(),
+        };
+        response.headers.set("Location", location);
+        response.headers.set("Content-Length", "0");
+        Some(response.to_bytes().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirect() {
+        let policy = HttpsPolicy {
+            redirect_port: Some(443),
+            hsts: None,
+        };
+        assert_eq!(
+            policy.redirect(&request(
+                "POST /a?b=c HTTP/1.1\r\nHost: app.portal.example.com:8080\r\n\r\n"
+            )),
+            Some(Bytes::from_static(
+                b"HTTP/1.1 308 Permanent Redirect\r\nLocation: https://app.portal.example.com/a?b=c\r\nContent-Length: 0\r\n\r\n"
+            ))
+        );
+
+        let other_port = HttpsPolicy {
+            redirect_port: Some(8443),
+            hsts: None,
+        };
+        assert_eq!(
+            other_port.redirect(&request(
+                "GET http://app.portal.example.com/x HTTP/1.1\r\nHost: app.portal.example.com\r\n\r\n",
+            )),
+            Some(Bytes::from_static(
+                b"HTTP/1.1 308 Permanent Redirect\r\nLocation: https://app.portal.example.com:8443/x\r\nContent-Length: 0\r\n\r\n"
+            ))
+        );
+
+        // certificates must still be issuable over plain http
+        assert!(policy
+            .redirect(&request(
+                "GET /.well-known/acme-challenge/token HTTP/1.1\r\nHost: app.portal.example.com\r\n\r\n"
+            ))
+            .is_none());
+        assert!(HttpsPolicy::default()
+            .redirect(&request("GET / HTTP/1.1\r\nHost: a.example.com\r\n\r\n"))
+            .is_none());
+    }
+}
@@ -0,0 +1,19 @@
+pub use portal_lib::http::*;
+
+pub mod cache;
+pub mod cors;
+pub mod edge;
+pub mod expect;
+pub mod forwarded;
+pub mod framing;
+pub mod h2;
+pub mod https;
+pub mod range;
+pub mod sse;
+pub mod sticky;
+
+/// The head of a request written out in full
+#[cfg(test)]
+pub fn request(raw: &str) -> RequestHead {
+    RequestHead::parse(raw.as_bytes()).unwrap().unwrap().0
+}
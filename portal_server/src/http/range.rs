@@ -0,0 +1,88 @@
+//! The `Range` header, for serving parts of cached responses, so downloads cut off
+//! halfway resume where they stopped. Only single byte ranges are served this way: a
+//! request for several goes to the tunnel, which may answer it with
+//! `multipart/byteranges`.
+use super::*;
+
+/// The bytes of a representation a request asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// from the first offset on, to the second one if given
+    From(u64, Option<u64>),
+    /// the last so many bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// The single byte range of a `Range` header, `None` for any other kind
+    pub fn parse(value: &str) -> Option<ByteRange> {
+        let (unit, range) = value.split_once('=')?;
+        if !unit.trim().eq_ignore_ascii_case("bytes") || range.contains(',') {
+            return None;
+        }
+
+        let (first, last) = range.trim().split_once('-')?;
+        if first.is_empty() {
+            return Some(ByteRange::Suffix(last.parse().ok()?));
+        }
+        let first = first.parse().ok()?;
+        let last = match last {
+            "" => None,
+            last => Some(last.parse().ok()?),
+        };
+        if last.is_some_and(|last| last < first) {
+            return None;
+        }
+        Some(ByteRange::From(first, last))
+    }
+
+    /// The offsets of the first and last byte of the range in a representation of
+    /// `len` bytes, or `None` if it has none of them
+    pub fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRange::From(first, _) if first >= len => None,
+            ByteRange::From(first, last) => {
+                Some((first, last.map_or(len - 1, |last| last.min(len - 1))))
+            }
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(_) if len == 0 => None,
+            ByteRange::Suffix(n) => Some((len.saturating_sub(n), len - 1)),
+        }
+    }
+}
+
+/// Whether the `If-Range` of a request names the response with `headers`, by a
+/// strong `ETag` or its exact `Last-Modified` date. When it doesn't, the visitor's
+/// copy is outdated and gets the whole response instead.
+pub fn if_range_matches(if_range: &str, headers: &Headers) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with("W/") {
+        return false;
+    }
+    let validator = if if_range.starts_with('"') {
+        headers.get("etag")
+    } else {
+        headers.get("last-modified")
+    };
+    validator.is_some_and(|validator| validator.trim() == if_range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranges() {
+        let resolve = |value| ByteRange::parse(value).and_then(|range| range.resolve(10));
+
+        assert_eq!(resolve("bytes=0-4"), Some((0, 4)));
+        assert_eq!(resolve("bytes=5-"), Some((5, 9)));
+        assert_eq!(resolve("bytes=8-20"), Some((8, 9)));
+        assert_eq!(resolve("bytes=-3"), Some((7, 9)));
+        assert_eq!(resolve("bytes=-30"), Some((0, 9)));
+        assert_eq!(resolve("bytes=10-"), None);
+        assert_eq!(ByteRange::parse("bytes=0-1,4-5"), None);
+        assert_eq!(ByteRange::parse("bytes=4-1"), None);
+        assert_eq!(ByteRange::parse("items=0-4"), None);
+    }
+}
@@ -0,0 +1,67 @@
+//! Server-sent events: responses of `Content-Type: text/event-stream` stay open for as
+//! long as the app has events to send, and may go quiet for longer than a stream is
+//! allowed to idle. Their bytes are relayed as they arrive like any other's, we only
+//! have to tell when a stream is relaying one.
+use super::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Follows the responses of one stream to tell whether it's relaying an event stream
+#[derive(Debug, Default)]
+pub struct EventStreamTracker {
+    streaming: AtomicBool,
+}
+
+impl EventStreamTracker {
+    /// Response frames on their way to the visitor
+    pub fn response(&self, frames: &[ResponseFrame]) {
+        for frame in frames {
+            match frame {
+                ResponseFrame::Head(head) if head.is_informational() => {}
+                ResponseFrame::Head(head) => {
+                    self.streaming
+                        .store(is_event_stream(head), Ordering::Relaxed);
+                }
+                ResponseFrame::Body(_) => {}
+                ResponseFrame::End { .. } => self.streaming.store(false, Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Whether the response going out now is an event stream
+    pub fn is_streaming(&self) -> bool {
+        self.streaming.load(Ordering::Relaxed)
+    }
+}
+
+fn is_event_stream(head: &ResponseHead) -> bool {
+    head.headers
+        .get("content-type")
+        .is_some_and(|content_type| {
+            content_type
+                .split(';')
+                .next()
+                .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/event-stream"))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::framing::ResponseTracker;
+
+    #[test]
+    fn test_tells_event_streams() {
+        let responses = ResponseTracker::default();
+        let tracker = EventStreamTracker::default();
+        responses.request(&request("GET /a HTTP/1.1\r\n\r\n"));
+        responses.request(&request("GET /events HTTP/1.1\r\n\r\n"));
+        let relay = |data: &[u8]| tracker.response(&responses.response(data).unwrap().unwrap());
+
+        relay(b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 2\r\n\r\n");
+        assert!(!tracker.is_streaming());
+        relay(b"hiHTTP/1.1 200 OK\r\nContent-Type: text/event-stream; charset=utf-8\r\n\r\n");
+        assert!(tracker.is_streaming());
+        relay(b"data: one\n\n");
+        assert!(tracker.is_streaming());
+    }
+}
@@ -0,0 +1,99 @@
+//! Cookie based stickiness for hosts served by several agents.
+//!
+//! The first response on a connection gets a `Set-Cookie` naming the agent that
+//! served it, so later connections from the same browser are routed back to it.
+use super::*;
+use bytes::Bytes;
+
+/// Find the value of cookie `name` in `Cookie` header values
+pub fn cookie_value<'a>(cookies: impl IntoIterator<Item = &'a str>, name: &str) -> Option<&'a str> {
+    cookies
+        .into_iter()
+        .flat_map(|header| header.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Adds a `Set-Cookie` header to the first response written to a visitor
+#[derive(Debug)]
+pub struct CookieInjector {
+    header: Option<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl CookieInjector {
+    pub fn new(name: &str, value: &str) -> Self {
+        CookieInjector {
+            header: Some(
+                format!("Set-Cookie: {}={}; Path=/; HttpOnly\r\n", name, value).into_bytes(),
+            ),
+            buf: vec![],
+        }
+    }
+
+    /// An injector that never touches the response
+    pub fn disabled() -> Self {
+        CookieInjector {
+            header: None,
+            buf: vec![],
+        }
+    }
+
+    /// Feed response bytes, returning what can be written to the visitor so far
+    pub fn push(&mut self, data: Bytes) -> Bytes {
+        let Some(header) = &self.header else {
+            return data;
+        };
+        self.buf.extend_from_slice(&data);
+
+        // we insert the header right after the status line
+        let Some(end) = self.buf.windows(2).position(|w| w == b"\r\n") else {
+            if self.buf.len() > MAX_HEAD_SIZE {
+                self.header = None;
+            }
+            return if self.header.is_some() {
+                Bytes::new()
+            } else {
+                std::mem::take(&mut self.buf).into()
+            };
+        };
+
+        let mut out = std::mem::take(&mut self.buf);
+        // informational responses (i.e. 100 Continue, 101 Switching Protocols) can't set cookies
+        if !out.starts_with(b"HTTP/1.1 1") && !out.starts_with(b"HTTP/1.0 1") {
+            out.splice(end + 2..end + 2, header.iter().copied());
+        }
+        self.header = None;
+        out.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_value() {
+        let cookies = ["a=1; portal_agent=abc", "b=2"];
+        assert_eq!(cookie_value(cookies, "portal_agent"), Some("abc"));
+        assert_eq!(cookie_value(cookies, "b"), Some("2"));
+        assert_eq!(cookie_value(cookies, "c"), None);
+    }
+
+    #[test]
+    fn test_inject_cookie() {
+        let mut injector = CookieInjector::new("portal_agent", "abc");
+        assert!(injector
+            .push(Bytes::from_static(b"HTTP/1.1 200"))
+            .is_empty());
+        assert_eq!(
+            injector.push(Bytes::from_static(b" OK\r\nContent-Length: 0\r\n\r\n")),
+            b"HTTP/1.1 200 OK\r\nSet-Cookie: portal_agent=abc; Path=/; HttpOnly\r\nContent-Length: 0\r\n\r\n".to_vec()
+        );
+        assert_eq!(
+            injector.push(Bytes::from_static(b"HTTP/1.1")),
+            b"HTTP/1.1".to_vec()
+        );
+    }
+}
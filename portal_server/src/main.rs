@@ -1,50 +1,121 @@
-use futures::{SinkExt, StreamExt};
-use warp::ws::{Message, WebSocket, Ws};
+use futures::{FutureExt, SinkExt, StreamExt};
+use warp::ws::Ws;
 use warp::Filter;
 
+use arc_swap::ArcSwap;
+use bytes::Bytes;
 use dashmap::DashMap;
 pub use portal_lib::*;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
 use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 use tokio::net::TcpListener;
 
-use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
-use futures::stream::{SplitSink, SplitStream};
+use futures::channel::mpsc::{channel, Receiver, Sender};
 
 mod connected_clients;
 use self::connected_clients::*;
 mod active_stream;
 use self::active_stream::*;
 
+mod access_log;
+mod admin;
+mod admission;
+mod agent_updates;
+mod alerts;
+mod buffer_pool;
+mod client_manager;
+use self::access_log::AccessLog;
+use self::admission::{Admission, Gate};
+use self::agent_updates::AgentUpdates;
+use self::alerts::Alerts;
+use self::buffer_pool::BufferPool;
+
 mod auth;
 pub use self::auth::client_auth;
 
 // pub use self::auth_db::AuthDbService;
 
 mod control_server;
+mod deadline;
+mod dns;
+mod drain;
+mod error_page;
+mod health;
+use self::error_page::ErrorPages;
+use self::health::Health;
+pub mod http;
+use self::http::cache::ResponseCache;
+mod mirror;
+mod offline;
+use self::offline::OfflineQueues;
+use self::share::ShareLinks;
+mod proxy_protocol;
+mod reload;
 mod remote;
+mod replay;
+mod request_log;
+mod service;
+mod share;
+mod socket;
+mod storage;
+mod subdomain;
+mod tasks;
+use self::tasks::Tasks;
+#[cfg(all(test, feature = "integration-tests"))]
+mod testing;
+mod transport;
+use self::request_log::RequestLog;
+mod throttle;
+mod usage;
+use self::usage::Usage;
+mod webhooks;
+use self::webhooks::Webhooks;
 
 mod config;
 pub use self::config::Config;
 mod network;
+use self::network::{HostCache, HostRegistry, Links};
 
 mod observability;
 
 mod cli;
 use clap::Parser;
-use cli::Cli;
-
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::registry;
+use cli::{Cli, Command};
 
-use tracing::{error, info, Instrument};
+use tracing::{error, info, warn, Instrument};
 
 static CLI: OnceLock<Cli> = OnceLock::new();
 static CONNECTIONS: OnceLock<Connections> = OnceLock::new();
 static ACTIVE_STREAMS: OnceLock<ActiveStreams> = OnceLock::new();
-static CONFIG: OnceLock<Config> = OnceLock::new();
+static TUNNEL_TRAFFIC: OnceLock<TunnelTraffic> = OnceLock::new();
+static CONFIG: OnceLock<ArcSwap<Config>> = OnceLock::new();
 static AUTH_DB_SERVICE: OnceLock<crate::auth::NoAuth> = OnceLock::new();
+static ERROR_PAGES: OnceLock<ArcSwap<ErrorPages>> = OnceLock::new();
+static REQUEST_LOG: OnceLock<RequestLog> = OnceLock::new();
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+static HEALTH: OnceLock<Health> = OnceLock::new();
+static WEBHOOKS: OnceLock<Webhooks> = OnceLock::new();
+static USAGE: OnceLock<Usage> = OnceLock::new();
+static STORAGE: OnceLock<Box<dyn storage::Storage>> = OnceLock::new();
+static DNS_PROVIDER: OnceLock<Option<Box<dyn dns::DnsProvider>>> = OnceLock::new();
+static ALERTS: OnceLock<Alerts> = OnceLock::new();
+static HOST_REGISTRY: OnceLock<Box<dyn HostRegistry>> = OnceLock::new();
+static HOST_CACHE: OnceLock<HostCache> = OnceLock::new();
+static LINKS: OnceLock<Links> = OnceLock::new();
+static BUFFER_POOL: OnceLock<BufferPool> = OnceLock::new();
+static GATE: OnceLock<Gate> = OnceLock::new();
+static TASKS: OnceLock<Tasks> = OnceLock::new();
+static OFFLINE_QUEUES: OnceLock<OfflineQueues> = OnceLock::new();
+static RESPONSE_CACHE: OnceLock<ResponseCache> = OnceLock::new();
+static SHARE_LINKS: OnceLock<ShareLinks> = OnceLock::new();
+static AGENT_UPDATES: OnceLock<AgentUpdates> = OnceLock::new();
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+static URING: OnceLock<Option<portal_lib::uring::UringRelay>> = OnceLock::new();
+static GENERATOR: OnceLock<Box<dyn subdomain::Generator>> = OnceLock::new();
 
 pub fn get_cli() -> &'static Cli {
     CLI.get_or_init(Cli::parse)
@@ -58,70 +129,350 @@ pub fn get_active_streams() -> &'static ActiveStreams {
     ACTIVE_STREAMS.get_or_init(|| Arc::new(DashMap::new()))
 }
 
-pub fn get_config() -> &'static Config {
-    CONFIG.get_or_init(|| match get_cli().config {
-        Some(ref config_path) => Config::load_from_file(config_path.to_str().unwrap()).unwrap(),
-        None => Config::load_from_env(),
+pub fn get_tunnel_traffic() -> &'static TunnelTraffic {
+    TUNNEL_TRAFFIC.get_or_init(TunnelTraffic::default)
+}
+
+fn config_handle() -> &'static ArcSwap<Config> {
+    // `serve` sets it from a config it has validated, so only the tests load it here
+    CONFIG.get_or_init(|| {
+        ArcSwap::from_pointee(Config::load(get_cli()).expect("the config is validated first"))
     })
 }
 
+/// The current configuration, which a reload may replace while we're running
+pub fn get_config() -> Arc<Config> {
+    config_handle().load_full()
+}
+
+/// Swap in a new configuration, returning the previous one
+pub fn set_config(config: Config) -> Arc<Config> {
+    config_handle().swap(Arc::new(config))
+}
+
 pub fn get_auth_db_service() -> &'static crate::auth::NoAuth {
     AUTH_DB_SERVICE.get_or_init(|| crate::auth::NoAuth)
 }
 
+fn error_pages_handle() -> &'static ArcSwap<ErrorPages> {
+    ERROR_PAGES.get_or_init(|| {
+        ArcSwap::from_pointee(ErrorPages::load(get_config().error_pages_dir.as_deref()))
+    })
+}
+
+/// The error page templates, which a reload may replace while we're running
+pub fn get_error_pages() -> Arc<ErrorPages> {
+    error_pages_handle().load_full()
+}
+
+pub fn set_error_pages(pages: ErrorPages) {
+    error_pages_handle().store(Arc::new(pages));
+}
+
+pub fn get_request_log() -> &'static RequestLog {
+    REQUEST_LOG.get_or_init(|| {
+        let config = get_config();
+        RequestLog::new(config.request_log_size, config.capture_size)
+    })
+}
+
+pub fn get_access_log() -> &'static AccessLog {
+    ACCESS_LOG.get_or_init(|| AccessLog::open(&get_config()))
+}
+
+pub fn get_health() -> &'static Health {
+    HEALTH.get_or_init(Health::default)
+}
+
+pub fn get_webhooks() -> &'static Webhooks {
+    WEBHOOKS.get_or_init(|| Webhooks::new(get_config().webhooks.clone()))
+}
+
+pub fn get_usage() -> &'static Usage {
+    USAGE.get_or_init(|| {
+        let retention = chrono::Duration::from_std(get_config().usage_retention)
+            .unwrap_or(chrono::Duration::max_value());
+        Usage::new(retention, get_storage())
+    })
+}
+
+pub fn get_storage() -> &'static dyn storage::Storage {
+    STORAGE
+        .get_or_init(|| storage::open(&get_config()).expect("storage opened before serving"))
+        .as_ref()
+}
+
+pub fn get_alerts() -> &'static Alerts {
+    ALERTS.get_or_init(|| {
+        let config = get_config();
+        Alerts::new(config.alert_window, config.alert_min_requests)
+    })
+}
+
+pub fn get_dns_provider() -> Option<&'static dyn dns::DnsProvider> {
+    DNS_PROVIDER
+        .get_or_init(|| dns::open(&get_config()))
+        .as_deref()
+}
+
+pub fn get_host_registry() -> &'static dyn HostRegistry {
+    HOST_REGISTRY
+        .get_or_init(|| {
+            network::new_registry(&get_config()).expect("host registry opened before serving")
+        })
+        .as_ref()
+}
+
+pub fn get_host_cache() -> &'static HostCache {
+    HOST_CACHE.get_or_init(|| HostCache::new(get_config().host_cache_ttl))
+}
+
+pub fn get_links() -> &'static Links {
+    LINKS.get_or_init(|| Links::new(get_config().instance_links))
+}
+
+pub fn get_buffer_pool() -> &'static BufferPool {
+    BUFFER_POOL.get_or_init(|| {
+        let config = get_config();
+        BufferPool::new(config.buffer_pool_size, config.read_buffer_size)
+    })
+}
+
+pub fn get_gate() -> &'static Gate {
+    GATE.get_or_init(Gate::default)
+}
+
+pub fn get_tasks() -> &'static Tasks {
+    TASKS.get_or_init(Tasks::default)
+}
+
+pub fn get_offline_queues() -> &'static OfflineQueues {
+    OFFLINE_QUEUES.get_or_init(|| OfflineQueues::load(get_config().offline_queue_dir.as_deref()))
+}
+
+pub fn get_response_cache() -> &'static ResponseCache {
+    RESPONSE_CACHE.get_or_init(ResponseCache::default)
+}
+
+pub fn get_share_links() -> &'static ShareLinks {
+    SHARE_LINKS.get_or_init(ShareLinks::default)
+}
+
+pub fn get_agent_updates() -> &'static AgentUpdates {
+    AGENT_UPDATES.get_or_init(AgentUpdates::default)
+}
+
+/// The io_uring threads relaying raw TCP, unless the kernel won't let us have them
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub fn get_uring() -> Option<&'static portal_lib::uring::UringRelay> {
+    URING
+        .get_or_init(|| {
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get() / 2);
+            match portal_lib::uring::UringRelay::start(threads) {
+                Ok(relay) => Some(relay),
+                Err(error) => {
+                    tracing::warn!(%error, "io_uring unavailable, relaying on epoll");
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+pub fn get_generator() -> &'static dyn subdomain::Generator {
+    GENERATOR
+        .get_or_init(|| subdomain::generator(&get_config()))
+        .as_ref()
+}
+
 #[tokio::main]
 async fn main() {
-    // if let Some(config_path) = &CLI.config {
-    //     println!("Value for config: {}", config_path.display());
-    // };
+    let cli = get_cli();
+    let result = match &cli.command {
+        _ if cli.print_config => cli::commands::check_config(true),
+        None | Some(Command::Serve) => serve().await,
+        Some(Command::CheckConfig { print }) => cli::commands::check_config(*print),
+        Some(Command::GenerateKey) => cli::commands::generate_key(),
+        Some(Command::ListClients { url, token, json }) => {
+            cli::commands::list_clients(url.as_deref(), token.as_deref(), *json).await
+        }
+        Some(Command::Broadcast {
+            message,
+            level,
+            url,
+            token,
+        }) => cli::commands::broadcast(message, *level, url.as_deref(), token.as_deref()).await,
+        Some(Command::Migrate) => cli::commands::migrate(),
+    };
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
+
+async fn serve() -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load(get_cli()).map_err(|error| format!("invalid config:\n{}", error))?;
+    let _ = CONFIG.set(ArcSwap::from_pointee(config));
 
     // setup observability
-    let subscriber = registry::Registry::default()
-        .with(LevelFilter::DEBUG)
-        .with(tracing_subscriber::fmt::Layer::default());
-    tracing::subscriber::set_global_default(subscriber).expect("setting global default failed");
+    observability::logging::init(get_cli().log_format);
 
     info!("starting server!");
 
     let config = get_config();
 
-    control_server::spawn(([0, 0, 0, 0], config.control_port));
-    info!(
-        "started portal control server on 0.0.0.0:{}",
-        config.control_port
-    );
+    // open the backends now, so one we can't reach stops us rather than the first tunnel
+    let storage = storage::open(&config)?;
+    tokio::time::timeout(STARTUP_CHECK_TIMEOUT, storage.check())
+        .await
+        .map_err(|_| "timed out reaching the storage".to_string())?
+        .map_err(|error| format!("failed to reach the storage: {}", error))?;
+    let _ = STORAGE.set(storage);
+    let _ = HOST_REGISTRY.set(network::new_registry(&config)?);
+
+    let bind_error = |addr: SocketAddr| move |error| format!("failed to bind {}: {}", addr, error);
+
+    // agents of either transport share the tunnels, so a fleet can move between them
+    control_server::spawn(config.control_addr).map_err(bind_error(config.control_addr))?;
+    info!("started portal control server on {}", config.control_addr);
+
+    if let Some(quic_addr) = config.quic_addr {
+        transport::spawn_quic(quic_addr)
+            .map_err(|error| format!("failed to start the quic control server: {}", error))?;
+    }
+
+    network::spawn(config.internal_network_addr)
+        .map_err(bind_error(config.internal_network_addr))?;
+    info!("start network service on {}", config.internal_network_addr);
 
-    network::spawn(([0, 0, 0, 0, 0, 0, 0, 0], config.internal_network_port));
-    info!(
-        "start network service on [::]:{}",
-        config.internal_network_port
-    );
+    if let Some(metrics_addr) = config.metrics_addr {
+        observability::metrics::spawn(metrics_addr)?;
+        info!("serving metrics on {}", metrics_addr);
+    }
+
+    if let Some(admin_addr) = config.admin_addr {
+        admin::spawn(admin_addr)?;
+        info!("serving admin api on {}", admin_addr);
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_addr) = config.grpc_addr {
+        admin::grpc::spawn(grpc_addr)
+            .map_err(|error| format!("failed to bind {}: {}", grpc_addr, error))?;
+        info!("serving grpc admin api on {}", grpc_addr);
+    }
+
+    active_stream::spawn_reaper();
+    usage::spawn_flusher();
+    get_offline_queues();
+    get_access_log();
+    storage::spawn_cleanup();
+    dns::spawn_provision();
+    offline::spawn_sweeper();
+    network::spawn_registry_refresher();
+    agent_updates::spawn_notifier();
+    drain::spawn_signal_handler();
+    reload::spawn_reload_handler();
 
-    let listen_addr = format!("[::]:{}", config.remote_port);
-    info!("listening on: {}", &listen_addr);
     info!("portal server with hostname: {}", config.portal_host);
 
-    // create our accept any server
-    let listener = TcpListener::bind(listen_addr)
-        .await
-        .expect("failed to bind");
+    // create our accept any servers
+    // each shard's accept loop is a task of its own, so they run on every worker
+    let mut listeners = Vec::with_capacity(config.remote_addrs.len() * config.accept_shards);
+    for listen_addr in &config.remote_addrs {
+        let shards = socket::bind_shards(*listen_addr, config.accept_shards)
+            .map_err(bind_error(*listen_addr))?;
+        info!(
+            "listening on: {} ({} accept loops)",
+            listen_addr,
+            shards.len()
+        );
+        listeners.extend(
+            shards
+                .into_iter()
+                .map(|listener| tokio::spawn(accept_remote(listener))),
+        );
+    }
+    if let Some(tls_addr) = config.tls_addr {
+        let listener = socket::bind(tls_addr).map_err(bind_error(tls_addr))?;
+        info!("passing tls through on: {}", tls_addr);
+        tokio::spawn(accept_visitors(listener, service::accept_tls));
+    }
+    get_health().set_remote_listening(true);
+
+    futures::future::join_all(listeners).await;
+    Ok(())
+}
 
+/// How long we wait on the storage when starting before giving up on it
+const STARTUP_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to stop accepting after it failed, i.e. when we ran out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serve visitors connecting to one of our remote listeners
+async fn accept_remote(listener: TcpListener) {
+    accept_visitors(listener, remote::accept_connection).await
+}
+
+/// Admit visitors connecting to `listener` and serve each with `serve`
+pub async fn accept_visitors<F, Fut>(listener: TcpListener, serve: F)
+where
+    F: Fn(tokio::net::TcpStream, std::net::SocketAddr) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
     loop {
-        let socket = match listener.accept().await {
-            Ok((socket, _)) => socket,
+        let (socket, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
             Err(e) => {
                 error!("failed to accept socket: {:?}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
                 continue;
             }
         };
 
-        info!("accepted connection from: {}", socket.peer_addr().unwrap());
+        // the limits may have been reloaded since
+        let config = get_config();
+        let permit = match get_gate().admit(
+            config.max_connections,
+            config.accept_rate,
+            std::time::Instant::now(),
+        ) {
+            Admission::Admitted(permit) => permit,
+            shed => {
+                admission::shed(socket, &shed);
+                continue;
+            }
+        };
+
+        info!("accepted connection from: {}", peer_addr);
+        if let Err(error) = socket::tune(&socket) {
+            warn!(?error, "failed to set socket options");
+        }
 
-        tokio::spawn(
+        let visitor = serve(socket, peer_addr);
+        get_tasks().spawn(
+            "visitor",
+            get_tasks().token(),
             async move {
-                remote::accept_connection(socket).await;
+                visitor.await;
+                drop(permit);
             }
             .instrument(observability::remote_trace("remote_connect")),
         );
     }
 }
+
+/// Run a connection's task, logging a panic in the task's span rather than only
+/// on stderr. Only the connection it served closes.
+pub async fn catch_panic(task: impl Future<Output = ()>) {
+    if let Err(panic) = AssertUnwindSafe(task).catch_unwind().await {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        error!(panic = message, "connection task panicked");
+    }
+}
@@ -0,0 +1,90 @@
+//! Copies of a tunnel's requests for the tunnels mirroring it, i.e. a new version of a
+//! service tried against live traffic. Their responses are dropped: visitors only ever
+//! see the answers of the tunnel they reached.
+use crate::connected_clients::{ConnectedClient, Connections};
+use crate::http::RequestHead;
+use crate::observability::metrics::get_metrics;
+use crate::{get_config, get_request_log, get_tasks, replay};
+
+/// A request being copied as it's read from the visitor
+#[derive(Debug)]
+struct CopiedRequest {
+    mirrors: Vec<ConnectedClient>,
+    head: RequestHead,
+    /// bytes of the head at the start of `request`
+    len: usize,
+    request: Vec<u8>,
+    too_large: bool,
+}
+
+/// Copies the requests of one visitor stream to the mirrors of its tunnel
+#[derive(Debug, Default)]
+pub struct Mirror {
+    current: Option<CopiedRequest>,
+}
+
+impl Mirror {
+    /// A request head went to `client` as `bytes`, ending the request before it
+    pub fn request(&mut self, client: &ConnectedClient, head: &RequestHead, bytes: &[u8]) {
+        self.end();
+
+        // an upgraded connection isn't made of requests we could copy
+        if head.is_upgrade() {
+            return;
+        }
+        let mirrors = Connections::mirrors_of(client);
+        if mirrors.is_empty() {
+            return;
+        }
+        self.current = Some(CopiedRequest {
+            mirrors,
+            head: head.clone(),
+            len: bytes.len(),
+            request: bytes.to_vec(),
+            too_large: false,
+        });
+    }
+
+    /// Body bytes of the current request went to the agent
+    pub fn body(&mut self, body: &[u8]) {
+        let Some(copy) = &mut self.current else {
+            return;
+        };
+        if copy.request.len() - copy.len + body.len() > get_config().mirror_max_body {
+            copy.too_large = true;
+            copy.request.truncate(copy.len);
+        }
+        if !copy.too_large {
+            copy.request.extend_from_slice(body);
+        }
+    }
+
+    /// The current request is complete, send it on to the mirrors
+    pub fn end(&mut self) {
+        let Some(copy) = self.current.take() else {
+            return;
+        };
+        if copy.too_large {
+            get_metrics().mirrored("too_large");
+            return;
+        }
+
+        for mirror in copy.mirrors {
+            let head = copy.head.clone();
+            let request = copy.request.clone();
+            let len = copy.len;
+            get_tasks().spawn("mirror", mirror.cancel.clone(), async move {
+                match replay::send(&mirror, &head, len, &request).await {
+                    Ok((stream_id, recorded)) => {
+                        get_metrics().mirrored("sent");
+                        get_request_log().record(&mirror, &stream_id, recorded);
+                    }
+                    Err(error) => {
+                        tracing::debug!(tunnel=%mirror.host, %error, "mirrored request failed");
+                        get_metrics().mirrored("failed");
+                    }
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Remember which instance serves a host for a little while
+use super::Instance;
+use crate::ClientId;
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct HostCache {
+    ttl: Option<Duration>,
+    hosts: DashMap<String, (Instance, ClientId, Instant)>,
+}
+
+impl HostCache {
+    /// Keep lookups for `ttl`, `None` disables the cache
+    pub fn new(ttl: Option<Duration>) -> Self {
+        HostCache {
+            ttl,
+            hosts: DashMap::new(),
+        }
+    }
+
+    pub fn get(&self, host: &str) -> Option<(Instance, ClientId)> {
+        let ttl = self.ttl?;
+        let entry = self.hosts.get(host)?;
+        let (instance, client_id, cached_at) = entry.value();
+        if cached_at.elapsed() > ttl {
+            drop(entry);
+            self.hosts.remove(host);
+            return None;
+        }
+        Some((instance.clone(), client_id.clone()))
+    }
+
+    pub fn insert(&self, host: &str, instance: &Instance, client_id: &ClientId) {
+        if self.ttl.is_none() {
+            return;
+        }
+        self.hosts.insert(
+            host.to_string(),
+            (instance.clone(), client_id.clone(), Instant::now()),
+        );
+    }
+
+    pub fn invalidate(&self, host: &str) {
+        if self.hosts.remove(host).is_some() {
+            tracing::debug!(%host, "invalidated cached host");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry() {
+        let instance = Instance {
+            ip: [10, 0, 0, 1].into(),
+        };
+        let client_id = ClientId::generate();
+
+        let cache = HostCache::new(Some(Duration::from_millis(20)));
+        cache.insert("foo", &instance, &client_id);
+        assert_eq!(cache.get("foo").map(|(i, _)| i.ip), Some(instance.ip));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(cache.get("foo").is_none());
+
+        cache.insert("foo", &instance, &client_id);
+        cache.invalidate("foo");
+        assert!(cache.get("foo").is_none());
+
+        let disabled = HostCache::new(None);
+        disabled.insert("foo", &instance, &client_id);
+        assert!(disabled.get("foo").is_none());
+    }
+}
@@ -0,0 +1,177 @@
+//! Find the other instances in a Consul service catalog rather than through DNS
+use super::registry::ask_instances;
+use super::{Error, HostRegistry, Instance};
+use crate::{get_config, ClientId};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// How long Consul may hold a watch open before answering that nothing changed
+const WATCH_WAIT: Duration = Duration::from_secs(60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Registration {
+    #[serde(rename = "ID")]
+    id: String,
+    name: String,
+    address: IpAddr,
+    port: u16,
+    check: Check,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Check {
+    #[serde(rename = "HTTP")]
+    http: String,
+    interval: String,
+    deregister_critical_service_after: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    service: Service,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Service {
+    address: String,
+}
+
+/// Registers us as a service in Consul and watches which instances are passing their checks,
+/// asking each of them whether it serves a host like we do with DNS
+pub struct Consul {
+    client: reqwest::Client,
+    url: String,
+    service: String,
+    /// the address other instances reach us on
+    ip: IpAddr,
+    instances: RwLock<Vec<Instance>>,
+}
+
+impl Consul {
+    pub fn new(url: &str, service: &str, ip: IpAddr) -> Self {
+        Consul {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            service: service.to_string(),
+            ip,
+            instances: RwLock::new(vec![]),
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, format!("{}{}", self.url, path));
+        match &get_config().consul_token {
+            Some(token) => request.header("X-Consul-Token", token),
+            None => request,
+        }
+    }
+
+    /// Add us to the catalog, with a check taking us out of it while we aren't ready
+    async fn register(&self) -> Result<(), Error> {
+        let config = get_config();
+        let network = SocketAddr::new(self.ip, config.internal_network_port);
+        let registration = Registration {
+            id: config.instance_id.clone(),
+            name: self.service.clone(),
+            address: self.ip,
+            port: config.internal_network_port,
+            check: Check {
+                http: format!("http://{}/readyz", network),
+                interval: "10s".to_string(),
+                deregister_critical_service_after: "1m".to_string(),
+            },
+        };
+        self.request(reqwest::Method::PUT, "/v1/agent/service/register")
+            .json(&registration)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// The healthy instances once they differ from those at `index`, and the index they're at
+    async fn healthy_instances(&self, index: u64) -> Result<(Vec<Instance>, u64), Error> {
+        let path = format!("/v1/health/service/{}", self.service);
+        let response = self
+            .request(reqwest::Method::GET, &path)
+            .query(&[
+                ("passing", "true".to_string()),
+                ("index", index.to_string()),
+                ("wait", format!("{}s", WATCH_WAIT.as_secs())),
+            ])
+            .timeout(WATCH_WAIT + RETRY_INTERVAL)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let index = response
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|index| index.to_str().ok())
+            .and_then(|index| index.parse().ok())
+            .unwrap_or_default();
+        let entries: Vec<ServiceEntry> = response.json().await?;
+        let instances = entries
+            .iter()
+            .filter_map(|entry| entry.service.address.parse().ok())
+            .map(|ip| Instance { ip })
+            .collect();
+        Ok((instances, index))
+    }
+
+    async fn watch(&'static self) {
+        while let Err(error) = self.register().await {
+            tracing::error!(%error, "failed to register with consul");
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+        tracing::info!(service=%self.service, "registered with consul");
+
+        let mut index = 0;
+        loop {
+            match self.healthy_instances(index).await {
+                Ok((instances, next)) => {
+                    tracing::debug!(?instances, "consul instances");
+                    *self.instances.write().unwrap() = instances;
+                    // the index going backwards means consul started over
+                    index = if next < index { 0 } else { next };
+                }
+                Err(error) => {
+                    tracing::error!(%error, "failed to watch consul instances");
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl HostRegistry for Consul {
+    async fn publish(&self, _host: &str, _client_id: &ClientId) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn unpublish(&self, _host: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn lookup(&self, host: &str) -> Result<(Instance, ClientId), Error> {
+        let instances = self.instances.read().unwrap().clone();
+        ask_instances(instances, host).await
+    }
+
+    async fn instances(&self) -> Result<Vec<Instance>, Error> {
+        Ok(self.instances.read().unwrap().clone())
+    }
+
+    fn spawn(&'static self) {
+        tokio::spawn(self.watch());
+    }
+}
@@ -0,0 +1,876 @@
+//! SWIM style cluster membership, with the hosts of every instance gossiped along
+//!
+//! Every [`PROBE_INTERVAL`] we ping a member, ask a few others to ping it for us if it
+//! doesn't answer, and suspect it otherwise. Suspects that don't refute within
+//! [`SUSPECT_TIMEOUT`] are declared dead, and their hosts dropped. Membership and host
+//! changes are piggybacked on pings and acks, and members periodically exchange their
+//! whole state to repair whatever gossip got lost.
+use super::{Error, HostRegistry, Instance};
+use crate::admin::constant_time_eq;
+use crate::connected_clients::Connections;
+use crate::{get_config, get_host_cache, ClientId};
+use async_trait::async_trait;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// Wait for a direct ack before asking others to probe
+const PROBE_TIMEOUT: Duration = Duration::from_millis(400);
+/// Members asked to probe one that didn't answer us
+const INDIRECT_PROBES: usize = 3;
+const SUSPECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long we remember dead members, so stale gossip won't bring them back
+const DEAD_RETENTION: Duration = Duration::from_secs(60);
+/// How often we exchange our whole state with a random member
+const SYNC_INTERVAL: Duration = Duration::from_secs(30);
+/// How often we knock on the seeds while we know no other member
+const JOIN_INTERVAL: Duration = Duration::from_secs(5);
+/// Updates piggybacked per message
+const MAX_UPDATES: usize = 32;
+/// Hosts per sync message, keeping each within a datagram
+const SYNC_CHUNK: usize = 128;
+const MAX_DATAGRAM: usize = 65_507;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Member {
+    /// the instance id
+    pub id: String,
+    /// where the member gossips, its ip is the one other instances reach it on
+    pub addr: SocketAddr,
+    /// bumped by the member to refute suspicion of it
+    pub incarnation: u64,
+    pub state: State,
+}
+
+/// Which client serves a host on an instance, `None` once none does
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HostUpdate {
+    host: String,
+    instance_id: String,
+    client_id: Option<ClientId>,
+    /// only the instance itself changes its hosts, so its version orders them
+    version: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Update {
+    Member(Member),
+    Host(HostUpdate),
+}
+
+impl Update {
+    fn is_about(&self, other: &Update) -> bool {
+        match (self, other) {
+            (Update::Member(a), Update::Member(b)) => a.id == b.id,
+            (Update::Host(a), Update::Host(b)) => {
+                a.host == b.host && a.instance_id == b.instance_id
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    Ping {
+        seq: u64,
+        from: Member,
+        updates: Vec<Update>,
+    },
+    /// ping `target` for the sender, and ack with `seq` if it answers
+    PingReq {
+        seq: u64,
+        from: Member,
+        target: SocketAddr,
+        updates: Vec<Update>,
+    },
+    Ack {
+        seq: u64,
+        from: Member,
+        updates: Vec<Update>,
+    },
+    /// (part of) everything the sender knows, answered in kind if `reply` is set
+    Sync {
+        from: Member,
+        members: Vec<Member>,
+        hosts: Vec<HostUpdate>,
+        reply: bool,
+    },
+}
+
+#[derive(Debug)]
+struct Known {
+    member: Member,
+    /// when its state last changed
+    since: Instant,
+}
+
+/// What we know about the cluster, without any networking
+#[derive(Debug)]
+struct Cluster {
+    me: Member,
+    members: HashMap<String, Known>,
+    /// host -> instance id -> the client serving it there and the version of that
+    hosts: HashMap<String, HashMap<String, (Option<ClientId>, u64)>>,
+    /// changes still to gossip, and how many more times
+    queue: Vec<(Update, usize)>,
+}
+
+impl Cluster {
+    fn new(me: Member) -> Self {
+        Cluster {
+            me,
+            members: HashMap::new(),
+            hosts: HashMap::new(),
+            queue: vec![],
+        }
+    }
+
+    /// Members we believe to be up, not counting us
+    fn live_members(&self) -> impl Iterator<Item = &Member> {
+        self.members
+            .values()
+            .map(|known| &known.member)
+            .filter(|member| member.state != State::Dead)
+    }
+
+    /// Gossip every update about `3 * log2(cluster size)` times, enough for it to reach everyone
+    fn retransmits(&self) -> usize {
+        let size = self.live_members().count() + 1;
+        3 * (usize::BITS - size.leading_zeros()) as usize
+    }
+
+    fn enqueue(&mut self, update: Update) {
+        self.queue.retain(|(queued, _)| !queued.is_about(&update));
+        let retransmits = self.retransmits();
+        self.queue.push((update, retransmits));
+    }
+
+    /// The updates to piggyback on the next message, the least gossiped ones first
+    fn take_updates(&mut self) -> Vec<Update> {
+        self.queue
+            .sort_by_key(|(_, remaining)| std::cmp::Reverse(*remaining));
+        let updates = self
+            .queue
+            .iter_mut()
+            .take(MAX_UPDATES)
+            .map(|(update, remaining)| {
+                *remaining -= 1;
+                update.clone()
+            })
+            .collect();
+        self.queue.retain(|(_, remaining)| *remaining > 0);
+        updates
+    }
+
+    /// Returns the hosts whose routing changed
+    fn apply(&mut self, update: Update, now: Instant) -> Vec<String> {
+        match update {
+            Update::Member(member) => self.apply_member(member, now),
+            Update::Host(update) => self.apply_host(update).into_iter().collect(),
+        }
+    }
+
+    fn apply_member(&mut self, member: Member, now: Instant) -> Vec<String> {
+        if member.id == self.me.id {
+            if member.state != State::Alive && member.incarnation >= self.me.incarnation {
+                self.me.incarnation = member.incarnation + 1;
+                tracing::info!(
+                    incarnation = self.me.incarnation,
+                    "refuting suspicion of us"
+                );
+                self.enqueue(Update::Member(self.me.clone()));
+            }
+            return vec![];
+        }
+
+        match self.members.get_mut(&member.id) {
+            None if member.state == State::Dead => return vec![],
+            None => {
+                tracing::info!(member=%member.id, addr=%member.addr, "member joined");
+                self.members.insert(
+                    member.id.clone(),
+                    Known {
+                        member: member.clone(),
+                        since: now,
+                    },
+                );
+            }
+            Some(known) => {
+                let supersedes = member.incarnation > known.member.incarnation
+                    || (member.incarnation == known.member.incarnation
+                        && member.state > known.member.state)
+                    || (member.state == State::Dead && known.member.state != State::Dead);
+                if !supersedes {
+                    return vec![];
+                }
+                if member.state != known.member.state {
+                    tracing::info!(member=%member.id, state=?member.state, "member changed state");
+                    known.since = now;
+                }
+                known.member = member.clone();
+            }
+        }
+
+        let dropped = if member.state == State::Dead {
+            self.drop_hosts_of(&member.id)
+        } else {
+            vec![]
+        };
+        self.enqueue(Update::Member(member));
+        dropped
+    }
+
+    fn apply_host(&mut self, update: HostUpdate) -> Option<String> {
+        // we know our own hosts best
+        if update.instance_id == self.me.id {
+            return None;
+        }
+        let dead = self
+            .members
+            .get(&update.instance_id)
+            .is_some_and(|known| known.member.state == State::Dead);
+        if dead {
+            return None;
+        }
+
+        let instances = self.hosts.entry(update.host.clone()).or_default();
+        if instances
+            .get(&update.instance_id)
+            .is_some_and(|(_, version)| *version >= update.version)
+        {
+            return None;
+        }
+        instances.insert(
+            update.instance_id.clone(),
+            (update.client_id.clone(), update.version),
+        );
+
+        let host = update.host.clone();
+        self.enqueue(Update::Host(update));
+        Some(host)
+    }
+
+    /// Our agents started or stopped serving `host`
+    fn publish(&mut self, host: &str, client_id: Option<ClientId>, version: u64) {
+        self.hosts
+            .entry(host.to_string())
+            .or_default()
+            .insert(self.me.id.clone(), (client_id.clone(), version));
+        self.enqueue(Update::Host(HostUpdate {
+            host: host.to_string(),
+            instance_id: self.me.id.clone(),
+            client_id,
+            version,
+        }));
+    }
+
+    fn drop_hosts_of(&mut self, instance_id: &str) -> Vec<String> {
+        let mut dropped = vec![];
+        self.hosts.retain(|host, instances| {
+            if instances.remove(instance_id).is_some() {
+                dropped.push(host.clone());
+            }
+            !instances.is_empty()
+        });
+        dropped
+    }
+
+    /// The instance serving `host`, where it's reached, and the client serving it there
+    fn lookup(&self, host: &str) -> Option<(String, IpAddr, ClientId)> {
+        self.hosts
+            .get(host)?
+            .iter()
+            .filter_map(|(instance_id, (client_id, _))| {
+                let client_id = client_id.clone()?;
+                if *instance_id == self.me.id {
+                    return Some((instance_id.clone(), self.me.addr.ip(), client_id));
+                }
+                let member = &self.members.get(instance_id)?.member;
+                (member.state != State::Dead)
+                    .then(|| (instance_id.clone(), member.addr.ip(), client_id))
+            })
+            .next()
+    }
+
+    /// Stop trusting a member that didn't answer any probe
+    fn suspect(&mut self, id: &str, now: Instant) {
+        let Some(known) = self.members.get(id) else {
+            return;
+        };
+        if known.member.state != State::Alive {
+            return;
+        }
+        tracing::warn!(member=%id, "member suspected");
+        let member = Member {
+            state: State::Suspect,
+            ..known.member.clone()
+        };
+        self.apply_member(member, now);
+    }
+
+    /// Declare long suspected members dead and forget long dead ones,
+    /// returning the hosts whose routing changed
+    fn expire(&mut self, now: Instant) -> Vec<String> {
+        let timed_out: Vec<Member> = self
+            .members
+            .values()
+            .filter(|known| {
+                known.member.state == State::Suspect
+                    && now.saturating_duration_since(known.since) > SUSPECT_TIMEOUT
+            })
+            .map(|known| Member {
+                state: State::Dead,
+                ..known.member.clone()
+            })
+            .collect();
+
+        let mut dropped = vec![];
+        for member in timed_out {
+            tracing::warn!(member=%member.id, "member declared dead");
+            dropped.extend(self.apply_member(member, now));
+        }
+
+        self.members.retain(|_, known| {
+            known.member.state != State::Dead
+                || now.saturating_duration_since(known.since) <= DEAD_RETENTION
+        });
+        dropped
+    }
+
+    fn snapshot(&self) -> (Vec<Member>, Vec<HostUpdate>) {
+        let members = std::iter::once(self.me.clone())
+            .chain(self.members.values().map(|known| known.member.clone()))
+            .collect();
+        let hosts = self
+            .hosts
+            .iter()
+            .flat_map(|(host, instances)| {
+                instances
+                    .iter()
+                    .map(move |(instance_id, (client_id, version))| HostUpdate {
+                        host: host.clone(),
+                        instance_id: instance_id.clone(),
+                        client_id: client_id.clone(),
+                        version: *version,
+                    })
+            })
+            .collect();
+        (members, hosts)
+    }
+}
+
+/// Find the instance serving a host from what the cluster gossiped
+pub struct Gossip {
+    socket: UdpSocket,
+    cluster: Mutex<Cluster>,
+    /// `host:port` addresses to join the cluster through
+    seeds: Vec<String>,
+    seq: AtomicU64,
+    acks: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+    version: AtomicU64,
+}
+
+impl Gossip {
+    pub fn new(ip: IpAddr, port: u16, seeds: Vec<String>) -> Result<Self, Error> {
+        let bind_ip: IpAddr = if ip.is_ipv4() {
+            [0, 0, 0, 0].into()
+        } else {
+            [0u16; 8].into()
+        };
+        let socket = std::net::UdpSocket::bind(SocketAddr::new(bind_ip, port))?;
+        socket.set_nonblocking(true)?;
+
+        // a restarted instance must supersede what the cluster remembers of it
+        let started = now_millis();
+        let me = Member {
+            id: get_config().instance_id.clone(),
+            addr: SocketAddr::new(ip, port),
+            incarnation: started,
+            state: State::Alive,
+        };
+        Ok(Gossip {
+            socket: UdpSocket::from_std(socket)?,
+            cluster: Mutex::new(Cluster::new(me)),
+            seeds,
+            seq: AtomicU64::new(0),
+            acks: Mutex::new(HashMap::new()),
+            version: AtomicU64::new(started),
+        })
+    }
+
+    fn me(&self) -> Member {
+        self.cluster.lock().unwrap().me.clone()
+    }
+
+    fn updates(&self) -> Vec<Update> {
+        self.cluster.lock().unwrap().take_updates()
+    }
+
+    async fn send(&self, addr: SocketAddr, message: &Message) {
+        let data = match serde_json::to_vec(message) {
+            Ok(data) => data,
+            Err(error) => {
+                tracing::error!(%error, "failed to serialize gossip message");
+                return;
+            }
+        };
+        let data = match &get_config().internal_secret {
+            Some(secret) => {
+                let mut signed = hmac_sha256::HMAC::mac(&data, secret.as_bytes()).to_vec();
+                signed.extend_from_slice(&data);
+                signed
+            }
+            None => data,
+        };
+        if data.len() > MAX_DATAGRAM {
+            tracing::error!(len = data.len(), "gossip message too large");
+            return;
+        }
+        if let Err(error) = self.socket.send_to(&data, addr).await {
+            tracing::debug!(%addr, %error, "failed to send gossip message");
+        }
+    }
+
+    /// Take in what a member told us, invalidating the routes that changed
+    fn learn(&self, from: Member, updates: Vec<Update>) {
+        let now = Instant::now();
+        let changed: Vec<String> = {
+            let mut cluster = self.cluster.lock().unwrap();
+            std::iter::once(Update::Member(from))
+                .chain(updates)
+                .flat_map(|update| cluster.apply(update, now))
+                .collect()
+        };
+        for host in changed {
+            get_host_cache().invalidate(&host);
+        }
+    }
+
+    fn expect_ack(&self) -> (u64, oneshot::Receiver<()>) {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.acks.lock().unwrap().insert(seq, tx);
+        (seq, rx)
+    }
+
+    async fn wait_ack(&self, seq: u64, rx: oneshot::Receiver<()>, timeout: Duration) -> bool {
+        let acked = matches!(tokio::time::timeout(timeout, rx).await, Ok(Ok(())));
+        self.acks.lock().unwrap().remove(&seq);
+        acked
+    }
+
+    async fn ping(&self, target: SocketAddr, timeout: Duration) -> bool {
+        let (seq, rx) = self.expect_ack();
+        let message = Message::Ping {
+            seq,
+            from: self.me(),
+            updates: self.updates(),
+        };
+        self.send(target, &message).await;
+        self.wait_ack(seq, rx, timeout).await
+    }
+
+    /// Ping `target`, through others if it doesn't answer us directly
+    async fn probe(&self, target: &Member) -> bool {
+        if self.ping(target.addr, PROBE_TIMEOUT).await {
+            return true;
+        }
+
+        let relays: Vec<SocketAddr> = {
+            let cluster = self.cluster.lock().unwrap();
+            let others: Vec<_> = cluster
+                .live_members()
+                .filter(|member| member.id != target.id)
+                .map(|member| member.addr)
+                .collect();
+            others
+                .choose_multiple(&mut rand::thread_rng(), INDIRECT_PROBES)
+                .copied()
+                .collect()
+        };
+        if relays.is_empty() {
+            return false;
+        }
+
+        let (seq, rx) = self.expect_ack();
+        for relay in relays {
+            let message = Message::PingReq {
+                seq,
+                from: self.me(),
+                target: target.addr,
+                updates: self.updates(),
+            };
+            self.send(relay, &message).await;
+        }
+        self.wait_ack(seq, rx, PROBE_INTERVAL - PROBE_TIMEOUT).await
+    }
+
+    /// Send everything we know to `addr`, in as many datagrams as it takes
+    async fn sync(&self, addr: SocketAddr, reply: bool) {
+        let (members, hosts) = self.cluster.lock().unwrap().snapshot();
+        let from = self.me();
+        let mut chunks = hosts.chunks(SYNC_CHUNK);
+        let message = Message::Sync {
+            from: from.clone(),
+            members,
+            hosts: chunks.next().unwrap_or_default().to_vec(),
+            reply,
+        };
+        self.send(addr, &message).await;
+        for chunk in chunks {
+            let message = Message::Sync {
+                from: from.clone(),
+                members: vec![],
+                hosts: chunk.to_vec(),
+                reply: false,
+            };
+            self.send(addr, &message).await;
+        }
+    }
+
+    async fn handle(&'static self, message: Message, addr: SocketAddr) {
+        match message {
+            Message::Ping { seq, from, updates } => {
+                self.learn(from, updates);
+                let ack = Message::Ack {
+                    seq,
+                    from: self.me(),
+                    updates: self.updates(),
+                };
+                self.send(addr, &ack).await;
+            }
+            Message::PingReq {
+                seq,
+                from,
+                target,
+                updates,
+            } => {
+                self.learn(from, updates);
+                tokio::spawn(async move {
+                    if self.ping(target, PROBE_TIMEOUT).await {
+                        let ack = Message::Ack {
+                            seq,
+                            from: self.me(),
+                            updates: self.updates(),
+                        };
+                        self.send(addr, &ack).await;
+                    }
+                });
+            }
+            Message::Ack { seq, from, updates } => {
+                self.learn(from, updates);
+                if let Some(tx) = self.acks.lock().unwrap().remove(&seq) {
+                    let _ = tx.send(());
+                }
+            }
+            Message::Sync {
+                from,
+                members,
+                hosts,
+                reply,
+            } => {
+                let updates = members
+                    .into_iter()
+                    .map(Update::Member)
+                    .chain(hosts.into_iter().map(Update::Host))
+                    .collect();
+                self.learn(from, updates);
+                if reply {
+                    self.sync(addr, false).await;
+                }
+            }
+        }
+    }
+
+    async fn receive(&'static self) {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(error) => {
+                    tracing::debug!(%error, "failed to receive gossip message");
+                    continue;
+                }
+            };
+            let Some(data) = verify(&buf[..len]) else {
+                tracing::debug!(%addr, "dropping unsigned gossip message");
+                continue;
+            };
+            match serde_json::from_slice::<Message>(data) {
+                Ok(message) => self.handle(message, addr).await,
+                Err(error) => tracing::debug!(%addr, %error, "invalid gossip message"),
+            }
+        }
+    }
+
+    async fn run_probes(&'static self) {
+        let mut interval = tokio::time::interval(PROBE_INTERVAL);
+        let mut targets: Vec<Member> = vec![];
+        loop {
+            interval.tick().await;
+
+            let dropped = self.cluster.lock().unwrap().expire(Instant::now());
+            for host in dropped {
+                get_host_cache().invalidate(&host);
+            }
+
+            // probe members in a random order, so each is probed within a round
+            if targets.is_empty() {
+                targets = self
+                    .cluster
+                    .lock()
+                    .unwrap()
+                    .live_members()
+                    .cloned()
+                    .collect();
+                targets.shuffle(&mut rand::thread_rng());
+            }
+            let Some(target) = targets.pop() else {
+                continue;
+            };
+
+            if !self.probe(&target).await {
+                self.cluster
+                    .lock()
+                    .unwrap()
+                    .suspect(&target.id, Instant::now());
+            }
+        }
+    }
+
+    /// Where to look for the cluster: our seeds, and every instance found through DNS
+    async fn seed_addrs(&self) -> Vec<SocketAddr> {
+        let port = self.me().addr.port();
+        let mut addrs = vec![];
+        for seed in &self.seeds {
+            match tokio::net::lookup_host(seed).await {
+                Ok(found) => addrs.extend(found),
+                Err(error) => tracing::warn!(%seed, %error, "failed to resolve gossip seed"),
+            }
+        }
+        if get_config().gossip_dns_host.is_some() {
+            match Instance::get_instances().await {
+                Ok(instances) => addrs.extend(
+                    instances
+                        .into_iter()
+                        .map(|instance| SocketAddr::new(instance.ip, port)),
+                ),
+                Err(error) => tracing::warn!(%error, "failed to resolve gossip instances"),
+            }
+        }
+        let me = self.me().addr;
+        addrs.retain(|addr| *addr != me);
+        addrs
+    }
+
+    async fn run_syncs(&'static self) {
+        loop {
+            let peers: Vec<SocketAddr> = self
+                .cluster
+                .lock()
+                .unwrap()
+                .live_members()
+                .map(|member| member.addr)
+                .collect();
+            let peer = peers.choose(&mut rand::thread_rng()).copied();
+
+            let period = match peer {
+                Some(peer) => {
+                    self.sync(peer, true).await;
+                    SYNC_INTERVAL
+                }
+                None => {
+                    for seed in self.seed_addrs().await {
+                        self.sync(seed, true).await;
+                    }
+                    JOIN_INTERVAL
+                }
+            };
+            tokio::time::sleep(period).await;
+        }
+    }
+}
+
+#[async_trait]
+impl HostRegistry for Gossip {
+    async fn publish(&self, host: &str, client_id: &ClientId) -> Result<(), Error> {
+        let version = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+        self.cluster
+            .lock()
+            .unwrap()
+            .publish(host, Some(client_id.clone()), version);
+        Ok(())
+    }
+
+    async fn unpublish(&self, host: &str) -> Result<(), Error> {
+        let version = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+        self.cluster.lock().unwrap().publish(host, None, version);
+        Ok(())
+    }
+
+    async fn lookup(&self, host: &str) -> Result<(Instance, ClientId), Error> {
+        let (instance_id, ip, client_id) = self
+            .cluster
+            .lock()
+            .unwrap()
+            .lookup(host)
+            .ok_or(Error::DoesNotServeHost)?;
+
+        // we may have lost the host since we gossiped it
+        if instance_id == get_config().instance_id {
+            let client_id =
+                Connections::client_for_host(&host.to_string()).ok_or(Error::DoesNotServeHost)?;
+            return Ok((Instance { ip }, client_id));
+        }
+
+        Ok((Instance { ip }, client_id))
+    }
+
+    async fn instances(&self) -> Result<Vec<Instance>, Error> {
+        let cluster = self.cluster.lock().unwrap();
+        Ok(cluster
+            .live_members()
+            .map(|member| Instance {
+                ip: member.addr.ip(),
+            })
+            .chain(std::iter::once(Instance {
+                ip: cluster.me.addr.ip(),
+            }))
+            .collect())
+    }
+
+    fn members(&self) -> Option<Vec<Member>> {
+        let cluster = self.cluster.lock().unwrap();
+        Some(
+            cluster
+                .live_members()
+                .cloned()
+                .chain(std::iter::once(cluster.me.clone()))
+                .collect(),
+        )
+    }
+
+    fn spawn(&'static self) {
+        tracing::info!(addr=%self.me().addr, "gossiping cluster membership");
+        tokio::spawn(self.receive());
+        tokio::spawn(self.run_probes());
+        tokio::spawn(self.run_syncs());
+    }
+}
+
+/// The message in a datagram, if it was signed with our internal secret when we have one
+fn verify(datagram: &[u8]) -> Option<&[u8]> {
+    let Some(secret) = &get_config().internal_secret else {
+        return Some(datagram);
+    };
+    if datagram.len() < 32 {
+        return None;
+    }
+    let (mac, data) = datagram.split_at(32);
+    let expected = hmac_sha256::HMAC::mac(data, secret.as_bytes());
+    constant_time_eq(mac, expected).then_some(data)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(id: &str, incarnation: u64, state: State) -> Member {
+        Member {
+            id: id.to_string(),
+            addr: SocketAddr::new([10, 0, 0, id.len() as u8].into(), 7946),
+            incarnation,
+            state,
+        }
+    }
+
+    #[test]
+    fn test_membership() {
+        let now = Instant::now();
+        let mut cluster = Cluster::new(member("me", 1, State::Alive));
+
+        // others suspecting us get refuted
+        cluster.apply(Update::Member(member("me", 1, State::Suspect)), now);
+        assert_eq!(cluster.me.incarnation, 2);
+        assert!(matches!(&cluster.queue[0].0, Update::Member(m) if m.id == "me"));
+
+        let client_id = ClientId::generate();
+        cluster.apply(Update::Member(member("other", 1, State::Alive)), now);
+        let changed = cluster.apply(
+            Update::Host(HostUpdate {
+                host: "foo".into(),
+                instance_id: "other".into(),
+                client_id: Some(client_id.clone()),
+                version: 2,
+            }),
+            now,
+        );
+        assert_eq!(changed, vec!["foo".to_string()]);
+        assert_eq!(cluster.lookup("foo").unwrap().2, client_id);
+
+        // older news is ignored
+        let changed = cluster.apply(
+            Update::Host(HostUpdate {
+                host: "foo".into(),
+                instance_id: "other".into(),
+                client_id: None,
+                version: 1,
+            }),
+            now,
+        );
+        assert!(changed.is_empty());
+        assert!(cluster.lookup("foo").is_some());
+
+        // a suspect that stays silent dies, taking its hosts with it
+        cluster.suspect("other", now);
+        assert!(cluster.expire(now).is_empty());
+        assert_eq!(
+            cluster.expire(now + SUSPECT_TIMEOUT * 2),
+            vec!["foo".to_string()]
+        );
+        assert!(cluster.lookup("foo").is_none());
+
+        // but may come back with a new incarnation
+        cluster.apply(Update::Member(member("other", 2, State::Alive)), now);
+        assert_eq!(cluster.live_members().count(), 1);
+    }
+
+    #[test]
+    fn test_retransmits() {
+        let mut cluster = Cluster::new(member("me", 1, State::Alive));
+        cluster.publish("foo", Some(ClientId::generate()), 1);
+        cluster.publish("foo", None, 2);
+        assert_eq!(cluster.queue.len(), 1);
+
+        let retransmits = cluster.retransmits();
+        for _ in 0..retransmits {
+            assert_eq!(cluster.take_updates().len(), 1);
+        }
+        assert!(cluster.take_updates().is_empty());
+    }
+}
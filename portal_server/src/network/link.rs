@@ -0,0 +1,351 @@
+//! Long lived websocket links between instances, multiplexing the visitor streams
+//! one instance proxies to another rather than dialing it for each of them
+use super::Error;
+use crate::deadline::Deadline;
+use crate::error_page::ErrorPage;
+use crate::observability::metrics::get_metrics;
+use crate::tasks::CancellationToken;
+use crate::{get_buffer_pool, get_config, get_tasks};
+use bytes::Bytes;
+use dashmap::DashMap;
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use portal_lib::{ControlPacket, StreamId};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, AUTHORIZATION};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Streams on a link before we'd rather open another one
+const STREAMS_PER_LINK: usize = 64;
+
+/// What the other end sent for a stream
+#[derive(Debug)]
+enum Incoming {
+    Data(Bytes),
+    End,
+    Refused,
+}
+
+/// The visitor behind a stream we opened, answered with an error page when the
+/// other end can't serve them
+struct Visitor<'a> {
+    hostname: &'a str,
+    deadline: Deadline,
+}
+
+/// One websocket to another instance, either end of it
+pub struct Link {
+    out: UnboundedSender<ControlPacket>,
+    streams: DashMap<StreamId, UnboundedSender<Incoming>>,
+    closed: AtomicBool,
+    /// cancelled once the websocket closed, ending the tasks serving the link
+    cancel: CancellationToken,
+}
+
+impl Link {
+    /// Relay packets over a websocket until it closes, serving the streams the other end
+    /// opens on our remote listener if `accept` is set
+    fn start<I, O>(incoming: I, mut outgoing: O, accept: bool) -> Arc<Link>
+    where
+        I: Stream<Item = Vec<u8>> + Send + Unpin + 'static,
+        O: Sink<Vec<u8>> + Send + Unpin + 'static,
+    {
+        let (out, mut rx) = unbounded_channel::<ControlPacket>();
+        let link = Arc::new(Link {
+            out,
+            streams: DashMap::new(),
+            closed: AtomicBool::new(false),
+            cancel: get_tasks().token(),
+        });
+
+        get_tasks().spawn("link_writer", link.cancel.clone(), async move {
+            while let Some(packet) = rx.recv().await {
+                if outgoing.send(packet.serialize()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let reader_link = link.clone();
+        get_tasks().spawn("link_reader", link.cancel.clone(), async move {
+            let link = reader_link;
+            let mut incoming = incoming;
+            while let Some(data) = incoming.next().await {
+                let packet = match ControlPacket::deserialize(data) {
+                    Ok(packet) => packet,
+                    Err(error) => {
+                        tracing::warn!(%error, "invalid packet on instance link");
+                        continue;
+                    }
+                };
+                match packet {
+                    ControlPacket::Init(stream_id) if accept => {
+                        // data may follow before we connected, so we take it right away
+                        let incoming = link.register(stream_id.clone());
+                        get_tasks().spawn(
+                            "link_stream",
+                            link.cancel.child_token(),
+                            serve_stream(link.clone(), stream_id, incoming),
+                        );
+                    }
+                    ControlPacket::Data(stream_id, data) => {
+                        link.deliver(&stream_id, Incoming::Data(data))
+                    }
+                    ControlPacket::End(stream_id) => link.deliver(&stream_id, Incoming::End),
+                    ControlPacket::Refused(stream_id) => {
+                        link.deliver(&stream_id, Incoming::Refused)
+                    }
+                    other => {
+                        tracing::debug!(
+                            packet = other.packet_type(),
+                            "ignoring packet on instance link"
+                        )
+                    }
+                }
+            }
+
+            // the streams notice their link is gone once they stop hearing from it
+            link.closed.store(true, Ordering::SeqCst);
+            link.streams.clear();
+            link.cancel.cancel();
+            tracing::debug!("instance link closed");
+        });
+
+        link
+    }
+
+    fn deliver(&self, stream_id: &StreamId, incoming: Incoming) {
+        if let Some(stream) = self.streams.get(stream_id) {
+            let _ = stream.send(incoming);
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn register(&self, stream_id: StreamId) -> UnboundedReceiver<Incoming> {
+        let (tx, rx) = unbounded_channel();
+        self.streams.insert(stream_id, tx);
+        rx
+    }
+
+    /// Relay `socket` over this link until both sides are done with it,
+    /// answering the `visitor` if the other end can't take it or doesn't in time
+    async fn relay(
+        self: Arc<Self>,
+        stream_id: StreamId,
+        socket: TcpStream,
+        mut incoming: UnboundedReceiver<Incoming>,
+        visitor: Option<Visitor<'_>>,
+    ) {
+        let (mut read, mut write) = socket.into_split();
+
+        let link = self.clone();
+        let upstream_id = stream_id.clone();
+        let upstream_cancel = self.cancel.child_token();
+        let upstream = get_tasks().spawn("link_upstream", upstream_cancel.clone(), async move {
+            let mut buf = get_buffer_pool().get();
+            loop {
+                buf.reserve();
+                match read.read_buf(&mut *buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                };
+                let packet = ControlPacket::Data(upstream_id.clone(), buf.split().freeze());
+                if link.out.send(packet).is_err() {
+                    return;
+                }
+            }
+            let _ = link.out.send(ControlPacket::End(upstream_id));
+        });
+
+        let mut received = false;
+        let finished = loop {
+            let next = match &visitor {
+                Some(visitor) if !received => visitor.deadline.run(incoming.recv()).await,
+                _ => Ok(incoming.recv().await),
+            };
+            let Ok(next) = next else {
+                tracing::error!(?stream_id, "instance didn't respond in time");
+                get_metrics().routing_error("timeout");
+                if let Some(visitor) = &visitor {
+                    let page = ErrorPage::GatewayTimeout.response(visitor.hostname);
+                    let _ = write.write_all(&page).await;
+                }
+                break false;
+            };
+            match next {
+                Some(Incoming::Data(data)) => {
+                    received = true;
+                    if write.write_all(&data).await.is_err() {
+                        break false;
+                    }
+                }
+                Some(Incoming::End) => break true,
+                Some(Incoming::Refused) | None => {
+                    if let (false, Some(visitor)) = (received, &visitor) {
+                        let page = ErrorPage::ErrorProxyingTunnel.response(visitor.hostname);
+                        let _ = write.write_all(&page).await;
+                    }
+                    break false;
+                }
+            }
+        };
+        let _ = write.shutdown().await;
+
+        // the other end is done writing, but we may still be sending to it
+        if finished {
+            let _ = upstream.await;
+        } else {
+            upstream_cancel.cancel();
+            let _ = self.out.send(ControlPacket::End(stream_id.clone()));
+        }
+        self.streams.remove(&stream_id);
+    }
+}
+
+/// Open a stream the other end of the link asked for on our own remote listener,
+/// so it's served just like a visitor connecting to us
+async fn serve_stream(link: Arc<Link>, stream_id: StreamId, incoming: UnboundedReceiver<Incoming>) {
+    match crate::socket::connect(get_config().local_remote_addr()).await {
+        Ok(socket) => link.relay(stream_id, socket, incoming, None).await,
+        Err(error) => {
+            tracing::error!(%error, "failed to open linked stream on our remote listener");
+            link.streams.remove(&stream_id);
+            let _ = link.out.send(ControlPacket::Refused(stream_id));
+        }
+    }
+}
+
+/// Serve the link another instance opened to our network service
+pub async fn accept(websocket: warp::ws::WebSocket) {
+    let (sink, stream) = websocket.split();
+    let incoming = stream
+        .take_while(|message| future::ready(message.is_ok()))
+        .filter_map(|message| {
+            future::ready(
+                message
+                    .ok()
+                    .filter(|message| message.is_binary())
+                    .map(|message| message.into_bytes()),
+            )
+        });
+    let outgoing =
+        sink.with(|data: Vec<u8>| future::ok::<_, warp::Error>(warp::ws::Message::binary(data)));
+    tracing::debug!("accepted instance link");
+    Link::start(Box::pin(incoming), Box::pin(outgoing), true);
+}
+
+/// Pooled links to every instance we proxy to
+pub struct Links {
+    size: usize,
+    pools: DashMap<IpAddr, Vec<Arc<Link>>>,
+}
+
+impl Links {
+    pub fn new(size: usize) -> Self {
+        Links {
+            size,
+            pools: DashMap::new(),
+        }
+    }
+
+    /// Relay a visitor stream to the instance at `ip` after sending it `header`, the stream
+    /// having been answered with `error_page` if the instance couldn't take it
+    pub async fn proxy(
+        &self,
+        ip: IpAddr,
+        socket: TcpStream,
+        header: Option<Vec<u8>>,
+        hostname: &str,
+        deadline: Deadline,
+    ) -> Result<(), Error> {
+        let link = self.link(ip).await?;
+        let stream_id = StreamId::generate();
+        let incoming = link.register(stream_id.clone());
+        if link
+            .out
+            .send(ControlPacket::Init(stream_id.clone()))
+            .is_err()
+        {
+            link.streams.remove(&stream_id);
+            return Err(Error::LinkClosed);
+        }
+        if let Some(header) = header {
+            let _ = link
+                .out
+                .send(ControlPacket::Data(stream_id.clone(), header.into()));
+        }
+        let visitor = Visitor { hostname, deadline };
+        link.relay(stream_id, socket, incoming, Some(visitor)).await;
+        Ok(())
+    }
+
+    /// The least busy link to `ip`, opening another one while the pool isn't full
+    async fn link(&self, ip: IpAddr) -> Result<Arc<Link>, Error> {
+        let least_busy = {
+            let mut pool = self.pools.entry(ip).or_default();
+            pool.retain(|link| !link.is_closed());
+            let least_busy = pool.iter().min_by_key(|link| link.streams.len()).cloned();
+            match least_busy {
+                Some(link) if link.streams.len() < STREAMS_PER_LINK || pool.len() >= self.size => {
+                    return Ok(link)
+                }
+                other => other,
+            }
+        };
+
+        match self.connect(ip).await {
+            Ok(link) => {
+                self.pools.entry(ip).or_default().push(link.clone());
+                Ok(link)
+            }
+            // a busy link beats none at all
+            Err(error) => least_busy.ok_or(error),
+        }
+    }
+
+    async fn connect(&self, ip: IpAddr) -> Result<Arc<Link>, Error> {
+        let addr = SocketAddr::new(ip, get_config().internal_network_port);
+        let mut request = format!("ws://{}/link", addr)
+            .into_client_request()
+            .map_err(Box::new)?;
+        if let Some(authorization) = super::internal_authorization() {
+            let value = HeaderValue::from_str(&authorization).map_err(|_| Error::LinkClosed)?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+        let (websocket, _) = tokio::time::timeout(CONNECT_TIMEOUT, async {
+            let stream = crate::socket::connect(addr).await?;
+            tokio_tungstenite::client_async(request, stream).await
+        })
+        .await
+        .map_err(|_| Error::LinkTimeout)?
+        .map_err(Box::new)?;
+        tracing::debug!(%addr, "opened instance link");
+
+        let (sink, stream) = websocket.split();
+        let incoming = stream
+            .take_while(|message| future::ready(message.is_ok()))
+            .filter_map(|message| {
+                future::ready(
+                    message
+                        .ok()
+                        .filter(|message| message.is_binary())
+                        .map(|message| message.into_data()),
+                )
+            });
+        let outgoing = sink.with(|data: Vec<u8>| {
+            future::ok::<_, tokio_tungstenite::tungstenite::Error>(
+                tokio_tungstenite::tungstenite::Message::binary(data),
+            )
+        });
+        Ok(Link::start(Box::pin(incoming), Box::pin(outgoing), false))
+    }
+}
@@ -1,13 +1,28 @@
-use futures::future::select_ok;
-use futures::FutureExt;
 use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 use thiserror::Error;
 mod server;
 pub use self::server::spawn;
 mod proxy;
 pub use self::proxy::proxy_stream;
+mod registry;
+pub use self::registry::*;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisRegistry;
+mod cache;
+pub use self::cache::HostCache;
+mod gossip;
+pub use self::gossip::{Gossip, Member};
+mod link;
+pub use self::link::Links;
+mod consul;
+mod placement;
+pub use self::consul::Consul;
+use crate::connected_clients::Connections;
 use crate::network::server::{HostQuery, HostQueryResponse};
-use crate::{get_config, ClientId};
+use crate::{get_config, get_host_cache, get_host_registry, ClientId, Config};
 use reqwest::StatusCode;
 use trust_dns_resolver::TokioAsyncResolver;
 
@@ -22,10 +37,32 @@ pub enum Error {
     #[error("ResolverError: {0}")]
     Resolver(#[from] trust_dns_resolver::error::ResolveError),
 
+    #[cfg(feature = "redis")]
+    #[error("RedisError: {0}")]
+    Redis(#[from] ::redis::RedisError),
+
+    #[error("WebSocketError: {0}")]
+    WebSocket(#[from] Box<tokio_tungstenite::tungstenite::Error>),
+
+    #[error("Timed out linking to instance")]
+    LinkTimeout,
+
+    #[error("Instance link closed")]
+    LinkClosed,
+
     #[error("Does not serve host")]
     DoesNotServeHost,
 }
 
+/// An instance serving the host we asked about
+#[derive(Debug)]
+pub struct HostAnswer {
+    pub instance: Instance,
+    pub client_id: ClientId,
+    /// where the instance runs, if it told us
+    pub region: Option<String>,
+}
+
 /// An instance of our server
 #[derive(Debug, Clone)]
 pub struct Instance {
@@ -53,23 +90,63 @@ impl Instance {
         Ok(instances)
     }
 
-    /// query the instance and see if it runs our host
-    async fn serves_host(self, host: &str) -> Result<(Instance, ClientId), Error> {
+    /// query the instance and see if it runs our host, retrying queries that fail
+    /// rather than being answered
+    async fn serves_host(self, host: &str) -> Result<HostAnswer, Error> {
+        let config = get_config();
+        let mut attempt = 0;
+        loop {
+            match self.clone().hedged_query(host).await {
+                Err(Error::DoesNotServeHost) => return Err(Error::DoesNotServeHost),
+                Err(error) if attempt < config.host_query_retries => {
+                    let delay = retry_delay(attempt, &mut rand::thread_rng());
+                    tracing::debug!(ip=%self.ip, %error, ?delay, "retrying host query");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// query the instance, asking again should it take longer than the hedge delay
+    /// and going with whichever answer comes first
+    async fn hedged_query(self, host: &str) -> Result<HostAnswer, Error> {
+        let Some(hedge) = get_config().host_query_hedge else {
+            return self.query_host(host).await;
+        };
+
+        let mut first = Box::pin(self.clone().query_host(host));
+        match futures::future::select(&mut first, Box::pin(tokio::time::sleep(hedge))).await {
+            futures::future::Either::Left((result, _)) => result,
+            futures::future::Either::Right(_) => {
+                tracing::debug!(ip=%self.ip, "hedging slow host query");
+                let second = Box::pin(self.query_host(host));
+                Ok(futures::future::select_ok([first, second]).await?.0)
+            }
+        }
+    }
+
+    async fn query_host(self, host: &str) -> Result<HostAnswer, Error> {
         let addr = SocketAddr::new(self.ip, get_config().internal_network_port);
         let url = format!("http://{}", addr);
-        let client = reqwest::Client::new();
-        let response = client
+        let client = crate::socket::http_client();
+        let request = client
             .get(url)
-            .timeout(std::time::Duration::from_secs(2))
+            .timeout(get_config().host_query_timeout)
             .query(&HostQuery {
                 host: host.to_string(),
-            })
-            .send()
-            .await
-            .map_err(|e| {
-                tracing::error!(error=?e, "failed to send a host query");
-                e
-            })?;
+            });
+        let request = authorize(request);
+        let request = crate::observability::otel::trace_headers()
+            .into_iter()
+            .fold(request, |request, (name, value)| {
+                request.header(name, value)
+            });
+        let response = request.send().await.map_err(|e| {
+            tracing::error!(error=?e, "failed to send a host query");
+            e
+        })?;
         let status = response.status();
         let result: HostQueryResponse = response.json().await?;
 
@@ -78,28 +155,197 @@ impl Instance {
             .as_ref()
             .map(|c| c.to_string())
             .unwrap_or_default();
-        tracing::debug!(status=%status, found=%found_client, "got net svc response");
+        tracing::debug!(status=%status, found=%found_client, draining=result.draining, "got net svc response");
 
         match (status, result.client_id) {
-            (StatusCode::OK, Some(client_id)) => Ok((self, client_id)),
+            (StatusCode::OK, Some(client_id)) => Ok(HostAnswer {
+                instance: self,
+                client_id,
+                region: result.region,
+            }),
             _ => Err(Error::DoesNotServeHost),
         }
     }
 }
 
+/// The host registry chosen by our config: redis if configured, gossip if it has a port,
+/// asking every instance found through DNS otherwise
+pub fn new_registry(config: &Config) -> Result<Box<dyn HostRegistry>, String> {
+    let instance_ip = || {
+        config
+            .instance_ip
+            .ok_or_else(|| "a shared host registry needs the instance ip set".to_string())
+    };
+
+    #[cfg(feature = "redis")]
+    if let Some(url) = &config.redis_url {
+        return match RedisRegistry::new(url, instance_ip()?) {
+            Ok(registry) => Ok(Box::new(registry)),
+            Err(error) => Err(format!("invalid redis url: {}", error)),
+        };
+    }
+    #[cfg(not(feature = "redis"))]
+    if config.redis_url.is_some() {
+        return Err("redis_url needs a server built with the redis feature".to_string());
+    }
+
+    if let Some(port) = config.gossip_port {
+        return match Gossip::new(instance_ip()?, port, config.gossip_seeds.clone()) {
+            Ok(registry) => Ok(Box::new(registry)),
+            Err(error) => Err(format!(
+                "failed to start gossip on port {}: {}",
+                port, error
+            )),
+        };
+    }
+
+    if let Some(url) = &config.consul_url {
+        return Ok(Box::new(Consul::new(
+            url,
+            &config.consul_service,
+            instance_ip()?,
+        )));
+    }
+
+    Ok(Box::new(DnsGossip))
+}
+
+/// Announce a host an agent of ours started serving
+pub fn publish_host(host: String, client_id: ClientId) {
+    tokio::spawn(async move {
+        if let Err(error) = get_host_registry().publish(&host, &client_id).await {
+            tracing::error!(%host, %error, "failed to publish host");
+        }
+    });
+}
+
+/// Withdraw a host none of our agents serve anymore
+pub fn unpublish_host(host: String) {
+    tokio::spawn(async move {
+        if let Err(error) = get_host_registry().unpublish(&host).await {
+            tracing::error!(%host, %error, "failed to unpublish host");
+        }
+    });
+}
+
+/// Start the registry, and keep publishing our hosts if it forgets them otherwise
+pub fn spawn_registry_refresher() {
+    get_host_registry().spawn();
+
+    let Some(period) = get_host_registry().refresh_interval() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            for (host, client_id) in Connections::hosts() {
+                if let Err(error) = get_host_registry().publish(&host, &client_id).await {
+                    tracing::error!(%host, %error, "failed to refresh host");
+                }
+            }
+        }
+    });
+}
+
+/// Back off exponentially from 50ms between failed host queries, with jitter so
+/// instances don't retry in lockstep
+fn retry_delay(attempt: u32, rng: &mut impl rand::Rng) -> Duration {
+    let base = Duration::from_millis(50) * 2u32.saturating_pow(attempt.min(6));
+    base.mul_f64(rng.gen_range(0.5..=1.0))
+}
+
+/// The `Authorization` header the other instances' network services expect from us
+pub fn internal_authorization() -> Option<String> {
+    get_config()
+        .internal_secret
+        .as_ref()
+        .map(|secret| format!("Bearer {}", secret))
+}
+
+fn authorize(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match internal_authorization() {
+        Some(authorization) => request.header(reqwest::header::AUTHORIZATION, authorization),
+        None => request,
+    }
+}
+
+/// The instance `host` belongs on, if we place subdomains by hashing and know the members
+pub fn placed_instance(host: &str) -> Option<Member> {
+    if !get_config().consistent_hashing {
+        return None;
+    }
+    let members = get_host_registry().members()?;
+    placement::owner(host, &members).cloned()
+}
+
+/// Whether a stream from `ip` was proxied to us by another instance, which already
+/// placed it, so we don't send it back when our views of the cluster disagree
+pub fn from_instance(ip: IpAddr) -> bool {
+    ip.is_loopback()
+        || get_host_registry()
+            .members()
+            .is_some_and(|members| members.iter().any(|member| member.addr.ip() == ip))
+}
+
 /// get the ip address we need to connect to that runs our host
 #[tracing::instrument]
 pub async fn instance_for_host(host: &str) -> Result<(Instance, ClientId), Error> {
-    let instances = Instance::get_instances()
-        .await?
-        .into_iter()
-        .map(|i| i.serves_host(host).boxed());
-
-    if instances.len() == 0 {
-        return Err(Error::DoesNotServeHost);
+    if let Some(instance) = get_host_cache().get(host) {
+        tracing::debug!(instance_ip=%instance.0.ip, subdomain=%host, "cached instance for host");
+        return Ok(instance);
     }
 
-    let instance = select_ok(instances).await?.0;
+    let instance = get_host_registry().lookup(host).await?;
     tracing::info!(instance_ip=%instance.0.ip, client_id=%instance.1.to_string(), subdomain=%host, "found instance for host");
+    get_host_cache().insert(host, &instance.0, &instance.1);
     Ok(instance)
 }
+
+/// Tell every instance to forget where `host` is served, i.e. because we stopped serving it
+pub fn broadcast_invalidate(host: String) {
+    if get_config().host_cache_ttl.is_none() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let instances = match get_host_registry().instances().await {
+            Ok(instances) => instances,
+            Err(error) => {
+                tracing::error!(%host, %error, "failed to find instances to invalidate host");
+                return;
+            }
+        };
+
+        let client = crate::socket::http_client();
+        let requests = instances.into_iter().map(|instance| {
+            let addr = SocketAddr::new(instance.ip, get_config().internal_network_port);
+            let request = client
+                .post(format!("http://{}/invalidate", addr))
+                .timeout(get_config().host_query_timeout)
+                .query(&HostQuery { host: host.clone() });
+            authorize(request).send()
+        });
+        for result in futures::future::join_all(requests).await {
+            if let Err(error) = result {
+                tracing::warn!(%host, %error, "failed to invalidate host on instance");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        let mut rng = rand::thread_rng();
+        for attempt in 0..10 {
+            let delay = retry_delay(attempt, &mut rng);
+            let base = Duration::from_millis(50) * 2u32.pow(attempt.min(6));
+            assert!(delay >= base / 2 && delay <= base);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Place subdomains on instances by rendezvous hashing over the cluster membership,
+//! so every instance agrees where a subdomain belongs without asking any other
+use super::Member;
+use sha2::{Digest, Sha256};
+
+/// The member `host` belongs on: the one scoring highest for it. A member joining or
+/// leaving only moves the hosts it wins or held.
+pub fn owner<'a>(host: &str, members: &'a [Member]) -> Option<&'a Member> {
+    members.iter().max_by_key(|member| score(host, &member.id))
+}
+
+fn score(host: &str, instance_id: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(instance_id)
+        .chain_update([0])
+        .chain_update(host)
+        .finalize();
+    let mut score = [0u8; 8];
+    score.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::gossip::State;
+
+    #[test]
+    fn test_owner_stability() {
+        let members: Vec<Member> = (0..5)
+            .map(|i| Member {
+                id: format!("instance-{}", i),
+                addr: ([10, 0, 0, i as u8], 7946).into(),
+                incarnation: 0,
+                state: State::Alive,
+            })
+            .collect();
+        let hosts: Vec<String> = (0..200).map(|i| format!("host{}", i)).collect();
+        let owners: Vec<&str> = hosts
+            .iter()
+            .map(|host| owner(host, &members).unwrap().id.as_str())
+            .collect();
+
+        // every member gets some hosts
+        for member in &members {
+            assert!(owners.contains(&member.id.as_str()));
+        }
+
+        // only the hosts of a member that left move
+        let remaining = &members[1..];
+        for (host, previous) in hosts.iter().zip(&owners) {
+            let now = owner(host, remaining).unwrap().id.as_str();
+            if *previous != "instance-0" {
+                assert_eq!(now, *previous);
+            }
+        }
+
+        assert!(owner("foo", &[]).is_none());
+    }
+}
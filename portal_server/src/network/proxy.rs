@@ -1,29 +1,124 @@
-use crate::get_config;
+use crate::deadline::{Deadline, Expired};
+use crate::error_page::ErrorPage;
 use crate::network::Instance;
+use crate::observability::metrics::get_metrics;
+use crate::proxy_protocol;
+use crate::{get_buffer_pool, get_config, get_host_cache, get_links};
 use std::net::SocketAddr;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
-const HTTP_ERROR_PROXYING_TUNNEL_RESPONSE: &[u8] =
-    b"HTTP/1.1 500\r\nContent-Length: 28\r\n\r\nError: Error proxying tunnel";
+/// Hand a public connection from `visitor` for `host` to the instance serving it,
+/// answering a 504 if it doesn't start responding before `deadline`
+pub async fn proxy_stream(
+    instance: Instance,
+    mut stream: TcpStream,
+    visitor: SocketAddr,
+    host: &str,
+    hostname: &str,
+    deadline: Deadline,
+) {
+    // the other instance expects a header too, we keep who the visitor is in it
+    let header = get_config().proxy_protocol.then(|| {
+        let destination = stream.local_addr().unwrap_or(get_config().remote_addrs[0]);
+        proxy_protocol::header_v1(visitor, destination)
+    });
+
+    if get_config().instance_links > 0 {
+        if let Err(error) = get_links()
+            .proxy(instance.ip, stream, header, hostname, deadline)
+            .await
+        {
+            tracing::error!(?error, "Error linking to instance");
+            get_host_cache().invalidate(host);
+            get_metrics().routing_error("proxy_failed");
+        }
+        return;
+    }
 
-pub async fn proxy_stream(instance: Instance, mut stream: TcpStream) {
     let addr = SocketAddr::new(instance.ip, get_config().remote_port);
-    let mut instance = match TcpStream::connect(addr).await {
-        Ok(stream) => stream,
-        Err(error) => {
+    let mut instance = match deadline.run(crate::socket::connect(addr)).await {
+        Ok(Ok(stream)) => stream,
+        Err(Expired) => {
+            tracing::error!(%addr, "timed out connecting to instance");
+            get_metrics().routing_error("timeout");
+            let _ = stream
+                .write_all(&ErrorPage::GatewayTimeout.response(hostname))
+                .await;
+            return;
+        }
+        Ok(Err(error)) => {
             tracing::error!(?error, "Error connecting to instance");
-            let _ = stream.write_all(HTTP_ERROR_PROXYING_TUNNEL_RESPONSE).await;
+            // the instance may be gone, don't send the next connection there too
+            get_host_cache().invalidate(host);
+            get_metrics().routing_error("proxy_failed");
+            let _ = stream
+                .write_all(&ErrorPage::ErrorProxyingTunnel.response(hostname))
+                .await;
             return;
         }
     };
 
-    let (mut i_read, mut i_write) = instance.split();
-    let (mut r_read, mut r_write) = stream.split();
+    if let Some(header) = header {
+        if let Err(error) = instance.write_all(&header).await {
+            tracing::error!(?error, "Error writing proxy protocol header to instance");
+            return;
+        }
+    }
+
+    // the other instance answers a stuck agent itself, but may be stuck too
+    match deadline
+        .run(until_answered(&mut stream, &mut instance))
+        .await
+    {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => {
+            tracing::debug!(?error, "instance stream closed");
+            return;
+        }
+        Err(Expired) => {
+            tracing::error!(%addr, "instance didn't respond in time");
+            get_metrics().routing_error("timeout");
+            let _ = stream
+                .write_all(&ErrorPage::GatewayTimeout.response(hostname))
+                .await;
+            return;
+        }
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if let Some(uring) = crate::get_uring() {
+        match (stream.into_std(), instance.into_std()) {
+            (Ok(stream), Ok(instance)) => {
+                let _ = uring.relay(stream, instance).await;
+            }
+            (Err(error), _) | (_, Err(error)) => {
+                tracing::error!(?error, "failed to hand stream to io_uring")
+            }
+        }
+        return;
+    }
+
+    let _ = tokio::io::copy_bidirectional(&mut stream, &mut instance).await;
+}
 
-    let _ = futures::future::join(
-        tokio::io::copy(&mut r_read, &mut i_write),
-        tokio::io::copy(&mut i_read, &mut r_write),
-    )
-    .await;
+/// Relay what the visitor sends until the instance starts answering
+async fn until_answered(visitor: &mut TcpStream, instance: &mut TcpStream) -> std::io::Result<()> {
+    let mut buf = get_buffer_pool().get();
+    let mut peeked = [0; 1];
+    let mut sending = true;
+    loop {
+        buf.clear();
+        buf.reserve();
+        tokio::select! {
+            answered = instance.peek(&mut peeked) => return answered.map(|_| ()),
+            read = visitor.read_buf(&mut *buf), if sending => match read {
+                Ok(0) | Err(_) => {
+                    sending = false;
+                    instance.shutdown().await?;
+                }
+                Ok(_) => instance.write_all(&buf).await?,
+            },
+        }
+    }
 }
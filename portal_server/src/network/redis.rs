@@ -0,0 +1,114 @@
+//! Publish the hosts we serve in redis, where every instance looks them up
+use super::{Error, HostRegistry, Instance};
+use crate::connected_clients::Connections;
+use crate::{get_config, ClientId};
+use async_trait::async_trait;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+/// How long a published host outlives its instance
+const HOST_TTL: Duration = Duration::from_secs(60);
+
+/// What we store in redis for each host
+#[derive(Debug, Serialize, Deserialize)]
+struct HostEntry {
+    instance_id: String,
+    ip: IpAddr,
+    client_id: ClientId,
+}
+
+/// Every instance publishes its hosts under `portal:host:<host>` with a TTL,
+/// so finding the one serving a host is a single lookup
+pub struct RedisRegistry {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+    /// the address other instances reach us on
+    ip: IpAddr,
+}
+
+impl RedisRegistry {
+    pub fn new(url: &str, ip: IpAddr) -> Result<Self, Error> {
+        Ok(RedisRegistry {
+            client: redis::Client::open(url)?,
+            connection: OnceCell::new(),
+            ip,
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, Error> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await?;
+        Ok(connection.clone())
+    }
+
+    fn key(host: &str) -> String {
+        format!("portal:host:{}", host)
+    }
+}
+
+#[async_trait]
+impl HostRegistry for RedisRegistry {
+    async fn publish(&self, host: &str, client_id: &ClientId) -> Result<(), Error> {
+        let entry = HostEntry {
+            instance_id: get_config().instance_id.clone(),
+            ip: self.ip,
+            client_id: client_id.clone(),
+        };
+        let value = serde_json::to_string(&entry).unwrap_or_default();
+        redis::cmd("SET")
+            .arg(Self::key(host))
+            .arg(value)
+            .arg("EX")
+            .arg(HOST_TTL.as_secs())
+            .query_async::<()>(&mut self.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn unpublish(&self, host: &str) -> Result<(), Error> {
+        // only remove the host if no other instance took it over in the meantime
+        let script = redis::Script::new(
+            r#"
+            local entry = redis.call("GET", KEYS[1])
+            if entry and cjson.decode(entry).instance_id == ARGV[1] then
+                return redis.call("DEL", KEYS[1])
+            end
+            return 0
+            "#,
+        );
+        script
+            .key(Self::key(host))
+            .arg(&get_config().instance_id)
+            .invoke_async::<()>(&mut self.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn lookup(&self, host: &str) -> Result<(Instance, ClientId), Error> {
+        let value: Option<String> = redis::cmd("GET")
+            .arg(Self::key(host))
+            .query_async(&mut self.connection().await?)
+            .await?;
+        let entry: HostEntry = value
+            .and_then(|value| serde_json::from_str(&value).ok())
+            .ok_or(Error::DoesNotServeHost)?;
+
+        // we may have lost the host since we published it
+        if entry.instance_id == get_config().instance_id {
+            let client_id =
+                Connections::client_for_host(&host.to_string()).ok_or(Error::DoesNotServeHost)?;
+            return Ok((Instance { ip: self.ip }, client_id));
+        }
+
+        Ok((Instance { ip: entry.ip }, entry.client_id))
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(HOST_TTL / 3)
+    }
+}
@@ -0,0 +1,100 @@
+//! Where to find the instance serving a host
+use super::{Error, HostAnswer, Instance, Member};
+use crate::{get_config, ClientId};
+use async_trait::async_trait;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use std::time::Duration;
+
+/// Keeps track of which instance serves which host
+#[async_trait]
+pub trait HostRegistry: Send + Sync {
+    /// Announce that an agent of this instance serves `host`
+    async fn publish(&self, host: &str, client_id: &ClientId) -> Result<(), Error>;
+
+    /// No agent of this instance serves `host` anymore
+    async fn unpublish(&self, host: &str) -> Result<(), Error>;
+
+    /// The instance serving `host`, and the client it serves it with
+    async fn lookup(&self, host: &str) -> Result<(Instance, ClientId), Error>;
+
+    /// The instances we know of, i.e. to tell them something
+    async fn instances(&self) -> Result<Vec<Instance>, Error> {
+        Instance::get_instances().await
+    }
+
+    /// How often published hosts must be published again to stay registered
+    fn refresh_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Start whatever the registry keeps doing in the background
+    fn spawn(&'static self) {}
+
+    /// Every instance in the cluster including us, if the registry keeps track of them
+    fn members(&self) -> Option<Vec<Member>> {
+        None
+    }
+}
+
+/// Ask every instance found through DNS whether it serves the host
+#[derive(Debug, Default)]
+pub struct DnsGossip;
+
+#[async_trait]
+impl HostRegistry for DnsGossip {
+    async fn publish(&self, _host: &str, _client_id: &ClientId) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn unpublish(&self, _host: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn lookup(&self, host: &str) -> Result<(Instance, ClientId), Error> {
+        ask_instances(Instance::get_instances().await?, host).await
+    }
+}
+
+/// Ask every instance whether it serves the host, going with the first in our region
+/// that does, or else the first of any region
+pub(super) async fn ask_instances(
+    instances: Vec<Instance>,
+    host: &str,
+) -> Result<(Instance, ClientId), Error> {
+    let mut queries: FuturesUnordered<_> =
+        instances.into_iter().map(|i| i.serves_host(host)).collect();
+    let config = get_config();
+    let region = config.region.as_deref();
+
+    let mut fallback: Option<HostAnswer> = None;
+    let mut last_error = Error::DoesNotServeHost;
+    // how long we still wait for an instance in our region
+    let mut patience = Box::pin(tokio::time::sleep(Duration::MAX));
+    loop {
+        tokio::select! {
+            answer = queries.next() => match answer {
+                Some(Ok(answer)) if region.is_none() || answer.region.as_deref() == region => {
+                    return Ok((answer.instance, answer.client_id));
+                }
+                Some(Ok(answer)) => {
+                    if fallback.is_none() {
+                        patience = Box::pin(tokio::time::sleep(get_config().region_wait));
+                        fallback = Some(answer);
+                    }
+                }
+                Some(Err(error)) => last_error = error,
+                None => break,
+            },
+            _ = &mut patience => break,
+        }
+    }
+
+    match fallback {
+        Some(answer) => {
+            tracing::debug!(region=?answer.region, "no instance in our region serves host");
+            Ok((answer.instance, answer.client_id))
+        }
+        None => Err(last_error),
+    }
+}
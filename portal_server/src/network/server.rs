@@ -1,10 +1,20 @@
 use super::*;
+use crate::admin::constant_time_eq;
 use crate::connected_clients::Connections;
-use crate::ClientId;
+use crate::observability;
+use crate::{get_health, get_share_links, ClientId};
 use serde::{Deserialize, Serialize};
-use warp::Filter;
+use warp::http::HeaderMap;
+use warp::{Filter, Rejection, Reply};
 
-pub fn spawn<A: Into<SocketAddr>>(addr: A) {
+pub fn spawn<A: Into<SocketAddr>>(addr: A) -> std::io::Result<()> {
+    let listener = crate::socket::bind(addr.into())?;
+    tokio::spawn(crate::socket::serve(listener, warp::service(routes())));
+    Ok(())
+}
+
+/// What other instances and operators ask of us
+fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let health_check = warp::get().and(warp::path("health_check")).map(|| {
         tracing::debug!("Net svc health check triggered");
         "ok"
@@ -12,13 +22,77 @@ pub fn spawn<A: Into<SocketAddr>>(addr: A) {
 
     let query_svc = warp::path::end()
         .and(warp::get())
+        .and(authorized())
         .and(warp::query::<HostQuery>())
-        .map(|query| warp::reply::json(&handle_query(query)));
+        .and(warp::header::headers_cloned())
+        .map(|query: HostQuery, headers: HeaderMap| {
+            let span = tracing::info_span!("host_query", host = %query.host);
+            let headers = headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect();
+            observability::otel::continue_trace(&span, &headers);
+            span.in_scope(|| warp::reply::json(&handle_query(query)))
+        });
 
-    let routes = query_svc.or(health_check);
+    let invalidate = warp::path!("invalidate")
+        .and(warp::post())
+        .and(authorized())
+        .and(warp::query::<HostQuery>())
+        .map(|query: HostQuery| {
+            get_host_cache().invalidate(&query.host);
+            "ok"
+        });
 
-    // spawn our websocket control server
-    tokio::spawn(warp::serve(routes).run(addr.into()));
+    let link = warp::path!("link")
+        .and(authorized())
+        .and(warp::ws())
+        .map(|ws: warp::ws::Ws| ws.on_upgrade(super::link::accept));
+
+    query_svc
+        .or(invalidate)
+        .or(link)
+        .or(health_check)
+        .or(crate::health::routes())
+        .or(observability::metrics::route())
+        .or(warp::path("log-level")
+            .and(authorized())
+            .and(observability::logging::route()))
+        .recover(handle_rejection)
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+/// Requests other instances make must carry `Authorization: Bearer <internal_secret>`
+/// once we have one
+fn authorized() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(|header: Option<String>| async move {
+            let config = get_config();
+            let Some(expected) = config.internal_secret.as_deref() else {
+                return Ok(());
+            };
+            match header.as_deref().and_then(|h| h.strip_prefix("Bearer ")) {
+                Some(given) if constant_time_eq(expected, given) => Ok(()),
+                _ => Err(warp::reject::custom(Unauthorized)),
+            }
+        })
+        .untuple_one()
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        tracing::warn!("unauthorized request to the network service");
+        return Ok(warp::reply::with_status(
+            "unauthorized",
+            warp::http::StatusCode::UNAUTHORIZED,
+        ));
+    }
+    Err(rejection)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,11 +103,52 @@ pub struct HostQuery {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HostQueryResponse {
     pub client_id: Option<ClientId>,
+    /// we're shutting down, so don't send us the host even if we still serve it
+    #[serde(default)]
+    pub draining: bool,
+    /// where we run, so those asking can prefer an instance near them
+    #[serde(default)]
+    pub region: Option<String>,
 }
 
 fn handle_query(query: HostQuery) -> HostQueryResponse {
     tracing::debug!(host=%query.host, "got query");
+    if get_health().is_draining() {
+        return HostQueryResponse {
+            client_id: None,
+            draining: true,
+            region: get_config().region.clone(),
+        };
+    }
     HostQueryResponse {
-        client_id: Connections::client_for_host(&query.host),
+        client_id: Connections::client_for_host(&query.host)
+            .or_else(|| get_share_links().client_for(&query.host)),
+        draining: false,
+        region: get_config().region.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[tokio::test]
+    async fn test_log_level_is_authorized() {
+        let config = Config::load_from_file("tests/config.toml").unwrap();
+        let _ = crate::CONFIG.set(arc_swap::ArcSwap::from_pointee(config));
+
+        for authorization in [None, Some("Bearer wrong"), Some("wrong")] {
+            let mut request = warp::test::request().path("/log-level");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let response = request.reply(&routes()).await;
+            assert_eq!(response.status(), warp::http::StatusCode::UNAUTHORIZED);
+        }
+
+        // other paths are still not found, not unauthorized
+        let response = warp::test::request().path("/nope").reply(&routes()).await;
+        assert_eq!(response.status(), warp::http::StatusCode::NOT_FOUND);
     }
 }
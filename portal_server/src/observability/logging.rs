@@ -0,0 +1,147 @@
+//! Log output, with a level that can be changed while running
+use crate::admin::constant_time_eq;
+use crate::cli::LogFormat;
+use crate::get_config;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{reload, Layer, Registry};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+static LEVEL: OnceLock<reload::Handle<LevelFilter, Registry>> = OnceLock::new();
+
+/// Install the global subscriber
+pub fn init(format: LogFormat) {
+    let (level, handle) = reload::Layer::new(crate::get_config().log_level);
+    let _ = LEVEL.set(handle);
+
+    let output = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().boxed(),
+    };
+
+    let subscriber = Registry::default()
+        .with(level)
+        .with(output)
+        .with(super::otel::layer(&crate::get_config()));
+    tracing::subscriber::set_global_default(subscriber).expect("setting global default failed");
+}
+
+pub fn level() -> Option<LevelFilter> {
+    LEVEL.get()?.clone_current()
+}
+
+pub fn set_level(level: LevelFilter) -> Result<(), reload::Error> {
+    match LEVEL.get() {
+        Some(handle) => handle.reload(level),
+        None => Ok(()),
+    }
+}
+
+/// `GET` the level, or `PUT` one like `info` as the body, for those holding the admin
+/// token or the internal secret. Served at `/log-level`.
+pub fn route() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let get = warp::get().map(|| {
+        let level = level().map(|l| l.to_string()).unwrap_or_default();
+        warp::reply::with_status(level, StatusCode::OK)
+    });
+
+    let put = warp::put()
+        .and(warp::body::content_length_limit(32))
+        .and(warp::body::bytes())
+        .map(|body: warp::hyper::body::Bytes| {
+            let body = String::from_utf8_lossy(&body);
+            let level = match LevelFilter::from_str(body.trim()) {
+                Ok(level) => level,
+                Err(_) => {
+                    return warp::reply::with_status(
+                        format!("invalid log level: {}", body.trim()),
+                        StatusCode::BAD_REQUEST,
+                    )
+                }
+            };
+
+            match set_level(level) {
+                Ok(()) => {
+                    tracing::warn!(%level, "changed log level");
+                    warp::reply::with_status(level.to_string(), StatusCode::OK)
+                }
+                Err(error) => {
+                    warp::reply::with_status(error.to_string(), StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            }
+        });
+
+    warp::path::end()
+        .and(authorized())
+        .and(get.or(put).unify())
+        .recover(handle_rejection)
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+/// Requests must carry `Authorization: Bearer` with the admin token or the internal
+/// secret, without either configured the level only changes on a reload
+fn authorized() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(|header: Option<String>| async move {
+            let config = get_config();
+            let given = header.as_deref().and_then(|h| h.strip_prefix("Bearer "));
+            let tokens = [
+                config.admin_token.as_deref(),
+                config.internal_secret.as_deref(),
+            ];
+            let known = given.is_some_and(|given| {
+                tokens
+                    .into_iter()
+                    .flatten()
+                    .any(|token| constant_time_eq(token, given))
+            });
+            if known {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(Unauthorized))
+            }
+        })
+        .untuple_one()
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        tracing::warn!("unauthorized request for the log level");
+        return Ok(warp::reply::with_status(
+            "unauthorized",
+            StatusCode::UNAUTHORIZED,
+        ));
+    }
+    Err(rejection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    #[tokio::test]
+    async fn test_log_level_needs_a_token() {
+        // tokens are never empty, so none of these is one
+        let config = Config::load_from_file("tests/config.toml").unwrap();
+        let _ = crate::CONFIG.set(arc_swap::ArcSwap::from_pointee(config));
+
+        for authorization in [None, Some("Bearer "), Some("Bearer wrong")] {
+            let mut request = warp::test::request()
+                .method("PUT")
+                .path("/log-level")
+                .body("trace");
+            if let Some(authorization) = authorization {
+                request = request.header("authorization", authorization);
+            }
+            let response = request.reply(&warp::path("log-level").and(route())).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+use warp::Filter;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Prometheus metrics for this instance
+pub struct Metrics {
+    registry: Registry,
+    pub connected_clients: IntGauge,
+    pub active_streams: IntGauge,
+    /// visitor connections open, and those turned away at accept
+    pub visitor_connections: IntGauge,
+    pub connections_shed: IntCounterVec,
+    /// bytes relayed per tunnel, `in` from visitors and `out` to them
+    pub tunnel_bytes: IntCounterVec,
+    pub handshake_failures: IntCounterVec,
+    pub routing_errors: IntCounterVec,
+    /// streams the sweep closed because nothing else would have
+    pub streams_swept: IntCounterVec,
+    /// time from routing a visitor's stream to the first response byte
+    pub proxy_latency: Histogram,
+    /// tasks serving agents and visitors by kind, to catch leaks
+    pub live_tasks: IntGaugeVec,
+    /// read buffers relays hold, those idle in the pool and how many we had to allocate
+    pub buffers_in_use: IntGauge,
+    pub buffers_pooled: IntGauge,
+    pub buffers_allocated: IntCounter,
+    /// requests waiting for their agent to come back, and those that never reached it
+    pub offline_queued: IntGauge,
+    pub offline_dropped: IntCounterVec,
+    /// requests answered from the response cache or not, and the bytes it holds
+    pub cache_lookups: IntCounterVec,
+    pub cache_bytes: IntGauge,
+    /// requests copied to the tunnels mirroring theirs
+    pub mirrored_requests: IntCounterVec,
+}
+
+pub fn get_metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("portal".to_string()), None).expect("invalid metrics prefix");
+
+        let connected_clients =
+            IntGauge::new("connected_clients", "Agents connected to this instance").unwrap();
+        let active_streams =
+            IntGauge::new("active_streams", "Visitor streams open on this instance").unwrap();
+        let visitor_connections =
+            IntGauge::new("visitor_connections", "Visitor connections being served").unwrap();
+        let connections_shed = IntCounterVec::new(
+            Opts::new(
+                "connections_shed_total",
+                "Visitor connections turned away as soon as they were accepted",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let tunnel_bytes = IntCounterVec::new(
+            Opts::new("tunnel_bytes_total", "Bytes relayed through a tunnel"),
+            &["tunnel", "direction"],
+        )
+        .unwrap();
+        let handshake_failures = IntCounterVec::new(
+            Opts::new("handshake_failures_total", "Agent handshakes that failed"),
+            &["reason"],
+        )
+        .unwrap();
+        let routing_errors = IntCounterVec::new(
+            Opts::new(
+                "routing_errors_total",
+                "Visitor connections we couldn't route to a tunnel",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let streams_swept = IntCounterVec::new(
+            Opts::new(
+                "streams_swept_total",
+                "Streams closed by the periodic sweep rather than their relays",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let proxy_latency = Histogram::with_opts(HistogramOpts::new(
+            "proxy_latency_seconds",
+            "Time from routing a visitor's stream to the first response byte",
+        ))
+        .unwrap();
+        let live_tasks = IntGaugeVec::new(
+            Opts::new("live_tasks", "Tasks serving agents and visitors"),
+            &["kind"],
+        )
+        .unwrap();
+        let buffers_in_use =
+            IntGauge::new("buffers_in_use", "Read buffers held by stream relays").unwrap();
+        let buffers_pooled =
+            IntGauge::new("buffers_pooled", "Idle read buffers kept for reuse").unwrap();
+        let buffers_allocated = IntCounter::new(
+            "buffers_allocated_total",
+            "Read buffers allocated because none was idle",
+        )
+        .unwrap();
+        let offline_queued = IntGauge::new(
+            "offline_queued_requests",
+            "Requests waiting for their agent to come back",
+        )
+        .unwrap();
+        let offline_dropped = IntCounterVec::new(
+            Opts::new(
+                "offline_dropped_total",
+                "Requests queued for an offline agent that never reached it",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new(
+                "cache_lookups_total",
+                "Requests to tunnels that allow caching, by whether the cache answered them",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let cache_bytes =
+            IntGauge::new("cache_bytes", "Bytes of responses held in the cache").unwrap();
+        let mirrored_requests = IntCounterVec::new(
+            Opts::new(
+                "mirrored_requests_total",
+                "Requests copied to the tunnels mirroring theirs",
+            ),
+            &["result"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(connected_clients.clone()))
+            .unwrap();
+        registry.register(Box::new(active_streams.clone())).unwrap();
+        registry
+            .register(Box::new(visitor_connections.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(connections_shed.clone()))
+            .unwrap();
+        registry.register(Box::new(tunnel_bytes.clone())).unwrap();
+        registry
+            .register(Box::new(handshake_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(routing_errors.clone())).unwrap();
+        registry.register(Box::new(streams_swept.clone())).unwrap();
+        registry.register(Box::new(proxy_latency.clone())).unwrap();
+        registry.register(Box::new(live_tasks.clone())).unwrap();
+        registry.register(Box::new(buffers_in_use.clone())).unwrap();
+        registry.register(Box::new(buffers_pooled.clone())).unwrap();
+        registry
+            .register(Box::new(buffers_allocated.clone()))
+            .unwrap();
+        registry.register(Box::new(offline_queued.clone())).unwrap();
+        registry
+            .register(Box::new(offline_dropped.clone()))
+            .unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        registry.register(Box::new(cache_bytes.clone())).unwrap();
+        registry
+            .register(Box::new(mirrored_requests.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            connected_clients,
+            active_streams,
+            visitor_connections,
+            connections_shed,
+            tunnel_bytes,
+            handshake_failures,
+            routing_errors,
+            streams_swept,
+            proxy_latency,
+            live_tasks,
+            buffers_in_use,
+            buffers_pooled,
+            buffers_allocated,
+            offline_queued,
+            offline_dropped,
+            cache_lookups,
+            cache_bytes,
+            mirrored_requests,
+        }
+    }
+
+    pub fn bytes_in(&self, tunnel: &str, bytes: usize) {
+        self.tunnel_bytes
+            .with_label_values(&[tunnel, "in"])
+            .inc_by(bytes as u64);
+    }
+
+    pub fn bytes_out(&self, tunnel: &str, bytes: usize) {
+        self.tunnel_bytes
+            .with_label_values(&[tunnel, "out"])
+            .inc_by(bytes as u64);
+    }
+
+    /// Bytes relayed through a tunnel so far, `(in, out)`
+    pub fn tunnel_totals(&self, tunnel: &str) -> (u64, u64) {
+        (
+            self.tunnel_bytes.with_label_values(&[tunnel, "in"]).get(),
+            self.tunnel_bytes.with_label_values(&[tunnel, "out"]).get(),
+        )
+    }
+
+    pub fn handshake_failed(&self, reason: &str) {
+        self.handshake_failures.with_label_values(&[reason]).inc();
+    }
+
+    pub fn routing_error(&self, reason: &str) {
+        self.routing_errors.with_label_values(&[reason]).inc();
+    }
+
+    pub fn offline_dropped(&self, reason: &str, requests: usize) {
+        self.offline_dropped
+            .with_label_values(&[reason])
+            .inc_by(requests as u64);
+    }
+
+    pub fn cache_lookup(&self, result: &str) {
+        self.cache_lookups.with_label_values(&[result]).inc();
+    }
+
+    pub fn mirrored(&self, result: &str) {
+        self.mirrored_requests.with_label_values(&[result]).inc();
+    }
+
+    pub fn connection_shed(&self, reason: &str) {
+        self.connections_shed.with_label_values(&[reason]).inc();
+    }
+
+    pub fn stream_swept(&self, reason: &str) {
+        self.streams_swept.with_label_values(&[reason]).inc();
+    }
+
+    /// Render all metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        self.active_streams
+            .set(crate::get_active_streams().len() as i64);
+
+        let mut buf = vec![];
+        if let Err(error) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            tracing::error!(?error, "failed to encode metrics");
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+/// `GET /metrics`
+pub fn route() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .map(|| {
+            warp::reply::with_header(
+                get_metrics().render(),
+                "content-type",
+                prometheus::TEXT_FORMAT,
+            )
+        })
+}
+
+/// Serve `/metrics` on a dedicated port
+pub fn spawn<A: Into<std::net::SocketAddr>>(addr: A) -> Result<(), warp::Error> {
+    let (_, server) = warp::serve(route()).try_bind_ephemeral(addr.into())?;
+    tokio::spawn(server);
+    Ok(())
+}
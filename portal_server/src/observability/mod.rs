@@ -1,3 +1,7 @@
+pub mod logging;
+pub mod metrics;
+pub mod otel;
+
 use tracing::Span;
 use uuid::Uuid;
 
@@ -13,7 +17,16 @@ pub fn remote_trace(source: &str) -> Span {
     let id = get_config().instance_id.clone();
 
     // Create a span using tracing macros
-    let span = tracing::info_span!(target: "event", parent: &current, "begin span", id = %id, source = %source, req = %trace_id);
+    let span = tracing::info_span!(
+        target: "event",
+        parent: &current,
+        "begin span",
+        id = %id,
+        source = %source,
+        req = %trace_id,
+        tunnel = tracing::field::Empty,
+        client_id = tracing::field::Empty,
+    );
     span.in_scope(|| {
         // let _ = register_dist_tracing_root(trace_id, None).map_err(|e| {
         //     eprintln!("register trace root error: {:?}", e);
@@ -21,6 +34,12 @@ pub fn remote_trace(source: &str) -> Span {
     });
     span
 }
+
+/// Attach the tunnel a span is working for
+pub fn record_tunnel(span: &Span, client: &crate::ConnectedClient) {
+    span.record("tunnel", client.host.as_str());
+    span.record("client_id", client.id.to_string().as_str());
+}
 //
 // pub fn network_trace(info: Info) -> Span {
 //     let request_id = TraceId::new();
@@ -0,0 +1,69 @@
+//! Export traces to an OpenTelemetry collector over OTLP/HTTP
+use crate::Config;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use tracing::Span;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A tracing layer exporting spans to the configured OTLP endpoint, if any
+pub fn layer<S>(config: &Config) -> Option<OpenTelemetryLayer<S, Tracer>>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint = config.otlp_endpoint.as_ref()?;
+
+    // tracing isn't set up yet, so we can only complain on stderr
+    let exporter = match opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .with_headers(config.otlp_headers.clone())
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(error) => {
+            eprintln!("failed to create otlp exporter: {:?}", error);
+            return None;
+        }
+    };
+
+    let resource = Resource::builder()
+        .with_service_name("portal_server")
+        .with_attribute(KeyValue::new(
+            "service.instance.id",
+            config.instance_id.clone(),
+        ))
+        .build();
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+
+    Some(tracing_opentelemetry::layer().with_tracer(provider.tracer("portal_server")))
+}
+
+/// Trace context headers for the current span, to send along with requests to other instances
+pub fn trace_headers() -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut headers)
+    });
+    headers
+}
+
+/// Continue the trace described by the (lowercase) `headers` of a request in `span`
+pub fn continue_trace(span: &Span, headers: &HashMap<String, String>) {
+    let context = global::get_text_map_propagator(|propagator| propagator.extract(headers));
+    if let Err(error) = span.set_parent(context) {
+        tracing::debug!(?error, "failed to continue trace");
+    }
+}
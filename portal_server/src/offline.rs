@@ -0,0 +1,621 @@
+//! Requests to tunnels whose agent asked to have them wait while it's offline, i.e.
+//! webhooks, delivered in the order they arrived once it's back rather than failing.
+//! Each request is kept as a file of its own when there's an `offline_queue_dir`.
+use crate::connected_clients::{ConnectedClient, Connections};
+use crate::deadline::{Deadline, Expired};
+use crate::error_page::ErrorPage;
+use crate::http::forwarded::ForwardedContext;
+use crate::http::{Limits, RequestFrame, RequestFramer, RequestHead};
+use crate::observability::metrics::get_metrics;
+use crate::{get_config, get_offline_queues, get_request_log, get_tasks, replay};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use portal_lib::ClientId;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// How often a request the agent didn't answer is sent before we give up on it
+const MAX_ATTEMPTS: u32 = 3;
+
+/// How long we wait to send a request again that the agent didn't answer
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How often expired requests are dropped and tunnels gone for good forgotten
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+const HTTP_ACCEPTED_RESPONSE: &[u8] = b"HTTP/1.1 202 Accepted\r\nContent-Length: 40\r\nConnection: close\r\n\r\nqueued until the tunnel is back online\r\n";
+
+/// The tunnel's queue is full
+#[derive(Debug)]
+pub struct Full;
+
+/// Requests waiting per tunnel for its agent to come back
+#[derive(Debug)]
+pub struct OfflineQueues {
+    tunnels: Mutex<HashMap<String, Tunnel>>,
+    /// where each tunnel's requests are kept across restarts
+    dir: Option<PathBuf>,
+    /// numbers requests in the order they arrived
+    next_seq: AtomicU64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Tunnel {
+    /// the account whose agents the requests are for
+    client_id: ClientId,
+    /// when the last of its agents went away, `None` while one is connected
+    offline_since: Option<DateTime<Utc>>,
+    /// whether its agents still ask for queueing, or we only deliver what's left
+    enabled: bool,
+    #[serde(skip)]
+    requests: VecDeque<Queued>,
+    #[serde(skip)]
+    delivering: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Queued {
+    seq: u64,
+    received_at: DateTime<Utc>,
+    request: Bytes,
+    attempts: u32,
+}
+
+impl Queued {
+    fn is_expired(&self, ttl: Duration, now: DateTime<Utc>) -> bool {
+        expired(self.received_at, ttl, now)
+    }
+}
+
+fn expired(since: DateTime<Utc>, ttl: Duration, now: DateTime<Utc>) -> bool {
+    chrono::Duration::from_std(ttl).is_ok_and(|ttl| since + ttl < now)
+}
+
+impl OfflineQueues {
+    /// Start with the requests kept in `dir`, if any
+    pub fn load(dir: Option<&str>) -> Self {
+        let queues = OfflineQueues {
+            tunnels: Mutex::new(HashMap::new()),
+            dir: dir.map(PathBuf::from),
+            next_seq: AtomicU64::new(0),
+        };
+        if let Some(dir) = &queues.dir {
+            let tunnels = load_tunnels(dir);
+            let last_seq = tunnels
+                .values()
+                .filter_map(|tunnel| tunnel.requests.back())
+                .map(|queued| queued.seq + 1)
+                .max()
+                .unwrap_or(0);
+            queues.next_seq.store(last_seq, Ordering::Relaxed);
+            let queued = tunnels
+                .values()
+                .map(|tunnel| tunnel.requests.len())
+                .sum::<usize>();
+            get_metrics().offline_queued.add(queued as i64);
+            tracing::info!(tunnels = tunnels.len(), queued, "loaded offline queues");
+            *queues.tunnels.lock().unwrap() = tunnels;
+        }
+        queues
+    }
+
+    /// An agent serving the tunnel on its host connected, asking for queueing or not.
+    /// What's queued for it is delivered now.
+    pub fn connected(&self, client: &ConnectedClient, queue_offline: bool) {
+        if !client.protocol.is_http() {
+            return;
+        }
+        // anyone may take an anonymous sub-domain once its agent is gone
+        let enabled = queue_offline && !client.is_anonymous && get_config().offline_queue_size > 0;
+
+        let mut tunnels = self.tunnels.lock().unwrap();
+        // the requests of another account's tunnel are none of this one's business
+        let taken_over = tunnels
+            .get(&client.host)
+            .filter(|tunnel| tunnel.client_id != client.id)
+            .map(|tunnel| tunnel.requests.len());
+        if let Some(dropped) = taken_over {
+            tracing::warn!(host=%client.host, dropped, "tunnel taken over, dropping its queue");
+            get_metrics().offline_dropped("taken_over", dropped);
+            get_metrics().offline_queued.sub(dropped as i64);
+            tunnels.remove(&client.host);
+            self.remove_tunnel(&client.host);
+        }
+
+        let tunnel = match tunnels.entry(client.host.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) if enabled => entry.insert(Tunnel {
+                client_id: client.id.clone(),
+                offline_since: None,
+                enabled,
+                requests: VecDeque::new(),
+                delivering: false,
+            }),
+            Entry::Vacant(_) => return,
+        };
+        tunnel.offline_since = None;
+        tunnel.enabled = enabled;
+        self.save_tunnel(&client.host, tunnel);
+
+        let deliver = !tunnel.requests.is_empty() && !tunnel.delivering;
+        if deliver {
+            tunnel.delivering = true;
+        }
+        drop(tunnels);
+        if deliver {
+            self.spawn_delivery(client.host.clone());
+        }
+    }
+
+    /// The last agent serving the tunnel on `host` went away
+    pub fn went_offline(&self, host: &str) {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let Some(tunnel) = tunnels.get_mut(host) else {
+            return;
+        };
+        if !tunnel.enabled && tunnel.requests.is_empty() {
+            tunnels.remove(host);
+            self.remove_tunnel(host);
+            return;
+        }
+        tunnel.offline_since = Some(Utc::now());
+        self.save_tunnel(host, tunnel);
+    }
+
+    /// Whether requests to `host` are queued: its agent is offline, or still catching
+    /// up on what was queued, which newer requests wait behind
+    pub fn accepts(&self, host: &str) -> bool {
+        let config = get_config();
+        if config.offline_queue_size == 0 {
+            return false;
+        }
+        let tunnels = self.tunnels.lock().unwrap();
+        tunnels.get(host).is_some_and(|tunnel| {
+            !tunnel.requests.is_empty()
+                || tunnel.enabled
+                    && tunnel
+                        .offline_since
+                        .is_some_and(|since| !expired(since, config.offline_queue_ttl, Utc::now()))
+        })
+    }
+
+    /// Queue `request` for the tunnel on `host`, delivering it right away if its agent
+    /// is connected
+    pub fn push(&self, host: &str, request: Bytes) -> Result<(), Full> {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let Some(tunnel) = tunnels.get_mut(host) else {
+            return Err(Full);
+        };
+        if tunnel.requests.len() >= get_config().offline_queue_size {
+            get_metrics().offline_dropped("full", 1);
+            return Err(Full);
+        }
+
+        let queued = Queued {
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            received_at: Utc::now(),
+            request,
+            attempts: 0,
+        };
+        self.save_request(host, &queued);
+        tunnel.requests.push_back(queued);
+        get_metrics().offline_queued.inc();
+        tracing::debug!(%host, queued = tunnel.requests.len(), "queued request for offline tunnel");
+
+        let deliver = tunnel.offline_since.is_none() && !tunnel.delivering;
+        if deliver {
+            tunnel.delivering = true;
+        }
+        drop(tunnels);
+        if deliver {
+            self.spawn_delivery(host.to_string());
+        }
+        Ok(())
+    }
+
+    /// Deliver the requests queued for `host` one after the other, for as long as an
+    /// agent serves it
+    fn spawn_delivery(&self, host: String) {
+        get_tasks().spawn("offline_delivery", get_tasks().token(), async move {
+            let queues = get_offline_queues();
+            let mut delivering = Delivering {
+                host: host.clone(),
+                ended: false,
+            };
+            while let Some((client, queued)) = queues.next(&host) {
+                queues.deliver(&client, queued).await;
+            }
+            delivering.ended = true;
+        });
+    }
+
+    /// The oldest request for `host` that isn't expired yet, with an agent to deliver
+    /// it to. Without either the delivery ends, letting the next one start.
+    fn next(&self, host: &str) -> Option<(ConnectedClient, Queued)> {
+        let ttl = get_config().offline_queue_ttl;
+        let now = Utc::now();
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let tunnel = tunnels.get_mut(host)?;
+        while let Some(queued) = tunnel.requests.front() {
+            if !queued.is_expired(ttl, now) {
+                break;
+            }
+            let seq = queued.seq;
+            self.drop_request(host, tunnel, seq, "expired");
+        }
+
+        let next = tunnel.requests.front().cloned().and_then(|queued| {
+            let client = Connections::find_by_host(&host.to_string(), None)?;
+            Some((client, queued))
+        });
+        if next.is_none() {
+            tunnel.delivering = false;
+        }
+        next
+    }
+
+    /// Send `queued` to `client`, dropping it from the queue once answered or once it
+    /// ran out of attempts
+    async fn deliver(&self, client: &ConnectedClient, queued: Queued) {
+        let Ok(Some((head, len))) = RequestHead::parse(&queued.request) else {
+            self.done(&client.host, queued.seq, Some("invalid"));
+            return;
+        };
+
+        match replay::send(client, &head, len, &queued.request).await {
+            Ok((stream_id, recorded)) => {
+                tracing::debug!(host=%client.host, seq = queued.seq, "delivered queued request");
+                get_request_log().record(client, &stream_id, recorded);
+                self.done(&client.host, queued.seq, None);
+            }
+            Err(error) if queued.attempts + 1 >= MAX_ATTEMPTS => {
+                tracing::warn!(host=%client.host, %error, "giving up on queued request");
+                self.done(&client.host, queued.seq, Some("undeliverable"));
+            }
+            Err(error) => {
+                tracing::debug!(host=%client.host, %error, "queued request not answered, retrying");
+                if let Some(tunnel) = self.tunnels.lock().unwrap().get_mut(&client.host) {
+                    if let Some(front) = tunnel.requests.front_mut() {
+                        front.attempts += 1;
+                    }
+                }
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+
+    /// Take request `seq` off the queue of `host`, counting it as dropped for `reason`
+    /// unless it was delivered
+    fn done(&self, host: &str, seq: u64, dropped: Option<&str>) {
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let Some(tunnel) = tunnels.get_mut(host) else {
+            return;
+        };
+        match dropped {
+            Some(reason) => self.drop_request(host, tunnel, seq, reason),
+            None => {
+                tunnel.requests.retain(|queued| queued.seq != seq);
+                get_metrics().offline_queued.dec();
+                self.remove_request(host, seq);
+            }
+        }
+    }
+
+    fn drop_request(&self, host: &str, tunnel: &mut Tunnel, seq: u64, reason: &str) {
+        let before = tunnel.requests.len();
+        tunnel.requests.retain(|queued| queued.seq != seq);
+        if tunnel.requests.len() < before {
+            get_metrics().offline_queued.dec();
+            get_metrics().offline_dropped(reason, 1);
+            self.remove_request(host, seq);
+        }
+    }
+
+    /// Drop expired requests, and forget the tunnels offline for longer than requests
+    /// are kept with nothing left to deliver
+    fn sweep(&self) {
+        let ttl = get_config().offline_queue_ttl;
+        let now = Utc::now();
+        let mut tunnels = self.tunnels.lock().unwrap();
+        let mut forgotten = vec![];
+        for (host, tunnel) in tunnels.iter_mut() {
+            let expired_seqs: Vec<u64> = tunnel
+                .requests
+                .iter()
+                .take_while(|queued| queued.is_expired(ttl, now))
+                .map(|queued| queued.seq)
+                .collect();
+            for seq in expired_seqs {
+                self.drop_request(host, tunnel, seq, "expired");
+            }
+
+            let gone = tunnel
+                .offline_since
+                .is_some_and(|since| expired(since, ttl, now));
+            if gone && tunnel.requests.is_empty() && !tunnel.delivering {
+                forgotten.push(host.clone());
+            }
+        }
+        for host in forgotten {
+            tracing::debug!(%host, "forgetting offline queue");
+            tunnels.remove(&host);
+            self.remove_tunnel(&host);
+        }
+    }
+
+    fn save_tunnel(&self, host: &str, tunnel: &Tunnel) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let dir = dir.join(host);
+        let saved = std::fs::create_dir_all(&dir).and_then(|_| {
+            let json = serde_json::to_vec(tunnel).map_err(std::io::Error::other)?;
+            std::fs::write(dir.join("tunnel.json"), json)
+        });
+        if let Err(error) = saved {
+            tracing::warn!(%host, %error, "failed to save offline queue");
+        }
+    }
+
+    fn save_request(&self, host: &str, queued: &Queued) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let name = format!(
+            "{:020}-{}.http",
+            queued.seq,
+            queued.received_at.timestamp_millis()
+        );
+        if let Err(error) = std::fs::write(dir.join(host).join(name), &queued.request) {
+            tracing::warn!(%host, %error, "failed to save queued request");
+        }
+    }
+
+    fn remove_request(&self, host: &str, seq: u64) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let prefix = format!("{:020}-", seq);
+        let Ok(entries) = std::fs::read_dir(dir.join(host)) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    fn remove_tunnel(&self, host: &str) {
+        if let Some(dir) = &self.dir {
+            let _ = std::fs::remove_dir_all(dir.join(host));
+        }
+    }
+}
+
+/// Lets another delivery start for a tunnel once this one was cut short
+struct Delivering {
+    host: String,
+    /// the delivery ran out of requests or agents, and said so itself
+    ended: bool,
+}
+
+impl Drop for Delivering {
+    fn drop(&mut self) {
+        if self.ended {
+            return;
+        }
+        if let Some(tunnel) = get_offline_queues()
+            .tunnels
+            .lock()
+            .unwrap()
+            .get_mut(&self.host)
+        {
+            tunnel.delivering = false;
+        }
+    }
+}
+
+/// The tunnels kept in `dir`, each in a directory named after its host
+fn load_tunnels(dir: &Path) -> HashMap<String, Tunnel> {
+    let mut tunnels = HashMap::new();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return tunnels;
+    };
+    for entry in entries.flatten() {
+        let host = entry.file_name().to_string_lossy().to_string();
+        match load_tunnel(&entry.path()) {
+            Ok(tunnel) => {
+                tunnels.insert(host, tunnel);
+            }
+            Err(error) => tracing::warn!(%host, %error, "failed to load offline queue"),
+        }
+    }
+    tunnels
+}
+
+fn load_tunnel(dir: &Path) -> std::io::Result<Tunnel> {
+    let json = std::fs::read(dir.join("tunnel.json"))?;
+    let mut tunnel: Tunnel = serde_json::from_slice(&json).map_err(std::io::Error::other)?;
+    // we went down while an agent was connected, so it's been offline since
+    tunnel.offline_since.get_or_insert_with(Utc::now);
+
+    let mut requests = vec![];
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some((seq, received_at)) = name
+            .strip_suffix(".http")
+            .and_then(|name| name.split_once('-'))
+        else {
+            continue;
+        };
+        let (Ok(seq), Some(received_at)) = (
+            seq.parse(),
+            received_at
+                .parse()
+                .ok()
+                .and_then(DateTime::from_timestamp_millis),
+        ) else {
+            continue;
+        };
+        requests.push(Queued {
+            seq,
+            received_at,
+            request: std::fs::read(entry.path())?.into(),
+            attempts: 0,
+        });
+    }
+    requests.sort_by_key(|queued| queued.seq);
+    tunnel.requests = requests.into();
+    Ok(tunnel)
+}
+
+pub fn spawn_sweeper() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            get_offline_queues().sweep();
+        }
+    });
+}
+
+/// Take the request of a visitor to the tunnel on `host` into its queue, telling the
+/// visitor it was accepted
+#[tracing::instrument(skip(socket, deadline))]
+pub async fn queue_visitor(
+    mut socket: TcpStream,
+    host: String,
+    hostname: String,
+    peer_addr: SocketAddr,
+    deadline: Deadline,
+) {
+    let config = get_config();
+    let forwarded = ForwardedContext::new(
+        peer_addr,
+        config.trusts_forwarded_headers_from(peer_addr.ip()),
+    );
+    let limits = Limits {
+        max_head_size: config.max_header_size,
+        max_body_size: Some(
+            config
+                .max_body_size
+                .map_or(config.offline_queue_max_body, |max| {
+                    max.min(config.offline_queue_max_body)
+                }),
+        ),
+    };
+
+    let response = match deadline
+        .run(read_request(&mut socket, limits, &forwarded))
+        .await
+    {
+        Ok(Ok(request)) => match get_offline_queues().push(&host, request.into()) {
+            Ok(()) => HTTP_ACCEPTED_RESPONSE.to_vec(),
+            Err(Full) => {
+                tracing::warn!(%host, "offline queue is full");
+                ErrorPage::TunnelOffline.response(&hostname)
+            }
+        },
+        Ok(Err(page)) => page.response(&hostname),
+        Err(Expired) => ErrorPage::RequestTimeout.response(&hostname),
+    };
+    let _ = socket.write_all(&response).await;
+    let _ = socket.shutdown().await;
+}
+
+/// Read the visitor's first request in full, with the forwarding headers we add
+async fn read_request(
+    socket: &mut TcpStream,
+    limits: Limits,
+    forwarded: &ForwardedContext,
+) -> Result<Vec<u8>, ErrorPage> {
+    let mut framer = RequestFramer::with_limits(limits);
+    let mut request = vec![];
+    let mut buf = vec![0; 16 * 1024];
+    let mut has_head = false;
+
+    loop {
+        let n = socket
+            .read(&mut buf)
+            .await
+            .map_err(|_| ErrorPage::InvalidRequest)?;
+        if n == 0 {
+            return Err(ErrorPage::InvalidRequest);
+        }
+        let frames = framer
+            .push(&buf[..n])
+            .map_err(|error| ErrorPage::from(&error))?;
+        for frame in frames {
+            match frame {
+                // a pipelined request, which the visitor sends again when we close
+                RequestFrame::Head(_) if has_head => return Ok(request),
+                // there's no one to switch protocols with
+                RequestFrame::Head(head) if head.is_upgrade() => {
+                    return Err(ErrorPage::TunnelOffline)
+                }
+                RequestFrame::Head(mut head) => {
+                    forwarded.apply(&mut head);
+                    request.extend(head.to_bytes());
+                    has_head = true;
+                }
+                RequestFrame::Body(body) => request.extend(body),
+            }
+        }
+        if has_head && framer.is_between_requests() {
+            return Ok(request);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_tunnel() {
+        let dir = std::env::temp_dir().join(format!("offline-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tunnel = Tunnel {
+            client_id: ClientId::generate(),
+            offline_since: None,
+            enabled: true,
+            requests: VecDeque::new(),
+            delivering: false,
+        };
+        std::fs::write(
+            dir.join("tunnel.json"),
+            serde_json::to_vec(&tunnel).unwrap(),
+        )
+        .unwrap();
+        for (seq, request) in [(12, "second"), (3, "first")] {
+            let name = format!("{:020}-1700000000000.http", seq);
+            std::fs::write(dir.join(name), request).unwrap();
+        }
+        std::fs::write(dir.join("stray.txt"), "not a request").unwrap();
+
+        let loaded = load_tunnel(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.client_id, tunnel.client_id);
+        assert!(loaded.offline_since.is_some());
+        let requests: Vec<_> = loaded
+            .requests
+            .iter()
+            .map(|queued| (queued.seq, &queued.request[..]))
+            .collect();
+        assert_eq!(requests, vec![(3, &b"first"[..]), (12, &b"second"[..])]);
+        assert_eq!(
+            loaded.requests[0].received_at.timestamp_millis(),
+            1_700_000_000_000
+        );
+    }
+}
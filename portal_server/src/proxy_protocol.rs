@@ -0,0 +1,211 @@
+//! The PROXY protocol (v1 and v2) a load balancer in front of us uses to tell us
+//! who actually connected, see https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// A v1 header is at most this long, including its `\r\n`
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_FIXED_LENGTH: usize = 16;
+/// Generous for the addresses and whatever TLVs a load balancer adds
+const MAX_HEADER: usize = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("connection doesn't start with a PROXY protocol header")]
+    Missing,
+    #[error("invalid PROXY protocol header")]
+    Invalid,
+    #[error("timed out waiting for the PROXY protocol header")]
+    Timeout,
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// What a complete header says
+#[derive(Debug, PartialEq)]
+pub struct Header {
+    /// bytes the header takes up on the stream
+    pub length: usize,
+    /// who connected to the load balancer, `None` for health checks and unknown protocols
+    pub source: Option<SocketAddr>,
+}
+
+/// Consume the header at the start of `socket`, returning who connected to the load balancer
+pub async fn read_header(
+    socket: &mut TcpStream,
+    timeout: Option<Duration>,
+) -> Result<Option<SocketAddr>, Error> {
+    let mut buf = vec![0u8; MAX_HEADER];
+    let peek = async {
+        loop {
+            let n = socket.peek(&mut buf).await?;
+            if n == 0 {
+                return Err(Error::Missing);
+            }
+            match parse(&buf[..n])? {
+                Some(header) => return Ok(header),
+                None if n == buf.len() => return Err(Error::Invalid),
+                // the rest of the header is on its way
+                None => tokio::time::sleep(Duration::from_millis(5)).await,
+            }
+        }
+    };
+    let header = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, peek)
+            .await
+            .map_err(|_| Error::Timeout)??,
+        None => peek.await?,
+    };
+
+    // leave the request itself for the host peeking that follows
+    socket.read_exact(&mut buf[..header.length]).await?;
+    Ok(header.source)
+}
+
+/// Parse the header at the start of `buf`, `None` while it's incomplete
+pub fn parse(buf: &[u8]) -> Result<Option<Header>, Error> {
+    if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if buf.starts_with(V1_PREFIX) {
+        parse_v1(buf)
+    } else if V2_SIGNATURE.starts_with(buf) || V1_PREFIX.starts_with(buf) {
+        Ok(None)
+    } else {
+        Err(Error::Missing)
+    }
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<Header>, Error> {
+    let end = match buf.windows(2).position(|window| window == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() < V1_MAX_LENGTH => return Ok(None),
+        None => return Err(Error::Invalid),
+    };
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end]).map_err(|_| Error::Invalid)?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    let source = match fields.as_slice() {
+        ["UNKNOWN", ..] => None,
+        ["TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source.parse().map_err(|_| Error::Invalid)?;
+            let port: u16 = source_port.parse().map_err(|_| Error::Invalid)?;
+            Some(SocketAddr::new(ip, port))
+        }
+        _ => return Err(Error::Invalid),
+    };
+    Ok(Some(Header {
+        length: end + 2,
+        source,
+    }))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<Header>, Error> {
+    if buf.len() < V2_FIXED_LENGTH {
+        return Ok(None);
+    }
+    let version_command = buf[12];
+    let family = buf[13];
+    let length = V2_FIXED_LENGTH + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(Error::Invalid);
+    }
+    if buf.len() < length {
+        return Ok(None);
+    }
+
+    let addresses = &buf[V2_FIXED_LENGTH..length];
+    let source = match (version_command & 0x0F, family >> 4) {
+        // LOCAL, the load balancer itself i.e. checking our health
+        (0, _) => None,
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        (1, 2) if addresses.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        // unix sockets and unspecified families
+        (1, _) => None,
+        _ => return Err(Error::Invalid),
+    };
+    Ok(Some(Header { length, source }))
+}
+
+/// A v1 header telling whoever we pass a connection on to who made it
+pub fn header_v1(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+    let destination = SocketAddr::new(destination.ip().to_canonical(), destination.port());
+    let header = match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => format!(
+            "PROXY TCP4 {} {} {} {}\r\n",
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        ),
+        (SocketAddr::V6(source), SocketAddr::V6(destination)) => format!(
+            "PROXY TCP6 {} {} {} {}\r\n",
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        ),
+        _ => "PROXY UNKNOWN\r\n".to_string(),
+    };
+    header.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+
+        let mut v1 = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n".to_vec();
+        let header = parse(&v1).unwrap().unwrap();
+        assert_eq!(header.length, v1.len());
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        v1.extend_from_slice(request);
+        assert_eq!(
+            parse(&v1).unwrap().unwrap().length,
+            v1.len() - request.len()
+        );
+        assert!(parse(b"PROXY TCP4 192.0.2").unwrap().is_none());
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap().source, None);
+        assert!(parse(b"PROXY TCP4 nonsense\r\n").is_err());
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        v2.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+        v2.extend_from_slice(&56324u16.to_be_bytes());
+        v2.extend_from_slice(&443u16.to_be_bytes());
+        assert!(parse(&v2[..20]).unwrap().is_none());
+        let header = parse(&v2).unwrap().unwrap();
+        assert_eq!(header.length, 28);
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+
+        assert!(matches!(parse(request), Err(Error::Missing)));
+        assert!(parse(b"PRO").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_header_v1() {
+        let header = header_v1(
+            "[::ffff:192.0.2.1]:56324".parse().unwrap(),
+            "10.0.0.1:8080".parse().unwrap(),
+        );
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 10.0.0.1 56324 8080\r\n");
+        let parsed = parse(&header).unwrap().unwrap();
+        assert_eq!(parsed.source, Some("192.0.2.1:56324".parse().unwrap()));
+    }
+}
@@ -0,0 +1,68 @@
+//! Pick up configuration changes without restarting and dropping every tunnel
+use crate::config::Config;
+use crate::error_page::ErrorPages;
+use crate::observability::logging;
+use crate::{get_cli, get_config, set_config, set_error_pages, transport};
+
+/// Reload the configuration every time we get a SIGHUP
+#[cfg(unix)]
+pub fn spawn_reload_handler() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(error) => {
+                tracing::error!(%error, "failed to listen for reload signals");
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            reload().await;
+        }
+    });
+}
+
+/// Without SIGHUP there's nothing to reload on, changes take a restart
+#[cfg(not(unix))]
+pub fn spawn_reload_handler() {}
+
+/// Load the configuration again, keeping the current one if the new one is invalid.
+/// Blocked ips and sub-domains, limits and timeouts, the QUIC certificate and the error
+/// pages apply to the connections that follow; listeners and cluster settings need a
+/// restart.
+pub async fn reload() {
+    tracing::info!("reloading config");
+
+    // a config we can't parse panics, which shouldn't take the server down with it
+    let load = || Config::load(get_cli()).map_err(|error| error.to_string());
+    let mut config = match tokio::task::spawn_blocking(load).await {
+        Ok(Ok(config)) => config,
+        Ok(Err(error)) => {
+            tracing::error!(%error, "failed to reload config, keeping the current one");
+            return;
+        }
+        Err(error) => {
+            tracing::error!(%error, "invalid config, keeping the current one");
+            return;
+        }
+    };
+    let current = get_config();
+    config.keep_startup_settings(&current);
+    transport::reload_quic(&mut config, &current);
+
+    let dir = config.error_pages_dir.clone();
+    match tokio::task::spawn_blocking(move || ErrorPages::load(dir.as_deref())).await {
+        Ok(pages) => set_error_pages(pages),
+        Err(error) => tracing::error!(%error, "failed to reload error pages"),
+    }
+
+    if let Err(error) = logging::set_level(config.log_level) {
+        tracing::error!(%error, "failed to change log level");
+    }
+    set_config(config);
+    tracing::info!("reloaded config");
+    // records for the allowed hosts the new config added
+    crate::dns::spawn_provision();
+}
@@ -1,143 +1,353 @@
 use super::*;
+use crate::buffer_pool::PooledBuffer;
+use crate::deadline::{Deadline, Expired};
+use crate::error_page::ErrorPage;
+use crate::http::forwarded::ForwardedContext;
+use crate::http::framing;
+use crate::http::sticky::CookieInjector;
+use crate::http::{RequestFrame, RequestFramer};
+use crate::mirror::Mirror;
+use crate::observability::metrics::get_metrics;
+use crate::offline;
+use crate::proxy_protocol;
+use crate::request_log::Recorded;
+use crate::share;
+use crate::webhooks::Event;
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::oneshot;
 use tracing::debug;
 use tracing::{error, Instrument};
 
 /// Response Constants
 const HTTP_REDIRECT_RESPONSE:&[u8] = b"HTTP/1.1 301 Moved Permanently\r\nLocation: https://tunnelto.dev/\r\nContent-Length: 20\r\n\r\nhttps://tunnelto.dev";
-const HTTP_INVALID_HOST_RESPONSE: &[u8] =
-    b"HTTP/1.1 400\r\nContent-Length: 23\r\n\r\nError: Invalid Hostname";
-const HTTP_NOT_FOUND_RESPONSE: &[u8] =
-    b"HTTP/1.1 404\r\nContent-Length: 23\r\n\r\nError: Tunnel Not Found";
-const HTTP_ERROR_LOCATING_HOST_RESPONSE: &[u8] =
-    b"HTTP/1.1 500\r\nContent-Length: 27\r\n\r\nError: Error finding tunnel";
-const HTTP_TUNNEL_REFUSED_RESPONSE: &[u8] =
-    b"HTTP/1.1 500\r\nContent-Length: 32\r\n\r\nTunnel says: connection refused.";
 const HTTP_OK_RESPONSE: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
 const HEALTH_CHECK_PATH: &[u8] = b"/0xDEADBEEF_HEALTH_CHECK";
 
-async fn direct_to_control(mut incoming: TcpStream) {
-    let mut control_socket =
-        match TcpStream::connect(format!("localhost:{}", get_config().control_port)).await {
-            Ok(s) => s,
+/// Most chunks written to a visitor with one vectored write
+const MAX_BATCH: usize = 32;
+
+/// How long we take in what a visitor we answered still sends, see [`respond_and_close`]
+const CLOSE_LINGER: Duration = Duration::from_secs(1);
+
+/// Serve a visitor connection, returning once it closed
+#[tracing::instrument(skip(socket))]
+pub async fn accept_connection(mut socket: TcpStream, mut peer_addr: SocketAddr) {
+    // learn who connected to the load balancer in front of us
+    if get_config().proxy_protocol {
+        // anyone else could claim to be whoever they like
+        if !get_config().trusts_proxy_header_from(peer_addr.ip()) {
+            tracing::warn!("dropping stream from a peer that isn't a trusted proxy");
+            get_metrics().routing_error("proxy_protocol_untrusted");
+            return;
+        }
+        match proxy_protocol::read_header(&mut socket, get_config().header_read_timeout).await {
+            Ok(Some(source)) => {
+                tracing::debug!(%source, "proxy protocol source");
+                peer_addr = source;
+            }
+            Ok(None) => {}
             Err(error) => {
-                tracing::warn!(?error, "failed to connect to local control server");
+                tracing::warn!(%error, "dropping stream without proxy protocol header");
+                get_metrics().routing_error("proxy_protocol");
                 return;
             }
-        };
-
-    let (mut control_r, mut control_w) = control_socket.split();
-    let (mut incoming_r, mut incoming_w) = incoming.split();
-
-    let join_1 = tokio::io::copy(&mut control_r, &mut incoming_w);
-    let join_2 = tokio::io::copy(&mut incoming_r, &mut control_w);
-
-    match futures::future::join(join_1, join_2).await {
-        (Ok(_), Ok(_)) => {}
-        (Err(error), _) | (_, Err(error)) => {
-            tracing::error!(?error, "directing stream to control failed");
         }
     }
-}
 
-#[tracing::instrument(skip(socket))]
-pub async fn accept_connection(socket: TcpStream) {
     // peek the host of the http request
     // if health check, then handle it and return
     let StreamWithPeekedHost {
-        mut socket,
+        socket,
         host,
         forwarded_for,
+        h2c,
+        sticky,
+        share_visitor,
+        control,
     } = match peek_http_request_host(socket).await {
         Some(s) => s,
         None => return,
     };
 
     let config = get_config();
+    let deadline = Deadline::start();
 
     tracing::info!(%host, %forwarded_for, "new remote connection");
     tracing::debug!("Allowed hosts: {}", config.allowed_hosts.join(", "));
 
+    // agents that can only reach our public port connect on the control path
+    let own_host = config
+        .allowed_hosts
+        .iter()
+        .any(|allowed| host.split(':').next() == Some(allowed.as_str()));
+    if control && own_host {
+        control_server::serve_connection(socket, peer_addr).await;
+        return;
+    }
+
     // parse the host string and find our client
     if config.allowed_hosts.contains(&host) {
         error!("redirect to homepage");
-        let _ = socket.write_all(HTTP_REDIRECT_RESPONSE).await;
+        respond_and_close(socket, HTTP_REDIRECT_RESPONSE).await;
         return;
     }
-    let host = match validate_host_prefix(&host) {
+    let hostname = host;
+    let host = match validate_host_prefix(&hostname) {
         Some(sub_domain) => sub_domain,
         None => {
             error!("invalid host specified");
-            let _ = socket.write_all(HTTP_INVALID_HOST_RESPONSE).await;
+            get_metrics().routing_error("invalid_host");
+            respond_and_close(socket, &ErrorPage::InvalidHost.response(&hostname)).await;
             return;
         }
     };
 
     // Special case -- we redirect this tcp connection to the control server
     if host.as_str() == "wormhole" {
-        direct_to_control(socket).await;
+        control_server::serve_connection(socket, peer_addr).await;
         return;
     }
 
+    // a share link leads to its tunnel while it's good, or to the instance holding it
+    let mut share_cookie = None;
+    let host = if share::is_link(&host) {
+        let visitor = share_visitor.as_deref();
+        match get_share_links().admit(&config.master_sig_key, &host, visitor) {
+            share::Admission::Admitted { sub_domain, cookie } => {
+                share_cookie = cookie;
+                sub_domain
+            }
+            share::Admission::Elsewhere => host,
+            share::Admission::Refused => {
+                error!(%host, "share link refused");
+                get_metrics().routing_error("share_link");
+                respond_and_close(socket, &ErrorPage::ShareLinkGone.response(&hostname)).await;
+                return;
+            }
+        }
+    } else {
+        host
+    };
+
     // find the client listening for this host
-    let client = match Connections::find_by_host(&host) {
+    let client = match Connections::find_by_host(&host, sticky) {
         Some(client) => client.clone(),
         None => {
+            // the instance the host is placed on serves it, or knows who does
+            let placed = network::placed_instance(&host)
+                .filter(|owner| owner.id != config.instance_id)
+                .filter(|_| !network::from_instance(peer_addr.ip()));
+            if let Some(owner) = placed {
+                let instance = network::Instance {
+                    ip: owner.addr.ip(),
+                };
+                network::proxy_stream(instance, socket, peer_addr, &host, &hostname, deadline)
+                    .await;
+                return;
+            }
+
             // check other instances that may be serving this host
-            match network::instance_for_host(&host).await {
+            let located = match deadline.run(network::instance_for_host(&host)).await {
+                Ok(located) => located,
+                Err(Expired) => {
+                    error!(%host, "timed out finding instance");
+                    get_metrics().routing_error("timeout");
+                    let page = ErrorPage::GatewayTimeout.response(&hostname);
+                    respond_and_close(socket, &page).await;
+                    return;
+                }
+            };
+            match located {
                 Ok((instance, _)) => {
-                    network::proxy_stream(instance, socket).await;
+                    network::proxy_stream(instance, socket, peer_addr, &host, &hostname, deadline)
+                        .await;
+                    return;
+                }
+                // the agent asked for its requests to wait while it's offline
+                Err(network::Error::DoesNotServeHost)
+                    if !h2c && get_offline_queues().accepts(&host) =>
+                {
+                    tracing::info!(%host, "tunnel offline, queueing request");
+                    offline::queue_visitor(socket, host, hostname, peer_addr, deadline).await;
                     return;
                 }
                 Err(network::Error::DoesNotServeHost) => {
+                    // the agent just dropped, and may well be back in a moment
+                    if let Some(remaining) = Connections::reconnecting(&host) {
+                        tracing::info!(%host, "tunnel reconnecting, asking to retry");
+                        get_metrics().routing_error("reconnecting");
+                        let page = ErrorPage::TunnelReconnecting {
+                            retry_after: remaining.as_secs() + 1,
+                        };
+                        respond_and_close(socket, &page.response(&hostname)).await;
+                        return;
+                    }
                     error!(%host, "no tunnel found");
-                    let _ = socket.write_all(HTTP_NOT_FOUND_RESPONSE).await;
+                    get_metrics().routing_error("not_found");
+                    respond_and_close(socket, &ErrorPage::TunnelNotFound.response(&hostname)).await;
                     return;
                 }
                 Err(error) => {
                     error!(%host, ?error, "failed to find instance");
-                    let _ = socket.write_all(HTTP_ERROR_LOCATING_HOST_RESPONSE).await;
+                    get_metrics().routing_error("locate_failed");
+                    let page = ErrorPage::ErrorLocatingTunnel.response(&hostname);
+                    respond_and_close(socket, &page).await;
                     return;
                 }
             }
         }
     };
 
+    // tunnels of other protocols have listeners of their own
+    if !client.protocol.is_http() {
+        error!(%host, protocol=%client.protocol, "tunnel isn't reached over http");
+        get_metrics().routing_error("not_found");
+        respond_and_close(socket, &ErrorPage::TunnelNotFound.response(&hostname)).await;
+        return;
+    }
+
+    // newer requests wait behind those queued while the agent was offline
+    if !h2c && get_offline_queues().accepts(&host) {
+        offline::queue_visitor(socket, host, hostname, peer_addr, deadline).await;
+        return;
+    }
+
+    let framer = if h2c {
+        RequestFramer::passthrough()
+    } else {
+        RequestFramer::with_limits(config.request_limits())
+    };
+    // pin the visitor to this agent unless they already are, and a share link's
+    // visitor first of all to the link
+    let cookie = match (&config.sticky_cookie, share_cookie) {
+        (_, Some(visitor)) if !h2c => CookieInjector::new(share::VISITOR_COOKIE, &visitor),
+        (Some(name), None) if !h2c && sticky != Some(client.session_id) => {
+            CookieInjector::new(name, &client.session_id.to_string())
+        }
+        _ => CookieInjector::disabled(),
+    };
+
+    let visit = Visit {
+        hostname,
+        peer_addr,
+        framer,
+        cookie,
+        deadline,
+        http: true,
+    };
+    tunnel_visitor(socket, client, visit).await;
+}
+
+/// How a visitor's connection is tunneled, see [`tunnel_visitor`]
+pub struct Visit {
+    /// the host the visitor asked for
+    pub hostname: String,
+    pub peer_addr: SocketAddr,
+    pub framer: RequestFramer,
+    pub cookie: CookieInjector,
+    pub deadline: Deadline,
+    /// whether the visitor speaks http, and so is answered with error pages
+    pub http: bool,
+}
+
+impl Visit {
+    /// A connection we pass through untouched, i.e. TLS or raw TCP
+    pub fn raw(hostname: String, peer_addr: SocketAddr) -> Self {
+        Visit {
+            hostname,
+            peer_addr,
+            framer: RequestFramer::passthrough(),
+            cookie: CookieInjector::disabled(),
+            deadline: Deadline::none(),
+            http: false,
+        }
+    }
+}
+
+/// Tunnel a visitor's connection to `client`, returning once it closed
+pub async fn tunnel_visitor(socket: TcpStream, client: ConnectedClient, visit: Visit) {
+    let forwarded = ForwardedContext::new(
+        visit.peer_addr,
+        get_config().trusts_forwarded_headers_from(visit.peer_addr.ip()),
+    );
+
     // allocate a new stream for this request
     let (active_stream, queue_rx) = ActiveStream::new(client.clone());
     let stream_id = active_stream.id.clone();
+    let stats = active_stream.stats.clone();
+    if visit.framer.is_passthrough() {
+        stats.responses.disable();
+    }
 
     tracing::debug!(
         stream_id = %active_stream.id.to_string(),
         "new stream connected"
     );
-    let (stream, sink) = tokio::io::split(socket);
+    let (stream, sink) = socket.into_split();
 
     // add our stream
     get_active_streams().insert(stream_id.clone(), active_stream.clone());
 
     // read from socket, write to client
     let span = observability::remote_trace("process_tcp_stream");
-    tokio::spawn(
-        async move {
-            process_tcp_stream(active_stream, stream).await;
-        }
-        .instrument(span),
+    observability::record_tunnel(&span, &client);
+    let cancel = active_stream.cancel.clone();
+    get_tasks().spawn(
+        "stream_reader",
+        cancel.clone(),
+        process_tcp_stream(active_stream, stream, visit.framer, forwarded).instrument(span),
     );
 
-    // read from client, write to socket
+    // read from client, write to socket, until the connection is done with
+    let opened = std::time::SystemTime::now();
     let span = observability::remote_trace("tunnel_to_stream");
-    tokio::spawn(
-        async move {
-            tunnel_to_stream(host, stream_id, sink, queue_rx).await;
-        }
+    observability::record_tunnel(&span, &client);
+    let writer = get_tasks().spawn(
+        "stream_writer",
+        cancel.clone(),
+        tunnel_to_stream(
+            visit.hostname,
+            visit.peer_addr,
+            stream_id.clone(),
+            sink,
+            queue_rx,
+            client.clone(),
+            stats.clone(),
+            visit.cookie,
+            visit.deadline,
+            visit.http,
+        )
         .instrument(span),
     );
+    let _ = writer.await;
+    if !visit.http {
+        get_access_log().connection(&client, visit.peer_addr.ip(), opened, stats.bytes_out());
+    }
+
+    // stop waiting on a visitor that may never send or hang up
+    cancel.cancel();
+    get_active_streams().remove(&stream_id);
+}
+
+/// Answer a visitor we won't tunnel and hang up. The request we only peeked at is
+/// still unread, and closing on it would reset the connection, losing the answer.
+async fn respond_and_close(mut socket: TcpStream, response: &[u8]) {
+    if socket.write_all(response).await.is_err() || socket.shutdown().await.is_err() {
+        return;
+    }
+    let mut discard = [0; 4096];
+    let _ = tokio::time::timeout(CLOSE_LINGER, async {
+        while matches!(socket.read(&mut discard).await, Ok(n) if n > 0) {}
+    })
+    .await;
 }
 
-fn validate_host_prefix(host: &str) -> Option<String> {
+pub fn validate_host_prefix(host: &str) -> Option<String> {
     let url = format!("http://{}", host);
     debug!(%url, "parsing host");
 
@@ -173,6 +383,14 @@ struct StreamWithPeekedHost {
     socket: TcpStream,
     host: String,
     forwarded_for: String,
+    /// an HTTP/2 prior-knowledge connection that we pass through untouched
+    h2c: bool,
+    /// the agent session the visitor's sticky cookie points to
+    sticky: Option<SessionId>,
+    /// the cookie a share link gave the visitor
+    share_visitor: Option<String>,
+    /// a websocket upgrade on the control path
+    control: bool,
 }
 /// Filter incoming remote streams
 #[tracing::instrument(skip(socket))]
@@ -184,7 +402,22 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
 
     tracing::debug!("checking stream headers");
 
-    let n = match socket.peek(&mut buf).await {
+    let peek = socket.peek(&mut buf);
+    let peeked = match get_config().header_read_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, peek).await {
+            Ok(peeked) => peeked,
+            Err(_) => {
+                tracing::debug!("timed out waiting for request head");
+                let _ = socket
+                    .write_all(&ErrorPage::RequestTimeout.response(""))
+                    .await;
+                return None;
+            }
+        },
+        None => peek.await,
+    };
+
+    let n = match peeked {
         Ok(n) => n,
         Err(e) => {
             error!("failed to read from tcp socket to determine host: {:?}", e);
@@ -200,6 +433,10 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
 
     tracing::debug!("peeked {} stream bytes ", n);
 
+    if http::h2::is_preface(&buf[..n]) {
+        return peek_h2c_host(socket, buf).await;
+    }
+
     let mut headers = [httparse::EMPTY_HEADER; 64]; // 30 seems like a generous # of headers
     let mut req = httparse::Request::new(&mut headers);
 
@@ -240,10 +477,38 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
     {
         tracing::info!(host=%host, path=%req.path.unwrap_or_default(), "peek request");
 
+        let cookies = || {
+            req.headers
+                .iter()
+                .filter(|h| h.name.eq_ignore_ascii_case("cookie"))
+                .filter_map(|h| std::str::from_utf8(h.value).ok())
+        };
+        let sticky = get_config()
+            .sticky_cookie
+            .as_deref()
+            .and_then(|name| http::sticky::cookie_value(cookies(), name)?.parse().ok());
+        let share_visitor =
+            http::sticky::cookie_value(cookies(), share::VISITOR_COOKIE).map(str::to_string);
+
+        let control = match (&get_config().control_path, req.path) {
+            (Some(control_path), Some(path)) => {
+                path.split('?').next() == Some(control_path.as_str())
+                    && req.headers.iter().any(|h| {
+                        h.name.eq_ignore_ascii_case("upgrade")
+                            && h.value.eq_ignore_ascii_case(b"websocket")
+                    })
+            }
+            _ => false,
+        };
+
         return Some(StreamWithPeekedHost {
             socket,
             host: host.to_string(),
             forwarded_for,
+            h2c: false,
+            sticky,
+            share_visitor,
+            control,
         });
     }
 
@@ -251,52 +516,236 @@ async fn peek_http_request_host(mut socket: TcpStream) -> Option<StreamWithPeeke
     None
 }
 
+/// Wait for the first request's headers on an h2c connection to learn its host
+async fn peek_h2c_host(socket: TcpStream, mut buf: Vec<u8>) -> Option<StreamWithPeekedHost> {
+    const MAX_WAIT: Duration = Duration::from_secs(2);
+    let started = Instant::now();
+
+    loop {
+        let n = match socket.peek(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                error!("failed to read from tcp socket to determine host: {:?}", e);
+                return None;
+            }
+        };
+
+        match http::h2::peek_authority(&buf[..n]) {
+            Ok(Some(host)) => {
+                tracing::info!(host=%host, "peek h2c request");
+                return Some(StreamWithPeekedHost {
+                    socket,
+                    host,
+                    forwarded_for: String::default(),
+                    h2c: true,
+                    sticky: None,
+                    share_visitor: None,
+                    control: false,
+                });
+            }
+            Ok(None) if n < buf.len() && started.elapsed() < MAX_WAIT => {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Ok(None) => {
+                tracing::info!("found no h2c request headers, dropping connection.");
+                return None;
+            }
+            Err(error) => {
+                error!(?error, "failed to parse incoming h2c bytes");
+                return None;
+            }
+        }
+    }
+}
+
 /// Process Messages from the control path in & out of the remote stream
-#[tracing::instrument(skip(tunnel_stream, tcp_stream))]
-async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: ReadHalf<TcpStream>) {
+#[tracing::instrument(skip(tunnel_stream, tcp_stream, framer, forwarded))]
+async fn process_tcp_stream(
+    mut tunnel_stream: ActiveStream,
+    tcp_stream: OwnedReadHalf,
+    mut framer: RequestFramer,
+    forwarded: ForwardedContext,
+) {
     // send initial control stream init to client
     control_server::send_client_stream_init(tunnel_stream.clone()).await;
 
-    // now read from stream and forward to clients
-    let mut buf = [0; 1024];
+    // when the visitor has to finish sending the request head it started
+    let header_read_timeout = get_config().header_read_timeout;
+    let mut head_deadline: Option<tokio::time::Instant> = None;
+    let mut mirror = Mirror::default();
+    // whether the request being read was answered here, so its body goes nowhere
+    let mut answered = false;
+    // tells whether the upgrade the visitor asked for switched protocols
+    let mut upgrade: Option<oneshot::Receiver<bool>> = None;
 
     loop {
         // client is no longer connected
-        if Connections::get(&tunnel_stream.client.id).is_none() {
+        if Connections::get(&tunnel_stream.client.session_id).is_none() {
             debug!("client disconnected, closing stream");
             let _ = tunnel_stream.tx.send(StreamMessage::NoClientTunnel).await;
             tunnel_stream.tx.close_channel();
             return;
         }
 
-        // read from stream
-        let n = match tcp_stream.read(&mut buf).await {
-            Ok(n) => n,
-            Err(e) => {
-                error!("failed to read from tcp socket: {:?}", e);
+        let frames = match upgrade.take() {
+            // what the visitor sent after it waits for the agent's answer, as
+            // a refused upgrade leaves the connection to http
+            Some(answer) => framer.upgraded(answer.await.unwrap_or(false)),
+            None => {
+                head_deadline = match header_read_timeout {
+                    Some(timeout) if framer.has_partial_head() => {
+                        head_deadline.or_else(|| Some(tokio::time::Instant::now() + timeout))
+                    }
+                    _ => None,
+                };
+
+                // read from stream
+                let read = read_visitor(&tcp_stream);
+                let read = match head_deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline, read).await {
+                        Ok(read) => read,
+                        Err(_) => {
+                            debug!("timed out waiting for request head, closing stream");
+                            reject_request(&mut tunnel_stream, ErrorPage::RequestTimeout).await;
+                            return;
+                        }
+                    },
+                    None => read.await,
+                };
+
+                let mut buf = match read {
+                    Ok(buf) => buf,
+                    Err(e) => {
+                        error!("failed to read from tcp socket: {:?}", e);
+                        return;
+                    }
+                };
+                let n = buf.len();
+
+                // the tunnel closed this stream while we were waiting on the visitor
+                if !get_active_streams().contains_key(&tunnel_stream.id) {
+                    debug!("stream closed by tunnel, dropping visitor data");
+                    return;
+                }
+
+                if n == 0 {
+                    debug!("stream ended");
+                    let _ = tunnel_stream
+                        .client
+                        .tx
+                        .send(ControlPacket::End(tunnel_stream.id.clone()))
+                        .await
+                        .map_err(|e| {
+                            error!("failed to send end signal: {:?}", e);
+                        });
+                    return;
+                }
+
+                debug!("read {} bytes", n);
+                tunnel_stream.touch();
+
+                if let Some(throttle) = &tunnel_stream.client.throttle {
+                    throttle.consume(n).await;
+                }
+                get_metrics().bytes_in(&tunnel_stream.client.host, n);
+                get_usage().bytes_in(&tunnel_stream.client.id, n);
+                tunnel_stream.stats.add_in(n);
+
+                // upgraded connections (i.e. websockets) are streamed through untouched
+                if framer.is_passthrough() {
+                    if answered {
+                        continue;
+                    }
+                    // the read buffer goes to the agent as is
+                    let packet =
+                        ControlPacket::Data(tunnel_stream.id.clone(), buf.split().freeze());
+                    if tunnel_stream.client.tx.send(packet).await.is_err() {
+                        error!("failed to forward tcp packets to disconnected client. dropping client.");
+                        Connections::remove(&tunnel_stream.client);
+                    }
+                    continue;
+                }
+
+                framer.push(&buf)
+            }
+        };
+        let frames = match frames {
+            Ok(frames) => frames,
+            Err(error) => {
+                error!(?error, "invalid http request, closing stream");
+                if let Some((quota, limit)) = error.exceeded_limit() {
+                    get_webhooks().emit(Event::QuotaExceeded {
+                        sub_domain: tunnel_stream.client.host.clone(),
+                        quota,
+                        limit,
+                    });
+                }
+                reject_request(&mut tunnel_stream, ErrorPage::from(&error)).await;
                 return;
             }
         };
 
-        if n == 0 {
-            debug!("stream ended");
-            let _ = tunnel_stream
-                .client
-                .tx
-                .send(ControlPacket::End(tunnel_stream.id.clone()))
-                .await
-                .map_err(|e| {
-                    error!("failed to send end signal: {:?}", e);
-                });
-            return;
+        let mut data = Vec::new();
+        for frame in frames {
+            match frame {
+                RequestFrame::Head(mut head) => {
+                    if let Some(answer) = tunnel_stream.stats.responses.request(&head) {
+                        upgrade = Some(answer);
+                    }
+                    let interim = tunnel_stream.stats.expect.request(&head);
+                    let secure = forwarded.is_https(&head);
+                    let edge = tunnel_stream.stats.edge.request(&head, secure);
+                    if let Some(response) =
+                        edge.or_else(|| tunnel_stream.stats.cache.request(&head))
+                    {
+                        // answered here, the agent never hears of it
+                        tunnel_stream
+                            .stats
+                            .requests
+                            .request(&head, &head.to_bytes());
+                        get_usage().request(&tunnel_stream.client.id);
+                        let _ = tunnel_stream.tx.send(StreamMessage::Cached(response)).await;
+                        answered = true;
+                        continue;
+                    }
+                    answered = false;
+                    forwarded.apply(&mut head);
+                    if let Some(interim) = interim {
+                        // the visitor sends the body on our word, not the tunnel's
+                        head.headers.remove("expect");
+                        let _ = tunnel_stream.tx.send(StreamMessage::Cached(interim)).await;
+                    }
+                    let bytes = head.to_bytes();
+                    tunnel_stream.stats.requests.request(&head, &bytes);
+                    get_usage().request(&tunnel_stream.client.id);
+                    mirror.request(&tunnel_stream.client, &head, &bytes);
+                    data.extend(bytes);
+                }
+                // without its head the agent would take it for a request of its own
+                RequestFrame::Body(_) if answered => {}
+                RequestFrame::Body(body) => {
+                    tunnel_stream.stats.requests.request_body(&body);
+                    mirror.body(&body);
+                    if data.is_empty() {
+                        data = body;
+                    } else {
+                        data.extend(body);
+                    }
+                }
+            }
+        }
+
+        if framer.is_between_requests() {
+            mirror.end();
         }
 
-        debug!("read {} bytes", n);
+        if data.is_empty() {
+            continue;
+        }
 
-        let data = &buf[..n];
-        let packet = ControlPacket::Data(tunnel_stream.id.clone(), data.to_vec());
+        let packet = ControlPacket::Data(tunnel_stream.id.clone(), data.into());
 
-        match tunnel_stream.client.tx.send(packet.clone()).await {
+        match tunnel_stream.client.tx.send(packet).await {
             Ok(_) => debug!(client_id = %tunnel_stream.client.id, "sent data packet to client"),
             Err(_) => {
                 error!("failed to forward tcp packets to disconnected client. dropping client.");
@@ -306,37 +755,118 @@ async fn process_tcp_stream(mut tunnel_stream: ActiveStream, mut tcp_stream: Rea
     }
 }
 
-#[tracing::instrument(skip(sink, stream_id, queue))]
+/// Wait for the visitor to send something and read it into a buffer from the pool.
+/// The buffer is only taken once there's data, so idle keep-alive connections don't
+/// each hold one.
+async fn read_visitor(socket: &OwnedReadHalf) -> std::io::Result<PooledBuffer> {
+    loop {
+        socket.readable().await?;
+        let mut buf = get_buffer_pool().get();
+        buf.reserve();
+        match socket.try_read_buf(&mut *buf) {
+            Ok(_) => return Ok(buf),
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Answer the visitor with an error page and end the stream on the agent's side
+async fn reject_request(tunnel_stream: &mut ActiveStream, page: ErrorPage) {
+    let _ = tunnel_stream
+        .tx
+        .send(StreamMessage::InvalidRequest(page))
+        .await;
+    let _ = tunnel_stream
+        .client
+        .tx
+        .send(ControlPacket::End(tunnel_stream.id.clone()))
+        .await;
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(sink, stream_id, queue, client, stats, cookie, deadline, error_pages))]
 async fn tunnel_to_stream(
-    subdomain: String,
+    hostname: String,
+    peer_addr: SocketAddr,
     stream_id: StreamId,
-    mut sink: WriteHalf<TcpStream>,
-    mut queue: UnboundedReceiver<StreamMessage>,
+    mut sink: OwnedWriteHalf,
+    mut queue: Receiver<StreamMessage>,
+    client: ConnectedClient,
+    stats: Arc<StreamStats>,
+    mut cookie: CookieInjector,
+    deadline: Deadline,
+    error_pages: bool,
 ) {
+    let mut first_byte = true;
+    // what we took off the queue while gathering data to write at once
+    let mut pending = None;
+    // the credits go out as the visitor takes the data, so they're never dropped
+    let mut credits = client.flow_controls().then(|| client.control.clone());
+
     loop {
-        let result = queue.next().await;
+        let result = match pending.take() {
+            Some(message) => Some(message),
+            // the agent has what's left of the request's budget to start answering
+            None if first_byte => match deadline.run(queue.next()).await {
+                Ok(message) => message,
+                Err(Expired) => {
+                    tracing::warn!(%hostname, ?stream_id, "no response in time, giving up");
+                    get_metrics().routing_error("timeout");
+                    client.queue(ControlPacket::End(stream_id.clone()));
+                    Some(StreamMessage::InvalidRequest(ErrorPage::GatewayTimeout))
+                }
+            },
+            None => queue.next().await,
+        };
 
+        // the stream ends on anything but data, possibly answering with an error page
         let result = if let Some(message) = result {
             match message {
-                StreamMessage::Data(data) => Some(data),
+                StreamMessage::Data(data) => Ok((data, true)),
+                StreamMessage::Cached(data) => Ok((data, false)),
                 StreamMessage::TunnelRefused => {
                     tracing::debug!(?stream_id, "tunnel refused");
-                    let _ = sink.write_all(HTTP_TUNNEL_REFUSED_RESPONSE).await;
-                    None
+                    Err(Some(ErrorPage::TunnelRefused))
                 }
                 StreamMessage::NoClientTunnel => {
-                    tracing::info!(%subdomain, ?stream_id, "client tunnel not found");
-                    let _ = sink.write_all(HTTP_NOT_FOUND_RESPONSE).await;
-                    None
+                    tracing::info!(%hostname, ?stream_id, "client tunnel not found");
+                    Err(Some(ErrorPage::TunnelOffline))
+                }
+                StreamMessage::InvalidRequest(page) => {
+                    tracing::debug!(?stream_id, ?page, "invalid request");
+                    Err(Some(page))
+                }
+                StreamMessage::Close => {
+                    tracing::debug!(?stream_id, "tunnel closed stream");
+                    Err(None)
                 }
             }
         } else {
-            None
+            Err(None)
         };
 
-        let data = match result {
-            Some(data) => data,
-            None => {
+        let (data, from_agent) = match result {
+            Ok((data, from_agent)) => match outgoing(&stats, &mut cookie, data, from_agent) {
+                Ok(data) => (data, from_agent),
+                Err(error) => {
+                    pending = Some(malformed_response(&client, &stream_id, error, first_byte));
+                    continue;
+                }
+            },
+            Err(page) => {
+                if let Some(page) = page.filter(|_| error_pages) {
+                    let response = page.response(&hostname);
+                    let _ = sink.write_all(&response).await;
+                    log_requests(
+                        &client,
+                        peer_addr,
+                        &stream_id,
+                        stats.requests.response(&response),
+                    );
+                }
+                log_requests(&client, peer_addr, &stream_id, stats.requests.close());
+
                 tracing::debug!("done tunneling to sink");
                 let _ = sink.shutdown().await.map_err(|_e| {
                     error!("error shutting down tcp stream");
@@ -347,11 +877,227 @@ async fn tunnel_to_stream(
             }
         };
 
-        let result = sink.write_all(&data).await;
+        // more data may be waiting, which goes out with the same write
+        let mut batch = Batch::default();
+        // the agent's data packets in it, which we credit once written
+        let mut from_agent = from_agent as u32;
+        batch.push(data);
+        while batch.len() < MAX_BATCH {
+            match queue.try_next() {
+                Ok(Some(StreamMessage::Data(data))) => {
+                    from_agent += 1;
+                    match outgoing(&stats, &mut cookie, data, true) {
+                        Ok(data) => batch.push(data),
+                        Err(error) => {
+                            let first_byte = first_byte && batch.size() == 0;
+                            pending =
+                                Some(malformed_response(&client, &stream_id, error, first_byte));
+                            break;
+                        }
+                    }
+                }
+                Ok(Some(StreamMessage::Cached(data))) => {
+                    match outgoing(&stats, &mut cookie, data, false) {
+                        Ok(data) => batch.push(data),
+                        Err(error) => {
+                            let first_byte = first_byte && batch.size() == 0;
+                            pending =
+                                Some(malformed_response(&client, &stream_id, error, first_byte));
+                            break;
+                        }
+                    }
+                }
+                Ok(Some(message)) => {
+                    pending = Some(message);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        let size = batch.size();
+
+        if let Some(throttle) = &client.throttle {
+            throttle.consume(size).await;
+        }
 
-        if let Some(error) = result.err() {
-            tracing::warn!(?error, "stream closed, disconnecting");
-            return;
+        if first_byte && size > 0 {
+            first_byte = false;
+            get_metrics()
+                .proxy_latency
+                .observe(stats.created_at.elapsed().as_secs_f64());
+        }
+        get_metrics().bytes_out(&client.host, size);
+        get_usage().bytes_out(&client.id, size);
+        stats.add_out(size);
+
+        // a visitor that stopped reading fills its queue, and then holds up every
+        // stream of the tunnel behind it, unless the agent flow controls
+        let write_timeout = get_config().stream_write_timeout;
+        match tokio::time::timeout(write_timeout, batch.write_to(&mut sink)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => {
+                tracing::warn!(?error, "stream closed, disconnecting");
+                get_active_streams().remove(&stream_id);
+                return;
+            }
+            Err(_) => {
+                tracing::warn!(?stream_id, "visitor stopped reading, disconnecting");
+                get_active_streams().remove(&stream_id);
+                client.queue(ControlPacket::End(stream_id.clone()));
+                return;
+            }
+        }
+        if let Some(credits) = credits.as_mut().filter(|_| from_agent > 0) {
+            let _ = credits
+                .send(ControlPacket::Credit(stream_id.clone(), from_agent))
+                .await;
+        }
+        for data in batch.chunks() {
+            log_requests(
+                &client,
+                peer_addr,
+                &stream_id,
+                stats.requests.response(data),
+            );
+        }
+    }
+}
+
+/// Response bytes for the visitor, from the agent or answered here: framed once for
+/// the trackers that look at them, then with what the edge adds
+fn outgoing(
+    stats: &StreamStats,
+    cookie: &mut CookieInjector,
+    data: Bytes,
+    from_agent: bool,
+) -> Result<Bytes, http::Error> {
+    let Some(mut frames) = stats.responses.response(&data)? else {
+        return Ok(cookie.push(data));
+    };
+    // only what the agent sent is worth caching
+    if from_agent {
+        stats.cache.response(&frames);
+    }
+    stats.expect.response(&frames);
+    stats.events.response(&frames);
+    stats.edge.response(&mut frames);
+    Ok(cookie.push(framing::to_bytes(frames)))
+}
+
+/// End a stream whose agent sent a response we can't frame, answering with an error
+/// page if the visitor got nothing of it yet
+fn malformed_response(
+    client: &ConnectedClient,
+    stream_id: &StreamId,
+    error: http::Error,
+    first_byte: bool,
+) -> StreamMessage {
+    tracing::warn!(?stream_id, %error, "malformed response from tunnel, closing stream");
+    get_metrics().routing_error("malformed_response");
+    client.queue(ControlPacket::End(stream_id.clone()));
+    if first_byte {
+        StreamMessage::InvalidRequest(ErrorPage::InvalidResponse)
+    } else {
+        StreamMessage::Close
+    }
+}
+
+/// Chunks of a response written to the visitor together, with vectored writes
+#[derive(Default)]
+struct Batch {
+    chunks: Vec<Bytes>,
+    /// the chunk and the offset in it we write from next
+    index: usize,
+    offset: usize,
+}
+
+impl Batch {
+    fn push(&mut self, chunk: Bytes) {
+        if !chunk.is_empty() {
+            self.chunks.push(chunk);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    fn chunks(&self) -> &[Bytes] {
+        &self.chunks
+    }
+
+    fn size(&self) -> usize {
+        self.chunks.iter().map(Bytes::len).sum()
+    }
+
+    async fn write_to(&mut self, sink: &mut OwnedWriteHalf) -> std::io::Result<()> {
+        while self.index < self.chunks.len() {
+            let slices: Vec<IoSlice> = std::iter::once(&self.chunks[self.index][self.offset..])
+                .chain(self.chunks[self.index + 1..].iter().map(|chunk| &chunk[..]))
+                .map(IoSlice::new)
+                .collect();
+            let written = sink.write_vectored(&slices).await?;
+            if written == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            self.advance(written);
+        }
+        Ok(())
+    }
+
+    fn advance(&mut self, mut written: usize) {
+        while written > 0 {
+            let left = self.chunks[self.index].len() - self.offset;
+            if written < left {
+                self.offset += written;
+                return;
+            }
+            written -= left;
+            self.index += 1;
+            self.offset = 0;
+        }
+    }
+}
+
+fn log_requests(
+    client: &ConnectedClient,
+    peer_addr: SocketAddr,
+    stream_id: &StreamId,
+    requests: impl IntoIterator<Item = Recorded>,
+) {
+    for recorded in requests {
+        get_access_log().request(client, peer_addr.ip(), &recorded);
+        get_request_log().record(client, stream_id, recorded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_visitor_that_disconnects_immediately() {
+        let config = Config::load_from_file("tests/config.toml").unwrap();
+        let _ = crate::CONFIG.set(arc_swap::ArcSwap::from_pointee(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // hanging up before sending anything, and in the middle of a request head
+        for sent in [&b""[..], b"GET / HTTP/1.1\r\nHo"] {
+            let mut visitor = TcpStream::connect(addr).await.unwrap();
+            visitor.write_all(sent).await.unwrap();
+            drop(visitor);
+
+            let (socket, peer_addr) = listener.accept().await.unwrap();
+            let served = tokio::spawn(accept_connection(socket, peer_addr));
+            let served = tokio::time::timeout(Duration::from_secs(5), served).await;
+            assert!(
+                matches!(served, Ok(Ok(()))),
+                "{:?} wasn't closed cleanly",
+                sent
+            );
         }
     }
 }
@@ -0,0 +1,101 @@
+//! Re-send captured requests through their tunnel
+use crate::connected_clients::{ConnectedClient, Connections};
+use crate::http::RequestHead;
+use crate::request_log::Recorded;
+use crate::{
+    get_active_streams, get_request_log, ActiveStream, ControlPacket, StreamId, StreamMessage,
+};
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+use thiserror::Error;
+
+/// How long we wait for the agent to answer a replayed request
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("no captured request with this id")]
+    NotFound,
+
+    #[error("the captured request was cut off and can't be replayed")]
+    Truncated,
+
+    #[error("the tunnel is offline")]
+    TunnelOffline,
+
+    #[error("the tunnel didn't answer in time")]
+    Timeout,
+
+    #[error("the tunnel closed the stream without answering")]
+    Closed,
+}
+
+/// Replay the captured request `id` of `tunnel` on a new stream, recording it in the log
+pub async fn replay(tunnel: &str, id: &str) -> Result<Recorded, Error> {
+    let capture = get_request_log()
+        .find(tunnel, id)
+        .await
+        .and_then(|recorded| recorded.capture)
+        .ok_or(Error::NotFound)?;
+    if capture.request_truncated {
+        return Err(Error::Truncated);
+    }
+    let Ok(Some((head, len))) = RequestHead::parse(&capture.request) else {
+        return Err(Error::NotFound);
+    };
+
+    let client =
+        Connections::find_by_host(&tunnel.to_string(), None).ok_or(Error::TunnelOffline)?;
+
+    tracing::info!(%tunnel, %id, "replaying request");
+    let (stream_id, mut recorded) = send(&client, &head, len, &capture.request).await?;
+    recorded.entry.replay = true;
+    get_request_log().record(&client, &stream_id, recorded.clone());
+    Ok(recorded)
+}
+
+/// Send `request`, whose head is `head` and `len` bytes long, to `client` on a new
+/// stream and wait for the head of its response
+pub async fn send(
+    client: &ConnectedClient,
+    head: &RequestHead,
+    len: usize,
+    request: &[u8],
+) -> Result<(StreamId, Recorded), Error> {
+    let (stream, mut queue) = ActiveStream::new(client.clone());
+    get_active_streams().insert(stream.id.clone(), stream.clone());
+
+    let (head_bytes, body) = request.split_at(len);
+    stream.stats.requests.request(head, head_bytes);
+    stream.stats.requests.request_body(body);
+    let mut tx = client.tx.clone();
+    let _ = tx.send(ControlPacket::Init(stream.id.clone())).await;
+    let _ = tx
+        .send(ControlPacket::Data(
+            stream.id.clone(),
+            request.to_vec().into(),
+        ))
+        .await;
+
+    let response = tokio::time::timeout(REPLAY_TIMEOUT, async {
+        let mut credits = client.flow_controls().then(|| client.control.clone());
+        while let Some(StreamMessage::Data(data)) = queue.next().await {
+            stream.touch();
+            if let Some(credits) = &mut credits {
+                let _ = credits
+                    .send(ControlPacket::Credit(stream.id.clone(), 1))
+                    .await;
+            }
+            stream.stats.add_out(data.len());
+            if let Some(recorded) = stream.stats.requests.response(&data).into_iter().next() {
+                return Ok(recorded);
+            }
+        }
+        stream.stats.requests.close().ok_or(Error::Closed)
+    })
+    .await;
+    stream.close();
+
+    let recorded = response.map_err(|_| Error::Timeout)??;
+    Ok((stream.id.clone(), recorded))
+}
@@ -0,0 +1,387 @@
+//! The most recent requests proxied through each tunnel
+use crate::connected_clients::{ConnectedClient, Connections};
+use crate::http::{RequestHead, ResponseEvent, ResponseFramer};
+use crate::{get_alerts, get_storage, storage};
+use dashmap::DashMap;
+use portal_lib::{ControlPacket, RequestLogEntry, StreamId};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// The raw bytes of a request and its response, each cut off after the capture size
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    pub request: Vec<u8>,
+    pub request_truncated: bool,
+    pub response: Vec<u8>,
+    pub response_truncated: bool,
+}
+
+impl Capture {
+    fn append(buf: &mut Vec<u8>, truncated: &mut bool, data: &[u8], limit: usize) {
+        let n = data.len().min(limit.saturating_sub(buf.len()));
+        buf.extend_from_slice(&data[..n]);
+        *truncated |= n < data.len();
+    }
+}
+
+/// A completed request, as kept in the log
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub entry: RequestLogEntry,
+    pub capture: Option<Arc<Capture>>,
+    /// the minor version of the request's `HTTP/1.x`, and its headers the access log shows
+    pub version: u8,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Requests a slow tail subscriber may fall behind before it misses some
+const TAIL_CAPACITY: usize = 256;
+
+/// A bounded log of requests per tunnel
+#[derive(Debug)]
+pub struct RequestLog {
+    capacity: usize,
+    /// bytes of each request and response to capture, 0 captures nothing
+    capture_size: usize,
+    tunnels: DashMap<String, VecDeque<Recorded>>,
+    /// every request as it's recorded, with its tunnel
+    tail: broadcast::Sender<(String, RequestLogEntry)>,
+}
+
+impl RequestLog {
+    pub fn new(capacity: usize, capture_size: usize) -> Self {
+        RequestLog {
+            capacity,
+            capture_size,
+            tunnels: DashMap::new(),
+            tail: broadcast::channel(TAIL_CAPACITY).0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Keep a request for the client's tunnel and pass it on to the agents that asked for it:
+    /// the one serving it, and those tailing the tunnel
+    pub fn record(&self, client: &ConnectedClient, stream_id: &StreamId, recorded: Recorded) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let entry = &recorded.entry;
+        get_alerts().observe(client, entry);
+        tracing::debug!(tunnel=%client.host, method=%entry.method, path=%entry.path, status=%entry.status, "request");
+        for agent in Connections::for_host(&client.host) {
+            let served = agent.session_id == client.session_id && client.request_log;
+            if served || agent.is_tailing() {
+                agent.queue(ControlPacket::Request(stream_id.clone(), entry.clone()));
+            }
+        }
+        let _ = self.tail.send((client.host.clone(), entry.clone()));
+        if let Some(capture) = recorded
+            .capture
+            .as_ref()
+            .filter(|_| get_storage().is_shared())
+        {
+            storage::save_capture(client.host.clone(), entry.clone(), capture.clone());
+        }
+
+        let mut entries = self.tunnels.entry(client.host.clone()).or_default();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(recorded);
+    }
+
+    /// The tunnel's recent requests, oldest first
+    pub fn recent(&self, tunnel: &str) -> Vec<Recorded> {
+        self.tunnels
+            .get(tunnel)
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Follow requests of all tunnels as they're recorded
+    pub fn subscribe(&self) -> broadcast::Receiver<(String, RequestLogEntry)> {
+        self.tail.subscribe()
+    }
+
+    pub fn get(&self, tunnel: &str, id: &str) -> Option<Recorded> {
+        self.tunnels
+            .get(tunnel)?
+            .iter()
+            .find(|recorded| recorded.entry.id == id)
+            .cloned()
+    }
+
+    /// A request of `tunnel` from our log or, with shared storage, another instance's
+    pub async fn find(&self, tunnel: &str, id: &str) -> Option<Recorded> {
+        if let Some(recorded) = self.get(tunnel, id) {
+            return Some(recorded);
+        }
+        match get_storage().capture(tunnel, id).await {
+            Ok(found) => found.map(|(entry, capture)| Recorded {
+                entry,
+                capture: Some(Arc::new(capture)),
+                version: 1,
+                referer: None,
+                user_agent: None,
+            }),
+            Err(error) => {
+                tracing::error!(%error, %tunnel, %id, "failed to load capture");
+                None
+            }
+        }
+    }
+
+    /// Forget a tunnel no agent serves anymore
+    pub fn remove(&self, tunnel: &str) {
+        self.tunnels.remove(tunnel);
+    }
+}
+
+/// A request still waiting for (the end of) its response
+#[derive(Debug)]
+struct Pending {
+    method: String,
+    path: String,
+    version: u8,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    timestamp: u64,
+    started: Instant,
+    size: u64,
+    status: Option<u16>,
+    capture: Option<Capture>,
+}
+
+impl Pending {
+    fn complete(self, response_size: u64) -> Recorded {
+        Recorded {
+            entry: RequestLogEntry {
+                id: Uuid::new_v4().simple().to_string(),
+                method: self.method,
+                path: self.path,
+                status: self.status.unwrap_or_default(),
+                timestamp: self.timestamp,
+                duration: self.started.elapsed().as_millis() as u64,
+                request_size: self.size,
+                response_size,
+                replay: false,
+            },
+            capture: self.capture.map(Arc::new),
+            version: self.version,
+            referer: self.referer,
+            user_agent: self.user_agent,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Exchanges {
+    pending: VecDeque<Pending>,
+    framer: ResponseFramer,
+    /// bytes of the current response seen so far
+    response_size: u64,
+    /// we lost track of the responses, i.e. they aren't HTTP/1.x
+    failed: bool,
+}
+
+/// Pairs the requests of one stream with their responses
+#[derive(Debug)]
+pub struct RequestTracker {
+    enabled: bool,
+    capture_size: usize,
+    exchanges: Mutex<Exchanges>,
+}
+
+impl RequestTracker {
+    /// Track the requests of a stream for the log, and the access log if it's `logged`
+    pub fn new(log: &RequestLog, logged: bool) -> Self {
+        RequestTracker {
+            enabled: log.is_enabled() || logged,
+            capture_size: if log.is_enabled() {
+                log.capture_size
+            } else {
+                0
+            },
+            exchanges: Mutex::new(Exchanges::default()),
+        }
+    }
+
+    /// A request head was sent to the agent
+    pub fn request(&self, head: &RequestHead, bytes: &[u8]) {
+        if !self.enabled {
+            return;
+        }
+
+        let capture = (self.capture_size > 0).then(|| {
+            let mut capture = Capture::default();
+            Capture::append(
+                &mut capture.request,
+                &mut capture.request_truncated,
+                bytes,
+                self.capture_size,
+            );
+            capture
+        });
+
+        let mut exchanges = self.exchanges.lock().unwrap();
+        exchanges.framer.expect(&head.method);
+        exchanges.pending.push_back(Pending {
+            method: head.method.clone(),
+            path: head.path.clone(),
+            version: head.version,
+            referer: head.headers.get("referer").map(String::from),
+            user_agent: head.headers.get("user-agent").map(String::from),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            started: Instant::now(),
+            size: bytes.len() as u64,
+            status: None,
+            capture,
+        });
+    }
+
+    /// Body bytes of the latest request were sent to the agent
+    pub fn request_body(&self, body: &[u8]) {
+        if !self.enabled {
+            return;
+        }
+
+        if let Some(pending) = self.exchanges.lock().unwrap().pending.back_mut() {
+            pending.size += body.len() as u64;
+            if let Some(capture) = &mut pending.capture {
+                Capture::append(
+                    &mut capture.request,
+                    &mut capture.request_truncated,
+                    body,
+                    self.capture_size,
+                );
+            }
+        }
+    }
+
+    /// Response bytes were written to the visitor, returning the requests they completed
+    pub fn response(&self, data: &[u8]) -> Vec<Recorded> {
+        if !self.enabled {
+            return vec![];
+        }
+
+        let mut exchanges = self.exchanges.lock().unwrap();
+        if exchanges.failed {
+            return vec![];
+        }
+
+        let events = match exchanges.framer.push(data) {
+            Ok(events) => events,
+            Err(error) => {
+                tracing::debug!(?error, "unable to follow responses, not logging requests");
+                exchanges.failed = true;
+                exchanges.pending.clear();
+                return vec![];
+            }
+        };
+
+        // split the data along the responses it completed
+        let mut rest = data;
+        let mut completed = vec![];
+        for event in events {
+            if let ResponseEvent::End { size } = event {
+                let n = (size.saturating_sub(exchanges.response_size) as usize).min(rest.len());
+                let (response, next) = rest.split_at(n);
+                exchanges.capture_response(response, self.capture_size);
+                exchanges.response_size = 0;
+                rest = next;
+            }
+            completed.extend(exchanges.apply(event));
+        }
+        exchanges.capture_response(rest, self.capture_size);
+        exchanges.response_size += rest.len() as u64;
+
+        completed
+    }
+
+    /// The stream ended, completing a response delimited by that
+    pub fn close(&self) -> Option<Recorded> {
+        if !self.enabled {
+            return None;
+        }
+
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let event = exchanges.framer.close()?;
+        exchanges.response_size = 0;
+        exchanges.apply(event)
+    }
+}
+
+impl Exchanges {
+    fn apply(&mut self, event: ResponseEvent) -> Option<Recorded> {
+        match event {
+            ResponseEvent::Head(head) => {
+                if let Some(pending) = self.pending.front_mut() {
+                    pending.status = Some(head.status);
+                }
+                None
+            }
+            ResponseEvent::End { size } => Some(self.pending.pop_front()?.complete(size)),
+        }
+    }
+
+    /// Keep bytes of the response to the oldest open request
+    fn capture_response(&mut self, data: &[u8], limit: usize) {
+        if let Some(capture) = self
+            .pending
+            .front_mut()
+            .and_then(|pending| pending.capture.as_mut())
+        {
+            Capture::append(
+                &mut capture.response,
+                &mut capture.response_truncated,
+                data,
+                limit,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(raw: &[u8]) -> RequestHead {
+        RequestHead::parse(raw).unwrap().unwrap().0
+    }
+
+    #[test]
+    fn test_capture_pipelined() {
+        let tracker = RequestTracker::new(&RequestLog::new(10, 64), false);
+        let a = b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\n";
+        tracker.request(&head(a), a);
+        tracker.request_body(b"hi");
+        let b = b"GET /b HTTP/1.1\r\n\r\n";
+        tracker.request(&head(b), b);
+
+        let first = b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        let second = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec();
+        let recorded = tracker.response(&[first.clone(), second.clone()].concat());
+
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].entry.status, 201);
+        assert_eq!(recorded[1].entry.path, "/b");
+
+        let capture = recorded[0].capture.as_deref().unwrap();
+        assert_eq!(capture.request, [&a[..], b"hi"].concat());
+        assert_eq!(capture.response, first);
+        let capture = recorded[1].capture.as_deref().unwrap();
+        assert_eq!(capture.response, second);
+        assert!(!capture.response_truncated);
+    }
+}
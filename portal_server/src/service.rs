@@ -0,0 +1,363 @@
+//! Tunnels visitors reach over something other than http: TLS passed through by the
+//! name visitors ask for on `tls_port`, and raw TCP on a port of the tunnel's own.
+use crate::observability::metrics::get_metrics;
+use crate::remote::{self, Visit};
+use crate::{get_config, ConnectedClient, Connections};
+use portal_lib::{Endpoint, Protocol, ServiceInfo};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest TLS record, which a client hello fits in
+const MAX_RECORD: usize = 5 + 16 * 1024;
+
+/// How long a TLS visitor has to send its client hello, unless `header_read_timeout`
+/// says otherwise
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ports from `first` to `last`, both included, i.e. `20000-20999`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid port range {}, expected i.e. 20000-20999", s);
+        let (first, last) = s.split_once('-').unwrap_or((s, s));
+        let first: u16 = first.trim().parse().map_err(|_| invalid())?;
+        let last: u16 = last.trim().parse().map_err(|_| invalid())?;
+        if first == 0 || first > last {
+            return Err(invalid());
+        }
+        Ok(PortRange { first, last })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        range.to_string()
+    }
+}
+
+/// Where visitors reach the tunnel on `sub_domain` serving `service`, along with the
+/// listener of tcp tunnels. The error is for the agent.
+pub async fn open(
+    service: &ServiceInfo,
+    sub_domain: &str,
+) -> Result<(Endpoint, Option<TcpListener>), String> {
+    let config = get_config();
+    let host = format!("{}.{}", sub_domain, config.portal_host);
+    if !service.routes.is_empty() && !service.protocol.is_http() {
+        return Err(format!(
+            "path routes only apply to http tunnels, not {}",
+            service.protocol
+        ));
+    }
+    let unsupported = || {
+        format!(
+            "{} tunnels aren't supported by this server",
+            service.protocol
+        )
+    };
+
+    let (host, port, listener) = match service.protocol {
+        Protocol::Http => (host, config.public_http_port, None),
+        Protocol::Https => (
+            host,
+            config.public_https_port.ok_or_else(unsupported)?,
+            None,
+        ),
+        Protocol::Tls => (host, config.tls_addr.ok_or_else(unsupported)?.port(), None),
+        Protocol::Tcp => {
+            let range = config.tcp_ports.ok_or_else(unsupported)?;
+            let listener = bind_tcp(range, service.port).await?;
+            let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+            (config.portal_host.clone(), port, Some(listener))
+        }
+        Protocol::Udp => return Err(unsupported()),
+    };
+
+    let endpoint = Endpoint {
+        protocol: service.protocol,
+        host,
+        port,
+    };
+    Ok((endpoint, listener))
+}
+
+/// Bind the port asked for, or else any free one in `range`
+async fn bind_tcp(range: PortRange, port: Option<u16>) -> Result<TcpListener, String> {
+    let ip = get_config().remote_addrs[0].ip();
+
+    if let Some(port) = port {
+        if !range.contains(port) {
+            return Err(format!(
+                "port {} isn't one of this server's tcp ports {}",
+                port, range
+            ));
+        }
+        return TcpListener::bind(SocketAddr::new(ip, port))
+            .await
+            .map_err(|_| format!("port {} is taken", port));
+    }
+
+    // start somewhere random, so a port just given up isn't handed out right away
+    let size = (range.last - range.first) as u32 + 1;
+    let start = rand::thread_rng().gen_range(0..size);
+    for offset in 0..size {
+        let port = range.first + ((start + offset) % size) as u16;
+        if let Ok(listener) = TcpListener::bind(SocketAddr::new(ip, port)).await {
+            return Ok(listener);
+        }
+    }
+    Err("no tcp port is free, try again later".to_string())
+}
+
+/// Serve visitors of a tcp tunnel, until the task is cancelled with its agent
+pub async fn serve_tcp(listener: TcpListener, client: ConnectedClient) {
+    crate::accept_visitors(listener, move |socket, peer_addr| {
+        let client = client.clone();
+        async move {
+            let visit = Visit::raw(client.host.clone(), peer_addr);
+            remote::tunnel_visitor(socket, client, visit).await;
+        }
+    })
+    .await;
+}
+
+/// Serve a visitor connecting to the tls listener, returning once it closed
+#[tracing::instrument(skip(socket))]
+pub async fn accept_tls(socket: TcpStream, peer_addr: SocketAddr) {
+    let Some(name) = peek_server_name(&socket).await else {
+        tracing::debug!("no server name in the client hello, dropping connection");
+        get_metrics().routing_error("no_server_name");
+        return;
+    };
+    let Some(sub_domain) = remote::validate_host_prefix(&name) else {
+        tracing::debug!(%name, "invalid server name");
+        get_metrics().routing_error("invalid_host");
+        return;
+    };
+
+    // the TLS is the agent's, so we can't tell the visitor what went wrong
+    let client = match Connections::find_by_host(&sub_domain, None) {
+        Some(client) if client.protocol == Protocol::Tls => client,
+        _ => {
+            tracing::debug!(%name, "no tls tunnel found");
+            get_metrics().routing_error("not_found");
+            return;
+        }
+    };
+
+    tracing::info!(%name, "new tls connection");
+    remote::tunnel_visitor(socket, client, Visit::raw(name, peer_addr)).await;
+}
+
+/// Wait for the visitor's client hello to learn the name it asks for
+async fn peek_server_name(socket: &TcpStream) -> Option<String> {
+    let mut buf = vec![0; MAX_RECORD];
+    let peek = async {
+        loop {
+            let n = socket.peek(&mut buf).await.ok()?;
+            match server_name(&buf[..n]) {
+                ServerName::Incomplete if n > 0 && n < buf.len() => {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                ServerName::Found(name) => return Some(name),
+                _ => return None,
+            }
+        }
+    };
+    let timeout = get_config().header_read_timeout.unwrap_or(HELLO_TIMEOUT);
+    tokio::time::timeout(timeout, peek).await.ok().flatten()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ServerName {
+    /// the client hello didn't arrive in full yet
+    Incomplete,
+    Found(String),
+    /// not a client hello, or one without the server name extension
+    Missing,
+}
+
+/// The server name a client hello at the start of `buf` asks for
+fn server_name(buf: &[u8]) -> ServerName {
+    const HANDSHAKE: u8 = 0x16;
+    match buf.first() {
+        None => return ServerName::Incomplete,
+        Some(&HANDSHAKE) => {}
+        Some(_) => return ServerName::Missing,
+    }
+    if buf.len() < 5 {
+        return ServerName::Incomplete;
+    }
+    let length = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    match buf.get(5..5 + length) {
+        Some(record) => {
+            client_hello_server_name(record).map_or(ServerName::Missing, ServerName::Found)
+        }
+        None => ServerName::Incomplete,
+    }
+}
+
+fn client_hello_server_name(record: &[u8]) -> Option<String> {
+    const CLIENT_HELLO: u8 = 1;
+    const SERVER_NAME: u16 = 0;
+    const HOST_NAME: u8 = 0;
+
+    let mut hello = Reader(record);
+    if hello.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    // length, version and random
+    hello.take(3 + 2 + 32)?;
+    let session_id = hello.u8()? as usize;
+    hello.take(session_id)?;
+    let cipher_suites = hello.u16()? as usize;
+    hello.take(cipher_suites)?;
+    let compression_methods = hello.u8()? as usize;
+    hello.take(compression_methods)?;
+
+    let length = hello.u16()? as usize;
+    let mut extensions = Reader(hello.take(length)?);
+    while let Some(kind) = extensions.u16() {
+        let length = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(length)?);
+        if kind != SERVER_NAME {
+            continue;
+        }
+        let length = extension.u16()? as usize;
+        let mut names = Reader(extension.take(length)?);
+        while let Some(name_type) = names.u8() {
+            let length = names.u16()? as usize;
+            let name = names.take(length)?;
+            if name_type == HOST_NAME {
+                return std::str::from_utf8(name).ok().map(str::to_lowercase);
+            }
+        }
+    }
+    None
+}
+
+/// Reads the length prefixed fields of a TLS message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TLS record with a client hello asking for `name`
+    fn client_hello(name: Option<&str>) -> Vec<u8> {
+        let mut extensions = vec![];
+        // supported groups, which we skip
+        extensions.extend([0, 10, 0, 4, 0, 2, 0, 29]);
+        if let Some(name) = name {
+            let name = name.as_bytes();
+            let list_len = 3 + name.len() as u16;
+            extensions.extend([0, 0]);
+            extensions.extend((list_len + 2).to_be_bytes());
+            extensions.extend(list_len.to_be_bytes());
+            extensions.push(0);
+            extensions.extend((name.len() as u16).to_be_bytes());
+            extensions.extend(name);
+        }
+
+        let mut body = vec![3, 3];
+        body.extend([7; 32]);
+        body.extend([1, 9]);
+        body.extend([0, 2, 0x13, 0x01]);
+        body.extend([1, 0]);
+        body.extend((extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+
+        let mut handshake = vec![1, 0];
+        handshake.extend((body.len() as u16).to_be_bytes());
+        handshake.extend(body);
+
+        let mut record = vec![0x16, 3, 1];
+        record.extend((handshake.len() as u16).to_be_bytes());
+        record.extend(handshake);
+        record
+    }
+
+    #[test]
+    fn test_server_name() {
+        let hello = client_hello(Some("Demo.Example.com"));
+        assert_eq!(
+            server_name(&hello),
+            ServerName::Found("demo.example.com".to_string())
+        );
+        assert_eq!(server_name(&hello[..3]), ServerName::Incomplete);
+        assert_eq!(
+            server_name(&hello[..hello.len() - 1]),
+            ServerName::Incomplete
+        );
+        assert_eq!(server_name(&client_hello(None)), ServerName::Missing);
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), ServerName::Missing);
+    }
+
+    #[test]
+    fn test_port_range() {
+        let range: PortRange = "20000-20999".parse().unwrap();
+        assert!(range.contains(20000) && range.contains(20999));
+        assert!(!range.contains(21000));
+        assert_eq!(
+            "8000".parse(),
+            Ok(PortRange {
+                first: 8000,
+                last: 8000
+            })
+        );
+        assert!("20999-20000".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+    }
+}
@@ -0,0 +1,279 @@
+//! Share links: hosts of their own leading visitors to a tunnel for a while or for a
+//! number of visitors, so it can be shared without giving its sub-domain away.
+//!
+//! A link's label is signed with our master key, so forged ones are turned away
+//! without asking anyone. Links live on the instance serving their tunnel, which
+//! the others find the way they find tunnels, and end when the tunnel closes.
+use crate::auth::SigKey;
+use crate::connected_clients::{ConnectedClient, Connections};
+use crate::{get_config, get_share_links};
+use dashmap::DashMap;
+use portal_lib::{ClientId, Endpoint, Protocol, ShareLink, ShareRequest};
+use rand::Rng;
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// What every share link's label starts with, kept from agents as a sub-domain
+pub const LINK_PREFIX: &str = "share-";
+
+/// The cookie marking visitors a link let in, so their later requests don't use it up
+pub const VISITOR_COOKIE: &str = "portal_share";
+
+/// Random characters of a label, ahead of its signature
+const NONCE_LENGTH: usize = 16;
+
+/// Hex characters of the signature a label ends with
+const SIGNATURE_LENGTH: usize = 16;
+
+/// Most links one tunnel may have at a time
+const MAX_LINKS_PER_TUNNEL: usize = 100;
+
+#[derive(Debug)]
+struct Link {
+    sub_domain: String,
+    expires_at: Option<Instant>,
+    max_uses: Option<u32>,
+    /// the visitors it let in, each one use
+    visitors: HashSet<String>,
+}
+
+/// Whether a visitor may follow a link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    /// to this sub-domain, giving them the visitor cookie if there's one
+    Admitted {
+        sub_domain: String,
+        cookie: Option<String>,
+    },
+    /// the link is signed by us, but held by another instance if any
+    Elsewhere,
+    /// the link is forged, expired or used up
+    Refused,
+}
+
+/// The share links of the tunnels we serve, by label
+#[derive(Debug, Default)]
+pub struct ShareLinks {
+    links: DashMap<String, Link>,
+}
+
+impl ShareLinks {
+    /// The label of a new link to `sub_domain`, unless it has too many already
+    pub fn create(
+        &self,
+        key: &SigKey,
+        sub_domain: &str,
+        max_uses: Option<u32>,
+        ttl: Option<Duration>,
+    ) -> Option<String> {
+        let links = self
+            .links
+            .iter()
+            .filter(|link| link.sub_domain == sub_domain)
+            .count();
+        if links >= MAX_LINKS_PER_TUNNEL {
+            return None;
+        }
+
+        let nonce = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(NONCE_LENGTH)
+            .map(char::from)
+            .collect::<String>()
+            .to_lowercase();
+        let label = format!("{}{}-{}", LINK_PREFIX, nonce, signature(key, &nonce));
+        self.links.insert(
+            label.clone(),
+            Link {
+                sub_domain: sub_domain.to_string(),
+                expires_at: ttl.map(|ttl| Instant::now() + ttl),
+                max_uses,
+                visitors: HashSet::new(),
+            },
+        );
+        Some(label)
+    }
+
+    /// Let a visitor follow the link `label`, if it's still good. `visitor` is the
+    /// cookie a link gave them before.
+    pub fn admit(&self, key: &SigKey, label: &str, visitor: Option<&str>) -> Admission {
+        if !is_signed(key, label) {
+            return Admission::Refused;
+        }
+        let Some(mut link) = self.links.get_mut(label) else {
+            return Admission::Elsewhere;
+        };
+        if link.expires_at.is_some_and(|at| at <= Instant::now()) {
+            return Admission::Refused;
+        }
+        let Some(max_uses) = link.max_uses else {
+            return Admission::Admitted {
+                sub_domain: link.sub_domain.clone(),
+                cookie: None,
+            };
+        };
+
+        if let Some(visitor) = visitor.filter(|visitor| link.visitors.contains(*visitor)) {
+            tracing::trace!(%label, %visitor, "returning share link visitor");
+            return Admission::Admitted {
+                sub_domain: link.sub_domain.clone(),
+                cookie: None,
+            };
+        }
+        if link.visitors.len() >= max_uses as usize {
+            return Admission::Refused;
+        }
+        let cookie = nonce_hex();
+        link.visitors.insert(cookie.clone());
+        Admission::Admitted {
+            sub_domain: link.sub_domain.clone(),
+            cookie: Some(cookie),
+        }
+    }
+
+    /// The tunnel behind the link `label` if we hold it, for other instances asking
+    pub fn client_for(&self, label: &str) -> Option<ClientId> {
+        let sub_domain = self.links.get(label)?.sub_domain.clone();
+        Connections::client_for_host(&sub_domain)
+    }
+
+    /// Forget the links of a tunnel that closed
+    pub fn remove(&self, sub_domain: &str) {
+        self.links.retain(|_, link| link.sub_domain != sub_domain);
+    }
+}
+
+/// Whether a visitor asked for a share link rather than a tunnel
+pub fn is_link(label: &str) -> bool {
+    label.starts_with(LINK_PREFIX)
+}
+
+/// Answer an agent asking for a share link to its tunnel
+pub fn share(client: &ConnectedClient, request: &ShareRequest) -> ShareLink {
+    let refused = |error: &str| ShareLink {
+        id: request.id.clone(),
+        error: Some(error.to_string()),
+        ..ShareLink::default()
+    };
+    if !client.protocol.is_http() {
+        return refused("share links are only for http tunnels");
+    }
+    if request.max_uses == Some(0) {
+        return refused("a share link must allow at least one visitor");
+    }
+
+    let config = get_config();
+    let ttl = request.ttl.filter(|ttl| *ttl > 0).map(Duration::from_secs);
+    let Some(label) =
+        get_share_links().create(&config.master_sig_key, &client.host, request.max_uses, ttl)
+    else {
+        return refused("this tunnel has too many share links");
+    };
+    tracing::info!(sub_domain=%client.host, %label, ?request.max_uses, ?ttl, "created share link");
+
+    let port = match client.protocol {
+        Protocol::Https => config.public_https_port.unwrap_or(443),
+        _ => config.public_http_port,
+    };
+    let endpoint = Endpoint {
+        protocol: client.protocol,
+        host: format!("{}.{}", label, config.portal_host),
+        port,
+    };
+    ShareLink {
+        id: request.id.clone(),
+        url: endpoint.to_string(),
+        max_uses: request.max_uses,
+        expires_in: ttl.map(|ttl| ttl.as_secs()),
+        error: None,
+    }
+}
+
+/// The start of our signature of a label's nonce
+fn signature(key: &SigKey, nonce: &str) -> String {
+    let mut signature = key.sign(nonce.as_bytes()).as_str().to_string();
+    signature.truncate(SIGNATURE_LENGTH);
+    signature
+}
+
+fn is_signed(key: &SigKey, label: &str) -> bool {
+    let Some((nonce, signature)) = label
+        .strip_prefix(LINK_PREFIX)
+        .and_then(|rest| rest.split_once('-'))
+    else {
+        return false;
+    };
+    nonce.len() == NONCE_LENGTH
+        && crate::admin::constant_time_eq(self::signature(key, nonce), signature)
+}
+
+fn nonce_hex() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 16]>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forged_links_are_refused() {
+        let links = ShareLinks::default();
+        let key = SigKey::generate();
+        let label = links.create(&key, "app", None, None).unwrap();
+        assert!(is_link(&label));
+        assert_eq!(
+            links.admit(&key, &label, None),
+            Admission::Admitted {
+                sub_domain: "app".to_string(),
+                cookie: None
+            }
+        );
+
+        let last = if label.ends_with('0') { '1' } else { '0' };
+        let forged = format!("{}{}", &label[..label.len() - 1], last);
+        assert_eq!(links.admit(&key, &forged, None), Admission::Refused);
+        assert_eq!(
+            links.admit(&SigKey::generate(), &label, None),
+            Admission::Refused
+        );
+
+        // another instance's link is looked for elsewhere
+        let theirs = ShareLinks::default()
+            .create(&key, "app", None, None)
+            .unwrap();
+        assert_eq!(links.admit(&key, &theirs, None), Admission::Elsewhere);
+    }
+
+    #[test]
+    fn test_links_run_out() {
+        let links = ShareLinks::default();
+        let key = SigKey::generate();
+        let label = links.create(&key, "app", Some(1), None).unwrap();
+        let Admission::Admitted {
+            cookie: Some(cookie),
+            ..
+        } = links.admit(&key, &label, None)
+        else {
+            panic!("first visitor refused");
+        };
+
+        // the visitor keeps coming back, no one else gets in
+        assert!(matches!(
+            links.admit(&key, &label, Some(&cookie)),
+            Admission::Admitted { cookie: None, .. }
+        ));
+        assert_eq!(links.admit(&key, &label, None), Admission::Refused);
+        assert_eq!(links.admit(&key, &label, Some("other")), Admission::Refused);
+
+        let expired = links
+            .create(&key, "app", None, Some(Duration::ZERO))
+            .unwrap();
+        assert_eq!(links.admit(&key, &expired, None), Admission::Refused);
+
+        links.remove("app");
+        assert_eq!(
+            links.admit(&key, &label, Some(&cookie)),
+            Admission::Elsewhere
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! The socket options of `Config` applied alike to the sockets we listen on and the
+//! ones we dial: the remote listener, the control server and the links between instances.
+use crate::get_config;
+use socket2::{SockRef, TcpKeepalive};
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use warp::hyper::server::conn::{AddrStream, Http};
+use warp::hyper::service::{make_service_fn, service_fn, Service};
+use warp::hyper::{Body, Request, Response};
+
+/// Connections waiting to be accepted on each listener
+const BACKLOG: u32 = 1024;
+
+/// The peer of a connection served by `serve`, in each request's extensions
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// Listen on `addr`, with `SO_REUSEPORT` when the config asks for it
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    bind_with(addr, get_config().reuse_port)
+}
+
+/// `shards` listeners on `addr` sharing it with `SO_REUSEPORT`, for as many accept
+/// loops. The first picks the port when `addr`'s is 0, and the others take the same.
+/// Without `SO_REUSEPORT`, i.e. on Windows, there's only the one.
+pub fn bind_shards(addr: SocketAddr, shards: usize) -> io::Result<Vec<TcpListener>> {
+    if cfg!(not(unix)) || shards <= 1 {
+        return Ok(vec![bind(addr)?]);
+    }
+
+    let first = bind_with(addr, true)?;
+    let addr = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..shards {
+        listeners.push(bind_with(addr, true)?);
+    }
+    Ok(listeners)
+}
+
+fn bind_with(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(reuse_port)?;
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket.bind(addr)?;
+    socket.listen(BACKLOG)
+}
+
+/// Connect to `addr` and tune the stream
+pub async fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(addr).await?;
+    tune(&stream)?;
+    Ok(stream)
+}
+
+/// A client for http requests to other instances. Reqwest can't set the keepalive
+/// interval, so those probes go at the system's default one.
+pub fn http_client() -> reqwest::Client {
+    let config = get_config();
+    reqwest::Client::builder()
+        .tcp_nodelay(config.tcp_nodelay)
+        .tcp_keepalive(config.tcp_keepalive)
+        .build()
+        .unwrap_or_default()
+}
+
+/// Apply `TCP_NODELAY` and keepalive to a stream we accepted or dialed
+pub fn tune(stream: &TcpStream) -> io::Result<()> {
+    let config = get_config();
+    stream.set_nodelay(config.tcp_nodelay)?;
+    if let Some(time) = config.tcp_keepalive {
+        let mut keepalive = TcpKeepalive::new().with_time(time);
+        if let Some(interval) = config.tcp_keepalive_interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+/// Serve http on `listener` with `service`, i.e. `warp::service(routes)`, tuning every
+/// connection it accepts. Warp only knows the peer of connections it accepts itself, so
+/// it's in each request's extensions as a `PeerAddr` instead.
+pub async fn serve<S>(listener: TcpListener, service: S)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    let config = get_config();
+    let mut incoming = match warp::hyper::server::conn::AddrIncoming::from_listener(listener) {
+        Ok(incoming) => incoming,
+        Err(error) => {
+            tracing::error!(?error, "failed to serve listener");
+            return;
+        }
+    };
+    incoming
+        .set_nodelay(config.tcp_nodelay)
+        .set_keepalive(config.tcp_keepalive)
+        .set_keepalive_interval(config.tcp_keepalive_interval);
+
+    let make_service = make_service_fn(move |connection: &AddrStream| {
+        let peer = PeerAddr(connection.remote_addr());
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+                request.extensions_mut().insert(peer);
+                service.clone().call(request)
+            }))
+        }
+    });
+
+    if let Err(error) = warp::hyper::Server::builder(incoming)
+        .serve(make_service)
+        .await
+    {
+        tracing::error!(?error, "http server failed");
+    }
+}
+
+/// Serve http with `service` on a connection accepted elsewhere, its peer being
+/// `peer_addr` as far as the requests' `PeerAddr` goes
+pub async fn serve_connection<S>(stream: TcpStream, peer_addr: SocketAddr, service: S)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+{
+    let peer = PeerAddr(peer_addr);
+    let service = service_fn(move |mut request: Request<Body>| {
+        request.extensions_mut().insert(peer);
+        service.clone().call(request)
+    });
+
+    if let Err(error) = Http::new()
+        .serve_connection(stream, service)
+        .with_upgrades()
+        .await
+    {
+        tracing::debug!(?error, "http connection failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_shards_share_the_port() {
+        let listeners = bind_shards("127.0.0.1:0".parse().unwrap(), 3).unwrap();
+        assert_eq!(listeners.len(), 3);
+        let port = listeners[0].local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        for listener in &listeners {
+            assert_eq!(listener.local_addr().unwrap().port(), port);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Storage that lives and dies with the process, for a single instance
+use super::{Agent, Error, Session, Storage};
+use crate::usage::{Counters, UsageRecord};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    reservations: DashMap<String, String>,
+    usage: DashMap<(String, DateTime<Utc>), Counters>,
+    sessions: DashMap<String, Session>,
+    /// by agent id and account
+    agents: DashMap<(String, String), Agent>,
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn is_shared(&self) -> bool {
+        false
+    }
+
+    async fn reservation(&self, sub_domain: &str) -> Result<Option<String>, Error> {
+        Ok(self
+            .reservations
+            .get(sub_domain)
+            .map(|account| account.clone()))
+    }
+
+    async fn reserve(&self, sub_domain: &str, account: &str) -> Result<bool, Error> {
+        let reserved = self
+            .reservations
+            .entry(sub_domain.to_string())
+            .or_insert_with(|| account.to_string());
+        Ok(*reserved == account)
+    }
+
+    async fn release(&self, sub_domain: &str) -> Result<bool, Error> {
+        Ok(self.reservations.remove(sub_domain).is_some())
+    }
+
+    async fn add_usage(&self, records: &[UsageRecord]) -> Result<(), Error> {
+        for record in records {
+            self.usage
+                .entry((record.account.clone(), record.period_start))
+                .or_default()
+                .merge(&record.counters);
+        }
+        Ok(())
+    }
+
+    async fn usage(
+        &self,
+        account: Option<&str>,
+        after: Option<DateTime<Utc>>,
+        before: Option<DateTime<Utc>>,
+    ) -> Result<Vec<UsageRecord>, Error> {
+        let mut records: Vec<_> = self
+            .usage
+            .iter()
+            .filter(|entry| {
+                let (key_account, period_start) = entry.key();
+                account.is_none_or(|account| account == key_account)
+                    && after.is_none_or(|after| *period_start > after)
+                    && before.is_none_or(|before| *period_start < before)
+            })
+            .map(|entry| UsageRecord {
+                account: entry.key().0.clone(),
+                period_start: entry.key().1,
+                counters: *entry.value(),
+            })
+            .collect();
+        records.sort_by(|a, b| (&a.account, a.period_start).cmp(&(&b.account, b.period_start)));
+        Ok(records)
+    }
+
+    async fn prune_usage(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        self.usage
+            .retain(|(_, period_start), _| *period_start >= before);
+        Ok(())
+    }
+
+    async fn open_session(&self, session: &Session) -> Result<(), Error> {
+        self.sessions
+            .insert(session.session_id.clone(), session.clone());
+        Ok(())
+    }
+
+    async fn close_session(&self, session_id: &str) -> Result<(), Error> {
+        self.sessions.remove(session_id);
+        Ok(())
+    }
+
+    async fn sessions(&self) -> Result<Vec<Session>, Error> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .iter()
+            .map(|session| session.clone())
+            .collect();
+        sessions.sort_by_key(|session| session.connected_at);
+        Ok(sessions)
+    }
+
+    async fn clear_sessions(&self, instance_id: &str) -> Result<(), Error> {
+        self.sessions
+            .retain(|_, session| session.instance_id != instance_id);
+        Ok(())
+    }
+
+    async fn agent_seen(&self, agent: &Agent) -> Result<(), Error> {
+        let key = (agent.agent_id.clone(), agent.account.clone());
+        let first_seen = self
+            .agents
+            .get(&key)
+            .map_or(agent.first_seen, |seen| seen.first_seen);
+        self.agents.insert(
+            key,
+            Agent {
+                first_seen,
+                ..agent.clone()
+            },
+        );
+        Ok(())
+    }
+
+    async fn agents(&self, account: Option<&str>) -> Result<Vec<Agent>, Error> {
+        let mut agents: Vec<_> = self
+            .agents
+            .iter()
+            .filter(|agent| account.is_none_or(|account| account == agent.account))
+            .map(|agent| agent.clone())
+            .collect();
+        agents.sort_by_key(|agent| std::cmp::Reverse(agent.last_seen));
+        Ok(agents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reservations() {
+        let storage = MemoryStorage::default();
+        assert!(storage.reserve("demo", "a").await.unwrap());
+        assert!(storage.reserve("demo", "a").await.unwrap());
+        assert!(!storage.reserve("demo", "b").await.unwrap());
+        assert_eq!(
+            storage.reservation("demo").await.unwrap().as_deref(),
+            Some("a")
+        );
+        assert!(storage.release("demo").await.unwrap());
+        assert!(!storage.release("demo").await.unwrap());
+        assert!(storage.reserve("demo", "b").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_agents_keep_first_seen() {
+        let storage = MemoryStorage::default();
+        let first_seen = DateTime::from_timestamp(3600, 0).unwrap();
+        let agent = Agent {
+            agent_id: "laptop".to_string(),
+            account: "a".to_string(),
+            name: None,
+            version: Some("0.1.0".to_string()),
+            last_ip: Some("192.0.2.1".to_string()),
+            first_seen,
+            last_seen: first_seen,
+        };
+        storage.agent_seen(&agent).await.unwrap();
+
+        let later = first_seen + chrono::Duration::hours(1);
+        storage
+            .agent_seen(&Agent {
+                version: Some("0.2.0".to_string()),
+                first_seen: later,
+                last_seen: later,
+                ..agent.clone()
+            })
+            .await
+            .unwrap();
+        storage
+            .agent_seen(&Agent {
+                account: "b".to_string(),
+                ..agent.clone()
+            })
+            .await
+            .unwrap();
+
+        let agents = storage.agents(Some("a")).await.unwrap();
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0].first_seen, first_seen);
+        assert_eq!(agents[0].last_seen, later);
+        assert_eq!(agents[0].version.as_deref(), Some("0.2.0"));
+
+        // the same machine with another key is another agent, most recently seen first
+        let agents = storage.agents(None).await.unwrap();
+        assert_eq!(agents.len(), 2);
+        assert_eq!(agents[0].account, "a");
+    }
+}
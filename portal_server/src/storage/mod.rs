@@ -0,0 +1,256 @@
+//! Where the state that outlives a connection is kept: sub-domain reservations, usage,
+//! the sessions of agents, the machines they ran on and captured requests. In memory
+//! by default, so a single instance needs nothing else, or in SQLite or redis to keep
+//! it across restarts and share it within a cluster.
+use crate::config::Config;
+use crate::request_log::Capture;
+use crate::usage::UsageRecord;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use portal_lib::RequestLogEntry;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+mod memory;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use self::memory::MemoryStorage;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[cfg(feature = "redis")]
+    #[error("RedisError: {0}")]
+    Redis(#[from] ::redis::RedisError),
+
+    #[cfg(feature = "sqlite")]
+    #[error("SqliteError: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("invalid stored value: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("storage task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// An agent connected to one of the instances sharing the storage
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub session_id: String,
+    /// the client id of the agent key
+    pub account: String,
+    pub sub_domain: String,
+    /// the instance the agent is connected to
+    pub instance_id: String,
+    pub connected_at: DateTime<Utc>,
+}
+
+/// A machine an agent key was used from, told apart by the id its agent keeps
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Agent {
+    pub agent_id: String,
+    /// the client id of the agent key
+    pub account: String,
+    /// the agent's self reported name and version when it was last seen
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// the address it last connected from
+    pub last_ip: Option<String>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// Keeps the server state instances may share
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// The account `sub_domain` is reserved for, if any
+    async fn reservation(&self, sub_domain: &str) -> Result<Option<String>, Error>;
+
+    /// Reserve `sub_domain` for `account`, returning false if another account has it
+    async fn reserve(&self, sub_domain: &str, account: &str) -> Result<bool, Error>;
+
+    /// Free `sub_domain` for anyone, returning whether it was reserved
+    async fn release(&self, sub_domain: &str) -> Result<bool, Error>;
+
+    /// Add to the usage of the records' accounts and periods
+    async fn add_usage(&self, records: &[UsageRecord]) -> Result<(), Error>;
+
+    /// Usage of the periods starting strictly between `after` and `before`, sorted by
+    /// account and period
+    async fn usage(
+        &self,
+        account: Option<&str>,
+        after: Option<DateTime<Utc>>,
+        before: Option<DateTime<Utc>>,
+    ) -> Result<Vec<UsageRecord>, Error>;
+
+    /// Forget the usage of the periods starting before `before`
+    async fn prune_usage(&self, before: DateTime<Utc>) -> Result<(), Error>;
+
+    async fn open_session(&self, session: &Session) -> Result<(), Error>;
+
+    async fn close_session(&self, session_id: &str) -> Result<(), Error>;
+
+    /// The sessions of every instance, oldest first
+    async fn sessions(&self) -> Result<Vec<Session>, Error>;
+
+    /// Forget the sessions an instance left behind, i.e. when it restarts
+    async fn clear_sessions(&self, instance_id: &str) -> Result<(), Error>;
+
+    /// Record an agent's handshake, keeping when it was first seen with this account
+    async fn agent_seen(&self, agent: &Agent) -> Result<(), Error>;
+
+    /// The agents seen, with one account's key if given, most recently seen first
+    async fn agents(&self, account: Option<&str>) -> Result<Vec<Agent>, Error>;
+
+    /// Fail unless the storage can be reached, checked before we serve anyone
+    async fn check(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Whether other instances see what we store, and so need our captures
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    /// Keep a captured request for the other instances, which the request log of the
+    /// instance that served it already has
+    async fn save_capture(
+        &self,
+        _tunnel: &str,
+        _entry: &RequestLogEntry,
+        _capture: &Capture,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// A captured request of `tunnel` another instance saved
+    async fn capture(
+        &self,
+        _tunnel: &str,
+        _id: &str,
+    ) -> Result<Option<(RequestLogEntry, Capture)>, Error> {
+        Ok(None)
+    }
+}
+
+/// How long shared storage keeps a captured request
+#[cfg(any(feature = "redis", feature = "sqlite"))]
+const CAPTURE_TTL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// The storage `storage_url` names: `memory`, `sqlite://<path>` or `redis://...`
+pub fn open(config: &Config) -> Result<Box<dyn Storage>, String> {
+    let url = config.storage_url.as_deref().unwrap_or("memory");
+    check_url(url)?;
+    if url == "memory" {
+        return Ok(Box::new(MemoryStorage::default()));
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = url.strip_prefix("sqlite://") {
+        return match sqlite::SqliteStorage::open(path) {
+            Ok(storage) => Ok(Box::new(storage)),
+            Err(error) => Err(format!("failed to open {}: {}", path, error)),
+        };
+    }
+    #[cfg(feature = "redis")]
+    if url.starts_with("redis://") || url.starts_with("rediss://") {
+        return match self::redis::RedisStorage::new(url) {
+            Ok(storage) => Ok(Box::new(storage)),
+            Err(error) => Err(format!("invalid storage url {}: {}", url, error)),
+        };
+    }
+    Err(format!("storage_url {} can't be opened", url))
+}
+
+/// Why `storage_url` can't be opened, checked with the rest of the config
+pub fn check_url(url: &str) -> Result<(), String> {
+    if url == "memory" {
+        Ok(())
+    } else if url.starts_with("redis://") || url.starts_with("rediss://") {
+        if !cfg!(feature = "redis") {
+            return Err(
+                "storage_url redis:// needs a server built with the redis feature".to_string(),
+            );
+        }
+        #[cfg(feature = "redis")]
+        if let Err(error) = ::redis::Client::open(url) {
+            return Err(format!(
+                "storage_url {} isn't a valid redis url: {}",
+                url, error
+            ));
+        }
+        Ok(())
+    } else if let Some(path) = url.strip_prefix("sqlite://") {
+        if !cfg!(feature = "sqlite") {
+            return Err(
+                "storage_url sqlite:// needs a server built with the sqlite feature".to_string(),
+            );
+        }
+        // the file can only be made in a directory that's there
+        match std::path::Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => Err(format!(
+                "storage_url {} is in {}, which isn't a directory",
+                path,
+                dir.display()
+            )),
+            _ => Ok(()),
+        }
+    } else {
+        Err(format!(
+            "storage_url {} isn't memory, sqlite:// or redis://",
+            url
+        ))
+    }
+}
+
+/// Record a session an agent of ours opened
+pub fn open_session(session: Session) {
+    tokio::spawn(async move {
+        if let Err(error) = crate::get_storage().open_session(&session).await {
+            tracing::error!(%error, session_id = %session.session_id, "failed to store session");
+        }
+    });
+}
+
+/// Forget a session an agent of ours closed
+pub fn close_session(session_id: String) {
+    tokio::spawn(async move {
+        if let Err(error) = crate::get_storage().close_session(&session_id).await {
+            tracing::error!(%error, %session_id, "failed to remove session");
+        }
+    });
+}
+
+/// Record the handshake of an agent of ours
+pub fn agent_seen(agent: Agent) {
+    tokio::spawn(async move {
+        if let Err(error) = crate::get_storage().agent_seen(&agent).await {
+            tracing::error!(%error, agent_id = %agent.agent_id, "failed to store agent");
+        }
+    });
+}
+
+/// Share a captured request with the other instances
+pub fn save_capture(tunnel: String, entry: RequestLogEntry, capture: std::sync::Arc<Capture>) {
+    tokio::spawn(async move {
+        if let Err(error) = crate::get_storage()
+            .save_capture(&tunnel, &entry, &capture)
+            .await
+        {
+            tracing::error!(%error, %tunnel, id = %entry.id, "failed to store capture");
+        }
+    });
+}
+
+/// Forget the sessions we left behind when we last ran under the same instance id
+pub fn spawn_cleanup() {
+    tokio::spawn(async move {
+        let instance_id = crate::get_config().instance_id.clone();
+        if let Err(error) = crate::get_storage().clear_sessions(&instance_id).await {
+            tracing::error!(%error, "failed to clear our old sessions");
+        }
+    });
+}
@@ -0,0 +1,363 @@
+//! Storage in redis, shared by every instance pointed at it
+//!
+//! - `portal:reservation:<sub-domain>` holds the account a sub-domain is reserved for
+//! - `portal:usage:<period>:<account>` hashes the counters of an account's period, and
+//!   `portal:usage` indexes them by the period's timestamp
+//! - `portal:sessions` hashes the sessions by id
+//! - `portal:agents` hashes the agents seen by `<account>:<agent id>`
+//! - `portal:capture:<tunnel>:<id>` holds a captured request until it expires
+use super::{Agent, Error, Session, Storage, CAPTURE_TTL};
+use crate::request_log::Capture;
+use crate::usage::{Counters, UsageRecord};
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
+use chrono::{DateTime, Utc};
+use portal_lib::RequestLogEntry;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::OnceCell;
+
+const USAGE_INDEX: &str = "portal:usage";
+const SESSIONS: &str = "portal:sessions";
+const AGENTS: &str = "portal:agents";
+
+pub struct RedisStorage {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl RedisStorage {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        Ok(RedisStorage {
+            client: redis::Client::open(url)?,
+            connection: OnceCell::new(),
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, Error> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| ConnectionManager::new(self.client.clone()))
+            .await?;
+        Ok(connection.clone())
+    }
+
+    fn reservation_key(sub_domain: &str) -> String {
+        format!("portal:reservation:{}", sub_domain)
+    }
+
+    /// The index member of an account's period, which is also its key's suffix
+    fn usage_member(account: &str, period_start: DateTime<Utc>) -> String {
+        format!("{}:{}", period_start.timestamp(), account)
+    }
+
+    fn usage_key(member: &str) -> String {
+        format!("{}:{}", USAGE_INDEX, member)
+    }
+
+    fn capture_key(tunnel: &str, id: &str) -> String {
+        format!("portal:capture:{}:{}", tunnel, id)
+    }
+}
+
+/// A captured request as we keep it in redis, with its bytes base64 encoded
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredCapture {
+    entry: RequestLogEntry,
+    request: String,
+    request_truncated: bool,
+    response: String,
+    response_truncated: bool,
+}
+
+impl StoredCapture {
+    fn new(entry: &RequestLogEntry, capture: &Capture) -> Self {
+        StoredCapture {
+            entry: entry.clone(),
+            request: general_purpose::STANDARD.encode(&capture.request),
+            request_truncated: capture.request_truncated,
+            response: general_purpose::STANDARD.encode(&capture.response),
+            response_truncated: capture.response_truncated,
+        }
+    }
+
+    fn into_parts(self) -> (RequestLogEntry, Capture) {
+        let decode = |data: &str| general_purpose::STANDARD.decode(data).unwrap_or_default();
+        let capture = Capture {
+            request: decode(&self.request),
+            request_truncated: self.request_truncated,
+            response: decode(&self.response),
+            response_truncated: self.response_truncated,
+        };
+        (self.entry, capture)
+    }
+}
+
+/// An exclusive score bound for `ZRANGEBYSCORE`
+fn bound(time: Option<DateTime<Utc>>, unbounded: &str) -> String {
+    time.map_or(unbounded.to_string(), |time| {
+        format!("({}", time.timestamp())
+    })
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    async fn check(&self) -> Result<(), Error> {
+        self.connection().await.map(|_| ())
+    }
+
+    async fn reservation(&self, sub_domain: &str) -> Result<Option<String>, Error> {
+        Ok(redis::cmd("GET")
+            .arg(Self::reservation_key(sub_domain))
+            .query_async(&mut self.connection().await?)
+            .await?)
+    }
+
+    async fn reserve(&self, sub_domain: &str, account: &str) -> Result<bool, Error> {
+        let mut connection = self.connection().await?;
+        let key = Self::reservation_key(sub_domain);
+        let set: bool = redis::cmd("SET")
+            .arg(&key)
+            .arg(account)
+            .arg("NX")
+            .query_async::<Option<String>>(&mut connection)
+            .await?
+            .is_some();
+        if set {
+            return Ok(true);
+        }
+        let reserved: Option<String> = redis::cmd("GET")
+            .arg(&key)
+            .query_async(&mut connection)
+            .await?;
+        Ok(reserved.as_deref() == Some(account))
+    }
+
+    async fn release(&self, sub_domain: &str) -> Result<bool, Error> {
+        let removed: u64 = redis::cmd("DEL")
+            .arg(Self::reservation_key(sub_domain))
+            .query_async(&mut self.connection().await?)
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn add_usage(&self, records: &[UsageRecord]) -> Result<(), Error> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for record in records {
+            let member = Self::usage_member(&record.account, record.period_start);
+            let key = Self::usage_key(&member);
+            let counters = &record.counters;
+            for (field, value) in [
+                ("bytes_in", counters.bytes_in),
+                ("bytes_out", counters.bytes_out),
+                ("requests", counters.requests),
+                ("connection_ms", counters.connection_ms),
+            ] {
+                pipe.cmd("HINCRBY").arg(&key).arg(field).arg(value).ignore();
+            }
+            pipe.cmd("ZADD")
+                .arg(USAGE_INDEX)
+                .arg(record.period_start.timestamp())
+                .arg(&member)
+                .ignore();
+        }
+        pipe.query_async::<()>(&mut self.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn usage(
+        &self,
+        account: Option<&str>,
+        after: Option<DateTime<Utc>>,
+        before: Option<DateTime<Utc>>,
+    ) -> Result<Vec<UsageRecord>, Error> {
+        let mut connection = self.connection().await?;
+        let members: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(USAGE_INDEX)
+            .arg(bound(after, "-inf"))
+            .arg(bound(before, "+inf"))
+            .query_async(&mut connection)
+            .await?;
+        let periods: Vec<(String, DateTime<Utc>, String)> = members
+            .into_iter()
+            .filter_map(|member| {
+                let (timestamp, key_account) = member.split_once(':')?;
+                let period_start = DateTime::from_timestamp(timestamp.parse().ok()?, 0)?;
+                Some((key_account.to_string(), period_start, member))
+            })
+            .filter(|(key_account, _, _)| account.is_none_or(|account| account == key_account))
+            .collect();
+        if periods.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut pipe = redis::pipe();
+        for (_, _, member) in &periods {
+            pipe.cmd("HGETALL").arg(Self::usage_key(member));
+        }
+        let counters: Vec<HashMap<String, u64>> = pipe.query_async(&mut connection).await?;
+
+        let mut records: Vec<_> = periods
+            .into_iter()
+            .zip(counters)
+            .map(|((account, period_start, _), counters)| {
+                let counter = |name: &str| counters.get(name).copied().unwrap_or_default();
+                UsageRecord {
+                    account,
+                    period_start,
+                    counters: Counters {
+                        bytes_in: counter("bytes_in"),
+                        bytes_out: counter("bytes_out"),
+                        requests: counter("requests"),
+                        connection_ms: counter("connection_ms"),
+                    },
+                }
+            })
+            .collect();
+        records.sort_by(|a, b| (&a.account, a.period_start).cmp(&(&b.account, b.period_start)));
+        Ok(records)
+    }
+
+    async fn prune_usage(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        let mut connection = self.connection().await?;
+        let members: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(USAGE_INDEX)
+            .arg("-inf")
+            .arg(bound(Some(before), "+inf"))
+            .query_async(&mut connection)
+            .await?;
+        if members.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for member in &members {
+            pipe.cmd("DEL").arg(Self::usage_key(member)).ignore();
+        }
+        pipe.cmd("ZREM").arg(USAGE_INDEX).arg(&members).ignore();
+        pipe.query_async::<()>(&mut connection).await?;
+        Ok(())
+    }
+
+    async fn open_session(&self, session: &Session) -> Result<(), Error> {
+        redis::cmd("HSET")
+            .arg(SESSIONS)
+            .arg(&session.session_id)
+            .arg(serde_json::to_string(session)?)
+            .query_async::<()>(&mut self.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn close_session(&self, session_id: &str) -> Result<(), Error> {
+        redis::cmd("HDEL")
+            .arg(SESSIONS)
+            .arg(session_id)
+            .query_async::<()>(&mut self.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn sessions(&self) -> Result<Vec<Session>, Error> {
+        let values: Vec<String> = redis::cmd("HVALS")
+            .arg(SESSIONS)
+            .query_async(&mut self.connection().await?)
+            .await?;
+        let mut sessions: Vec<Session> = values
+            .iter()
+            .filter_map(|value| serde_json::from_str(value).ok())
+            .collect();
+        sessions.sort_by_key(|session| session.connected_at);
+        Ok(sessions)
+    }
+
+    async fn clear_sessions(&self, instance_id: &str) -> Result<(), Error> {
+        let ours: Vec<String> = self
+            .sessions()
+            .await?
+            .into_iter()
+            .filter(|session| session.instance_id == instance_id)
+            .map(|session| session.session_id)
+            .collect();
+        if ours.is_empty() {
+            return Ok(());
+        }
+        redis::cmd("HDEL")
+            .arg(SESSIONS)
+            .arg(&ours)
+            .query_async::<()>(&mut self.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn agent_seen(&self, agent: &Agent) -> Result<(), Error> {
+        let mut connection = self.connection().await?;
+        let field = format!("{}:{}", agent.account, agent.agent_id);
+        let seen: Option<String> = redis::cmd("HGET")
+            .arg(AGENTS)
+            .arg(&field)
+            .query_async(&mut connection)
+            .await?;
+        let first_seen = seen
+            .and_then(|seen| serde_json::from_str::<Agent>(&seen).ok())
+            .map_or(agent.first_seen, |seen| seen.first_seen);
+        redis::cmd("HSET")
+            .arg(AGENTS)
+            .arg(&field)
+            .arg(serde_json::to_string(&Agent {
+                first_seen,
+                ..agent.clone()
+            })?)
+            .query_async::<()>(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn agents(&self, account: Option<&str>) -> Result<Vec<Agent>, Error> {
+        let values: Vec<String> = redis::cmd("HVALS")
+            .arg(AGENTS)
+            .query_async(&mut self.connection().await?)
+            .await?;
+        let mut agents: Vec<Agent> = values
+            .iter()
+            .filter_map(|value| serde_json::from_str::<Agent>(value).ok())
+            .filter(|agent| account.is_none_or(|account| account == agent.account))
+            .collect();
+        agents.sort_by_key(|agent| std::cmp::Reverse(agent.last_seen));
+        Ok(agents)
+    }
+
+    async fn save_capture(
+        &self,
+        tunnel: &str,
+        entry: &RequestLogEntry,
+        capture: &Capture,
+    ) -> Result<(), Error> {
+        redis::cmd("SET")
+            .arg(Self::capture_key(tunnel, &entry.id))
+            .arg(serde_json::to_string(&StoredCapture::new(entry, capture))?)
+            .arg("EX")
+            .arg(CAPTURE_TTL.as_secs())
+            .query_async::<()>(&mut self.connection().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn capture(
+        &self,
+        tunnel: &str,
+        id: &str,
+    ) -> Result<Option<(RequestLogEntry, Capture)>, Error> {
+        let value: Option<String> = redis::cmd("GET")
+            .arg(Self::capture_key(tunnel, id))
+            .query_async(&mut self.connection().await?)
+            .await?;
+        value
+            .map(|value| Ok(serde_json::from_str::<StoredCapture>(&value)?.into_parts()))
+            .transpose()
+    }
+}
@@ -0,0 +1,466 @@
+//! Storage in a SQLite file, kept across restarts without running anything else
+use super::{Agent, Error, Session, Storage, CAPTURE_TTL};
+use crate::request_log::Capture;
+use crate::usage::{Counters, UsageRecord};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use portal_lib::RequestLogEntry;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::sync::{Arc, Mutex};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS reservations (
+        sub_domain TEXT PRIMARY KEY,
+        account TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS usage (
+        account TEXT NOT NULL,
+        period_start INTEGER NOT NULL,
+        bytes_in INTEGER NOT NULL,
+        bytes_out INTEGER NOT NULL,
+        requests INTEGER NOT NULL,
+        connection_ms INTEGER NOT NULL,
+        PRIMARY KEY (account, period_start)
+    );
+    CREATE TABLE IF NOT EXISTS sessions (
+        session_id TEXT PRIMARY KEY,
+        account TEXT NOT NULL,
+        sub_domain TEXT NOT NULL,
+        instance_id TEXT NOT NULL,
+        connected_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS agents (
+        agent_id TEXT NOT NULL,
+        account TEXT NOT NULL,
+        name TEXT,
+        version TEXT,
+        last_ip TEXT,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL,
+        PRIMARY KEY (agent_id, account)
+    );
+    CREATE TABLE IF NOT EXISTS captures (
+        tunnel TEXT NOT NULL,
+        id TEXT NOT NULL,
+        entry TEXT NOT NULL,
+        request BLOB NOT NULL,
+        request_truncated INTEGER NOT NULL,
+        response BLOB NOT NULL,
+        response_truncated INTEGER NOT NULL,
+        saved_at INTEGER NOT NULL,
+        PRIMARY KEY (tunnel, id)
+    );
+";
+
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteStorage {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Run `f` on the connection off the async threads
+    async fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || f(&connection.lock().unwrap())).await?
+    }
+}
+
+fn timestamp(time: DateTime<Utc>) -> i64 {
+    time.timestamp_millis()
+}
+
+fn from_timestamp(millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(millis).unwrap_or_default()
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn reservation(&self, sub_domain: &str) -> Result<Option<String>, Error> {
+        let sub_domain = sub_domain.to_string();
+        self.run(move |connection| {
+            Ok(connection
+                .query_row(
+                    "SELECT account FROM reservations WHERE sub_domain = ?1",
+                    params![sub_domain],
+                    |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn reserve(&self, sub_domain: &str, account: &str) -> Result<bool, Error> {
+        let (sub_domain, account) = (sub_domain.to_string(), account.to_string());
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT OR IGNORE INTO reservations (sub_domain, account) VALUES (?1, ?2)",
+                params![sub_domain, account],
+            )?;
+            let reserved: String = connection.query_row(
+                "SELECT account FROM reservations WHERE sub_domain = ?1",
+                params![sub_domain],
+                |row| row.get(0),
+            )?;
+            Ok(reserved == account)
+        })
+        .await
+    }
+
+    async fn release(&self, sub_domain: &str) -> Result<bool, Error> {
+        let sub_domain = sub_domain.to_string();
+        self.run(move |connection| {
+            let removed = connection.execute(
+                "DELETE FROM reservations WHERE sub_domain = ?1",
+                params![sub_domain],
+            )?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn add_usage(&self, records: &[UsageRecord]) -> Result<(), Error> {
+        let records = records.to_vec();
+        self.run(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "INSERT INTO usage
+                    (account, period_start, bytes_in, bytes_out, requests, connection_ms)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (account, period_start) DO UPDATE SET
+                    bytes_in = bytes_in + excluded.bytes_in,
+                    bytes_out = bytes_out + excluded.bytes_out,
+                    requests = requests + excluded.requests,
+                    connection_ms = connection_ms + excluded.connection_ms",
+            )?;
+            for record in records {
+                let counters = record.counters;
+                statement.execute(params![
+                    record.account,
+                    timestamp(record.period_start),
+                    counters.bytes_in as i64,
+                    counters.bytes_out as i64,
+                    counters.requests as i64,
+                    counters.connection_ms as i64,
+                ])?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn usage(
+        &self,
+        account: Option<&str>,
+        after: Option<DateTime<Utc>>,
+        before: Option<DateTime<Utc>>,
+    ) -> Result<Vec<UsageRecord>, Error> {
+        let account = account.map(String::from);
+        let after = after.map_or(i64::MIN, timestamp);
+        let before = before.map_or(i64::MAX, timestamp);
+        self.run(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT account, period_start, bytes_in, bytes_out, requests, connection_ms
+                 FROM usage
+                 WHERE (?1 IS NULL OR account = ?1) AND period_start > ?2 AND period_start < ?3
+                 ORDER BY account, period_start",
+            )?;
+            let records = statement
+                .query_map(params![account, after, before], |row| {
+                    Ok(UsageRecord {
+                        account: row.get(0)?,
+                        period_start: from_timestamp(row.get(1)?),
+                        counters: Counters {
+                            bytes_in: row.get::<_, i64>(2)? as u64,
+                            bytes_out: row.get::<_, i64>(3)? as u64,
+                            requests: row.get::<_, i64>(4)? as u64,
+                            connection_ms: row.get::<_, i64>(5)? as u64,
+                        },
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(records)
+        })
+        .await
+    }
+
+    async fn prune_usage(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM usage WHERE period_start < ?1",
+                params![timestamp(before)],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn open_session(&self, session: &Session) -> Result<(), Error> {
+        let session = session.clone();
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO sessions
+                    (session_id, account, sub_domain, instance_id, connected_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    session.session_id,
+                    session.account,
+                    session.sub_domain,
+                    session.instance_id,
+                    timestamp(session.connected_at),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn close_session(&self, session_id: &str) -> Result<(), Error> {
+        let session_id = session_id.to_string();
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM sessions WHERE session_id = ?1",
+                params![session_id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn sessions(&self) -> Result<Vec<Session>, Error> {
+        self.run(|connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT session_id, account, sub_domain, instance_id, connected_at
+                 FROM sessions ORDER BY connected_at",
+            )?;
+            let sessions = statement
+                .query_map([], |row| {
+                    Ok(Session {
+                        session_id: row.get(0)?,
+                        account: row.get(1)?,
+                        sub_domain: row.get(2)?,
+                        instance_id: row.get(3)?,
+                        connected_at: from_timestamp(row.get(4)?),
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(sessions)
+        })
+        .await
+    }
+
+    async fn clear_sessions(&self, instance_id: &str) -> Result<(), Error> {
+        let instance_id = instance_id.to_string();
+        self.run(move |connection| {
+            connection.execute(
+                "DELETE FROM sessions WHERE instance_id = ?1",
+                params![instance_id],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn agent_seen(&self, agent: &Agent) -> Result<(), Error> {
+        let agent = agent.clone();
+        self.run(move |connection| {
+            connection.execute(
+                "INSERT INTO agents
+                    (agent_id, account, name, version, last_ip, first_seen, last_seen)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT (agent_id, account) DO UPDATE SET
+                    name = excluded.name,
+                    version = excluded.version,
+                    last_ip = excluded.last_ip,
+                    last_seen = excluded.last_seen",
+                params![
+                    agent.agent_id,
+                    agent.account,
+                    agent.name,
+                    agent.version,
+                    agent.last_ip,
+                    timestamp(agent.first_seen),
+                    timestamp(agent.last_seen),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn agents(&self, account: Option<&str>) -> Result<Vec<Agent>, Error> {
+        let account = account.map(String::from);
+        self.run(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT agent_id, account, name, version, last_ip, first_seen, last_seen
+                 FROM agents WHERE ?1 IS NULL OR account = ?1 ORDER BY last_seen DESC",
+            )?;
+            let agents = statement
+                .query_map(params![account], |row| {
+                    Ok(Agent {
+                        agent_id: row.get(0)?,
+                        account: row.get(1)?,
+                        name: row.get(2)?,
+                        version: row.get(3)?,
+                        last_ip: row.get(4)?,
+                        first_seen: from_timestamp(row.get(5)?),
+                        last_seen: from_timestamp(row.get(6)?),
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(agents)
+        })
+        .await
+    }
+
+    async fn save_capture(
+        &self,
+        tunnel: &str,
+        entry: &RequestLogEntry,
+        capture: &Capture,
+    ) -> Result<(), Error> {
+        let tunnel = tunnel.to_string();
+        let id = entry.id.clone();
+        let entry = serde_json::to_string(entry)?;
+        let capture = capture.clone();
+        self.run(move |connection| {
+            let now = Utc::now();
+            connection.execute(
+                "DELETE FROM captures WHERE saved_at < ?1",
+                params![timestamp(now) - CAPTURE_TTL.as_millis() as i64],
+            )?;
+            connection.execute(
+                "INSERT OR REPLACE INTO captures
+                    (tunnel, id, entry, request, request_truncated, response,
+                     response_truncated, saved_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    tunnel,
+                    id,
+                    entry,
+                    capture.request,
+                    capture.request_truncated,
+                    capture.response,
+                    capture.response_truncated,
+                    timestamp(now),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn capture(
+        &self,
+        tunnel: &str,
+        id: &str,
+    ) -> Result<Option<(RequestLogEntry, Capture)>, Error> {
+        let (tunnel, id) = (tunnel.to_string(), id.to_string());
+        let oldest = timestamp(Utc::now()) - CAPTURE_TTL.as_millis() as i64;
+        let row = self
+            .run(move |connection| {
+                Ok(connection
+                    .query_row(
+                        "SELECT entry, request, request_truncated, response, response_truncated
+                         FROM captures WHERE tunnel = ?1 AND id = ?2 AND saved_at >= ?3",
+                        params![tunnel, id, oldest],
+                        |row| {
+                            Ok((
+                                row.get::<_, String>(0)?,
+                                Capture {
+                                    request: row.get(1)?,
+                                    request_truncated: row.get(2)?,
+                                    response: row.get(3)?,
+                                    response_truncated: row.get(4)?,
+                                },
+                            ))
+                        },
+                    )
+                    .optional()?)
+            })
+            .await?;
+        row.map(|(entry, capture)| Ok((serde_json::from_str(&entry)?, capture)))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_usage_adds_up() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let period_start = DateTime::from_timestamp(3600 * 24, 0).unwrap();
+        let record = UsageRecord {
+            account: "a".to_string(),
+            period_start,
+            counters: Counters {
+                requests: 2,
+                ..Default::default()
+            },
+        };
+        storage
+            .add_usage(std::slice::from_ref(&record))
+            .await
+            .unwrap();
+        storage.add_usage(&[record]).await.unwrap();
+
+        let records = storage.usage(Some("a"), None, None).await.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].counters.requests, 4);
+        assert!(storage
+            .usage(None, Some(period_start), None)
+            .await
+            .unwrap()
+            .is_empty());
+
+        storage
+            .prune_usage(period_start + chrono::Duration::hours(1))
+            .await
+            .unwrap();
+        assert!(storage.usage(None, None, None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_agents_keep_first_seen() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        let first_seen = DateTime::from_timestamp(3600, 0).unwrap();
+        let agent = Agent {
+            agent_id: "laptop".to_string(),
+            account: "a".to_string(),
+            name: Some("laptop".to_string()),
+            version: None,
+            last_ip: Some("192.0.2.1".to_string()),
+            first_seen,
+            last_seen: first_seen,
+        };
+        storage.agent_seen(&agent).await.unwrap();
+        let later = first_seen + chrono::Duration::hours(1);
+        storage
+            .agent_seen(&Agent {
+                last_ip: Some("192.0.2.2".to_string()),
+                first_seen: later,
+                last_seen: later,
+                ..agent.clone()
+            })
+            .await
+            .unwrap();
+
+        let agents = storage.agents(Some("a")).await.unwrap();
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0].first_seen, first_seen);
+        assert_eq!(agents[0].last_seen, later);
+        assert_eq!(agents[0].last_ip.as_deref(), Some("192.0.2.2"));
+        assert!(storage.agents(Some("b")).await.unwrap().is_empty());
+    }
+}
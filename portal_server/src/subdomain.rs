@@ -0,0 +1,270 @@
+//! Which sub-domains agents may have, and making up names for those that don't ask for one
+use crate::auth::Tier;
+use crate::connected_clients::Connections;
+use crate::{get_config, get_generator, get_storage, Config};
+use portal_lib::ClientId;
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Draws of a name that's taken before we make it longer, for schemes with few names
+const MAX_ATTEMPTS: usize = 16;
+
+/// How many sub-domains we suggest instead of one that's taken
+const SUGGESTIONS: usize = 3;
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "bold", "brave", "bright", "calm", "clever", "cosmic", "crisp", "curious", "dapper",
+    "eager", "early", "fancy", "fast", "fluffy", "fresh", "gentle", "giant", "glad", "golden",
+    "happy", "hidden", "humble", "icy", "jolly", "keen", "kind", "lively", "lucky", "lunar",
+    "mellow", "merry", "mighty", "misty", "modest", "noble", "odd", "plain", "polite", "proud",
+    "quick", "quiet", "rapid", "rare", "rosy", "rustic", "shiny", "silent", "silver", "sleepy",
+    "smooth", "snowy", "solar", "spicy", "steady", "sunny", "swift", "tidy", "tiny", "vivid",
+    "warm", "wild", "wise", "witty",
+];
+
+const NOUNS: &[&str] = &[
+    "badger", "bear", "beaver", "bison", "canyon", "cedar", "cloud", "comet", "coral", "crane",
+    "creek", "dolphin", "dune", "eagle", "falcon", "fern", "finch", "fox", "galaxy", "garden",
+    "gecko", "glacier", "harbor", "hawk", "heron", "island", "koala", "lagoon", "lake", "lemur",
+    "lynx", "maple", "meadow", "meteor", "moose", "moth", "nebula", "oak", "ocean", "orca",
+    "otter", "owl", "panda", "pebble", "pine", "planet", "pond", "puffin", "rabbit", "raven",
+    "reef", "river", "robin", "salmon", "sparrow", "spruce", "star", "stone", "tiger", "tulip",
+    "valley", "walrus", "willow", "wolf",
+];
+
+/// Makes up sub-domains for agents that don't ask for one
+pub trait Generator: Send + Sync {
+    fn generate(&self, rng: &mut dyn RngCore) -> String;
+}
+
+/// How random sub-domains look
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scheme {
+    /// lowercase letters and digits, i.e. `x7k2m9qa`
+    #[default]
+    Alphanumeric,
+    /// i.e. `3fa9c01d`
+    Hex,
+    /// an adjective and a noun, i.e. `brave-otter`
+    Words,
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alphanumeric" => Ok(Scheme::Alphanumeric),
+            "hex" => Ok(Scheme::Hex),
+            "words" => Ok(Scheme::Words),
+            other => Err(format!("unknown sub-domain scheme: {}", other)),
+        }
+    }
+}
+
+pub struct Alphanumeric {
+    pub length: usize,
+}
+
+impl Generator for Alphanumeric {
+    fn generate(&self, rng: &mut dyn RngCore) -> String {
+        (0..self.length)
+            .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+            .collect::<String>()
+            .to_lowercase()
+    }
+}
+
+pub struct Hex {
+    pub length: usize,
+}
+
+impl Generator for Hex {
+    fn generate(&self, rng: &mut dyn RngCore) -> String {
+        (0..self.length)
+            .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap_or('0'))
+            .collect()
+    }
+}
+
+pub struct WordPairs;
+
+impl Generator for WordPairs {
+    fn generate(&self, rng: &mut dyn RngCore) -> String {
+        let adjective = ADJECTIVES[rng.gen_range(0..ADJECTIVES.len())];
+        let noun = NOUNS[rng.gen_range(0..NOUNS.len())];
+        format!("{}-{}", adjective, noun)
+    }
+}
+
+/// Puts a fixed prefix and suffix around another generator's names
+pub struct Affixed {
+    pub inner: Box<dyn Generator>,
+    pub prefix: String,
+    pub suffix: String,
+}
+
+impl Generator for Affixed {
+    fn generate(&self, rng: &mut dyn RngCore) -> String {
+        format!("{}{}{}", self.prefix, self.inner.generate(rng), self.suffix)
+    }
+}
+
+/// The generator our config asks for
+pub fn generator(config: &Config) -> Box<dyn Generator> {
+    let length = config.sub_domain_length;
+    let inner: Box<dyn Generator> = match config.sub_domain_scheme {
+        Scheme::Alphanumeric => Box::new(Alphanumeric { length }),
+        Scheme::Hex => Box::new(Hex { length }),
+        Scheme::Words => Box::new(WordPairs),
+    };
+    if config.sub_domain_prefix.is_empty() && config.sub_domain_suffix.is_empty() {
+        return inner;
+    }
+    Box::new(Affixed {
+        inner,
+        prefix: config.sub_domain_prefix.clone(),
+        suffix: config.sub_domain_suffix.clone(),
+    })
+}
+
+/// Whether `sub_domain` is kept from agents, by name or because it contains a filtered word,
+/// unless their tier may have reserved sub-domains. Share links are kept from everyone.
+pub fn is_reserved_for(config: &Config, tier: &Tier, sub_domain: &str) -> bool {
+    crate::share::is_link(sub_domain)
+        || (!tier.reserved_sub_domains && is_reserved(config, sub_domain))
+}
+
+pub fn is_reserved(config: &Config, sub_domain: &str) -> bool {
+    matches(
+        sub_domain,
+        &config.blocked_sub_domains,
+        &config.filtered_words,
+    )
+}
+
+fn matches(sub_domain: &str, names: &[String], words: &[String]) -> bool {
+    names
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(sub_domain))
+        || words.iter().any(|word| sub_domain.contains(word.as_str()))
+}
+
+/// A random sub-domain that isn't reserved nor served by one of our agents
+pub fn random() -> String {
+    let config = get_config();
+    let mut rng = rand::thread_rng();
+    let mut attempt = 0;
+    loop {
+        let mut sub_domain = get_generator().generate(&mut rng);
+        if attempt >= MAX_ATTEMPTS {
+            sub_domain = format!("{}-{:04x}", sub_domain, rng.gen::<u16>());
+        }
+        if !is_reserved(&config, &sub_domain) && Connections::for_host(&sub_domain).is_empty() {
+            return sub_domain;
+        }
+        attempt += 1;
+    }
+}
+
+/// Names like `sub_domain` to suggest instead of it, numbered and with an adjective in
+/// turn, i.e. `myapp-2`, `myapp-brave`, `myapp-3`
+fn candidates(sub_domain: &str, rng: &mut impl Rng) -> Vec<String> {
+    let adjectives = ADJECTIVES.choose_multiple(rng, SUGGESTIONS * 2);
+    (2..)
+        .zip(adjectives)
+        .flat_map(|(n, adjective)| {
+            [
+                format!("{}-{}", sub_domain, n),
+                format!("{}-{}", sub_domain, adjective),
+            ]
+        })
+        .collect()
+}
+
+/// Sub-domains like `sub_domain` that an agent of `account` on `tier` could have instead,
+/// for when it's taken, made up by the generator if there aren't enough
+pub async fn suggestions(sub_domain: &str, tier: &Tier, account: Option<&ClientId>) -> Vec<String> {
+    let config = get_config();
+    let candidates = candidates(sub_domain, &mut rand::thread_rng());
+    let mut suggestions = Vec::with_capacity(SUGGESTIONS);
+    for candidate in candidates {
+        if suggestions.len() == SUGGESTIONS {
+            break;
+        }
+        if is_reserved_for(&config, tier, &candidate)
+            || !Connections::for_host(&candidate).is_empty()
+        {
+            continue;
+        }
+        let reserved = match get_storage().reservation(&candidate).await {
+            Ok(Some(owner)) => account.is_none_or(|account| account.to_string() != owner),
+            Ok(None) => false,
+            Err(error) => {
+                tracing::warn!(%candidate, %error, "failed to look up the sub-domain's reservation");
+                true
+            }
+        };
+        if !reserved {
+            suggestions.push(candidate);
+        }
+    }
+    while suggestions.len() < SUGGESTIONS {
+        suggestions.push(random());
+    }
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let names = vec!["dashboard".to_string(), "API".to_string()];
+        let words = vec!["paypal".to_string()];
+        assert!(matches("dashboard", &names, &words));
+        assert!(matches("api", &names, &words));
+        assert!(matches("paypal-login", &names, &words));
+        assert!(matches("my-paypal", &names, &words));
+        assert!(!matches("dashboards", &names, &words));
+        assert!(!matches("pay-pal", &names, &words));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let candidates = candidates("myapp", &mut rand::thread_rng());
+        assert_eq!(candidates.len(), SUGGESTIONS * 4);
+        assert_eq!(candidates[0], "myapp-2");
+        assert_eq!(candidates[2], "myapp-3");
+        let adjective = candidates[1].strip_prefix("myapp-").unwrap();
+        assert!(ADJECTIVES.contains(&adjective));
+    }
+
+    #[test]
+    fn test_generators() {
+        let mut rng = rand::thread_rng();
+        let valid = |name: &str| name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+
+        let name = Alphanumeric { length: 10 }.generate(&mut rng);
+        assert!(name.len() == 10 && valid(&name) && name == name.to_lowercase());
+
+        let name = Hex { length: 6 }.generate(&mut rng);
+        assert!(name.len() == 6 && name.chars().all(|c| c.is_ascii_hexdigit()));
+
+        let name = WordPairs.generate(&mut rng);
+        let (adjective, noun) = name.split_once('-').unwrap();
+        assert!(ADJECTIVES.contains(&adjective) && NOUNS.contains(&noun));
+
+        let name = Affixed {
+            inner: Box::new(WordPairs),
+            prefix: "demo-".to_string(),
+            suffix: "-eu".to_string(),
+        }
+        .generate(&mut rng);
+        assert!(name.starts_with("demo-") && name.ends_with("-eu") && valid(&name));
+    }
+}
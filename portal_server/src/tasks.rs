@@ -0,0 +1,105 @@
+//! The tasks serving agents and visitors. Each is tied to the connection or stream
+//! it serves through a `CancellationToken` so it ends along with it, and counted
+//! by kind so a leak shows in the `live_tasks` metric.
+use crate::catch_panic;
+use crate::observability::metrics::get_metrics;
+use prometheus::IntGauge;
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+pub use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+pub struct Tasks {
+    tracker: TaskTracker,
+    /// every token descends from this one, cancelled when we shut down
+    root: CancellationToken,
+}
+
+/// Counts a task as live until it ends, however it ends
+struct Live(IntGauge);
+
+impl Drop for Live {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Tasks {
+            tracker: TaskTracker::new(),
+            root: CancellationToken::new(),
+        }
+    }
+}
+
+impl Tasks {
+    /// A token for a new agent connection or anything else living on its own
+    pub fn token(&self) -> CancellationToken {
+        self.root.child_token()
+    }
+
+    /// Run `task` until it's done or `cancel` is cancelled, catching its panics
+    pub fn spawn<F>(&self, kind: &'static str, cancel: CancellationToken, task: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let live = get_metrics().live_tasks.with_label_values(&[kind]);
+        live.inc();
+        let live = Live(live);
+        self.tracker.spawn(async move {
+            let _live = live;
+            tokio::select! {
+                _ = cancel.cancelled() => tracing::trace!(kind, "task cancelled"),
+                _ = catch_panic(task) => {}
+            }
+        })
+    }
+
+    /// Tasks running now
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracker.is_empty()
+    }
+
+    /// Cancel every task and wait up to `grace` for them to wind down
+    pub async fn shutdown(&self, grace: Duration) {
+        self.root.cancel();
+        self.tracker.close();
+        if tokio::time::timeout(grace, self.tracker.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!(remaining = self.len(), "tasks didn't end on shutdown");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancel_ends_children() {
+        let tasks = Tasks::default();
+        let connection = tasks.token();
+        let stream = connection.child_token();
+
+        let pending = tasks.spawn("test", stream, futures::future::pending());
+        let done = tasks.spawn("test", tasks.token(), async {});
+        done.await.unwrap();
+        assert_eq!(tasks.len(), 1);
+
+        // cancelling the connection ends the tasks of its streams
+        connection.cancel();
+        tokio::time::timeout(Duration::from_secs(1), pending)
+            .await
+            .expect("task wasn't cancelled")
+            .unwrap();
+        assert!(tasks.is_empty());
+    }
+}
@@ -0,0 +1,1218 @@
+//! Boot this server on ephemeral ports and drive it end to end: a scripted agent
+//! speaks the wormhole protocol through `portal_lib`, visitors send raw HTTP to
+//! the public port.
+//!
+//! ```sh
+//! cargo test -p portal_server --features integration-tests
+//! ```
+//!
+//! Every test gets its own listeners in its own runtime, while the connected
+//! agents and streams are shared by the process, so tests tell theirs apart by
+//! sub-domain.
+use crate::{
+    accept_remote, active_stream, client_manager, control_server, get_active_streams, transport,
+    Config, Connections, CONFIG,
+};
+use futures::{SinkExt, StreamExt};
+use portal_lib::quic::{self, QuicControl};
+use portal_lib::{
+    ClientHello, ClientType, ControlPacket, CorsOptions, SecretKey, ServerHello, StreamId,
+};
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::rustls::{self, pki_types::CertificateDer, RootCertStore};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// How long we wait on the server before failing a test
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The QUIC control server's certificate, for `localhost`, which the process shares
+static QUIC_CERT: OnceLock<(PathBuf, PathBuf, CertificateDer<'static>)> = OnceLock::new();
+
+fn quic_cert() -> &'static (PathBuf, PathBuf, CertificateDer<'static>) {
+    QUIC_CERT.get_or_init(|| {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("portal-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path, cert.cert.der().clone())
+    })
+}
+
+/// A server listening for agents and visitors on local ephemeral ports
+pub struct TestServer {
+    pub control: SocketAddr,
+    pub quic: SocketAddr,
+    pub remote: SocketAddr,
+}
+
+impl TestServer {
+    pub async fn start() -> TestServer {
+        let mut config = Config::load_from_file("tests/config.toml").unwrap();
+        config.allowed_hosts = vec!["localhost".to_string()];
+        // short enough for a test to wait out, long enough for every other to answer
+        config.request_timeout = Some(Duration::from_secs(2));
+        // authenticated agents get the sub-domain they ask for
+        config.default_tier = "pro".to_string();
+        // only tunnels that let us cache their responses have ranges served from it
+        config.cache_ranges = true;
+        // a proxy in front of us would tell which visitors came over https
+        config.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+        // agents connect from 127.0.0.1, tests wanting a blocked one from 127.0.0.2
+        config.blocked_ips = vec!["127.0.0.2".parse().unwrap()];
+        // scripted agents say they're this crate's version, tests wanting an older one ask
+        config.minimum_agent_version = Some(semver::Version::new(0, 1, 0));
+        // QUIC agents trust the certificate only we have
+        let (cert, key, _) = quic_cert();
+        config.quic_cert = Some(cert.display().to_string());
+        config.quic_key = Some(key.display().to_string());
+        // the unit tests may have configured the process already
+        let config = Arc::new(config);
+        if CONFIG.set(arc_swap::ArcSwap::new(config.clone())).is_err() {
+            CONFIG.get().unwrap().store(config);
+        }
+
+        let control = control_server::spawn(([127, 0, 0, 1], 0)).expect("control server");
+        let quic = transport::spawn_quic(([127, 0, 0, 1], 0).into()).expect("quic server");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = listener.local_addr().unwrap();
+        tokio::spawn(accept_remote(listener));
+        active_stream::spawn_reaper();
+
+        TestServer {
+            control,
+            quic,
+            remote,
+        }
+    }
+
+    /// Open a QUIC control connection, as an agent holding its tunnel over QUIC would
+    pub async fn quic_control(&self) -> QuicControl {
+        let mut roots = RootCertStore::empty();
+        roots.add(quic_cert().2.clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![quic::ALPN.to_vec()];
+        let mut client =
+            quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls).unwrap()));
+        client.transport_config(quic::transport());
+
+        let mut endpoint = quinn::Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+        endpoint.set_default_client_config(client);
+        let connection = endpoint
+            .connect(self.quic, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        QuicControl::open(connection).await.unwrap()
+    }
+
+    /// Open a tunnel asking for `sub_domain`
+    pub async fn agent(&self, sub_domain: &str) -> ScriptedAgent {
+        ScriptedAgent::connect(self.control, sub_domain)
+            .await
+            .unwrap_or_else(|hello| panic!("agent refused: {:?}", hello))
+    }
+
+    /// Open a tunnel on `sub_domain` authenticated with `key`, from the machine `agent_id`
+    pub async fn authenticated_agent(
+        &self,
+        sub_domain: &str,
+        key: &str,
+        agent_id: &str,
+    ) -> ScriptedAgent {
+        let mut hello = ClientHello::generate(
+            Some(sub_domain.to_string()),
+            ClientType::Auth {
+                key: SecretKey(key.to_string()),
+            },
+        );
+        hello.agent_id = Some(agent_id.to_string());
+        ScriptedAgent::connect_with(self.control, hello)
+            .await
+            .unwrap_or_else(|hello| panic!("agent refused: {:?}", hello))
+    }
+
+    /// Connect a visitor and send it `request`
+    pub async fn visit(&self, request: &str) -> TcpStream {
+        let mut visitor = TcpStream::connect(self.remote).await.unwrap();
+        visitor.write_all(request.as_bytes()).await.unwrap();
+        visitor
+    }
+}
+
+/// An agent whose every packet the test sends and checks itself
+pub struct ScriptedAgent {
+    websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pub sub_domain: String,
+    /// the data packets of each stream it may send ahead of credits, if it asked
+    pub stream_window: Option<u32>,
+}
+
+impl ScriptedAgent {
+    pub async fn connect(control: SocketAddr, sub_domain: &str) -> Result<Self, ServerHello> {
+        let hello = ClientHello::generate(Some(sub_domain.to_string()), ClientType::Anonymous);
+        Self::connect_with(control, hello).await
+    }
+
+    pub async fn connect_with(
+        control: SocketAddr,
+        mut hello: ClientHello,
+    ) -> Result<Self, ServerHello> {
+        hello.version.get_or_insert_with(agent_version);
+        let url = format!("ws://{}/wormhole", control);
+        let (mut websocket, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+
+        let hello = serde_json::to_vec(&hello).unwrap();
+        websocket.send(Message::binary(hello)).await.unwrap();
+
+        let reply = within(websocket.next()).await.unwrap().unwrap();
+        match serde_json::from_slice(&reply.into_data()).unwrap() {
+            ServerHello::Success {
+                sub_domain,
+                stream_window,
+                ..
+            } => Ok(ScriptedAgent {
+                websocket,
+                sub_domain,
+                stream_window,
+            }),
+            refused => Err(refused),
+        }
+    }
+
+    /// The host visitors reach this tunnel on
+    pub fn host(&self) -> String {
+        format!("{}.localhost", self.sub_domain)
+    }
+
+    /// The next packet from the server but pings
+    pub async fn next_packet(&mut self) -> Option<ControlPacket> {
+        loop {
+            let message = within(self.websocket.next()).await?.ok()?;
+            if message.is_close() {
+                return None;
+            }
+            if !message.is_binary() {
+                continue;
+            }
+            match ControlPacket::deserialize(message.into_data()) {
+                Ok(ControlPacket::Ping(_)) => continue,
+                Ok(packet) => return Some(packet),
+                Err(error) => panic!("invalid packet: {:?}", error),
+            }
+        }
+    }
+
+    pub async fn send(&mut self, packet: ControlPacket) {
+        let message = Message::binary(packet.serialize());
+        self.websocket.send(message).await.unwrap();
+    }
+
+    /// Wait for a stream to open, returning it with the first data sent on it
+    pub async fn accept(&mut self) -> (StreamId, Vec<u8>) {
+        let stream_id = match self.next_packet().await {
+            Some(ControlPacket::Init(stream_id)) => stream_id,
+            other => panic!("expected a new stream, got {:?}", other),
+        };
+        match self.next_packet().await {
+            Some(ControlPacket::Data(id, data)) if id == stream_id => (stream_id, data.to_vec()),
+            other => panic!("expected the request, got {:?}", other),
+        }
+    }
+
+    /// The next packet but pings if one comes within `wait`, for checking that none does
+    pub async fn packet_within(&mut self, wait: Duration) -> Option<ControlPacket> {
+        tokio::time::timeout(wait, self.next_packet())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Answer on a stream and end it, like a local service closing its connection
+    pub async fn respond(&mut self, stream_id: &StreamId, response: &str) {
+        let data = response.as_bytes().to_vec().into();
+        self.send(ControlPacket::Data(stream_id.clone(), data))
+            .await;
+        self.send(ControlPacket::End(stream_id.clone())).await;
+    }
+
+    /// Hang up without ending the streams
+    pub async fn disconnect(mut self) {
+        let _ = self.websocket.close(None).await;
+    }
+}
+
+/// Read everything the server sends a visitor until it closes the connection
+pub async fn read_response(visitor: &mut TcpStream) -> String {
+    let mut response = vec![];
+    tokio::time::timeout(TIMEOUT, visitor.read_to_end(&mut response))
+        .await
+        .expect("the visitor's connection wasn't closed")
+        .unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// Read what the server sends a visitor until it ends with `end`, for responses that
+/// leave the connection open
+pub async fn read_until(visitor: &mut TcpStream, end: &str) -> String {
+    let mut response = vec![];
+    while !response.ends_with(end.as_bytes()) {
+        let mut buf = [0; 1024];
+        let n = tokio::time::timeout(TIMEOUT, visitor.read(&mut buf))
+            .await
+            .expect("the response wasn't sent")
+            .unwrap();
+        assert!(n > 0, "{}", String::from_utf8_lossy(&response));
+        response.extend(&buf[..n]);
+    }
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// Poll `condition` until it holds
+pub async fn eventually(condition: impl Fn() -> bool) -> bool {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    while tokio::time::Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    condition()
+}
+
+/// The version scripted agents say they are, unless their hello says otherwise
+fn agent_version() -> String {
+    env!("CARGO_PKG_VERSION").to_string()
+}
+
+async fn within<T>(future: impl std::future::Future<Output = Option<T>>) -> Option<T> {
+    tokio::time::timeout(TIMEOUT, future).await.ok().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(host: &str, path: &str) -> String {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        )
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let server = TestServer::start().await;
+        let agent = server.agent("it-handshake").await;
+
+        assert!(!agent.sub_domain.is_empty());
+        assert!(!Connections::for_host(&agent.sub_domain).is_empty());
+    }
+
+    /// The hello an agent gets connecting from `source` through the remote port, `None`
+    /// when it's turned away without one
+    async fn hello_through_remote(
+        server: &TestServer,
+        source: [u8; 4],
+        sub_domain: &str,
+    ) -> Option<ServerHello> {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::from((source, 0))).unwrap();
+        let stream = socket.connect(server.remote).await.unwrap();
+        let (mut websocket, _) =
+            tokio_tungstenite::client_async("ws://wormhole.localhost/wormhole", stream)
+                .await
+                .unwrap();
+
+        let mut hello = ClientHello::generate(Some(sub_domain.to_string()), ClientType::Anonymous);
+        hello.version = Some(agent_version());
+        let hello = serde_json::to_vec(&hello).unwrap();
+        websocket.send(Message::binary(hello)).await.unwrap();
+        let reply = within(websocket.next()).await?.ok()?;
+        serde_json::from_slice(&reply.into_data()).ok()
+    }
+
+    #[tokio::test]
+    async fn test_blocked_ip_is_refused_through_the_remote_port() {
+        let server = TestServer::start().await;
+
+        let allowed = hello_through_remote(&server, [127, 0, 0, 1], "it-remote-allowed").await;
+        assert!(
+            matches!(allowed, Some(ServerHello::Success { .. })),
+            "{:?}",
+            allowed
+        );
+
+        let blocked = hello_through_remote(&server, [127, 0, 0, 2], "it-remote-blocked").await;
+        assert!(blocked.is_none(), "{:?}", blocked);
+        assert!(Connections::for_host("it-remote-blocked").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_turns_away_old_agents() {
+        let server = TestServer::start().await;
+        let mut hello =
+            ClientHello::generate(Some("it-old-agent".to_string()), ClientType::Anonymous);
+        hello.version = Some("0.0.9".to_string());
+
+        let refused = ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .err();
+        assert!(
+            matches!(&refused, Some(ServerHello::VersionTooOld { minimum, .. }) if minimum == "0.1.0"),
+            "{:?}",
+            refused
+        );
+        assert!(Connections::for_host("it-old-agent").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_routes_request_to_agent() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-routing").await;
+
+        let mut visitor = server.visit(&get(&agent.host(), "/hello")).await;
+        let (stream_id, request) = agent.accept().await;
+        let request = String::from_utf8_lossy(&request);
+        assert!(
+            request.starts_with("GET /hello HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+
+        agent
+            .respond(
+                &stream_id,
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+            )
+            .await;
+        let response = read_response(&mut visitor).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhi"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_host() {
+        let server = TestServer::start().await;
+
+        let mut visitor = server.visit(&get("it-nobody.localhost", "/")).await;
+        let response = read_response(&mut visitor).await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_stuck_agent_times_out() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-stuck").await;
+
+        let mut visitor = server.visit(&get(&agent.host(), "/")).await;
+        let (stream_id, _) = agent.accept().await;
+
+        let response = read_response(&mut visitor).await;
+        assert!(response.starts_with("HTTP/1.1 504"), "{}", response);
+        match agent.next_packet().await {
+            Some(ControlPacket::End(id)) => assert_eq!(id, stream_id),
+            other => panic!("expected the stream to end, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_cleans_up() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-disconnect").await;
+        let sub_domain = agent.sub_domain.clone();
+
+        let mut visitor = server.visit(&get(&agent.host(), "/")).await;
+        let (stream_id, _) = agent.accept().await;
+        agent.disconnect().await;
+
+        // the tunnel and its stream go away, and the visitor isn't left hanging
+        assert!(eventually(|| Connections::for_host(&sub_domain).is_empty()).await);
+        let _ = read_response(&mut visitor).await;
+        assert!(eventually(|| !get_active_streams().contains_key(&stream_id)).await);
+    }
+
+    #[tokio::test]
+    async fn test_streams_server_sent_events() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-events").await;
+
+        let mut visitor = server.visit(&get(&agent.host(), "/events")).await;
+        let (stream_id, _) = agent.accept().await;
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\r\n";
+        for data in [head, "data: one\n\n", "data: two\n\n"] {
+            let data = data.as_bytes().to_vec().into();
+            agent
+                .send(ControlPacket::Data(stream_id.clone(), data))
+                .await;
+        }
+
+        // each event reaches the visitor as it's sent, not once the response ends
+        let mut received = vec![];
+        while !String::from_utf8_lossy(&received).ends_with("data: two\n\n") {
+            let mut buf = [0; 1024];
+            let n = tokio::time::timeout(TIMEOUT, visitor.read(&mut buf))
+                .await
+                .expect("the events weren't relayed")
+                .unwrap();
+            assert!(n > 0, "{}", String::from_utf8_lossy(&received));
+            received.extend(&buf[..n]);
+        }
+        let stream = get_active_streams().get(&stream_id).unwrap().clone();
+        assert!(stream.stats.events.is_streaming());
+    }
+
+    #[tokio::test]
+    async fn test_answers_expect_continue() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-continue").await;
+
+        // the visitor is told to go ahead without waiting on the tunnel
+        let head = format!(
+            "PUT /upload HTTP/1.1\r\nHost: {}\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            agent.host()
+        );
+        let mut visitor = server.visit(&head).await;
+        let mut buf = [0; 1024];
+        let n = tokio::time::timeout(TIMEOUT, visitor.read(&mut buf))
+            .await
+            .expect("no 100 continue")
+            .unwrap();
+        assert_eq!(&buf[..n], b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        // and the tunnel gets the request without the expectation, then the body
+        let (stream_id, request) = agent.accept().await;
+        let request = String::from_utf8_lossy(&request).into_owned();
+        assert!(!request.to_lowercase().contains("expect:"), "{}", request);
+        visitor.write_all(b"hello").await.unwrap();
+        match agent.next_packet().await {
+            Some(ControlPacket::Data(id, data)) => {
+                assert_eq!(id, stream_id);
+                assert_eq!(&data[..], b"hello");
+            }
+            other => panic!("expected the body, got {:?}", other),
+        }
+        agent
+            .respond(
+                &stream_id,
+                "HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await;
+        let response = read_response(&mut visitor).await;
+        assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_passes_ranges_through() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-ranges").await;
+
+        let request = format!(
+            "GET /file HTTP/1.1\r\nHost: {}\r\nRange: bytes=2-5\r\n\r\n",
+            agent.host()
+        );
+        let mut visitor = server.visit(&request).await;
+        let (stream_id, received) = agent.accept().await;
+        let received = String::from_utf8_lossy(&received).into_owned();
+        assert!(
+            received.contains("\r\nRange: bytes=2-5\r\n"),
+            "{}",
+            received
+        );
+
+        let partial = "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 2-5/10\r\nContent-Length: 4\r\n\r\n2345";
+        agent.respond(&stream_id, partial).await;
+        let response = read_response(&mut visitor).await;
+        assert_eq!(response, partial);
+    }
+
+    #[tokio::test]
+    async fn test_serves_ranges_from_cache() {
+        let server = TestServer::start().await;
+        let mut hello =
+            ClientHello::generate(Some("it-cached-ranges".to_string()), ClientType::Anonymous);
+        hello.cache = true;
+        let mut agent = ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .unwrap();
+
+        let mut visitor = server.visit(&get(&agent.host(), "/file")).await;
+        let (stream_id, _) = agent.accept().await;
+        let full = "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nETag: \"v1\"\r\nContent-Length: 10\r\n\r\n0123456789";
+        agent.respond(&stream_id, full).await;
+        let _ = read_response(&mut visitor).await;
+
+        // the rest of the download comes from the cache
+        let ranged = |range: &str, if_range: &str| {
+            format!(
+                "GET /file HTTP/1.1\r\nHost: {}\r\nRange: {}\r\n{}\r\n",
+                agent.host(),
+                range,
+                if_range
+            )
+        };
+        let mut visitor = server.visit(&ranged("bytes=6-", "")).await;
+        let response = read_until(&mut visitor, "6789").await;
+        assert!(response.starts_with("HTTP/1.1 206"), "{}", response);
+        assert!(
+            response.contains("\r\nContent-Range: bytes 6-9/10\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("\r\nX-Cache: HIT\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n6789"), "{}", response);
+
+        // past the end, or of another version of the file
+        let mut visitor = server.visit(&ranged("bytes=10-", "")).await;
+        let response = read_until(&mut visitor, "\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 416"), "{}", response);
+        assert!(
+            response.contains("\r\nContent-Range: bytes */10\r\n"),
+            "{}",
+            response
+        );
+        let mut visitor = server
+            .visit(&ranged("bytes=6-", "If-Range: \"v0\"\r\n"))
+            .await;
+        let response = read_until(&mut visitor, "0123456789").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n0123456789"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_cache_leaves_requests_with_bodies_to_the_tunnel() {
+        let server = TestServer::start().await;
+        let mut hello =
+            ClientHello::generate(Some("it-cache-body".to_string()), ClientType::Anonymous);
+        hello.cache = true;
+        let mut agent = ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .unwrap();
+
+        let mut visitor = server.visit(&get(&agent.host(), "/file")).await;
+        let (stream_id, _) = agent.accept().await;
+        let full = "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 2\r\n\r\nhi";
+        agent.respond(&stream_id, full).await;
+        let _ = read_response(&mut visitor).await;
+
+        // a GET with a body isn't answered from the cache, so its body keeps its head
+        let request = format!(
+            "GET /file HTTP/1.1\r\nHost: {}\r\nContent-Length: 5\r\n\r\nhello",
+            agent.host()
+        );
+        let _visitor = server.visit(&request).await;
+        let (stream_id, mut received) = agent.accept().await;
+        while !received.ends_with(b"hello") {
+            match agent.next_packet().await {
+                Some(ControlPacket::Data(id, data)) if id == stream_id => {
+                    received.extend(&data[..])
+                }
+                other => panic!("expected the body, got {:?}", other),
+            }
+        }
+        let received = String::from_utf8_lossy(&received).into_owned();
+        assert!(
+            received.starts_with("GET /file HTTP/1.1\r\n"),
+            "{}",
+            received
+        );
+    }
+
+    #[tokio::test]
+    async fn test_preflight_body_stays_at_the_edge() {
+        let server = TestServer::start().await;
+        let mut hello =
+            ClientHello::generate(Some("it-preflight".to_string()), ClientType::Anonymous);
+        hello.cors = Some(CorsOptions::default());
+        let mut agent = ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .unwrap();
+
+        // a preflight carrying a request of its own as its body
+        let smuggled = "GET /smuggled HTTP/1.1\r\nHost: x\r\n\r\n";
+        let request = format!(
+            "OPTIONS /a HTTP/1.1\r\nHost: {}\r\nOrigin: https://app.example.com\r\nAccess-Control-Request-Method: PUT\r\nContent-Length: {}\r\n\r\n{}",
+            agent.host(),
+            smuggled.len(),
+            smuggled
+        );
+        let mut visitor = server.visit(&request).await;
+        let response = read_until(&mut visitor, "\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 204"), "{}", response);
+
+        assert!(matches!(
+            agent.next_packet().await,
+            Some(ControlPacket::Init(_))
+        ));
+        assert!(agent
+            .packet_within(Duration::from_millis(300))
+            .await
+            .is_none());
+        visitor
+            .write_all(get(&agent.host(), "/next").as_bytes())
+            .await
+            .unwrap();
+        match agent.next_packet().await {
+            Some(ControlPacket::Data(_, data)) => assert!(data.starts_with(b"GET /next ")),
+            other => panic!("expected the next request, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redirected_body_stays_at_the_edge() {
+        let server = TestServer::start().await;
+        let mut hello =
+            ClientHello::generate(Some("it-redirect-body".to_string()), ClientType::Anonymous);
+        hello.https_redirect = true;
+        let mut agent = ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .unwrap();
+
+        // a POST over plain http carrying a request of its own as its body
+        let smuggled = "GET /smuggled HTTP/1.1\r\nHost: x\r\n\r\n";
+        let request = format!(
+            "POST /form HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+            agent.host(),
+            smuggled.len(),
+            smuggled
+        );
+        let mut visitor = server.visit(&request).await;
+        let response = read_until(&mut visitor, "\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 308"), "{}", response);
+
+        assert!(matches!(
+            agent.next_packet().await,
+            Some(ControlPacket::Init(_))
+        ));
+        assert!(agent
+            .packet_within(Duration::from_millis(300))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_upgraded_stream_passes_through() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-upgrade").await;
+
+        let request = format!(
+            "GET /ws HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n",
+            agent.host()
+        );
+        let mut visitor = server.visit(&request).await;
+        let (stream_id, _) = agent.accept().await;
+        let switched =
+            "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        agent
+            .send(ControlPacket::Data(stream_id.clone(), switched.into()))
+            .await;
+        let response = read_until(&mut visitor, "\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 101"), "{}", response);
+
+        // frames go both ways as they are, though they'd make no sense as http
+        let frame = b"\x81\x05hello\r\n\r\n";
+        visitor.write_all(frame).await.unwrap();
+        match agent.next_packet().await {
+            Some(ControlPacket::Data(id, data)) if id == stream_id => {
+                assert_eq!(&data[..], &frame[..])
+            }
+            other => panic!("expected the frame, got {:?}", other),
+        }
+        agent
+            .send(ControlPacket::Data(
+                stream_id.clone(),
+                b"\x81\x02hi".to_vec().into(),
+            ))
+            .await;
+        let mut echoed = [0; 4];
+        tokio::time::timeout(TIMEOUT, visitor.read_exact(&mut echoed))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&echoed, b"\x81\x02hi");
+
+        // the agent closing the stream closes the visitor's connection
+        agent.send(ControlPacket::End(stream_id)).await;
+        assert_eq!(read_response(&mut visitor).await, "");
+    }
+
+    #[tokio::test]
+    async fn test_flow_controlled_stream_overrun_ends_only_it() {
+        let server = TestServer::start().await;
+        let mut hello = ClientHello::generate(Some("it-flow".to_string()), ClientType::Anonymous);
+        hello.flow_control = true;
+        let mut agent = ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .unwrap();
+        assert!(agent.stream_window.is_some());
+
+        // what a visitor reads is credited back
+        let mut visitor = server.visit(&get(&agent.host(), "/")).await;
+        let (stream_id, _) = agent.accept().await;
+        let head = "HTTP/1.1 200 OK\r\nContent-Length: 100000000\r\n\r\n";
+        agent
+            .send(ControlPacket::Data(stream_id.clone(), head.into()))
+            .await;
+        let _ = read_until(&mut visitor, "\r\n\r\n").await;
+        match agent.next_packet().await {
+            Some(ControlPacket::Credit(id, 1)) if id == stream_id => {}
+            other => panic!("expected a credit, got {:?}", other),
+        }
+
+        // a visitor that stops reading has its queue fill up, and an agent sending on
+        // regardless loses the stream
+        let chunk = vec![b'x'; 64 * 1024];
+        for _ in 0..400 {
+            agent
+                .send(ControlPacket::Data(stream_id.clone(), chunk.clone().into()))
+                .await;
+        }
+        loop {
+            match agent.next_packet().await {
+                Some(ControlPacket::Credit(id, _)) if id == stream_id => continue,
+                Some(ControlPacket::End(id)) if id == stream_id => break,
+                other => panic!("expected the stream to end, got {:?}", other),
+            }
+        }
+
+        // while the tunnel's other streams go on
+        let mut other = server.visit(&get(&agent.host(), "/other")).await;
+        let other_id = loop {
+            match agent.next_packet().await {
+                Some(ControlPacket::Credit(id, _)) if id == stream_id => continue,
+                Some(ControlPacket::Init(id)) => break id,
+                other => panic!("expected a new stream, got {:?}", other),
+            }
+        };
+        let _request = agent.next_packet().await;
+        agent
+            .respond(&other_id, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await;
+        let response = read_response(&mut other).await;
+        assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+        drop(visitor);
+    }
+
+    #[tokio::test]
+    async fn test_serves_websocket_and_quic_agents_at_once() {
+        let server = TestServer::start().await;
+        let mut websocket = server.agent("it-mixed-websocket").await;
+
+        let mut control = server.quic_control().await;
+        let mut hello =
+            ClientHello::generate(Some("it-mixed-quic".to_string()), ClientType::Anonymous);
+        hello.version = Some(agent_version());
+        control
+            .send_frame(serde_json::to_vec(&hello).unwrap())
+            .await
+            .unwrap();
+        let reply = within(control.next()).await.unwrap().unwrap();
+        let host = match serde_json::from_slice(&reply).unwrap() {
+            ServerHello::Success { sub_domain, .. } => format!("{}.localhost", sub_domain),
+            refused => panic!("quic agent refused: {:?}", refused),
+        };
+        let (mut sender, mut receiver) = control.split();
+
+        // a visitor of the QUIC agent's tunnel
+        let mut visitor = server.visit(&get(&host, "/quic")).await;
+        let stream_id = loop {
+            let frame = within(receiver.next()).await.unwrap().unwrap();
+            match ControlPacket::deserialize(frame).unwrap() {
+                ControlPacket::Init(stream_id) => break stream_id,
+                ControlPacket::Ping(_) => continue,
+                other => panic!("expected a new stream, got {:?}", other),
+            }
+        };
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nquic";
+        sender
+            .send(ControlPacket::Data(stream_id.clone(), response.into()))
+            .await
+            .unwrap();
+        sender.send(ControlPacket::End(stream_id)).await.unwrap();
+        let response = read_response(&mut visitor).await;
+        assert!(response.ends_with("\r\n\r\nquic"), "{}", response);
+
+        // and one of the websocket agent's, connected all along
+        let mut visitor = server.visit(&get(&websocket.host(), "/websocket")).await;
+        let (stream_id, _) = websocket.accept().await;
+        websocket
+            .respond(&stream_id, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nws")
+            .await;
+        let response = read_response(&mut visitor).await;
+        assert!(response.ends_with("\r\n\r\nws"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_refused_upgrade_keeps_framing_requests() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-refused-upgrade").await;
+
+        let request = format!(
+            "GET /ws HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n{}",
+            agent.host(),
+            get(&agent.host(), "/next")
+        );
+        let mut visitor = server.visit(&request).await;
+        let (stream_id, received) = agent.accept().await;
+        let received = String::from_utf8_lossy(&received).into_owned();
+        assert!(received.starts_with("GET /ws "), "{}", received);
+        assert!(!received.contains("/next"), "{}", received);
+
+        // what follows the upgrade waits for the agent to take it or not
+        assert!(agent
+            .packet_within(Duration::from_millis(300))
+            .await
+            .is_none());
+        let refused = "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n";
+        agent
+            .send(ControlPacket::Data(stream_id.clone(), refused.into()))
+            .await;
+        let response = read_until(&mut visitor, "\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+
+        // so the request after it arrives as one, with the headers we add to requests
+        match agent.next_packet().await {
+            Some(ControlPacket::Data(id, data)) if id == stream_id => {
+                let data = String::from_utf8_lossy(&data).into_owned();
+                assert!(data.starts_with("GET /next "), "{}", data);
+                assert!(data.contains("X-Forwarded-For: 127.0.0.1"), "{}", data);
+            }
+            other => panic!("expected the next request, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relays_chunked_responses() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-chunked").await;
+
+        // the ambiguous Content-Length goes, the chunks and trailers stay as they are
+        let mut visitor = server.visit(&get(&agent.host(), "/")).await;
+        let (stream_id, _) = agent.accept().await;
+        let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n";
+        let body = "5\r\nhello\r\n0\r\nX-Checksum: abc\r\n\r\n";
+        agent
+            .respond(&stream_id, &format!("{}{}", head, body))
+            .await;
+        let response = read_response(&mut visitor).await;
+        assert!(!response.contains("Content-Length"), "{}", response);
+        assert!(response.ends_with(body), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_malformed_responses() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-malformed").await;
+
+        // a response with conflicting lengths never reaches the visitor
+        let mut visitor = server.visit(&get(&agent.host(), "/")).await;
+        let (stream_id, _) = agent.accept().await;
+        let head = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello";
+        agent
+            .send(ControlPacket::Data(
+                stream_id.clone(),
+                head.as_bytes().to_vec().into(),
+            ))
+            .await;
+        let response = read_response(&mut visitor).await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{}", response);
+        match agent.next_packet().await {
+            Some(ControlPacket::End(id)) => assert_eq!(id, stream_id),
+            other => panic!("expected the stream to end, got {:?}", other),
+        }
+
+        // a broken chunk halfway through a response cuts it off
+        let mut visitor = server.visit(&get(&agent.host(), "/")).await;
+        let (stream_id, _) = agent.accept().await;
+        let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n";
+        agent
+            .send(ControlPacket::Data(
+                stream_id.clone(),
+                head.as_bytes().to_vec().into(),
+            ))
+            .await;
+        let mut buf = [0; 1024];
+        tokio::time::timeout(TIMEOUT, visitor.read(&mut buf))
+            .await
+            .expect("the response wasn't relayed")
+            .unwrap();
+        agent
+            .send(ControlPacket::Data(
+                stream_id.clone(),
+                "zz\r\n".as_bytes().to_vec().into(),
+            ))
+            .await;
+        let rest = read_response(&mut visitor).await;
+        assert!(!rest.contains("HTTP/1.1"), "{}", rest);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_drains_streams() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-drain").await;
+        let host = agent.host();
+
+        let mut visitor = server.visit(&get(&host, "/")).await;
+        let (stream_id, _) = agent.accept().await;
+        let data = "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\npartial".as_bytes();
+        agent
+            .send(ControlPacket::Data(stream_id, data.to_vec().into()))
+            .await;
+        agent.disconnect().await;
+
+        // what the agent sent before it left still reaches the visitor
+        let response = read_response(&mut visitor).await;
+        assert!(response.ends_with("\r\n\r\npartial"), "{}", response);
+
+        // new visitors are asked to come back rather than told there's no tunnel
+        let mut visitor = server.visit(&get(&host, "/")).await;
+        let response = read_response(&mut visitor).await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        assert!(response.contains("\r\nRetry-After: "), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_streams_outlive_their_agent_leaving() {
+        let server = TestServer::start().await;
+        let mut agent = server.agent("it-leaving").await;
+        let client = Connections::for_host(&agent.sub_domain).remove(0);
+
+        let mut visitor = server.visit(&get(&agent.host(), "/")).await;
+        let (stream_id, _) = agent.accept().await;
+        let head = "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n";
+        agent
+            .send(ControlPacket::Data(stream_id.clone(), head.into()))
+            .await;
+        read_until(&mut visitor, "\r\n\r\n").await;
+
+        // visitors no longer reach the agent, while it has a grace to finish its stream
+        // that outlasts a sweep of the reaper
+        Connections::forget(&client);
+        tokio::time::sleep(active_stream::REAP_INTERVAL + Duration::from_secs(1)).await;
+        assert!(get_active_streams().contains_key(&stream_id));
+        agent
+            .send(ControlPacket::Data(stream_id.clone(), "body".into()))
+            .await;
+        read_until(&mut visitor, "body").await;
+
+        // once the agent is gone, so is its stream
+        client.cancel.cancel();
+        assert!(eventually(|| !get_active_streams().contains_key(&stream_id)).await);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_replaces_connection() {
+        let server = TestServer::start().await;
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        let mut first = server
+            .authenticated_agent("it-replace", "it-replace-key", "it-replace-machine")
+            .await;
+        let old = Connections::for_host(&first.sub_domain).remove(0);
+        let mut visitor = server.visit(&get(&first.host(), "/")).await;
+        let (stream_id, _) = first.accept().await;
+
+        // the agent reconnects before we noticed its connection was gone
+        let mut second = server
+            .authenticated_agent("it-replace", "it-replace-key", "it-replace-machine")
+            .await;
+        assert_eq!(second.sub_domain, first.sub_domain);
+        let agents = Connections::for_host(&second.sub_domain);
+        assert_eq!(agents.len(), 1);
+        assert_ne!(agents[0].session_id, old.session_id);
+        assert!(Connections::get(&old.session_id).is_none());
+
+        // the old connection finishes its stream, while new visitors reach the new one
+        first.respond(&stream_id, ok).await;
+        let response = read_response(&mut visitor).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let mut visitor = server.visit(&get(&second.host(), "/")).await;
+        let (stream_id, _) = second.accept().await;
+        second.respond(&stream_id, ok).await;
+        let response = read_response(&mut visitor).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        // then it's closed
+        assert!(eventually(|| old.cancel.is_cancelled()).await);
+        assert_eq!(Connections::for_host(&second.sub_domain).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mirrors_requests_to_the_accounts_tunnels() {
+        let server = TestServer::start().await;
+        let mut primary = server
+            .authenticated_agent("it-mirrored", "it-mirror-key", "it-mirrored-machine")
+            .await;
+        let mut hello = ClientHello::generate(
+            Some("it-mirror".to_string()),
+            ClientType::Auth {
+                key: SecretKey("it-mirror-key".to_string()),
+            },
+        );
+        hello.agent_id = Some("it-mirror-machine".to_string());
+        hello.mirror_of = Some(primary.sub_domain.clone());
+        let mut mirror = ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .unwrap_or_else(|hello| panic!("mirror refused: {:?}", hello));
+
+        let mut visitor = server.visit(&get(&primary.host(), "/mirrored")).await;
+        let (stream_id, request) = primary.accept().await;
+        let (copy_id, copy) = mirror.accept().await;
+        assert_eq!(
+            String::from_utf8_lossy(&copy),
+            String::from_utf8_lossy(&request)
+        );
+
+        // only the mirrored tunnel's answer reaches the visitor
+        mirror
+            .respond(&copy_id, "HTTP/1.1 500 Oops\r\nContent-Length: 0\r\n\r\n")
+            .await;
+        primary
+            .respond(
+                &stream_id,
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+            )
+            .await;
+        let response = read_response(&mut visitor).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+        // anonymous agents have no account to mirror tunnels of
+        let mut hello = ClientHello::generate(
+            Some("it-mirror-anonymous".to_string()),
+            ClientType::Anonymous,
+        );
+        hello.mirror_of = Some(primary.sub_domain.clone());
+        let refused = ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .err();
+        assert!(
+            matches!(refused, Some(ServerHello::Error(_))),
+            "{:?}",
+            refused
+        );
+    }
+
+    #[tokio::test]
+    async fn test_closes_tunnels_at_the_end_of_their_lifetime() {
+        let server = TestServer::start().await;
+        let mut hello =
+            ClientHello::generate(Some("it-expires".to_string()), ClientType::Anonymous);
+        hello.ttl = Some(1);
+        let mut agent = ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .unwrap_or_else(|hello| panic!("agent refused: {:?}", hello));
+        assert!(!Connections::for_host(&agent.sub_domain).is_empty());
+
+        assert!(matches!(
+            agent.next_packet().await,
+            Some(ControlPacket::Expired)
+        ));
+        // closed on the agent that didn't leave once its grace is over
+        assert!(eventually(|| Connections::for_host(&agent.sub_domain).is_empty()).await);
+    }
+
+    #[tokio::test]
+    async fn test_machines_tunnels_are_not_replaced() {
+        let server = TestServer::start().await;
+        let first = server
+            .authenticated_agent("it-machine-one", "it-machine-key", "it-machine")
+            .await;
+        let second = server
+            .authenticated_agent("it-machine-two", "it-machine-key", "it-machine")
+            .await;
+
+        assert_eq!(Connections::for_host(&first.sub_domain).len(), 1);
+        assert_eq!(Connections::for_host(&second.sub_domain).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_taken_sub_domain_suggests_others() {
+        let server = TestServer::start().await;
+        crate::get_storage()
+            .reserve("it-taken", "another-account")
+            .await
+            .unwrap();
+
+        let mut hello = ClientHello::generate(
+            Some("it-taken".to_string()),
+            ClientType::Auth {
+                key: SecretKey("it-taken-other".to_string()),
+            },
+        );
+        hello.accepts_suggestions = true;
+        match ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .err()
+        {
+            Some(ServerHello::SubDomainTaken { suggestions }) => {
+                assert_eq!(suggestions.len(), 3);
+                assert_eq!(suggestions[0], "it-taken-2");
+                assert!(!suggestions.contains(&"it-taken".to_string()));
+            }
+            other => panic!("expected suggestions, got {:?}", other),
+        }
+
+        // agents that don't understand suggestions are refused like before
+        let hello = ClientHello::generate(
+            Some("it-taken".to_string()),
+            ClientType::Auth {
+                key: SecretKey("it-taken-other".to_string()),
+            },
+        );
+        match ScriptedAgent::connect_with(server.control, hello)
+            .await
+            .err()
+        {
+            Some(ServerHello::SubDomainInUse) => {}
+            other => panic!("expected the sub-domain in use, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfer_sub_domain() {
+        let server = TestServer::start().await;
+        let mut owner = server.agent("it-transfer-owner").await;
+        let mut agent = server.agent("it-transfer-agent").await;
+        let session_id = Connections::for_host(&agent.sub_domain)[0].session_id;
+
+        let moved = client_manager::transfer(&session_id, &owner.sub_domain).unwrap();
+        assert_eq!(moved.host, owner.sub_domain);
+        assert!(Connections::for_host(&agent.sub_domain).is_empty());
+
+        // the owner is told why it's let go, the agent where it is now
+        match owner.next_packet().await {
+            Some(ControlPacket::Disconnected(reason)) => assert!(reason.contains("transferred")),
+            other => panic!("expected the owner to be disconnected, got {:?}", other),
+        }
+        assert!(owner.next_packet().await.is_none());
+        match agent.next_packet().await {
+            Some(ControlPacket::Transferred(transfer)) => {
+                assert_eq!(transfer.sub_domain, owner.sub_domain)
+            }
+            other => panic!("expected the agent to be moved, got {:?}", other),
+        }
+
+        let mut visitor = server.visit(&get(&owner.host(), "/")).await;
+        let (stream_id, _) = agent.accept().await;
+        agent
+            .respond(
+                &stream_id,
+                "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+        let response = read_response(&mut visitor).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+}
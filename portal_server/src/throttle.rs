@@ -0,0 +1,106 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A token bucket shared by every stream of a tunnel, limiting its bandwidth
+#[derive(Clone)]
+pub struct Throttle(Arc<Mutex<TokenBucket>>);
+
+impl std::fmt::Debug for Throttle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Throttle").field(&self.rate()).finish()
+    }
+}
+
+impl Throttle {
+    /// Limit to `rate` bytes per second, allowing bursts of up to one second's worth
+    pub fn new(rate: u64) -> Self {
+        Throttle(Arc::new(Mutex::new(TokenBucket::new(rate, Instant::now()))))
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.0.lock().map(|bucket| bucket.rate).unwrap_or_default()
+    }
+
+    /// Wait until `bytes` may be sent through the tunnel
+    pub async fn consume(&self, bytes: usize) {
+        let wait = match self.0.lock() {
+            Ok(mut bucket) => bucket.take(bytes as u64, Instant::now()),
+            Err(_) => None,
+        };
+
+        if let Some(wait) = wait {
+            tracing::trace!(?wait, bytes, "throttling tunnel");
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+pub(crate) struct TokenBucket {
+    pub rate: u64,
+    /// may go negative: frames larger than the bucket are let through,
+    /// and later ones wait for the debt to be paid off
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Take `bytes` tokens, returning how long the caller should wait if the bucket ran dry
+    fn take(&mut self, bytes: u64, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 || self.rate == 0 {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(-self.tokens / self.rate as f64))
+    }
+
+    /// Take `tokens` only if there are as many left, never going into debt
+    pub fn try_take(&mut self, tokens: u64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < tokens as f64 {
+            return false;
+        }
+        self.tokens -= tokens as f64;
+        true
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+
+        // the initial burst is free
+        assert_eq!(bucket.take(1000, start), None);
+
+        // then we pay for every byte
+        assert_eq!(bucket.take(500, start), Some(Duration::from_millis(500)));
+
+        // and refill over time
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(bucket.take(1000, later), None);
+        assert_eq!(bucket.take(1, later), Some(Duration::from_millis(1)));
+    }
+}
@@ -0,0 +1,369 @@
+//! The connections agents hold their tunnels over: a websocket on the control port,
+//! or a QUIC connection on `quic_port` for agents on networks they may leave.
+//!
+//! The handshake, the agent's streams and its bookkeeping are `control_server`'s, whatever
+//! the transport. Another transport implements `ControlTransport`, gets a variant of
+//! `AgentSink` and `AgentStream`, and is served with `serve`.
+use crate::{get_config, get_health, get_tasks, observability, Config};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use portal_lib::quic::{self, QuicControl, QuicReceiver, QuicSender};
+use portal_lib::ControlPacket;
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::rustls::{self, pki_types::PrivateKeyDer};
+use quinn::{Endpoint, Incoming};
+use std::error::Error;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{error, info, warn, Instrument};
+use warp::ws::{Message, WebSocket};
+
+/// How long an agent has to open its control stream once connected
+const CONTROL_STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The QUIC control server's endpoint, for reloads to swap its certificate
+static QUIC_ENDPOINT: OnceLock<Endpoint> = OnceLock::new();
+
+/// What agents hold their tunnels over: accepting their connections, and the frames
+/// of their handshake and packets going both ways
+#[async_trait]
+pub trait ControlTransport: Send + 'static {
+    /// An agent that reached us, before its control connection is set up
+    type Incoming: Send + 'static;
+    type Sink: Send + 'static;
+    type Stream: Send + 'static;
+
+    /// The transport's name in logs and traces
+    const NAME: &'static str;
+
+    /// The next agent to reach us and its ip, `None` once we stopped listening
+    async fn accept(&mut self) -> Option<(IpAddr, Self::Incoming)>;
+
+    /// Set up an agent's control connection, apart from accepting as it takes a while
+    async fn open(incoming: Self::Incoming) -> io::Result<(Self::Sink, Self::Stream)>;
+
+    /// Send a frame, i.e. a handshake message
+    async fn write_frame(sink: &mut Self::Sink, frame: Vec<u8>) -> io::Result<()>;
+
+    /// Send a packet of the agent's tunnel
+    async fn write_packet(sink: &mut Self::Sink, packet: ControlPacket) -> io::Result<()> {
+        Self::write_frame(sink, packet.serialize()).await
+    }
+
+    /// The next frame, `None` once the agent hung up
+    async fn read_frame(stream: &mut Self::Stream) -> Option<Bytes>;
+
+    /// Hang up on the agent once it got what we sent
+    async fn close(sink: &mut Self::Sink);
+}
+
+/// Hand the agents `transport` accepts to `control_server::handle_new_connection`
+pub async fn serve<T>(mut transport: T)
+where
+    T: ControlTransport,
+    AgentSink: From<T::Sink>,
+    AgentStream: From<T::Stream>,
+{
+    while let Some((client_ip, incoming)) = transport.accept().await {
+        get_tasks().spawn(
+            "control_handshake",
+            get_tasks().token(),
+            async move {
+                match tokio::time::timeout(CONTROL_STREAM_TIMEOUT, T::open(incoming)).await {
+                    Ok(Ok((sink, stream))) => {
+                        let connection = AgentConnection {
+                            sink: sink.into(),
+                            stream: stream.into(),
+                        };
+                        crate::control_server::handle_new_connection(client_ip, connection).await
+                    }
+                    Ok(Err(error)) => {
+                        warn!(%client_ip, %error, transport = T::NAME, "control connection failed")
+                    }
+                    Err(_) => warn!(%client_ip, transport = T::NAME, "agent didn't open its control connection"),
+                }
+            }
+            .instrument(observability::remote_trace(&format!("handle_{}", T::NAME))),
+        );
+    }
+}
+
+/// An agent's control connection, whatever the transport
+pub struct AgentConnection {
+    sink: AgentSink,
+    stream: AgentStream,
+}
+
+pub enum AgentSink {
+    WebSocket(SplitSink<WebSocket, Message>),
+    Quic(QuicSender),
+}
+
+pub enum AgentStream {
+    WebSocket(SplitStream<WebSocket>),
+    Quic(QuicReceiver),
+}
+
+impl AgentConnection {
+    /// Send a handshake message
+    pub async fn send(&mut self, data: Vec<u8>) -> io::Result<()> {
+        self.sink.send_frame(data).await
+    }
+
+    /// The next handshake message, `None` once the agent hung up
+    pub async fn next(&mut self) -> Option<Bytes> {
+        self.stream.next().await
+    }
+
+    pub async fn close(mut self) {
+        self.sink.close().await
+    }
+
+    pub fn split(self) -> (AgentSink, AgentStream) {
+        (self.sink, self.stream)
+    }
+}
+
+impl AgentSink {
+    async fn send_frame(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        match self {
+            AgentSink::WebSocket(sink) => WebSocketTransport::write_frame(sink, frame).await,
+            AgentSink::Quic(sender) => QuicTransport::write_frame(sender, frame).await,
+        }
+    }
+
+    pub async fn send(&mut self, packet: ControlPacket) -> io::Result<()> {
+        match self {
+            AgentSink::WebSocket(sink) => WebSocketTransport::write_packet(sink, packet).await,
+            AgentSink::Quic(sender) => QuicTransport::write_packet(sender, packet).await,
+        }
+    }
+
+    /// Hang up on the agent once it got what we sent
+    pub async fn close(&mut self) {
+        match self {
+            AgentSink::WebSocket(sink) => WebSocketTransport::close(sink).await,
+            AgentSink::Quic(sender) => QuicTransport::close(sender).await,
+        }
+    }
+}
+
+impl AgentStream {
+    /// The next packet, `None` once the agent hung up
+    pub async fn next(&mut self) -> Option<Bytes> {
+        match self {
+            AgentStream::WebSocket(stream) => WebSocketTransport::read_frame(stream).await,
+            AgentStream::Quic(receiver) => QuicTransport::read_frame(receiver).await,
+        }
+    }
+}
+
+impl From<SplitSink<WebSocket, Message>> for AgentSink {
+    fn from(sink: SplitSink<WebSocket, Message>) -> Self {
+        AgentSink::WebSocket(sink)
+    }
+}
+
+impl From<SplitStream<WebSocket>> for AgentStream {
+    fn from(stream: SplitStream<WebSocket>) -> Self {
+        AgentStream::WebSocket(stream)
+    }
+}
+
+impl From<QuicSender> for AgentSink {
+    fn from(sender: QuicSender) -> Self {
+        AgentSink::Quic(sender)
+    }
+}
+
+impl From<QuicReceiver> for AgentStream {
+    fn from(receiver: QuicReceiver) -> Self {
+        AgentStream::Quic(receiver)
+    }
+}
+
+/// Agents' websockets, which the control routes upgrade, so draining turns them away
+/// before they're accepted
+pub struct WebSocketTransport {
+    upgraded: UnboundedReceiver<(IpAddr, WebSocket)>,
+}
+
+impl WebSocketTransport {
+    /// A transport accepting the websockets handed to the sender, until every clone of
+    /// it is dropped
+    pub fn new() -> (Self, UnboundedSender<(IpAddr, WebSocket)>) {
+        let (tx, upgraded) = unbounded_channel();
+        (WebSocketTransport { upgraded }, tx)
+    }
+}
+
+#[async_trait]
+impl ControlTransport for WebSocketTransport {
+    type Incoming = WebSocket;
+    type Sink = SplitSink<WebSocket, Message>;
+    type Stream = SplitStream<WebSocket>;
+
+    const NAME: &'static str = "websocket";
+
+    async fn accept(&mut self) -> Option<(IpAddr, WebSocket)> {
+        self.upgraded.recv().await
+    }
+
+    async fn open(websocket: WebSocket) -> io::Result<(Self::Sink, Self::Stream)> {
+        Ok(websocket.split())
+    }
+
+    async fn write_frame(sink: &mut Self::Sink, frame: Vec<u8>) -> io::Result<()> {
+        sink.send(Message::binary(frame))
+            .await
+            .map_err(io::Error::other)
+    }
+
+    async fn read_frame(stream: &mut Self::Stream) -> Option<Bytes> {
+        match stream.next().await? {
+            Ok(msg) if (msg.is_binary() || msg.is_text()) && !msg.as_bytes().is_empty() => {
+                Some(Bytes::from(msg.into_bytes()))
+            }
+            Ok(msg) if msg.is_close() && !msg.as_bytes().is_empty() => {
+                tracing::debug!(close_reason=?msg, "got close");
+                None
+            }
+            _ => None,
+        }
+    }
+
+    async fn close(sink: &mut Self::Sink) {
+        let _ = sink.close().await;
+    }
+}
+
+/// Agents' QUIC connections, each with a control stream the agent opens
+pub struct QuicTransport {
+    endpoint: Endpoint,
+}
+
+#[async_trait]
+impl ControlTransport for QuicTransport {
+    type Incoming = Incoming;
+    type Sink = QuicSender;
+    type Stream = QuicReceiver;
+
+    const NAME: &'static str = "quic";
+
+    async fn accept(&mut self) -> Option<(IpAddr, Incoming)> {
+        loop {
+            let incoming = self.endpoint.accept().await?;
+            // agents retry until they get to an instance that stays
+            if get_health().is_draining() {
+                incoming.refuse();
+                continue;
+            }
+            return Some((incoming.remote_address().ip(), incoming));
+        }
+    }
+
+    async fn open(incoming: Incoming) -> io::Result<(QuicSender, QuicReceiver)> {
+        let connection = incoming.await.map_err(io::Error::from)?;
+        Ok(QuicControl::accept(connection).await?.split())
+    }
+
+    async fn write_frame(sender: &mut QuicSender, frame: Vec<u8>) -> io::Result<()> {
+        sender.send_frame(frame).await
+    }
+
+    /// Each stream's packets go over a QUIC stream of its own
+    async fn write_packet(sender: &mut QuicSender, packet: ControlPacket) -> io::Result<()> {
+        sender.send(packet).await
+    }
+
+    async fn read_frame(receiver: &mut QuicReceiver) -> Option<Bytes> {
+        match receiver.next().await? {
+            Ok(frame) if !frame.is_empty() => Some(frame),
+            Ok(_) => None,
+            Err(error) => {
+                tracing::debug!(%error, "quic control stream failed");
+                None
+            }
+        }
+    }
+
+    async fn close(sender: &mut QuicSender) {
+        sender.close().await
+    }
+}
+
+/// Serve agents over QUIC on `addr`, returning the address bound
+pub fn spawn_quic(addr: SocketAddr) -> Result<SocketAddr, Box<dyn Error>> {
+    let endpoint = Endpoint::server(server_config(&get_config())?, addr)?;
+    let addr = endpoint.local_addr()?;
+    let _ = QUIC_ENDPOINT.set(endpoint.clone());
+
+    tokio::spawn(serve(QuicTransport { endpoint }));
+    info!("started quic control server on {}", addr);
+    Ok(addr)
+}
+
+/// Serve the certificate a reloaded `config` names to the agents connecting next, read
+/// again as it may have been renewed in place. One that fails to load leaves the
+/// current one, and `config` naming it.
+pub fn reload_quic(config: &mut Config, current: &Config) {
+    let Some(endpoint) = QUIC_ENDPOINT.get() else {
+        return;
+    };
+    // a self-signed certificate is kept rather than generated anew
+    if config.quic_cert.is_none() && current.quic_cert.is_none() {
+        return;
+    }
+    match server_config(config) {
+        Ok(server) => {
+            endpoint.set_server_config(Some(server));
+            info!("reloaded the quic certificate");
+        }
+        Err(error) => {
+            error!(%error, "failed to reload the quic certificate, keeping the current one");
+            config.quic_cert = current.quic_cert.clone();
+            config.quic_key = current.quic_key.clone();
+        }
+    }
+}
+
+/// Our certificate for QUIC, the configured one or else a self-signed one for
+/// `portal_host` agents only accept with TLS verification off
+fn server_config(config: &Config) -> Result<quinn::ServerConfig, Box<dyn Error>> {
+    let (certs, key) = match (&config.quic_cert, &config.quic_key) {
+        (Some(cert), Some(key)) => {
+            let certs = rustls_pemfile::certs(&mut io::BufReader::new(std::fs::File::open(cert)?))
+                .collect::<Result<Vec<_>, _>>()?;
+            let key =
+                rustls_pemfile::private_key(&mut io::BufReader::new(std::fs::File::open(key)?))?
+                    .ok_or_else(|| format!("no private key in {}", key))?;
+            (certs, key)
+        }
+        _ => {
+            warn!("no quic_cert given, using a self-signed certificate");
+            let names = vec![
+                config.portal_host.clone(),
+                format!("*.{}", config.portal_host),
+            ];
+            let cert = rcgen::generate_simple_self_signed(names)?;
+            let key = PrivateKeyDer::Pkcs8(cert.key_pair.serialize_der().into());
+            (vec![cert.cert.into()], key)
+        }
+    };
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    tls.alpn_protocols = vec![quic::ALPN.to_vec()];
+
+    let mut server = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    server.transport_config(quic::transport());
+    Ok(server)
+}
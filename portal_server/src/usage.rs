@@ -0,0 +1,319 @@
+//! Traffic, requests and connection time per account, for metering and fair use
+use crate::auth::AuthService;
+use crate::connected_clients::{ConnectedClient, Connections};
+use crate::storage::Storage;
+use crate::{get_auth_db_service, get_config, ClientId};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Usage is kept in buckets of this length
+const PERIOD: Duration = Duration::hours(1);
+
+/// Usage of one account
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Counters {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub requests: u64,
+    /// time the account's agents were connected, summed over all of them
+    pub connection_ms: u64,
+}
+
+impl Counters {
+    pub fn merge(&mut self, other: &Counters) {
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.requests += other.requests;
+        self.connection_ms += other.connection_ms;
+    }
+}
+
+/// Usage of one account during one period
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageRecord {
+    /// the client id of the agent key
+    pub account: String,
+    pub period_start: DateTime<Utc>,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+type Key = (String, DateTime<Utc>);
+
+pub struct Usage {
+    retention: Duration,
+    /// everything within the retention, for queries
+    storage: &'static dyn Storage,
+    /// what the storage hasn't seen yet
+    unsaved: DashMap<Key, Counters>,
+    /// what the auth backend hasn't seen yet
+    pending: DashMap<Key, Counters>,
+    last_accrual: Mutex<DateTime<Utc>>,
+}
+
+impl Usage {
+    pub fn new(retention: Duration, storage: &'static dyn Storage) -> Self {
+        Usage {
+            retention,
+            storage,
+            unsaved: DashMap::new(),
+            pending: DashMap::new(),
+            last_accrual: Mutex::new(Utc::now()),
+        }
+    }
+
+    fn add(&self, account: &ClientId, counters: Counters) {
+        let key = (account.to_string(), period_of(Utc::now()));
+        self.unsaved
+            .entry(key.clone())
+            .or_default()
+            .merge(&counters);
+        self.pending.entry(key).or_default().merge(&counters);
+    }
+
+    pub fn bytes_in(&self, account: &ClientId, bytes: usize) {
+        self.add(
+            account,
+            Counters {
+                bytes_in: bytes as u64,
+                ..Default::default()
+            },
+        );
+    }
+
+    pub fn bytes_out(&self, account: &ClientId, bytes: usize) {
+        self.add(
+            account,
+            Counters {
+                bytes_out: bytes as u64,
+                ..Default::default()
+            },
+        );
+    }
+
+    pub fn request(&self, account: &ClientId) {
+        self.add(
+            account,
+            Counters {
+                requests: 1,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Count the time an agent was connected since it connected or we last counted
+    fn accrue(&self, client: &ConnectedClient, since: DateTime<Utc>, now: DateTime<Utc>) {
+        let since = since.max(client.connected_at);
+        let connection_ms = (now - since).num_milliseconds().max(0) as u64;
+        self.add(
+            &client.id,
+            Counters {
+                connection_ms,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// An agent disconnected, count the rest of its connection time
+    pub fn disconnected(&self, client: &ConnectedClient) {
+        let since = *self.last_accrual.lock().unwrap();
+        self.accrue(client, since, Utc::now());
+    }
+
+    /// Count the connection time of every connected agent
+    fn accrue_connections(&self) {
+        let now = Utc::now();
+        let since = std::mem::replace(&mut *self.last_accrual.lock().unwrap(), now);
+        for client in Connections::all() {
+            self.accrue(&client, since, now);
+        }
+    }
+
+    /// Usage within `[from, to)`, by account and period, including what the storage
+    /// hasn't seen yet
+    pub async fn query(
+        &self,
+        account: Option<&str>,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<UsageRecord>, crate::storage::Error> {
+        // the periods that end after `from`
+        let after = from.map(|from| from - PERIOD);
+        let mut records: BTreeMap<Key, Counters> = self
+            .storage
+            .usage(account, after, to)
+            .await?
+            .into_iter()
+            .map(|record| ((record.account, record.period_start), record.counters))
+            .collect();
+        for entry in self.unsaved.iter() {
+            let (key_account, period_start) = entry.key();
+            if account.is_none_or(|account| account == key_account)
+                && after.is_none_or(|after| *period_start > after)
+                && to.is_none_or(|to| *period_start < to)
+            {
+                records
+                    .entry(entry.key().clone())
+                    .or_default()
+                    .merge(entry.value());
+            }
+        }
+        Ok(records
+            .into_iter()
+            .map(|((account, period_start), counters)| UsageRecord {
+                account,
+                period_start,
+                counters,
+            })
+            .collect())
+    }
+
+    /// Usage within `[from, to)` summed up per account
+    pub async fn totals(
+        &self,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<UsageTotal>, crate::storage::Error> {
+        let mut totals: Vec<UsageTotal> = vec![];
+        for record in self.query(None, from, to).await? {
+            match totals.last_mut() {
+                Some(total) if total.account == record.account => {
+                    total.counters.merge(&record.counters)
+                }
+                _ => totals.push(UsageTotal {
+                    account: record.account,
+                    counters: record.counters,
+                }),
+            }
+        }
+        Ok(totals)
+    }
+
+    /// Hand the usage accrued since the last flush to the storage and the auth backend,
+    /// and forget what's older than the retention
+    pub async fn flush(&self) {
+        self.accrue_connections();
+
+        let records = take(&self.unsaved);
+        if !records.is_empty() {
+            if let Err(error) = self.storage.add_usage(&records).await {
+                tracing::error!(%error, "failed to store usage, will retry");
+                put_back(&self.unsaved, records);
+            }
+        }
+
+        let records = take(&self.pending);
+        if !records.is_empty() {
+            if let Err(error) = get_auth_db_service().record_usage(&records) {
+                tracing::error!(?error, "failed to persist usage, will retry");
+                put_back(&self.pending, records);
+            }
+        }
+
+        let cutoff = self
+            .retention
+            .checked_add(&PERIOD)
+            .and_then(|age| Utc::now().checked_sub_signed(age));
+        if let Some(cutoff) = cutoff {
+            if let Err(error) = self.storage.prune_usage(cutoff).await {
+                tracing::error!(%error, "failed to forget old usage");
+            }
+        }
+    }
+}
+
+/// Empty `counters` into records
+fn take(counters: &DashMap<Key, Counters>) -> Vec<UsageRecord> {
+    let keys: Vec<Key> = counters.iter().map(|entry| entry.key().clone()).collect();
+    keys.into_iter()
+        .filter_map(|key| counters.remove(&key))
+        .map(|((account, period_start), counters)| UsageRecord {
+            account,
+            period_start,
+            counters,
+        })
+        .collect()
+}
+
+/// Add back records that couldn't be handed over
+fn put_back(counters: &DashMap<Key, Counters>, records: Vec<UsageRecord>) {
+    for record in records {
+        counters
+            .entry((record.account, record.period_start))
+            .or_default()
+            .merge(&record.counters);
+    }
+}
+
+/// Usage of one account over a range of periods
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageTotal {
+    pub account: String,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+fn period_of(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(PERIOD).unwrap_or(time)
+}
+
+/// Periodically persist usage to the auth backend
+pub fn spawn_flusher() {
+    let interval = get_config().usage_flush_interval;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // the first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            crate::get_usage().flush().await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[tokio::test]
+    async fn test_query_range() {
+        let storage: &'static MemoryStorage = Box::leak(Box::default());
+        let usage = Usage::new(Duration::days(1), storage);
+        let now = period_of(Utc::now());
+        let record = |account: &str, period_start, requests| UsageRecord {
+            account: account.to_string(),
+            period_start,
+            counters: Counters {
+                requests,
+                ..Default::default()
+            },
+        };
+        // saved and unsaved usage of the same period add up
+        storage
+            .add_usage(&[record("a", now - PERIOD, 1), record("a", now, 1)])
+            .await
+            .unwrap();
+        put_back(
+            &usage.unsaved,
+            vec![record("a", now, 1), record("b", now, 4)],
+        );
+
+        assert_eq!(usage.query(Some("a"), None, None).await.unwrap().len(), 2);
+        assert_eq!(
+            usage.query(Some("a"), Some(now), None).await.unwrap()[0]
+                .counters
+                .requests,
+            2
+        );
+        assert_eq!(usage.query(None, None, Some(now)).await.unwrap().len(), 1);
+
+        let totals = usage.totals(None, None).await.unwrap();
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals[0].counters.requests, 3);
+        assert_eq!(totals[1].counters.requests, 4);
+    }
+}
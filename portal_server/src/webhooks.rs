@@ -0,0 +1,348 @@
+//! Notify external services (i.e. Slack or billing) about what happens on this instance
+use crate::connected_clients::ConnectedClient;
+use crate::get_config;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Attempts per delivery before we give up on it
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    TunnelOpened,
+    TunnelClosed,
+    AuthFailure,
+    QuotaExceeded,
+    Alert,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::TunnelOpened => "tunnel_opened",
+            EventKind::TunnelClosed => "tunnel_closed",
+            EventKind::AuthFailure => "auth_failure",
+            EventKind::QuotaExceeded => "quota_exceeded",
+            EventKind::Alert => "alert",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tunnel_opened" => Ok(EventKind::TunnelOpened),
+            "tunnel_closed" => Ok(EventKind::TunnelClosed),
+            "auth_failure" => Ok(EventKind::AuthFailure),
+            "quota_exceeded" => Ok(EventKind::QuotaExceeded),
+            "alert" => Ok(EventKind::Alert),
+            other => Err(format!("unknown webhook event: {}", other)),
+        }
+    }
+}
+
+/// Where to send events to, as configured
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Signs every delivery with HMAC-SHA256 in the `X-Portal-Signature` header
+    pub secret: Option<String>,
+    /// The events to send, all of them if empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+}
+
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("url", &self.url)
+            .field("secret", &self.secret.as_ref().map(|_| "<hidden>"))
+            .field("events", &self.events)
+            .finish()
+    }
+}
+
+impl Webhook {
+    fn wants(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+
+    /// `sha256=<hex hmac of the body>`
+    fn signature(&self, body: &[u8]) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let mac = hmac_sha256::HMAC::mac(body, secret.as_bytes());
+        Some(format!("sha256={}", hex::encode(mac)))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    TunnelOpened {
+        sub_domain: String,
+        client_id: String,
+        session_id: String,
+        is_anonymous: bool,
+        name: Option<String>,
+        version: Option<String>,
+    },
+    TunnelClosed {
+        sub_domain: String,
+        client_id: String,
+        session_id: String,
+        connected_secs: i64,
+    },
+    AuthFailure {
+        reason: &'static str,
+        sub_domain: Option<String>,
+    },
+    QuotaExceeded {
+        sub_domain: String,
+        quota: &'static str,
+        limit: u64,
+    },
+    /// a tunnel's latency or error rate crossed its threshold, either way
+    Alert {
+        sub_domain: String,
+        metric: &'static str,
+        value: f64,
+        threshold: f64,
+        firing: bool,
+    },
+}
+
+impl Event {
+    pub fn tunnel_opened(client: &ConnectedClient) -> Self {
+        Event::TunnelOpened {
+            sub_domain: client.host.clone(),
+            client_id: client.id.to_string(),
+            session_id: client.session_id.to_string(),
+            is_anonymous: client.is_anonymous,
+            name: client.name.clone(),
+            version: client.version.clone(),
+        }
+    }
+
+    pub fn tunnel_closed(client: &ConnectedClient) -> Self {
+        Event::TunnelClosed {
+            sub_domain: client.host.clone(),
+            client_id: client.id.to_string(),
+            session_id: client.session_id.to_string(),
+            connected_secs: (Utc::now() - client.connected_at).num_seconds(),
+        }
+    }
+
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::TunnelOpened { .. } => EventKind::TunnelOpened,
+            Event::TunnelClosed { .. } => EventKind::TunnelClosed,
+            Event::AuthFailure { .. } => EventKind::AuthFailure,
+            Event::QuotaExceeded { .. } => EventKind::QuotaExceeded,
+            Event::Alert { .. } => EventKind::Alert,
+        }
+    }
+
+    /// A one line summary, which lets Slack's incoming webhooks take our payloads as they are
+    fn text(&self) -> String {
+        match self {
+            Event::TunnelOpened { sub_domain, .. } => format!("tunnel `{}` opened", sub_domain),
+            Event::TunnelClosed {
+                sub_domain,
+                connected_secs,
+                ..
+            } => format!("tunnel `{}` closed after {}s", sub_domain, connected_secs),
+            Event::AuthFailure {
+                reason,
+                sub_domain: Some(sub_domain),
+            } => format!(
+                "agent failed to authenticate for `{}`: {}",
+                sub_domain, reason
+            ),
+            Event::AuthFailure { reason, .. } => {
+                format!("agent failed to authenticate: {}", reason)
+            }
+            Event::QuotaExceeded {
+                sub_domain,
+                quota,
+                limit,
+            } => format!("tunnel `{}` exceeded {} of {}", sub_domain, quota, limit),
+            Event::Alert {
+                sub_domain,
+                metric,
+                value,
+                threshold,
+                firing: true,
+            } => format!(
+                "tunnel `{}` {} is {} (threshold {})",
+                sub_domain, metric, value, threshold
+            ),
+            Event::Alert {
+                sub_domain,
+                metric,
+                value,
+                ..
+            } => format!("tunnel `{}` {} is back to {}", sub_domain, metric, value),
+        }
+    }
+}
+
+/// The body of every webhook request
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    id: String,
+    timestamp: DateTime<Utc>,
+    instance_id: &'a str,
+    text: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+pub struct Webhooks {
+    hooks: Vec<Arc<Webhook>>,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new(hooks: Vec<Webhook>) -> Self {
+        Webhooks {
+            hooks: hooks.into_iter().map(Arc::new).collect(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Send `event` to every webhook interested in it, retrying in the background
+    pub fn emit(&self, event: Event) {
+        let kind = event.kind();
+        let hooks: Vec<_> = self
+            .hooks
+            .iter()
+            .filter(|hook| hook.wants(kind))
+            .cloned()
+            .collect();
+        if hooks.is_empty() {
+            return;
+        }
+
+        let payload = Payload {
+            id: Uuid::new_v4().simple().to_string(),
+            timestamp: Utc::now(),
+            instance_id: &get_config().instance_id,
+            text: event.text(),
+            event: &event,
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => Arc::new(body),
+            Err(error) => {
+                tracing::error!(?error, "failed to serialize webhook payload");
+                return;
+            }
+        };
+
+        for hook in hooks {
+            tokio::spawn(deliver(
+                self.client.clone(),
+                hook,
+                kind,
+                payload.id.clone(),
+                body.clone(),
+            ));
+        }
+    }
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    hook: Arc<Webhook>,
+    kind: EventKind,
+    id: String,
+    body: Arc<Vec<u8>>,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let mut request = client
+            .post(&hook.url)
+            .timeout(REQUEST_TIMEOUT)
+            .header("content-type", "application/json")
+            .header("x-portal-event", kind.as_str())
+            .header("x-portal-delivery", &id)
+            .body(body.to_vec());
+        if let Some(signature) = hook.signature(&body) {
+            request = request.header("x-portal-signature", signature);
+        }
+
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                tracing::debug!(url=%hook.url, event=kind.as_str(), %id, "delivered webhook");
+                return;
+            }
+            // the receiver won't change its mind about these
+            Ok(response)
+                if response.status().is_client_error()
+                    && response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS =>
+            {
+                tracing::warn!(url=%hook.url, event=kind.as_str(), %id, status=%response.status(), "webhook rejected");
+                return;
+            }
+            Ok(response) => {
+                tracing::warn!(url=%hook.url, event=kind.as_str(), %id, attempt, status=%response.status(), "webhook failed")
+            }
+            Err(error) => {
+                tracing::warn!(url=%hook.url, event=kind.as_str(), %id, attempt, %error, "webhook failed")
+            }
+        }
+
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    tracing::error!(url=%hook.url, event=kind.as_str(), %id, "giving up on webhook");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload() {
+        let event = Event::QuotaExceeded {
+            sub_domain: "foo".into(),
+            quota: "max_body_size",
+            limit: 8,
+        };
+        let payload = Payload {
+            id: "1".into(),
+            timestamp: Utc::now(),
+            instance_id: "instance",
+            text: event.text(),
+            event: &event,
+        };
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["event"], "quota_exceeded");
+        assert_eq!(json["data"]["quota"], "max_body_size");
+        assert_eq!(json["instance_id"], "instance");
+
+        let hook = Webhook {
+            url: "http://localhost".into(),
+            secret: Some("key".into()),
+            events: vec![EventKind::TunnelOpened],
+        };
+        assert!(!hook.wants(EventKind::QuotaExceeded));
+        assert_eq!(
+            hook.signature(b"The quick brown fox jumps over the lazy dog")
+                .unwrap(),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+}